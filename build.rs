@@ -0,0 +1,44 @@
+use std::env;
+use std::process::Command;
+
+/// Runs the command and returns the first line of its output, or `None` if it fails.
+fn output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let output = String::from_utf8(output.stdout).ok()?;
+    let line = output.lines().next()?.trim();
+    if line.is_empty() {
+        return None;
+    }
+
+    Some(String::from(line))
+}
+
+fn main() {
+    // The commit is unknown in a source snapshot without the repository
+    let commit = output("git", &["rev-parse", "--short=12", "HEAD"]);
+    let is_dirty = output("git", &["status", "--porcelain", "--untracked-files=no"]).is_some();
+    let commit = match commit {
+        Some(commit) if is_dirty => format!("{}-dirty", commit),
+        Some(commit) => commit,
+        None => String::from("unknown"),
+    };
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| String::from("rustc"));
+    let rustc = output(&rustc, &["--version"]).unwrap_or_else(|| String::from("unknown"));
+
+    println!("cargo:rustc-env=PCAP2SOCKS_COMMIT={}", commit);
+    println!("cargo:rustc-env=PCAP2SOCKS_RUSTC={}", rustc);
+    println!(
+        "cargo:rustc-env=PCAP2SOCKS_TARGET={}",
+        env::var("TARGET").unwrap_or_default()
+    );
+    println!(
+        "cargo:rustc-env=PCAP2SOCKS_PROFILE={}",
+        env::var("PROFILE").unwrap_or_default()
+    );
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
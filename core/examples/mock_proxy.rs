@@ -0,0 +1,221 @@
+//! Drives a `Redirector` with a scripted device instead of a capture. The device opens a TCP
+//! connection, sends a request and reads the reply, which a mock SOCKS5 proxy on the loopback
+//! answers by itself.
+//!
+//! Run with `cargo run -p pcap2socks-core --example mock_proxy`.
+
+use pnet::datalink::MacAddr;
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::{self, Ipv4Packet, MutableIpv4Packet};
+use pnet::packet::tcp::{self, MutableTcpPacket, TcpFlags, TcpPacket};
+use pnet::packet::Packet;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use pcap2socks_core::upstream::{Fallback, Policy, Upstreams};
+use pcap2socks_core::{DataLinkReceiver, Forwarder, HardwareAddr, Redirector};
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use common::{DeviceReceiver, DeviceSender};
+
+const DEVICE_HARDWARE_ADDR: HardwareAddr = MacAddr(2, 0, 0, 0, 0, 2);
+const LOCAL_HARDWARE_ADDR: HardwareAddr = MacAddr(2, 0, 0, 0, 0, 1);
+const DEVICE_IP_ADDR: Ipv4Addr = Ipv4Addr::new(10, 6, 0, 2);
+const LOCAL_IP_ADDR: Ipv4Addr = Ipv4Addr::new(10, 6, 0, 1);
+const DEVICE_PORT: u16 = 40000;
+const TIMEOUT: u64 = 5;
+
+/// Spawns a SOCKS5 proxy without authentication answering each CONNECT by itself, with the
+/// request in upper case.
+fn spawn_proxy() -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            thread::spawn(move || -> io::Result<()> {
+                let mut buffer = [0u8; 1024];
+                // Methods
+                stream.read_exact(&mut buffer[..2])?;
+                let methods = buffer[1] as usize;
+                stream.read_exact(&mut buffer[..methods])?;
+                stream.write_all(&[5, 0])?;
+                // CONNECT to an IPv4 destination
+                stream.read_exact(&mut buffer[..10])?;
+                let dst = SocketAddrV4::new(
+                    Ipv4Addr::new(buffer[4], buffer[5], buffer[6], buffer[7]),
+                    u16::from_be_bytes([buffer[8], buffer[9]]),
+                );
+                println!("proxy: CONNECT {}", dst);
+                stream.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])?;
+
+                loop {
+                    let size = stream.read(&mut buffer)?;
+                    if size == 0 {
+                        return Ok(());
+                    }
+                    stream.write_all(&buffer[..size].to_ascii_uppercase())?;
+                }
+            });
+        }
+    });
+
+    Ok(addr)
+}
+
+/// Builds a TCP segment of the device in an Ethernet frame.
+fn build_frame(dst: SocketAddrV4, flags: u16, sequence: u32, ack: u32, payload: &[u8]) -> Vec<u8> {
+    let tcp_size = 20 + payload.len();
+    let ipv4_size = 20 + tcp_size;
+    let mut frame = vec![0u8; 14 + ipv4_size];
+
+    let mut ethernet = MutableEthernetPacket::new(&mut frame).unwrap();
+    ethernet.set_source(DEVICE_HARDWARE_ADDR);
+    ethernet.set_destination(LOCAL_HARDWARE_ADDR);
+    ethernet.set_ethertype(EtherTypes::Ipv4);
+
+    let mut ipv4 = MutableIpv4Packet::new(&mut frame[14..]).unwrap();
+    ipv4.set_version(4);
+    ipv4.set_header_length(5);
+    ipv4.set_total_length(ipv4_size as u16);
+    ipv4.set_ttl(64);
+    ipv4.set_next_level_protocol(IpNextHeaderProtocols::Tcp);
+    ipv4.set_source(DEVICE_IP_ADDR);
+    ipv4.set_destination(*dst.ip());
+    let checksum = ipv4::checksum(&ipv4.to_immutable());
+    ipv4.set_checksum(checksum);
+
+    let mut tcp = MutableTcpPacket::new(&mut frame[34..]).unwrap();
+    tcp.set_source(DEVICE_PORT);
+    tcp.set_destination(dst.port());
+    tcp.set_sequence(sequence);
+    tcp.set_acknowledgement(ack);
+    tcp.set_data_offset(5);
+    tcp.set_flags(flags);
+    tcp.set_window(65535);
+    tcp.set_payload(payload);
+    let checksum = tcp::ipv4_checksum(&tcp.to_immutable(), &DEVICE_IP_ADDR, dst.ip());
+    tcp.set_checksum(checksum);
+
+    frame
+}
+
+/// Represents a TCP segment to the device.
+struct Segment {
+    flags: u16,
+    sequence: u32,
+    payload: Vec<u8>,
+}
+
+/// Waits for the next TCP segment to the device.
+fn receive(rx: &Receiver<Vec<u8>>) -> io::Result<Segment> {
+    loop {
+        let frame = rx
+            .recv_timeout(Duration::from_secs(TIMEOUT))
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no segment to the device"))?;
+        let ethernet = match EthernetPacket::new(&frame) {
+            Some(ethernet) if ethernet.get_ethertype() == EtherTypes::Ipv4 => ethernet,
+            _ => continue,
+        };
+        let ipv4 = match Ipv4Packet::new(ethernet.payload()) {
+            Some(ipv4) if ipv4.get_next_level_protocol() == IpNextHeaderProtocols::Tcp => ipv4,
+            _ => continue,
+        };
+        // Frames are padded to the minimum size of Ethernet
+        let size = ipv4.get_total_length() as usize - ipv4.get_header_length() as usize * 4;
+        let tcp = match TcpPacket::new(&ipv4.payload()[..size]) {
+            Some(tcp) => tcp,
+            None => continue,
+        };
+
+        return Ok(Segment {
+            flags: tcp.get_flags(),
+            sequence: tcp.get_sequence(),
+            payload: tcp.payload().to_vec(),
+        });
+    }
+}
+
+fn main() -> io::Result<()> {
+    let proxy = spawn_proxy()?;
+    // Any destination, the mock proxy answers by itself
+    let dst = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 80), 80);
+
+    let (device_tx, capture_rx) = mpsc::channel();
+    let (capture_tx, device_rx) = mpsc::channel();
+    let forwarder = Forwarder::new(
+        Box::new(DeviceSender(capture_tx)),
+        1400,
+        LOCAL_HARDWARE_ADDR,
+        DEVICE_IP_ADDR,
+        LOCAL_IP_ADDR,
+    );
+    let upstreams = Upstreams::new(vec![proxy], Policy::First, Fallback::Fail)?;
+    let mut redirector = Redirector::new(
+        Arc::new(Mutex::new(forwarder)),
+        DEVICE_IP_ADDR,
+        None,
+        upstreams,
+        30000,
+        64,
+    );
+    let mut rx: Box<dyn DataLinkReceiver> = Box::new(DeviceReceiver::new(capture_rx));
+    let capture = thread::spawn(move || redirector.open(&mut rx));
+
+    // Handshake
+    let mut sequence = 1000;
+    device_tx
+        .send(build_frame(dst, TcpFlags::SYN, sequence, 0, &[]))
+        .unwrap();
+    let syn = receive(&device_rx)?;
+    assert_eq!(syn.flags & TcpFlags::SYN, TcpFlags::SYN, "no ACK/SYN");
+    sequence += 1;
+    let mut ack = syn.sequence.wrapping_add(1);
+    device_tx
+        .send(build_frame(dst, TcpFlags::ACK, sequence, ack, &[]))
+        .unwrap();
+    println!("device: connected to {}", dst);
+
+    // Request and reply
+    let request = b"hello through the proxy";
+    device_tx
+        .send(build_frame(
+            dst,
+            TcpFlags::ACK | TcpFlags::PSH,
+            sequence,
+            ack,
+            request,
+        ))
+        .unwrap();
+    sequence += request.len() as u32;
+    let mut reply = Vec::new();
+    while reply.len() < request.len() {
+        let segment = receive(&device_rx)?;
+        if !segment.payload.is_empty() && segment.sequence == ack {
+            ack = ack.wrapping_add(segment.payload.len() as u32);
+            reply.extend_from_slice(&segment.payload);
+            device_tx
+                .send(build_frame(dst, TcpFlags::ACK, sequence, ack, &[]))
+                .unwrap();
+        }
+    }
+    println!("device: receive {}", String::from_utf8_lossy(&reply));
+    assert_eq!(reply, request.to_ascii_uppercase());
+
+    // The capture ends once the device is gone
+    drop(device_tx);
+    match capture.join().unwrap() {
+        Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        result => result,
+    }
+}
@@ -0,0 +1,278 @@
+use std::cmp::min;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::random::derive_seed;
+
+/// Represents the default cap of retry delays in seconds.
+pub const DEFAULT_CAP: u64 = 60;
+
+/// Represents the default max number of proxy handshakes running concurrently.
+pub const DEFAULT_MAX_HANDSHAKES: usize = 128;
+
+/// Represents the time a handshake waits for the gate before giving up.
+const GATE_TIMEOUT: u64 = 10;
+
+/// Represents the max wait between 2 tries of entering the gate in milliseconds.
+const GATE_WAIT: u64 = 20;
+
+/// Represents the max exponent of the doubling, the cap is reached long before.
+const MAX_EXPONENT: u32 = 20;
+
+/// Represents a place retrying after failures.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Site {
+    /// Probes of a down upstream.
+    Probe,
+    /// Connects of the warm standby to its peer.
+    Standby,
+    /// New connects after the local ports are exhausted.
+    Ports,
+    /// Proxy handshakes waiting for the gate.
+    Handshake,
+    /// Associations of UDP servers bound again after failures.
+    Server,
+    /// Fills of the pool of pre-warmed connections after failed connects.
+    Prewarm,
+    /// Associations restored from a state file bound again while the port is in use.
+    Restore,
+}
+
+const SITES: [Site; 7] = [
+    Site::Probe,
+    Site::Standby,
+    Site::Ports,
+    Site::Handshake,
+    Site::Server,
+    Site::Prewarm,
+    Site::Restore,
+];
+
+impl Site {
+    fn index(self) -> usize {
+        match self {
+            Site::Probe => 0,
+            Site::Standby => 1,
+            Site::Ports => 2,
+            Site::Handshake => 3,
+            Site::Server => 4,
+            Site::Prewarm => 5,
+            Site::Restore => 6,
+        }
+    }
+}
+
+impl Display for Site {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Site::Probe => write!(f, "probe"),
+            Site::Standby => write!(f, "standby"),
+            Site::Ports => write!(f, "ports"),
+            Site::Handshake => write!(f, "handshake"),
+            Site::Server => write!(f, "server"),
+            Site::Prewarm => write!(f, "prewarm"),
+            Site::Restore => write!(f, "restore"),
+        }
+    }
+}
+
+static SEED: AtomicU64 = AtomicU64::new(0);
+static CAP: AtomicU64 = AtomicU64::new(DEFAULT_CAP * 1000);
+static MAX_HANDSHAKES: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_HANDSHAKES);
+static HANDSHAKES: AtomicUsize = AtomicUsize::new(0);
+static ATTEMPTS: [AtomicUsize; 7] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+static GIVE_UPS: [AtomicUsize; 7] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+
+/// Sets the global seed of the jitter, which is reproducible with the same seed.
+pub fn set_seed(seed: u64) {
+    SEED.store(derive_seed(seed, "backoff"), Ordering::Relaxed);
+}
+
+/// Sets the cap of retry delays.
+pub fn set_cap(cap: Duration) {
+    CAP.store(cap.as_millis() as u64, Ordering::Relaxed);
+}
+
+/// Sets the max number of proxy handshakes running concurrently crate-wide, 0 for unlimited.
+pub fn set_max_handshakes(max: usize) {
+    MAX_HANDSHAKES.store(max, Ordering::Relaxed);
+}
+
+/// Get a random value of the site in [0, n] from the global seed. The n-th attempt of a site draws
+/// the same value with the same seed.
+fn draw(site: Site, n: usize) -> u64 {
+    let attempt = ATTEMPTS[site.index()].fetch_add(1, Ordering::Relaxed);
+    let value = derive_seed(
+        SEED.load(Ordering::Relaxed) ^ attempt as u64,
+        &site.to_string(),
+    );
+
+    value % (n as u64 + 1)
+}
+
+/// Get the delay before the retry of the site after the given number of consecutive failures
+/// from 0, which is exponential in the base and capped, with full jitter: a random delay between
+/// 0 and the ceiling, so retries of many flows spread out instead of arriving in lockstep.
+pub fn get_delay(site: Site, base: Duration, failures: usize) -> Duration {
+    let exponent = min(failures, MAX_EXPONENT as usize) as u32;
+    let ceiling = min(
+        (base.as_millis() as u64).saturating_mul(1 << exponent),
+        CAP.load(Ordering::Relaxed),
+    );
+
+    Duration::from_millis(draw(site, ceiling as usize))
+}
+
+/// Records the site giving up retrying.
+pub fn give_up(site: Site) {
+    GIVE_UPS[site.index()].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Represents a place in the gate of proxy handshakes, which is released when dropped.
+#[derive(Debug)]
+pub struct Permit;
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        HANDSHAKES.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Enters the gate of proxy handshakes, waits with jitter while the gate is full, and gives up
+/// with a `TimedOut` error after a while.
+pub fn enter() -> io::Result<Permit> {
+    let start = Instant::now();
+    loop {
+        let max = MAX_HANDSHAKES.load(Ordering::Relaxed);
+        let count = HANDSHAKES.load(Ordering::Acquire);
+        if max == 0 || count < max {
+            if HANDSHAKES
+                .compare_exchange(count, count + 1, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                return Ok(Permit);
+            }
+            continue;
+        }
+        if start.elapsed().as_secs() >= GATE_TIMEOUT {
+            give_up(Site::Handshake);
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "too many proxy handshakes",
+            ));
+        }
+        thread::sleep(Duration::from_millis(
+            1 + draw(Site::Handshake, GATE_WAIT as usize - 1),
+        ));
+    }
+}
+
+/// Get the number of attempts and give-ups of each site which retried.
+pub fn get_counts() -> Vec<(Site, usize, usize)> {
+    SITES
+        .iter()
+        .map(|site| {
+            (
+                *site,
+                ATTEMPTS[site.index()].load(Ordering::Relaxed),
+                GIVE_UPS[site.index()].load(Ordering::Relaxed),
+            )
+        })
+        .filter(|(_, attempts, give_ups)| *attempts > 0 || *give_ups > 0)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Barrier};
+
+    /// Represents the number of waiters of the gate.
+    const WAITERS: usize = 1000;
+
+    #[test]
+    fn test_jitter_bounds() {
+        let base = Duration::from_millis(100);
+        let cap = Duration::from_millis(CAP.load(Ordering::Relaxed));
+        for failures in 0..12 {
+            let ceiling = min(base * (1 << failures), cap);
+            let delays: Vec<Duration> = (0..2000)
+                .map(|_| get_delay(Site::Probe, base, failures))
+                .collect();
+            assert!(delays.iter().all(|delay| *delay <= ceiling));
+
+            // Full jitter spreads the delays over the whole range
+            let sum: Duration = delays.iter().sum();
+            let mean = sum.as_secs_f64() / delays.len() as f64 / ceiling.as_secs_f64();
+            assert!(mean > 0.4 && mean < 0.6, "mean {} of {:?}", mean, ceiling);
+            assert!(*delays.iter().min().unwrap() < ceiling / 10);
+            assert!(*delays.iter().max().unwrap() > ceiling * 9 / 10);
+        }
+    }
+
+    #[test]
+    fn test_gate() {
+        let max = 8;
+        set_max_handshakes(max);
+        let barrier = Arc::new(Barrier::new(WAITERS));
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+
+        let waiters: Vec<_> = (0..WAITERS)
+            .map(|_| {
+                let barrier = Arc::clone(&barrier);
+                let running = Arc::clone(&running);
+                let max_running = Arc::clone(&max_running);
+                thread::Builder::new()
+                    .stack_size(64 * 1024)
+                    .spawn(move || {
+                        barrier.wait();
+                        let _permit = enter().unwrap();
+                        let count = running.fetch_add(1, Ordering::SeqCst) + 1;
+                        let mut current = max_running.load(Ordering::SeqCst);
+                        while current < count {
+                            match max_running.compare_exchange(
+                                current,
+                                count,
+                                Ordering::SeqCst,
+                                Ordering::SeqCst,
+                            ) {
+                                Ok(_) => break,
+                                Err(value) => current = value,
+                            }
+                        }
+                        thread::sleep(Duration::from_millis(1));
+                        running.fetch_sub(1, Ordering::SeqCst);
+                    })
+                    .unwrap()
+            })
+            .collect();
+        for waiter in waiters {
+            waiter.join().unwrap();
+        }
+        set_max_handshakes(DEFAULT_MAX_HANDSHAKES);
+
+        // Every waiter enters in the end, but never more than the max at once
+        assert_eq!(max_running.load(Ordering::SeqCst), max);
+        assert_eq!(HANDSHAKES.load(Ordering::SeqCst), 0);
+    }
+}
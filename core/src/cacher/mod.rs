@@ -69,7 +69,7 @@ impl Cacher {
                 self.buffer = new_buffer;
                 self.head = 0;
             } else {
-                return Err(io::Error::new(io::ErrorKind::Other, "cache is full"));
+                return Err(io::Error::other("cache is full"));
             }
         }
 
@@ -98,9 +98,9 @@ impl Cacher {
             .checked_sub(self.sequence)
             .unwrap_or_else(|| u32::MAX - self.sequence + sequence) as usize;
 
-        if size <= MAX_U32_WINDOW_SIZE as usize {
+        if size <= MAX_U32_WINDOW_SIZE {
             self.sequence = sequence;
-            self.size = self.size.checked_sub(size).unwrap_or(0);
+            self.size = self.size.saturating_sub(size);
             if self.size == 0 {
                 self.head = 0;
             } else {
@@ -222,7 +222,7 @@ impl RandomCacher {
                 self.buffer = new_buffer;
                 self.head = 0;
             } else {
-                return Err(io::Error::new(io::ErrorKind::Other, "cache is full"));
+                return Err(io::Error::other("cache is full"));
             }
         }
 
@@ -275,7 +275,7 @@ impl RandomCacher {
                     end = max(end, key + value as u64);
                 }
 
-                if pop_keys.len() <= 0 {
+                if pop_keys.is_empty() {
                     break;
                 }
 
@@ -314,7 +314,7 @@ impl RandomCacher {
 
             // Shrink range sequence is possible
             if ((u32::MAX - self.sequence) as usize) < size {
-                let keys: Vec<_> = self.edges.keys().map(|x| *x).collect();
+                let keys: Vec<_> = self.edges.keys().copied().collect();
 
                 for key in keys {
                     let value = self.edges.remove(&key).unwrap();
@@ -347,6 +347,18 @@ impl RandomCacher {
         Ok(None)
     }
 
+    /// Moves the beginning of the cache back by the size of bytes returned by the last append,
+    /// which are expected again, while the bytes after them are kept.
+    pub fn rewind(&mut self, size: usize) {
+        self.sequence = self
+            .sequence
+            .checked_sub(size as u32)
+            .unwrap_or_else(|| u32::MAX - (size as u32 - self.sequence));
+        self.head =
+            (self.head + self.buffer.len() - (size % self.buffer.len())) % self.buffer.len();
+        self.size += size;
+    }
+
     /// Get the sequence of the cache.
     pub fn get_sequence(&self) -> u32 {
         self.sequence
@@ -365,3 +377,31 @@ impl RandomCacher {
         self.expandable
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rewind() {
+        let mut cache = RandomCacher::new(1000);
+        assert_eq!(cache.append(1020, b"after").unwrap(), None);
+        assert_eq!(
+            cache.append(1000, b"0123456789").unwrap(),
+            Some(b"0123456789".to_vec())
+        );
+
+        // The bytes returned are expected again, the bytes after the gap are kept
+        cache.rewind(10);
+        assert_eq!(cache.get_sequence(), 1000);
+        assert_eq!(
+            cache.append(1000, b"0123456789").unwrap(),
+            Some(b"0123456789".to_vec())
+        );
+        assert_eq!(
+            cache.append(1010, b"abcdefghij").unwrap(),
+            Some(b"abcdefghijafter".to_vec())
+        );
+        assert_eq!(cache.get_sequence(), 1025);
+    }
+}
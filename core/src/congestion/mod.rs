@@ -0,0 +1,238 @@
+//! Congestion control of the TCP payload forwarded to the source, with slow start, congestion
+//! avoidance and the reactions to losses of RFC 5681, and the retransmission timer of RFC 6298.
+
+use std::cmp::{max, min};
+use std::time::{Duration, Instant};
+
+/// Represents the initial window in segments, as in RFC 6928.
+pub const INITIAL_WINDOW: usize = 10;
+
+/// Represents the initial slow start threshold in bytes, the max window of RFC 7323, so slow start
+/// lasts until the first loss.
+const INITIAL_SSTHRESH: usize = 65535 << 14;
+
+/// Represents the initial retransmission timeout in milliseconds.
+const INITIAL_RTO: u64 = 1000;
+
+/// Represents the min retransmission timeout in milliseconds, as in Linux, since the source is
+/// usually on the local network.
+const MIN_RTO: u64 = 200;
+
+/// Represents the max retransmission timeout in milliseconds.
+const MAX_RTO: u64 = 60 * 1000;
+
+/// Represents the max distance of sequences in a window.
+const MAX_SEQUENCE_DISTANCE: u32 = 1 << 30;
+
+/// Represents the congestion state of the payload forwarded to the source in a TCP connection.
+#[derive(Clone, Debug)]
+pub struct Congestion {
+    mss: usize,
+    cwnd: usize,
+    ssthresh: usize,
+    /// Represents the bytes acknowledged in congestion avoidance not counted in the window yet.
+    acked: usize,
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+    /// Represents the time the retransmission timer expires, or `None` if nothing is in flight.
+    deadline: Option<Instant>,
+    /// Represents the end of the segment timed for a sample of the RTT and the time it was sent.
+    /// Retransmitted segments are never timed, as in Karn's algorithm.
+    sample: Option<(u32, Instant)>,
+}
+
+impl Congestion {
+    /// Creates a new `Congestion` of the given MSS.
+    pub fn new(mss: usize) -> Congestion {
+        let mss = max(mss, 1);
+        Congestion {
+            mss,
+            cwnd: INITIAL_WINDOW * mss,
+            ssthresh: INITIAL_SSTHRESH,
+            acked: 0,
+            srtt: None,
+            rttvar: Duration::from_millis(0),
+            rto: Duration::from_millis(INITIAL_RTO),
+            deadline: None,
+            sample: None,
+        }
+    }
+
+    /// Get the congestion window in bytes.
+    pub fn get_cwnd(&self) -> usize {
+        self.cwnd
+    }
+
+    /// Get the slow start threshold in bytes.
+    pub fn get_ssthresh(&self) -> usize {
+        self.ssthresh
+    }
+
+    /// Get the retransmission timeout.
+    pub fn get_rto(&self) -> Duration {
+        self.rto
+    }
+
+    /// Records new payload sent up to the given sequence, which starts the timer if it is not
+    /// running, and is timed if no segment is.
+    pub fn on_send(&mut self, sequence_tail: u32, now: Instant) {
+        if self.deadline.is_none() {
+            self.deadline = Some(now + self.rto);
+        }
+        if self.sample.is_none() {
+            self.sample = Some((sequence_tail, now));
+        }
+    }
+
+    /// Records the source acknowledging new payload to the given sequence, which grows the window
+    /// and restarts the timer, or stops it if nothing is in flight.
+    pub fn on_ack(&mut self, acked: usize, acknowledgement: u32, is_flight: bool, now: Instant) {
+        if acked == 0 {
+            return;
+        }
+
+        // Slow start, or congestion avoidance growing a segment per window
+        if self.cwnd < self.ssthresh {
+            self.cwnd += min(acked, self.mss);
+        } else {
+            self.acked += acked;
+            if self.acked >= self.cwnd {
+                self.acked -= self.cwnd;
+                self.cwnd += self.mss;
+            }
+        }
+
+        if let Some((sequence_tail, instant)) = self.sample {
+            if acknowledgement.wrapping_sub(sequence_tail) < MAX_SEQUENCE_DISTANCE {
+                self.sample = None;
+                self.update_rto(now.saturating_duration_since(instant));
+            }
+        }
+
+        self.deadline = if is_flight {
+            Some(now + self.rto)
+        } else {
+            None
+        };
+    }
+
+    /// Updates the timeout by a sample of the RTT, as in RFC 6298.
+    fn update_rto(&mut self, rtt: Duration) {
+        match self.srtt {
+            Some(srtt) => {
+                let delta = srtt.abs_diff(rtt);
+                self.rttvar = (self.rttvar * 3 + delta) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+            None => {
+                self.rttvar = rtt / 2;
+                self.srtt = Some(rtt);
+            }
+        }
+        let rto = self.srtt.unwrap() + max(self.rttvar * 4, Duration::from_millis(1));
+        self.rto = min(
+            max(rto, Duration::from_millis(MIN_RTO)),
+            Duration::from_millis(MAX_RTO),
+        );
+    }
+
+    /// Records a fast retransmission of the payload in flight, which halves the window.
+    pub fn on_fast_retransmit(&mut self, flight: usize) {
+        self.ssthresh = max(flight / 2, 2 * self.mss);
+        self.cwnd = self.ssthresh;
+        self.acked = 0;
+        self.sample = None;
+    }
+
+    /// Returns if the timer expired.
+    pub fn is_timed_out(&self, now: Instant) -> bool {
+        match self.deadline {
+            Some(deadline) => now >= deadline,
+            None => false,
+        }
+    }
+
+    /// Stops the timer, for nothing is in flight.
+    pub fn stop(&mut self) {
+        self.deadline = None;
+    }
+
+    /// Records a retransmission of the payload in flight for the timeout, which restarts from a
+    /// segment and backs off the timer.
+    pub fn on_timeout(&mut self, flight: usize, now: Instant) {
+        self.ssthresh = max(flight / 2, 2 * self.mss);
+        self.cwnd = self.mss;
+        self.acked = 0;
+        self.sample = None;
+        self.rto = min(self.rto * 2, Duration::from_millis(MAX_RTO));
+        self.deadline = Some(now + self.rto);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MSS: usize = 1000;
+
+    #[test]
+    fn test_window() {
+        let now = Instant::now();
+        let mut congestion = Congestion::new(MSS);
+        assert_eq!(congestion.get_cwnd(), INITIAL_WINDOW * MSS);
+
+        // Slow start grows a segment per acknowledgement
+        congestion.on_send(3000, now);
+        congestion.on_ack(1000, 1000, true, now);
+        congestion.on_ack(2000, 3000, false, now);
+        assert_eq!(congestion.get_cwnd(), (INITIAL_WINDOW + 2) * MSS);
+
+        // A fast retransmission halves the window in flight, which grows a segment per window
+        congestion.on_fast_retransmit(8000);
+        assert_eq!(congestion.get_ssthresh(), 4000);
+        assert_eq!(congestion.get_cwnd(), 4000);
+        for _ in 0..3 {
+            congestion.on_ack(1000, 0, true, now);
+        }
+        assert_eq!(congestion.get_cwnd(), 4000);
+        congestion.on_ack(1000, 0, true, now);
+        assert_eq!(congestion.get_cwnd(), 5000);
+
+        // A timeout restarts from a segment
+        congestion.on_timeout(1000, now);
+        assert_eq!(congestion.get_ssthresh(), 2 * MSS);
+        assert_eq!(congestion.get_cwnd(), MSS);
+    }
+
+    #[test]
+    fn test_timer() {
+        let now = Instant::now();
+        let mut congestion = Congestion::new(MSS);
+        assert!(!congestion.is_timed_out(now + Duration::from_secs(3600)));
+
+        congestion.on_send(1000, now);
+        let rto = Duration::from_millis(INITIAL_RTO);
+        assert!(!congestion.is_timed_out(now + rto / 2));
+        assert!(congestion.is_timed_out(now + rto));
+
+        // The timeout backs off until an acknowledgement
+        congestion.on_timeout(1000, now + rto);
+        assert_eq!(congestion.get_rto(), rto * 2);
+        assert!(!congestion.is_timed_out(now + rto * 2));
+        assert!(congestion.is_timed_out(now + rto * 3));
+        // The retransmitted segment is never timed
+        congestion.on_ack(1000, 1000, false, now + rto * 3);
+        assert_eq!(congestion.get_rto(), rto * 2);
+        assert!(!congestion.is_timed_out(now + rto * 10));
+
+        // The first sample sets the timeout, which is never below the min
+        let later = now + rto * 4;
+        congestion.on_send(2000, later);
+        congestion.on_ack(1000, 2000, false, later + Duration::from_millis(10));
+        assert_eq!(congestion.get_rto(), Duration::from_millis(MIN_RTO));
+        congestion.on_send(3000, later);
+        congestion.on_ack(1000, 3000, false, later + Duration::from_millis(400));
+        assert!(congestion.get_rto() > Duration::from_millis(MIN_RTO));
+    }
+}
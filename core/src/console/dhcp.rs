@@ -0,0 +1,483 @@
+use log::debug;
+use std::cmp::min;
+use std::net::Ipv4Addr;
+
+use super::get_ip_addr;
+
+/// Represents the port of DHCP servers.
+pub const SERVER_PORT: u16 = 67;
+
+/// Represents the port of DHCP clients.
+pub const CLIENT_PORT: u16 = 68;
+
+/// Represents the default lease time in seconds.
+pub const DEFAULT_LEASE: u32 = 86400;
+
+/// Represents the size of the fixed part of a BOOTP message.
+const BOOTP_SIZE: usize = 236;
+
+/// Represents the min size of a BOOTP message, some clients drop shorter replies.
+const MIN_MESSAGE_SIZE: usize = 300;
+
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+const BOOT_REQUEST: u8 = 1;
+const BOOT_REPLY: u8 = 2;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS: u8 = 6;
+const OPTION_VENDOR_SPECIFIC: u8 = 43;
+const OPTION_REQUESTED_ADDR: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_VENDOR_CLASS: u8 = 60;
+const OPTION_END: u8 = 255;
+
+const DISCOVER: u8 = 1;
+const OFFER: u8 = 2;
+const REQUEST: u8 = 3;
+const ACK: u8 = 5;
+const NAK: u8 = 6;
+const INFORM: u8 = 8;
+
+/// Represents the DHCP responder, which leases the address of the source to the first device
+/// asking for it. The vendor-specific information (option 43) of the first vendor class (option
+/// 60) the device's class starts with is added to the replies, as console network tests check.
+#[derive(Debug)]
+pub struct Dhcp {
+    server: Ipv4Addr,
+    offer: Ipv4Addr,
+    mask: Ipv4Addr,
+    dns: Option<Ipv4Addr>,
+    lease: u32,
+    vendors: Vec<(String, Vec<u8>)>,
+    client: Option<[u8; 6]>,
+    answered: usize,
+}
+
+impl Dhcp {
+    /// Creates a new `Dhcp` of the gateway leasing the address of the source, in the smallest
+    /// network of a prefix of at most 24 containing both.
+    pub fn new(server: Ipv4Addr, offer: Ipv4Addr) -> Dhcp {
+        let prefix = min((u32::from(server) ^ u32::from(offer)).leading_zeros(), 24);
+
+        Dhcp {
+            server,
+            offer,
+            mask: Ipv4Addr::from((!0u32).checked_shl(32 - prefix).unwrap_or(0)),
+            dns: None,
+            lease: DEFAULT_LEASE,
+            vendors: Vec::new(),
+            client: None,
+            answered: 0,
+        }
+    }
+
+    /// Sets the DNS server given to the device.
+    pub fn set_dns(&mut self, dns: Option<Ipv4Addr>) {
+        self.dns = dns;
+    }
+
+    /// Sets the lease time in seconds.
+    pub fn set_lease(&mut self, lease: u32) {
+        self.lease = lease;
+    }
+
+    /// Adds the vendor-specific information answering devices of the vendor class prefix.
+    pub fn add_vendor(&mut self, class: String, information: Vec<u8>) {
+        self.vendors.push((class, information));
+    }
+
+    /// Get the subnet mask given to the device.
+    pub fn get_mask(&self) -> Ipv4Addr {
+        self.mask
+    }
+
+    /// Get the number of messages answered.
+    pub fn get_answered(&self) -> usize {
+        self.answered
+    }
+
+    /// Handles a DHCP message to the server, returns the reply to broadcast, if any.
+    pub fn handle(&mut self, message: &[u8]) -> Option<Vec<u8>> {
+        if message.len() < BOOTP_SIZE + MAGIC_COOKIE.len()
+            || message[0] != BOOT_REQUEST
+            || message[1] != 1
+            || message[2] != 6
+            || message[BOOTP_SIZE..BOOTP_SIZE + 4] != MAGIC_COOKIE
+        {
+            return None;
+        }
+        let mut hardware_addr = [0u8; 6];
+        hardware_addr.copy_from_slice(&message[28..34]);
+        let ciaddr = get_ip_addr(&message[12..]);
+
+        let options = parse_options(&message[BOOTP_SIZE + 4..]);
+        let get_option = |code: u8| {
+            options
+                .iter()
+                .find(|(c, _)| *c == code)
+                .map(|(_, value)| value.as_slice())
+        };
+        let message_type = match get_option(OPTION_MESSAGE_TYPE) {
+            Some(value) if value.len() == 1 => value[0],
+            _ => return None,
+        };
+        let class = get_option(OPTION_VENDOR_CLASS).map(|value| String::from_utf8_lossy(value));
+
+        // Only the first device is leased an address, others keep looking for their own server
+        let reply_type = match message_type {
+            DISCOVER => {
+                if !self.is_client(hardware_addr) {
+                    return None;
+                }
+                OFFER
+            }
+            REQUEST => {
+                if let Some(server) = get_option(OPTION_SERVER_ID) {
+                    if server.len() != 4 || get_ip_addr(server) != self.server {
+                        return None;
+                    }
+                }
+                if !self.is_client(hardware_addr) {
+                    return None;
+                }
+                let requested = match get_option(OPTION_REQUESTED_ADDR) {
+                    Some(value) if value.len() == 4 => get_ip_addr(value),
+                    _ => ciaddr,
+                };
+                if requested == self.offer {
+                    ACK
+                } else {
+                    NAK
+                }
+            }
+            INFORM => {
+                if ciaddr != self.offer {
+                    return None;
+                }
+                ACK
+            }
+            _ => return None,
+        };
+        debug!(
+            "DHCP: answer {} of {} with {}",
+            message_type,
+            format_hardware_addr(hardware_addr),
+            reply_type
+        );
+        self.answered = self.answered.saturating_add(1);
+
+        Some(self.new_reply(
+            message,
+            message_type,
+            reply_type,
+            class.as_ref().map(|class| class.as_ref()),
+        ))
+    }
+
+    fn is_client(&mut self, hardware_addr: [u8; 6]) -> bool {
+        match self.client {
+            Some(client) => client == hardware_addr,
+            None => {
+                self.client = Some(hardware_addr);
+
+                true
+            }
+        }
+    }
+
+    fn new_reply(
+        &self,
+        request: &[u8],
+        request_type: u8,
+        reply_type: u8,
+        class: Option<&str>,
+    ) -> Vec<u8> {
+        let mut reply = vec![0u8; BOOTP_SIZE];
+        reply[0] = BOOT_REPLY;
+        reply[1] = 1;
+        reply[2] = 6;
+        // Transaction ID
+        reply[4..8].copy_from_slice(&request[4..8]);
+        // Broadcast flag
+        reply[10..12].copy_from_slice(&request[10..12]);
+        if request_type == INFORM {
+            reply[12..16].copy_from_slice(&request[12..16]);
+        } else if reply_type != NAK {
+            reply[16..20].copy_from_slice(&self.offer.octets());
+        }
+        // Relay agent and client hardware address
+        reply[24..44].copy_from_slice(&request[24..44]);
+        reply.extend_from_slice(&MAGIC_COOKIE);
+
+        push_option(&mut reply, OPTION_MESSAGE_TYPE, &[reply_type]);
+        push_option(&mut reply, OPTION_SERVER_ID, &self.server.octets());
+        if reply_type != NAK {
+            if request_type != INFORM {
+                push_option(&mut reply, OPTION_LEASE_TIME, &self.lease.to_be_bytes());
+            }
+            push_option(&mut reply, OPTION_SUBNET_MASK, &self.mask.octets());
+            push_option(&mut reply, OPTION_ROUTER, &self.server.octets());
+            if let Some(dns) = self.dns {
+                push_option(&mut reply, OPTION_DNS, &dns.octets());
+            }
+            if let Some(class) = class {
+                if let Some((_, information)) = self
+                    .vendors
+                    .iter()
+                    .find(|(prefix, _)| class.starts_with(prefix.as_str()))
+                {
+                    for chunk in information.chunks(u8::MAX as usize) {
+                        push_option(&mut reply, OPTION_VENDOR_SPECIFIC, chunk);
+                    }
+                }
+            }
+        }
+        reply.push(OPTION_END);
+        if reply.len() < MIN_MESSAGE_SIZE {
+            reply.resize(MIN_MESSAGE_SIZE, OPTION_PAD);
+        }
+
+        reply
+    }
+}
+
+/// Parses DHCP options, concatenating the values of an option given in several parts.
+fn parse_options(buffer: &[u8]) -> Vec<(u8, Vec<u8>)> {
+    let mut options: Vec<(u8, Vec<u8>)> = Vec::new();
+
+    let mut n = 0;
+    while n < buffer.len() {
+        let code = buffer[n];
+        match code {
+            OPTION_PAD => {
+                n += 1;
+                continue;
+            }
+            OPTION_END => break,
+            _ => {}
+        }
+        if n + 1 >= buffer.len() {
+            break;
+        }
+        let length = buffer[n + 1] as usize;
+        if n + 2 + length > buffer.len() {
+            break;
+        }
+        let value = &buffer[n + 2..n + 2 + length];
+        match options.iter_mut().find(|(c, _)| *c == code) {
+            Some((_, ref mut v)) => v.extend_from_slice(value),
+            None => options.push((code, value.to_vec())),
+        }
+
+        n += 2 + length;
+    }
+
+    options
+}
+
+fn push_option(buffer: &mut Vec<u8>, code: u8, value: &[u8]) {
+    buffer.push(code);
+    buffer.push(value.len() as u8);
+    buffer.extend_from_slice(value);
+}
+
+fn format_hardware_addr(hardware_addr: [u8; 6]) -> String {
+    hardware_addr
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVER: Ipv4Addr = Ipv4Addr::new(10, 6, 0, 1);
+    const OFFER_ADDR: Ipv4Addr = Ipv4Addr::new(10, 6, 0, 2);
+    const CONSOLE: [u8; 6] = [0x00, 0xd9, 0xd1, 0x12, 0x34, 0x56];
+
+    /// Creates a DHCP request as sent by a console, with the options after the message type.
+    fn new_request(message_type: u8, hardware_addr: [u8; 6], options: &[u8]) -> Vec<u8> {
+        let mut message = vec![0u8; BOOTP_SIZE];
+        message[0] = BOOT_REQUEST;
+        message[1] = 1;
+        message[2] = 6;
+        message[4..8].copy_from_slice(&[0x3d, 0x1e, 0x6a, 0x01]);
+        // Broadcast flag
+        message[10] = 0x80;
+        message[28..34].copy_from_slice(&hardware_addr);
+        message.extend_from_slice(&MAGIC_COOKIE);
+        message.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, message_type]);
+        message.extend_from_slice(options);
+        message.extend_from_slice(&[OPTION_END, 0, 0, 0]);
+
+        message
+    }
+
+    fn get_options(reply: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        assert_eq!(reply[BOOTP_SIZE..BOOTP_SIZE + 4], MAGIC_COOKIE);
+        parse_options(&reply[BOOTP_SIZE + 4..])
+    }
+
+    fn get_option(options: &[(u8, Vec<u8>)], code: u8) -> Option<Vec<u8>> {
+        options
+            .iter()
+            .find(|(c, _)| *c == code)
+            .map(|(_, v)| v.clone())
+    }
+
+    fn new_dhcp() -> Dhcp {
+        let mut dhcp = Dhcp::new(SERVER, OFFER_ADDR);
+        dhcp.add_vendor(
+            String::from("PS4"),
+            vec![0x01, 0x04, 0xde, 0xad, 0xbe, 0xef],
+        );
+        dhcp
+    }
+
+    #[test]
+    fn test_mask() {
+        assert_eq!(new_dhcp().get_mask(), Ipv4Addr::new(255, 255, 255, 0));
+        let dhcp = Dhcp::new(Ipv4Addr::new(10, 6, 0, 1), Ipv4Addr::new(10, 6, 1, 2));
+        assert_eq!(dhcp.get_mask(), Ipv4Addr::new(255, 255, 254, 0));
+    }
+
+    #[test]
+    fn test_discover_with_vendor_class() {
+        let mut dhcp = new_dhcp();
+        // Vendor class "PS4 Console" split into two options, and a parameter request list
+        let options = [
+            OPTION_VENDOR_CLASS,
+            4,
+            b'P',
+            b'S',
+            b'4',
+            b' ',
+            OPTION_VENDOR_CLASS,
+            7,
+            b'C',
+            b'o',
+            b'n',
+            b's',
+            b'o',
+            b'l',
+            b'e',
+            55,
+            4,
+            1,
+            3,
+            6,
+            43,
+        ];
+        let discover = new_request(DISCOVER, CONSOLE, &options);
+        let offer = dhcp.handle(&discover).unwrap();
+
+        assert_eq!(offer.len(), MIN_MESSAGE_SIZE);
+        assert_eq!(offer[0], BOOT_REPLY);
+        assert_eq!(offer[4..8], discover[4..8]);
+        assert_eq!(offer[10], 0x80);
+        assert_eq!(get_ip_addr(&offer[16..]), OFFER_ADDR);
+        assert_eq!(offer[28..34], CONSOLE);
+        let options = get_options(&offer);
+        assert_eq!(get_option(&options, OPTION_MESSAGE_TYPE), Some(vec![OFFER]));
+        assert_eq!(
+            get_option(&options, OPTION_SERVER_ID),
+            Some(SERVER.octets().to_vec())
+        );
+        assert_eq!(
+            get_option(&options, OPTION_ROUTER),
+            Some(SERVER.octets().to_vec())
+        );
+        assert_eq!(
+            get_option(&options, OPTION_VENDOR_SPECIFIC),
+            Some(vec![0x01, 0x04, 0xde, 0xad, 0xbe, 0xef])
+        );
+        assert_eq!(get_option(&options, OPTION_DNS), None);
+    }
+
+    #[test]
+    fn test_request() {
+        let mut dhcp = new_dhcp();
+        dhcp.set_dns(Some(SERVER));
+        let mut options = vec![OPTION_REQUESTED_ADDR, 4];
+        options.extend_from_slice(&OFFER_ADDR.octets());
+        options.extend_from_slice(&[OPTION_SERVER_ID, 4]);
+        options.extend_from_slice(&SERVER.octets());
+        let ack = dhcp
+            .handle(&new_request(REQUEST, CONSOLE, &options))
+            .unwrap();
+        let options = get_options(&ack);
+        assert_eq!(get_option(&options, OPTION_MESSAGE_TYPE), Some(vec![ACK]));
+        assert_eq!(
+            get_option(&options, OPTION_LEASE_TIME),
+            Some(DEFAULT_LEASE.to_be_bytes().to_vec())
+        );
+        assert_eq!(
+            get_option(&options, OPTION_DNS),
+            Some(SERVER.octets().to_vec())
+        );
+        // No vendor class, no vendor-specific information
+        assert_eq!(get_option(&options, OPTION_VENDOR_SPECIFIC), None);
+
+        // Another address is refused
+        let mut options = vec![OPTION_REQUESTED_ADDR, 4];
+        options.extend_from_slice(&[10, 6, 0, 3]);
+        let nak = dhcp
+            .handle(&new_request(REQUEST, CONSOLE, &options))
+            .unwrap();
+        assert_eq!(get_ip_addr(&nak[16..]), Ipv4Addr::UNSPECIFIED);
+        let options = get_options(&nak);
+        assert_eq!(get_option(&options, OPTION_MESSAGE_TYPE), Some(vec![NAK]));
+        assert_eq!(get_option(&options, OPTION_SUBNET_MASK), None);
+
+        // Requests to other servers are left to them
+        let mut options = vec![OPTION_SERVER_ID, 4];
+        options.extend_from_slice(&[10, 6, 0, 254]);
+        assert_eq!(dhcp.handle(&new_request(REQUEST, CONSOLE, &options)), None);
+    }
+
+    #[test]
+    fn test_first_client() {
+        let mut dhcp = new_dhcp();
+        assert!(dhcp.handle(&new_request(DISCOVER, CONSOLE, &[])).is_some());
+        let other = [0x02, 0, 0, 0, 0, 1];
+        assert_eq!(dhcp.handle(&new_request(DISCOVER, other, &[])), None);
+        assert_eq!(dhcp.get_answered(), 1);
+    }
+
+    #[test]
+    fn test_inform() {
+        let mut dhcp = new_dhcp();
+        let mut inform = new_request(INFORM, CONSOLE, &[OPTION_VENDOR_CLASS, 3, b'P', b'S', b'4']);
+        assert_eq!(dhcp.handle(&inform), None);
+
+        inform[12..16].copy_from_slice(&OFFER_ADDR.octets());
+        let ack = dhcp.handle(&inform).unwrap();
+        assert_eq!(get_ip_addr(&ack[12..]), OFFER_ADDR);
+        assert_eq!(get_ip_addr(&ack[16..]), Ipv4Addr::UNSPECIFIED);
+        let options = get_options(&ack);
+        assert_eq!(get_option(&options, OPTION_MESSAGE_TYPE), Some(vec![ACK]));
+        assert_eq!(get_option(&options, OPTION_LEASE_TIME), None);
+        assert!(get_option(&options, OPTION_VENDOR_SPECIFIC).is_some());
+    }
+
+    #[test]
+    fn test_invalid() {
+        let mut dhcp = new_dhcp();
+        let discover = new_request(DISCOVER, CONSOLE, &[]);
+        assert_eq!(dhcp.handle(&discover[..BOOTP_SIZE + 2]), None);
+        let mut reply = discover.clone();
+        reply[0] = BOOT_REPLY;
+        assert_eq!(dhcp.handle(&reply), None);
+        // Truncated options
+        let mut truncated = discover[..BOOTP_SIZE + 4].to_vec();
+        truncated.extend_from_slice(&[OPTION_MESSAGE_TYPE, 4, DISCOVER]);
+        assert_eq!(dhcp.handle(&truncated), None);
+    }
+}
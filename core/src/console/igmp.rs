@@ -0,0 +1,309 @@
+use log::debug;
+use std::collections::HashSet;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+use super::get_ip_addr;
+use crate::packet::builder::checksum;
+
+/// Represents the all hosts group, the destination of general queries.
+pub const ALL_HOSTS: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 1);
+
+/// Represents the all routers group, which the gateway is a member of.
+pub const ALL_ROUTERS: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 2);
+
+/// Represents the interval between general queries while any group has members.
+const QUERY_INTERVAL: u64 = 125;
+
+/// Represents the max response time of general queries in tenths of a second.
+const QUERY_RESPONSE_TIME: u8 = 100;
+
+/// Represents the max response time of group-specific queries in tenths of a second.
+const LAST_MEMBER_RESPONSE_TIME: u8 = 10;
+
+/// Represents the size of an IGMPv1 or IGMPv2 message.
+const MESSAGE_SIZE: usize = 8;
+
+const MEMBERSHIP_QUERY: u8 = 0x11;
+const V1_MEMBERSHIP_REPORT: u8 = 0x12;
+const V2_MEMBERSHIP_REPORT: u8 = 0x16;
+const LEAVE_GROUP: u8 = 0x17;
+const V3_MEMBERSHIP_REPORT: u8 = 0x22;
+
+/// Represents the IGMPv3 group record types reporting the group is left, the include mode with
+/// no source, and joined otherwise.
+const MODE_IS_INCLUDE: u8 = 1;
+const CHANGE_TO_INCLUDE_MODE: u8 = 3;
+const BLOCK_OLD_SOURCES: u8 = 6;
+
+/// Represents the IGMP responder, which acts as the IGMPv2 querier of the link of the source.
+/// New memberships are answered with a general query, leaves with a group-specific query, and
+/// queries of the source with a report of the all routers group, so a multicast test sees a
+/// gateway taking part in IGMP.
+#[derive(Debug, Default)]
+pub struct Igmp {
+    groups: HashSet<Ipv4Addr>,
+    last_query: Option<Instant>,
+    answered: usize,
+}
+
+impl Igmp {
+    /// Creates a new `Igmp`.
+    pub fn new() -> Igmp {
+        Igmp::default()
+    }
+
+    /// Handles an IGMP message from the source, returns the destination and the message
+    /// answering it, if any.
+    pub fn handle(&mut self, message: &[u8]) -> Option<(Ipv4Addr, Vec<u8>)> {
+        if message.len() < MESSAGE_SIZE || checksum(message, 1) != get_checksum(message) {
+            return None;
+        }
+
+        let answer = match message[0] {
+            MEMBERSHIP_QUERY => Some((
+                ALL_ROUTERS,
+                new_message(V2_MEMBERSHIP_REPORT, 0, ALL_ROUTERS),
+            )),
+            V1_MEMBERSHIP_REPORT | V2_MEMBERSHIP_REPORT => {
+                let group = get_ip_addr(&message[4..]);
+                if self.join(group) {
+                    Some(self.new_general_query())
+                } else {
+                    None
+                }
+            }
+            LEAVE_GROUP => {
+                let group = get_ip_addr(&message[4..]);
+                if self.groups.remove(&group) {
+                    debug!("IGMP: leave {}", group);
+                    Some((
+                        group,
+                        new_message(MEMBERSHIP_QUERY, LAST_MEMBER_RESPONSE_TIME, group),
+                    ))
+                } else {
+                    None
+                }
+            }
+            V3_MEMBERSHIP_REPORT => self.handle_v3_report(message),
+            _ => None,
+        };
+        if answer.is_some() {
+            self.answered = self.answered.saturating_add(1);
+        }
+
+        answer
+    }
+
+    fn handle_v3_report(&mut self, message: &[u8]) -> Option<(Ipv4Addr, Vec<u8>)> {
+        let records = u16::from_be_bytes([message[6], message[7]]) as usize;
+        let mut is_joined = false;
+        let mut left = None;
+
+        let mut n = MESSAGE_SIZE;
+        for _ in 0..records {
+            if n + 8 > message.len() {
+                return None;
+            }
+            let record_type = message[n];
+            let aux_length = message[n + 1] as usize * 4;
+            let sources = u16::from_be_bytes([message[n + 2], message[n + 3]]) as usize;
+            let group = get_ip_addr(&message[n + 4..]);
+
+            let is_left = match record_type {
+                MODE_IS_INCLUDE | CHANGE_TO_INCLUDE_MODE => sources == 0,
+                _ => false,
+            };
+            if is_left {
+                if self.groups.remove(&group) {
+                    debug!("IGMP: leave {}", group);
+                    left = Some(group);
+                }
+            } else if record_type != BLOCK_OLD_SOURCES && self.join(group) {
+                is_joined = true;
+            }
+
+            n += 8 + sources * 4 + aux_length;
+        }
+
+        if is_joined {
+            Some(self.new_general_query())
+        } else {
+            left.map(|group| {
+                (
+                    group,
+                    new_message(MEMBERSHIP_QUERY, LAST_MEMBER_RESPONSE_TIME, group),
+                )
+            })
+        }
+    }
+
+    /// Returns if the group is joined for the first time.
+    fn join(&mut self, group: Ipv4Addr) -> bool {
+        if !group.is_multicast() || group == ALL_HOSTS {
+            return false;
+        }
+        let is_new = self.groups.insert(group);
+        if is_new {
+            debug!("IGMP: join {}", group);
+        }
+
+        is_new
+    }
+
+    fn new_general_query(&mut self) -> (Ipv4Addr, Vec<u8>) {
+        self.last_query = Some(Instant::now());
+
+        (
+            ALL_HOSTS,
+            new_message(MEMBERSHIP_QUERY, QUERY_RESPONSE_TIME, Ipv4Addr::UNSPECIFIED),
+        )
+    }
+
+    /// Returns the general query due, if any group has members and the last query is older than
+    /// the query interval.
+    pub fn poll(&mut self) -> Option<(Ipv4Addr, Vec<u8>)> {
+        if self.groups.is_empty() {
+            return None;
+        }
+        match self.last_query {
+            Some(last_query) if last_query.elapsed() < Duration::from_secs(QUERY_INTERVAL) => None,
+            _ => Some(self.new_general_query()),
+        }
+    }
+
+    /// Get the number of groups the source is a member of.
+    pub fn get_groups(&self) -> usize {
+        self.groups.len()
+    }
+
+    /// Get the number of messages answered.
+    pub fn get_answered(&self) -> usize {
+        self.answered
+    }
+}
+
+fn get_checksum(message: &[u8]) -> u16 {
+    u16::from_be_bytes([message[2], message[3]])
+}
+
+/// Creates an IGMPv2 message.
+fn new_message(t: u8, max_response_time: u8, group: Ipv4Addr) -> Vec<u8> {
+    let mut message = vec![t, max_response_time, 0, 0];
+    message.extend_from_slice(&group.octets());
+    let checksum = checksum(&message, 1);
+    message[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+    message
+}
+
+/// Get the hardware address of the multicast group.
+pub fn get_hardware_addr(group: Ipv4Addr) -> [u8; 6] {
+    let octets = group.octets();
+
+    [0x01, 0x00, 0x5e, octets[1] & 0x7f, octets[2], octets[3]]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // IGMPv2 report of 239.255.255.250 (SSDP) as sent by a console joining UPnP discovery
+    const V2_REPORT_SSDP: [u8; 8] = [0x16, 0x00, 0xfa, 0x04, 0xef, 0xff, 0xff, 0xfa];
+    // IGMPv2 leave of 239.255.255.250, which is sent to all routers
+    const V2_LEAVE_SSDP: [u8; 8] = [0x17, 0x00, 0xf9, 0x04, 0xef, 0xff, 0xff, 0xfa];
+    // IGMPv3 report changing to exclude mode of 239.255.255.250 and 224.0.0.251 (mDNS)
+    const V3_REPORT: [u8; 24] = [
+        0x22, 0x00, 0x05, 0x07, 0x00, 0x00, 0x00, 0x02, 0x04, 0x00, 0x00, 0x00, 0xef, 0xff, 0xff,
+        0xfa, 0x04, 0x00, 0x00, 0x00, 0xe0, 0x00, 0x00, 0xfb,
+    ];
+    // IGMPv2 general query
+    const GENERAL_QUERY: [u8; 8] = [0x11, 0x64, 0xee, 0x9b, 0x00, 0x00, 0x00, 0x00];
+
+    #[test]
+    fn test_probes_checksum() {
+        for probe in [&V2_REPORT_SSDP[..], &V2_LEAVE_SSDP[..], &V3_REPORT[..]].iter() {
+            assert_eq!(checksum(probe, 1), get_checksum(probe));
+        }
+        assert_eq!(
+            new_message(MEMBERSHIP_QUERY, QUERY_RESPONSE_TIME, Ipv4Addr::UNSPECIFIED),
+            GENERAL_QUERY.to_vec()
+        );
+    }
+
+    #[test]
+    fn test_report() {
+        let mut igmp = Igmp::new();
+        assert_eq!(
+            igmp.handle(&V2_REPORT_SSDP),
+            Some((ALL_HOSTS, GENERAL_QUERY.to_vec()))
+        );
+        assert_eq!(igmp.get_groups(), 1);
+
+        // Reports answering the query are not answered again
+        assert_eq!(igmp.handle(&V2_REPORT_SSDP), None);
+        assert_eq!(igmp.poll(), None);
+    }
+
+    #[test]
+    fn test_v3_report() {
+        let mut igmp = Igmp::new();
+        assert_eq!(
+            igmp.handle(&V3_REPORT),
+            Some((ALL_HOSTS, GENERAL_QUERY.to_vec()))
+        );
+        assert_eq!(igmp.get_groups(), 2);
+
+        // Truncated records are ignored
+        assert_eq!(igmp.handle(&V3_REPORT[..20]), None);
+    }
+
+    #[test]
+    fn test_leave() {
+        let mut igmp = Igmp::new();
+        let group = Ipv4Addr::new(239, 255, 255, 250);
+
+        // Leaves of groups without members are not answered
+        assert_eq!(igmp.handle(&V2_LEAVE_SSDP), None);
+
+        igmp.handle(&V2_REPORT_SSDP);
+        assert_eq!(
+            igmp.handle(&V2_LEAVE_SSDP),
+            Some((
+                group,
+                new_message(MEMBERSHIP_QUERY, LAST_MEMBER_RESPONSE_TIME, group)
+            ))
+        );
+        assert_eq!(igmp.get_groups(), 0);
+    }
+
+    #[test]
+    fn test_query() {
+        let mut igmp = Igmp::new();
+        let (dst, report) = igmp.handle(&GENERAL_QUERY).unwrap();
+        assert_eq!(dst, ALL_ROUTERS);
+        assert_eq!(report, vec![0x16, 0x00, 0x09, 0xfd, 0xe0, 0x00, 0x00, 0x02]);
+    }
+
+    #[test]
+    fn test_invalid() {
+        let mut igmp = Igmp::new();
+        let mut report = V2_REPORT_SSDP;
+        report[7] = 0xfb;
+        assert_eq!(igmp.handle(&report), None);
+        assert_eq!(igmp.handle(&V2_REPORT_SSDP[..4]), None);
+        assert_eq!(igmp.get_answered(), 0);
+    }
+
+    #[test]
+    fn test_hardware_addr() {
+        assert_eq!(
+            get_hardware_addr(Ipv4Addr::new(239, 255, 255, 250)),
+            [0x01, 0x00, 0x5e, 0x7f, 0xff, 0xfa]
+        );
+        assert_eq!(
+            get_hardware_addr(ALL_HOSTS),
+            [0x01, 0x00, 0x5e, 0x00, 0x00, 0x01]
+        );
+    }
+}
@@ -0,0 +1,302 @@
+//! Responders emulating the gateway behaviors network tests of game consoles expect.
+
+use std::fmt::{self, Display, Formatter};
+use std::net::Ipv4Addr;
+
+pub mod dhcp;
+pub mod igmp;
+pub mod probe;
+
+pub use dhcp::Dhcp;
+pub use igmp::Igmp;
+pub use probe::{Probe, Segment};
+
+/// Represents the TTL of replies to pings to the gateway of the presets.
+pub const DEFAULT_PING_TTL: u8 = 64;
+
+/// Represents a responder of the compatibility layer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Responder {
+    /// Answers pings to the gateway, see `--ping-ttl`.
+    Ping,
+    /// Answers IGMP membership reports and queries as a multicast router.
+    Igmp,
+    /// Answers DHCP requests with the source address and vendor-specific information.
+    Dhcp,
+    /// Answers HTTP requests to the gateway with a canned response.
+    Http,
+}
+
+impl Responder {
+    /// Creates a `Responder` by its name.
+    pub fn from_name(name: &str) -> Option<Responder> {
+        match name {
+            "ping" => Some(Responder::Ping),
+            "igmp" => Some(Responder::Igmp),
+            "dhcp" => Some(Responder::Dhcp),
+            "http" => Some(Responder::Http),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Responder {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Responder::Ping => write!(f, "ping"),
+            Responder::Igmp => write!(f, "igmp"),
+            Responder::Dhcp => write!(f, "dhcp"),
+            Responder::Http => write!(f, "http"),
+        }
+    }
+}
+
+/// Represents a preset of responders for the network test of a console family.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Preset {
+    /// Xbox consoles, which are configured statically and probe the gateway over HTTP.
+    Xbox,
+    /// PlayStation consoles, which also request their address over DHCP.
+    PlayStation,
+}
+
+impl Preset {
+    /// Creates a `Preset` by its name.
+    pub fn from_name(name: &str) -> Option<Preset> {
+        match name {
+            "xbox" => Some(Preset::Xbox),
+            "playstation" => Some(Preset::PlayStation),
+            _ => None,
+        }
+    }
+
+    /// Get the UDP destination ports of the online services of the console family, which are
+    /// never limited by destinations nor classified as discovery, since matchmaking pings many
+    /// servers with the same payload.
+    pub fn get_allowed_ports(&self) -> Vec<(u16, u16)> {
+        match self {
+            // Kerberos, IKE, Teredo, IPsec NAT-T and the Xbox services
+            Preset::Xbox => vec![
+                (88, 88),
+                (500, 500),
+                (3074, 3075),
+                (3544, 3544),
+                (4500, 4500),
+            ],
+            // The PlayStation Network, STUN is always allowed
+            Preset::PlayStation => vec![(3074, 3074), (3658, 3658)],
+        }
+    }
+
+    /// Get the responders of the preset.
+    pub fn get_responders(&self) -> Vec<Responder> {
+        match self {
+            Preset::Xbox => vec![Responder::Ping, Responder::Igmp, Responder::Http],
+            Preset::PlayStation => vec![
+                Responder::Ping,
+                Responder::Igmp,
+                Responder::Dhcp,
+                Responder::Http,
+            ],
+        }
+    }
+}
+
+impl Display for Preset {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Preset::Xbox => write!(f, "xbox"),
+            Preset::PlayStation => write!(f, "playstation"),
+        }
+    }
+}
+
+/// Represents the compatibility layer, where each responder is enabled independently. Pings are
+/// answered by the redirector with `set_ping_ttl`.
+#[derive(Debug, Default)]
+pub struct Console {
+    igmp: Option<Igmp>,
+    dhcp: Option<Dhcp>,
+    probe: Option<Probe>,
+}
+
+impl Console {
+    /// Creates a new `Console` without any responder.
+    pub fn new() -> Console {
+        Console::default()
+    }
+
+    /// Sets the IGMP responder.
+    pub fn set_igmp(&mut self, igmp: Igmp) {
+        self.igmp = Some(igmp);
+    }
+
+    /// Sets the DHCP responder.
+    pub fn set_dhcp(&mut self, dhcp: Dhcp) {
+        self.dhcp = Some(dhcp);
+    }
+
+    /// Sets the HTTP probe responder.
+    pub fn set_probe(&mut self, probe: Probe) {
+        self.probe = Some(probe);
+    }
+
+    /// Get the mutable IGMP responder.
+    pub fn get_igmp_mut(&mut self) -> Option<&mut Igmp> {
+        self.igmp.as_mut()
+    }
+
+    /// Get the mutable DHCP responder.
+    pub fn get_dhcp_mut(&mut self) -> Option<&mut Dhcp> {
+        self.dhcp.as_mut()
+    }
+
+    /// Get the mutable HTTP probe responder.
+    pub fn get_probe_mut(&mut self) -> Option<&mut Probe> {
+        self.probe.as_mut()
+    }
+
+    /// Returns if any responder is enabled.
+    pub fn is_enabled(&self) -> bool {
+        self.igmp.is_some() || self.dhcp.is_some() || self.probe.is_some()
+    }
+}
+
+/// Get the IPv4 address in the bytes, which have at least 4 bytes.
+fn get_ip_addr(bytes: &[u8]) -> Ipv4Addr {
+    Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::Indicator;
+    use crate::pcap::HardwareAddr;
+    use crate::testing::{self, LOCAL_IP_ADDR, SRC_IP_ADDR};
+    use pnet::packet::tcp::TcpFlags;
+    use std::net::SocketAddrV4;
+
+    /// Represents the offset of the IPv4 header in a frame.
+    const IPV4_OFFSET: usize = 14;
+
+    #[test]
+    fn test_preset() {
+        let preset = Preset::from_name("playstation").unwrap();
+        assert_eq!(preset.to_string(), "playstation");
+        assert!(preset.get_responders().contains(&Responder::Dhcp));
+        assert!(!Preset::Xbox.get_responders().contains(&Responder::Dhcp));
+        assert_eq!(Preset::from_name("switch"), None);
+        assert!(Preset::Xbox.get_allowed_ports().contains(&(3074, 3075)));
+        for name in ["ping", "igmp", "dhcp", "http"].iter() {
+            assert_eq!(Responder::from_name(name).unwrap().to_string(), *name);
+        }
+    }
+
+    #[test]
+    fn test_send_igmp() {
+        let (mut forwarder, rx) = testing::forwarder(1500);
+        forwarder.set_local_ip_addr(LOCAL_IP_ADDR);
+        let mut igmp = Igmp::new();
+        // IGMPv2 report of 239.255.255.250
+        let (group, message) = igmp
+            .handle(&[0x16, 0x00, 0xfa, 0x04, 0xef, 0xff, 0xff, 0xfa])
+            .unwrap();
+        forwarder.send_igmp(group, &message).unwrap();
+
+        let frame = rx.try_recv().unwrap();
+        let indicator = Indicator::from(&frame).unwrap();
+        assert_eq!(
+            indicator.get_ethernet().unwrap().get_dst(),
+            HardwareAddr::new(0x01, 0x00, 0x5e, 0x00, 0x00, 0x01)
+        );
+        let ipv4 = indicator.get_ipv4().unwrap();
+        assert!(ipv4.is_igmp());
+        assert_eq!(ipv4.get_src(), LOCAL_IP_ADDR);
+        assert_eq!(ipv4.get_dst(), igmp::ALL_HOSTS);
+        // TTL
+        assert_eq!(frame[IPV4_OFFSET + 8], 1);
+        let start = indicator.get_size();
+        assert_eq!(frame[start..start + 8], message[..]);
+    }
+
+    #[test]
+    fn test_send_dhcp() {
+        let (mut forwarder, rx) = testing::forwarder(1500);
+        forwarder.set_local_ip_addr(LOCAL_IP_ADDR);
+        let mut dhcp = Dhcp::new(LOCAL_IP_ADDR, SRC_IP_ADDR);
+        // DHCP discover of the console
+        let mut discover = vec![0u8; 236];
+        discover[0] = 1;
+        discover[1] = 1;
+        discover[2] = 6;
+        discover[28..34].copy_from_slice(&[2, 0, 0, 0, 0, 2]);
+        discover.extend_from_slice(&[99, 130, 83, 99, 53, 1, 1, 255]);
+        let offer = dhcp.handle(&discover).unwrap();
+        forwarder
+            .send_udp_broadcast(
+                SocketAddrV4::new(LOCAL_IP_ADDR, dhcp::SERVER_PORT),
+                dhcp::CLIENT_PORT,
+                &offer,
+            )
+            .unwrap();
+
+        let frame = rx.try_recv().unwrap();
+        let indicator = Indicator::from(&frame).unwrap();
+        assert_eq!(
+            indicator.get_ethernet().unwrap().get_dst(),
+            HardwareAddr::broadcast()
+        );
+        assert_eq!(indicator.get_ipv4().unwrap().get_dst(), Ipv4Addr::BROADCAST);
+        let udp = indicator.get_udp().unwrap();
+        assert_eq!(udp.get_src(), dhcp::SERVER_PORT);
+        assert_eq!(udp.get_dst(), dhcp::CLIENT_PORT);
+        assert!(indicator.validate(&frame, true).is_ok());
+        assert_eq!(frame[indicator.get_size()..], offer[..]);
+    }
+
+    #[test]
+    fn test_send_probe() {
+        let (mut forwarder, rx) = testing::forwarder(1500);
+        let mut probe = Probe::new(
+            probe::DEFAULT_PORT,
+            probe::DEFAULT_RESPONSE.as_bytes().to_vec(),
+        );
+        let gateway = SocketAddrV4::new(LOCAL_IP_ADDR, probe::DEFAULT_PORT);
+        let syn_ack = probe.handle(50000, TcpFlags::SYN, 1000, 0, &[], 1460);
+        forwarder
+            .send_tcp_segment(gateway, 50000, &syn_ack[0])
+            .unwrap();
+        let response = probe.handle(
+            50000,
+            TcpFlags::ACK,
+            1001,
+            syn_ack[0].sequence.wrapping_add(1),
+            b"GET / HTTP/1.1\r\n\r\n",
+            1460,
+        );
+        forwarder
+            .send_tcp_segment(gateway, 50000, &response[0])
+            .unwrap();
+
+        let frame = rx.try_recv().unwrap();
+        let indicator = Indicator::from(&frame).unwrap();
+        let tcp = indicator.get_tcp().unwrap();
+        assert!(tcp.is_syn() && tcp.is_ack());
+        assert_eq!(tcp.get_mss(), Some(1460));
+        assert_eq!(tcp.get_acknowledgement(), 1001);
+        assert!(indicator.validate(&frame, true).is_ok());
+
+        let frame = rx.try_recv().unwrap();
+        let indicator = Indicator::from(&frame).unwrap();
+        let tcp = indicator.get_tcp().unwrap();
+        assert!(tcp.is_fin() && tcp.is_psh());
+        assert_eq!(tcp.get_src(), probe::DEFAULT_PORT);
+        assert_eq!(tcp.get_dst(), 50000);
+        assert!(indicator.validate(&frame, true).is_ok());
+        let start = indicator.get_size();
+        assert_eq!(
+            &frame[start..start + probe::DEFAULT_RESPONSE.len()],
+            probe::DEFAULT_RESPONSE.as_bytes()
+        );
+    }
+}
@@ -0,0 +1,328 @@
+use log::debug;
+use pnet::packet::tcp::TcpFlags;
+use std::collections::HashMap;
+
+/// Represents the default port of the HTTP probe.
+pub const DEFAULT_PORT: u16 = 80;
+
+/// Represents the default canned response of the HTTP probe.
+pub const DEFAULT_RESPONSE: &str = "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 2\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\nOK";
+
+/// Represents the max size of the canned response.
+pub const MAX_RESPONSE_SIZE: usize = 16384;
+
+/// Represents the end of the header of an HTTP request.
+const HEADER_END: &[u8] = b"\r\n\r\n";
+
+/// Represents the max number of connections whose last acknowledgement is tracked.
+const TRACKED_ACKNOWLEDGEMENTS: usize = 1024;
+
+/// Represents a TCP segment answering the source.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Segment {
+    pub flags: u16,
+    pub sequence: u32,
+    pub acknowledgement: u32,
+    pub payload: Vec<u8>,
+}
+
+/// Represents the HTTP probe responder, which answers requests to a port of the gateway with a
+/// canned response and closes the connection. The responder keeps no connection: the initial
+/// sequence is derived from the source port, so each segment of the source tells which part of
+/// the response it acknowledges, and a duplicated acknowledgement retransmits the next segment.
+#[derive(Debug)]
+pub struct Probe {
+    port: u16,
+    response: Vec<u8>,
+    acknowledgements: HashMap<u16, u32>,
+    answered: usize,
+}
+
+impl Probe {
+    /// Creates a new `Probe` on the given port with the canned response.
+    pub fn new(port: u16, response: Vec<u8>) -> Probe {
+        Probe {
+            port,
+            response,
+            acknowledgements: HashMap::new(),
+            answered: 0,
+        }
+    }
+
+    /// Get the port of the probe on the gateway.
+    pub fn get_port(&self) -> u16 {
+        self.port
+    }
+
+    /// Get the number of requests answered.
+    pub fn get_answered(&self) -> usize {
+        self.answered
+    }
+
+    /// Handles a TCP segment from the given source port, returns the segments answering it, each
+    /// carrying at most `mss` bytes of the response.
+    pub fn handle(
+        &mut self,
+        src_port: u16,
+        flags: u16,
+        sequence: u32,
+        acknowledgement: u32,
+        payload: &[u8],
+        mss: usize,
+    ) -> Vec<Segment> {
+        if flags & TcpFlags::RST != 0 {
+            self.acknowledgements.remove(&src_port);
+            return vec![];
+        }
+        let initial = get_initial_sequence(src_port);
+        if flags & TcpFlags::SYN != 0 {
+            return vec![Segment {
+                flags: TcpFlags::SYN | TcpFlags::ACK,
+                sequence: initial,
+                acknowledgement: sequence.wrapping_add(1),
+                payload: vec![],
+            }];
+        }
+        if flags & TcpFlags::ACK == 0 {
+            return vec![];
+        }
+
+        // The response and the FIN follow the SYN
+        let offset = acknowledgement.wrapping_sub(initial.wrapping_add(1)) as usize;
+        if offset > self.response.len() + 1 {
+            return vec![];
+        }
+        let mut next = sequence.wrapping_add(payload.len() as u32);
+        if flags & TcpFlags::FIN != 0 {
+            next = next.wrapping_add(1);
+        }
+
+        if !payload.is_empty() {
+            self.acknowledgements.remove(&src_port);
+            if payload.ends_with(HEADER_END) {
+                debug!("HTTP probe: answer {}", src_port);
+                self.answered = self.answered.saturating_add(1);
+                return self.get_response(initial, offset, next, mss, None);
+            }
+            return vec![Segment {
+                flags: TcpFlags::ACK,
+                sequence: acknowledgement,
+                acknowledgement: next,
+                payload: vec![],
+            }];
+        }
+        if flags & TcpFlags::FIN != 0 {
+            self.acknowledgements.remove(&src_port);
+            return vec![Segment {
+                flags: TcpFlags::ACK,
+                sequence: acknowledgement,
+                acknowledgement: next,
+                payload: vec![],
+            }];
+        }
+
+        // Pure acknowledgements, after the response is sent
+        if offset == 0 || offset > self.response.len() {
+            self.acknowledgements.remove(&src_port);
+            return vec![];
+        }
+        let is_duplicate = self.acknowledgements.get(&src_port) == Some(&acknowledgement);
+        if self.acknowledgements.len() >= TRACKED_ACKNOWLEDGEMENTS {
+            self.acknowledgements.clear();
+        }
+        self.acknowledgements.insert(src_port, acknowledgement);
+        if is_duplicate {
+            return self.get_response(initial, offset, next, mss, Some(1));
+        }
+
+        vec![]
+    }
+
+    /// Get the segments of the response from the offset, the last of which carries the FIN.
+    fn get_response(
+        &self,
+        initial: u32,
+        offset: usize,
+        acknowledgement: u32,
+        mss: usize,
+        limit: Option<usize>,
+    ) -> Vec<Segment> {
+        let mut segments = Vec::new();
+
+        let mut n = offset;
+        loop {
+            if limit.is_some_and(|limit| segments.len() >= limit) {
+                break;
+            }
+            let end = std::cmp::min(n + mss, self.response.len());
+            let mut flags = TcpFlags::ACK;
+            if n < end {
+                flags |= TcpFlags::PSH;
+            }
+            if end == self.response.len() {
+                flags |= TcpFlags::FIN;
+            }
+            segments.push(Segment {
+                flags,
+                sequence: initial.wrapping_add(1).wrapping_add(n as u32),
+                acknowledgement,
+                payload: self.response[n..end].to_vec(),
+            });
+            if end == self.response.len() {
+                break;
+            }
+
+            n = end;
+        }
+
+        segments
+    }
+}
+
+/// Get the initial sequence of the connection from the source port.
+fn get_initial_sequence(src_port: u16) -> u32 {
+    (src_port as u32).wrapping_mul(2_654_435_761)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRC_PORT: u16 = 50432;
+    const SEQUENCE: u32 = 0x1c2d_3e4f;
+    // The probe of a console, a request of the root without a body
+    const REQUEST: &[u8] =
+        b"GET / HTTP/1.1\r\nHost: 10.6.0.1\r\nUser-Agent: Console\r\nConnection: close\r\n\r\n";
+
+    fn connect(probe: &mut Probe) -> u32 {
+        let syn_ack = probe.handle(SRC_PORT, TcpFlags::SYN, SEQUENCE, 0, &[], 1460);
+        assert_eq!(syn_ack.len(), 1);
+        assert_eq!(syn_ack[0].flags, TcpFlags::SYN | TcpFlags::ACK);
+        assert_eq!(syn_ack[0].acknowledgement, SEQUENCE + 1);
+
+        syn_ack[0].sequence.wrapping_add(1)
+    }
+
+    #[test]
+    fn test_request() {
+        let mut probe = Probe::new(DEFAULT_PORT, DEFAULT_RESPONSE.as_bytes().to_vec());
+        let start = connect(&mut probe);
+
+        // The handshake completes without an answer
+        assert!(probe
+            .handle(SRC_PORT, TcpFlags::ACK, SEQUENCE + 1, start, &[], 1460)
+            .is_empty());
+
+        let response = probe.handle(
+            SRC_PORT,
+            TcpFlags::ACK | TcpFlags::PSH,
+            SEQUENCE + 1,
+            start,
+            REQUEST,
+            1460,
+        );
+        let next = SEQUENCE + 1 + REQUEST.len() as u32;
+        assert_eq!(
+            response,
+            vec![Segment {
+                flags: TcpFlags::ACK | TcpFlags::PSH | TcpFlags::FIN,
+                sequence: start,
+                acknowledgement: next,
+                payload: DEFAULT_RESPONSE.as_bytes().to_vec(),
+            }]
+        );
+        assert_eq!(probe.get_answered(), 1);
+
+        // The FIN of the source is acknowledged after the FIN of the probe
+        let fin = start.wrapping_add(DEFAULT_RESPONSE.len() as u32 + 1);
+        let ack = probe.handle(
+            SRC_PORT,
+            TcpFlags::ACK | TcpFlags::FIN,
+            next,
+            fin,
+            &[],
+            1460,
+        );
+        assert_eq!(
+            ack,
+            vec![Segment {
+                flags: TcpFlags::ACK,
+                sequence: fin,
+                acknowledgement: next + 1,
+                payload: vec![],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_segmented_response() {
+        let response = vec![b'x'; 1000];
+        let mut probe = Probe::new(DEFAULT_PORT, response);
+        let start = connect(&mut probe);
+
+        let segments = probe.handle(SRC_PORT, TcpFlags::ACK, SEQUENCE + 1, start, REQUEST, 400);
+        assert_eq!(segments.len(), 3);
+        assert_eq!(segments[0].flags, TcpFlags::ACK | TcpFlags::PSH);
+        assert_eq!(segments[1].sequence, start + 400);
+        assert_eq!(segments[2].payload.len(), 200);
+        assert_eq!(
+            segments[2].flags,
+            TcpFlags::ACK | TcpFlags::PSH | TcpFlags::FIN
+        );
+
+        // A duplicated acknowledgement retransmits the next segment only
+        let next = SEQUENCE + 1 + REQUEST.len() as u32;
+        assert!(probe
+            .handle(SRC_PORT, TcpFlags::ACK, next, start + 400, &[], 400)
+            .is_empty());
+        let retransmitted = probe.handle(SRC_PORT, TcpFlags::ACK, next, start + 400, &[], 400);
+        assert_eq!(retransmitted, vec![segments[1].clone()]);
+
+        // A retransmitted request is answered again
+        let segments_again =
+            probe.handle(SRC_PORT, TcpFlags::ACK, SEQUENCE + 1, start, REQUEST, 400);
+        assert_eq!(segments_again, segments);
+    }
+
+    #[test]
+    fn test_partial_request() {
+        let mut probe = Probe::new(DEFAULT_PORT, DEFAULT_RESPONSE.as_bytes().to_vec());
+        let start = connect(&mut probe);
+
+        let ack = probe.handle(
+            SRC_PORT,
+            TcpFlags::ACK,
+            SEQUENCE + 1,
+            start,
+            &REQUEST[..16],
+            1460,
+        );
+        assert_eq!(ack.len(), 1);
+        assert_eq!(ack[0].flags, TcpFlags::ACK);
+        assert_eq!(ack[0].acknowledgement, SEQUENCE + 17);
+
+        let response = probe.handle(
+            SRC_PORT,
+            TcpFlags::ACK,
+            SEQUENCE + 17,
+            start,
+            &REQUEST[16..],
+            1460,
+        );
+        assert_eq!(response.len(), 1);
+        assert_eq!(response[0].payload, DEFAULT_RESPONSE.as_bytes());
+    }
+
+    #[test]
+    fn test_unexpected() {
+        let mut probe = Probe::new(DEFAULT_PORT, DEFAULT_RESPONSE.as_bytes().to_vec());
+        assert!(probe
+            .handle(SRC_PORT, TcpFlags::RST, SEQUENCE, 0, &[], 1460)
+            .is_empty());
+        // Acknowledgements of another connection
+        assert!(probe
+            .handle(SRC_PORT, TcpFlags::ACK, SEQUENCE, 12345, REQUEST, 1460)
+            .is_empty());
+        assert_eq!(probe.get_answered(), 0);
+    }
+}
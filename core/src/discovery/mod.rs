@@ -0,0 +1,288 @@
+use log::info;
+use lru::LruCache;
+use std::fmt::{self, Display, Formatter};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::{Duration, Instant};
+
+use crate::upnp::ssdp::{Ssdp, SSDP_PORT};
+
+/// Represents the default max number of distinct destinations of an identical payload from a
+/// source port in a window.
+pub const DEFAULT_LIMIT: usize = 64;
+
+/// Represents the window in which destinations of an identical payload are counted.
+const DISCOVERY_WINDOW: u64 = 10;
+
+/// Represents the max size of a payload considered, discovery probes are small.
+const MAX_PAYLOAD_SIZE: usize = 512;
+
+/// Represents the max number of payloads tracked.
+const TRACKED_PAYLOADS: usize = 1024;
+
+/// Represents what happens to a fan-out classified as discovery.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DiscoveryPolicy {
+    /// Never classifies datagrams as discovery.
+    Off,
+    /// Forwards the payload to the first destinations within the limit only.
+    Limit,
+    /// Drops the payload to all destinations once it exceeds the limit.
+    Drop,
+    /// Answers SSDP searches of the gateway by the responder, and limits the others as `Limit`.
+    Answer,
+}
+
+impl Display for DiscoveryPolicy {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            DiscoveryPolicy::Off => write!(f, "off"),
+            DiscoveryPolicy::Limit => write!(f, "limit"),
+            DiscoveryPolicy::Drop => write!(f, "drop"),
+            DiscoveryPolicy::Answer => write!(f, "answer"),
+        }
+    }
+}
+
+/// Represents the destinations of a payload from a source port in a window.
+#[derive(Debug)]
+struct Payload {
+    start: Instant,
+    destinations: Vec<SocketAddrV4>,
+    is_classified: bool,
+}
+
+/// Represents the detection of discovery of a host, which sends the same small payload from a
+/// source port to many distinct destinations, like SSDP searches, LAN game browsers or scans.
+/// Payloads are tracked by their hash in a bounded LRU, and each keeps at most the limit of
+/// destinations, so a fan-out costs a bounded memory however many destinations it has.
+pub struct Discovery {
+    src_ip_addr: Ipv4Addr,
+    policy: DiscoveryPolicy,
+    limit: usize,
+    payloads: LruCache<(u16, u64), Payload>,
+    classified: usize,
+    suppressed: usize,
+    ssdp: Option<Ssdp>,
+    answered: usize,
+}
+
+impl Discovery {
+    /// Creates a new `Discovery`.
+    pub fn new(src_ip_addr: Ipv4Addr) -> Discovery {
+        Discovery {
+            src_ip_addr,
+            policy: DiscoveryPolicy::Limit,
+            limit: DEFAULT_LIMIT,
+            payloads: LruCache::new(TRACKED_PAYLOADS),
+            classified: 0,
+            suppressed: 0,
+            ssdp: None,
+            answered: 0,
+        }
+    }
+
+    /// Sets the policy and the max number of distinct destinations of an identical payload from
+    /// a source port in a window.
+    pub fn set_policy(&mut self, policy: DiscoveryPolicy, limit: usize) {
+        self.policy = policy;
+        self.limit = limit;
+    }
+
+    /// Sets the SSDP responder answering searches of the gateway by the policy `Answer`.
+    pub fn set_ssdp(&mut self, ssdp: Ssdp) {
+        self.ssdp = Some(ssdp);
+    }
+
+    /// Get the response of the responder to a datagram to the destination, or `None` if it is
+    /// not answered by the policy.
+    pub fn answer(&mut self, dst: SocketAddrV4, payload: &[u8]) -> Option<Vec<u8>> {
+        if self.policy != DiscoveryPolicy::Answer || dst.port() != SSDP_PORT {
+            return None;
+        }
+        let response = self.ssdp.as_ref()?.answer(payload)?;
+        self.answered = self.answered.saturating_add(1);
+
+        Some(response)
+    }
+
+    /// Admits a datagram from the source port to the destination, returns `false` if it is
+    /// suppressed as discovery by the policy.
+    pub fn admit(&mut self, src_port: u16, dst: SocketAddrV4, payload: &[u8]) -> bool {
+        if self.policy == DiscoveryPolicy::Off
+            || payload.is_empty()
+            || payload.len() > MAX_PAYLOAD_SIZE
+        {
+            return true;
+        }
+
+        let key = (src_port, hash(payload));
+        let is_expired = match self.payloads.get(&key) {
+            Some(entry) => entry.start.elapsed() >= Duration::from_secs(DISCOVERY_WINDOW),
+            None => true,
+        };
+        if is_expired {
+            self.payloads.put(
+                key,
+                Payload {
+                    start: Instant::now(),
+                    destinations: Vec::new(),
+                    is_classified: false,
+                },
+            );
+        }
+        let entry = self.payloads.get_mut(&key).unwrap();
+
+        if entry.destinations.contains(&dst) {
+            if entry.is_classified && self.policy == DiscoveryPolicy::Drop {
+                self.suppressed = self.suppressed.saturating_add(1);
+                return false;
+            }
+            return true;
+        }
+        if entry.destinations.len() < self.limit {
+            entry.destinations.push(dst);
+            return true;
+        }
+
+        if !entry.is_classified {
+            entry.is_classified = true;
+            self.classified = self.classified.saturating_add(1);
+            info!(
+                "Classify UDP {}:{} as discovery: an identical {} Bytes payload to more than {} destinations in {} s, the rest are suppressed by policy {}",
+                self.src_ip_addr,
+                src_port,
+                payload.len(),
+                self.limit,
+                DISCOVERY_WINDOW,
+                self.policy
+            );
+        }
+        self.suppressed = self.suppressed.saturating_add(1);
+
+        false
+    }
+
+    /// Get the number of fan-outs classified as discovery and of datagrams suppressed.
+    pub fn get_counts(&self) -> (usize, usize) {
+        (self.classified, self.suppressed)
+    }
+
+    /// Get the number of datagrams answered by the responder.
+    pub fn get_answered(&self) -> usize {
+        self.answered
+    }
+}
+
+/// Hashes the payload with FNV-1a, which is cheap and enough to tell payloads of a host apart.
+fn hash(payload: &[u8]) -> u64 {
+    payload.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Represents the number of destinations of the fan-out.
+    const DESTINATIONS: usize = 500;
+
+    /// Represents the max number of destinations forwarded.
+    const K: usize = 16;
+
+    // SSDP search
+    const PAYLOAD: &[u8] = b"M-SEARCH * HTTP/1.1\r\nST: ssdp:all\r\n\r\n";
+
+    fn get_dst(i: usize) -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(10, 0, (i / 256) as u8, (i % 256) as u8), 1900)
+    }
+
+    /// Sends the payload to all the destinations, returns the destinations forwarded.
+    fn fan_out(discovery: &mut Discovery, payload: &[u8]) -> Vec<SocketAddrV4> {
+        (0..DESTINATIONS)
+            .map(get_dst)
+            .filter(|dst| discovery.admit(50000, *dst, payload))
+            .collect()
+    }
+
+    #[test]
+    fn test_limit() {
+        let mut discovery = Discovery::new(Ipv4Addr::new(10, 6, 0, 2));
+        discovery.set_policy(DiscoveryPolicy::Limit, K);
+        let forwarded = fan_out(&mut discovery, PAYLOAD);
+        assert_eq!(forwarded, (0..K).map(get_dst).collect::<Vec<_>>());
+        assert_eq!(discovery.get_counts(), (1, DESTINATIONS - K));
+
+        // The first destinations keep being forwarded
+        assert!(discovery.admit(50000, get_dst(0), PAYLOAD));
+        // Other payloads and source ports are counted apart
+        assert!(discovery.admit(50000, get_dst(K), b"other"));
+        assert!(discovery.admit(50001, get_dst(K), PAYLOAD));
+
+        // A new window starts over
+        let key = (50000, hash(PAYLOAD));
+        discovery.payloads.get_mut(&key).unwrap().start -= Duration::from_secs(DISCOVERY_WINDOW);
+        assert_eq!(fan_out(&mut discovery, PAYLOAD).len(), K);
+        assert_eq!(discovery.get_counts().0, 2);
+    }
+
+    #[test]
+    fn test_drop() {
+        let mut discovery = Discovery::new(Ipv4Addr::new(10, 6, 0, 2));
+        discovery.set_policy(DiscoveryPolicy::Drop, K);
+        assert_eq!(fan_out(&mut discovery, PAYLOAD).len(), K);
+
+        // Once classified, the first destinations are suppressed as well
+        assert!(fan_out(&mut discovery, PAYLOAD).is_empty());
+        assert_eq!(discovery.get_counts(), (1, 2 * DESTINATIONS - K));
+    }
+
+    #[test]
+    fn test_answer() {
+        const SEARCH: &[u8] = b"M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nMAN: \"ssdp:discover\"\r\nST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n";
+        let multicast = SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), SSDP_PORT);
+
+        let mut discovery = Discovery::new(Ipv4Addr::new(10, 6, 0, 2));
+        discovery.set_policy(DiscoveryPolicy::Answer, K);
+        // Nothing is answered without the responder
+        assert_eq!(discovery.answer(multicast, SEARCH), None);
+
+        discovery.set_ssdp(Ssdp::new(Ipv4Addr::new(10, 6, 0, 1), 5000));
+        assert!(discovery.answer(multicast, SEARCH).is_some());
+        assert!(discovery.answer(get_dst(0), SEARCH).is_some());
+        assert_eq!(discovery.answer(multicast, PAYLOAD), None);
+        assert_eq!(discovery.get_answered(), 2);
+        // Fan-outs which are not answered are limited
+        assert_eq!(fan_out(&mut discovery, PAYLOAD).len(), K);
+
+        discovery.set_policy(DiscoveryPolicy::Limit, K);
+        assert_eq!(discovery.answer(multicast, SEARCH), None);
+    }
+
+    #[test]
+    fn test_off_and_large() {
+        let mut discovery = Discovery::new(Ipv4Addr::new(10, 6, 0, 2));
+        assert_eq!(
+            fan_out(&mut discovery, &[0u8; MAX_PAYLOAD_SIZE + 1]).len(),
+            DESTINATIONS
+        );
+        discovery.set_policy(DiscoveryPolicy::Off, K);
+        assert_eq!(fan_out(&mut discovery, PAYLOAD).len(), DESTINATIONS);
+        assert_eq!(discovery.get_counts(), (0, 0));
+    }
+
+    #[test]
+    fn test_bounded() {
+        let mut discovery = Discovery::new(Ipv4Addr::new(10, 6, 0, 2));
+        discovery.set_policy(DiscoveryPolicy::Limit, K);
+        for i in 0..2 * TRACKED_PAYLOADS {
+            let payload = i.to_be_bytes();
+            fan_out(&mut discovery, &payload);
+        }
+        assert_eq!(discovery.payloads.len(), TRACKED_PAYLOADS);
+        assert!(discovery
+            .payloads
+            .iter()
+            .all(|(_, entry)| entry.destinations.len() <= K));
+    }
+}
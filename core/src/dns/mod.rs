@@ -0,0 +1,808 @@
+use log::{trace, warn};
+use lru::LruCache;
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::socks::Forward;
+
+/// Represents the port of DNS.
+pub const DNS_PORT: u16 = 53;
+
+/// Represents the time in which identical queries share one query to the upstream.
+const IN_FLIGHT_TIMEOUT: u64 = 2;
+
+/// Represents the max number of queries waiting for the same answer.
+const MAX_WAITERS: usize = 16;
+
+/// Represents the max number of distinct queries in flight.
+const MAX_IN_FLIGHT: usize = 256;
+
+/// Represents the max time in seconds a positive answer is cached.
+const POSITIVE_TTL_CAP: u32 = 300;
+
+/// Represents the max time in seconds a negative answer is cached.
+const NEGATIVE_TTL_CAP: u32 = 30;
+
+/// Represents the size of the DNS header.
+const HEADER_SIZE: usize = 12;
+
+/// Represents the type of SOA records.
+const TYPE_SOA: u16 = 6;
+
+/// Represents the type of OPT pseudo records, whose TTL carries flags.
+const TYPE_OPT: u16 = 41;
+
+/// Represents the class IN.
+const CLASS_IN: u16 = 1;
+
+/// Represents a question by the name in lower case in wire format and the type.
+type Key = (Vec<u8>, u16);
+
+/// Represents a query waiting for the answer to an identical query.
+struct Waiter {
+    tx: Arc<Mutex<dyn Forward>>,
+    src_ip_addr: Ipv4Addr,
+    src_port: u16,
+    dst: SocketAddrV4,
+    id: u16,
+}
+
+impl Waiter {
+    fn is_same(&self, other: &Waiter) -> bool {
+        self.src_ip_addr == other.src_ip_addr
+            && self.src_port == other.src_port
+            && self.dst == other.dst
+            && self.id == other.id
+    }
+}
+
+/// Represents a query in flight to the upstream.
+struct InFlight {
+    instant: Instant,
+    /// Represents the query forwarded to the upstream, whose answer is forwarded as is.
+    primary: Waiter,
+    waiters: Vec<Waiter>,
+}
+
+/// Represents a cached answer.
+struct Entry {
+    response: Vec<u8>,
+    instant: Instant,
+    ttl: u32,
+}
+
+/// Represents the result of a lookup.
+pub enum Lookup {
+    /// The query is answered from the cache with the given response.
+    Hit(Vec<u8>),
+    /// The query waits for the answer to an identical query in flight.
+    Coalesced,
+    /// The query must be forwarded to the upstream.
+    Miss,
+}
+
+/// Represents the statistics of a `DnsCache`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DnsStats {
+    pub queries: usize,
+    pub hits: usize,
+    pub coalesced: usize,
+    pub forwarded: usize,
+    pub answers: usize,
+    pub entries: usize,
+}
+
+impl Display for DnsStats {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let rate = |n: usize| {
+            if self.queries == 0 {
+                0.0
+            } else {
+                n as f64 * 100.0 / self.queries as f64
+            }
+        };
+        write!(
+            f,
+            "{} queries, {} hits ({:.1}%), {} coalesced ({:.1}%), {} forwarded, {} answers, {} entries",
+            self.queries,
+            self.hits,
+            rate(self.hits),
+            self.coalesced,
+            rate(self.coalesced),
+            self.forwarded,
+            self.answers,
+            self.entries
+        )
+    }
+}
+
+/// Represents a cache of DNS answers shared by the captured hosts. Identical queries in flight
+/// share one query to the upstream, and the answer is fanned out to each requester with its own
+/// transaction ID. Answers are cached for their TTL, negative answers for the SOA minimum, both
+/// capped.
+pub struct DnsCache {
+    cache: LruCache<Key, Entry>,
+    in_flight: HashMap<Key, InFlight>,
+    stats: DnsStats,
+}
+
+impl DnsCache {
+    /// Creates a new `DnsCache` of the given max number of answers.
+    pub fn new(size: usize) -> DnsCache {
+        DnsCache {
+            cache: LruCache::new(size),
+            in_flight: HashMap::new(),
+            stats: DnsStats::default(),
+        }
+    }
+
+    /// Looks up a query from the source port of the source to the destination. The given `tx`
+    /// forwards the answer if the query waits for an identical one.
+    pub fn lookup(
+        &mut self,
+        tx: Arc<Mutex<dyn Forward>>,
+        src_ip_addr: Ipv4Addr,
+        src_port: u16,
+        dst: SocketAddrV4,
+        query: &[u8],
+    ) -> Lookup {
+        let (id, key) = match parse_query(query) {
+            Some(query) => query,
+            None => return Lookup::Miss,
+        };
+        self.stats.queries = self.stats.queries.saturating_add(1);
+
+        // Cache
+        let mut is_expired = false;
+        if let Some(entry) = self.cache.get(&key) {
+            let age = entry.instant.elapsed().as_secs();
+            if age < entry.ttl as u64 {
+                self.stats.hits = self.stats.hits.saturating_add(1);
+                trace!("DNS hit of {}:{} #{}", src_ip_addr, src_port, id);
+
+                return Lookup::Hit(rewrite(&entry.response, id, age as u32));
+            }
+            is_expired = true;
+        }
+        if is_expired {
+            self.cache.pop(&key);
+        }
+
+        let waiter = Waiter {
+            tx,
+            src_ip_addr,
+            src_port,
+            dst,
+            id,
+        };
+
+        // Queries in flight
+        if let Some(in_flight) = self.in_flight.get_mut(&key) {
+            if in_flight.instant.elapsed().as_secs() < IN_FLIGHT_TIMEOUT {
+                // Retransmissions are forwarded again
+                if in_flight.primary.is_same(&waiter)
+                    || in_flight.waiters.iter().any(|w| w.is_same(&waiter))
+                {
+                    self.stats.forwarded = self.stats.forwarded.saturating_add(1);
+
+                    return Lookup::Miss;
+                }
+                if in_flight.waiters.len() < MAX_WAITERS {
+                    in_flight.waiters.push(waiter);
+                    self.stats.coalesced = self.stats.coalesced.saturating_add(1);
+                    trace!("DNS coalesce {}:{} #{}", src_ip_addr, src_port, id);
+
+                    return Lookup::Coalesced;
+                }
+            } else {
+                // The upstream is slow, the waiters wait for the new query instead
+                in_flight.instant = Instant::now();
+                in_flight.primary = waiter;
+            }
+            self.stats.forwarded = self.stats.forwarded.saturating_add(1);
+
+            return Lookup::Miss;
+        }
+
+        if self.in_flight.len() < MAX_IN_FLIGHT {
+            self.in_flight.insert(
+                key,
+                InFlight {
+                    instant: Instant::now(),
+                    primary: waiter,
+                    waiters: Vec::new(),
+                },
+            );
+        }
+        self.stats.forwarded = self.stats.forwarded.saturating_add(1);
+
+        Lookup::Miss
+    }
+
+    /// Completes the queries identical to the given response, which is cached if cacheable.
+    /// Returns the queries waiting for it with their responses.
+    fn complete(&mut self, response: &[u8]) -> Vec<(Waiter, Vec<u8>)> {
+        let (key, ttl) = match parse_response(response) {
+            Some(response) => response,
+            None => return Vec::new(),
+        };
+        self.stats.answers = self.stats.answers.saturating_add(1);
+        if let Some(ttl) = ttl {
+            self.cache.put(
+                key.clone(),
+                Entry {
+                    response: response.to_vec(),
+                    instant: Instant::now(),
+                    ttl,
+                },
+            );
+        }
+
+        match self.in_flight.remove(&key) {
+            Some(in_flight) => in_flight
+                .waiters
+                .into_iter()
+                .map(|waiter| {
+                    let response = rewrite(response, waiter.id, 0);
+                    (waiter, response)
+                })
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Removes queries in flight whose answers never come.
+    pub fn scavenge(&mut self) {
+        self.in_flight
+            .retain(|_, in_flight| in_flight.instant.elapsed().as_secs() < IN_FLIGHT_TIMEOUT);
+    }
+
+    /// Get the statistics of the `DnsCache`.
+    pub fn get_stats(&self) -> DnsStats {
+        DnsStats {
+            entries: self.cache.len(),
+            ..self.stats
+        }
+    }
+}
+
+/// Represents a `Forward` which completes identical queries waiting in the `DnsCache` with the
+/// answers forwarded to the source.
+pub struct DnsForward<F: Forward> {
+    inner: F,
+    cache: Arc<Mutex<DnsCache>>,
+}
+
+impl<F: Forward> DnsForward<F> {
+    /// Creates a new `DnsForward`.
+    pub fn new(inner: F, cache: Arc<Mutex<DnsCache>>) -> DnsForward<F> {
+        DnsForward { inner, cache }
+    }
+}
+
+impl<F: Forward> Forward for DnsForward<F> {
+    fn forward_tcp(&mut self, dst: SocketAddr, src_port: u16, payload: &[u8]) -> io::Result<()> {
+        self.inner.forward_tcp(dst, src_port, payload)
+    }
+
+    fn forward_udp(&mut self, dst: SocketAddr, src_port: u16, payload: &[u8]) -> io::Result<()> {
+        if dst.port() == DNS_PORT {
+            // The cache is released before forwarding, which locks the forwarders of the waiters
+            let waiters = self.cache.lock().unwrap().complete(payload);
+            for (waiter, response) in waiters {
+                if let Err(ref e) = waiter.tx.lock().unwrap().forward_udp(
+                    waiter.dst.into(),
+                    waiter.src_port,
+                    &response,
+                ) {
+                    warn!(
+                        "handle {}: {} -> {}: {}",
+                        "DNS", waiter.src_port, waiter.dst, e
+                    );
+                }
+            }
+        }
+
+        self.inner.forward_udp(dst, src_port, payload)
+    }
+
+    fn get_tcp_backlog(&mut self, dst: SocketAddr, src_port: u16) -> (usize, u32) {
+        self.inner.get_tcp_backlog(dst, src_port)
+    }
+
+    fn forward_tcp_connect(
+        &mut self,
+        dst: SocketAddr,
+        src_port: u16,
+        is_connected: bool,
+    ) -> io::Result<()> {
+        self.inner.forward_tcp_connect(dst, src_port, is_connected)
+    }
+
+    fn forward_tcp_close(
+        &mut self,
+        dst: SocketAddr,
+        src_port: u16,
+        is_reset: bool,
+    ) -> io::Result<()> {
+        self.inner.forward_tcp_close(dst, src_port, is_reset)
+    }
+}
+
+/// Represents a `Forward` which answers the queries to the gateway, forwarded to the resolver,
+/// from the gateway, so the source takes the answers as the ones of its DNS server.
+pub struct GatewayForward<F: Forward> {
+    inner: F,
+    resolver: SocketAddrV4,
+    gateway: SocketAddrV4,
+}
+
+impl<F: Forward> GatewayForward<F> {
+    /// Creates a new `GatewayForward`.
+    pub fn new(inner: F, resolver: SocketAddrV4, gateway: Ipv4Addr) -> GatewayForward<F> {
+        GatewayForward {
+            inner,
+            resolver,
+            gateway: SocketAddrV4::new(gateway, DNS_PORT),
+        }
+    }
+}
+
+impl<F: Forward> Forward for GatewayForward<F> {
+    fn forward_tcp(&mut self, dst: SocketAddr, src_port: u16, payload: &[u8]) -> io::Result<()> {
+        self.inner.forward_tcp(dst, src_port, payload)
+    }
+
+    fn forward_udp(&mut self, dst: SocketAddr, src_port: u16, payload: &[u8]) -> io::Result<()> {
+        let dst = if dst == SocketAddr::V4(self.resolver) {
+            SocketAddr::V4(self.gateway)
+        } else {
+            dst
+        };
+
+        self.inner.forward_udp(dst, src_port, payload)
+    }
+
+    fn get_tcp_backlog(&mut self, dst: SocketAddr, src_port: u16) -> (usize, u32) {
+        self.inner.get_tcp_backlog(dst, src_port)
+    }
+
+    fn forward_tcp_connect(
+        &mut self,
+        dst: SocketAddr,
+        src_port: u16,
+        is_connected: bool,
+    ) -> io::Result<()> {
+        self.inner.forward_tcp_connect(dst, src_port, is_connected)
+    }
+
+    fn forward_tcp_close(
+        &mut self,
+        dst: SocketAddr,
+        src_port: u16,
+        is_reset: bool,
+    ) -> io::Result<()> {
+        self.inner.forward_tcp_close(dst, src_port, is_reset)
+    }
+}
+
+fn read_u16(buffer: &[u8], pos: usize) -> Option<u16> {
+    if pos + 2 > buffer.len() {
+        return None;
+    }
+
+    Some((buffer[pos] as u16) << 8 | buffer[pos + 1] as u16)
+}
+
+fn read_u32(buffer: &[u8], pos: usize) -> Option<u32> {
+    Some((read_u16(buffer, pos)? as u32) << 16 | read_u16(buffer, pos + 2)? as u32)
+}
+
+/// Reads an uncompressed name in lower case, returns the name and the position after it.
+fn read_name(buffer: &[u8], mut pos: usize) -> Option<(Vec<u8>, usize)> {
+    let mut name = Vec::new();
+    loop {
+        let length = *buffer.get(pos)? as usize;
+        if length == 0 {
+            name.push(0);
+            return Some((name, pos + 1));
+        }
+        // Compressed or reserved
+        if length & 0xc0 != 0 || pos + 1 + length > buffer.len() {
+            return None;
+        }
+        name.push(length as u8);
+        name.extend(
+            buffer[pos + 1..pos + 1 + length]
+                .iter()
+                .map(u8::to_ascii_lowercase),
+        );
+        pos += 1 + length;
+    }
+}
+
+/// Skips a name which may be compressed, returns the position after it.
+fn skip_name(buffer: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let length = *buffer.get(pos)? as usize;
+        match length & 0xc0 {
+            0 if length == 0 => return Some(pos + 1),
+            0 => pos += 1 + length,
+            0xc0 if pos + 2 <= buffer.len() => return Some(pos + 2),
+            _ => return None,
+        }
+    }
+}
+
+/// Parses a standard query of a single question of class IN, returns the ID and the question.
+fn parse_query(buffer: &[u8]) -> Option<(u16, Key)> {
+    let id = read_u16(buffer, 0)?;
+    let flags = read_u16(buffer, 2)?;
+    // QR and opcode
+    if flags & 0xf800 != 0 || read_u16(buffer, 4)? != 1 {
+        return None;
+    }
+    let (name, pos) = read_name(buffer, HEADER_SIZE)?;
+    let qtype = read_u16(buffer, pos)?;
+    if read_u16(buffer, pos + 2)? != CLASS_IN {
+        return None;
+    }
+
+    Some((id, (name, qtype)))
+}
+
+/// Represents a record in a section of a response.
+struct Record {
+    rtype: u16,
+    /// Represents the position of the TTL.
+    ttl_pos: usize,
+    ttl: u32,
+    rdata: (usize, usize),
+}
+
+/// Get the records of the answer, the authority and the additional sections of a response, with
+/// the number of records in the answer section.
+fn parse_records(buffer: &[u8], pos: usize) -> Option<(Vec<Record>, usize)> {
+    let answers = read_u16(buffer, 6)? as usize;
+    let count = answers + read_u16(buffer, 8)? as usize + read_u16(buffer, 10)? as usize;
+
+    let mut records = Vec::with_capacity(count);
+    let mut pos = pos;
+    for _ in 0..count {
+        pos = skip_name(buffer, pos)?;
+        let rtype = read_u16(buffer, pos)?;
+        let ttl = read_u32(buffer, pos + 4)?;
+        let length = read_u16(buffer, pos + 8)? as usize;
+        let rdata = pos + 10;
+        if rdata + length > buffer.len() {
+            return None;
+        }
+        records.push(Record {
+            rtype,
+            ttl_pos: pos + 4,
+            ttl,
+            rdata: (rdata, length),
+        });
+        pos = rdata + length;
+    }
+
+    Some((records, answers))
+}
+
+/// Parses a response of a single question, returns the question and the time the response can
+/// be cached, or `None` if it cannot.
+fn parse_response(buffer: &[u8]) -> Option<(Key, Option<u32>)> {
+    let flags = read_u16(buffer, 2)?;
+    // QR
+    if flags & 0x8000 == 0 || read_u16(buffer, 4)? != 1 {
+        return None;
+    }
+    let (name, pos) = read_name(buffer, HEADER_SIZE)?;
+    let qtype = read_u16(buffer, pos)?;
+    if read_u16(buffer, pos + 2)? != CLASS_IN {
+        return None;
+    }
+    let key = (name, qtype);
+
+    // Truncated responses are retried over TCP
+    if flags & 0x0200 != 0 {
+        return Some((key, None));
+    }
+    let (records, answers) = match parse_records(buffer, pos + 4) {
+        Some(records) => records,
+        None => return Some((key, None)),
+    };
+
+    let rcode = flags & 0x000f;
+    let ttl = match rcode {
+        // NOERROR
+        0 if answers > 0 => records[..answers]
+            .iter()
+            .map(|record| record.ttl)
+            .min()
+            .map(|ttl| ttl.min(POSITIVE_TTL_CAP)),
+        // NODATA and NXDOMAIN, for the SOA minimum
+        0 | 3 => records[answers..]
+            .iter()
+            .find(|record| record.rtype == TYPE_SOA && record.rdata.1 >= 4)
+            .and_then(|record| {
+                let minimum = read_u32(buffer, record.rdata.0 + record.rdata.1 - 4)?;
+                Some(record.ttl.min(minimum).min(NEGATIVE_TTL_CAP))
+            }),
+        _ => None,
+    };
+
+    Some((key, ttl.filter(|ttl| *ttl > 0)))
+}
+
+/// Get a copy of the response with the given ID and the TTLs decreased by the age.
+fn rewrite(buffer: &[u8], id: u16, age: u32) -> Vec<u8> {
+    let mut response = buffer.to_vec();
+    response[0] = (id >> 8) as u8;
+    response[1] = id as u8;
+    if age == 0 {
+        return response;
+    }
+
+    let pos = match read_name(buffer, HEADER_SIZE) {
+        Some((_, pos)) => pos + 4,
+        None => return response,
+    };
+    if let Some((records, _)) = parse_records(buffer, pos) {
+        for record in records.iter().filter(|record| record.rtype != TYPE_OPT) {
+            let ttl = record.ttl.saturating_sub(age);
+            response[record.ttl_pos..record.ttl_pos + 4].copy_from_slice(&ttl.to_be_bytes());
+        }
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Represents a `Forward` which records the UDP payload forwarded.
+    #[derive(Default)]
+    struct Recorder {
+        sent: Vec<(SocketAddr, u16, Vec<u8>)>,
+    }
+
+    impl Forward for Recorder {
+        fn forward_tcp(&mut self, _: SocketAddr, _: u16, _: &[u8]) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn forward_udp(
+            &mut self,
+            dst: SocketAddr,
+            src_port: u16,
+            payload: &[u8],
+        ) -> io::Result<()> {
+            self.sent.push((dst, src_port, payload.to_vec()));
+
+            Ok(())
+        }
+
+        fn forward_tcp_connect(&mut self, _: SocketAddr, _: u16, _: bool) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn get_resolver() -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), DNS_PORT)
+    }
+
+    // example.com
+    const NAME: &[u8] = b"\x07example\x03com\x00";
+
+    const TYPE_A: u16 = 1;
+
+    fn new_header(id: u16, flags: u16, answers: u16, authorities: u16) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        for field in [id, flags, 1, answers, authorities, 0].iter() {
+            buffer.extend_from_slice(&field.to_be_bytes());
+        }
+        buffer.extend_from_slice(NAME);
+        buffer.extend_from_slice(&TYPE_A.to_be_bytes());
+        buffer.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+        buffer
+    }
+
+    fn new_query(id: u16) -> Vec<u8> {
+        // RD
+        new_header(id, 0x0100, 0, 0)
+    }
+
+    /// Creates a response of an A record of the given TTL.
+    fn new_response(id: u16, ttl: u32) -> Vec<u8> {
+        let mut buffer = new_header(id, 0x8180, 1, 0);
+        // Pointer to the question
+        buffer.extend_from_slice(&[0xc0, 0x0c]);
+        buffer.extend_from_slice(&TYPE_A.to_be_bytes());
+        buffer.extend_from_slice(&CLASS_IN.to_be_bytes());
+        buffer.extend_from_slice(&ttl.to_be_bytes());
+        buffer.extend_from_slice(&4u16.to_be_bytes());
+        buffer.extend_from_slice(&[93, 184, 216, 34]);
+
+        buffer
+    }
+
+    /// Creates a NXDOMAIN response of a SOA record of the given TTL and minimum.
+    fn new_nxdomain(id: u16, ttl: u32, minimum: u32) -> Vec<u8> {
+        let mut buffer = new_header(id, 0x8183, 0, 1);
+        buffer.extend_from_slice(&[0xc0, 0x0c]);
+        buffer.extend_from_slice(&TYPE_SOA.to_be_bytes());
+        buffer.extend_from_slice(&CLASS_IN.to_be_bytes());
+        buffer.extend_from_slice(&ttl.to_be_bytes());
+        buffer.extend_from_slice(&22u16.to_be_bytes());
+        // MNAME and RNAME of the root
+        buffer.extend_from_slice(&[0, 0]);
+        for field in [1, 7200, 3600, 1_209_600, minimum].iter() {
+            buffer.extend_from_slice(&(*field).to_be_bytes());
+        }
+
+        buffer
+    }
+
+    fn get_id(buffer: &[u8]) -> u16 {
+        read_u16(buffer, 0).unwrap()
+    }
+
+    fn get_ttl(buffer: &[u8]) -> u32 {
+        let pos = read_name(buffer, HEADER_SIZE).unwrap().1 + 4;
+        parse_records(buffer, pos).unwrap().0[0].ttl
+    }
+
+    fn new_requester() -> (Arc<Mutex<Recorder>>, Arc<Mutex<dyn Forward>>) {
+        let recorder = Arc::new(Mutex::new(Recorder::default()));
+        let tx: Arc<Mutex<dyn Forward>> = recorder.clone();
+
+        (recorder, tx)
+    }
+
+    fn lookup(cache: &mut DnsCache, tx: &Arc<Mutex<dyn Forward>>, host: u8, id: u16) -> Lookup {
+        cache.lookup(
+            tx.clone(),
+            Ipv4Addr::new(10, 6, 0, host),
+            50000,
+            get_resolver(),
+            &new_query(id),
+        )
+    }
+
+    /// Ages the cached answer of the question by the given duration.
+    fn elapse(cache: &mut DnsCache, d: Duration) {
+        let key = (NAME.to_vec(), TYPE_A);
+        cache.cache.peek_mut(&key).unwrap().instant -= d;
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let response = new_response(0x1234, 120);
+        let rewritten = rewrite(&response, 0xabcd, 0);
+        assert_eq!(get_id(&rewritten), 0xabcd);
+        assert_eq!(rewritten[2..], response[2..]);
+
+        // TTLs are decreased by the age
+        let rewritten = rewrite(&response, 0xabcd, 20);
+        assert_eq!(get_id(&rewritten), 0xabcd);
+        assert_eq!(get_ttl(&rewritten), 100);
+        assert_eq!(
+            rewrite(&response, 0xabcd, 200)[..],
+            rewrite(&response, 0xabcd, 120)[..]
+        );
+        assert_eq!(get_ttl(&rewrite(&response, 0xabcd, 200)), 0);
+    }
+
+    #[test]
+    fn test_fan_out() {
+        let cache = Arc::new(Mutex::new(DnsCache::new(16)));
+        let requesters: Vec<_> = (0..3).map(|_| new_requester()).collect();
+        {
+            let mut cache = cache.lock().unwrap();
+            for (i, (_, tx)) in requesters.iter().enumerate() {
+                let result = lookup(&mut cache, tx, 2 + i as u8, 0x1000 + i as u16);
+                match (i, result) {
+                    (0, Lookup::Miss) => {}
+                    (i, Lookup::Coalesced) if i > 0 => {}
+                    _ => panic!("unexpected lookup of requester {}", i),
+                }
+            }
+            // Retransmissions of the primary are forwarded again
+            assert!(matches!(
+                lookup(&mut cache, &requesters[0].1, 2, 0x1000),
+                Lookup::Miss
+            ));
+        }
+
+        // The answer to the query of the first requester
+        let response = new_response(0x1000, 60);
+        let mut forward = DnsForward::new(requesters[0].0.clone(), cache.clone());
+        forward
+            .forward_udp(get_resolver().into(), 50000, &response)
+            .unwrap();
+
+        for (i, (recorder, _)) in requesters.iter().enumerate() {
+            let sent = &recorder.lock().unwrap().sent;
+            assert_eq!(sent.len(), 1);
+            let (dst, src_port, payload) = &sent[0];
+            assert_eq!(*dst, SocketAddr::V4(get_resolver()));
+            assert_eq!(*src_port, 50000);
+            assert_eq!(get_id(payload), 0x1000 + i as u16);
+            assert_eq!(payload[2..], response[2..]);
+        }
+
+        let stats = cache.lock().unwrap().get_stats();
+        assert_eq!(stats.queries, 4);
+        assert_eq!(stats.coalesced, 2);
+        assert_eq!(stats.forwarded, 2);
+        assert_eq!(stats.answers, 1);
+        assert_eq!(stats.entries, 1);
+    }
+
+    #[test]
+    fn test_ttl_expiry() {
+        let mut cache = DnsCache::new(16);
+        let (_, tx) = new_requester();
+        assert!(matches!(lookup(&mut cache, &tx, 2, 1), Lookup::Miss));
+        assert!(cache.complete(&new_response(1, 60)).is_empty());
+
+        match lookup(&mut cache, &tx, 3, 2) {
+            Lookup::Hit(response) => {
+                assert_eq!(get_id(&response), 2);
+                assert_eq!(get_ttl(&response), 60);
+            }
+            _ => panic!("expected a hit"),
+        }
+
+        elapse(&mut cache, Duration::from_secs(45));
+        match lookup(&mut cache, &tx, 3, 3) {
+            Lookup::Hit(response) => assert_eq!(get_ttl(&response), 15),
+            _ => panic!("expected a hit"),
+        }
+
+        elapse(&mut cache, Duration::from_secs(15));
+        assert!(matches!(lookup(&mut cache, &tx, 3, 4), Lookup::Miss));
+        assert_eq!(cache.get_stats().entries, 0);
+        assert_eq!(cache.get_stats().hits, 2);
+    }
+
+    #[test]
+    fn test_ttl_cap() {
+        let mut cache = DnsCache::new(16);
+        let (_, tx) = new_requester();
+        cache.complete(&new_response(1, 86400));
+        elapse(&mut cache, Duration::from_secs(POSITIVE_TTL_CAP as u64 - 1));
+        assert!(matches!(lookup(&mut cache, &tx, 2, 2), Lookup::Hit(_)));
+        elapse(&mut cache, Duration::from_secs(1));
+        assert!(matches!(lookup(&mut cache, &tx, 2, 3), Lookup::Miss));
+
+        // Negative answers for the SOA minimum
+        let mut cache = DnsCache::new(16);
+        cache.complete(&new_nxdomain(1, 3600, 10));
+        match lookup(&mut cache, &tx, 2, 2) {
+            Lookup::Hit(response) => assert_eq!(response[3] & 0x0f, 3),
+            _ => panic!("expected a hit"),
+        }
+        elapse(&mut cache, Duration::from_secs(10));
+        assert!(matches!(lookup(&mut cache, &tx, 2, 3), Lookup::Miss));
+
+        // Capped
+        let mut cache = DnsCache::new(16);
+        cache.complete(&new_nxdomain(1, 3600, 3600));
+        elapse(&mut cache, Duration::from_secs(NEGATIVE_TTL_CAP as u64));
+        assert!(matches!(lookup(&mut cache, &tx, 2, 2), Lookup::Miss));
+
+        // Responses of a TTL of 0 are not cached
+        let mut cache = DnsCache::new(16);
+        cache.complete(&new_response(1, 0));
+        assert_eq!(cache.get_stats().entries, 0);
+    }
+}
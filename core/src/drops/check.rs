@@ -0,0 +1,205 @@
+use log::info;
+use std::fmt::Write;
+use std::io;
+use std::net::Ipv4Addr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{DropLog, DropReason};
+use crate::packet::layer::{Layer, LayerTypes};
+use crate::packet::{Indicator, Malformed};
+use crate::pcap::{HardwareAddr, Receiver};
+
+/// Represents the default duration of a check in seconds.
+pub const DEFAULT_DURATION: u64 = 10;
+
+/// Represents the wait time after a `TimedOut` `IoError`.
+const TIMEDOUT_WAIT: u64 = 20;
+
+/// Represents a check of the traffic of the source, which counts the packets the `Redirector`
+/// would drop before creating any flow by the reasons which do not depend on its state, without
+/// sending any frame, and tells the reason most of them are dropped for.
+pub struct Check {
+    src_ip_addr: Ipv4Addr,
+    gateway: Option<Ipv4Addr>,
+    src_hardware_addr: Option<HardwareAddr>,
+    drops: DropLog,
+    handled: usize,
+    elapsed: Duration,
+}
+
+impl Check {
+    /// Creates a new `Check` of the source. ARP requests of the source are checked against the
+    /// gateway if it is given.
+    pub fn new(src_ip_addr: Ipv4Addr, gateway: Option<Ipv4Addr>) -> Check {
+        Check {
+            src_ip_addr,
+            gateway,
+            src_hardware_addr: None,
+            drops: DropLog::new(),
+            handled: 0,
+            elapsed: Duration::from_secs(0),
+        }
+    }
+
+    /// Checks an `Interface` for the given duration.
+    pub fn open(&mut self, rx: &mut Receiver, duration: Duration) -> io::Result<()> {
+        let start = Instant::now();
+        info!("Check {} for {} s", self.src_ip_addr, duration.as_secs());
+        while start.elapsed() < duration {
+            match rx.next() {
+                Ok(frame) => self.handle(frame),
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::TimedOut {
+                        thread::sleep(Duration::from_millis(TIMEDOUT_WAIT));
+                        continue;
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        self.elapsed = start.elapsed();
+
+        Ok(())
+    }
+
+    /// Counts a captured frame as handled or dropped for a reason.
+    pub fn handle(&mut self, frame: &[u8]) {
+        match self.classify(frame) {
+            Some(reason) => self.drops.add(reason, || format!("{} Bytes", frame.len())),
+            None => self.handled += 1,
+        }
+    }
+
+    /// Get the reason the frame is dropped for, or `None` if it is handled.
+    fn classify(&mut self, frame: &[u8]) -> Option<DropReason> {
+        let indicator = match Indicator::from(frame) {
+            Some(indicator) => indicator,
+            None => return Some(DropReason::Frame),
+        };
+        let src_hardware_addr = indicator.get_ethernet().map(|ethernet| ethernet.get_src());
+
+        match indicator.get_network_type() {
+            Some(LayerTypes::Arp) => {
+                let arp = indicator.get_arp().unwrap();
+                if arp.get_src() != self.src_ip_addr {
+                    return Some(DropReason::OtherHost);
+                }
+                self.src_hardware_addr = src_hardware_addr;
+                match self.gateway {
+                    Some(gateway) if !arp.is_request_of(self.src_ip_addr, gateway) => {
+                        Some(DropReason::ArpOther)
+                    }
+                    _ => None,
+                }
+            }
+            Some(LayerTypes::Ipv4) => {
+                let ipv4 = indicator.get_ipv4().unwrap();
+                if ipv4.get_src() != self.src_ip_addr {
+                    return Some(DropReason::OtherHost);
+                }
+                self.src_hardware_addr = src_hardware_addr;
+                let size =
+                    indicator.get_ethernet().unwrap().get_size() + ipv4.get_total_length() as usize;
+                if size > frame.len() || (ipv4.get_total_length() as usize) < ipv4.get_size() {
+                    return Some(DropReason::Malformed(Malformed::Ipv4Length));
+                }
+                // Fragments are checked once reassembled
+                if ipv4.is_fragment() {
+                    return None;
+                }
+
+                match indicator.get_transport_type() {
+                    Some(LayerTypes::Tcp) | Some(LayerTypes::Udp) => indicator
+                        .validate(&frame[..size], true)
+                        .err()
+                        .map(DropReason::Malformed),
+                    Some(LayerTypes::Icmpv4) => Some(DropReason::Icmp),
+                    _ => Some(DropReason::Protocol),
+                }
+            }
+            // Other network protocols, like IPv6, are from the source if they are from its
+            // hardware address
+            _ => {
+                if self.src_hardware_addr.is_some() && src_hardware_addr == self.src_hardware_addr {
+                    Some(DropReason::Protocol)
+                } else {
+                    Some(DropReason::OtherHost)
+                }
+            }
+        }
+    }
+
+    /// Get the report in text.
+    pub fn report(&self) -> String {
+        let mut s = String::new();
+        let dropped = self.drops.get_total();
+        let _ = writeln!(
+            s,
+            "Check {} in {} s: {} packets, {} handled, {} dropped",
+            self.src_ip_addr,
+            self.elapsed.as_secs(),
+            self.handled + dropped,
+            self.handled,
+            dropped
+        );
+        for (reason, count) in self.drops.get_counts() {
+            let _ = writeln!(s, "    {}: {}", reason, count);
+        }
+        let diagnosis = match self.drops.diagnose() {
+            Some(diagnosis) if self.handled == 0 || dropped > self.handled => diagnosis,
+            _ if self.handled > 0 => String::from("the traffic of the source is handled"),
+            _ => String::from("no packet is captured, check the interface"),
+        };
+        let _ = writeln!(s, "Diagnosis: {}", diagnosis);
+
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::builder::Builder;
+    use crate::testing;
+
+    #[test]
+    fn test_check() {
+        let mut check = Check::new(testing::SRC_IP_ADDR, Some(testing::LOCAL_IP_ADDR));
+        assert!(check
+            .report()
+            .ends_with("no packet is captured, check the interface\n"));
+
+        check.handle(&testing::tcp_frame(50000, 80));
+        check.handle(&testing::udp_frame(50000, 53, b"query"));
+        assert!(check
+            .report()
+            .ends_with("the traffic of the source is handled\n"));
+
+        // Pings and other hosts
+        let mut builder = Builder::new(testing::SRC_HARDWARE_ADDR, testing::LOCAL_HARDWARE_ADDR);
+        builder.set_ipv4(1, testing::SRC_IP_ADDR, testing::DST_IP_ADDR);
+        builder.set_icmpv4_echo_reply();
+        let ping = builder.build(&[]).unwrap();
+        for _ in 0..3 {
+            check.handle(&ping);
+        }
+        let mut builder = Builder::new(testing::SRC_HARDWARE_ADDR, testing::LOCAL_HARDWARE_ADDR);
+        builder.set_ipv4(1, Ipv4Addr::new(10, 6, 0, 3), testing::DST_IP_ADDR);
+        builder.set_udp(50000, 53);
+        check.handle(&builder.build(b"query").unwrap());
+        check.handle(&[0u8; 6]);
+
+        let report = check.report();
+        assert!(report.starts_with(&format!(
+            "Check {} in 0 s: 7 packets, 2 handled, 5 dropped\n",
+            testing::SRC_IP_ADDR
+        )));
+        assert!(
+            report.contains("    short frame: 1\n    other host: 1\n    ICMP not answered: 3\n")
+        );
+        assert!(report.ends_with(
+            "Diagnosis: 3 packets are dropped for ICMP not answered: pings cannot be proxied, see --ping-ttl\n"
+        ));
+    }
+}
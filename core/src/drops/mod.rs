@@ -0,0 +1,218 @@
+use log::{info, trace};
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+
+use crate::envelope::Refusal;
+use crate::packet::Malformed;
+
+pub mod check;
+
+pub use check::Check;
+
+/// Represents the reason a captured packet is dropped before any flow is created for it.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum DropReason {
+    /// The frame is too short for an Ethernet header.
+    Frame,
+    /// The packet is not from the source.
+    OtherHost,
+    /// The network or transport protocol from the source is not supported, like IPv6 or IGMP.
+    Protocol,
+    /// The ARP request from the source is not for the gateway.
+    ArpOther,
+    /// The ARP request exceeds the rate of the requester.
+    ArpFlood,
+    /// The ICMPv4 packet is not a ping answered by `--ping-ttl`.
+    Icmp,
+    /// The packet fails the validation.
+    Malformed(Malformed),
+    /// The source port of the UDP datagram belongs to a UDP association killed recently.
+    UdpQuarantine,
+    /// The source has too many flows.
+    HostFlows,
+    /// The source opens flows too fast.
+    HostRate,
+    /// The source sends packets beyond its share of the processing.
+    HostIngress,
+    /// The packet is to the address of the host, which answers it by itself.
+    Gateway,
+    /// The source sends UDP datagrams to too many new destinations.
+    UdpDestinations,
+    /// The UDP datagram belongs to a fan-out classified as discovery.
+    UdpDiscovery,
+    /// The source has too many half-open TCP connections.
+    HalfOpen,
+    /// New TCP connections back off for the exhaustion of local ports.
+    PortExhaustion,
+    /// The UDP datagram is to a proxy which cannot relay UDP.
+    UdpUnsupported,
+    /// The TCP segment of an unknown connection may belong to a connection drained by the old
+    /// process after a handoff.
+    Handoff,
+    /// The UDP datagram of a new association is rejected by the fallback policy while all the
+    /// upstreams are down.
+    Fallback,
+}
+
+impl DropReason {
+    /// Get the hint to the user for packets dropped for the reason.
+    pub fn get_hint(&self) -> &'static str {
+        match self {
+            DropReason::Frame => "the interface may not be an Ethernet interface",
+            DropReason::OtherHost => {
+                "no packet is from the source, check the source address and that the device uses pcap2socks as its gateway"
+            }
+            DropReason::Protocol => "only IPv4 TCP, UDP and ICMP can be proxied, disable IPv6 on the device",
+            DropReason::ArpOther => "the device does not resolve the gateway, check its gateway address",
+            DropReason::ArpFlood => "the device sends too many ARP requests, see --arp-rate",
+            DropReason::Icmp => "pings cannot be proxied, see --ping-ttl",
+            DropReason::Malformed(_) => "the device or the network corrupts packets",
+            DropReason::UdpQuarantine => "UDP associations are killed and bound again too soon",
+            DropReason::HostFlows => "the device has too many flows, see --host-max-flows",
+            DropReason::HostRate => "the device opens flows too fast, see --host-connect-rate",
+            DropReason::HostIngress => "the device sends too many packets, see --host-ingress-rate",
+            DropReason::Gateway => {
+                "the host answers its own address, see --publish to answer the gateway locally"
+            }
+            DropReason::UdpDestinations => {
+                "the device sends UDP to too many destinations, see --udp-max-destinations"
+            }
+            DropReason::UdpDiscovery => {
+                "the device sends an identical UDP payload to many destinations, see --udp-discovery-policy"
+            }
+            DropReason::HalfOpen => {
+                "the device does not complete TCP handshakes, see --tcp-max-half-open"
+            }
+            DropReason::PortExhaustion => {
+                "local ports of this host are exhausted, see the warning for the tuning"
+            }
+            DropReason::UdpUnsupported => {
+                "HTTP proxies cannot relay UDP, see --proxy-type"
+            }
+            DropReason::Handoff => {
+                "the old process drains its TCP connections after a handoff, see --handoff-drain"
+            }
+            DropReason::Fallback => {
+                "all the upstreams are down, see --fallback and the fallback key of rules"
+            }
+        }
+    }
+}
+
+impl From<Refusal> for DropReason {
+    fn from(refusal: Refusal) -> Self {
+        match refusal {
+            Refusal::Flows => DropReason::HostFlows,
+            Refusal::Rate => DropReason::HostRate,
+            Refusal::Ingress => DropReason::HostIngress,
+        }
+    }
+}
+
+impl Display for DropReason {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            DropReason::Frame => write!(f, "short frame"),
+            DropReason::OtherHost => write!(f, "other host"),
+            DropReason::Protocol => write!(f, "unsupported protocol"),
+            DropReason::ArpOther => write!(f, "ARP not for the gateway"),
+            DropReason::ArpFlood => write!(f, "ARP rate limited"),
+            DropReason::Icmp => write!(f, "ICMP not answered"),
+            DropReason::Malformed(reason) => write!(f, "malformed {}", reason),
+            DropReason::UdpQuarantine => write!(f, "UDP quarantine"),
+            DropReason::HostFlows => write!(f, "too many flows"),
+            DropReason::HostRate => write!(f, "too many new flows in a second"),
+            DropReason::HostIngress => write!(f, "too many packets in a second"),
+            DropReason::Gateway => write!(f, "to the host"),
+            DropReason::UdpDestinations => write!(f, "too many UDP destinations"),
+            DropReason::UdpDiscovery => write!(f, "UDP discovery"),
+            DropReason::HalfOpen => write!(f, "too many half-open connections"),
+            DropReason::PortExhaustion => write!(f, "local ports exhausted"),
+            DropReason::UdpUnsupported => write!(f, "UDP unsupported by the proxy"),
+            DropReason::Handoff => write!(f, "TCP of the old process"),
+            DropReason::Fallback => write!(f, "rejected by the fallback"),
+        }
+    }
+}
+
+/// Represents the counters of packets dropped before flows for each reason, with an optional
+/// sampled log of one in every N dropped packets.
+#[derive(Debug, Default)]
+pub struct DropLog {
+    counts: HashMap<DropReason, usize>,
+    total: usize,
+    sample: Option<usize>,
+}
+
+impl DropLog {
+    /// Creates a new `DropLog`.
+    pub fn new() -> DropLog {
+        DropLog::default()
+    }
+
+    /// Sets to log one in every N dropped packets, or `None` to not log them.
+    pub fn set_sample(&mut self, sample: Option<usize>) {
+        self.sample = sample;
+    }
+
+    /// Counts a dropped packet. The brief of the packet is only taken if it is logged.
+    pub fn add<F>(&mut self, reason: DropReason, brief: F)
+    where
+        F: FnOnce() -> String,
+    {
+        *self.counts.entry(reason).or_insert(0) += 1;
+        self.total = self.total.saturating_add(1);
+
+        match self.sample {
+            Some(sample) if self.total.is_multiple_of(sample) => {
+                info!("drop {}: {}", brief(), reason)
+            }
+            _ => trace!("drop {}: {}", brief(), reason),
+        }
+    }
+
+    /// Get the number of packets dropped for each reason.
+    pub fn get_counts(&self) -> Vec<(DropReason, usize)> {
+        let mut counts: Vec<(DropReason, usize)> =
+            self.counts.iter().map(|(k, v)| (*k, *v)).collect();
+        counts.sort();
+
+        counts
+    }
+
+    /// Get the number of packets dropped.
+    pub fn get_total(&self) -> usize {
+        self.total
+    }
+
+    /// Get the reason most packets are dropped for. Packets of other hosts are only considered if
+    /// there is no other reason, since they are expected on a shared network.
+    pub fn get_dominant(&self) -> Option<(DropReason, usize)> {
+        let dominant = self
+            .counts
+            .iter()
+            .filter(|(reason, _)| **reason != DropReason::OtherHost)
+            .max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(a.0)))
+            .map(|(k, v)| (*k, *v));
+
+        match dominant {
+            Some(dominant) => Some(dominant),
+            None => self
+                .counts
+                .get(&DropReason::OtherHost)
+                .map(|count| (DropReason::OtherHost, *count)),
+        }
+    }
+
+    /// Get the reason most packets are dropped for, with the hint to the user.
+    pub fn diagnose(&self) -> Option<String> {
+        self.get_dominant().map(|(reason, count)| {
+            format!(
+                "{} packets are dropped for {}: {}",
+                count,
+                reason,
+                reason.get_hint()
+            )
+        })
+    }
+}
@@ -0,0 +1,270 @@
+use log::{info, warn};
+use std::fmt::{self, Display, Formatter};
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+/// Represents the default max number of flows of a host.
+pub const DEFAULT_MAX_FLOWS: usize = 512;
+
+/// Represents the default max number of new flows of a host in a second.
+pub const DEFAULT_CONNECT_RATE: usize = 200;
+
+/// Represents the default max number of packets of a host processed in a second.
+pub const DEFAULT_INGRESS_RATE: usize = 50_000;
+
+/// Represents the number of consecutive seconds with refused flows after which a host is
+/// quarantined.
+const QUARANTINE_STRIKES: usize = 3;
+
+/// Represents the time a quarantined host stays in quarantine.
+const QUARANTINE_DURATION: u64 = 60;
+
+/// Represents the max number of flows of a quarantined host.
+const QUARANTINE_MAX_FLOWS: usize = 16;
+
+/// Represents the max number of new flows of a quarantined host in a second.
+const QUARANTINE_CONNECT_RATE: usize = 2;
+
+/// Represents the max number of packets of a quarantined host processed in a second.
+const QUARANTINE_INGRESS_RATE: usize = 500;
+
+/// Represents the reason a new flow is refused.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Refusal {
+    /// The host has too many flows.
+    Flows,
+    /// The host opens flows too fast.
+    Rate,
+    /// The host sends packets beyond its share of the processing.
+    Ingress,
+}
+
+impl Display for Refusal {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Refusal::Flows => write!(f, "too many flows"),
+            Refusal::Rate => write!(f, "too many new flows in a second"),
+            Refusal::Ingress => write!(f, "too many packets in a second"),
+        }
+    }
+}
+
+/// Represents the number of flows refused and packets dropped for a host.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Drops {
+    pub flows: usize,
+    pub rate: usize,
+    /// Represents the number of packets dropped beyond the ingress budget.
+    pub ingress: usize,
+    /// Represents the number of times the host is quarantined.
+    pub quarantines: usize,
+}
+
+impl Display for Drops {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} refused by flows, {} refused by rate, {} packets dropped by ingress, {} quarantines",
+            self.flows, self.rate, self.ingress, self.quarantines
+        )
+    }
+}
+
+/// Represents the resource envelope of a captured host, which bounds the flows, the rate of new
+/// flows and the packets processed of the host, so a misbehaving host cannot take the threads,
+/// the proxy connections and the processing shared with other hosts. A host refused in several
+/// consecutive seconds is quarantined with much lower limits for a while.
+#[derive(Debug)]
+pub struct Envelope {
+    src_ip_addr: Ipv4Addr,
+    max_flows: usize,
+    connect_rate: usize,
+    ingress_rate: usize,
+    /// Represents the start of the current second and the number of new flows in it.
+    window: (Instant, usize),
+    /// Represents the number of packets processed in the current second.
+    packets: usize,
+    /// Represents if a flow is refused in the current second.
+    is_refused: bool,
+    strikes: usize,
+    quarantine: Option<Instant>,
+    drops: Drops,
+}
+
+impl Envelope {
+    /// Creates a new `Envelope`.
+    pub fn new(src_ip_addr: Ipv4Addr, max_flows: usize, connect_rate: usize) -> Envelope {
+        Envelope {
+            src_ip_addr,
+            max_flows,
+            connect_rate,
+            ingress_rate: DEFAULT_INGRESS_RATE,
+            window: (Instant::now(), 0),
+            packets: 0,
+            is_refused: false,
+            strikes: 0,
+            quarantine: None,
+            drops: Drops::default(),
+        }
+    }
+
+    /// Sets the max number of flows and the max number of new flows in a second.
+    pub fn set_limits(&mut self, max_flows: usize, connect_rate: usize) {
+        self.max_flows = max_flows;
+        self.connect_rate = connect_rate;
+    }
+
+    /// Sets the max number of packets processed in a second.
+    pub fn set_ingress_rate(&mut self, ingress_rate: usize) {
+        self.ingress_rate = ingress_rate;
+    }
+
+    /// Get the max number of packets processed in a second in effect, which is lowered in
+    /// quarantine.
+    pub fn get_ingress_rate(&self) -> usize {
+        if self.is_quarantined() {
+            self.ingress_rate.min(QUARANTINE_INGRESS_RATE)
+        } else {
+            self.ingress_rate
+        }
+    }
+
+    /// Get the max number of flows and the max number of new flows in a second in effect, which
+    /// are lowered in quarantine.
+    pub fn get_limits(&self) -> (usize, usize) {
+        if self.is_quarantined() {
+            (
+                self.max_flows.min(QUARANTINE_MAX_FLOWS),
+                self.connect_rate.min(QUARANTINE_CONNECT_RATE),
+            )
+        } else {
+            (self.max_flows, self.connect_rate)
+        }
+    }
+
+    /// Returns if the host is in quarantine.
+    pub fn is_quarantined(&self) -> bool {
+        match self.quarantine {
+            Some(instant) => instant.elapsed().as_secs() < QUARANTINE_DURATION,
+            None => false,
+        }
+    }
+
+    /// Admits a new flow of the host, which has the given number of flows.
+    pub fn admit(&mut self, flows: usize) -> Result<(), Refusal> {
+        self.update();
+
+        let (max_flows, connect_rate) = self.get_limits();
+        let refusal = if flows >= max_flows {
+            Some(Refusal::Flows)
+        } else if self.window.1 >= connect_rate {
+            Some(Refusal::Rate)
+        } else {
+            None
+        };
+
+        match refusal {
+            Some(refusal) => {
+                if refusal == Refusal::Flows {
+                    self.drops.flows += 1;
+                } else {
+                    self.drops.rate += 1;
+                }
+                self.is_refused = true;
+
+                Err(refusal)
+            }
+            None => {
+                self.window.1 += 1;
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Admits a packet of the host to the processing, the packets beyond the ingress budget in a
+    /// second are dropped before any flow.
+    pub fn admit_packet(&mut self) -> Result<(), Refusal> {
+        self.update();
+
+        if self.packets >= self.get_ingress_rate() {
+            self.drops.ingress += 1;
+            self.is_refused = true;
+
+            return Err(Refusal::Ingress);
+        }
+        self.packets += 1;
+
+        Ok(())
+    }
+
+    /// Moves to the next second and counts the strikes of the last seconds.
+    fn update(&mut self) {
+        // Release
+        if self.quarantine.is_some() && !self.is_quarantined() {
+            self.quarantine = None;
+            self.strikes = 0;
+            info!("Release {} from quarantine", self.src_ip_addr);
+        }
+
+        let elapsed = self.window.0.elapsed().as_secs();
+        if elapsed < 1 {
+            return;
+        }
+        // Strikes are consecutive seconds with refused flows
+        if self.is_refused && elapsed < 2 {
+            self.strikes += 1;
+        } else {
+            self.strikes = 0;
+        }
+        self.window = (Instant::now(), 0);
+        self.packets = 0;
+        self.is_refused = false;
+
+        if self.strikes >= QUARANTINE_STRIKES && self.quarantine.is_none() {
+            self.quarantine = Some(Instant::now());
+            self.drops.quarantines += 1;
+            warn!(
+                "Quarantine {} for {} s: flows are refused in {} consecutive seconds, {}",
+                self.src_ip_addr, QUARANTINE_DURATION, self.strikes, self.drops
+            );
+        }
+    }
+
+    /// Get the time left in quarantine, or `None` if the host is not quarantined.
+    pub fn get_quarantine_left(&self) -> Option<Duration> {
+        match self.quarantine {
+            Some(instant) if self.is_quarantined() => Some(
+                Duration::from_secs(QUARANTINE_DURATION)
+                    .checked_sub(instant.elapsed())
+                    .unwrap_or_default(),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Get the number of flows refused for the host.
+    pub fn get_drops(&self) -> Drops {
+        self.drops
+    }
+}
+
+impl Display for Envelope {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let (max_flows, connect_rate) = self.get_limits();
+        write!(
+            f,
+            "{}: {} flows, {} new flows per second, {} packets per second, {}",
+            self.src_ip_addr,
+            max_flows,
+            connect_rate,
+            self.get_ingress_rate(),
+            self.drops
+        )?;
+        if let Some(left) = self.get_quarantine_left() {
+            write!(f, ", quarantined for {} s", left.as_secs())?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,159 @@
+use log::warn;
+use lru::LruCache;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::{Duration, Instant};
+
+/// Represents the default max number of new UDP destinations of a host in a window.
+pub const DEFAULT_MAX_DESTINATIONS: usize = 512;
+
+/// Represents the default max number of new UDP destinations of an association in a window.
+pub const DEFAULT_MAX_DESTINATIONS_PER_PORT: usize = 128;
+
+/// Represents the window in which new destinations are counted.
+const FANOUT_WINDOW: u64 = 10;
+
+/// Represents the max number of recent destinations tracked.
+const RECENT_DESTINATIONS: usize = 4096;
+
+/// Represents the ports of protocols legitimately talking to many destinations, which are never
+/// limited: DNS, STUN and TURN, and the STUN servers of Google.
+const ALLOWED_PORTS: [(u16, u16); 4] = [(53, 53), (3478, 3479), (5349, 5349), (19302, 19309)];
+
+/// Represents the number of new destinations of an association in a window.
+type PortFanout = (Instant, usize);
+
+/// Represents the limits of distinct UDP destinations of a host, which contain hosts spraying
+/// datagrams to many destinations, like reflection attacks or scans, from creating a proxy
+/// association or permission for each of them. Recent destinations are tracked in a bounded LRU,
+/// a destination evicted from it counts as new again.
+pub struct Fanout {
+    src_ip_addr: Ipv4Addr,
+    max_destinations: usize,
+    max_destinations_per_port: usize,
+    allowed_ports: Vec<(u16, u16)>,
+    recent: LruCache<(u16, SocketAddrV4), ()>,
+    /// Represents the start of the current window and the number of new destinations in it.
+    window: (Instant, usize),
+    port_map: HashMap<u16, PortFanout>,
+    is_warned: bool,
+    dropped: usize,
+}
+
+impl Fanout {
+    /// Creates a new `Fanout`.
+    pub fn new(src_ip_addr: Ipv4Addr) -> Fanout {
+        Fanout {
+            src_ip_addr,
+            max_destinations: DEFAULT_MAX_DESTINATIONS,
+            max_destinations_per_port: DEFAULT_MAX_DESTINATIONS_PER_PORT,
+            allowed_ports: ALLOWED_PORTS.to_vec(),
+            recent: LruCache::new(RECENT_DESTINATIONS),
+            window: (Instant::now(), 0),
+            port_map: HashMap::new(),
+            is_warned: false,
+            dropped: 0,
+        }
+    }
+
+    /// Sets the max number of new destinations of the host and of an association in a window.
+    pub fn set_limits(&mut self, max_destinations: usize, max_destinations_per_port: usize) {
+        self.max_destinations = max_destinations;
+        self.max_destinations_per_port = max_destinations_per_port;
+    }
+
+    /// Allows the given range of destination ports in addition to the built-in ones.
+    pub fn allow_ports(&mut self, first: u16, last: u16) {
+        self.allowed_ports.push((first, last));
+    }
+
+    /// Returns if the destination port is never limited.
+    pub fn is_allowed(&self, port: u16) -> bool {
+        self.allowed_ports
+            .iter()
+            .any(|(first, last)| port >= *first && port <= *last)
+    }
+
+    /// Admits a datagram from the source port to the destination, returns `false` if the
+    /// destination is new and exceeds the limits.
+    pub fn admit(&mut self, src_port: u16, dst: SocketAddrV4) -> bool {
+        if self.is_allowed(dst.port()) {
+            return true;
+        }
+
+        let window = Duration::from_secs(FANOUT_WINDOW);
+        if self.window.0.elapsed() >= window {
+            self.window = (Instant::now(), 0);
+            self.is_warned = false;
+        }
+
+        // Known destinations
+        let key = (src_port, dst);
+        if self.recent.get(&key).is_some() {
+            return true;
+        }
+
+        let entry = self
+            .port_map
+            .entry(src_port)
+            .or_insert_with(|| (Instant::now(), 0));
+        if entry.0.elapsed() >= window {
+            *entry = (Instant::now(), 0);
+        }
+        if self.window.1 >= self.max_destinations || entry.1 >= self.max_destinations_per_port {
+            self.dropped = self.dropped.saturating_add(1);
+            if !self.is_warned {
+                self.is_warned = true;
+                warn!(
+                    "{} sends UDP to too many destinations, new destinations are dropped in {} s, the top is port {}",
+                    self.src_ip_addr,
+                    FANOUT_WINDOW,
+                    self.get_top_talkers(1)
+                        .first()
+                        .map(|(port, _)| *port)
+                        .unwrap_or(src_port)
+                );
+            }
+
+            return false;
+        }
+
+        entry.1 += 1;
+        self.window.1 += 1;
+        self.recent.put(key, ());
+
+        true
+    }
+
+    /// Removes the counts of expired windows.
+    pub fn scavenge(&mut self) {
+        let window = Duration::from_secs(FANOUT_WINDOW);
+        self.port_map
+            .retain(|_, (instant, _)| instant.elapsed() < window);
+    }
+
+    /// Get the source ports with the most new destinations in the current window.
+    pub fn get_top_talkers(&self, n: usize) -> Vec<(u16, usize)> {
+        let window = Duration::from_secs(FANOUT_WINDOW);
+        let mut talkers: Vec<(u16, usize)> = self
+            .port_map
+            .iter()
+            .filter(|(_, (instant, _))| instant.elapsed() < window)
+            .map(|(port, (_, count))| (*port, *count))
+            .collect();
+        talkers.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        talkers.truncate(n);
+
+        talkers
+    }
+
+    /// Get the number of datagrams dropped for new destinations beyond the limits.
+    pub fn get_dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// Get the number of recent destinations tracked.
+    pub fn get_count(&self) -> usize {
+        self.recent.len()
+    }
+}
@@ -0,0 +1,1414 @@
+use log::{debug, trace};
+use pnet::packet::ethernet::EtherTypes;
+use pnet::packet::tcp::TcpFlags;
+use std::cmp::min;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::{Duration, Instant};
+
+use crate::cacher::Cacher;
+use crate::congestion::Congestion;
+use crate::console::igmp;
+use crate::packet::builder::{self, Builder};
+use crate::packet::layer::LayerTypes;
+use crate::pcap::{HardwareAddr, Sender};
+use crate::random::{generate_key, siphash, Key, Random};
+use crate::socks::Forward;
+use crate::{MAX_U32_WINDOW_SIZE, TCP_HEADERS_SIZE};
+
+/// Represents the max shift of the TCP window scale option.
+const MAX_WINDOW_SCALE: u8 = 14;
+
+/// Represents the MSS encoded in a TCP SYN cookie by their indexes, as in Linux.
+const TCP_COOKIE_MSS: [u16; 4] = [536, 1300, 1440, 1460];
+
+/// Represents the period of a TCP SYN cookie in seconds, a cookie is valid in its period and the
+/// next one.
+const TCP_COOKIE_PERIOD: u64 = 64;
+
+/// Represents the channel forward traffic to the source in pcap.
+pub struct Forwarder {
+    tx: Sender,
+    mtu: u16,
+    src_hardware_addr: HardwareAddr,
+    is_src_llc_snap: bool,
+    local_hardware_addr: HardwareAddr,
+    src_ip_addr: Ipv4Addr,
+    local_ip_addr: Ipv4Addr,
+    ipv4_identification_map: HashMap<Ipv4Addr, u16>,
+    /// Represents the map mapping a TCP connection to the send window in bytes, scaled by the
+    /// window scale of the source.
+    tcp_send_window_map: HashMap<(u16, SocketAddrV4), u32>,
+    /// Represents the map mapping a TCP connection to the window scale the source offered in its
+    /// SYN, which is accepted in the ACK/SYN.
+    tcp_send_window_scale_map: HashMap<(u16, SocketAddrV4), u8>,
+    tcp_sequence_map: HashMap<(u16, SocketAddrV4), u32>,
+    tcp_acknowledgement_map: HashMap<(u16, SocketAddrV4), u32>,
+    tcp_window_map: HashMap<(u16, SocketAddrV4), u16>,
+    tcp_cache_map: HashMap<(u16, SocketAddrV4), Cacher>,
+    tcp_cache2_map: HashMap<(u16, SocketAddrV4), Cacher>,
+    /// Represents the map mapping a TCP connection to the MSS clamped for it when it opened.
+    tcp_mss_map: HashMap<(u16, SocketAddrV4), u16>,
+    /// Represents the map mapping a TCP connection to the effective MSS of the source.
+    tcp_peer_mss_map: HashMap<(u16, SocketAddrV4), u16>,
+    /// Represents the map mapping a TCP connection to the congestion state of the payload sent.
+    tcp_congestion_map: HashMap<(u16, SocketAddrV4), Congestion>,
+    /// Represents the TCP connections closed by the proxy whose FIN is sent once the cache is.
+    tcp_fin_pending: HashSet<(u16, SocketAddrV4)>,
+    /// Represents the map mapping a TCP connection to the sequence of the FIN sent.
+    tcp_fin_map: HashMap<(u16, SocketAddrV4), u32>,
+    /// Represents the TCP connections picked up in the middle and still connecting.
+    tcp_pickup_set: HashSet<(u16, SocketAddrV4)>,
+    /// Represents the key of initial sequences of TCP connections.
+    isn_key: Key,
+    /// Represents the start of the clock of initial sequences, which ticks every 4 µs, or none if
+    /// initial sequences are derived from a seed.
+    isn_clock: Option<Instant>,
+}
+
+impl Forwarder {
+    /// Creates a new `Forwarder`.
+    pub fn new(
+        tx: Sender,
+        mtu: u16,
+        local_hardware_addr: HardwareAddr,
+        src_ip_addr: Ipv4Addr,
+        local_ip_addr: Ipv4Addr,
+    ) -> Forwarder {
+        Forwarder {
+            tx,
+            mtu,
+            src_hardware_addr: crate::pcap::HARDWARE_ADDR_UNSPECIFIED,
+            is_src_llc_snap: false,
+            local_hardware_addr,
+            src_ip_addr,
+            local_ip_addr,
+            ipv4_identification_map: HashMap::new(),
+            tcp_send_window_map: HashMap::new(),
+            tcp_send_window_scale_map: HashMap::new(),
+            tcp_sequence_map: HashMap::new(),
+            tcp_acknowledgement_map: HashMap::new(),
+            tcp_window_map: HashMap::new(),
+            tcp_cache_map: HashMap::new(),
+            tcp_cache2_map: HashMap::new(),
+            tcp_mss_map: HashMap::new(),
+            tcp_peer_mss_map: HashMap::new(),
+            tcp_congestion_map: HashMap::new(),
+            tcp_fin_pending: HashSet::new(),
+            tcp_fin_map: HashMap::new(),
+            tcp_pickup_set: HashSet::new(),
+            isn_key: generate_key(),
+            isn_clock: Some(Instant::now()),
+        }
+    }
+
+    /// Sets the seed of initial sequences of TCP connections, which are a function of the seed
+    /// and the connection only. This is insecure, initial sequences become predictable to anyone
+    /// knowing the seed, and is for reproducing runs only.
+    pub fn set_insecure_isn_seed(&mut self, seed: u64) {
+        let mut random = Random::derive(seed, "isn");
+        self.isn_key = [random.next_u64(), random.next_u64()];
+        self.isn_clock = None;
+    }
+
+    /// Get the keyed hash of a TCP connection and the given data.
+    fn hash_connection(&self, dst: SocketAddrV4, src_port: u16, data: &[u8]) -> u32 {
+        let mut buffer = Vec::with_capacity(12 + data.len());
+        buffer.extend_from_slice(&self.src_ip_addr.octets());
+        buffer.extend_from_slice(&src_port.to_be_bytes());
+        buffer.extend_from_slice(&dst.ip().octets());
+        buffer.extend_from_slice(&dst.port().to_be_bytes());
+        buffer.extend_from_slice(data);
+
+        siphash(self.isn_key, &buffer) as u32
+    }
+
+    /// Get the initial sequence of a TCP connection, a SipHash of the connection with the key plus
+    /// the clock, as in RFC 6528.
+    fn get_initial_sequence(&self, dst: SocketAddrV4, src_port: u16) -> u32 {
+        let hash = self.hash_connection(dst, src_port, &[]);
+        let clock = match self.isn_clock {
+            Some(instant) => (instant.elapsed().as_micros() / 4) as u32,
+            None => 0,
+        };
+
+        hash.wrapping_add(clock)
+    }
+
+    /// Get the name and the number of entries of each table of the `Forwarder`, and the bytes
+    /// held by its caches.
+    pub fn get_table_sizes(&self) -> Vec<(&'static str, usize)> {
+        vec![
+            ("identifications", self.ipv4_identification_map.len()),
+            ("send windows", self.tcp_send_window_map.len()),
+            ("send window scales", self.tcp_send_window_scale_map.len()),
+            ("forward sequences", self.tcp_sequence_map.len()),
+            (
+                "forward acknowledgements",
+                self.tcp_acknowledgement_map.len(),
+            ),
+            ("windows", self.tcp_window_map.len()),
+            ("forward caches", self.tcp_cache_map.len()),
+            ("forward caches 2", self.tcp_cache2_map.len()),
+            ("mss", self.tcp_mss_map.len()),
+            ("peer mss", self.tcp_peer_mss_map.len()),
+            ("congestion", self.tcp_congestion_map.len()),
+            ("fins", self.tcp_fin_pending.len() + self.tcp_fin_map.len()),
+            ("pickups", self.tcp_pickup_set.len()),
+            (
+                "cached bytes",
+                self.tcp_cache_map
+                    .values()
+                    .chain(self.tcp_cache2_map.values())
+                    .map(|cache| cache.get_size())
+                    .sum(),
+            ),
+        ]
+    }
+
+    /// Get the MTU of the `Forwarder`.
+    pub fn get_mtu(&self) -> u16 {
+        self.mtu
+    }
+
+    /// Sets the MSS of a TCP connection, which is advertised in its ACK/SYN and limits the size of
+    /// its segments for its whole lifetime.
+    pub fn set_tcp_mss(&mut self, dst: SocketAddrV4, src_port: u16, mss: u16) {
+        let key = (src_port, dst);
+
+        self.tcp_mss_map.insert(key, mss);
+    }
+
+    /// Sets the effective MSS of the source of a TCP connection, which limits the size of segments
+    /// to the source.
+    pub fn set_tcp_peer_mss(&mut self, dst: SocketAddrV4, src_port: u16, mss: u16) {
+        let key = (src_port, dst);
+
+        self.tcp_peer_mss_map.insert(key, mss);
+    }
+
+    /// Marks the TCP connection as picked up in the middle, whose source is answered with an ACK
+    /// instead of an ACK/SYN once connected.
+    pub fn set_tcp_pickup(&mut self, dst: SocketAddrV4, src_port: u16) {
+        self.tcp_pickup_set.insert((src_port, dst));
+    }
+
+    /// Get the source hardware address.
+    pub fn get_src_hardware_addr(&self) -> HardwareAddr {
+        self.src_hardware_addr
+    }
+
+    /// Sets the source hardware address.
+    pub fn set_src_hardware_addr(&mut self, hardware_addr: HardwareAddr) {
+        self.src_hardware_addr = hardware_addr;
+        trace!("set source hardware address to {}", hardware_addr);
+    }
+
+    /// Sets if frames to the source use IEEE 802.3 with LLC and SNAP framing.
+    pub fn set_src_llc_snap(&mut self, is_llc_snap: bool) {
+        self.is_src_llc_snap = is_llc_snap;
+        trace!("set source LLC/SNAP framing to {}", is_llc_snap);
+    }
+
+    /// Sets the local hardware address.
+    pub fn set_local_hardware_addr(&mut self, hardware_addr: HardwareAddr) {
+        self.local_hardware_addr = hardware_addr;
+        trace!("set local hardware address to {}", hardware_addr);
+    }
+
+    /// Get the local hardware address.
+    pub fn get_local_hardware_addr(&self) -> HardwareAddr {
+        self.local_hardware_addr
+    }
+
+    /// Sets the local IP address.
+    pub fn set_local_ip_addr(&mut self, ip_addr: Ipv4Addr) {
+        self.local_ip_addr = ip_addr;
+        trace!("set local IP address to {}", ip_addr);
+    }
+
+    /// Get the local IP address.
+    pub fn get_local_ip_addr(&self) -> Ipv4Addr {
+        self.local_ip_addr
+    }
+
+    fn increase_ipv4_identification(&mut self, ip_addr: Ipv4Addr) {
+        let entry = self.ipv4_identification_map.entry(ip_addr).or_insert(0);
+        *entry = entry.checked_add(1).unwrap_or(0);
+        trace!("increase IPv4 identification of {} to {}", ip_addr, entry);
+    }
+
+    /// Sets the window scale of a TCP connection offered by the source in its SYN. The scale is
+    /// accepted in the ACK/SYN, so the windows the source advertises later are scaled by it.
+    pub fn set_tcp_send_window_scale(&mut self, dst: SocketAddrV4, src_port: u16, shift: u8) {
+        // RFC 7323 limits the shift to 14
+        let shift = min(shift, MAX_WINDOW_SCALE);
+        self.tcp_send_window_scale_map
+            .insert((src_port, dst), shift);
+        trace!(
+            "set TCP send window scale of {} -> {} to {}",
+            src_port,
+            dst,
+            shift,
+        );
+    }
+
+    /// Sets the send window size of a TCP connection from the window field advertised by the
+    /// source, returns if the window changed.
+    pub fn set_tcp_send_window(&mut self, dst: SocketAddrV4, src_port: u16, window: u16) -> bool {
+        let key = (src_port, dst);
+        let shift = *self.tcp_send_window_scale_map.get(&key).unwrap_or(&0);
+        let window = (window as u32) << shift;
+
+        let prev = self.tcp_send_window_map.insert(key, window);
+        trace!(
+            "set TCP send window of {} -> {} to {}",
+            src_port,
+            dst,
+            window,
+        );
+
+        prev != Some(window)
+    }
+
+    /// Resumes the sequence of a TCP connection at the acknowledgement of the source, for
+    /// replying to or picking up a connection whose sequence was never chosen by the forwarder.
+    pub fn resume_tcp_sequence(&mut self, dst: SocketAddrV4, src_port: u16, acknowledgement: u32) {
+        self.tcp_sequence_map
+            .insert((src_port, dst), acknowledgement);
+        trace!(
+            "resume TCP sequence of {} -> {} at {}",
+            dst,
+            src_port,
+            acknowledgement
+        );
+    }
+
+    /// Sets the acknowledgement of a TCP connection.
+    pub fn set_tcp_acknowledgement(&mut self, dst: SocketAddrV4, src_port: u16, sequence: u32) {
+        self.tcp_acknowledgement_map
+            .insert((src_port, dst), sequence);
+        trace!(
+            "set TCP acknowledgement of {} -> {} to {}",
+            dst,
+            src_port,
+            sequence
+        );
+    }
+
+    /// Adds acknowledgement to a TCP connection.
+    pub fn add_tcp_acknowledgement(&mut self, dst: SocketAddrV4, src_port: u16, n: u32) {
+        let entry = self
+            .tcp_acknowledgement_map
+            .entry((src_port, dst))
+            .or_insert(0);
+        *entry = entry
+            .checked_add(n)
+            .unwrap_or_else(|| n - (u32::MAX - *entry));
+        trace!(
+            "add TCP acknowledgement of {} -> {} to {}",
+            dst,
+            src_port,
+            entry
+        );
+    }
+
+    /// Get the acknowledgement of a TCP connection, which is the next sequence expected.
+    pub fn get_tcp_acknowledgement(&self, dst: SocketAddrV4, src_port: u16) -> Option<u32> {
+        self.tcp_acknowledgement_map.get(&(src_port, dst)).cloned()
+    }
+
+    /// Get the send window size of a TCP connection in bytes.
+    pub fn get_tcp_send_window(&self, dst: SocketAddrV4, src_port: u16) -> Option<u32> {
+        self.tcp_send_window_map.get(&(src_port, dst)).cloned()
+    }
+
+    /// Sets the window size of a TCP connection.
+    pub fn set_tcp_window(&mut self, dst: SocketAddrV4, src_port: u16, window: u16) {
+        self.tcp_window_map.insert((src_port, dst), window);
+        trace!("set TCP window of {} -> {} to {}", dst, src_port, window);
+    }
+
+    /// Invalidates TCP cache to the given sequence, the payload acknowledged grows the congestion
+    /// window.
+    pub fn invalidate_cache_to(&mut self, dst: SocketAddrV4, src_port: u16, sequence: u32) {
+        let key = (src_port, dst);
+        if let Some(cache) = self.tcp_cache_map.get_mut(&key) {
+            let size = cache.get_size();
+            cache.invalidate_to(sequence);
+            let acked = size.saturating_sub(cache.get_size());
+            if let Some(congestion) = self.tcp_congestion_map.get_mut(&key) {
+                congestion.on_ack(acked, sequence, cache.get_size() > 0, Instant::now());
+            }
+        }
+        trace!(
+            "invalidate cache {} -> {} to sequence {}",
+            dst,
+            src_port,
+            sequence
+        );
+    }
+
+    /// Removes all information related to a TCP connection.
+    pub fn remove(&mut self, dst: SocketAddrV4, src_port: u16) {
+        let key = (src_port, dst);
+
+        self.tcp_send_window_map.remove(&key);
+        self.tcp_send_window_scale_map.remove(&key);
+        self.tcp_sequence_map.remove(&key);
+        self.tcp_acknowledgement_map.remove(&key);
+        self.tcp_window_map.remove(&key);
+        self.tcp_cache_map.remove(&key);
+        self.tcp_cache2_map.remove(&key);
+        self.tcp_mss_map.remove(&key);
+        self.tcp_peer_mss_map.remove(&key);
+        self.tcp_congestion_map.remove(&key);
+        self.tcp_fin_pending.remove(&key);
+        self.tcp_fin_map.remove(&key);
+        self.tcp_pickup_set.remove(&key);
+        trace!("remove {} -> {}", dst, src_port);
+    }
+
+    /// Get all the TCP connections with any information.
+    pub fn get_tcp_keys(&self) -> HashSet<(u16, SocketAddrV4)> {
+        self.tcp_send_window_map
+            .keys()
+            .chain(self.tcp_send_window_scale_map.keys())
+            .chain(self.tcp_sequence_map.keys())
+            .chain(self.tcp_acknowledgement_map.keys())
+            .chain(self.tcp_window_map.keys())
+            .chain(self.tcp_cache_map.keys())
+            .chain(self.tcp_cache2_map.keys())
+            .chain(self.tcp_mss_map.keys())
+            .chain(self.tcp_peer_mss_map.keys())
+            .chain(self.tcp_congestion_map.keys())
+            .chain(self.tcp_fin_pending.iter())
+            .chain(self.tcp_fin_map.keys())
+            .chain(self.tcp_pickup_set.iter())
+            .cloned()
+            .collect()
+    }
+
+    /// Get the size of the cache of a TCP connection.
+    pub fn get_cache_size(&mut self, dst: SocketAddrV4, src_port: u16) -> usize {
+        let key = (src_port, dst);
+
+        let mut size = 0;
+        if let Some(cache) = self.tcp_cache_map.get(&key) {
+            size += cache.get_size();
+        }
+        if let Some(cache) = self.tcp_cache2_map.get(&key) {
+            size += cache.get_size();
+        }
+
+        size
+    }
+
+    /// Sends an ARP reply packet.
+    pub fn send_arp_reply(&mut self) -> io::Result<()> {
+        let mut builder = self.new_builder(self.src_hardware_addr);
+        builder.set_arp_reply(self.local_ip_addr, self.src_hardware_addr, self.src_ip_addr);
+
+        // Send
+        self.send(&builder, &[])
+    }
+
+    /// Sends a gratuitous ARP reply of the gateway in broadcast, so the source and the other
+    /// hosts of the network update the hardware address of the gateway at once.
+    pub fn send_gratuitous_arp(&mut self) -> io::Result<()> {
+        let mut builder = self.new_builder(HardwareAddr::broadcast());
+        builder.set_arp_reply(self.local_ip_addr, self.src_hardware_addr, self.src_ip_addr);
+
+        // Send
+        self.send(&builder, &[])
+    }
+
+    /// Appends TCP ACK payload to cache.
+    pub fn append_to_cache(
+        &mut self,
+        dst: SocketAddrV4,
+        src_port: u16,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let key = (src_port, dst);
+
+        // TCP sequence
+        let sequence = *self.tcp_sequence_map.get(&key).unwrap_or(&0);
+
+        // Append to cache
+        let cache = self
+            .tcp_cache2_map
+            .entry(key)
+            .or_insert_with(|| Cacher::new_expandable(sequence));
+        cache.append(payload)?;
+
+        self.send_tcp_ack(dst, src_port)
+    }
+
+    /// Resends TCP ACK packets from first (sent) cache, returns the bytes resent.
+    pub fn resend_tcp_ack(&mut self, dst: SocketAddrV4, src_port: u16) -> io::Result<usize> {
+        let key = (src_port, dst);
+
+        // Resend
+        let payload;
+        let sequence;
+        match self.tcp_cache_map.get(&key) {
+            Some(cache) => {
+                match cache.get_all() {
+                    Ok(buffer) => {
+                        payload = buffer;
+                    }
+                    Err(e) => return Err(e),
+                }
+                sequence = cache.get_sequence();
+            }
+            None => return Ok(0),
+        };
+
+        if !payload.is_empty() {
+            self.send_tcp_ack_raw(dst, src_port, sequence, payload.as_slice(), true)?;
+        } else if let Some(sequence) = self.tcp_fin_map.get(&key).cloned() {
+            // The FIN may be lost after all the payload is acknowledged
+            self.send_tcp_ack_fin_at(dst, src_port, sequence)?;
+        }
+
+        Ok(payload.len())
+    }
+
+    /// Resends TCP ACK packets from first (sent) cache for duplicate acknowledgements of the
+    /// source, which halves the congestion window, returns the bytes resent.
+    pub fn fast_retransmit_tcp(&mut self, dst: SocketAddrV4, src_port: u16) -> io::Result<usize> {
+        let key = (src_port, dst);
+        let flight = self
+            .tcp_cache_map
+            .get(&key)
+            .map_or(0, |cache| cache.get_size());
+        if let Some(congestion) = self.tcp_congestion_map.get_mut(&key) {
+            congestion.on_fast_retransmit(flight);
+        }
+
+        self.resend_tcp_ack(dst, src_port)
+    }
+
+    /// Resends TCP ACK packets from first (sent) cache of the connections whose retransmission
+    /// timer expired at the given time, returns the connections and the bytes resent.
+    pub fn retransmit_timed_out(
+        &mut self,
+        now: Instant,
+    ) -> io::Result<Vec<((u16, SocketAddrV4), usize)>> {
+        let keys: Vec<(u16, SocketAddrV4)> = self
+            .tcp_congestion_map
+            .iter()
+            .filter(|(_, congestion)| congestion.is_timed_out(now))
+            .map(|(key, _)| *key)
+            .collect();
+
+        let mut retransmitted = Vec::new();
+        for key in keys {
+            let flight = self
+                .tcp_cache_map
+                .get(&key)
+                .map_or(0, |cache| cache.get_size());
+            let congestion = self.tcp_congestion_map.get_mut(&key).unwrap();
+            if flight == 0 {
+                congestion.stop();
+                continue;
+            }
+            congestion.on_timeout(flight, now);
+            trace!(
+                "TCP retransmission timeout of {} -> {} ({} Bytes)",
+                key.1,
+                key.0,
+                flight
+            );
+            let size = self.resend_tcp_ack(key.1, key.0)?;
+            retransmitted.push((key, size));
+        }
+
+        Ok(retransmitted)
+    }
+
+    /// Get the congestion window, the slow start threshold and the retransmission timeout of a
+    /// TCP connection.
+    pub fn get_tcp_congestion(
+        &self,
+        dst: SocketAddrV4,
+        src_port: u16,
+    ) -> Option<(usize, usize, Duration)> {
+        self.tcp_congestion_map
+            .get(&(src_port, dst))
+            .map(|congestion| {
+                (
+                    congestion.get_cwnd(),
+                    congestion.get_ssthresh(),
+                    congestion.get_rto(),
+                )
+            })
+    }
+
+    /// Get the max size of the payload of a segment of a TCP connection.
+    fn get_max_payload_size(&self, key: &(u16, SocketAddrV4)) -> usize {
+        let header_size = builder::IPV4_HEADER_SIZE + builder::TCP_HEADER_SIZE;
+        let mut max_payload_size = self.mtu as usize - header_size;
+        if let Some(mss) = self.tcp_mss_map.get(key) {
+            max_payload_size = min(max_payload_size, *mss as usize);
+        }
+        if let Some(mss) = self.tcp_peer_mss_map.get(key) {
+            max_payload_size = min(max_payload_size, *mss as usize);
+        }
+
+        max_payload_size
+    }
+
+    /// Sends TCP ACK packets from second (unsent) cache, within the send window and the
+    /// congestion window.
+    pub fn send_tcp_ack(&mut self, dst: SocketAddrV4, src_port: u16) -> io::Result<()> {
+        let key = (src_port, dst);
+
+        if !self.tcp_cache2_map.contains_key(&key) {
+            return self.flush_tcp_fin(dst, src_port);
+        }
+
+        let mss = self.get_max_payload_size(&key);
+        let cwnd = self
+            .tcp_congestion_map
+            .entry(key)
+            .or_insert_with(|| Congestion::new(mss))
+            .get_cwnd();
+        let cache2 = self.tcp_cache2_map.get_mut(&key).unwrap();
+        let sequence = cache2.get_sequence();
+        let window = min(
+            *self.tcp_send_window_map.get(&key).unwrap_or(&0) as usize,
+            cwnd,
+        );
+        if window > 0 {
+            let cache = self
+                .tcp_cache_map
+                .entry(key)
+                .or_insert_with(|| Cacher::new(sequence));
+            let sent_size = cache.get_size();
+            let remain_size = window.saturating_sub(sent_size);
+
+            let size = min(remain_size, cache2.get_size());
+            if size > 0 {
+                let payload = cache2.get(size).unwrap();
+
+                let sequence_tail = sequence
+                    .checked_add(size as u32)
+                    .unwrap_or_else(|| size as u32 - (u32::MAX - sequence));
+                cache2.invalidate_to(sequence_tail);
+                // Push if all the forwarded payload is sent
+                let is_push = cache2.get_size() == 0;
+
+                // Append to cache
+                let cache = self
+                    .tcp_cache_map
+                    .entry(key)
+                    .or_insert_with(|| Cacher::new(sequence));
+                cache.append(&payload)?;
+
+                // Send
+                self.send_tcp_ack_raw(dst, src_port, sequence, &payload, is_push)?;
+                if let Some(congestion) = self.tcp_congestion_map.get_mut(&key) {
+                    congestion.on_send(sequence_tail, Instant::now());
+                }
+            }
+        }
+
+        self.flush_tcp_fin(dst, src_port)
+    }
+
+    /// Closes the TCP connection to the source in order, the ACK/FIN is sent once all the payload
+    /// forwarded before is sent.
+    pub fn queue_tcp_fin(&mut self, dst: SocketAddrV4, src_port: u16) -> io::Result<()> {
+        let key = (src_port, dst);
+        if self.tcp_fin_map.contains_key(&key) {
+            return Ok(());
+        }
+        self.tcp_fin_pending.insert(key);
+
+        self.send_tcp_ack(dst, src_port)
+    }
+
+    /// Sends the ACK/FIN queued if the payload is all sent. The FIN takes a sequence, so later
+    /// packets follow it.
+    fn flush_tcp_fin(&mut self, dst: SocketAddrV4, src_port: u16) -> io::Result<()> {
+        let key = (src_port, dst);
+        if !self.tcp_fin_pending.contains(&key) {
+            return Ok(());
+        }
+        if let Some(cache2) = self.tcp_cache2_map.get(&key) {
+            if cache2.get_size() > 0 {
+                return Ok(());
+            }
+        }
+
+        self.tcp_fin_pending.remove(&key);
+        let sequence = *self.tcp_sequence_map.get(&key).unwrap_or(&0);
+        self.send_tcp_ack_fin_at(dst, src_port, sequence)?;
+        self.tcp_sequence_map.insert(key, sequence.wrapping_add(1));
+        self.tcp_fin_map.insert(key, sequence);
+        trace!("send FIN {} -> {} at {}", dst, src_port, sequence);
+
+        Ok(())
+    }
+
+    /// Returns if the ACK/FIN of the TCP connection is sent or queued.
+    pub fn is_tcp_fin_sent(&self, dst: SocketAddrV4, src_port: u16) -> bool {
+        let key = (src_port, dst);
+
+        self.tcp_fin_map.contains_key(&key) || self.tcp_fin_pending.contains(&key)
+    }
+
+    /// Returns if the acknowledgement of the source covers the ACK/FIN sent.
+    pub fn is_tcp_fin_acknowledged(
+        &self,
+        dst: SocketAddrV4,
+        src_port: u16,
+        acknowledgement: u32,
+    ) -> bool {
+        match self.tcp_fin_map.get(&(src_port, dst)) {
+            Some(sequence) => acknowledgement == sequence.wrapping_add(1),
+            None => false,
+        }
+    }
+
+    /// Sends TCP ACK packets of the payload, the last packet is sent with PSH if `is_push` is
+    /// set.
+    fn send_tcp_ack_raw(
+        &mut self,
+        dst: SocketAddrV4,
+        src_port: u16,
+        sequence: u32,
+        payload: &[u8],
+        is_push: bool,
+    ) -> io::Result<()> {
+        let key = (src_port, dst);
+
+        // Segmentation
+        let max_payload_size = self.get_max_payload_size(&key);
+        let mut i = 0;
+        while max_payload_size * i < payload.len() {
+            let length = min(max_payload_size, payload.len() - i * max_payload_size);
+            let is_last = i * max_payload_size + length >= payload.len();
+            let payload = &payload[i * max_payload_size..i * max_payload_size + length];
+            let sequence = sequence
+                .checked_add((i * max_payload_size) as u32)
+                .unwrap_or_else(|| (i * max_payload_size) as u32 - (u32::MAX - sequence));
+
+            // TCP
+            let flags = if is_push && is_last {
+                TcpFlags::ACK | TcpFlags::PSH
+            } else {
+                TcpFlags::ACK
+            };
+            let mut builder = self.new_ipv4_builder(*dst.ip());
+            builder.set_tcp(
+                dst.port(),
+                src_port,
+                sequence,
+                *self.tcp_acknowledgement_map.get(&key).unwrap_or(&0),
+                *self.tcp_window_map.get(&key).unwrap_or(&65535),
+                flags,
+            );
+
+            // Send
+            self.send_ipv4(&builder, *dst.ip(), payload)?;
+
+            // Update TCP sequence
+            let next_sequence = sequence
+                .checked_add(length as u32)
+                .unwrap_or_else(|| length as u32 - (u32::MAX - sequence));
+            let record_sequence = *self.tcp_sequence_map.get(&key).unwrap_or(&0);
+            let sub_sequence = next_sequence
+                .checked_sub(record_sequence)
+                .unwrap_or_else(|| next_sequence + (u32::MAX - record_sequence));
+            if (sub_sequence as usize) < MAX_U32_WINDOW_SIZE {
+                self.tcp_sequence_map.insert(key, next_sequence);
+            }
+
+            i += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Sends an TCP ACK packet without payload.
+    pub fn send_tcp_ack_0(&mut self, dst: SocketAddrV4, src_port: u16) -> io::Result<()> {
+        self.send_tcp_0(dst, src_port, TcpFlags::ACK)
+    }
+
+    /// Sends an TCP ACK/SYN packet.
+    pub fn send_tcp_ack_syn(&mut self, dst: SocketAddrV4, src_port: u16) -> io::Result<()> {
+        let key = (src_port, dst);
+
+        // The initial sequence of a new connection
+        let sequence = self.get_initial_sequence(dst, src_port);
+        self.tcp_sequence_map.entry(key).or_insert(sequence);
+        self.send_tcp_0(dst, src_port, TcpFlags::ACK | TcpFlags::SYN)?;
+
+        // Update TCP sequence
+        let tcp_sequence_entry = self.tcp_sequence_map.entry(key).or_insert(0);
+        *tcp_sequence_entry = tcp_sequence_entry.checked_add(1).unwrap_or(0);
+
+        Ok(())
+    }
+
+    /// Sends an TCP ACK/SYN packet whose initial sequence is a SYN cookie of the SYN of the given
+    /// sequence, which encodes the MSS of the source, so the connection needs no state until the
+    /// source acknowledges it.
+    pub fn send_tcp_ack_syn_cookie(
+        &mut self,
+        dst: SocketAddrV4,
+        src_port: u16,
+        sequence: u32,
+        mss: u16,
+    ) -> io::Result<()> {
+        let index = TCP_COOKIE_MSS
+            .iter()
+            .rposition(|cookie_mss| *cookie_mss <= mss)
+            .unwrap_or(0);
+        let cookie = self.get_tcp_cookie(dst, src_port, sequence, index, self.get_cookie_period());
+        self.tcp_sequence_map.insert((src_port, dst), cookie);
+
+        self.send_tcp_0(dst, src_port, TcpFlags::ACK | TcpFlags::SYN)
+    }
+
+    /// Checks the acknowledgement of a TCP ACK of a connection without any state against the SYN
+    /// cookie of its SYN, returns the MSS of the source encoded if it is valid.
+    pub fn check_tcp_cookie(
+        &self,
+        dst: SocketAddrV4,
+        src_port: u16,
+        sequence: u32,
+        acknowledgement: u32,
+    ) -> Option<u16> {
+        let cookie = acknowledgement.wrapping_sub(1);
+        let sequence = sequence.wrapping_sub(1);
+        let index = (cookie & 3) as usize;
+        let period = self.get_cookie_period();
+
+        if self.get_tcp_cookie(dst, src_port, sequence, index, period) == cookie
+            || self.get_tcp_cookie(dst, src_port, sequence, index, period.wrapping_sub(1)) == cookie
+        {
+            Some(TCP_COOKIE_MSS[index])
+        } else {
+            None
+        }
+    }
+
+    /// Get the SYN cookie of a TCP connection, a hash of the connection and the SYN with the
+    /// secret in its period, whose lowest 2 bits are the index of the MSS.
+    fn get_tcp_cookie(
+        &self,
+        dst: SocketAddrV4,
+        src_port: u16,
+        sequence: u32,
+        index: usize,
+        period: u64,
+    ) -> u32 {
+        let mut syn = [0u8; 16];
+        syn[..4].copy_from_slice(&sequence.to_be_bytes());
+        syn[4..8].copy_from_slice(&(index as u32).to_be_bytes());
+        syn[8..].copy_from_slice(&period.to_be_bytes());
+        let hash = self.hash_connection(dst, src_port, &syn);
+
+        (hash & !3) | index as u32
+    }
+
+    /// Get the current period of TCP SYN cookies.
+    fn get_cookie_period(&self) -> u64 {
+        match self.isn_clock {
+            Some(instant) => instant.elapsed().as_secs() / TCP_COOKIE_PERIOD,
+            None => 0,
+        }
+    }
+
+    /// Sends an TCP ACK/RST packet.
+    pub fn send_tcp_ack_rst(&mut self, dst: SocketAddrV4, src_port: u16) -> io::Result<()> {
+        self.send_tcp_0(dst, src_port, TcpFlags::ACK | TcpFlags::RST)
+    }
+
+    /// Sends an TCP ACK/FIN packet.
+    pub fn send_tcp_ack_fin(&mut self, dst: SocketAddrV4, src_port: u16) -> io::Result<()> {
+        self.send_tcp_0(dst, src_port, TcpFlags::ACK | TcpFlags::FIN)
+    }
+
+    /// Sends an TCP ACK/FIN packet of the given sequence.
+    fn send_tcp_ack_fin_at(
+        &mut self,
+        dst: SocketAddrV4,
+        src_port: u16,
+        sequence: u32,
+    ) -> io::Result<()> {
+        let key = (src_port, dst);
+
+        // TCP
+        let mut builder = self.new_ipv4_builder(*dst.ip());
+        builder.set_tcp(
+            dst.port(),
+            src_port,
+            sequence,
+            *self.tcp_acknowledgement_map.get(&key).unwrap_or(&0),
+            *self.tcp_window_map.get(&key).unwrap_or(&65535),
+            TcpFlags::ACK | TcpFlags::FIN,
+        );
+
+        // Send
+        self.send_ipv4(&builder, *dst.ip(), &[])
+    }
+
+    /// Sends an TCP RST packet.
+    pub fn send_tcp_rst(&mut self, dst: SocketAddrV4, src_port: u16) -> io::Result<()> {
+        self.send_tcp_0(dst, src_port, TcpFlags::RST)
+    }
+
+    /// Sends a TCP RST packet refusing a segment to a port without a listener, with the given
+    /// sequence, and the acknowledgement if the segment has no ACK, instead of the ones of a
+    /// connection.
+    pub fn send_tcp_refusal(
+        &mut self,
+        dst: SocketAddrV4,
+        src_port: u16,
+        sequence: u32,
+        acknowledgement: Option<u32>,
+    ) -> io::Result<()> {
+        let (acknowledgement, flags) = match acknowledgement {
+            Some(acknowledgement) => (acknowledgement, TcpFlags::ACK | TcpFlags::RST),
+            None => (0, TcpFlags::RST),
+        };
+        let mut builder = self.new_ipv4_builder(*dst.ip());
+        builder.set_tcp(dst.port(), src_port, sequence, acknowledgement, 0, flags);
+
+        // Send
+        self.send_ipv4(&builder, *dst.ip(), &[])
+    }
+
+    /// Sends a TCP packet without payload of the given flags, with the sequence, the window and
+    /// the acknowledgement if the flags have ACK of the connection.
+    fn send_tcp_0(&mut self, dst: SocketAddrV4, src_port: u16, flags: u16) -> io::Result<()> {
+        let key = (src_port, dst);
+        let acknowledgement = if flags & TcpFlags::ACK != 0 {
+            *self.tcp_acknowledgement_map.get(&key).unwrap_or(&0)
+        } else {
+            0
+        };
+
+        // TCP
+        let mut builder = self.new_ipv4_builder(*dst.ip());
+        builder.set_tcp(
+            dst.port(),
+            src_port,
+            *self.tcp_sequence_map.get(&key).unwrap_or(&0),
+            acknowledgement,
+            *self.tcp_window_map.get(&key).unwrap_or(&65535),
+            flags,
+        );
+        if flags & TcpFlags::SYN != 0 {
+            if let Some(mss) = self.tcp_mss_map.get(&key) {
+                builder.set_mss(*mss);
+            }
+            // Windows to the source fit in 16 bits, so the window scale is accepted without
+            // scaling them
+            if self.tcp_send_window_scale_map.contains_key(&key) {
+                builder.set_wscale(0);
+            }
+        }
+
+        // Send
+        self.send_ipv4(&builder, *dst.ip(), &[])
+    }
+
+    /// Sends UDP packets. A datagram exceeding the MTU is serialized as a whole and sent in IPv4
+    /// fragments, so its length and checksum cover the whole payload.
+    pub fn send_udp(&mut self, dst: SocketAddrV4, src_port: u16, payload: &[u8]) -> io::Result<()> {
+        // UDP
+        let mut builder = self.new_ipv4_builder(*dst.ip());
+        builder.set_udp(dst.port(), src_port);
+
+        let size = builder::UDP_HEADER_SIZE + payload.len();
+        if size <= self.mtu as usize - builder::IPV4_HEADER_SIZE {
+            return self.send_ipv4(&builder, *dst.ip(), payload);
+        }
+
+        // Serialize
+        let datagram = builder.build_transport(payload)?;
+
+        // Fragmentation
+        let mut n = 0;
+        while n < size {
+            let mut length = min(size - n, self.mtu as usize - builder::IPV4_HEADER_SIZE);
+            let mut remain = size - n - length;
+
+            // Alignment
+            if remain > 0 {
+                length = length / 8 * 8;
+                remain = size - n - length;
+            }
+
+            // Leave at least 8 Bytes for last fragment
+            if remain > 0 && remain < 8 {
+                length -= 8;
+                remain += 8;
+            }
+
+            // Send
+            let mut builder = self.new_ipv4_builder(*dst.ip());
+            builder.set_fragment(LayerTypes::Udp, (n / 8) as u16, remain > 0);
+            self.send(&builder, &datagram[n..n + length])?;
+
+            n += length;
+        }
+
+        // Update IPv4 identification
+        self.increase_ipv4_identification(*dst.ip());
+
+        Ok(())
+    }
+
+    /// Sends an ICMPv4 echo reply packet from the given IP address with the given TTL.
+    pub fn send_icmpv4_echo_reply(
+        &mut self,
+        src_ip_addr: Ipv4Addr,
+        ttl: u8,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        // ICMPv4
+        let mut builder = self.new_ipv4_builder(src_ip_addr);
+        builder.set_ttl(ttl);
+        builder.set_icmpv4_echo_reply();
+
+        self.send_icmpv4(&builder, src_ip_addr, payload)
+    }
+
+    /// Sends an ICMPv4 port unreachable packet from the given IP address, quoting the IPv4 header
+    /// and the first 8 Bytes of the datagram refused.
+    pub fn send_icmpv4_port_unreachable(
+        &mut self,
+        src_ip_addr: Ipv4Addr,
+        quote: &[u8],
+    ) -> io::Result<()> {
+        // ICMPv4
+        let mut builder = self.new_ipv4_builder(src_ip_addr);
+        builder.set_icmpv4_port_unreachable();
+        let mut payload = vec![0u8; 4];
+        payload.extend_from_slice(quote);
+
+        self.send_icmpv4(&builder, src_ip_addr, &payload)
+    }
+
+    fn send_icmpv4(
+        &mut self,
+        builder: &Builder,
+        src_ip_addr: Ipv4Addr,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        // ICMPv4 packets are not fragmented
+        if builder.get_ip_size(payload.len()) > self.mtu as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "payload too big",
+            ));
+        }
+
+        // Send
+        self.send_ipv4(builder, src_ip_addr, payload)
+    }
+
+    /// Sends an IPv4 packet of the source to the given hardware address of the real gateway in an
+    /// Ethernet II frame. Replies of the gateway go to the source directly, so the address of the
+    /// source must be routable on the network of the gateway. A packet reassembled from fragments
+    /// is sent as a whole, with its header fixed.
+    pub fn send_bridged(&mut self, hardware_addr: HardwareAddr, packet: &[u8]) -> io::Result<()> {
+        let header_size = (packet[0] & 0x0f) as usize * 4;
+        let mut frame = Vec::with_capacity(14 + packet.len());
+        for addr in &[hardware_addr, self.local_hardware_addr] {
+            frame.extend_from_slice(&[addr.0, addr.1, addr.2, addr.3, addr.4, addr.5]);
+        }
+        frame.extend_from_slice(&EtherTypes::Ipv4.0.to_be_bytes());
+        frame.extend_from_slice(packet);
+
+        // Total length, flags and fragment offset keeping DF, and checksum
+        let header = &mut frame[14..14 + header_size];
+        header[2..4].copy_from_slice(&(packet.len() as u16).to_be_bytes());
+        header[6] &= 0x40;
+        header[7] = 0;
+        let checksum = builder::checksum(header, 5);
+        header[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+        // Send
+        self.tx.send_to(&frame, None).unwrap_or(Ok(()))?;
+        debug!(
+            "send to gateway: {} ({} Bytes)",
+            hardware_addr,
+            packet.len()
+        );
+
+        Ok(())
+    }
+
+    /// Sends an IGMP message from the local IP address to the given group.
+    pub fn send_igmp(&mut self, group: Ipv4Addr, message: &[u8]) -> io::Result<()> {
+        let octets = igmp::get_hardware_addr(group);
+        let hardware_addr = HardwareAddr::new(
+            octets[0], octets[1], octets[2], octets[3], octets[4], octets[5],
+        );
+        let mut builder = self.new_builder(hardware_addr);
+        builder.set_ipv4(
+            self.get_ipv4_identification(group),
+            self.local_ip_addr,
+            group,
+        );
+        builder.set_igmp();
+
+        // Send
+        self.send_ipv4(&builder, group, message)
+    }
+
+    /// Sends a UDP packet from the given address to the given port of the broadcast address, for
+    /// devices without an address yet.
+    pub fn send_udp_broadcast(
+        &mut self,
+        src: SocketAddrV4,
+        dst_port: u16,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let dst_ip_addr = Ipv4Addr::BROADCAST;
+
+        // UDP
+        let mut builder = self.new_builder(HardwareAddr::broadcast());
+        builder.set_ipv4(
+            self.get_ipv4_identification(dst_ip_addr),
+            *src.ip(),
+            dst_ip_addr,
+        );
+        builder.set_udp(src.port(), dst_port);
+
+        // Broadcasts are not fragmented
+        if builder.get_ip_size(payload.len()) > self.mtu as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "payload too big",
+            ));
+        }
+
+        // Send
+        self.send_ipv4(&builder, dst_ip_addr, payload)
+    }
+
+    /// Sends a TCP segment from the given address of the gateway, of the connection handled by a
+    /// responder instead of a proxy.
+    pub fn send_tcp_segment(
+        &mut self,
+        dst: SocketAddrV4,
+        src_port: u16,
+        segment: &crate::console::Segment,
+    ) -> io::Result<()> {
+        let mut builder = self.new_ipv4_builder(*dst.ip());
+        builder.set_tcp(
+            dst.port(),
+            src_port,
+            segment.sequence,
+            segment.acknowledgement,
+            u16::MAX,
+            segment.flags,
+        );
+        if segment.flags & TcpFlags::SYN != 0 {
+            builder.set_mss(self.mtu - TCP_HEADERS_SIZE);
+        }
+
+        // Send
+        self.send_ipv4(&builder, *dst.ip(), &segment.payload)
+    }
+
+    fn get_ipv4_identification(&self, ip_addr: Ipv4Addr) -> u16 {
+        *self.ipv4_identification_map.get(&ip_addr).unwrap_or(&0)
+    }
+
+    /// Creates a `Builder` of a frame from the local hardware address to the given one, in the
+    /// framing of the source.
+    fn new_builder(&self, hardware_addr: HardwareAddr) -> Builder {
+        let mut builder = Builder::new(self.local_hardware_addr, hardware_addr);
+        builder.set_llc_snap(self.is_src_llc_snap);
+
+        builder
+    }
+
+    /// Creates a `Builder` of an IPv4 packet from the given IP address to the source.
+    fn new_ipv4_builder(&self, ip_addr: Ipv4Addr) -> Builder {
+        let mut builder = self.new_builder(self.src_hardware_addr);
+        builder.set_ipv4(
+            self.get_ipv4_identification(ip_addr),
+            ip_addr,
+            self.src_ip_addr,
+        );
+
+        builder
+    }
+
+    /// Sends an IPv4 packet, and moves on the IPv4 identification of the given IP address.
+    fn send_ipv4(
+        &mut self,
+        builder: &Builder,
+        ip_addr: Ipv4Addr,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        self.send(builder, payload)?;
+
+        // Update IPv4 identification
+        self.increase_ipv4_identification(ip_addr);
+
+        Ok(())
+    }
+
+    fn send(&mut self, builder: &Builder, payload: &[u8]) -> io::Result<()> {
+        // Serialize
+        let frame = builder.build(payload)?;
+
+        // Send
+        self.tx.send_to(&frame, None).unwrap_or(Ok(()))?;
+        debug!(
+            "send to pcap: {} ({} + {} Bytes)",
+            builder.brief(),
+            builder.get_size(),
+            payload.len()
+        );
+
+        Ok(())
+    }
+}
+
+/// Get the IPv4 destination of a payload forwarded to the source, which only speaks IPv4.
+fn to_ipv4(dst: SocketAddr) -> io::Result<SocketAddrV4> {
+    match dst {
+        SocketAddr::V4(dst) => Ok(dst),
+        SocketAddr::V6(dst) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("cannot forward from IPv6 {} to an IPv4 source", dst),
+        )),
+    }
+}
+
+impl Forward for Forwarder {
+    fn forward_tcp(&mut self, dst: SocketAddr, src_port: u16, payload: &[u8]) -> io::Result<()> {
+        self.append_to_cache(to_ipv4(dst)?, src_port, payload)
+    }
+
+    fn forward_udp(&mut self, dst: SocketAddr, src_port: u16, payload: &[u8]) -> io::Result<()> {
+        self.send_udp(to_ipv4(dst)?, src_port, payload)
+    }
+
+    fn get_tcp_backlog(&mut self, dst: SocketAddr, src_port: u16) -> (usize, u32) {
+        let dst = match to_ipv4(dst) {
+            Ok(dst) => dst,
+            Err(_) => return (0, 0),
+        };
+        let window = self.get_tcp_send_window(dst, src_port).unwrap_or(0);
+
+        (self.get_cache_size(dst, src_port), window)
+    }
+
+    fn forward_tcp_connect(
+        &mut self,
+        dst: SocketAddr,
+        src_port: u16,
+        is_connected: bool,
+    ) -> io::Result<()> {
+        let dst = to_ipv4(dst)?;
+        if is_connected {
+            if self.tcp_pickup_set.remove(&(src_port, dst)) {
+                // The source has completed its handshake long before
+                return self.send_tcp_ack_0(dst, src_port);
+            }
+
+            // Send ACK/SYN
+            self.send_tcp_ack_syn(dst, src_port)
+        } else {
+            // Send ACK/RST
+            let result = self.send_tcp_ack_rst(dst, src_port);
+
+            // Clean up
+            self.remove(dst, src_port);
+
+            result
+        }
+    }
+
+    fn forward_tcp_close(
+        &mut self,
+        dst: SocketAddr,
+        src_port: u16,
+        is_reset: bool,
+    ) -> io::Result<()> {
+        let dst = to_ipv4(dst)?;
+        if is_reset {
+            // Send ACK/RST
+            let result = self.send_tcp_ack_rst(dst, src_port);
+
+            // Clean up
+            self.remove(dst, src_port);
+
+            result
+        } else {
+            self.queue_tcp_fin(dst, src_port)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::Indicator;
+    use crate::testing;
+    use std::sync::mpsc::Receiver;
+
+    /// Represents a `Forward` relying on the default backlog and close.
+    struct NullForward;
+
+    impl Forward for NullForward {
+        fn forward_tcp(&mut self, _: SocketAddr, _: u16, _: &[u8]) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn forward_udp(&mut self, _: SocketAddr, _: u16, _: &[u8]) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn forward_tcp_connect(&mut self, _: SocketAddr, _: u16, _: bool) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_forward_defaults() {
+        let dst = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 80));
+        let mut forward = NullForward;
+        assert_eq!(forward.get_tcp_backlog(dst, 50000), (0, 0));
+        assert!(forward.forward_tcp_close(dst, 50000, true).is_ok());
+    }
+
+    #[test]
+    fn test_send_window_scale() {
+        let (mut forwarder, rx) = testing::forwarder(1500);
+        let dst = SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 80);
+
+        // Without a window scale, windows are taken as they are
+        assert!(forwarder.set_tcp_send_window(dst, 50000, 512));
+        assert!(!forwarder.set_tcp_send_window(dst, 50000, 512));
+        assert_eq!(forwarder.get_tcp_send_window(dst, 50000), Some(512));
+        forwarder.send_tcp_ack_syn(dst, 50000).unwrap();
+        let frame = rx.try_recv().unwrap();
+        let indicator = Indicator::from(&frame).unwrap();
+        assert_eq!(indicator.get_tcp().unwrap().get_wscale(), None);
+
+        // The window scale of the source is clamped, accepted and applied
+        forwarder.remove(dst, 50000);
+        forwarder.set_tcp_send_window_scale(dst, 50000, 15);
+        assert!(forwarder.set_tcp_send_window(dst, 50000, 512));
+        assert_eq!(
+            forwarder.get_tcp_backlog(SocketAddr::V4(dst), 50000),
+            (0, 512 << MAX_WINDOW_SCALE)
+        );
+        forwarder.send_tcp_ack_syn(dst, 50000).unwrap();
+        let frame = rx.try_recv().unwrap();
+        let indicator = Indicator::from(&frame).unwrap();
+        assert_eq!(indicator.get_tcp().unwrap().get_wscale(), Some(0));
+        assert!(indicator.validate(&frame, true).is_ok());
+
+        forwarder.remove(dst, 50000);
+        assert_eq!(forwarder.get_tcp_keys().len(), 0);
+    }
+
+    /// Get the initial sequence of the ACK/SYN of the forwarder to the source port.
+    fn get_initial_sequence(forwarder: &mut Forwarder, rx: &Receiver<Vec<u8>>, port: u16) -> u32 {
+        let dst = SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 80);
+        forwarder.send_tcp_ack_syn(dst, port).unwrap();
+        let frame = rx.try_recv().unwrap();
+        let indicator = Indicator::from(&frame).unwrap();
+
+        indicator.get_tcp().unwrap().get_sequence()
+    }
+
+    #[test]
+    fn test_initial_sequence() {
+        // Initial sequences are secret, with a random secret of each forwarder
+        let (mut forwarder, rx) = testing::forwarder(1500);
+        let (mut other, other_rx) = testing::forwarder(1500);
+        let sequences: Vec<u32> = (50000..50004)
+            .map(|port| get_initial_sequence(&mut forwarder, &rx, port))
+            .collect();
+        let other_sequences: Vec<u32> = (50000..50004)
+            .map(|port| get_initial_sequence(&mut other, &other_rx, port))
+            .collect();
+        assert_ne!(sequences, other_sequences);
+        assert!(sequences.windows(2).all(|pair| pair[0] != pair[1]));
+
+        // Insecure initial sequences are reproduced with the same seed only
+        let mut sequences = Vec::new();
+        for seed in &[42, 42, 43] {
+            let (mut forwarder, rx) = testing::forwarder(1500);
+            forwarder.set_insecure_isn_seed(*seed);
+            let sequence: Vec<u32> = (50000..50004)
+                .map(|port| get_initial_sequence(&mut forwarder, &rx, port))
+                .collect();
+            sequences.push(sequence);
+        }
+        assert_eq!(sequences[0], sequences[1]);
+        assert_ne!(sequences[0], sequences[2]);
+    }
+
+    #[test]
+    fn test_send_llc_snap() {
+        let (mut forwarder, rx) = testing::forwarder(1500);
+        forwarder.set_src_llc_snap(true);
+        let dst = SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 53);
+        forwarder.send_udp(dst, 50000, b"odd").unwrap();
+
+        // The source is answered in its framing, whose length excludes the padding
+        let frame = rx.try_recv().unwrap();
+        assert_eq!(frame.len(), builder::MINIMUM_PACKET_SIZE);
+        assert_eq!(u16::from_be_bytes([frame[12], frame[13]]), 8 + 20 + 8 + 3);
+        let indicator = Indicator::from(&frame).unwrap();
+        assert!(indicator.get_ethernet().unwrap().is_llc_snap());
+        assert_eq!(indicator.get_udp().unwrap().get_src(), 53);
+        assert!(indicator.validate(&frame, true).is_ok());
+        let start = indicator.get_size();
+        assert_eq!(&frame[start..start + 3], b"odd");
+    }
+
+    #[test]
+    fn test_retransmission_timeout() {
+        let dst = SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 80);
+        let (mut forwarder, rx) = testing::forwarder(1500);
+        forwarder.set_tcp_send_window(dst, 50000, 65535);
+        forwarder
+            .append_to_cache(dst, 50000, &[0x5a; 3000])
+            .unwrap();
+        let first = rx.try_iter().next().unwrap();
+        let sequence = Indicator::from(&first)
+            .unwrap()
+            .get_tcp()
+            .unwrap()
+            .get_sequence();
+        assert_eq!(rx.try_iter().count(), 2);
+
+        // Nothing is resent before the timeout
+        let now = Instant::now();
+        assert!(forwarder.retransmit_timed_out(now).unwrap().is_empty());
+        assert_eq!(rx.try_iter().count(), 0);
+
+        // The payload in flight is resent from a segment, and the timeout backs off
+        let later = now + Duration::from_secs(2);
+        let retransmitted = forwarder.retransmit_timed_out(later).unwrap();
+        assert_eq!(retransmitted.len(), 1);
+        assert_eq!(retransmitted[0].0, (50000, dst));
+        let resent = rx.try_iter().next().unwrap();
+        let indicator = Indicator::from(&resent).unwrap();
+        assert_eq!(indicator.get_tcp().unwrap().get_sequence(), sequence);
+        let (cwnd, _, rto) = forwarder.get_tcp_congestion(dst, 50000).unwrap();
+        assert_eq!(cwnd, 1460);
+        assert_eq!(rto, Duration::from_secs(2));
+        assert!(forwarder.retransmit_timed_out(later).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,602 @@
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+
+use crate::stats::{self, FlowStats, Snapshot};
+
+/// Represents the first byte of a binary frame, which never starts a JSON or text message, so a
+/// reader tells binary clients from the others by the first byte.
+pub const FRAME_MAGIC: u8 = 0xb2;
+
+/// Represents the version of the framing and the encoding of values.
+pub const FRAME_VERSION: u8 = 1;
+
+/// Represents the size of the header of a frame.
+pub const HEADER_SIZE: usize = 8;
+
+/// Represents the max size of the payload of a frame.
+pub const MAX_PAYLOAD_SIZE: usize = 16 * 1024 * 1024;
+
+/// Represents the max depth of nested values.
+const MAX_DEPTH: usize = 16;
+
+/// Represents the type of a message. The discriminants are stable and never reused, so third-party
+/// clients can be written against them.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[repr(u8)]
+pub enum MessageType {
+    /// An error, whose payload is a string.
+    Error = 0,
+    /// A request for the statistics, whose payload is null.
+    StatsRequest = 1,
+    /// The statistics, whose payload is a map of the version, the time of the last update and
+    /// the counters by name.
+    Stats = 2,
+    /// A request for the flows, whose payload is null.
+    FlowsRequest = 3,
+    /// The flows, whose payload is a list of maps of the protocol, the source port, the
+    /// destination and the bytes of each flow.
+    Flows = 4,
+    /// A heartbeat of an instance in the warm standby, whose payload is a map of the role, the
+    /// priority, the nonce and the state replicated by the active instance.
+    Heartbeat = 5,
+    /// A step of the handoff from an old process to a new one, whose payload is a map of the
+    /// step, and the states released by the old process in the last step.
+    Handoff = 6,
+}
+
+impl MessageType {
+    /// Get the `MessageType` of the discriminant, or `None` if it is unknown.
+    pub fn from_u8(value: u8) -> Option<MessageType> {
+        match value {
+            0 => Some(MessageType::Error),
+            1 => Some(MessageType::StatsRequest),
+            2 => Some(MessageType::Stats),
+            3 => Some(MessageType::FlowsRequest),
+            4 => Some(MessageType::Flows),
+            5 => Some(MessageType::Heartbeat),
+            6 => Some(MessageType::Handoff),
+            _ => None,
+        }
+    }
+}
+
+/// Represents a self-describing value in the payload of a message. Each value starts with a tag
+/// byte, integers are LEB128 varints, and lengths and counts precede strings, bytes, lists and
+/// maps.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Uint(u64),
+    /// Represents a signed integer, encoded in zigzag.
+    Int(i64),
+    Bytes(Vec<u8>),
+    Str(String),
+    List(Vec<Value>),
+    /// Represents a map with string keys, encoded in the order of keys.
+    Map(BTreeMap<String, Value>),
+}
+
+const TAG_NULL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_UINT: u8 = 3;
+const TAG_INT: u8 = 4;
+const TAG_BYTES: u8 = 5;
+const TAG_STR: u8 = 6;
+const TAG_LIST: u8 = 7;
+const TAG_MAP: u8 = 8;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn encode_varint(mut value: u64, buffer: &mut Vec<u8>) {
+    while value >= 0x80 {
+        buffer.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+fn decode_varint(buffer: &[u8], pos: &mut usize) -> io::Result<u64> {
+    let mut value: u64 = 0;
+    for i in 0..10 {
+        let byte = *buffer
+            .get(*pos)
+            .ok_or_else(|| invalid("unexpected end of value"))?;
+        *pos += 1;
+        if i == 9 && byte > 1 {
+            return Err(invalid("varint overflows"));
+        }
+        value |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    Err(invalid("varint overflows"))
+}
+
+/// Decodes a length or a count, which cannot exceed the remaining bytes since each item takes at
+/// least a byte.
+fn decode_len(buffer: &[u8], pos: &mut usize) -> io::Result<usize> {
+    let len = decode_varint(buffer, pos)?;
+    if len > (buffer.len() - *pos) as u64 {
+        return Err(invalid("length exceeds the payload"));
+    }
+
+    Ok(len as usize)
+}
+
+impl Value {
+    /// Encodes the value to the end of the buffer.
+    pub fn encode(&self, buffer: &mut Vec<u8>) {
+        match self {
+            Value::Null => buffer.push(TAG_NULL),
+            Value::Bool(false) => buffer.push(TAG_FALSE),
+            Value::Bool(true) => buffer.push(TAG_TRUE),
+            Value::Uint(value) => {
+                buffer.push(TAG_UINT);
+                encode_varint(*value, buffer);
+            }
+            Value::Int(value) => {
+                buffer.push(TAG_INT);
+                encode_varint(((*value << 1) ^ (*value >> 63)) as u64, buffer);
+            }
+            Value::Bytes(bytes) => {
+                buffer.push(TAG_BYTES);
+                encode_varint(bytes.len() as u64, buffer);
+                buffer.extend_from_slice(bytes);
+            }
+            Value::Str(s) => {
+                buffer.push(TAG_STR);
+                encode_varint(s.len() as u64, buffer);
+                buffer.extend_from_slice(s.as_bytes());
+            }
+            Value::List(values) => {
+                buffer.push(TAG_LIST);
+                encode_varint(values.len() as u64, buffer);
+                for value in values {
+                    value.encode(buffer);
+                }
+            }
+            Value::Map(map) => {
+                buffer.push(TAG_MAP);
+                encode_varint(map.len() as u64, buffer);
+                for (key, value) in map {
+                    encode_varint(key.len() as u64, buffer);
+                    buffer.extend_from_slice(key.as_bytes());
+                    value.encode(buffer);
+                }
+            }
+        }
+    }
+
+    /// Decodes a value which takes the whole buffer.
+    pub fn decode(buffer: &[u8]) -> io::Result<Value> {
+        let mut pos = 0;
+        let value = Value::decode_at(buffer, &mut pos, 0)?;
+        if pos != buffer.len() {
+            return Err(invalid("trailing bytes after value"));
+        }
+
+        Ok(value)
+    }
+
+    fn decode_at(buffer: &[u8], pos: &mut usize, depth: usize) -> io::Result<Value> {
+        if depth >= MAX_DEPTH {
+            return Err(invalid("value is nested too deep"));
+        }
+        let tag = *buffer
+            .get(*pos)
+            .ok_or_else(|| invalid("unexpected end of value"))?;
+        *pos += 1;
+
+        match tag {
+            TAG_NULL => Ok(Value::Null),
+            TAG_FALSE => Ok(Value::Bool(false)),
+            TAG_TRUE => Ok(Value::Bool(true)),
+            TAG_UINT => Ok(Value::Uint(decode_varint(buffer, pos)?)),
+            TAG_INT => {
+                let value = decode_varint(buffer, pos)?;
+                Ok(Value::Int(((value >> 1) as i64) ^ -((value & 1) as i64)))
+            }
+            TAG_BYTES => {
+                let len = decode_len(buffer, pos)?;
+                let bytes = buffer[*pos..*pos + len].to_vec();
+                *pos += len;
+                Ok(Value::Bytes(bytes))
+            }
+            TAG_STR => Ok(Value::Str(decode_str(buffer, pos)?)),
+            TAG_LIST => {
+                let count = decode_len(buffer, pos)?;
+                let mut values = Vec::with_capacity(count);
+                for _ in 0..count {
+                    values.push(Value::decode_at(buffer, pos, depth + 1)?);
+                }
+                Ok(Value::List(values))
+            }
+            TAG_MAP => {
+                let count = decode_len(buffer, pos)?;
+                let mut map = BTreeMap::new();
+                for _ in 0..count {
+                    let key = decode_str(buffer, pos)?;
+                    let value = Value::decode_at(buffer, pos, depth + 1)?;
+                    if map.insert(key, value).is_some() {
+                        return Err(invalid("duplicate key in map"));
+                    }
+                }
+                Ok(Value::Map(map))
+            }
+            _ => Err(invalid("unknown tag")),
+        }
+    }
+
+    /// Get the value of the key if the value is a map.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Map(map) => map.get(key),
+            _ => None,
+        }
+    }
+
+    /// Get the string if the value is a string.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Get the integer if the value is an unsigned integer.
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Value::Uint(value) => Some(*value),
+            _ => None,
+        }
+    }
+}
+
+fn decode_str(buffer: &[u8], pos: &mut usize) -> io::Result<String> {
+    let len = decode_len(buffer, pos)?;
+    let s = String::from_utf8(buffer[*pos..*pos + len].to_vec())
+        .map_err(|_| invalid("string is not UTF-8"))?;
+    *pos += len;
+
+    Ok(s)
+}
+
+/// Represents a message in a binary frame. A frame is the magic, the version, the type, the flags
+/// and the length of the payload in big-endian 32-bit, followed by the payload.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    pub message_type: MessageType,
+    /// Represents the flags, which are reserved and 0 in version 1.
+    pub flags: u8,
+    pub payload: Value,
+}
+
+impl Message {
+    /// Creates a new `Message`.
+    pub fn new(message_type: MessageType, payload: Value) -> Message {
+        Message {
+            message_type,
+            flags: 0,
+            payload,
+        }
+    }
+
+    /// Encodes the message into a frame.
+    pub fn encode(&self) -> io::Result<Vec<u8>> {
+        let mut payload = Vec::new();
+        self.payload.encode(&mut payload);
+        if payload.len() > MAX_PAYLOAD_SIZE {
+            return Err(invalid("payload is too large"));
+        }
+
+        let mut frame = Vec::with_capacity(HEADER_SIZE + payload.len());
+        frame.push(FRAME_MAGIC);
+        frame.push(FRAME_VERSION);
+        frame.push(self.message_type as u8);
+        frame.push(self.flags);
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&payload);
+
+        Ok(frame)
+    }
+
+    /// Decodes a message from the frame at the start of the buffer, returns the message and the
+    /// size of the frame, or `None` if the frame is not complete yet.
+    pub fn decode(buffer: &[u8]) -> io::Result<Option<(Message, usize)>> {
+        if buffer.len() < HEADER_SIZE {
+            return Ok(None);
+        }
+        if buffer[0] != FRAME_MAGIC {
+            return Err(invalid("not a frame"));
+        }
+        if buffer[1] != FRAME_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported version {}", buffer[1]),
+            ));
+        }
+        let message_type =
+            MessageType::from_u8(buffer[2]).ok_or_else(|| invalid("unknown message type"))?;
+        let flags = buffer[3];
+        let len = u32::from_be_bytes([buffer[4], buffer[5], buffer[6], buffer[7]]) as usize;
+        if len > MAX_PAYLOAD_SIZE {
+            return Err(invalid("payload is too large"));
+        }
+        if buffer.len() < HEADER_SIZE + len {
+            return Ok(None);
+        }
+        let payload = Value::decode(&buffer[HEADER_SIZE..HEADER_SIZE + len])?;
+
+        Ok(Some((
+            Message {
+                message_type,
+                flags,
+                payload,
+            },
+            HEADER_SIZE + len,
+        )))
+    }
+
+    /// Writes the message to the writer.
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.encode()?)
+    }
+
+    /// Reads a message from the reader.
+    pub fn read<R: Read>(reader: &mut R) -> io::Result<Message> {
+        let mut buffer = vec![0u8; HEADER_SIZE];
+        reader.read_exact(&mut buffer)?;
+        let len = u32::from_be_bytes([buffer[4], buffer[5], buffer[6], buffer[7]]) as usize;
+        if buffer[0] == FRAME_MAGIC && len <= MAX_PAYLOAD_SIZE {
+            // The buffer grows with the bytes read, instead of the length the header claims
+            reader.take(len as u64).read_to_end(&mut buffer)?;
+        }
+
+        match Message::decode(&buffer)? {
+            Some((message, _)) => Ok(message),
+            None => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+        }
+    }
+}
+
+/// Returns if a client speaks binary frames by the first byte it sends.
+pub fn is_binary(first: u8) -> bool {
+    first == FRAME_MAGIC
+}
+
+/// Get the statistics message of the snapshot.
+pub fn encode_stats(snapshot: &Snapshot) -> Message {
+    let counters: BTreeMap<String, Value> = stats::COUNTERS
+        .iter()
+        .zip(snapshot.counters.iter())
+        .map(|(name, value)| (String::from(*name), Value::Uint(*value)))
+        .collect();
+    let mut map = BTreeMap::new();
+    map.insert(String::from("version"), Value::Uint(snapshot.version));
+    map.insert(String::from("updated"), Value::Uint(snapshot.updated));
+    map.insert(String::from("counters"), Value::Map(counters));
+
+    Message::new(MessageType::Stats, Value::Map(map))
+}
+
+/// Get the flows message of the flows.
+pub fn encode_flows(flows: &[FlowStats]) -> Message {
+    let flows = flows
+        .iter()
+        .map(|flow| {
+            let mut map = BTreeMap::new();
+            map.insert(String::from("protocol"), Value::Uint(flow.protocol as u64));
+            map.insert(String::from("src_port"), Value::Uint(flow.src_port as u64));
+            map.insert(String::from("dst"), Value::Str(flow.dst.to_string()));
+            map.insert(String::from("bytes_up"), Value::Uint(flow.bytes_up));
+            map.insert(String::from("bytes_down"), Value::Uint(flow.bytes_down));
+            if let Some(rule) = flow.rule {
+                map.insert(String::from("rule"), Value::Uint(rule as u64));
+            }
+            if let Some(window) = flow.window {
+                map.insert(String::from("window"), Value::Uint(window as u64));
+            }
+
+            Value::Map(map)
+        })
+        .collect();
+
+    Message::new(MessageType::Flows, Value::List(flows))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::Random;
+
+    /// Represents the number of random values and frames of the fuzz tests.
+    const ROUNDS: usize = 20000;
+
+    fn map(entries: Vec<(&str, Value)>) -> Value {
+        Value::Map(
+            entries
+                .into_iter()
+                .map(|(key, value)| (String::from(key), value))
+                .collect(),
+        )
+    }
+
+    fn round_trip(value: &Value) {
+        let mut buffer = Vec::new();
+        value.encode(&mut buffer);
+        assert_eq!(&Value::decode(&buffer).unwrap(), value);
+    }
+
+    fn random_value(random: &mut Random, depth: usize) -> Value {
+        let tag = if depth + 1 >= MAX_DEPTH {
+            random.next_below(7)
+        } else {
+            random.next_below(9)
+        };
+        match tag {
+            0 => Value::Null,
+            1 => Value::Bool(random.next_below(2) == 1),
+            2 => Value::Uint(random.next_u64() >> random.next_below(64)),
+            3 => Value::Int((random.next_u64() >> random.next_below(64)) as i64),
+            4 => Value::Bytes(
+                (0..random.next_below(32))
+                    .map(|_| random.next_u64() as u8)
+                    .collect(),
+            ),
+            5 | 6 => Value::Str(
+                (0..random.next_below(16))
+                    .map(|_| ['a', 'z', '0', ' ', 'é', '中', '😀'][random.next_below(7)])
+                    .collect(),
+            ),
+            7 => Value::List(
+                (0..random.next_below(4))
+                    .map(|_| random_value(random, depth + 1))
+                    .collect(),
+            ),
+            _ => Value::Map(
+                (0..random.next_below(4))
+                    .map(|i| (format!("key{}", i), random_value(random, depth + 1)))
+                    .collect(),
+            ),
+        }
+    }
+
+    #[test]
+    fn test_message_type() {
+        for value in 0..=u8::MAX {
+            match MessageType::from_u8(value) {
+                Some(message_type) => assert_eq!(message_type as u8, value),
+                None => assert!(value > MessageType::Handoff as u8),
+            }
+        }
+    }
+
+    #[test]
+    fn test_value_round_trip() {
+        let values = vec![
+            Value::Null,
+            Value::Bool(false),
+            Value::Bool(true),
+            Value::Uint(0),
+            Value::Uint(0x7f),
+            Value::Uint(0x80),
+            Value::Uint(u64::MAX),
+            Value::Int(0),
+            Value::Int(-1),
+            Value::Int(i64::MIN),
+            Value::Int(i64::MAX),
+            Value::Bytes(Vec::new()),
+            Value::Bytes(vec![0, 0xff, 0x80]),
+            Value::Str(String::new()),
+            Value::Str(String::from("stats 统计")),
+            Value::List(Vec::new()),
+            Value::List(vec![Value::Null, Value::Uint(1), Value::List(Vec::new())]),
+            map(Vec::new()),
+            map(vec![
+                ("a", Value::Uint(1)),
+                ("b", map(vec![("c", Value::Null)])),
+            ]),
+        ];
+        for value in &values {
+            round_trip(value);
+        }
+
+        // The deepest nesting allowed
+        let mut value = Value::Null;
+        for _ in 0..MAX_DEPTH - 1 {
+            value = Value::List(vec![value]);
+        }
+        round_trip(&value);
+        let mut buffer = Vec::new();
+        Value::List(vec![value]).encode(&mut buffer);
+        assert!(Value::decode(&buffer).is_err());
+
+        let mut random = Random::new(1);
+        for _ in 0..ROUNDS / 10 {
+            round_trip(&random_value(&mut random, 0));
+        }
+    }
+
+    #[test]
+    fn test_message_round_trip() {
+        let mut random = Random::new(2);
+        for value in 0..=MessageType::Handoff as u8 {
+            let message_type = MessageType::from_u8(value).unwrap();
+            let message = Message::new(message_type, random_value(&mut random, 0));
+            let frame = message.encode().unwrap();
+
+            // Incomplete frames wait for more bytes
+            for size in 0..frame.len() {
+                assert_eq!(Message::decode(&frame[..size]).unwrap(), None);
+            }
+            let mut buffer = frame.clone();
+            buffer.extend_from_slice(&frame);
+            assert_eq!(
+                Message::decode(&buffer).unwrap(),
+                Some((message.clone(), frame.len()))
+            );
+            assert_eq!(Message::read(&mut &frame[..]).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn test_decoder_fuzz() {
+        let mut random = Random::new(3);
+        let frames: Vec<Vec<u8>> = (0..16)
+            .map(|_| {
+                Message::new(MessageType::Flows, random_value(&mut random, 0))
+                    .encode()
+                    .unwrap()
+            })
+            .collect();
+
+        for _ in 0..ROUNDS {
+            // Mutate a valid frame by flipping, inserting, removing and truncating bytes
+            let mut buffer = frames[random.next_below(frames.len())].clone();
+            for _ in 0..1 + random.next_below(4) {
+                let pos = random.next_below(buffer.len());
+                match random.next_below(4) {
+                    0 => buffer[pos] ^= 1 << random.next_below(8),
+                    1 => buffer.insert(pos, random.next_u64() as u8),
+                    2 if buffer.len() > 1 => {
+                        buffer.remove(pos);
+                    }
+                    _ => buffer[pos] = random.next_u64() as u8,
+                }
+            }
+            if random.next_below(8) == 0 {
+                buffer.truncate(random.next_below(buffer.len() + 1));
+            }
+
+            // Never panics, and what decodes encodes back to the same value
+            if let Ok(Some((message, size))) = Message::decode(&buffer) {
+                assert!(size <= buffer.len());
+                round_trip(&message.payload);
+            }
+            let _ = Message::read(&mut &buffer[..]);
+            let _ = Value::decode(&buffer[HEADER_SIZE.min(buffer.len())..]);
+
+            // Random bytes
+            let noise: Vec<u8> = (0..random.next_below(64))
+                .map(|_| random.next_u64() as u8)
+                .collect();
+            if let Ok(value) = Value::decode(&noise) {
+                round_trip(&value);
+            }
+        }
+
+        // Lengths beyond the payload are refused without allocating them
+        let huge = [TAG_BYTES, 0xff, 0xff, 0xff, 0xff, 0x0f];
+        assert!(Value::decode(&huge).is_err());
+        let overflow = [
+            TAG_UINT, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02,
+        ];
+        assert!(Value::decode(&overflow).is_err());
+    }
+}
@@ -0,0 +1,274 @@
+//! Services of the published gateway itself, like the status page and the UPnP control. A TCP
+//! connection to a port of the gateway with a service is redirected like any other, but its
+//! worker connects to the listener of the service on the loopback instead of the proxy, so a
+//! service is a plain socket and its replies go back to the source through the redirector.
+
+use log::debug;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::threads::{Purpose, Threads};
+
+pub mod status;
+
+pub use status::Status;
+
+/// Represents the max size of the head of a request.
+const MAX_HEAD_SIZE: usize = 4096;
+
+/// Represents the max size of the body of a request.
+pub const MAX_BODY_SIZE: usize = 16384;
+
+/// Represents the timeout of reading a request and writing its response in seconds. Requests
+/// are served one by one, so a source stalls others for at most the timeout.
+const IO_TIMEOUT: u64 = 2;
+
+/// Represents an HTTP request to a service of the gateway.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Request {
+    pub method: String,
+    /// Represents the path of the request without the query.
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Parses the head of a request, returns `None` if it is not a request.
+    pub fn parse_head(head: &str) -> Option<Request> {
+        let mut lines = head.lines();
+        let mut words = lines.next()?.split_whitespace();
+        let method = words.next()?.to_string();
+        let path = words.next()?.split('?').next().unwrap_or("").to_string();
+        if !words.next()?.starts_with("HTTP/") {
+            return None;
+        }
+        let headers = lines
+            .take_while(|line| !line.is_empty())
+            .filter_map(|line| {
+                let mut parts = line.splitn(2, ':');
+                let name = parts.next()?.trim().to_string();
+                let value = parts.next()?.trim().to_string();
+                Some((name, value))
+            })
+            .collect();
+
+        Some(Request {
+            method,
+            path,
+            headers,
+            body: Vec::new(),
+        })
+    }
+
+    /// Get the value of the header, whose name is case-insensitive.
+    pub fn get_header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Get the length of the body the request declares.
+    fn get_content_length(&self) -> usize {
+        self.get_header("Content-Length")
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(0)
+    }
+}
+
+/// Represents an HTTP response of a service of the gateway.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// Creates a new `Response` with a JSON body.
+    pub fn json(status: u16, body: String) -> Response {
+        Response {
+            status,
+            content_type: "application/json",
+            body: body.into_bytes(),
+        }
+    }
+
+    /// Creates a new `Response` with an XML body.
+    pub fn xml(status: u16, body: String) -> Response {
+        Response {
+            status,
+            content_type: "text/xml; charset=\"utf-8\"",
+            body: body.into_bytes(),
+        }
+    }
+
+    /// Creates a new `Response` of 404.
+    pub fn not_found() -> Response {
+        Response::json(404, String::from("{\"error\":\"not found\"}"))
+    }
+
+    /// Creates a new `Response` of 405.
+    pub fn method_not_allowed() -> Response {
+        Response::json(405, String::from("{\"error\":\"method not allowed\"}"))
+    }
+
+    /// Get the bytes of the response, without the body if it answers a `HEAD`.
+    pub fn to_bytes(&self, is_head: bool) -> Vec<u8> {
+        let mut bytes = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+            self.status,
+            reason(self.status),
+            self.content_type,
+            self.body.len()
+        )
+        .into_bytes();
+        if !is_head {
+            bytes.extend_from_slice(&self.body);
+        }
+
+        bytes
+    }
+}
+
+/// Represents a service of the gateway, which answers each request of a connection and closes
+/// it.
+pub trait Service: Send + Sync {
+    /// Get the name of the service.
+    fn get_name(&self) -> &str;
+
+    /// Answers the request.
+    fn handle(&self, request: &Request) -> Response;
+}
+
+/// Binds a listener on the loopback and spawns the thread serving the service on it, returns the
+/// local address the workers of the gateway port connect to.
+pub fn serve(threads: &Arc<Threads>, service: Arc<dyn Service>) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let local_addr = listener.local_addr()?;
+    let name = format!("gateway {}", service.get_name());
+    Threads::spawn(threads, name, Purpose::Http, move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|mut stream| respond(&mut stream, service.as_ref()));
+            if let Err(ref e) = result {
+                debug!("gateway {}: {}", service.get_name(), e);
+            }
+        }
+    })?;
+
+    Ok(local_addr)
+}
+
+/// Reads a request from the stream, writes the response of the service and closes the stream.
+fn respond(stream: &mut TcpStream, service: &dyn Service) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(IO_TIMEOUT)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(IO_TIMEOUT)))?;
+
+    let response = match read_request(stream)? {
+        Some(request) => {
+            let response = service.handle(&request);
+            debug!(
+                "gateway {}: {} {} {}",
+                service.get_name(),
+                request.method,
+                request.path,
+                response.status
+            );
+            response.to_bytes(request.method == "HEAD")
+        }
+        None => Response::json(400, String::from("{\"error\":\"bad request\"}")).to_bytes(false),
+    };
+    stream.write_all(&response)?;
+    stream.flush()
+}
+
+/// Reads a request, returns `None` if it is malformed or too large.
+fn read_request<R: Read>(stream: &mut R) -> io::Result<Option<Request>> {
+    let mut bytes = Vec::new();
+    let mut buffer = [0u8; 1024];
+    let end = loop {
+        if let Some(position) = bytes.windows(4).position(|window| window == b"\r\n\r\n") {
+            break position + 4;
+        }
+        if bytes.len() >= MAX_HEAD_SIZE {
+            return Ok(None);
+        }
+        let size = stream.read(&mut buffer)?;
+        if size == 0 {
+            return Ok(None);
+        }
+        bytes.extend_from_slice(&buffer[..size]);
+    };
+
+    let mut request = match Request::parse_head(&String::from_utf8_lossy(&bytes[..end])) {
+        Some(request) => request,
+        None => return Ok(None),
+    };
+    let length = request.get_content_length();
+    if length > MAX_BODY_SIZE {
+        return Ok(None);
+    }
+    let mut body = bytes.split_off(end);
+    while body.len() < length {
+        let size = stream.read(&mut buffer)?;
+        if size == 0 {
+            return Ok(None);
+        }
+        body.extend_from_slice(&buffer[..size]);
+    }
+    body.truncate(length);
+    request.body = body;
+
+    Ok(Some(request))
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        500 => "Internal Server Error",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_request() {
+        let mut bytes: &[u8] = b"POST /ctl HTTP/1.1\r\nHost: 10.6.0.1\r\ncontent-length: 5\r\nSOAPAction: \"a#b\"\r\n\r\nhello and more";
+        let request = read_request(&mut bytes).unwrap().unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/ctl");
+        assert_eq!(request.get_header("soapaction"), Some("\"a#b\""));
+        assert_eq!(request.body, b"hello");
+
+        // The query is dropped from the path
+        let mut bytes: &[u8] = b"GET /?a=1 HTTP/1.1\r\n\r\n";
+        let request = read_request(&mut bytes).unwrap().unwrap();
+        assert_eq!(request.path, "/");
+        assert!(request.body.is_empty());
+    }
+
+    #[test]
+    fn test_read_request_malformed() {
+        // Truncated in the body
+        let mut bytes: &[u8] = b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nhello";
+        assert!(read_request(&mut bytes).unwrap().is_none());
+        let mut bytes: &[u8] = b"hello\r\n\r\n";
+        assert!(read_request(&mut bytes).unwrap().is_none());
+        // Too large
+        let head = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY_SIZE + 1
+        );
+        let mut bytes = head.as_bytes();
+        assert!(read_request(&mut bytes).unwrap().is_none());
+    }
+}
@@ -0,0 +1,100 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use super::{Request, Response, Service};
+use crate::hooks::Protocol;
+use crate::monitor::Monitor;
+
+/// Represents the default port of the status page.
+pub const DEFAULT_PORT: u16 = 8080;
+
+/// Represents the status page of the gateway, which answers `/` with the uptime and the live
+/// flows of the monitor in JSON, so a device behind the gateway can tell it is redirected.
+#[derive(Debug)]
+pub struct Status {
+    monitor: Arc<Monitor>,
+    started: Instant,
+}
+
+impl Status {
+    /// Creates a new `Status` of the workers of the monitor.
+    pub fn new(monitor: Arc<Monitor>) -> Status {
+        Status {
+            monitor,
+            started: Instant::now(),
+        }
+    }
+
+    /// Get the body of the status page.
+    fn get_body(&self) -> String {
+        let entries = self.monitor.get_entries();
+        let streams = entries
+            .iter()
+            .filter(|entry| entry.protocol == Protocol::Tcp)
+            .count();
+        let (bytes_up, bytes_down) = entries.iter().fold((0, 0), |(up, down), entry| {
+            (up + entry.stats.bytes_up, down + entry.stats.bytes_down)
+        });
+
+        format!(
+            "{{\"version\":\"{}\",\"uptime\":{},\"streams\":{},\"associations\":{},\"bytes_up\":{},\"bytes_down\":{}}}",
+            env!("CARGO_PKG_VERSION"),
+            self.started.elapsed().as_secs(),
+            streams,
+            entries.len() - streams,
+            bytes_up,
+            bytes_down
+        )
+    }
+}
+
+impl Service for Status {
+    fn get_name(&self) -> &str {
+        "status"
+    }
+
+    fn handle(&self, request: &Request) -> Response {
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/") | ("HEAD", "/") => Response::json(200, self.get_body()),
+            (_, "/") => Response::method_not_allowed(),
+            _ => Response::not_found(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitor::Counters;
+    use std::net::SocketAddrV4;
+
+    fn get(path: &str) -> Request {
+        Request {
+            method: String::from("GET"),
+            path: path.to_string(),
+            ..Request::default()
+        }
+    }
+
+    #[test]
+    fn test_status() {
+        let monitor = Arc::new(Monitor::new());
+        let counters = Arc::new(Counters::new());
+        counters.add_up(100);
+        counters.add_down(2000);
+        let dst = "203.0.113.1:443".parse::<SocketAddrV4>().unwrap();
+        let _registration = Monitor::register(&monitor, Protocol::Tcp, 50000, Some(dst), counters);
+        let status = Status::new(monitor);
+
+        let response = status.handle(&get("/"));
+        assert_eq!(response.status, 200);
+        let body = String::from_utf8(response.body).unwrap();
+        assert!(body.contains("\"streams\":1,\"associations\":0"));
+        assert!(body.contains("\"bytes_up\":100,\"bytes_down\":2000"));
+
+        assert_eq!(status.handle(&get("/admin")).status, 404);
+        let mut post = get("/");
+        post.method = String::from("POST");
+        assert_eq!(status.handle(&post).status, 405);
+    }
+}
@@ -0,0 +1,719 @@
+use log::info;
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::TcpListener;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::frame::{Message, MessageType, Value};
+
+/// Represents the environment variable passing the descriptor of the channel to the new process.
+pub const HANDOFF_FD_ENV: &str = "PCAP2SOCKS_HANDOFF_FD";
+
+/// Represents the default time the old process drains its TCP connections after a handoff.
+pub const DEFAULT_DRAIN: u64 = 60;
+
+/// Represents the max time the peer has to take a step, which covers opening all the captures in
+/// the new process.
+const STEP_TIMEOUT: u64 = 30;
+
+/// Represents the max time the captures of the old process have to release their states.
+const RELEASE_TIMEOUT: u64 = 5;
+
+/// Represents the max number of listeners passed to the new process.
+const MAX_LISTENERS: usize = 8;
+
+/// Represents the phase of a process in a handoff.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Phase {
+    /// Serves the sources, like the old process before a handoff and the new process after it.
+    Serving,
+    /// The new process waits for the states of the old process, and serves nothing.
+    Waiting,
+    /// The captures of the old process release their states.
+    Releasing,
+    /// The old process released its states, and drains its TCP connections.
+    Draining,
+    /// The handoff failed after the captures of the old process released their states, which
+    /// serve again with them.
+    Aborted,
+    /// The handoff failed in the new process, which exits.
+    Failed,
+}
+
+impl Display for Phase {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Phase::Serving => write!(f, "serving"),
+            Phase::Waiting => write!(f, "waiting"),
+            Phase::Releasing => write!(f, "releasing"),
+            Phase::Draining => write!(f, "draining"),
+            Phase::Aborted => write!(f, "aborted"),
+            Phase::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+/// Represents the handoff shared by all the captures of the process.
+struct Shared {
+    phase: Phase,
+    members: usize,
+    /// Represents the states released by the captures, by their keys.
+    states: BTreeMap<String, String>,
+    drain: Duration,
+    /// Represents the time the new process took over, since which segments of unknown TCP
+    /// connections may belong to the connections drained by the old process.
+    took_over: Option<Instant>,
+    listeners: Vec<(String, TcpListener)>,
+    channel: Option<Channel>,
+}
+
+/// Represents the handoff from an old process to a new one serving the same sources, like in an
+/// upgrade. The processes take steps in binary frames over a channel: the old process passes its
+/// listeners, the new one tells it is ready once its captures are open, the captures of the old
+/// process release their states, like the UDP associations with their local ports, and the new
+/// process binds them again and serves. The old process drains its TCP connections meanwhile.
+///
+/// Each capture of a process is a member of the handoff by `join`, and follows the phase of the
+/// process with its own state under its key.
+pub struct Handoff {
+    shared: Mutex<Shared>,
+    released: Condvar,
+}
+
+impl Handoff {
+    /// Creates a new `Handoff` of the old process, which drains TCP connections for the given time
+    /// after handing off.
+    pub fn new(drain: Duration) -> Handoff {
+        Handoff::with_phase(Phase::Serving, drain, Vec::new(), None)
+    }
+
+    fn with_phase(
+        phase: Phase,
+        drain: Duration,
+        listeners: Vec<(String, TcpListener)>,
+        channel: Option<Channel>,
+    ) -> Handoff {
+        Handoff {
+            shared: Mutex::new(Shared {
+                phase,
+                members: 0,
+                states: BTreeMap::new(),
+                drain,
+                took_over: None,
+                listeners,
+                channel,
+            }),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Takes over from the old process on the other end of the channel, and receives the
+    /// listeners it passes. The new process waits for the states of the old process until `ready`.
+    pub fn take_over(mut channel: Channel) -> io::Result<Handoff> {
+        channel.set_timeout(Duration::from_secs(STEP_TIMEOUT))?;
+        let hello = channel.receive()?;
+        check_step(&hello, "hello")?;
+        let names: Vec<String> = match hello.payload.get("listeners") {
+            Some(Value::List(names)) => names
+                .iter()
+                .filter_map(|name| name.as_str().map(String::from))
+                .collect(),
+            _ => Vec::new(),
+        };
+        let listeners = if names.is_empty() {
+            Vec::new()
+        } else {
+            channel.receive_listeners(names.len())?
+        };
+        info!("Take over from the old process");
+
+        Ok(Handoff::with_phase(
+            Phase::Waiting,
+            Duration::from_secs(DEFAULT_DRAIN),
+            names.into_iter().zip(listeners).collect(),
+            Some(channel),
+        ))
+    }
+
+    /// Adds a listener passed to the new process by its name.
+    pub fn add_listener(&self, name: &str, listener: &TcpListener) -> io::Result<()> {
+        let mut shared = self.shared.lock().unwrap();
+        if shared.listeners.len() >= MAX_LISTENERS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "too many listeners",
+            ));
+        }
+        shared
+            .listeners
+            .push((String::from(name), listener.try_clone()?));
+
+        Ok(())
+    }
+
+    /// Takes the listener passed by the old process by its name.
+    pub fn take_listener(&self, name: &str) -> Option<TcpListener> {
+        let mut shared = self.shared.lock().unwrap();
+        let index = shared.listeners.iter().position(|(n, _)| n == name)?;
+
+        Some(shared.listeners.remove(index).1)
+    }
+
+    /// Joins a capture to the handoff, which releases its state or takes over one under its key.
+    pub fn join(&self) {
+        self.shared.lock().unwrap().members += 1;
+    }
+
+    /// Get the phase of the process.
+    pub fn get_phase(&self) -> Phase {
+        self.shared.lock().unwrap().phase
+    }
+
+    /// Get the time the old process drains its TCP connections after handing off.
+    pub fn get_drain(&self) -> Duration {
+        self.shared.lock().unwrap().drain
+    }
+
+    /// Returns if segments of unknown TCP connections may belong to connections drained by the old
+    /// process, which must not be reset by the new one.
+    pub fn is_guarding(&self) -> bool {
+        let shared = self.shared.lock().unwrap();
+        match shared.took_over {
+            Some(instant) => instant.elapsed() < shared.drain,
+            None => false,
+        }
+    }
+
+    /// Releases the state of a capture of the old process.
+    pub fn release(&self, key: &str, state: String) {
+        self.shared
+            .lock()
+            .unwrap()
+            .states
+            .insert(String::from(key), state);
+        self.released.notify_all();
+    }
+
+    /// Takes the state of a capture, which is released by the old process in the new one, or its
+    /// own in the old process after the handoff is aborted.
+    pub fn take_state(&self, key: &str) -> Option<String> {
+        self.shared.lock().unwrap().states.remove(key)
+    }
+
+    /// Hands off to the new process on the other end of the channel, and blocks until the new
+    /// process takes the states of all the captures. The captures drain their TCP connections
+    /// then, or serve again if the handoff is aborted after they released their states.
+    pub fn hand_off(&self, mut channel: Channel) -> io::Result<()> {
+        let (names, listeners) = {
+            let mut shared = self.shared.lock().unwrap();
+            match shared.phase {
+                Phase::Serving => {}
+                // A capture still holds the released state of the last handoff otherwise
+                Phase::Aborted if shared.states.is_empty() => shared.phase = Phase::Serving,
+                phase => return Err(io::Error::other(format!("the handoff is {}", phase))),
+            }
+            let mut names = Vec::new();
+            let mut listeners = Vec::new();
+            for (name, listener) in shared.listeners.iter() {
+                names.push(Value::Str(name.clone()));
+                listeners.push(listener.try_clone()?);
+            }
+
+            (names, listeners)
+        };
+
+        channel.set_timeout(Duration::from_secs(STEP_TIMEOUT))?;
+        let mut map = BTreeMap::new();
+        map.insert(String::from("listeners"), Value::List(names));
+        channel.send(&new_step("hello", map))?;
+        if !listeners.is_empty() {
+            channel.send_listeners(&listeners)?;
+        }
+        check_step(&channel.receive()?, "ready")?;
+
+        // Release
+        let (states, drain) = {
+            let mut shared = self.shared.lock().unwrap();
+            shared.phase = Phase::Releasing;
+            let deadline = Instant::now() + Duration::from_secs(RELEASE_TIMEOUT);
+            while shared.states.len() < shared.members {
+                let now = Instant::now();
+                if now >= deadline {
+                    shared.phase = Phase::Aborted;
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "captures do not release their states",
+                    ));
+                }
+                shared = self
+                    .released
+                    .wait_timeout(shared, deadline - now)
+                    .unwrap()
+                    .0;
+            }
+
+            (shared.states.clone(), shared.drain)
+        };
+
+        let mut map = BTreeMap::new();
+        map.insert(
+            String::from("states"),
+            Value::Map(
+                states
+                    .into_iter()
+                    .map(|(key, state)| (key, Value::Str(state)))
+                    .collect(),
+            ),
+        );
+        map.insert(String::from("drain"), Value::Uint(drain.as_secs()));
+        let result = channel
+            .send(&new_step("state", map))
+            .and_then(|_| channel.receive())
+            .and_then(|done| check_step(&done, "done"));
+
+        let mut shared = self.shared.lock().unwrap();
+        match result {
+            Ok(_) => {
+                shared.phase = Phase::Draining;
+                shared.states.clear();
+                info!(
+                    "Hand off to the new process, drain TCP connections for {} seconds",
+                    drain.as_secs()
+                );
+
+                Ok(())
+            }
+            Err(e) => {
+                shared.phase = Phase::Aborted;
+
+                Err(e)
+            }
+        }
+    }
+
+    /// Tells the old process the new one is ready to serve, and waits for the states of the
+    /// captures of the old process. The process fails if the handoff does not complete.
+    pub fn ready(&self) -> io::Result<()> {
+        let channel = self.shared.lock().unwrap().channel.take();
+        let mut channel = match channel {
+            Some(channel) => channel,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "no handoff to take over",
+                ))
+            }
+        };
+
+        let result = receive_states(&mut channel);
+        let mut shared = self.shared.lock().unwrap();
+        match result {
+            Ok((states, drain)) => {
+                info!("Take over {} captures from the old process", states.len());
+                shared.states = states;
+                shared.drain = drain;
+                shared.took_over = Some(Instant::now());
+                shared.phase = Phase::Serving;
+
+                Ok(())
+            }
+            Err(e) => {
+                shared.phase = Phase::Failed;
+
+                Err(e)
+            }
+        }
+    }
+}
+
+fn receive_states(channel: &mut Channel) -> io::Result<(BTreeMap<String, String>, Duration)> {
+    channel.send(&new_step("ready", BTreeMap::new()))?;
+    let state = channel.receive()?;
+    check_step(&state, "state")?;
+    let states = match state.payload.get("states") {
+        Some(Value::Map(map)) => map
+            .iter()
+            .filter_map(|(key, state)| {
+                state
+                    .as_str()
+                    .map(|state| (key.clone(), String::from(state)))
+            })
+            .collect(),
+        _ => BTreeMap::new(),
+    };
+    let drain = state
+        .payload
+        .get("drain")
+        .and_then(|drain| drain.as_u64())
+        .unwrap_or(DEFAULT_DRAIN);
+    channel.send(&new_step("done", BTreeMap::new()))?;
+
+    Ok((states, Duration::from_secs(drain)))
+}
+
+fn new_step(step: &str, mut map: BTreeMap<String, Value>) -> Message {
+    map.insert(String::from("step"), Value::Str(String::from(step)));
+
+    Message::new(MessageType::Handoff, Value::Map(map))
+}
+
+fn check_step(message: &Message, step: &str) -> io::Result<()> {
+    let actual = match message.message_type {
+        MessageType::Handoff => message.payload.get("step").and_then(|step| step.as_str()),
+        MessageType::Error => {
+            return Err(io::Error::other(
+                message
+                    .payload
+                    .as_str()
+                    .unwrap_or("unknown error")
+                    .to_string(),
+            ))
+        }
+        _ => None,
+    };
+    if actual != Some(step) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("expect the {} step of the handoff", step),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Represents the channel between an old process and a new one in a handoff, which passes
+/// listeners as well. Only supported on Unix.
+pub struct Channel {
+    #[cfg(unix)]
+    stream: std::os::unix::net::UnixStream,
+}
+
+#[cfg(unix)]
+impl Channel {
+    /// Creates a pair of connected `Channel`s.
+    pub fn pair() -> io::Result<(Channel, Channel)> {
+        let (a, b) = std::os::unix::net::UnixStream::pair()?;
+
+        Ok((Channel { stream: a }, Channel { stream: b }))
+    }
+
+    /// Creates a `Channel` from the descriptor in the environment variable, or `None` if the
+    /// process is not started by a handoff. The variable is removed, so processes spawned later,
+    /// like hooks, never take the channel.
+    pub fn from_env() -> io::Result<Option<Channel>> {
+        use std::os::unix::io::{FromRawFd, RawFd};
+
+        let fd = match std::env::var(HANDOFF_FD_ENV) {
+            Ok(fd) => fd,
+            Err(_) => return Ok(None),
+        };
+        std::env::remove_var(HANDOFF_FD_ENV);
+        let fd: RawFd = fd.parse().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a descriptor", HANDOFF_FD_ENV),
+            )
+        })?;
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Some(Channel {
+            stream: unsafe { std::os::unix::net::UnixStream::from_raw_fd(fd) },
+        }))
+    }
+
+    /// Get the descriptor of the `Channel` to pass to a new process, which is inherited across
+    /// `exec`.
+    pub fn get_inheritable_fd(&self) -> io::Result<i32> {
+        use std::os::unix::io::AsRawFd;
+
+        let fd = self.stream.as_raw_fd();
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, 0) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(fd)
+    }
+
+    fn set_timeout(&self, timeout: Duration) -> io::Result<()> {
+        self.stream.set_read_timeout(Some(timeout))?;
+        self.stream.set_write_timeout(Some(timeout))
+    }
+
+    fn send(&mut self, message: &Message) -> io::Result<()> {
+        message.write(&mut self.stream)
+    }
+
+    fn receive(&mut self) -> io::Result<Message> {
+        Message::read(&mut self.stream)
+    }
+
+    /// Sends the listeners attached to a byte, which never reaches the reader of messages.
+    fn send_listeners(&mut self, listeners: &[TcpListener]) -> io::Result<()> {
+        use std::os::unix::io::{AsRawFd, RawFd};
+
+        let fds: Vec<RawFd> = listeners.iter().map(|l| l.as_raw_fd()).collect();
+        let size = fds.len() * std::mem::size_of::<RawFd>();
+        let space = unsafe { libc::CMSG_SPACE(size as u32) } as usize;
+        // The control buffer is aligned to the header
+        let mut control = vec![0u64; space.div_ceil(8)];
+        let mut byte = [0u8; 1];
+        let mut iov = libc::iovec {
+            iov_base: byte.as_mut_ptr() as *mut libc::c_void,
+            iov_len: 1,
+        };
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = space as _;
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(size as u32) as _;
+            std::ptr::copy_nonoverlapping(fds.as_ptr() as *const u8, libc::CMSG_DATA(cmsg), size);
+        }
+
+        if unsafe { libc::sendmsg(self.stream.as_raw_fd(), &msg, 0) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    /// Receives the given number of listeners attached to a byte.
+    fn receive_listeners(&mut self, n: usize) -> io::Result<Vec<TcpListener>> {
+        use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+        let space =
+            unsafe { libc::CMSG_SPACE((MAX_LISTENERS * std::mem::size_of::<RawFd>()) as u32) }
+                as usize;
+        let mut control = vec![0u64; space.div_ceil(8)];
+        let mut byte = [0u8; 1];
+        let mut iov = libc::iovec {
+            iov_base: byte.as_mut_ptr() as *mut libc::c_void,
+            iov_len: 1,
+        };
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = space as _;
+
+        let size = unsafe { libc::recvmsg(self.stream.as_raw_fd(), &mut msg, 0) };
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if size == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+
+        let mut listeners = Vec::new();
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            if !cmsg.is_null()
+                && (*cmsg).cmsg_level == libc::SOL_SOCKET
+                && (*cmsg).cmsg_type == libc::SCM_RIGHTS
+            {
+                let count = ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize)
+                    / std::mem::size_of::<RawFd>();
+                let data = libc::CMSG_DATA(cmsg);
+                for i in 0..count {
+                    let mut bytes = [0u8; std::mem::size_of::<RawFd>()];
+                    std::ptr::copy_nonoverlapping(
+                        data.add(i * bytes.len()),
+                        bytes.as_mut_ptr(),
+                        bytes.len(),
+                    );
+                    let fd = RawFd::from_ne_bytes(bytes);
+                    libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+                    listeners.push(TcpListener::from_raw_fd(fd));
+                }
+            }
+        }
+        if listeners.len() != n || msg.msg_flags & libc::MSG_CTRUNC != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("expect {} listeners, receive {}", n, listeners.len()),
+            ));
+        }
+
+        Ok(listeners)
+    }
+}
+
+#[cfg(not(unix))]
+impl Channel {
+    /// Creates a pair of connected `Channel`s.
+    pub fn pair() -> io::Result<(Channel, Channel)> {
+        Err(unsupported())
+    }
+
+    /// Creates a `Channel` from the descriptor in the environment variable, or `None` if the
+    /// process is not started by a handoff.
+    pub fn from_env() -> io::Result<Option<Channel>> {
+        match std::env::var(HANDOFF_FD_ENV) {
+            Ok(_) => Err(unsupported()),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Get the descriptor of the `Channel` to pass to a new process.
+    pub fn get_inheritable_fd(&self) -> io::Result<i32> {
+        Err(unsupported())
+    }
+
+    fn set_timeout(&self, _: Duration) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    fn send(&mut self, _: &Message) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    fn receive(&mut self) -> io::Result<Message> {
+        Err(unsupported())
+    }
+
+    fn send_listeners(&mut self, _: &[TcpListener]) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    fn receive_listeners(&mut self, _: usize) -> io::Result<Vec<TcpListener>> {
+        Err(unsupported())
+    }
+}
+
+#[cfg(not(unix))]
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        "the handoff is only supported on Unix",
+    )
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::sync::Arc;
+    use std::thread;
+
+    /// Releases the states of the members once the old process releases.
+    fn release_members(
+        old: &Arc<Handoff>,
+        keys: &'static [&'static str],
+    ) -> thread::JoinHandle<()> {
+        for _ in keys {
+            old.join();
+        }
+        let old = Arc::clone(old);
+        thread::spawn(move || {
+            while old.get_phase() != Phase::Releasing {
+                thread::sleep(Duration::from_millis(1));
+            }
+            for key in keys {
+                old.release(key, format!("state of {}", key));
+            }
+        })
+    }
+
+    #[test]
+    fn test_hand_off() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let old = Arc::new(Handoff::new(Duration::from_secs(7)));
+        old.add_listener("health", &listener).unwrap();
+        let members = release_members(&old, &["10.6.0.2", "10.6.1.2"]);
+
+        let (channel, peer) = Channel::pair().unwrap();
+        let new = thread::spawn(move || {
+            let new = Handoff::take_over(peer).unwrap();
+            assert_eq!(new.get_phase(), Phase::Waiting);
+            new.join();
+            new.join();
+            let listener = new.take_listener("health").unwrap();
+            new.ready().unwrap();
+
+            (new, listener)
+        });
+        old.hand_off(channel).unwrap();
+        members.join().unwrap();
+        let (new, taken) = new.join().unwrap();
+        assert_eq!(old.get_phase(), Phase::Draining);
+        assert_eq!(old.take_state("10.6.0.2"), None);
+
+        // The new process serves with the states and guards the connections drained
+        assert_eq!(new.get_phase(), Phase::Serving);
+        assert_eq!(new.get_drain(), Duration::from_secs(7));
+        assert!(new.is_guarding());
+        assert_eq!(
+            new.take_state("10.6.1.2"),
+            Some(String::from("state of 10.6.1.2"))
+        );
+        assert_eq!(new.take_listener("health").map(|_| ()), None);
+
+        // The listener passed accepts connections to the same address
+        drop(listener);
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"ping").unwrap();
+        let (mut server, _) = taken.accept().unwrap();
+        let mut buffer = [0u8; 4];
+        server.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"ping");
+
+        // Only one handoff at a time
+        let (channel, _) = Channel::pair().unwrap();
+        assert!(old.hand_off(channel).is_err());
+    }
+
+    #[test]
+    fn test_aborted() {
+        let old = Arc::new(Handoff::new(Duration::from_secs(DEFAULT_DRAIN)));
+        let members = release_members(&old, &["10.6.0.2"]);
+
+        // The new process quits before taking the states
+        let (channel, mut peer) = Channel::pair().unwrap();
+        let new = thread::spawn(move || {
+            check_step(&peer.receive().unwrap(), "hello").unwrap();
+            peer.send(&new_step("ready", BTreeMap::new())).unwrap();
+            check_step(&peer.receive().unwrap(), "state").unwrap();
+        });
+        assert!(old.hand_off(channel).is_err());
+        members.join().unwrap();
+        new.join().unwrap();
+
+        // The capture serves again with its own state
+        assert_eq!(old.get_phase(), Phase::Aborted);
+        assert_eq!(
+            old.take_state("10.6.0.2"),
+            Some(String::from("state of 10.6.0.2"))
+        );
+
+        // The old process never waits for a new process failing to start
+        let (channel, peer) = Channel::pair().unwrap();
+        drop(peer);
+        assert!(old.hand_off(channel).is_err());
+        assert_eq!(old.get_phase(), Phase::Serving);
+    }
+
+    #[test]
+    fn test_failed() {
+        // The old process quits before releasing the states
+        let (channel, mut peer) = Channel::pair().unwrap();
+        let old = thread::spawn(move || {
+            peer.send(&new_step("hello", BTreeMap::new())).unwrap();
+            check_step(&peer.receive().unwrap(), "ready").unwrap();
+        });
+        let new = Handoff::take_over(channel).unwrap();
+        assert!(new.ready().is_err());
+        old.join().unwrap();
+        assert_eq!(new.get_phase(), Phase::Failed);
+        assert!(!new.is_guarding());
+    }
+}
@@ -0,0 +1,198 @@
+use log::info;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::http::{self, Response};
+use crate::threads::Threads;
+
+/// Represents a check of the health of the process.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Check {
+    /// The capture handle is open.
+    Capture,
+    /// At least one upstream is up.
+    Upstreams,
+    /// The interface is up.
+    Interface,
+    /// The arguments are validated and the captures are set up.
+    Validation,
+}
+
+impl Display for Check {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Check::Capture => write!(f, "capture"),
+            Check::Upstreams => write!(f, "upstreams"),
+            Check::Interface => write!(f, "interface"),
+            Check::Validation => write!(f, "validation"),
+        }
+    }
+}
+
+/// Represents the health of the process for liveness and readiness probes. The states are set by
+/// the `Redirector` from the same signals the notifications follow, so the probes never probe the
+/// upstreams or the interface by themselves.
+#[derive(Debug, Default)]
+pub struct Health {
+    is_capture_open: AtomicBool,
+    is_upstreams_down: AtomicBool,
+    is_interface_lost: AtomicBool,
+    is_validated: AtomicBool,
+}
+
+impl Health {
+    /// Creates a new `Health`.
+    pub fn new() -> Health {
+        Health::default()
+    }
+
+    /// Sets if the capture handle is open.
+    pub fn set_capture_open(&self, is_open: bool) {
+        self.is_capture_open.store(is_open, Ordering::Relaxed);
+    }
+
+    /// Sets if all the upstreams are down.
+    pub fn set_upstreams_down(&self, is_down: bool) {
+        self.is_upstreams_down.store(is_down, Ordering::Relaxed);
+    }
+
+    /// Sets if the interface is lost.
+    pub fn set_interface_lost(&self, is_lost: bool) {
+        self.is_interface_lost.store(is_lost, Ordering::Relaxed);
+    }
+
+    /// Sets if the arguments are validated and the captures are set up.
+    pub fn set_validated(&self, is_validated: bool) {
+        self.is_validated.store(is_validated, Ordering::Relaxed);
+    }
+
+    /// Get the failing checks of liveness.
+    pub fn get_live_failures(&self) -> Vec<Check> {
+        let mut failures = Vec::new();
+        if !self.is_capture_open.load(Ordering::Relaxed) {
+            failures.push(Check::Capture);
+        }
+
+        failures
+    }
+
+    /// Get the failing checks of readiness.
+    pub fn get_ready_failures(&self) -> Vec<Check> {
+        let mut failures = Vec::new();
+        if self.is_upstreams_down.load(Ordering::Relaxed) {
+            failures.push(Check::Upstreams);
+        }
+        if self.is_interface_lost.load(Ordering::Relaxed) {
+            failures.push(Check::Interface);
+        }
+        if !self.is_validated.load(Ordering::Relaxed) {
+            failures.push(Check::Validation);
+        }
+
+        failures
+    }
+
+    /// Binds the address and spawns the thread answering `/healthz` for liveness and `/readyz`
+    /// for readiness, with 200 if all the checks pass or 503 with the failing ones.
+    pub fn start(health: &Arc<Health>, threads: &Arc<Threads>, addr: SocketAddr) -> io::Result<()> {
+        Health::serve(health, threads, TcpListener::bind(addr)?)
+    }
+
+    /// Spawns the thread answering health probes like `start` on a bound listener.
+    pub fn serve(
+        health: &Arc<Health>,
+        threads: &Arc<Threads>,
+        listener: TcpListener,
+    ) -> io::Result<()> {
+        let addr = listener.local_addr()?;
+        let health = Arc::clone(health);
+        http::serve(threads, String::from("health"), listener, move |path| {
+            health.route(path)
+        })?;
+        info!("Answer health probes on {}", addr);
+
+        Ok(())
+    }
+
+    /// Get the response of the probe of the path, or `None` if it is not a probe.
+    fn route(&self, path: &str) -> Option<Response> {
+        match path {
+            "/healthz" => Some(respond(&self.get_live_failures())),
+            "/readyz" => Some(respond(&self.get_ready_failures())),
+            _ => None,
+        }
+    }
+}
+
+fn respond(failures: &[Check]) -> Response {
+    let failing: Vec<String> = failures
+        .iter()
+        .map(|check| format!("\"{}\"", check))
+        .collect();
+    let (status, s) = if failures.is_empty() {
+        (200, "ok")
+    } else {
+        (503, "fail")
+    };
+
+    Response::new(
+        status,
+        format!(
+            "{{\"status\":\"{}\",\"failing\":[{}]}}",
+            s,
+            failing.join(",")
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ready() -> Option<Response> {
+        Some(Response::new(
+            200,
+            String::from("{\"status\":\"ok\",\"failing\":[]}"),
+        ))
+    }
+
+    fn not_ready(failing: &str) -> Option<Response> {
+        Some(Response::new(
+            503,
+            format!("{{\"status\":\"fail\",\"failing\":[{}]}}", failing),
+        ))
+    }
+
+    #[test]
+    fn test_readiness_transitions() {
+        let health = Health::new();
+        health.set_capture_open(true);
+
+        // Not ready until validated
+        assert_eq!(health.route("/readyz"), not_ready("\"validation\""));
+        health.set_validated(true);
+        assert_eq!(health.route("/readyz"), ready());
+
+        // Not ready while the upstreams are down, and ready again once one is up
+        health.set_upstreams_down(true);
+        assert_eq!(health.route("/readyz"), not_ready("\"upstreams\""));
+        health.set_interface_lost(true);
+        assert_eq!(
+            health.route("/readyz"),
+            not_ready("\"upstreams\",\"interface\"")
+        );
+        health.set_interface_lost(false);
+        health.set_upstreams_down(false);
+        assert_eq!(health.route("/readyz"), ready());
+
+        // Readiness never fails liveness
+        health.set_upstreams_down(true);
+        assert_eq!(health.route("/healthz"), ready());
+        health.set_capture_open(false);
+        assert_eq!(health.route("/healthz"), not_ready("\"capture\""));
+        assert_eq!(health.route("/metrics"), None);
+    }
+}
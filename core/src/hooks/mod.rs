@@ -0,0 +1,541 @@
+use ipnetwork::Ipv4Network;
+use log::{debug, trace, warn};
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::SocketAddrV4;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::privacy::{Privacy, Redactor};
+use crate::rule::Rule;
+use crate::threads::{Purpose, Threads};
+
+/// Represents the number of threads running hooks.
+const HOOK_WORKERS: usize = 2;
+
+/// Represents the max number of hooks waiting to run.
+const HOOK_QUEUE: usize = 64;
+
+/// Represents the max number of hooks queued in a second.
+const HOOK_RATE: usize = 10;
+
+/// Represents the time after which a running hook is killed.
+const HOOK_TIMEOUT: u64 = 10;
+
+/// Represents the interval between 2 checks of a running hook.
+const HOOK_POLL: u64 = 100;
+
+/// Represents the niceness of hooks, a low priority.
+#[cfg(unix)]
+const HOOK_NICENESS: libc::c_int = 10;
+
+/// Represents the protocol of a flow.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Display for Protocol {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Protocol::Tcp => write!(f, "tcp"),
+            Protocol::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+/// Represents a flow reported to hooks.
+#[derive(Clone, Copy, Debug)]
+pub struct Flow {
+    pub id: usize,
+    pub protocol: Protocol,
+    pub src: SocketAddrV4,
+    pub dst: SocketAddrV4,
+    /// Represents the privacy of the rule admitting the flow, which overrides the global one.
+    pub privacy: Option<Privacy>,
+    /// Represents if the flow matches the filters, whose hooks are run.
+    pub is_match: bool,
+}
+
+/// Represents a command to run with its environment variables.
+struct Invocation {
+    command: String,
+    envs: Vec<(&'static str, String)>,
+}
+
+/// Represents the external commands run when flows open and close.
+pub struct Hooks {
+    on_open: Option<String>,
+    on_close: Option<String>,
+    filters: Vec<Ipv4Network>,
+    rule_filters: Vec<String>,
+    timeout: Duration,
+    tx: Option<SyncSender<Invocation>>,
+    next_id: usize,
+    window: Instant,
+    queued: usize,
+    dropped: usize,
+    failures: Arc<AtomicUsize>,
+    redactor: Redactor,
+}
+
+impl Hooks {
+    /// Creates a new `Hooks`. Only flows to destinations in the filters run hooks, or all flows if
+    /// there is no filter.
+    pub fn new(
+        on_open: Option<String>,
+        on_close: Option<String>,
+        filters: Vec<Ipv4Network>,
+    ) -> Hooks {
+        Hooks {
+            on_open,
+            on_close,
+            filters,
+            rule_filters: Vec::new(),
+            timeout: Duration::from_secs(HOOK_TIMEOUT),
+            tx: None,
+            next_id: 0,
+            window: Instant::now(),
+            queued: 0,
+            dropped: 0,
+            failures: Arc::new(AtomicUsize::new(0)),
+            redactor: Redactor::default(),
+        }
+    }
+
+    /// Sets the names of rules whose flows run hooks. Flows must be admitted by one of the rules
+    /// as well as match the filters of destinations, or all flows if there is no rule.
+    pub fn set_rule_filters(&mut self, rule_filters: Vec<String>) {
+        self.rule_filters = rule_filters;
+    }
+
+    /// Sets the time after which a running hook is killed.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Sets the redaction of destinations given to hooks.
+    pub fn set_redactor(&mut self, redactor: Redactor) {
+        self.redactor = redactor;
+    }
+
+    /// Spawns the threads running hooks.
+    pub fn start(&mut self, threads: &Arc<Threads>) -> io::Result<()> {
+        if self.on_open.is_none() && self.on_close.is_none() {
+            return Ok(());
+        }
+
+        let (tx, rx) = mpsc::sync_channel(HOOK_QUEUE);
+        let rx = Arc::new(Mutex::new(rx));
+        for i in 0..HOOK_WORKERS {
+            let rx = Arc::clone(&rx);
+            let failures = Arc::clone(&self.failures);
+            let timeout = self.timeout;
+            Threads::spawn(threads, format!("hook {}", i), Purpose::Hook, move || {
+                work(rx, failures, timeout)
+            })?;
+        }
+        self.tx = Some(tx);
+
+        Ok(())
+    }
+
+    /// Reports a new flow admitted by the given rule if any, whose destination is redacted with
+    /// the privacy of the rule.
+    pub fn open(
+        &mut self,
+        protocol: Protocol,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        rule: Option<&Rule>,
+    ) -> Flow {
+        let privacy = rule.and_then(|rule| rule.get_privacy());
+        let flow = Flow {
+            id: self.next_id,
+            protocol,
+            src,
+            dst,
+            privacy,
+            is_match: self.is_match(dst, rule),
+        };
+        self.next_id = self.next_id.wrapping_add(1);
+
+        if let Some(ref command) = self.on_open {
+            if flow.is_match {
+                let mut envs = vec![
+                    ("FLOW_ID", flow.id.to_string()),
+                    ("PROTO", protocol.to_string()),
+                    ("SRC", src.to_string()),
+                ];
+                self.push_dst(&mut envs, dst, privacy);
+                if let Some(rule) = rule {
+                    envs.push(("RULE", String::from(rule.get_name())));
+                }
+                let invocation = Invocation {
+                    command: command.clone(),
+                    envs,
+                };
+                self.queue(invocation);
+            }
+        }
+
+        flow
+    }
+
+    /// Reports a closed flow with the bytes sent and received.
+    pub fn close(&mut self, flow: &Flow, bytes_up: usize, bytes_down: usize, reason: &str) {
+        if let Some(ref command) = self.on_close {
+            if flow.is_match {
+                let mut envs = vec![
+                    ("FLOW_ID", flow.id.to_string()),
+                    ("PROTO", flow.protocol.to_string()),
+                    ("SRC", flow.src.to_string()),
+                ];
+                self.push_dst(&mut envs, flow.dst, flow.privacy);
+                envs.push(("BYTES_UP", bytes_up.to_string()));
+                envs.push(("BYTES_DOWN", bytes_down.to_string()));
+                envs.push(("REASON", String::from(reason)));
+                let invocation = Invocation {
+                    command: command.clone(),
+                    envs,
+                };
+                self.queue(invocation);
+            }
+        }
+    }
+
+    /// Pushes the redacted destination to the environment, or nothing if destinations are
+    /// omitted.
+    fn push_dst(
+        &self,
+        envs: &mut Vec<(&'static str, String)>,
+        dst: SocketAddrV4,
+        privacy: Option<Privacy>,
+    ) {
+        let redactor = self.redactor.with_privacy(privacy);
+        if redactor.get_privacy() != Privacy::Omitted {
+            envs.push(("DST", redactor.redact(dst).to_string()));
+        }
+    }
+
+    fn is_match(&self, dst: SocketAddrV4, rule: Option<&Rule>) -> bool {
+        let is_rule_match = self.rule_filters.is_empty()
+            || rule.is_some_and(|rule| {
+                self.rule_filters
+                    .iter()
+                    .any(|name| name.as_str() == rule.get_name())
+            });
+
+        is_rule_match
+            && (self.filters.is_empty()
+                || self.filters.iter().any(|filter| filter.contains(*dst.ip())))
+    }
+
+    fn queue(&mut self, invocation: Invocation) {
+        let tx = match self.tx {
+            Some(ref tx) => tx,
+            None => return,
+        };
+
+        // Rate limit
+        if self.window.elapsed().as_secs() >= 1 {
+            self.window = Instant::now();
+            self.queued = 0;
+        }
+        if self.queued >= HOOK_RATE {
+            self.dropped = self.dropped.saturating_add(1);
+            trace!("drop hook {}: rate limited", invocation.command);
+            return;
+        }
+
+        match tx.try_send(invocation) {
+            Ok(_) => self.queued += 1,
+            Err(TrySendError::Full(invocation)) => {
+                self.dropped = self.dropped.saturating_add(1);
+                trace!("drop hook {}: queue is full", invocation.command);
+            }
+            Err(TrySendError::Disconnected(invocation)) => {
+                self.dropped = self.dropped.saturating_add(1);
+                warn!("drop hook {}: no running thread", invocation.command);
+            }
+        }
+    }
+
+    /// Get the number of hooks dropped by the rate limit or the full queue.
+    pub fn get_dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// Get the number of hooks failed, exited unsuccessfully or timed out.
+    pub fn get_failures(&self) -> usize {
+        self.failures.load(Ordering::Relaxed)
+    }
+}
+
+fn work(rx: Arc<Mutex<Receiver<Invocation>>>, failures: Arc<AtomicUsize>, timeout: Duration) {
+    loop {
+        let invocation = match rx.lock().unwrap().recv() {
+            Ok(invocation) => invocation,
+            // The `Hooks` is dropped
+            Err(_) => return,
+        };
+        if let Err(ref e) = run(&invocation, timeout) {
+            failures.fetch_add(1, Ordering::Relaxed);
+            warn!("hook {}: {}", invocation.command, e);
+        }
+    }
+}
+
+/// Runs the invocation in a process group of its own at a low priority, and kills the group once
+/// the command times out, so commands it spawns are killed as well.
+fn run(invocation: &Invocation, timeout: Duration) -> io::Result<()> {
+    let mut command = create_command();
+    let mut child = command
+        .arg(&invocation.command)
+        .envs(invocation.envs.iter().map(|(k, v)| (k, v)))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    debug!("run hook {}", invocation.command);
+
+    let instant = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            if status.success() {
+                return Ok(());
+            }
+            return Err(io::Error::other(format!("exit with {}", status)));
+        }
+        if instant.elapsed() >= timeout {
+            kill_group(&mut child)?;
+            // Reap the child
+            child.wait()?;
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "timed out"));
+        }
+        thread::sleep(Duration::from_millis(HOOK_POLL));
+    }
+}
+
+#[cfg(unix)]
+fn create_command() -> Command {
+    use std::os::unix::process::CommandExt;
+
+    let mut command = Command::new("sh");
+    command.arg("-c");
+    unsafe {
+        command.pre_exec(|| {
+            // A process group of its own
+            if libc::setpgid(0, 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // The niceness is best effort
+            libc::setpriority(libc::PRIO_PROCESS, 0, HOOK_NICENESS);
+
+            Ok(())
+        });
+    }
+
+    command
+}
+
+#[cfg(windows)]
+fn create_command() -> Command {
+    use std::os::windows::process::CommandExt;
+
+    const CREATE_NEW_PROCESS_GROUP: u32 = 0x0000_0200;
+    const BELOW_NORMAL_PRIORITY_CLASS: u32 = 0x0000_4000;
+
+    let mut command = Command::new("cmd");
+    command
+        .arg("/C")
+        .creation_flags(CREATE_NEW_PROCESS_GROUP | BELOW_NORMAL_PRIORITY_CLASS);
+
+    command
+}
+
+#[cfg(not(any(unix, windows)))]
+fn create_command() -> Command {
+    let mut command = Command::new("sh");
+    command.arg("-c");
+
+    command
+}
+
+/// Kills the process group of the child.
+#[cfg(unix)]
+fn kill_group(child: &mut Child) -> io::Result<()> {
+    if unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Kills the child. Processes in its group are not killed without a job object.
+#[cfg(not(unix))]
+fn kill_group(child: &mut Child) -> io::Result<()> {
+    child.kill()
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::env;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::process;
+
+    /// Creates a new empty directory of the test.
+    fn create_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("pcap2socks-hooks-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    /// Waits for the file written by a hook, and returns its content.
+    fn wait_file(path: &Path) -> String {
+        let instant = Instant::now();
+        loop {
+            // The file is complete once the hook exits
+            if let Ok(content) = fs::read_to_string(path) {
+                if content.ends_with("END\n") {
+                    return content;
+                }
+            }
+            assert!(instant.elapsed().as_secs() < 5, "no file {:?}", path);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Parses the environment written by `env`.
+    fn parse_env(content: &str) -> HashMap<String, String> {
+        content
+            .lines()
+            .filter_map(|line| {
+                let mut parts = line.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some(key), Some(value)) => Some((String::from(key), String::from(value))),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_env() {
+        let dir = create_dir("env");
+        let command = |name: &str| {
+            format!(
+                "env > {0}/{1}-$FLOW_ID; echo END >> {0}/{1}-$FLOW_ID",
+                dir.display(),
+                name
+            )
+        };
+        let mut hooks = Hooks::new(
+            Some(command("open")),
+            Some(command("close")),
+            vec!["203.0.113.0/24".parse().unwrap()],
+        );
+        hooks.set_rule_filters(vec![String::from("games")]);
+        hooks.start(&Arc::new(Threads::new(16))).unwrap();
+        let games = Rule::parse("games ports=3074").unwrap();
+        let updates = Rule::parse("updates ports=443").unwrap();
+        let src = "10.6.0.2:40000".parse().unwrap();
+        let dst = "203.0.113.5:3074".parse().unwrap();
+
+        // Only flows of the rule to the network run hooks
+        let flow = hooks.open(Protocol::Udp, src, dst, Some(&games));
+        assert!(flow.is_match);
+        assert!(!hooks.open(Protocol::Tcp, src, dst, Some(&updates)).is_match);
+        assert!(!hooks.open(Protocol::Tcp, src, dst, None).is_match);
+        let other = "198.51.100.1:3074".parse().unwrap();
+        assert!(!hooks.open(Protocol::Udp, src, other, Some(&games)).is_match);
+        hooks.close(&flow, 100, 2000, "idle");
+
+        let envs = parse_env(&wait_file(&dir.join("open-0")));
+        assert_eq!(envs["FLOW_ID"], "0");
+        assert_eq!(envs["PROTO"], "udp");
+        assert_eq!(envs["SRC"], "10.6.0.2:40000");
+        assert_eq!(envs["DST"], "203.0.113.5:3074");
+        assert_eq!(envs["RULE"], "games");
+        assert!(!envs.contains_key("BYTES_UP"));
+
+        let envs = parse_env(&wait_file(&dir.join("close-0")));
+        assert_eq!(envs["FLOW_ID"], "0");
+        assert_eq!(envs["PROTO"], "udp");
+        assert_eq!(envs["DST"], "203.0.113.5:3074");
+        assert_eq!(envs["BYTES_UP"], "100");
+        assert_eq!(envs["BYTES_DOWN"], "2000");
+        assert_eq!(envs["REASON"], "idle");
+
+        // Flows not matching never ran hooks
+        let mut names: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["close-0", "open-0"]);
+        assert_eq!(hooks.get_failures(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_group_priority() {
+        let dir = create_dir("priority");
+        let path = dir.join("stat");
+        let invocation = Invocation {
+            command: format!("cat /proc/self/stat > {}", path.display()),
+            envs: Vec::new(),
+        };
+        run(&invocation, Duration::from_secs(5)).unwrap();
+
+        // The process group and the niceness, after the name in parentheses
+        let stat = fs::read_to_string(&path).unwrap();
+        let fields: Vec<&str> = stat[stat.rfind(')').unwrap() + 2..].split(' ').collect();
+        let group: libc::pid_t = fields[2].parse().unwrap();
+        let niceness: libc::c_int = fields[16].parse().unwrap();
+        assert_ne!(group, unsafe { libc::getpgrp() });
+        assert!(niceness >= HOOK_NICENESS, "niceness {}", niceness);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_timeout_kills_group() {
+        let dir = create_dir("timeout");
+        let path = dir.join("pid");
+        let invocation = Invocation {
+            command: format!("sleep 30 & echo $! > {}; wait", path.display()),
+            envs: Vec::new(),
+        };
+        let e = run(&invocation, Duration::from_millis(500)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+
+        // The command spawned by the hook is killed with it, gone or a zombie left to the init
+        let pid = fs::read_to_string(&path).unwrap();
+        let stat = PathBuf::from(format!("/proc/{}/stat", pid.trim()));
+        let instant = Instant::now();
+        loop {
+            match fs::read_to_string(&stat) {
+                Err(_) => break,
+                Ok(ref content) if content[content.rfind(')').unwrap() + 2..].starts_with('Z') => {
+                    break
+                }
+                Ok(_) => {}
+            }
+            assert!(instant.elapsed().as_secs() < 5, "sleep {} is alive", pid);
+            thread::sleep(Duration::from_millis(10));
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,125 @@
+use std::io;
+use std::net::TcpListener;
+use std::sync::Arc;
+
+use crate::threads::Threads;
+
+/// Represents the max size of the head of a request.
+#[cfg(feature = "http")]
+const MAX_HEAD_SIZE: usize = 4096;
+
+/// Represents the timeout of reading a request and writing its response. Requests are served one
+/// by one, so a client stalls others for at most the timeout.
+#[cfg(feature = "http")]
+const IO_TIMEOUT: u64 = 1;
+
+/// Represents a response of the HTTP endpoint, whose body is always JSON.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Response {
+    status: u16,
+    body: String,
+}
+
+impl Response {
+    /// Creates a new `Response`.
+    pub fn new(status: u16, body: String) -> Response {
+        Response { status, body }
+    }
+}
+
+/// Spawns the thread serving HTTP requests on the listener. A request is answered by the handler
+/// of its path and the connection is closed, which is all the probes of the endpoint need.
+#[cfg(feature = "http")]
+pub fn serve<F>(
+    threads: &Arc<Threads>,
+    name: String,
+    listener: TcpListener,
+    handler: F,
+) -> io::Result<()>
+where
+    F: Fn(&str) -> Option<Response> + Send + 'static,
+{
+    use crate::threads::Purpose;
+    use log::debug;
+
+    Threads::spawn(threads, name.clone(), Purpose::Http, move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|mut stream| respond(&mut stream, &handler));
+            if let Err(ref e) = result {
+                debug!("{}: {}", name, e);
+            }
+        }
+    })?;
+
+    Ok(())
+}
+
+#[cfg(not(feature = "http"))]
+pub fn serve<F>(_: &Arc<Threads>, _: String, _: TcpListener, _: F) -> io::Result<()>
+where
+    F: Fn(&str) -> Option<Response> + Send + 'static,
+{
+    Err(io::Error::other(
+        "HTTP endpoints are not supported without the http feature",
+    ))
+}
+
+#[cfg(feature = "http")]
+fn respond<F>(stream: &mut std::net::TcpStream, handler: &F) -> io::Result<()>
+where
+    F: Fn(&str) -> Option<Response>,
+{
+    use std::io::{Read, Write};
+    use std::time::Duration;
+
+    stream.set_read_timeout(Some(Duration::from_secs(IO_TIMEOUT)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(IO_TIMEOUT)))?;
+
+    // Request head, the body is ignored
+    let mut head = Vec::new();
+    let mut buffer = [0u8; 512];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        if head.len() >= MAX_HEAD_SIZE {
+            break;
+        }
+        let size = stream.read(&mut buffer)?;
+        if size == 0 {
+            break;
+        }
+        head.extend_from_slice(&buffer[..size]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let mut words = head.lines().next().unwrap_or("").split_whitespace();
+    let method = words.next().unwrap_or("");
+    // The query is ignored
+    let path = words.next().unwrap_or("").split('?').next().unwrap_or("");
+
+    let response = match method {
+        "GET" | "HEAD" => handler(path)
+            .unwrap_or_else(|| Response::new(404, String::from("{\"error\":\"not found\"}"))),
+        _ => Response::new(405, String::from("{\"error\":\"method not allowed\"}")),
+    };
+
+    let mut s = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason(response.status),
+        response.body.len()
+    );
+    if method != "HEAD" {
+        s.push_str(&response.body);
+    }
+    stream.write_all(s.as_bytes())?;
+    stream.flush()
+}
+
+#[cfg(feature = "http")]
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
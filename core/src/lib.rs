@@ -0,0 +1,5060 @@
+use log::{debug, info, trace, warn};
+use lru::LruCache;
+use std::cmp::{max, min};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub mod backoff;
+mod cacher;
+mod congestion;
+pub mod console;
+pub mod discovery;
+mod dns;
+pub mod drops;
+pub mod envelope;
+pub mod fanout;
+mod forwarder;
+pub mod frame;
+pub mod gateway;
+pub mod handoff;
+pub mod health;
+pub mod hooks;
+mod http;
+pub mod monitor;
+pub mod notify;
+pub mod observer;
+mod packet;
+mod pcap;
+pub mod privacy;
+mod random;
+pub mod record;
+mod route;
+pub mod rule;
+mod scavenger;
+pub mod schedule;
+mod soak;
+mod socks;
+pub mod standby;
+pub mod stats;
+pub mod stun;
+mod sync;
+mod tcp;
+#[cfg(test)]
+mod testing;
+pub mod threads;
+mod timer;
+pub mod upnp;
+pub mod upstream;
+
+pub use self::forwarder::Forwarder;
+pub use self::random::generate_seed;
+use self::socks::{get_port_exhaustions, is_port_exhausted, is_unsupported, probe_path_mtu};
+pub use self::socks::{is_path_mtu_probe_supported, test_nat};
+pub use self::socks::{
+    ChainForward, Credentials, FilterForward, Forward, ProxyType, Secret, TeeForward, Verdict,
+};
+use self::socks::{DatagramWorker, Pool, ProxyConnector, StreamWorker};
+use backoff::Site;
+use cacher::RandomCacher;
+use console::{dhcp, Console};
+use discovery::{Discovery, DiscoveryPolicy};
+use dns::{DnsCache, DnsForward, DnsStats, GatewayForward, Lookup};
+use drops::{DropLog, DropReason};
+use envelope::Envelope;
+use fanout::Fanout;
+use gateway::Service;
+use handoff::{Handoff, Phase};
+use health::Health;
+use hooks::{Flow, Hooks, Protocol};
+use monitor::{Kill, Monitor};
+use notify::{Event, Notifier};
+use packet::layer::{Layer, LayerTypes};
+use packet::{Defraggler, Indicator, Malformed};
+pub use pcap::{
+    probe, HardwareAddr, HostInterfaces, Interface, Interfaces, Probe, Receiver, Sender,
+};
+use upnp::ssdp::SSDP_PORT;
+use upnp::{Control, Lease, Leases, Ssdp};
+// Channels of other backends implement the traits of the channels of pcap
+pub use pnet::datalink::{DataLinkReceiver, DataLinkSender, NetworkInterface};
+use privacy::Redactor;
+use random::{derive_seed, Random};
+use record::{Kind, Recording};
+use rule::{Admission, Rules};
+use soak::Snapshot;
+use standby::{Role, Standby, Transition};
+use stats::{Export, FlowStats};
+use threads::Threads;
+use timer::{TimerHandle, TimerWheel};
+use upstream::{Route, Upstreams};
+
+/// Gets a list of available network interfaces for the current machine.
+pub fn interfaces() -> Vec<Interface> {
+    pcap::interfaces()
+        .into_iter()
+        .filter(|inter| !inter.is_loopback)
+        .collect()
+}
+
+/// Gets a list of available network interfaces which is possibly can be used for the current machine.
+fn auto_interfaces() -> Vec<Interface> {
+    // With specified IP address
+    let mut inters: Vec<Interface> = interfaces()
+        .into_iter()
+        .filter(|inter| !inter.ip_addrs[0].is_unspecified())
+        .collect();
+    // Is up
+    if inters.len() > 1 {
+        inters.retain(|inter| inter.is_up);
+    }
+
+    inters
+}
+
+/// Gets an available network interface match the name.
+pub fn interface(name: Option<String>) -> Option<Interface> {
+    let inters = match name {
+        Some(name) => {
+            let mut inters = interfaces();
+            inters.retain(|current_inter| current_inter.name == name);
+            inters
+        }
+        None => auto_interfaces(),
+    };
+
+    if inters.len() != 1 {
+        None
+    } else {
+        Some(inters[0].clone())
+    }
+}
+
+/// Prints the dialog with information how to set up the proxied device.
+pub fn show_info(ip_addr: Ipv4Addr, gateway: Ipv4Addr, mtu: u16) {
+    let ip_addr_octets = ip_addr.octets();
+    let gateway_octets = gateway.octets();
+    let mask = Ipv4Addr::new(
+        !(ip_addr_octets[0] ^ gateway_octets[0]),
+        !(ip_addr_octets[1] ^ gateway_octets[1]),
+        !(ip_addr_octets[2] ^ gateway_octets[2]),
+        0,
+    );
+    info!("Please set the network of your device which is going to be proxied with the following parameters:");
+    info!("    ┌─{:─<10}─{:─>15}─┐", "", "");
+    info!("    │ {:<10} {:>15} │", "IP Address", ip_addr);
+    info!("    │ {:<10} {:>15} │", "Mask", mask);
+    info!("    │ {:<10} {:>15} │", "Gateway", gateway);
+    info!("    │─{:─<10}─{:─>15}─│", "", "");
+    info!("    │ {:<10} {:>15} │", "MTU", mtu);
+    info!("    └─{:─<10}─{:─>15}─┘", "", "");
+}
+
+/// Represents the max distance of `u32` values between packets in an `u32` window.
+const MAX_U32_WINDOW_SIZE: usize = 256 * 1024;
+
+/// Get the indexes of the rule and the window labelling a flow in the statistics export.
+fn get_label_indexes(admission: Option<&Admission>) -> (Option<u16>, Option<u16>) {
+    match admission {
+        Some(admission) => (
+            Some(admission.rule as u16),
+            admission.window.map(|window| window as u16),
+        ),
+        None => (None, None),
+    }
+}
+
+/// Represents the number of destination prefixes in a dump of latencies.
+const DUMP_LATENCIES: usize = 10;
+
+/// Represents the interval between 2 scavenges.
+const SCAVENGE_INTERVAL: u64 = 60;
+/// Represents the interval between 2 publications of the top UDP talkers to the monitor.
+const TALKERS_INTERVAL: u64 = 1;
+/// Represents the number of source ports published as the top UDP talkers of a source.
+const TOP_TALKERS: usize = 16;
+/// Represents the interval between 2 saves of the state.
+const STATE_SAVE_INTERVAL: u64 = 10;
+/// Represents the interval between 2 prunes of records older than the retention.
+const RECORD_PRUNE_INTERVAL: u64 = 24 * 60 * 60;
+/// Represents the max number of binds of a restored UDP association on a local port still in use,
+/// and the base of the backoff between them in milliseconds. A socket closed by the old process in
+/// a handoff may be released by the kernel a little later.
+const RESTORE_BINDS: usize = 10;
+const RESTORE_BIND_INTERVAL: u64 = 10;
+/// Represents the interval between 2 exports of statistics.
+const STATS_EXPORT_INTERVAL: u64 = 1;
+/// Represents the time a closed stream is kept before reset by the scavenger.
+const STALE_TIMEOUT: u64 = 120;
+
+/// Represents the interval between 2 kills in the chaos mode.
+const CHAOS_INTERVAL: u64 = 5;
+/// Represents the number of kills in the chaos mode between 2 flaps of an upstream.
+const CHAOS_FLAP_KILLS: usize = 6;
+
+/// Represents the interval between 2 checks of the associations of UDP servers.
+const UDP_SERVER_CHECK_INTERVAL: u64 = 1;
+
+/// Represents the base of the time before binding the association of a UDP server again after
+/// a failure.
+const UDP_SERVER_BACKOFF: u64 = 1;
+
+/// Represents the number of clients of a UDP server in the soak report.
+const TOP_UDP_CLIENTS: usize = 3;
+
+/// Represents the default time a killed UDP association is not bound again.
+pub const DEFAULT_UDP_QUARANTINE: u64 = 30;
+
+/// Represents the default max number of ARP replies to a requester in a second.
+pub const DEFAULT_ARP_RATE: usize = 4;
+
+/// Represents the min path MTU to upstreams.
+const MIN_PATH_MTU: u16 = 576;
+
+/// Represents the size of IPv4 and TCP headers without options.
+const TCP_HEADERS_SIZE: u16 = 40;
+
+/// Represents the interval between 2 checks of the addresses of the interface.
+const INTERFACE_CHECK_INTERVAL: u64 = 5;
+
+/// Represents the interval between 2 checks of the traffic against the quota.
+const QUOTA_CHECK_INTERVAL: u64 = 1;
+
+/// Represents the number of gratuitous ARP replies sent in an announcement of the gateway, in case
+/// some are lost.
+const GRATUITOUS_ARP_COUNT: usize = 3;
+
+/// Represents the max limit of UDP port for binding in local.
+pub const PORT_COUNT: usize = 64;
+
+/// Represents the default time the source has to complete a TCP handshake after the ACK/SYN.
+pub const DEFAULT_TCP_HANDSHAKE_TIMEOUT: u64 = 10;
+
+/// Represents the default time without datagrams after which a UDP association is closed.
+pub const DEFAULT_UDP_IDLE_TIMEOUT: u64 = 300;
+
+/// Represents the default time without data after which a TCP connection is closed.
+pub const DEFAULT_TCP_IDLE_TIMEOUT: u64 = 7200;
+
+/// Represents the default max size of data a TCP connection queues for sending to the proxy.
+pub const DEFAULT_TCP_SEND_BUFFER: usize = socks::DEFAULT_SEND_CAPACITY;
+
+/// Represents the default max number of half-open TCP connections of the source.
+pub const DEFAULT_TCP_MAX_HALF_OPEN: usize = 64;
+
+/// Represents the MSS of the source if its SYN has no MSS option or an MSS option of 0.
+const TCP_DEFAULT_PEER_MSS: u16 = 536;
+
+/// Represents the default min MSS of the source, smaller ones advertised are clamped to it.
+pub const DEFAULT_TCP_MIN_MSS: u16 = 536;
+
+/// Represents the smallest min MSS of the source allowed.
+pub const TCP_MIN_MSS_FLOOR: u16 = 88;
+
+/// Represents a timer of a flow in the timer wheel of the `Redirector`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Timer {
+    /// The handshake timeout of a half-open TCP connection.
+    HalfOpen((u16, SocketAddrV4)),
+    /// The timeout after which a closed stream is reset.
+    Stale((u16, SocketAddrV4)),
+    /// The idle timeout of a TCP connection, from its last activity.
+    Idle((u16, SocketAddrV4)),
+    /// The idle timeout of a UDP association by its index, from its last activity.
+    UdpIdle(usize),
+    /// The idle timeout of a TCP connection bridged to the real gateway.
+    TcpBridge((u16, SocketAddrV4)),
+    /// The idle timeout of the UDP datagrams of a source port bridged to the real gateway.
+    UdpBridge(u16),
+}
+
+/// Represents a UDP server of the source, whose clients send first. Its association is bound
+/// eagerly, never reused for other ports and bound again whenever it fails.
+#[derive(Clone, Copy, Debug)]
+struct UdpServer {
+    retry_at: Instant,
+    failures: usize,
+    binds: usize,
+}
+
+/// Represents the handling of a SYN beyond the max number of half-open TCP connections.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HalfOpenOverflow {
+    /// Drops the SYN silently.
+    Drop,
+    /// Answers the SYN with ACK/RST without keeping any state.
+    Reset,
+    /// Answers the SYN with an ACK/SYN of a SYN cookie without keeping any state, the connection
+    /// opens once the source acknowledges it.
+    Cookie,
+}
+
+impl Display for HalfOpenOverflow {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            HalfOpenOverflow::Drop => write!(f, "drop"),
+            HalfOpenOverflow::Reset => write!(f, "reset"),
+            HalfOpenOverflow::Cookie => write!(f, "cookie"),
+        }
+    }
+}
+
+/// Represents the handling of established TCP connections through an upstream which goes down.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum ResumeStrategy {
+    /// Leaves the connections as they are, they resume if the upstream comes back in time.
+    Freeze,
+    /// Closes the connections to the source with FIN, so applications retry at once.
+    Fin,
+    /// Resets the connections to the source.
+    Rst,
+}
+
+impl ResumeStrategy {
+    /// Creates a `ResumeStrategy` by its name.
+    pub fn from_name(name: &str) -> Option<ResumeStrategy> {
+        match name {
+            "freeze" => Some(ResumeStrategy::Freeze),
+            "fin" => Some(ResumeStrategy::Fin),
+            "rst" => Some(ResumeStrategy::Rst),
+            _ => None,
+        }
+    }
+}
+
+impl Display for ResumeStrategy {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            ResumeStrategy::Freeze => write!(f, "freeze"),
+            ResumeStrategy::Fin => write!(f, "fin"),
+            ResumeStrategy::Rst => write!(f, "rst"),
+        }
+    }
+}
+
+/// Represents the statistics of the loss and reordering of a TCP connection.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TcpStats {
+    /// Represents the number of segments from the source ahead of the expected sequence.
+    pub out_of_order: usize,
+    /// Represents the number of segments from the source already received.
+    pub duplicates: usize,
+    /// Represents the number of fast retransmissions to the source.
+    pub fast_retransmissions: usize,
+    /// Represents the number of retransmissions to the source for the retransmission timeout.
+    pub timeouts: usize,
+    /// Represents the bytes retransmitted to the source.
+    pub retransmitted: usize,
+}
+
+impl TcpStats {
+    fn add(&mut self, other: &TcpStats) {
+        self.out_of_order += other.out_of_order;
+        self.duplicates += other.duplicates;
+        self.fast_retransmissions += other.fast_retransmissions;
+        self.timeouts += other.timeouts;
+        self.retransmitted += other.retransmitted;
+    }
+}
+
+impl Display for TcpStats {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} out-of-order, {} duplicates, {} fast retransmissions, {} timeouts, {} Bytes retransmitted",
+            self.out_of_order,
+            self.duplicates,
+            self.fast_retransmissions,
+            self.timeouts,
+            self.retransmitted
+        )
+    }
+}
+
+/// Represents the state of a TCP connection of the source.
+#[derive(Default)]
+struct TcpFlow {
+    stream: Option<StreamWorker>,
+    /// Represents the flow in the hooks once the connection is connected.
+    flow: Option<Flow>,
+    /// Represents if the connection is connecting.
+    is_connecting: bool,
+    /// Represents the proxy of the connection.
+    remote: Option<SocketAddr>,
+    /// Represents if the first round trip of data through the proxy is not sampled yet.
+    is_rtt_pending: bool,
+    /// Represents the rule admitting the connection.
+    rule: Option<Admission>,
+    /// Represents the handshake timer of the connection not acknowledged by the source yet, which
+    /// starts from its SYN, or from the ACK/SYN once the proxy connects.
+    half_open: Option<TimerHandle>,
+    /// Represents if the send queue is congested, which is told once the window opens again.
+    is_congested: bool,
+    /// Represents if the connection is picked up in the middle and still connecting.
+    is_picking_up: bool,
+    sequence: Option<u32>,
+    acknowledgement: Option<u32>,
+    duplicates: usize,
+    last_retransmission: Option<Instant>,
+    cache: Option<RandomCacher>,
+    /// Represents the start and the number of pure ACK duplicates in the current second.
+    duplicate_rate: Option<(Instant, usize)>,
+    stats: Option<TcpStats>,
+    /// Represents the MSS advertised by the source, and the effective one.
+    peer_mss: Option<(Option<u16>, u16)>,
+    /// Represents the stale timer of the connection whose worker was found closed.
+    closed: Option<TimerHandle>,
+    idle: Option<TimerHandle>,
+    /// Represents the last packet and the idle timer of the connection bridged to the real
+    /// gateway.
+    bridge: Option<(Instant, TimerHandle)>,
+}
+
+impl TcpFlow {
+    /// Returns if the state keeps nothing of the connection.
+    fn is_empty(&self) -> bool {
+        self.stream.is_none()
+            && self.flow.is_none()
+            && !self.is_connecting
+            && self.remote.is_none()
+            && !self.is_rtt_pending
+            && self.rule.is_none()
+            && self.half_open.is_none()
+            && !self.is_congested
+            && !self.is_picking_up
+            && self.sequence.is_none()
+            && self.acknowledgement.is_none()
+            && self.duplicates == 0
+            && self.last_retransmission.is_none()
+            && self.cache.is_none()
+            && self.duplicate_rate.is_none()
+            && self.stats.is_none()
+            && self.peer_mss.is_none()
+            && self.closed.is_none()
+            && self.idle.is_none()
+            && self.bridge.is_none()
+    }
+}
+
+/// Represents the channel redirect traffic to the proxy of SOCKS or loopback to the source in pcap.
+pub struct Redirector {
+    tx: Arc<Mutex<Forwarder>>,
+    is_tx_src_hardware_addr_set: bool,
+    is_src_llc_snap: bool,
+    src_ip_addr: Ipv4Addr,
+    local_ip_addr: Option<Ipv4Addr>,
+    upstreams: Upstreams,
+    credentials: Credentials,
+    hooks: Hooks,
+    threads: Arc<Threads>,
+    /// Represents the resource envelope of the source.
+    envelope: Envelope,
+    /// Represents the limits of distinct UDP destinations of the source.
+    fanout: Fanout,
+    discovery: Discovery,
+    /// Represents the resolver of DNS queries to the gateway.
+    gateway_dns: Option<SocketAddrV4>,
+    /// Represents the number of packets to the gateway refused locally.
+    gateway_refused: usize,
+    /// Represents the UDP servers of the source by their ports, whose associations are pinned.
+    udp_servers: BTreeMap<u16, UdpServer>,
+    last_udp_server_check: Instant,
+    /// Represents the map mapping a TCP port of the published gateway to the local address of its
+    /// service.
+    gateway_services: HashMap<u16, SocketAddr>,
+    /// Represents the cache of DNS answers shared by the captured hosts, or `None` if DNS queries
+    /// are forwarded as is.
+    dns: Option<Arc<Mutex<DnsCache>>>,
+    /// Represents the map mapping a TCP connection to its state.
+    tcp_flows: HashMap<(u16, SocketAddrV4), TcpFlow>,
+    /// Represents the map mapping a UDP association through a proxy by its index, whose first
+    /// round trip of datagrams is not sampled yet, to its proxy and its first destination.
+    udp_rtt_pending: HashMap<usize, (SocketAddr, SocketAddrV4)>,
+    /// Represents the pool of pre-warmed connections to the proxy.
+    tcp_pool: Option<Arc<Pool>>,
+    resume_strategy: ResumeStrategy,
+    redactor: Redactor,
+    proxy_type: ProxyType,
+    /// Represents if the UDP dropped for a proxy not relaying UDP is warned.
+    is_udp_unsupported_warned: bool,
+    recording: Option<Recording>,
+    rules: Rules,
+    /// Represents the recordings into the directories of the rules.
+    rule_recordings: Vec<Option<Recording>>,
+    /// Represents the number of new flows admitted by each rule.
+    rule_admitted: Vec<usize>,
+    /// Represents the number of flows of each rule closed by expiries.
+    rule_expired: Vec<usize>,
+    monitor: Option<Arc<Monitor>>,
+    /// Represents the number of dumps requested in the monitor which are logged.
+    last_dump: usize,
+    /// Represents the number of expiries requested in the monitor which are applied.
+    last_expiry: usize,
+    /// Represents the number of kills requested in the monitor which are applied.
+    last_kill: usize,
+    tcp_failovers: HashMap<ResumeStrategy, usize>,
+    /// Represents the timers of flows.
+    timers: TimerWheel<Timer>,
+    tcp_handshake_timeout: Duration,
+    tcp_max_half_open: usize,
+    tcp_half_open_overflow: HalfOpenOverflow,
+    /// Represents the number of half-open TCP connections reset for the handshake timeout.
+    tcp_half_open_expired: usize,
+    /// Represents the number of SYN refused for the max number of half-open TCP connections.
+    tcp_half_open_refused: usize,
+    /// Represents the number of TCP connections opened from the acknowledgement of a SYN cookie.
+    tcp_cookies_accepted: usize,
+    tcp_idle_timeout: Option<Duration>,
+    tcp_send_buffer: usize,
+    /// Represents the number of TCP connections closed for the idle timeout.
+    tcp_idle_expired: usize,
+    /// Represents the statistics of closed TCP connections.
+    tcp_closed_stats: TcpStats,
+    tcp_keepalives: usize,
+    is_verify_checksum: bool,
+    /// Represents the packets dropped before flows for each reason.
+    drops: DropLog,
+    /// Represents the number of ACK/SYN from the source in TCP simultaneous opens.
+    tcp_simultaneous_opens: usize,
+    is_pickup_established: bool,
+    /// Represents the number of TCP connections picked up in the middle.
+    tcp_pickups: usize,
+    /// Represents the number of TCP connections failed to pick up, which are reset.
+    tcp_pickup_failures: usize,
+    tcp_min_mss: u16,
+    tcp_mss_clamped: usize,
+    tcp_dropped_duplicates: usize,
+    datagrams: Vec<Option<DatagramWorker>>,
+    /// Represents the flows of datagrams and the bytes sent and received before the flows.
+    datagram_flows: Vec<Option<(Flow, (usize, usize))>>,
+    /// Represents the rules admitting the datagrams.
+    datagram_rules: Vec<Option<Admission>>,
+    udp_initial_port: u16,
+    is_normalize_remote_port: bool,
+    ping_ttl: Option<u8>,
+    console: Console,
+    /// Represents the map mapping a source port to a local port (datagram).
+    datagram_map: Vec<u16>,
+    /// Represents the LRU mapping a local port to a source port.
+    udp_lru: LruCache<u16, u16>,
+    /// Represents the map mapping a source port of a killed UDP association to the time it can be
+    /// bound again.
+    udp_quarantine_map: HashMap<u16, Instant>,
+    /// Represents the port mappings leased to the source through UPnP.
+    upnp_leases: Arc<Mutex<Leases>>,
+    /// Represents the hardware address of the real gateway, which new flows are bridged to by
+    /// the gateway fallback policy.
+    gateway_hardware_addr: Option<HardwareAddr>,
+    /// Represents the map mapping a source port whose UDP datagrams are bridged to the real
+    /// gateway to its last datagram and its idle timer.
+    udp_bridge_map: HashMap<u16, (Instant, TimerHandle)>,
+    udp_quarantine: Duration,
+    udp_idle_timeout: Option<Duration>,
+    /// Represents the number of UDP associations closed for the idle timeout.
+    udp_idle_expired: usize,
+    /// Represents the map mapping the index of a UDP association to its idle timer.
+    udp_idle_map: HashMap<usize, TimerHandle>,
+    defrag: Defraggler,
+    last_scavenge: Instant,
+    last_talkers: Instant,
+    last_retransmission_check: Instant,
+    repairs: usize,
+    soak_interval: Option<Duration>,
+    last_soak: Instant,
+    last_snapshot: Option<Snapshot>,
+    is_chaos: bool,
+    last_chaos: Instant,
+    chaos_kills: usize,
+    random: Random,
+    /// Represents the max number of ARP replies to a requester in a second.
+    arp_rate: usize,
+    /// Represents the map mapping a requester to the start and the number of ARP replies in the
+    /// current second.
+    arp_rate_map: HashMap<HardwareAddr, (Instant, usize)>,
+    arp_suppressed: usize,
+    path_mtu_interval: Option<Duration>,
+    last_path_mtu_probe: Instant,
+    /// Represents the smallest path MTU to the upstreams, or `None` if it is not probed.
+    path_mtu: Option<u16>,
+    /// Represents the path the state is saved to.
+    state_path: Option<PathBuf>,
+    last_state_save: Instant,
+    /// Represents the last prune of records, or `None` if they were never pruned.
+    last_record_prune: Option<Instant>,
+    stats_export: Option<Export>,
+    last_stats_export: Instant,
+    is_stats_published: bool,
+    /// Represents the interface watched for address changes.
+    inter: Option<Interface>,
+    /// Represents the interfaces of the host the interface is checked in.
+    interfaces: Box<dyn Interfaces>,
+    last_interface_check: Instant,
+    /// Represents if the interface is not found in the last check.
+    is_interface_lost: bool,
+    mtu: u16,
+    notifier: Notifier,
+    /// Represents if the upstreams are in the fallback mode in the last notification.
+    is_fallback_notified: bool,
+    /// Represents if UDP associations through the upstreams failed since the last one bound.
+    is_udp_degraded: bool,
+    /// Represents the quota of the traffic of all the workers of the monitor in bytes.
+    quota: Option<u64>,
+    is_quota_exceeded: bool,
+    last_quota_check: Instant,
+    health: Option<Arc<Health>>,
+    standby: Option<Standby>,
+    /// Represents if the `Redirector` is the standby, which serves nothing.
+    is_standby: bool,
+    last_standby_check: Instant,
+    last_announce: Option<Instant>,
+    handoff: Option<Arc<Handoff>>,
+    /// Represents the time the drain of TCP connections ends after handing off.
+    drain_until: Option<Instant>,
+}
+
+impl Redirector {
+    /// Creates a new `Redirector`.
+    pub fn new(
+        tx: Arc<Mutex<Forwarder>>,
+        src_ip_addr: Ipv4Addr,
+        local_ip_addr: Option<Ipv4Addr>,
+        upstreams: Upstreams,
+        initial: u16,
+        max_threads: usize,
+    ) -> Redirector {
+        let mut redirector = Redirector {
+            tx,
+            is_tx_src_hardware_addr_set: false,
+            is_src_llc_snap: false,
+            src_ip_addr,
+            local_ip_addr,
+            upstreams,
+            credentials: Credentials::new(),
+            hooks: Hooks::new(None, None, Vec::new()),
+            threads: Arc::new(Threads::new(max_threads)),
+            tcp_flows: HashMap::new(),
+            udp_rtt_pending: HashMap::new(),
+            tcp_pool: None,
+            resume_strategy: ResumeStrategy::Freeze,
+            redactor: Redactor::default(),
+            proxy_type: ProxyType::Socks5,
+            is_udp_unsupported_warned: false,
+            recording: None,
+            rules: Rules::default(),
+            rule_recordings: Vec::new(),
+            rule_admitted: Vec::new(),
+            rule_expired: Vec::new(),
+            monitor: None,
+            last_dump: 0,
+            last_expiry: 0,
+            last_kill: 0,
+            tcp_failovers: HashMap::new(),
+            timers: TimerWheel::new(),
+            tcp_handshake_timeout: Duration::from_secs(DEFAULT_TCP_HANDSHAKE_TIMEOUT),
+            tcp_max_half_open: DEFAULT_TCP_MAX_HALF_OPEN,
+            tcp_half_open_overflow: HalfOpenOverflow::Reset,
+            tcp_half_open_expired: 0,
+            tcp_half_open_refused: 0,
+            tcp_cookies_accepted: 0,
+            tcp_idle_timeout: Some(Duration::from_secs(DEFAULT_TCP_IDLE_TIMEOUT)),
+            tcp_send_buffer: DEFAULT_TCP_SEND_BUFFER,
+            tcp_idle_expired: 0,
+            tcp_closed_stats: TcpStats::default(),
+            tcp_keepalives: 0,
+            is_verify_checksum: false,
+            drops: DropLog::new(),
+            tcp_simultaneous_opens: 0,
+            is_pickup_established: false,
+            tcp_pickups: 0,
+            tcp_pickup_failures: 0,
+            tcp_min_mss: DEFAULT_TCP_MIN_MSS,
+            tcp_mss_clamped: 0,
+            tcp_dropped_duplicates: 0,
+            datagrams: (0..PORT_COUNT).map(|_| None).collect(),
+            datagram_flows: (0..PORT_COUNT).map(|_| None).collect(),
+            datagram_rules: vec![None; PORT_COUNT],
+            udp_initial_port: initial,
+            is_normalize_remote_port: true,
+            ping_ttl: None,
+            console: Console::new(),
+            datagram_map: vec![0u16; u16::MAX as usize],
+            udp_lru: LruCache::new(PORT_COUNT),
+            udp_quarantine_map: HashMap::new(),
+            upnp_leases: Arc::new(Mutex::new(Leases::new())),
+            gateway_hardware_addr: None,
+            udp_bridge_map: HashMap::new(),
+            udp_quarantine: Duration::from_secs(DEFAULT_UDP_QUARANTINE),
+            udp_idle_timeout: Some(Duration::from_secs(DEFAULT_UDP_IDLE_TIMEOUT)),
+            udp_idle_expired: 0,
+            udp_idle_map: HashMap::new(),
+            defrag: Defraggler::new(),
+            last_scavenge: Instant::now(),
+            last_talkers: Instant::now(),
+            last_retransmission_check: Instant::now(),
+            repairs: 0,
+            soak_interval: None,
+            last_soak: Instant::now(),
+            last_snapshot: None,
+            is_chaos: false,
+            last_chaos: Instant::now(),
+            chaos_kills: 0,
+            random: Random::derive(generate_seed(), "chaos"),
+            arp_rate: DEFAULT_ARP_RATE,
+            arp_rate_map: HashMap::new(),
+            arp_suppressed: 0,
+            envelope: Envelope::new(
+                src_ip_addr,
+                envelope::DEFAULT_MAX_FLOWS,
+                envelope::DEFAULT_CONNECT_RATE,
+            ),
+            fanout: Fanout::new(src_ip_addr),
+            discovery: Discovery::new(src_ip_addr),
+            gateway_dns: None,
+            gateway_refused: 0,
+            gateway_services: HashMap::new(),
+            udp_servers: BTreeMap::new(),
+            last_udp_server_check: Instant::now(),
+            dns: None,
+            path_mtu_interval: None,
+            last_path_mtu_probe: Instant::now(),
+            path_mtu: None,
+            state_path: None,
+            last_state_save: Instant::now(),
+            last_record_prune: None,
+            stats_export: None,
+            last_stats_export: Instant::now(),
+            is_stats_published: false,
+            inter: None,
+            interfaces: Box::new(HostInterfaces),
+            last_interface_check: Instant::now(),
+            is_interface_lost: false,
+            mtu: 0,
+            notifier: Notifier::new(Vec::new()),
+            is_fallback_notified: false,
+            is_udp_degraded: false,
+            quota: None,
+            is_quota_exceeded: false,
+            last_quota_check: Instant::now(),
+            health: None,
+            standby: None,
+            is_standby: false,
+            last_standby_check: Instant::now(),
+            last_announce: None,
+            handoff: None,
+            drain_until: None,
+        };
+        if let Some(local_ip_addr) = local_ip_addr {
+            redirector
+                .tx
+                .lock()
+                .unwrap()
+                .set_local_ip_addr(local_ip_addr);
+        }
+        for i in 0..PORT_COUNT {
+            redirector.udp_lru.put(i as u16, 0);
+        }
+
+        redirector
+    }
+
+    /// Sets the interface the `Redirector` captures on and the MTU shown in the instructions. The
+    /// addresses of the interface are watched, and the forwarder follows their changes.
+    pub fn set_interface(&mut self, inter: Interface, mtu: u16) {
+        self.inter = Some(inter);
+        self.mtu = mtu;
+        self.last_interface_check = Instant::now();
+    }
+
+    /// Sets the interfaces of the host the interface is checked in, which are the ones found by
+    /// pcap by default.
+    pub fn set_interfaces(&mut self, interfaces: Box<dyn Interfaces>) {
+        self.interfaces = interfaces;
+    }
+
+    /// Sets the interval between 2 probes of the path MTU to upstreams, or `None` to not probe.
+    /// New TCP connections are clamped to the MSS of the smallest path MTU when it is probed, the
+    /// segmentation of existing connections never changes. The path MTU is also probed again
+    /// after an upstream or the interface reconnects.
+    pub fn set_path_mtu_probe(&mut self, interval: Option<Duration>) -> io::Result<()> {
+        if interval.is_some() && !is_path_mtu_probe_supported() {
+            return Err(io::Error::other(
+                "path MTU probing is only supported on Linux and Windows",
+            ));
+        }
+        self.path_mtu_interval = interval;
+        self.path_mtu = None;
+        if interval.is_some() {
+            self.probe_path_mtu();
+        }
+
+        Ok(())
+    }
+
+    /// Get the smallest path MTU to upstreams, or `None` if it is not probed.
+    pub fn get_path_mtu(&self) -> Option<u16> {
+        self.path_mtu
+    }
+
+    /// Sets the interval between 2 snapshots of resources and tables in the log, or `None` to not
+    /// take snapshots.
+    pub fn set_soak_report(&mut self, interval: Option<Duration>) {
+        self.soak_interval = interval;
+    }
+
+    /// Sets if a random TCP connection is reset and a random UDP association is closed
+    /// periodically, and a random upstream is flapped less often, for finding leaks in the release
+    /// of resources. This is for testing only.
+    pub fn set_chaos(&mut self, is_chaos: bool) {
+        self.is_chaos = is_chaos;
+    }
+
+    /// Sets the global seed of the random decisions, which are reproducible with the same seed.
+    /// Each source derives its own seeds from it, so captures do not share sequences.
+    pub fn set_seed(&mut self, seed: u64) {
+        let seed = derive_seed(seed, &self.src_ip_addr.to_string());
+        self.random = Random::derive(seed, "chaos");
+        self.upstreams.set_seed(seed);
+    }
+
+    /// Sets if UDP datagrams from the proxy whose source differs from the destination sent to only
+    /// in the port are rewritten back to the port sent to.
+    pub fn set_normalize_remote_port(&mut self, is_normalize: bool) {
+        self.is_normalize_remote_port = is_normalize;
+    }
+
+    /// Sets if TCP connections established before the `Redirector` starts are picked up from
+    /// their segments, instead of reset.
+    pub fn set_pickup_established(&mut self, is_pickup: bool) {
+        self.is_pickup_established = is_pickup;
+    }
+
+    /// Sets the max number of ARP replies to a requester in a second. The first request in a
+    /// second is always answered at once, the requests beyond the limit are not answered.
+    pub fn set_arp_rate(&mut self, rate: usize) {
+        self.arp_rate = max(rate, 1);
+    }
+
+    /// Get the number of ARP requests not answered for exceeding the rate.
+    pub fn get_arp_suppressed(&self) -> usize {
+        self.arp_suppressed
+    }
+
+    /// Sets the time the source has to complete a TCP handshake after the ACK/SYN, the max number
+    /// of half-open TCP connections of the source and the handling of SYN beyond it.
+    pub fn set_tcp_half_open(
+        &mut self,
+        handshake_timeout: Duration,
+        max_half_open: usize,
+        overflow: HalfOpenOverflow,
+    ) {
+        self.tcp_handshake_timeout = handshake_timeout;
+        self.tcp_max_half_open = max_half_open;
+        self.tcp_half_open_overflow = overflow;
+    }
+
+    /// Sets the protocol of the proxies.
+    pub fn set_proxy_type(&mut self, proxy_type: ProxyType) {
+        self.proxy_type = proxy_type;
+    }
+
+    /// Sets the redaction of destinations of flows in the statistics export.
+    pub fn set_redactor(&mut self, redactor: Redactor) {
+        self.redactor = redactor;
+    }
+
+    /// Sets the recording of the data exchanged with the proxy in flows to some destinations.
+    pub fn set_recording(&mut self, recording: Recording) {
+        self.recording = Some(recording);
+    }
+
+    /// Sets the routing rules evaluated when flows are created, before the upstreams and their
+    /// schedule, and the UDP servers of the source in the rules of servers.
+    pub fn set_rules(&mut self, rules: Rules) {
+        self.set_udp_servers(&rules.get_server_ports());
+        self.rule_admitted = vec![0; rules.len()];
+        self.rule_expired = vec![0; rules.len()];
+        self.rule_recordings = vec![None; rules.len()];
+        self.upstreams.set_rules(&rules);
+        self.rules = rules;
+    }
+
+    /// Sets the hardware address of the real gateway, which new flows are bridged to when all the
+    /// upstreams are down and their fallback policy is the gateway.
+    pub fn set_gateway_hardware_addr(&mut self, hardware_addr: HardwareAddr) {
+        self.gateway_hardware_addr = Some(hardware_addr);
+    }
+
+    /// Sets the recording of the data exchanged with the proxy in flows admitted by the rule of
+    /// the index, instead of the global recording. Rules must be set first.
+    pub fn set_rule_recording(&mut self, rule: usize, recording: Recording) {
+        self.rule_recordings[rule] = Some(recording);
+    }
+
+    /// Sets the monitor the workers are registered in, which may be shared by many `Redirector`s.
+    pub fn set_monitor(&mut self, monitor: Arc<Monitor>) {
+        self.last_dump = monitor.get_dumps();
+        self.last_expiry = monitor.get_expiries().0;
+        self.last_kill = monitor.get_kill_count();
+        self.monitor = Some(monitor);
+    }
+
+    /// Sets the min MSS of the source, smaller ones advertised by the source are clamped to it.
+    pub fn set_tcp_min_mss(&mut self, mss: u16) {
+        self.tcp_min_mss = max(mss, TCP_MIN_MSS_FLOOR);
+    }
+
+    /// Handles the MSS advertised by the source. A missing MSS or an MSS of 0 is taken as the
+    /// default MSS, and an MSS below the min MSS is clamped, so segments to a pathological source
+    /// are never tiny.
+    fn get_effective_mss(&mut self, advertised: Option<u16>) -> u16 {
+        match advertised {
+            None => TCP_DEFAULT_PEER_MSS,
+            Some(mss) if mss < self.tcp_min_mss => {
+                self.tcp_mss_clamped = self.tcp_mss_clamped.saturating_add(1);
+                if mss == 0 {
+                    TCP_DEFAULT_PEER_MSS.max(self.tcp_min_mss)
+                } else {
+                    self.tcp_min_mss
+                }
+            }
+            Some(mss) => mss,
+        }
+    }
+
+    /// Sets the time without datagrams after which a UDP association is closed and the time
+    /// without data after which a TCP connection is closed, or `None` if they are never closed
+    /// for idle. Associations of UDP servers are never closed for idle.
+    pub fn set_idle_timeouts(&mut self, udp: Option<Duration>, tcp: Option<Duration>) {
+        self.udp_idle_timeout = udp;
+        self.tcp_idle_timeout = tcp;
+    }
+
+    /// Sets the max size of data a TCP connection queues for sending to the proxy. The window
+    /// advertised to the source shrinks as the queue fills, so a slow proxy slows the source down
+    /// instead of the capture.
+    pub fn set_tcp_send_buffer(&mut self, size: usize) {
+        self.tcp_send_buffer = size;
+    }
+
+    /// Sets the number of pre-warmed connections to the proxy kept for new TCP connections, or 0
+    /// for none. The pool fills the proxy of the last connection, and its connections are taken
+    /// even while the local ports are exhausted.
+    pub fn set_tcp_prewarm(&mut self, size: usize) -> io::Result<()> {
+        self.tcp_pool = None;
+        if size > 0 {
+            let pool = Arc::new(Pool::new(size));
+            Pool::start(&pool, &self.threads)?;
+            self.tcp_pool = Some(pool);
+        }
+
+        Ok(())
+    }
+
+    /// Returns if new TCP connections back off for the exhaustion of local ports, which they do
+    /// not while the pool has connections.
+    fn is_connect_backed_off(&self) -> bool {
+        is_port_exhausted() && self.tcp_pool.as_ref().is_none_or(|pool| pool.is_empty())
+    }
+
+    /// Sets the handling of established TCP connections through an upstream which goes down.
+    pub fn set_resume_strategy(&mut self, strategy: ResumeStrategy) {
+        self.resume_strategy = strategy;
+    }
+
+    /// Sets the max number of flows of the source and the max number of new flows of the source in
+    /// a second. Flows beyond the limits are refused, TCP connections with RST and UDP datagrams
+    /// silently.
+    pub fn set_host_limits(&mut self, max_flows: usize, connect_rate: usize) {
+        self.envelope.set_limits(max_flows, connect_rate);
+    }
+
+    /// Sets the max number of packets of the source processed in a second. Packets beyond the
+    /// budget are dropped before any flow, so a flooding source leaves the processing to others.
+    pub fn set_host_ingress_rate(&mut self, ingress_rate: usize) {
+        self.envelope.set_ingress_rate(ingress_rate);
+    }
+
+    /// Sets the max number of new UDP destinations of the source and of an association in 10
+    /// seconds, and the ranges of destination ports never limited besides DNS and STUN.
+    pub fn set_udp_destination_limits(
+        &mut self,
+        max_destinations: usize,
+        max_destinations_per_port: usize,
+        allowed_ports: &[(u16, u16)],
+    ) {
+        self.fanout
+            .set_limits(max_destinations, max_destinations_per_port);
+        for (first, last) in allowed_ports {
+            self.fanout.allow_ports(*first, *last);
+        }
+    }
+
+    /// Sets what happens to an identical UDP payload from a source port to more than the given
+    /// number of distinct destinations in 10 seconds. Ports never limited by destinations are
+    /// never classified either.
+    pub fn set_udp_discovery(&mut self, policy: DiscoveryPolicy, limit: usize) {
+        self.discovery.set_policy(policy, limit);
+    }
+
+    /// Sets the resolver of DNS queries to the gateway, for sources using the gateway as their
+    /// DNS server. The gateway must be published, since the host answers its own address.
+    pub fn set_gateway_dns(&mut self, resolver: SocketAddrV4) -> io::Result<()> {
+        if self.local_ip_addr.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the gateway DNS requires a publishing address",
+            ));
+        }
+        self.gateway_dns = Some(resolver);
+
+        Ok(())
+    }
+
+    /// Serves the TCP port of the published gateway with the service. Connections to the port are
+    /// redirected to the service instead of the proxy.
+    pub fn set_gateway_service(&mut self, port: u16, service: Arc<dyn Service>) -> io::Result<()> {
+        if self.local_ip_addr.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "services of the gateway require a publishing address",
+            ));
+        }
+        let name = service.get_name().to_string();
+        let local_addr = gateway::serve(&self.threads, service)?;
+        info!("Serve {} on {}:{}", name, self.local_ip_addr.unwrap(), port);
+        self.gateway_services.insert(port, local_addr);
+
+        Ok(())
+    }
+
+    /// Get the local address of the service of the destination, if it is a port of the published
+    /// gateway with a service.
+    fn get_gateway_service(&self, dst: SocketAddrV4) -> Option<SocketAddr> {
+        if self.local_ip_addr != Some(*dst.ip()) {
+            return None;
+        }
+
+        self.gateway_services.get(&dst.port()).copied()
+    }
+
+    /// Sets to cache DNS answers of the given max number, and to share one query to the upstream
+    /// among identical queries in flight.
+    pub fn set_dns_cache(&mut self, size: usize) {
+        self.dns = Some(Arc::new(Mutex::new(DnsCache::new(size))));
+    }
+
+    /// Shares the DNS cache of another `Redirector`, so identical queries of both sources are
+    /// coalesced.
+    pub fn share_dns_cache(&mut self, other: &Redirector) {
+        self.dns = other.dns.as_ref().map(Arc::clone);
+    }
+
+    /// Get the statistics of the DNS cache, or `None` if DNS answers are not cached.
+    pub fn get_dns_stats(&self) -> Option<DnsStats> {
+        self.dns.as_ref().map(|dns| dns.lock().unwrap().get_stats())
+    }
+
+    /// Get the number of UDP datagrams dropped for new destinations beyond the limits.
+    pub fn get_udp_fanout_dropped(&self) -> usize {
+        self.fanout.get_dropped()
+    }
+
+    /// Get the source ports with the most new UDP destinations in the current window, for
+    /// finding the application spraying datagrams.
+    pub fn dump_udp_talkers(&self, n: usize) -> String {
+        self.fanout
+            .get_top_talkers(n)
+            .iter()
+            .map(|(port, count)| format!("{}:{} {} destinations", self.src_ip_addr, port, count))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    /// Get the resource envelope of the source.
+    pub fn get_envelope(&self) -> &Envelope {
+        &self.envelope
+    }
+
+    /// Get the number of flows of the source, TCP connections and UDP associations.
+    fn get_flow_count(&self) -> usize {
+        self.get_stream_count() + self.datagrams.iter().filter(|d| d.is_some()).count()
+    }
+
+    /// Sets the time a killed UDP association is not bound again, datagrams of the association are
+    /// dropped in the time.
+    pub fn set_udp_quarantine(&mut self, quarantine: Duration) {
+        self.udp_quarantine = quarantine;
+    }
+
+    /// Adds a port mapping leased to the source through UPnP, which is released once the flow of
+    /// its internal port is killed.
+    pub fn add_upnp_lease(&mut self, lease: Lease) {
+        info!("Lease UPnP {} to {}", lease, self.src_ip_addr);
+        if let Some(replaced) = self.upnp_leases.lock().unwrap().add(lease) {
+            debug!("Replace UPnP lease {}", replaced);
+        }
+    }
+
+    /// Get the port mappings leased to the source through UPnP.
+    pub fn get_upnp_leases(&self) -> MutexGuard<'_, Leases> {
+        self.upnp_leases.lock().unwrap()
+    }
+
+    /// Serves the UPnP control of the leases on the TCP port of the published gateway, which
+    /// reports the gateway as the external IP address.
+    pub fn set_upnp(&mut self, port: u16) -> io::Result<()> {
+        let gateway = self.local_ip_addr.unwrap_or(Ipv4Addr::UNSPECIFIED);
+        let control = Control::new(Arc::clone(&self.upnp_leases), gateway);
+        self.set_gateway_service(port, Arc::new(control))?;
+        // Searches are answered with the control, which is served on a published gateway only
+        if self.local_ip_addr.is_some() {
+            self.discovery.set_ssdp(Ssdp::new(gateway, port));
+        }
+
+        Ok(())
+    }
+
+    /// Sets the UDP server ports of the source, like the ports of dedicated game servers, which
+    /// receive datagrams from clients anywhere before sending any. Each is bound in an association
+    /// as soon as the redirect starts, accepts datagrams from all the remotes, is never expired
+    /// or reused, and is bound again once it fails with a backoff.
+    fn set_udp_servers(&mut self, ports: &[u16]) {
+        self.udp_servers = ports
+            .iter()
+            .map(|port| {
+                (
+                    *port,
+                    UdpServer {
+                        retry_at: Instant::now(),
+                        failures: 0,
+                        binds: 0,
+                    },
+                )
+            })
+            .collect();
+    }
+
+    /// Binds the associations of the UDP servers which are not bound or failed.
+    fn check_udp_servers(&mut self) {
+        self.last_udp_server_check = Instant::now();
+        let ports: Vec<u16> = self.udp_servers.keys().cloned().collect();
+        for src_port in ports {
+            if let Some(instant) = self.udp_quarantine_map.get(&src_port) {
+                if Instant::now() < *instant {
+                    continue;
+                }
+            }
+            let port = self.get_local_udp_port(src_port);
+            let index = (port - self.udp_initial_port) as usize;
+            let is_bound = match self.datagrams[index] {
+                Some(ref worker) => !worker.is_closed() && worker.get_src_port() == src_port,
+                None => false,
+            };
+            let server = self.udp_servers[&src_port];
+            if is_bound || Instant::now() < server.retry_at {
+                continue;
+            }
+
+            let result =
+                self.bind_datagram(port, src_port, SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+            let server = self.udp_servers.get_mut(&src_port).unwrap();
+            match result {
+                Ok(_) => {
+                    if server.binds > 0 {
+                        info!(
+                            "Bind UDP server {}:{} again on port {}",
+                            self.src_ip_addr, src_port, port
+                        );
+                    } else {
+                        info!(
+                            "Serve UDP {}:{} on port {}",
+                            self.src_ip_addr, src_port, port
+                        );
+                    }
+                    server.failures = 0;
+                    server.binds += 1;
+                }
+                Err(ref e) => {
+                    let delay = backoff::get_delay(
+                        Site::Server,
+                        Duration::from_secs(UDP_SERVER_BACKOFF),
+                        server.failures,
+                    );
+                    server.failures += 1;
+                    server.retry_at = Instant::now() + delay;
+                    warn!(
+                        "bind UDP server {}:{}: {}, retry in {} ms",
+                        self.src_ip_addr,
+                        src_port,
+                        e,
+                        delay.as_millis()
+                    );
+                }
+            }
+        }
+    }
+
+    /// Removes the records older than the retention, on the start and then daily.
+    fn prune_records(&mut self) {
+        self.last_record_prune = Some(Instant::now());
+        let now = SystemTime::now();
+        for recording in self
+            .recording
+            .iter()
+            .chain(self.rule_recordings.iter().flatten())
+        {
+            if let Err(ref e) = recording.prune(now) {
+                warn!("prune records: {}", e);
+            }
+        }
+    }
+
+    /// Applies the kills requested in the monitor since the last poll.
+    fn poll_kills(&mut self) {
+        if let Some(kills) = self
+            .monitor
+            .as_ref()
+            .map(|monitor| monitor.get_kills(self.last_kill))
+        {
+            self.last_kill += kills.len();
+            for kill in kills {
+                self.kill(kill);
+            }
+        }
+    }
+
+    /// Kills the flow, if the `Redirector` has it.
+    fn kill(&mut self, kill: Kill) {
+        let is_killed = match kill {
+            Kill::Tcp(src_port, dst, is_abort) => self.kill_tcp(src_port, dst, is_abort),
+            Kill::Udp(src_port) => self.kill_udp(src_port),
+        };
+        if !is_killed {
+            debug!("Kill {} of {}: no such flow", kill, self.src_ip_addr);
+        }
+    }
+
+    /// Kills a TCP connection. The source is reset, while the stream to the proxy is shut down in
+    /// order so the target sees a normal close, or reset if it is an abort. Returns `false` if
+    /// there is no such connection.
+    pub fn kill_tcp(&mut self, src_port: u16, dst: SocketAddrV4, is_abort: bool) -> bool {
+        let key = (src_port, dst);
+        let stream = match self.get_stream_mut(&key) {
+            Some(stream) => stream,
+            None => return false,
+        };
+        if is_abort {
+            stream.abort();
+        }
+        let reason = if is_abort { "kill-abort" } else { "kill" };
+        info!("Kill stream {} -> {} ({})", src_port, dst, reason);
+
+        self.remove_key(key, reason);
+        let mut tx_locked = self.tx.lock().unwrap();
+        if let Err(ref e) = tx_locked.send_tcp_ack_rst(dst, src_port) {
+            warn!("handle {}: {}", "TCP", e);
+        }
+        tx_locked.remove(dst, src_port);
+
+        true
+    }
+
+    /// Kills the UDP association of a source port. The local port is released with the UPnP leases
+    /// forwarding to the source port, and datagrams from the source port are dropped in the
+    /// quarantine instead of binding again. Returns
+    /// `false` if there is no such association.
+    pub fn kill_udp(&mut self, src_port: u16) -> bool {
+        let local_port = self.datagram_map[src_port as usize];
+        if local_port == 0 {
+            return false;
+        }
+        let index = local_port.wrapping_sub(self.udp_initial_port);
+        info!(
+            "Kill datagram {} = {} for {} s",
+            src_port,
+            local_port,
+            self.udp_quarantine.as_secs()
+        );
+
+        self.close_datagram_flow(index as usize, "kill");
+        self.datagrams[index as usize] = None;
+        self.datagram_map[src_port as usize] = 0;
+        self.udp_lru.put(index, 0);
+        self.udp_quarantine_map
+            .insert(src_port, Instant::now() + self.udp_quarantine);
+        for lease in self
+            .upnp_leases
+            .lock()
+            .unwrap()
+            .release(Protocol::Udp, src_port)
+        {
+            info!("Release UPnP lease {} (kill)", lease);
+        }
+
+        true
+    }
+
+    /// Sets the TTL of echo replies to pings to the gateway, or `None` to ignore these pings.
+    pub fn set_ping_ttl(&mut self, ttl: Option<u8>) {
+        self.ping_ttl = ttl;
+    }
+
+    /// Sets the responders for network tests of game consoles.
+    pub fn set_console(&mut self, console: Console) {
+        self.console = console;
+    }
+
+    /// Loads the state from the given path and saves to it periodically. The state has the
+    /// hardware address and the framing of the source and the UDP associations, which are bound
+    /// again with the same local ports, so a restarted process serves the source without learning
+    /// them again. A missing file is not an error.
+    pub fn load_state(&mut self, path: PathBuf) -> io::Result<()> {
+        // The old process still holds the local ports in a handoff, and releases the state later
+        if self.is_handoff_waiting() {
+            self.state_path = Some(path);
+            return Ok(());
+        }
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) => {
+                self.state_path = Some(path);
+                if e.kind() == io::ErrorKind::NotFound {
+                    return Ok(());
+                }
+                return Err(e);
+            }
+        };
+
+        let associations = self.restore_state(&content);
+        info!(
+            "Restore {} UDP associations from {}",
+            associations,
+            path.display()
+        );
+        self.state_path = Some(path);
+
+        Ok(())
+    }
+
+    /// Restores the state in the format of the state file, and returns the number of UDP
+    /// associations bound again.
+    fn restore_state(&mut self, content: &str) -> usize {
+        let mut associations = 0;
+        for line in content.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                ["source", hardware_addr, framing] => {
+                    let hardware_addr: HardwareAddr = match hardware_addr.parse() {
+                        Ok(hardware_addr) => hardware_addr,
+                        Err(_) => continue,
+                    };
+                    let is_llc_snap = *framing == "llc-snap";
+                    let mut tx_locked = self.tx.lock().unwrap();
+                    tx_locked.set_src_hardware_addr(hardware_addr);
+                    tx_locked.set_src_llc_snap(is_llc_snap);
+                    self.is_tx_src_hardware_addr_set = true;
+                    self.is_src_llc_snap = is_llc_snap;
+                }
+                ["udp", src_port, local_port, dst] => {
+                    let (src_port, local_port, dst) = match (
+                        src_port.parse::<u16>(),
+                        local_port.parse::<u16>(),
+                        dst.parse::<SocketAddrV4>(),
+                    ) {
+                        (Ok(src_port), Ok(local_port), Ok(dst)) => (src_port, local_port, dst),
+                        _ => continue,
+                    };
+                    if src_port == 0
+                        || local_port < self.udp_initial_port
+                        || local_port - self.udp_initial_port >= PORT_COUNT as u16
+                        || self.datagram_map[src_port as usize] != 0
+                    {
+                        continue;
+                    }
+
+                    // Map the source port to the same local port
+                    let index = local_port - self.udp_initial_port;
+                    if let Some(prev_src_port) = self.udp_lru.put(index, src_port) {
+                        if prev_src_port != 0 {
+                            self.datagram_map[prev_src_port as usize] = 0;
+                        }
+                    }
+                    self.datagram_map[src_port as usize] = local_port;
+
+                    // Associate again
+                    let mut result = self.bind_datagram(local_port, src_port, dst);
+                    for failures in 0..RESTORE_BINDS - 1 {
+                        match result {
+                            Err(ref e) if e.kind() == io::ErrorKind::AddrInUse => {
+                                thread::sleep(backoff::get_delay(
+                                    Site::Restore,
+                                    Duration::from_millis(RESTORE_BIND_INTERVAL),
+                                    failures,
+                                ));
+                                result = self.bind_datagram(local_port, src_port, dst);
+                            }
+                            _ => break,
+                        }
+                    }
+                    if let Err(ref e) = result {
+                        if e.kind() == io::ErrorKind::AddrInUse {
+                            backoff::give_up(Site::Restore);
+                        }
+                    }
+                    match result {
+                        Ok(_) => associations += 1,
+                        Err(ref e) => warn!("restore UDP {} -> {}: {}", src_port, dst, e),
+                    }
+                }
+                _ => continue,
+            }
+        }
+
+        associations
+    }
+
+    /// Saves the state to the path it was loaded from.
+    pub fn save_state(&mut self) -> io::Result<()> {
+        self.last_state_save = Instant::now();
+        let path = match self.state_path {
+            Some(ref path) => path.clone(),
+            None => return Ok(()),
+        };
+
+        let (content, associations) = self.get_state();
+
+        // Replace the file at once, so a process started meanwhile never reads a partial state
+        let mut temp = path.clone().into_os_string();
+        temp.push(".tmp");
+        fs::write(&temp, content)?;
+        fs::rename(&temp, &path)?;
+        trace!(
+            "save {} UDP associations to {}",
+            associations,
+            path.display()
+        );
+
+        Ok(())
+    }
+
+    /// Get the state in the format of the state file, and the number of UDP associations in it.
+    fn get_state(&self) -> (String, usize) {
+        let mut content = String::new();
+        if self.is_tx_src_hardware_addr_set {
+            content.push_str(&format!(
+                "source {} {}\n",
+                self.tx.lock().unwrap().get_src_hardware_addr(),
+                if self.is_src_llc_snap {
+                    "llc-snap"
+                } else {
+                    "ethernet"
+                }
+            ));
+        }
+        // From the least recently used, so the order of the LRU is kept in loading
+        let mut associations = 0;
+        for (index, src_port) in self.udp_lru.iter().rev() {
+            if *src_port == 0 {
+                continue;
+            }
+            let dst = match self.datagram_flows[*index as usize] {
+                Some((ref flow, _)) => flow.dst,
+                None => continue,
+            };
+            let is_alive = match self.datagrams[*index as usize] {
+                Some(ref worker) => !worker.is_closed(),
+                None => false,
+            };
+            if !is_alive {
+                continue;
+            }
+            content.push_str(&format!(
+                "udp {} {} {}\n",
+                src_port,
+                self.udp_initial_port + index,
+                dst
+            ));
+            associations += 1;
+        }
+
+        (content, associations)
+    }
+
+    /// Exports statistics to a memory-mapped file at the given path every second.
+    pub fn set_stats_export(&mut self, path: PathBuf) -> io::Result<()> {
+        self.stats_export = Some(Export::create(&path)?);
+        self.export_stats();
+
+        Ok(())
+    }
+
+    /// Publishes the statistics exported by `set_stats_export` to the monitor every second, which
+    /// the control answers `stats` and `dump flows` with, with or without a file.
+    pub fn set_stats_publish(&mut self) {
+        self.is_stats_published = true;
+        self.export_stats();
+    }
+
+    /// Writes the aggregate counters and the top flows by bytes to the exported region, and
+    /// publishes them to the monitor.
+    fn export_stats(&mut self) {
+        self.last_stats_export = Instant::now();
+
+        let tcp_stats = self.get_tcp_stats();
+        let drops = self.envelope.get_drops();
+        let counters = [
+            self.get_stream_count(),
+            self.datagrams.iter().filter(|d| d.is_some()).count(),
+            tcp_stats.out_of_order,
+            tcp_stats.duplicates,
+            tcp_stats.fast_retransmissions,
+            tcp_stats.timeouts,
+            tcp_stats.retransmitted,
+            self.tcp_keepalives,
+            self.tcp_dropped_duplicates,
+            self.tcp_simultaneous_opens,
+            self.get_tcp_pauses(),
+            self.arp_suppressed,
+            self.repairs,
+            drops.flows,
+            drops.rate,
+            drops.quarantines,
+            self.drops.get_total(),
+            self.threads.len(),
+        ];
+        let counters: Vec<u64> = counters.iter().map(|value| *value as u64).collect();
+
+        let mut flows: Vec<FlowStats> = self
+            .tcp_flows
+            .iter()
+            .filter_map(|(key, flow)| flow.stream.as_ref().map(|stream| (key, flow, stream)))
+            .map(|(key, flow, stream)| {
+                let (bytes_up, bytes_down) = stream.get_bytes();
+                let admission = flow.rule.as_ref();
+                let (rule, window) = get_label_indexes(admission);
+                let redactor = self.redactor.with_privacy(self.get_rule_privacy(admission));
+                FlowStats {
+                    protocol: 6,
+                    src_port: key.0,
+                    dst: redactor.redact(key.1),
+                    bytes_up: bytes_up as u64,
+                    bytes_down: bytes_down as u64,
+                    rule,
+                    window,
+                }
+            })
+            .collect();
+        for (index, (datagram, flow)) in self
+            .datagrams
+            .iter()
+            .zip(self.datagram_flows.iter())
+            .enumerate()
+        {
+            if let (Some(datagram), Some((flow, (prev_bytes_up, prev_bytes_down)))) =
+                (datagram, flow)
+            {
+                let (bytes_up, bytes_down) = datagram.get_bytes();
+                let admission = self.datagram_rules[index].as_ref();
+                let (rule, window) = get_label_indexes(admission);
+                let redactor = self.redactor.with_privacy(self.get_rule_privacy(admission));
+                flows.push(FlowStats {
+                    protocol: 17,
+                    src_port: flow.src.port(),
+                    dst: redactor.redact(flow.dst),
+                    bytes_up: (bytes_up - prev_bytes_up) as u64,
+                    bytes_down: (bytes_down - prev_bytes_down) as u64,
+                    rule,
+                    window,
+                });
+            }
+        }
+        flows.sort_by_key(|flow| std::cmp::Reverse(flow.bytes_up + flow.bytes_down));
+        flows.truncate(stats::TOP_FLOWS);
+
+        let updated = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(duration) => duration.as_secs(),
+            Err(_) => 0,
+        };
+        if let Some(ref mut export) = self.stats_export {
+            export.write(updated, &counters, &flows);
+        }
+        if self.is_stats_published {
+            if let Some(ref monitor) = self.monitor {
+                monitor.set_snapshot(stats::Snapshot {
+                    version: stats::VERSION,
+                    updated,
+                    counters,
+                    flows,
+                });
+            }
+        }
+    }
+
+    /// Sets the hooks run when flows open and close, and spawns threads running them.
+    pub fn set_hooks(&mut self, hooks: Hooks) -> io::Result<()> {
+        self.hooks = hooks;
+
+        self.hooks.start(&self.threads)
+    }
+
+    /// Sets the notifier of critical state changes, and spawns the thread delivering
+    /// notifications at most once in the hold time for each condition.
+    pub fn set_notifier(&mut self, notifier: Notifier, hold: Duration) -> io::Result<()> {
+        self.notifier = notifier;
+
+        self.notifier.start(&self.threads, hold)
+    }
+
+    /// Shares the notifier of another `Redirector`, so the events of all captures are delivered
+    /// by one thread.
+    pub fn share_notifier(&mut self, other: &Redirector) {
+        self.notifier = other.notifier.share();
+    }
+
+    /// Sets the quota of the traffic in bytes, which notifies once the workers of the monitor
+    /// exceed it, or `None` to not check. The quota covers all the `Redirector`s sharing the
+    /// monitor, so only one of them should check it.
+    pub fn set_quota(&mut self, quota: Option<u64>) {
+        self.quota = quota;
+        self.is_quota_exceeded = false;
+    }
+
+    /// Sets the health followed by the `Redirector`, and spawns the thread answering health
+    /// probes on the given address.
+    pub fn set_health(&mut self, health: Arc<Health>, addr: SocketAddr) -> io::Result<()> {
+        Health::start(&health, &self.threads, addr)?;
+        self.health = Some(health);
+        self.update_health();
+
+        Ok(())
+    }
+
+    /// Sets the health followed by the `Redirector` like `set_health`, but answers health probes
+    /// on a bound listener, like the one passed in a handoff.
+    pub fn set_health_listener(
+        &mut self,
+        health: Arc<Health>,
+        listener: TcpListener,
+    ) -> io::Result<()> {
+        Health::serve(&health, &self.threads, listener)?;
+        self.health = Some(health);
+        self.update_health();
+
+        Ok(())
+    }
+
+    /// Pairs the `Redirector` with a peer in the warm standby, receiving heartbeats on the given
+    /// address. The `Redirector` starts in standby and serves the source only while it is active,
+    /// and the gateway must be published so it can be taken over.
+    pub fn set_standby(
+        &mut self,
+        mut standby: Standby,
+        listen: SocketAddr,
+        peer: SocketAddr,
+    ) -> io::Result<()> {
+        if self.local_ip_addr.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the warm standby requires a publishing address",
+            ));
+        }
+        standby.start(&self.threads, listen, peer)?;
+        self.standby = Some(standby);
+        self.is_standby = true;
+        self.last_standby_check = Instant::now();
+
+        Ok(())
+    }
+
+    /// Joins the warm standby of another `Redirector`, so the capture takes over and stands down
+    /// with it, and its state is replicated in the heartbeats of the other `Redirector`. The
+    /// gateway must be published like `set_standby`.
+    pub fn share_standby(&mut self, other: &Redirector) -> io::Result<()> {
+        let standby = match other.standby {
+            Some(ref standby) => standby.join(self.src_ip_addr.to_string()),
+            None => return Ok(()),
+        };
+        if self.local_ip_addr.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the warm standby requires a publishing address",
+            ));
+        }
+        self.standby = Some(standby);
+        self.is_standby = true;
+        self.last_standby_check = Instant::now();
+
+        Ok(())
+    }
+
+    /// Joins the handoff to or from another process, which cannot be used with the warm standby.
+    /// A `Redirector` of the new process serves nothing until the old process releases its state.
+    /// A `Redirector` of the old process releases its state once handing off, drains its TCP
+    /// connections, and returns from `open` once they are closed or the drain is over.
+    pub fn set_handoff(&mut self, handoff: Arc<Handoff>) -> io::Result<()> {
+        if self.standby.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the handoff cannot be used with the warm standby",
+            ));
+        }
+        handoff.join();
+        self.handoff = Some(handoff);
+        if self.is_handoff_waiting() {
+            self.is_standby = true;
+        }
+
+        Ok(())
+    }
+
+    fn is_handoff_waiting(&self) -> bool {
+        match self.handoff {
+            Some(ref handoff) => handoff.get_phase() == Phase::Waiting,
+            None => false,
+        }
+    }
+
+    /// Notifies once UDP associations through the upstreams start failing, or bind again after
+    /// failing.
+    fn follow_udp(&mut self, result: &io::Result<DatagramWorker>) {
+        match result {
+            Err(ref e) if !self.is_udp_degraded => {
+                self.is_udp_degraded = true;
+                warn!("UDP is degraded: {}", e);
+                self.notifier.notify(Event::UdpDegraded(e.to_string()));
+            }
+            Ok(_) if self.is_udp_degraded => {
+                self.is_udp_degraded = false;
+                info!("UDP is recovered");
+                self.notifier.notify(Event::UdpRecovered);
+            }
+            _ => {}
+        }
+    }
+
+    /// Notifies once the traffic of the workers of the monitor exceeds the quota.
+    fn check_quota(&mut self) {
+        self.last_quota_check = Instant::now();
+        let (quota, monitor) = match (self.quota, self.monitor.as_ref()) {
+            (Some(quota), Some(monitor)) => (quota, monitor),
+            _ => return,
+        };
+        if self.is_quota_exceeded {
+            return;
+        }
+
+        let (bytes_up, bytes_down) = monitor.get_bytes();
+        if (bytes_up as u64).saturating_add(bytes_down as u64) >= quota {
+            self.is_quota_exceeded = true;
+            warn!("Traffic exceeds the quota of {} Bytes", quota);
+            self.notifier.notify(Event::QuotaExceeded(quota));
+        }
+    }
+
+    fn update_health(&self) {
+        if let Some(ref health) = self.health {
+            health.set_upstreams_down(self.upstreams.is_fallback());
+            health.set_interface_lost(
+                self.is_interface_lost
+                    || self
+                        .inter
+                        .as_ref()
+                        .map(|inter| !inter.is_up)
+                        .unwrap_or(false),
+            );
+        }
+    }
+
+    /// Get the hooks run when flows open and close.
+    pub fn get_hooks(&self) -> &Hooks {
+        &self.hooks
+    }
+
+    /// Shares the thread registry of another `Redirector`, so the max number of threads is
+    /// counted across them. This must be called before spawning any thread.
+    pub fn share_threads(&mut self, other: &Redirector) {
+        self.threads = Arc::clone(&other.threads);
+    }
+
+    /// Sets the thread registry of the `Redirector`, which may be shared by the whole process.
+    /// This must be called before spawning any thread.
+    pub fn set_threads(&mut self, threads: Arc<Threads>) {
+        self.threads = threads;
+    }
+
+    /// Sets the credentials of the proxies.
+    pub fn set_credentials(&mut self, credentials: Credentials) {
+        self.credentials = credentials;
+    }
+
+    /// Opens an `Interface` for redirect.
+    pub fn open(&mut self, rx: &mut Receiver) -> io::Result<()> {
+        // Latencies are saved off the capture
+        if let Err(ref e) = self.upstreams.start(&self.threads) {
+            warn!("save latencies: {}", e);
+        }
+        if let Some(ref health) = self.health {
+            health.set_capture_open(true);
+        }
+        let result = self.redirect(rx);
+        if let Some(ref health) = self.health {
+            health.set_capture_open(false);
+        }
+
+        result
+    }
+
+    fn redirect(&mut self, rx: &mut Receiver) -> io::Result<()> {
+        loop {
+            if self.handoff.is_some() && self.check_handoff()? {
+                return Ok(());
+            }
+            if self.last_scavenge.elapsed().as_secs() >= SCAVENGE_INTERVAL {
+                self.scavenge();
+            }
+            if self.last_talkers.elapsed().as_secs() >= TALKERS_INTERVAL {
+                self.publish_talkers();
+            }
+            if let Some(interval) = self.soak_interval {
+                if self.last_soak.elapsed() >= interval {
+                    self.soak_report();
+                }
+            }
+            if self.is_chaos && self.last_chaos.elapsed().as_secs() >= CHAOS_INTERVAL {
+                self.chaos();
+            }
+            if self.quota.is_some()
+                && self.last_quota_check.elapsed().as_secs() >= QUOTA_CHECK_INTERVAL
+            {
+                self.check_quota();
+            }
+            if let Some(interval) = self.path_mtu_interval {
+                if self.last_path_mtu_probe.elapsed() >= interval {
+                    self.probe_path_mtu();
+                }
+            }
+            if self.inter.is_some()
+                && self.last_interface_check.elapsed().as_secs() >= INTERFACE_CHECK_INTERVAL
+            {
+                self.check_interface();
+                self.update_health();
+            }
+            // The standby never overwrites the state of the active instance in a shared file
+            if self.state_path.is_some()
+                && !self.is_standby
+                && self.last_state_save.elapsed().as_secs() >= STATE_SAVE_INTERVAL
+            {
+                if let Err(ref e) = self.save_state() {
+                    warn!("save state: {}", e);
+                }
+            }
+            if self
+                .last_record_prune
+                .map(|instant| instant.elapsed().as_secs() >= RECORD_PRUNE_INTERVAL)
+                .unwrap_or(true)
+            {
+                self.prune_records();
+            }
+            if (self.stats_export.is_some() || self.is_stats_published)
+                && self.last_stats_export.elapsed().as_secs() >= STATS_EXPORT_INTERVAL
+            {
+                self.export_stats();
+            }
+            if self.standby.is_some()
+                && self.last_standby_check.elapsed().as_secs() >= standby::HEARTBEAT_INTERVAL
+            {
+                self.check_standby();
+            }
+            if !self.udp_servers.is_empty()
+                && !self.is_standby
+                && self.last_udp_server_check.elapsed().as_secs() >= UDP_SERVER_CHECK_INTERVAL
+            {
+                self.check_udp_servers();
+            }
+            for timer in self.timers.advance() {
+                self.fire(timer);
+            }
+            if !self.is_standby {
+                if let Some((group, message)) =
+                    self.console.get_igmp_mut().and_then(|igmp| igmp.poll())
+                {
+                    if let Err(ref e) = self.tx.lock().unwrap().send_igmp(group, &message) {
+                        warn!("send IGMP query: {}", e);
+                    }
+                }
+            }
+            if let Some(dumps) = self.monitor.as_ref().map(|monitor| monitor.get_dumps()) {
+                if dumps != self.last_dump {
+                    self.last_dump = dumps;
+                    self.dump();
+                }
+            }
+            if let Some((expiries, rule)) =
+                self.monitor.as_ref().map(|monitor| monitor.get_expiries())
+            {
+                if expiries != self.last_expiry {
+                    self.last_expiry = expiries;
+                    self.expire_rules(rule.as_deref());
+                }
+            }
+            self.poll_kills();
+            self.poll_connecting();
+            self.poll_early_rtt();
+            self.poll_congested();
+            self.poll_retransmissions();
+            for remote in self.upstreams.take_downs() {
+                self.fail_over(remote);
+            }
+            self.poll_ups();
+            self.follow_fallback();
+
+            match rx.next() {
+                Ok(frame) => {
+                    // The new process in a handoff takes over as soon as the old process released
+                    // the state, so the frame waiting meanwhile is served
+                    if self.is_standby && self.handoff.is_some() && self.check_handoff()? {
+                        return Ok(());
+                    }
+                    // The active instance serves the source, and the old process in a handoff its
+                    // TCP connections draining
+                    if self.is_standby && !self.is_draining(frame) {
+                        continue;
+                    }
+                    match Indicator::from(frame) {
+                        Some(ref indicator) => match indicator.get_network_type() {
+                            Some(t) => match t {
+                                LayerTypes::Arp => {
+                                    if let Err(ref e) = self.handle_arp(indicator) {
+                                        warn!("handle {}: {}", indicator.brief(), e);
+                                    }
+                                }
+                                LayerTypes::Ipv4 => {
+                                    if let Err(ref e) = self.handle_ipv4(indicator, frame) {
+                                        warn!("handle {}: {}", indicator.brief(), e);
+                                    }
+                                }
+                                _ => unreachable!(),
+                            },
+                            None => self.drop_unknown(indicator),
+                        },
+                        None => self
+                            .drops
+                            .add(DropReason::Frame, || format!("{} Bytes", frame.len())),
+                    };
+                }
+                Err(e) => {
+                    // The receive already waited on the device for its read timeout, the periodic
+                    // tasks run again at once
+                    if e.kind() == io::ErrorKind::TimedOut {
+                        continue;
+                    }
+                    return Err(e);
+                }
+            };
+        }
+    }
+
+    fn handle_arp(&mut self, indicator: &Indicator) -> io::Result<()> {
+        // ARP is answered by the system if the gateway is the interface itself
+        if let Some(local_ip_addr) = self.local_ip_addr {
+            if let Some(arp) = indicator.get_arp() {
+                if self.standby.is_some()
+                    && arp.get_src() == local_ip_addr
+                    && arp.get_src_hardware_addr()
+                        != self.tx.lock().unwrap().get_local_hardware_addr()
+                {
+                    self.handle_conflict(arp.get_src_hardware_addr());
+                } else if arp.get_src() != self.src_ip_addr {
+                    self.drop_packet(indicator, DropReason::OtherHost);
+                } else if !arp.is_request_of(self.src_ip_addr, local_ip_addr) {
+                    self.drop_packet(indicator, DropReason::ArpOther);
+                } else {
+                    debug!(
+                        "receive from pcap: {} ({} Bytes)",
+                        indicator.brief(),
+                        indicator.get_size()
+                    );
+
+                    // Set forwarder's hardware address
+                    if !self.is_tx_src_hardware_addr_set {
+                        self.tx
+                            .lock()
+                            .unwrap()
+                            .set_src_hardware_addr(arp.get_src_hardware_addr());
+                        self.is_tx_src_hardware_addr_set = true;
+                    }
+                    self.update_src_framing(indicator);
+
+                    // Rate limit, identical replies in a second are suppressed after the limit
+                    if self.is_arp_flood(arp.get_src_hardware_addr()) {
+                        self.arp_suppressed = self.arp_suppressed.saturating_add(1);
+                        self.drop_packet(indicator, DropReason::ArpFlood);
+
+                        return Ok(());
+                    }
+
+                    // Send
+                    self.tx.lock().unwrap().send_arp_reply()?
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns if the ARP requests of the requester exceed the rate in the current second.
+    fn is_arp_flood(&mut self, hardware_addr: HardwareAddr) -> bool {
+        let entry = self
+            .arp_rate_map
+            .entry(hardware_addr)
+            .or_insert_with(|| (Instant::now(), 0));
+        if entry.0.elapsed().as_secs() >= 1 {
+            *entry = (Instant::now(), 0);
+        }
+        entry.1 += 1;
+
+        entry.1 > self.arp_rate
+    }
+
+    /// Follows the framing of the source, so frames to a source using IEEE 802.3 with LLC and
+    /// SNAP framing are framed alike.
+    fn update_src_framing(&mut self, indicator: &Indicator) {
+        let is_llc_snap = match indicator.get_ethernet() {
+            Some(ethernet) => ethernet.is_llc_snap(),
+            None => return,
+        };
+        if is_llc_snap != self.is_src_llc_snap {
+            self.tx.lock().unwrap().set_src_llc_snap(is_llc_snap);
+            self.is_src_llc_snap = is_llc_snap;
+            debug!(
+                "Source {} uses {} framing",
+                self.src_ip_addr,
+                if is_llc_snap {
+                    "LLC/SNAP"
+                } else {
+                    "Ethernet II"
+                }
+            );
+        }
+    }
+
+    fn handle_ipv4(&mut self, indicator: &Indicator, buffer: &[u8]) -> io::Result<()> {
+        if let Some(ipv4) = indicator.get_ipv4() {
+            let size =
+                indicator.get_ethernet().unwrap().get_size() + ipv4.get_total_length() as usize;
+            // Devices ask for their address before they have one
+            if let Some(udp) = indicator.get_udp() {
+                if udp.get_src() == dhcp::CLIENT_PORT
+                    && udp.get_dst() == dhcp::SERVER_PORT
+                    && self.console.get_dhcp_mut().is_some()
+                {
+                    return self.handle_dhcp(indicator, &buffer[..min(size, buffer.len())]);
+                }
+            }
+            if ipv4.get_src() == self.src_ip_addr
+                && (size > buffer.len() || (ipv4.get_total_length() as usize) < ipv4.get_size())
+            {
+                self.drop_packet(indicator, DropReason::Malformed(Malformed::Ipv4Length));
+                return Ok(());
+            }
+            if ipv4.get_src() != self.src_ip_addr {
+                self.drop_packet(indicator, DropReason::OtherHost);
+                return Ok(());
+            }
+            // Ingress budget
+            if let Err(refusal) = self.envelope.admit_packet() {
+                self.drop_packet(indicator, DropReason::from(refusal));
+                return Ok(());
+            }
+            let buffer_without_padding = &buffer[..min(size, buffer.len())];
+            if ipv4.get_src() == self.src_ip_addr {
+                debug!(
+                    "receive from pcap: {} ({} + {} Bytes)",
+                    indicator.brief(),
+                    indicator.get_size(),
+                    buffer_without_padding.len() - indicator.get_size()
+                );
+                // Set forwarder's hardware address
+                if !self.is_tx_src_hardware_addr_set {
+                    self.tx
+                        .lock()
+                        .unwrap()
+                        .set_src_hardware_addr(indicator.get_ethernet().unwrap().get_src());
+                    self.is_tx_src_hardware_addr_set = true;
+                }
+                self.update_src_framing(indicator);
+
+                if ipv4.is_fragment() {
+                    // Fragmentation
+                    let frag = match self.defrag.add(indicator, buffer_without_padding) {
+                        Some(frag) => frag,
+                        None => return Ok(()),
+                    };
+                    let (indicator, buffer_without_padding) = frag.concatenate();
+
+                    match indicator.get_transport_type() {
+                        Some(t) => match t {
+                            LayerTypes::Tcp => {
+                                self.handle_tcp(&indicator, buffer_without_padding)?
+                            }
+                            LayerTypes::Udp => {
+                                self.handle_udp(&indicator, buffer_without_padding)?
+                            }
+                            LayerTypes::Icmpv4 => {
+                                self.handle_icmpv4(&indicator, buffer_without_padding)?
+                            }
+                            _ => unreachable!(),
+                        },
+                        None => self.handle_other(&indicator, buffer_without_padding)?,
+                    }
+                } else {
+                    match indicator.get_transport_type() {
+                        Some(t) => match t {
+                            LayerTypes::Tcp => {
+                                self.handle_tcp(indicator, buffer_without_padding)?
+                            }
+                            LayerTypes::Udp => {
+                                self.handle_udp(indicator, buffer_without_padding)?
+                            }
+                            LayerTypes::Icmpv4 => {
+                                self.handle_icmpv4(indicator, buffer_without_padding)?
+                            }
+                            _ => unreachable!(),
+                        },
+                        None => self.handle_other(indicator, buffer_without_padding)?,
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handles an IPv4 packet of a transport protocol without a layer, which is dropped unless it
+    /// is an IGMP message answered by the console responders.
+    fn handle_other(&mut self, indicator: &Indicator, buffer: &[u8]) -> io::Result<()> {
+        let is_igmp = indicator.get_ipv4().is_some_and(|ipv4| ipv4.is_igmp());
+        if is_igmp {
+            if let Some(igmp) = self.console.get_igmp_mut() {
+                if let Some((group, message)) = igmp.handle(&buffer[indicator.get_size()..]) {
+                    return self.tx.lock().unwrap().send_igmp(group, &message);
+                }
+
+                return Ok(());
+            }
+        }
+        self.drop_packet(indicator, DropReason::Protocol);
+
+        Ok(())
+    }
+
+    /// Handles a DHCP message to the server, which is answered by the console responders.
+    fn handle_dhcp(&mut self, indicator: &Indicator, buffer: &[u8]) -> io::Result<()> {
+        let reply = match self.console.get_dhcp_mut() {
+            Some(dhcp) => dhcp.handle(&buffer[min(indicator.get_size(), buffer.len())..]),
+            None => None,
+        };
+        if let Some(reply) = reply {
+            let mut tx_locked = self.tx.lock().unwrap();
+            let src = SocketAddrV4::new(tx_locked.get_local_ip_addr(), dhcp::SERVER_PORT);
+            return tx_locked.send_udp_broadcast(src, dhcp::CLIENT_PORT, &reply);
+        }
+
+        Ok(())
+    }
+
+    /// Counts a packet dropped before any flow is created for it.
+    fn drop_packet(&mut self, indicator: &Indicator, reason: DropReason) {
+        self.drops.add(reason, || indicator.brief());
+    }
+
+    /// Counts a frame of an unsupported network protocol, which is from the source if it is from
+    /// the hardware address of the source.
+    fn drop_unknown(&mut self, indicator: &Indicator) {
+        let is_src = match indicator.get_ethernet() {
+            Some(ethernet) => {
+                self.is_tx_src_hardware_addr_set
+                    && ethernet.get_src() == self.tx.lock().unwrap().get_src_hardware_addr()
+            }
+            None => false,
+        };
+        let reason = if is_src {
+            DropReason::Protocol
+        } else {
+            DropReason::OtherHost
+        };
+        self.drops.add(reason, || match indicator.get_ethernet() {
+            Some(ethernet) => ethernet.to_string(),
+            None => String::from("frame"),
+        });
+    }
+
+    /// Returns if the TCP or UDP packet from the source is malformed, which is counted and
+    /// dropped before any state is created for it.
+    fn is_malformed(&mut self, indicator: &Indicator, buffer: &[u8]) -> bool {
+        match indicator.validate(buffer, self.is_verify_checksum) {
+            Ok(_) => false,
+            Err(reason) => {
+                self.drop_packet(indicator, DropReason::Malformed(reason));
+
+                true
+            }
+        }
+    }
+
+    /// Handles a timer fired.
+    fn fire(&mut self, timer: Timer) {
+        match timer {
+            Timer::HalfOpen(key) => self.expire_half_open(key),
+            Timer::Stale(key) => self.expire_stale(key),
+            Timer::Idle(key) => self.expire_idle_stream(key),
+            Timer::UdpIdle(index) => self.expire_idle_datagram(index),
+            Timer::TcpBridge(key) => self.expire_tcp_bridge(key),
+            Timer::UdpBridge(src_port) => self.expire_udp_bridge(src_port),
+        }
+    }
+
+    /// Closes the UDP association idle for longer than its timeout, which releases its place in
+    /// the thread registry, its socket and its association with the proxy. An association active
+    /// since the timer was scheduled is checked again once its idle timeout from the last
+    /// activity is over.
+    fn expire_idle_datagram(&mut self, index: usize) {
+        self.udp_idle_map.remove(&index);
+        let (src_port, remaining) = match self.datagrams[index] {
+            Some(ref worker) => (worker.get_src_port(), worker.get_idle_remaining()),
+            None => return,
+        };
+        match remaining {
+            Some(remaining) if remaining > Duration::from_secs(0) => {
+                let handle = self.timers.schedule(remaining, Timer::UdpIdle(index));
+                self.udp_idle_map.insert(index, handle);
+                return;
+            }
+            Some(_) => {}
+            None => return,
+        }
+
+        let local_port = self.udp_initial_port + index as u16;
+        self.udp_idle_expired = self.udp_idle_expired.saturating_add(1);
+        debug!(
+            "UDP {} = {} is idle for {} s, close",
+            src_port,
+            local_port,
+            self.udp_idle_timeout.unwrap_or_default().as_secs()
+        );
+        self.close_datagram_flow(index, "idle");
+        self.datagrams[index] = None;
+        if self.datagram_map.get(src_port as usize) == Some(&local_port) {
+            self.datagram_map[src_port as usize] = 0;
+        }
+        self.udp_lru.put(index as u16, 0);
+    }
+
+    fn handle_udp(&mut self, indicator: &Indicator, buffer: &[u8]) -> io::Result<()> {
+        if self.is_malformed(indicator, buffer) {
+            return Ok(());
+        }
+        if let Some(udp) = indicator.get_udp() {
+            // SSDP searches of the gateway, answered by the gateway itself
+            let dst = SocketAddrV4::new(udp.get_dst_ip_addr(), udp.get_dst());
+            if let Some(response) = self.discovery.answer(dst, &buffer[indicator.get_size()..]) {
+                let mut tx_locked = self.tx.lock().unwrap();
+                let gateway = SocketAddrV4::new(tx_locked.get_local_ip_addr(), SSDP_PORT);
+                trace!("answer SSDP search {} -> {}", udp.get_src(), dst);
+                return tx_locked.send_udp(gateway, udp.get_src(), &response);
+            }
+
+            // Local delivery, except DNS to the resolver
+            let is_gateway_dns = udp.get_dst() == dns::DNS_PORT && self.gateway_dns.is_some();
+            if udp.get_dst_ip_addr() == self.tx.lock().unwrap().get_local_ip_addr()
+                && !is_gateway_dns
+            {
+                return self.deliver_local(indicator, buffer);
+            }
+
+            // Bridge
+            if self.bridge(indicator, buffer)? {
+                return Ok(());
+            }
+
+            // Quarantine
+            if let Some(instant) = self.udp_quarantine_map.get(&udp.get_src()) {
+                if Instant::now() < *instant {
+                    self.drop_packet(indicator, DropReason::UdpQuarantine);
+                    return Ok(());
+                }
+                self.udp_quarantine_map.remove(&udp.get_src());
+            }
+
+            // Destinations, a server replies to all its clients from its port
+            let is_server = self.udp_servers.contains_key(&udp.get_src());
+            if !is_server
+                && !self.fanout.admit(
+                    udp.get_src(),
+                    SocketAddrV4::new(udp.get_dst_ip_addr(), udp.get_dst()),
+                )
+            {
+                self.drop_packet(indicator, DropReason::UdpDestinations);
+                return Ok(());
+            }
+
+            // Discovery
+            if !is_server
+                && !self.fanout.is_allowed(udp.get_dst())
+                && !self.discovery.admit(
+                    udp.get_src(),
+                    SocketAddrV4::new(udp.get_dst_ip_addr(), udp.get_dst()),
+                    &buffer[indicator.get_size()..],
+                )
+            {
+                self.drop_packet(indicator, DropReason::UdpDiscovery);
+                return Ok(());
+            }
+
+            // DNS
+            if udp.get_dst() == dns::DNS_PORT {
+                if let Some(ref dns) = self.dns {
+                    let dst = SocketAddrV4::new(udp.get_dst_ip_addr(), udp.get_dst());
+                    let tx: Arc<Mutex<dyn Forward>> = self.get_tx();
+                    let lookup = dns.lock().unwrap().lookup(
+                        tx,
+                        self.src_ip_addr,
+                        udp.get_src(),
+                        dst,
+                        &buffer[indicator.get_size()..],
+                    );
+                    match lookup {
+                        Lookup::Hit(response) => {
+                            return self
+                                .tx
+                                .lock()
+                                .unwrap()
+                                .send_udp(dst, udp.get_src(), &response);
+                        }
+                        Lookup::Coalesced => return Ok(()),
+                        Lookup::Miss => {}
+                    }
+                }
+            }
+
+            // DNS to the gateway is sent to the resolver, whose answers come back from the gateway
+            let dst = match self.gateway_dns {
+                Some(resolver)
+                    if udp.get_dst() == dns::DNS_PORT
+                        && udp.get_dst_ip_addr() == self.tx.lock().unwrap().get_local_ip_addr() =>
+                {
+                    resolver
+                }
+                _ => SocketAddrV4::new(udp.get_dst_ip_addr(), udp.get_dst()),
+            };
+
+            let port = self.get_local_udp_port(udp.get_src());
+            let index = (port - self.udp_initial_port) as usize;
+
+            // Bind
+            let is_create;
+            let is_set;
+            match self.datagrams[index] {
+                Some(ref worker) => {
+                    is_create = worker.is_closed();
+                    is_set = worker.get_src_port() != udp.get_src();
+                    if let Some(reason) = worker.get_close_reason() {
+                        trace!(
+                            "bind datagram {} = {} again, closed for {}",
+                            udp.get_src(),
+                            port,
+                            reason
+                        );
+                    }
+                }
+                None => {
+                    is_create = true;
+                    is_set = false;
+                }
+            };
+            if is_create || is_set {
+                // Envelope, only the associations bound count in the flows of the source
+                let flows = self.get_flow_count() - if is_set { 1 } else { 0 };
+                if let Err(refusal) = self.envelope.admit(flows) {
+                    self.drop_packet(indicator, DropReason::from(refusal));
+                    return Ok(());
+                }
+            }
+            if is_create {
+                // Bind, UDP to a proxy which cannot relay it is dropped, while UDP connected
+                // directly still works
+                if let Err(e) = self.bind_datagram(port, udp.get_src(), dst) {
+                    if e.kind() == io::ErrorKind::ConnectionRefused {
+                        // Rejected by the fallback policy, quotes the IPv4 header and the UDP
+                        // header
+                        self.drop_packet(indicator, DropReason::Fallback);
+                        let start = indicator.get_ethernet().unwrap().get_size();
+                        let end = min(
+                            start + indicator.get_ipv4().unwrap().get_size() + 8,
+                            buffer.len(),
+                        );
+                        return self
+                            .tx
+                            .lock()
+                            .unwrap()
+                            .send_icmpv4_port_unreachable(*dst.ip(), &buffer[start..end]);
+                    }
+                    if !is_unsupported(&e) {
+                        return Err(e);
+                    }
+                    if !self.is_udp_unsupported_warned {
+                        self.is_udp_unsupported_warned = true;
+                        warn!("UDP is dropped: {}", e);
+                    }
+                    self.drop_packet(indicator, DropReason::UdpUnsupported);
+                    return Ok(());
+                }
+            } else if is_set {
+                // Replace
+                self.close_datagram_flow(index, "reuse");
+                self.datagrams[index]
+                    .as_mut()
+                    .unwrap()
+                    .set_src_port(udp.get_src());
+                self.open_datagram_flow(index, udp.get_src(), *dst.ip(), dst.port());
+            }
+
+            // Send
+            self.datagrams[index]
+                .as_mut()
+                .unwrap()
+                .send_to(&buffer[indicator.get_size()..], dst.into())?;
+        }
+
+        Ok(())
+    }
+
+    /// Handles a TCP segment or a UDP datagram to the gateway itself, which is never proxied. A
+    /// published gateway refuses it like a host without the port open, with a RST or an ICMPv4
+    /// port unreachable, while the host answers its own address by itself.
+    fn deliver_local(&mut self, indicator: &Indicator, buffer: &[u8]) -> io::Result<()> {
+        let gateway = match self.local_ip_addr {
+            Some(local_ip_addr) => local_ip_addr,
+            None => {
+                self.drop_packet(indicator, DropReason::Gateway);
+                return Ok(());
+            }
+        };
+
+        if let Some(tcp) = indicator.get_tcp() {
+            if let Some(probe) = self.console.get_probe_mut() {
+                if tcp.get_dst() == probe.get_port() {
+                    let mut tx_locked = self.tx.lock().unwrap();
+                    let segments = probe.handle(
+                        tcp.get_src(),
+                        tcp.get_flags(),
+                        tcp.get_sequence(),
+                        tcp.get_acknowledgement(),
+                        &buffer[indicator.get_size()..],
+                        (tx_locked.get_mtu() - TCP_HEADERS_SIZE) as usize,
+                    );
+                    for segment in segments {
+                        tx_locked.send_tcp_segment(
+                            SocketAddrV4::new(gateway, tcp.get_dst()),
+                            tcp.get_src(),
+                            &segment,
+                        )?;
+                    }
+
+                    return Ok(());
+                }
+            }
+            // A RST is never answered
+            if tcp.is_rst() {
+                return Ok(());
+            }
+            let (sequence, acknowledgement) = if tcp.is_ack() {
+                (tcp.get_acknowledgement(), None)
+            } else {
+                let mut size = (buffer.len() - indicator.get_size()) as u32;
+                if tcp.is_syn() {
+                    size += 1;
+                }
+                if tcp.is_fin() {
+                    size += 1;
+                }
+                (0, Some(tcp.get_sequence().wrapping_add(size)))
+            };
+            self.gateway_refused = self.gateway_refused.saturating_add(1);
+            debug!("refuse {}", indicator.brief());
+
+            return self.tx.lock().unwrap().send_tcp_refusal(
+                SocketAddrV4::new(gateway, tcp.get_dst()),
+                tcp.get_src(),
+                sequence,
+                acknowledgement,
+            );
+        }
+        if indicator.get_udp().is_some() {
+            // Quotes the IPv4 header and the UDP header
+            let start = indicator.get_ethernet().unwrap().get_size();
+            let end = min(
+                start + indicator.get_ipv4().unwrap().get_size() + 8,
+                buffer.len(),
+            );
+            self.gateway_refused = self.gateway_refused.saturating_add(1);
+            debug!("refuse {}", indicator.brief());
+
+            return self
+                .tx
+                .lock()
+                .unwrap()
+                .send_icmpv4_port_unreachable(gateway, &buffer[start..end]);
+        }
+
+        Ok(())
+    }
+
+    fn handle_icmpv4(&mut self, indicator: &Indicator, buffer: &[u8]) -> io::Result<()> {
+        if let Some(icmpv4) = indicator.get_icmpv4() {
+            if let Some(ttl) = self.ping_ttl {
+                // Answer pings to the gateway only, pings to others cannot be proxied
+                let mut tx_locked = self.tx.lock().unwrap();
+                if icmpv4.is_echo_request()
+                    && icmpv4.get_dst_ip_addr() == tx_locked.get_local_ip_addr()
+                {
+                    return tx_locked.send_icmpv4_echo_reply(
+                        icmpv4.get_dst_ip_addr(),
+                        ttl,
+                        &buffer[indicator.get_size()..],
+                    );
+                }
+            }
+            self.drop_packet(indicator, DropReason::Icmp);
+        }
+
+        Ok(())
+    }
+
+    /// Binds a UDP association of the source port on the local port, routed by the destination.
+    fn bind_datagram(&mut self, port: u16, src_port: u16, dst: SocketAddrV4) -> io::Result<()> {
+        let index = (port - self.udp_initial_port) as usize;
+
+        let admission = self.rules.find(dst);
+        let route = self.select_route(dst, admission);
+        let datagram = match route {
+            Route::Proxy(remote) => {
+                self.reload_credentials();
+                let connector =
+                    ProxyConnector::new(self.proxy_type, remote, self.credentials.get_auth());
+                let datagram = DatagramWorker::bind(
+                    self.get_udp_tx(),
+                    &self.threads,
+                    src_port,
+                    port,
+                    &connector,
+                );
+                if let Err(ref e) = datagram {
+                    self.upstreams.report_failure(remote, e);
+                }
+                self.follow_udp(&datagram);
+
+                datagram
+            }
+            Route::Direct => {
+                debug!("Bind for {} directly", dst.ip());
+                DatagramWorker::bind_direct(self.get_udp_tx(), &self.threads, src_port, port)
+            }
+            // A flow bridged is never bound, and UDP has no local services
+            Route::Reject | Route::Bridge | Route::Local(_) => Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                "all destinations are down",
+            )),
+        };
+        let mut datagram = datagram?;
+        // Clients of a server come from anywhere, replies are never normalized to one of them
+        let is_server = self.udp_servers.contains_key(&src_port);
+        let is_normalize = admission
+            .and_then(|admission| self.rules.get(admission.rule).get_normalize_remote_port())
+            .unwrap_or(self.is_normalize_remote_port);
+        if is_server {
+            datagram.set_track_clients()?;
+        } else {
+            datagram.set_idle_timeout(self.udp_idle_timeout);
+        }
+        if let Some(ref monitor) = self.monitor {
+            datagram.set_monitor(monitor);
+        }
+        if let Some(recorder) = self.create_recorder(Kind::Datagram, src_port, dst, admission) {
+            datagram.set_recorder(recorder);
+        }
+        self.close_datagram_flow(index, "rebind");
+        self.datagrams[index] = Some(datagram);
+        if let Some(handle) = self.udp_idle_map.remove(&index) {
+            self.timers.cancel(handle);
+        }
+        if let (Some(timeout), false) = (self.udp_idle_timeout, is_server) {
+            let handle = self.timers.schedule(timeout, Timer::UdpIdle(index));
+            self.udp_idle_map.insert(index, handle);
+        }
+        self.datagram_rules[index] = admission;
+        match route {
+            Route::Proxy(remote) => self.udp_rtt_pending.insert(index, (remote, dst)),
+            _ => self.udp_rtt_pending.remove(&index),
+        };
+        self.datagrams[index]
+            .as_mut()
+            .unwrap()
+            .set_normalize_remote_port(is_normalize && !is_server);
+        self.open_datagram_flow(index, src_port, *dst.ip(), dst.port());
+
+        Ok(())
+    }
+
+    fn open_datagram_flow(
+        &mut self,
+        index: usize,
+        src_port: u16,
+        dst_ip_addr: Ipv4Addr,
+        dst_port: u16,
+    ) {
+        let bytes = match self.datagrams[index] {
+            Some(ref worker) => worker.get_bytes(),
+            None => return,
+        };
+        let rules = &self.rules;
+        let rule = self.datagram_rules[index].map(|admission| rules.get(admission.rule));
+        let flow = self.hooks.open(
+            Protocol::Udp,
+            SocketAddrV4::new(self.src_ip_addr, src_port),
+            SocketAddrV4::new(dst_ip_addr, dst_port),
+            rule,
+        );
+        self.datagram_flows[index] = Some((flow, bytes));
+    }
+
+    fn close_datagram_flow(&mut self, index: usize, reason: &str) {
+        if let Some((flow, (prev_bytes_up, prev_bytes_down))) = self.datagram_flows[index].take() {
+            let (bytes_up, bytes_down) = match self.datagrams[index] {
+                Some(ref worker) => {
+                    debug!(
+                        "UDP {} is closed for {}: {}",
+                        self.udp_initial_port as usize + index,
+                        reason,
+                        worker.get_stats()
+                    );
+                    worker.get_bytes()
+                }
+                None => (prev_bytes_up, prev_bytes_down),
+            };
+            self.hooks.close(
+                &flow,
+                bytes_up - prev_bytes_up,
+                bytes_down - prev_bytes_down,
+                reason,
+            );
+        }
+    }
+
+    /// Probes the path MTU again once an upstream reconnects, since the route to it may have
+    /// changed.
+    fn poll_ups(&mut self) {
+        if !self.upstreams.take_ups().is_empty() && self.path_mtu_interval.is_some() {
+            self.probe_path_mtu();
+        }
+    }
+
+    /// Probes the path MTU to upstreams and updates the MSS for new TCP connections.
+    fn probe_path_mtu(&mut self) {
+        self.last_path_mtu_probe = Instant::now();
+        let mtu = self.tx.lock().unwrap().get_mtu();
+
+        let mut path_mtu = None;
+        for remote in self.upstreams.get_remotes() {
+            match probe_path_mtu(*remote, mtu) {
+                Ok(value) => {
+                    trace!("probe path MTU to {}: {}", remote, value);
+                    path_mtu = Some(min(path_mtu.unwrap_or(value), value));
+                }
+                Err(ref e) => {
+                    if e.kind() == io::ErrorKind::Other {
+                        // Not supported
+                        warn!("path MTU: {}", e);
+                        self.path_mtu_interval = None;
+                        return;
+                    }
+                    debug!("probe path MTU to {}: {}", remote, e);
+                }
+            }
+        }
+        let path_mtu = match path_mtu {
+            Some(path_mtu) => max(min(path_mtu, mtu), MIN_PATH_MTU),
+            None => return,
+        };
+
+        if self.path_mtu != Some(path_mtu) {
+            info!(
+                "Path MTU to destinations is {}, new connections use MSS {}",
+                path_mtu,
+                path_mtu - TCP_HEADERS_SIZE
+            );
+            self.path_mtu = Some(path_mtu);
+        }
+    }
+
+    /// Checks the addresses of the interface and migrates to the new ones if they changed. Flows
+    /// are kept since the addresses they use in the source are virtual.
+    fn check_interface(&mut self) {
+        self.last_interface_check = Instant::now();
+        let prev = match self.inter {
+            Some(ref inter) => inter.clone(),
+            None => return,
+        };
+        let inter = match self.interfaces.get(&prev.name) {
+            Some(inter) => inter,
+            // The interface is gone or has no IPv4 address for now, keep the previous addresses
+            None => {
+                if !self.is_interface_lost {
+                    self.is_interface_lost = true;
+                    warn!("Interface {} is lost", prev.name);
+                    self.notifier.notify(Event::InterfaceLost(prev.name));
+                }
+                return;
+            }
+        };
+        if self.is_interface_lost {
+            self.is_interface_lost = false;
+            info!("Interface {} is back", inter.name);
+            self.notifier
+                .notify(Event::InterfaceReacquired(inter.name.clone()));
+            if self.path_mtu_interval.is_some() {
+                self.probe_path_mtu();
+            }
+        }
+        // The state of the interface is followed by the health even if its addresses are kept
+        if let Some(ref mut prev) = self.inter {
+            prev.is_up = inter.is_up;
+        }
+
+        let is_hardware_addr_changed = inter.hardware_addr != prev.hardware_addr;
+        let is_ip_addr_changed = inter.ip_addrs[0] != prev.ip_addrs[0];
+        if !is_hardware_addr_changed && !is_ip_addr_changed {
+            return;
+        }
+
+        {
+            let mut tx_locked = self.tx.lock().unwrap();
+            if is_hardware_addr_changed {
+                info!(
+                    "Interface {} changes hardware address from {} to {}",
+                    inter.name, prev.hardware_addr, inter.hardware_addr
+                );
+                tx_locked.set_local_hardware_addr(inter.hardware_addr);
+            }
+            // The gateway is the interface itself if no publishing address is given
+            if is_ip_addr_changed {
+                info!(
+                    "Interface {} changes IP address from {} to {}",
+                    inter.name, prev.ip_addrs[0], inter.ip_addrs[0]
+                );
+                if self.local_ip_addr.is_none() {
+                    tx_locked.set_local_ip_addr(inter.ip_addrs[0]);
+                }
+            }
+
+            // Announce the gateway again so the source learns the new hardware address
+            if self.local_ip_addr.is_some() && self.is_tx_src_hardware_addr_set {
+                if let Err(ref e) = tx_locked.send_arp_reply() {
+                    warn!("handle {}: {}", "ARP", e);
+                }
+            }
+        }
+
+        if is_ip_addr_changed && self.local_ip_addr.is_none() {
+            let src = self.src_ip_addr.octets();
+            let gateway = inter.ip_addrs[0].octets();
+            if src[..3] != gateway[..3] {
+                warn!(
+                    "Interface {} moves to another network, the source {} must be set up again",
+                    inter.name, self.src_ip_addr
+                );
+                show_info(self.src_ip_addr, inter.ip_addrs[0], self.mtu);
+            }
+        }
+
+        self.inter = Some(inter);
+        // A new address is a reconnect, which may take another route to upstreams
+        if is_ip_addr_changed && self.path_mtu_interval.is_some() {
+            self.probe_path_mtu();
+        }
+    }
+
+    /// Logs a snapshot of resources and tables with the changes since the previous one.
+    fn soak_report(&mut self) {
+        self.last_soak = Instant::now();
+
+        let mut entries = vec![
+            ("registered threads", self.threads.dump().len()),
+            ("tcp flows", self.tcp_flows.len()),
+            ("streams", self.get_stream_count()),
+            ("half-open", self.get_tcp_half_open_count()),
+            (
+                "caches",
+                self.tcp_flows
+                    .values()
+                    .filter(|flow| flow.cache.is_some())
+                    .count(),
+            ),
+            ("timers", self.timers.len()),
+            (
+                "datagrams",
+                self.datagrams.iter().filter(|d| d.is_some()).count(),
+            ),
+            (
+                "datagram flows",
+                self.datagram_flows.iter().filter(|d| d.is_some()).count(),
+            ),
+            (
+                "datagram ports",
+                self.datagram_map.iter().filter(|p| **p != 0).count(),
+            ),
+            ("fragments", self.defrag.get_count()),
+            ("udp destinations", self.fanout.get_count()),
+        ];
+        entries.extend(self.tx.lock().unwrap().get_table_sizes());
+
+        let snapshot = Snapshot::new(entries);
+        info!(
+            "Soak {}: {}",
+            self.src_ip_addr,
+            snapshot.diff(self.last_snapshot.as_ref())
+        );
+        if self.gateway_refused > 0 {
+            info!(
+                "Gateway {}: {} refused locally",
+                self.src_ip_addr, self.gateway_refused
+            );
+        }
+        info!(
+            "TCP {}: {}, {} simultaneous opens, {} picked up, {} pickups failed, {} half-open expired, {} half-open refused, {} SYN cookies accepted, {} idle expired, {} MSS clamped, {} local port exhaustions, {} pre-warmed connections taken",
+            self.src_ip_addr,
+            self.get_tcp_stats(),
+            self.tcp_simultaneous_opens,
+            self.tcp_pickups,
+            self.tcp_pickup_failures,
+            self.tcp_half_open_expired,
+            self.tcp_half_open_refused,
+            self.tcp_cookies_accepted,
+            self.tcp_idle_expired,
+            self.tcp_mss_clamped,
+            get_port_exhaustions(),
+            self.tcp_pool.as_ref().map_or(0, |pool| pool.get_taken())
+        );
+        let retries: Vec<String> = backoff::get_counts()
+            .iter()
+            .map(|(site, attempts, give_ups)| {
+                format!("{} {} attempts {} give-ups", site, attempts, give_ups)
+            })
+            .collect();
+        if !retries.is_empty() {
+            info!("Backoff: {}", retries.join(", "));
+        }
+        let failovers = self.get_tcp_failovers();
+        if !failovers.is_empty() {
+            info!(
+                "Fail over {}: {}",
+                self.src_ip_addr,
+                failovers
+                    .iter()
+                    .map(|(strategy, count)| format!("{} {}", count, strategy))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            );
+        }
+        if let Some(ref standby) = self.standby {
+            let (takeovers, stand_downs) = standby.get_transitions();
+            info!(
+                "Standby {}: {}, {} takeovers, {} stand downs",
+                self.src_ip_addr,
+                standby.get_role(),
+                takeovers,
+                stand_downs
+            );
+        }
+        info!("Envelope {}", self.envelope);
+        if let Some(stats) = self.get_dns_stats() {
+            info!("DNS {}: {}", self.src_ip_addr, stats);
+        }
+        let talkers = self.dump_udp_talkers(3);
+        if self.fanout.get_dropped() > 0 && !talkers.is_empty() {
+            info!(
+                "UDP {}: {} dropped for too many destinations, top talkers:\n{}",
+                self.src_ip_addr,
+                self.fanout.get_dropped(),
+                talkers
+            );
+        }
+        for (src_port, server) in &self.udp_servers {
+            let port = self.datagram_map[*src_port as usize];
+            let clients = match port {
+                0 => Vec::new(),
+                _ => match self.datagrams[(port - self.udp_initial_port) as usize] {
+                    Some(ref worker) => worker.get_clients(),
+                    None => Vec::new(),
+                },
+            };
+            let top: Vec<String> = clients
+                .iter()
+                .take(TOP_UDP_CLIENTS)
+                .map(|(addr, stats)| {
+                    format!(
+                        "{} {}/{} datagrams {}/{} Bytes",
+                        addr,
+                        stats.datagrams_up,
+                        stats.datagrams_down,
+                        stats.bytes_up,
+                        stats.bytes_down
+                    )
+                })
+                .collect();
+            info!(
+                "UDP server {}:{}: {} binds, {} clients{}{}",
+                self.src_ip_addr,
+                src_port,
+                server.binds,
+                clients.len(),
+                if top.is_empty() { "" } else { ", top: " },
+                top.join(", ")
+            );
+        }
+        if self.udp_idle_expired > 0 {
+            info!(
+                "UDP {}: {} associations closed for idle",
+                self.src_ip_addr, self.udp_idle_expired
+            );
+        }
+        let (classified, suppressed) = self.discovery.get_counts();
+        if classified > 0 {
+            info!(
+                "UDP {}: {} fan-outs classified as discovery, {} suppressed",
+                self.src_ip_addr, classified, suppressed
+            );
+        }
+        let answered = self.discovery.get_answered();
+        if answered > 0 {
+            info!(
+                "UDP {}: {} SSDP searches answered",
+                self.src_ip_addr, answered
+            );
+        }
+        let malformed = self.get_malformed();
+        if !malformed.is_empty() {
+            let malformed: Vec<String> = malformed
+                .iter()
+                .map(|(reason, count)| format!("{} {}", reason, count))
+                .collect();
+            info!("Malformed {}: {}", self.src_ip_addr, malformed.join(", "));
+        }
+        let drops = self.get_drops();
+        if !drops.is_empty() {
+            let drops: Vec<String> = drops
+                .iter()
+                .map(|(reason, count)| format!("{} {}", reason, count))
+                .collect();
+            info!("Dropped {}: {}", self.src_ip_addr, drops.join(", "));
+        }
+        if let Some(diagnosis) = self.diagnose() {
+            info!("Diagnosis {}: {}", self.src_ip_addr, diagnosis);
+        }
+        self.last_snapshot = Some(snapshot);
+    }
+
+    /// Replicates the state to the peer if active, and follows the role elected.
+    fn check_standby(&mut self) {
+        self.last_standby_check = Instant::now();
+        let is_active = match self.standby {
+            Some(ref standby) => standby.get_role() == Role::Active,
+            None => return,
+        };
+        let state = if is_active {
+            Some(self.get_state().0)
+        } else {
+            None
+        };
+        let transition = match self.standby {
+            Some(ref mut standby) => {
+                if let Some(state) = state {
+                    standby.set_state(state);
+                }
+                standby.elect()
+            }
+            None => return,
+        };
+        match transition {
+            Some(Transition::TakeOver(state)) => self.take_over(state),
+            Some(Transition::StandDown) => self.stand_down("the peer is preferred"),
+            None => {}
+        }
+    }
+
+    /// Becomes the active instance, restores the state replicated from the peer and announces the
+    /// gateway.
+    fn take_over(&mut self, state: Option<String>) {
+        self.is_standby = false;
+        warn!(
+            "Take over the gateway {} for {}",
+            self.local_ip_addr.unwrap_or(Ipv4Addr::UNSPECIFIED),
+            self.src_ip_addr
+        );
+        if let Some(state) = state {
+            let associations = self.restore_state(&state);
+            info!("Restore {} UDP associations from the peer", associations);
+        }
+        self.announce();
+    }
+
+    /// Becomes the standby instance and releases all the flows without notifying the source,
+    /// which is served by the peer then.
+    fn stand_down(&mut self, reason: &str) {
+        self.is_standby = true;
+        warn!(
+            "Stand down from the gateway {} for {}: {}",
+            self.local_ip_addr.unwrap_or(Ipv4Addr::UNSPECIFIED),
+            self.src_ip_addr,
+            reason
+        );
+
+        for key in self.get_stream_keys() {
+            if let Some(stream) = self.get_stream_mut(&key) {
+                stream.abort();
+            }
+            self.remove_key(key, "standby");
+            self.tx.lock().unwrap().remove(key.1, key.0);
+        }
+        self.release_datagrams("standby");
+    }
+
+    /// Releases all the UDP associations and their local ports without notifying the source.
+    fn release_datagrams(&mut self, reason: &str) {
+        for src_port in 0..self.datagram_map.len() {
+            let local_port = self.datagram_map[src_port];
+            if local_port == 0 {
+                continue;
+            }
+            let index = local_port - self.udp_initial_port;
+            self.close_datagram_flow(index as usize, reason);
+            self.datagrams[index as usize] = None;
+            self.datagram_map[src_port] = 0;
+            self.udp_lru.put(index, 0);
+        }
+    }
+
+    /// Follows the phase of the handoff, and returns if the old process is drained.
+    fn check_handoff(&mut self) -> io::Result<bool> {
+        let handoff = match self.handoff {
+            Some(ref handoff) => Arc::clone(handoff),
+            None => return Ok(false),
+        };
+        let key = self.src_ip_addr.to_string();
+        match (handoff.get_phase(), self.drain_until) {
+            // The new process serves once the old process released the state
+            (Phase::Serving, None) if self.is_standby => {
+                self.is_standby = false;
+                let associations = handoff
+                    .take_state(&key)
+                    .map_or(0, |state| self.restore_state(&state));
+                info!(
+                    "Take over {} with {} UDP associations from the old process",
+                    self.src_ip_addr, associations
+                );
+            }
+            (Phase::Failed, _) => {
+                return Err(io::Error::other("the handoff from the old process fails"))
+            }
+            (Phase::Releasing, None) if !self.is_standby => {
+                let (state, associations) = self.get_state();
+                self.is_standby = true;
+                self.release_datagrams("handoff");
+                self.drain_until = Some(Instant::now() + handoff.get_drain());
+                handoff.release(&key, state);
+                info!(
+                    "Release {} UDP associations of {}, drain {} TCP connections",
+                    associations,
+                    self.src_ip_addr,
+                    self.get_stream_count()
+                );
+            }
+            (Phase::Aborted, Some(_)) => {
+                self.is_standby = false;
+                self.drain_until = None;
+                let associations = handoff
+                    .take_state(&key)
+                    .map_or(0, |state| self.restore_state(&state));
+                warn!(
+                    "Handoff of {} is aborted, restore {} UDP associations",
+                    self.src_ip_addr, associations
+                );
+            }
+            (Phase::Draining, Some(drain_until)) => {
+                if self.get_stream_count() == 0 {
+                    info!("Drain {}", self.src_ip_addr);
+                    return Ok(true);
+                }
+                if Instant::now() >= drain_until {
+                    // The source connects again through the new process
+                    warn!(
+                        "Drain {}, reset {} TCP connections left",
+                        self.src_ip_addr,
+                        self.get_stream_count()
+                    );
+                    for key in self.get_stream_keys() {
+                        if let Some(stream) = self.get_stream_mut(&key) {
+                            stream.abort();
+                        }
+                        self.remove_key(key, "handoff");
+                        let mut tx_locked = self.tx.lock().unwrap();
+                        if let Err(ref e) = tx_locked.send_tcp_ack_rst(key.1, key.0) {
+                            warn!("handle {}: {}", "TCP", e);
+                        }
+                        tx_locked.remove(key.1, key.0);
+                    }
+                    return Ok(true);
+                }
+            }
+            _ => {}
+        }
+
+        Ok(false)
+    }
+
+    /// Returns if the frame is a TCP segment of a connection drained after handing off.
+    fn is_draining(&self, frame: &[u8]) -> bool {
+        if self.drain_until.is_none() {
+            return false;
+        }
+        match Indicator::from(frame) {
+            Some(ref indicator) => match indicator.get_tcp() {
+                Some(tcp) => self
+                    .get_stream(&(
+                        tcp.get_src(),
+                        SocketAddrV4::new(tcp.get_dst_ip_addr(), tcp.get_dst()),
+                    ))
+                    .is_some(),
+                None => false,
+            },
+            None => false,
+        }
+    }
+
+    /// Handles another hardware address claiming the gateway, which stands down the instance if it
+    /// took over recently, or announces the gateway again otherwise.
+    fn handle_conflict(&mut self, hardware_addr: HardwareAddr) {
+        let transition = match self.standby {
+            Some(ref mut standby) => standby.report_conflict(),
+            None => return,
+        };
+        match transition {
+            Some(Transition::StandDown) => {
+                self.stand_down(&format!("{} still claims the gateway", hardware_addr))
+            }
+            _ => {
+                let is_due = self
+                    .last_announce
+                    .is_none_or(|instant| instant.elapsed().as_secs() >= standby::PEER_HOLD);
+                if is_due {
+                    warn!(
+                        "{} claims the gateway {}, announce it again",
+                        hardware_addr,
+                        self.local_ip_addr.unwrap_or(Ipv4Addr::UNSPECIFIED)
+                    );
+                    self.announce();
+                }
+            }
+        }
+    }
+
+    /// Announces the gateway with gratuitous ARP replies, if the source is known.
+    fn announce(&mut self) {
+        self.last_announce = Some(Instant::now());
+        // The source learns the gateway from its ARP request otherwise
+        if !self.is_tx_src_hardware_addr_set {
+            return;
+        }
+        let mut tx_locked = self.tx.lock().unwrap();
+        for _ in 0..GRATUITOUS_ARP_COUNT {
+            if let Err(ref e) = tx_locked.send_gratuitous_arp() {
+                warn!("handle {}: {}", "ARP", e);
+                break;
+            }
+        }
+    }
+
+    /// Resets a random TCP connection and closes a random UDP association, and flaps a random
+    /// upstream every few times.
+    fn chaos(&mut self) {
+        self.last_chaos = Instant::now();
+        self.chaos_kills = self.chaos_kills.wrapping_add(1);
+
+        if self.chaos_kills.is_multiple_of(CHAOS_FLAP_KILLS) {
+            let remotes = self.upstreams.get_remotes();
+            if !remotes.is_empty() {
+                let remote = remotes[self.random.next_below(remotes.len())];
+                info!("Chaos: flap destination {}", remote);
+                // Failed over by the run loop, and back once a probe connects
+                self.upstreams.flap(remote);
+            }
+        }
+
+        let keys = self.get_stream_keys();
+        if !keys.is_empty() {
+            let key = keys[self.random.next_below(keys.len())];
+            info!("Chaos: reset stream {} -> {}", key.0, key.1);
+            self.remove_key(key, "chaos");
+            let mut tx_locked = self.tx.lock().unwrap();
+            if let Err(ref e) = tx_locked.send_tcp_ack_rst(key.1, key.0) {
+                warn!("handle {}: {}", "TCP", e);
+            }
+            tx_locked.remove(key.1, key.0);
+        }
+
+        let indexes: Vec<usize> = (0..self.datagrams.len())
+            .filter(|index| self.datagrams[*index].is_some())
+            .collect();
+        if !indexes.is_empty() {
+            let index = indexes[self.random.next_below(indexes.len())];
+            info!(
+                "Chaos: close datagram {}",
+                self.udp_initial_port + index as u16
+            );
+            // The association is bound again by the next datagram of the source
+            self.close_datagram_flow(index, "chaos");
+            self.datagrams[index] = None;
+        }
+    }
+
+    /// Publishes the source ports with the most new UDP destinations to the monitor, so the
+    /// control can tell which application of the source sprays datagrams.
+    fn publish_talkers(&mut self) {
+        self.last_talkers = Instant::now();
+        if let Some(ref monitor) = self.monitor {
+            monitor.set_talkers(self.src_ip_addr, self.fanout.get_top_talkers(TOP_TALKERS));
+        }
+    }
+
+    fn reload_credentials(&mut self) {
+        match self.credentials.reload() {
+            Ok(true) => info!("Reload password"),
+            Ok(false) => {}
+            // Keep the previous password
+            Err(ref e) => warn!("reload password: {}", e),
+        }
+    }
+
+    fn get_tx(&self) -> Arc<Mutex<Forwarder>> {
+        Arc::clone(&self.tx)
+    }
+
+    /// Get the `Forward` of UDP associations, which completes identical DNS queries if DNS
+    /// answers are cached.
+    fn get_udp_tx(&self) -> Arc<Mutex<dyn Forward>> {
+        let tx: Arc<Mutex<dyn Forward>> = match self.dns {
+            Some(ref dns) => Arc::new(Mutex::new(DnsForward::new(self.get_tx(), Arc::clone(dns)))),
+            None => self.get_tx(),
+        };
+        match (self.gateway_dns, self.local_ip_addr) {
+            (Some(resolver), Some(gateway)) => {
+                Arc::new(Mutex::new(GatewayForward::new(tx, resolver, gateway)))
+            }
+            _ => tx,
+        }
+    }
+
+    /// Get the statistics of all the TCP connections, open or closed.
+    pub fn get_tcp_stats(&self) -> TcpStats {
+        let mut stats = self.tcp_closed_stats;
+        for flow_stats in self
+            .tcp_flows
+            .values()
+            .filter_map(|flow| flow.stats.as_ref())
+        {
+            stats.add(flow_stats);
+        }
+
+        stats
+    }
+
+    /// Get the statistics of each open TCP connection.
+    pub fn dump_tcp_stats(&self) -> String {
+        let mut flows: Vec<(&(u16, SocketAddrV4), &TcpFlow, &TcpStats)> = self
+            .tcp_flows
+            .iter()
+            .filter_map(|(key, flow)| flow.stats.as_ref().map(|stats| (key, flow, stats)))
+            .collect();
+        flows.sort_by_key(|(key, _, _)| (key.0, *key.1.ip(), key.1.port()));
+
+        let tx_locked = self.tx.lock().unwrap();
+        flows
+            .iter()
+            .map(|(key, flow, stats)| {
+                let mut s = format!("{} -> {}: {}", key.0, key.1, stats);
+                if let Some((advertised, effective)) = flow.peer_mss {
+                    s.push_str(&format!(
+                        ", MSS {} (advertised {})",
+                        effective,
+                        advertised.map_or(String::from("none"), |mss| mss.to_string())
+                    ));
+                }
+                if let Some((cwnd, ssthresh, rto)) = tx_locked.get_tcp_congestion(key.1, key.0) {
+                    s.push_str(&format!(
+                        ", cwnd {} Bytes, ssthresh {} Bytes, RTO {} ms",
+                        cwnd,
+                        ssthresh,
+                        rto.as_millis()
+                    ));
+                }
+
+                s
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    /// Get the number of TCP connections whose MSS advertised by the source is clamped.
+    pub fn get_tcp_mss_clamped(&self) -> usize {
+        self.tcp_mss_clamped
+    }
+
+    /// Get the number of TCP keep-alives answered without forwarding.
+    pub fn get_tcp_keepalives(&self) -> usize {
+        self.tcp_keepalives
+    }
+
+    /// Sets if the checksums of UDP datagrams from the source are verified. Datagrams without a
+    /// checksum are always accepted.
+    pub fn set_verify_checksum(&mut self, is_verify_checksum: bool) {
+        self.is_verify_checksum = is_verify_checksum;
+    }
+
+    /// Get the number of malformed packets dropped for each reason.
+    pub fn get_malformed(&self) -> Vec<(Malformed, usize)> {
+        self.drops
+            .get_counts()
+            .into_iter()
+            .filter_map(|(reason, count)| match reason {
+                DropReason::Malformed(reason) => Some((reason, count)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Sets to log one in every N packets dropped before flows with the reason, or `None` to not
+    /// log them.
+    pub fn set_log_drops(&mut self, sample: Option<usize>) {
+        self.drops.set_sample(sample);
+    }
+
+    /// Get the number of packets dropped before flows for each reason.
+    pub fn get_drops(&self) -> Vec<(DropReason, usize)> {
+        self.drops.get_counts()
+    }
+
+    /// Get the reason most packets of the source are dropped for, with the hint to the user.
+    pub fn diagnose(&self) -> Option<String> {
+        self.drops.diagnose()
+    }
+
+    /// Get the number of ACK/SYN from the source in TCP simultaneous opens.
+    pub fn get_tcp_simultaneous_opens(&self) -> usize {
+        self.tcp_simultaneous_opens
+    }
+
+    /// Get the number of TCP connections picked up in the middle, and the number of those failed,
+    /// which are reset.
+    pub fn get_tcp_pickups(&self) -> (usize, usize) {
+        (self.tcp_pickups, self.tcp_pickup_failures)
+    }
+
+    /// Get the number of half-open TCP connections reset for the handshake timeout.
+    pub fn get_tcp_half_open_expired(&self) -> usize {
+        self.tcp_half_open_expired
+    }
+
+    /// Get the number of UDP associations and TCP connections closed for the idle timeouts.
+    pub fn get_idle_expired(&self) -> (usize, usize) {
+        (self.udp_idle_expired, self.tcp_idle_expired)
+    }
+
+    /// Get the number of SYN refused for the max number of half-open TCP connections.
+    pub fn get_tcp_half_open_refused(&self) -> usize {
+        self.tcp_half_open_refused
+    }
+
+    /// Get the number of TCP connections opened from the acknowledgement of a SYN cookie.
+    pub fn get_tcp_cookies_accepted(&self) -> usize {
+        self.tcp_cookies_accepted
+    }
+
+    /// Get the number of established TCP connections handled by each resume strategy when their
+    /// upstreams went down.
+    pub fn get_tcp_failovers(&self) -> Vec<(ResumeStrategy, usize)> {
+        let mut failovers: Vec<(ResumeStrategy, usize)> =
+            self.tcp_failovers.iter().map(|(k, v)| (*k, *v)).collect();
+        failovers.sort();
+
+        failovers
+    }
+
+    /// Get the number of pure TCP ACK duplicates dropped for exceeding the rate.
+    pub fn get_tcp_dropped_duplicates(&self) -> usize {
+        self.tcp_dropped_duplicates
+    }
+
+    /// Get the number of times the open TCP streams paused reading from proxies for the slow
+    /// source.
+    pub fn get_tcp_pauses(&self) -> usize {
+        self.tcp_flows
+            .values()
+            .filter_map(|flow| flow.stream.as_ref())
+            .map(|stream| stream.get_pauses())
+            .sum()
+    }
+
+    /// Get the inventory of threads spawned by the `Redirector`.
+    pub fn dump_threads(&self) -> String {
+        format!("{}", self.threads)
+    }
+
+    /// Logs the inventories of the `Redirector`, once a dump is requested in its monitor.
+    fn dump(&self) {
+        info!("Threads of {}: {}", self.src_ip_addr, self.dump_threads());
+        if !self.rules.is_empty() {
+            info!("Rules of {}:", self.src_ip_addr);
+            for line in self.dump_rules().lines() {
+                info!("    {}", line);
+            }
+        }
+        let latencies = self.dump_latencies(DUMP_LATENCIES);
+        if !latencies.is_empty() {
+            info!("Latencies of {}:", self.src_ip_addr);
+            for line in latencies.lines() {
+                info!("    {}", line);
+            }
+        }
+    }
+
+    /// Get the number of UDP datagrams whose remote port is normalized in each association.
+    pub fn dump_normalized(&self) -> String {
+        self.datagrams
+            .iter()
+            .filter_map(|datagram| datagram.as_ref())
+            .map(|datagram| format!("{}: {}", datagram.get_src_port(), datagram.get_normalized()))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    /// Get the top destination prefixes and the upstreams chosen for them.
+    pub fn dump_latencies(&self, n: usize) -> String {
+        self.upstreams
+            .dump(n)
+            .iter()
+            .map(|entry| entry.to_string())
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    fn get_local_udp_port(&mut self, src_port: u16) -> u16 {
+        let local_port = self.datagram_map[src_port as usize];
+        if local_port == 0 {
+            // The ports of UDP servers are pinned, which are used again at once
+            let mut pair = self.udp_lru.pop_lru().unwrap();
+            while pair.1 != 0 && self.udp_servers.contains_key(&pair.1) {
+                self.udp_lru.put(pair.0, pair.1);
+                pair = self.udp_lru.pop_lru().unwrap();
+            }
+            let index = pair.0;
+            let prev_src_port = pair.1;
+            let local_port = self.udp_initial_port + index;
+
+            // Update LRU
+            self.udp_lru.put(index, src_port);
+
+            if prev_src_port != 0 {
+                // Reuse
+                self.datagram_map[prev_src_port as usize] = 0;
+                trace!(
+                    "reuse UDP port {} = {} to {} = {}",
+                    prev_src_port,
+                    local_port,
+                    src_port,
+                    local_port
+                );
+            }
+            self.datagram_map[src_port as usize] = local_port;
+            debug_assert_eq!(self.udp_lru.peek(&index), Some(&src_port));
+
+            local_port
+        } else {
+            // Update LRU, which is keyed by the index of the local port
+            let index = local_port - self.udp_initial_port;
+            debug_assert_eq!(self.udp_lru.peek(&index), Some(&src_port));
+            self.udp_lru.get(&index);
+
+            local_port
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::builder::Builder;
+    use crate::packet::Indicator;
+    use crate::testing;
+    use pnet::packet::tcp::TcpFlags;
+    use std::sync::atomic::{self, AtomicUsize};
+    use std::sync::mpsc::Receiver;
+
+    #[test]
+    fn test_peer_mss() {
+        use upstream::{Fallback, Policy};
+
+        let dst = SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 80);
+        let payload = vec![0x5au8; 60000];
+        let cases = [
+            (Some(0), 536),
+            (Some(64), 536),
+            (Some(536), 536),
+            (Some(65535), 65535),
+            (None, 536),
+        ];
+        for (i, (advertised, effective)) in cases.iter().enumerate() {
+            let (forwarder, rx) = testing::forwarder(1500);
+            let tx = Arc::new(Mutex::new(forwarder));
+            let upstreams = Upstreams::new(
+                vec![SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1080))],
+                Policy::First,
+                Fallback::Fail,
+            )
+            .unwrap();
+            let mut redirector = Redirector::new(
+                Arc::clone(&tx),
+                testing::SRC_IP_ADDR,
+                None,
+                upstreams,
+                30000,
+                64,
+            );
+            assert_eq!(redirector.get_effective_mss(*advertised), *effective);
+            // MSS of 0 and 64 are clamped
+            assert_eq!(redirector.get_tcp_mss_clamped(), (i < 2) as usize);
+
+            let mut tx_locked = tx.lock().unwrap();
+            tx_locked.set_tcp_peer_mss(dst, 50000, *effective);
+            tx_locked.set_tcp_send_window(dst, 50000, 65535);
+            tx_locked.append_to_cache(dst, 50000, &payload).unwrap();
+
+            // Segments are the effective MSS, or the MTU of the source, in the initial congestion
+            // window
+            let segment_size = min(*effective as usize, 1500 - 40);
+            let window = min(payload.len(), congestion::INITIAL_WINDOW * segment_size);
+            let sizes: Vec<usize> = rx
+                .try_iter()
+                .map(|frame| {
+                    let indicator = Indicator::from(&frame).unwrap();
+                    assert!(indicator.validate(&frame, true).is_ok());
+                    frame.len() - indicator.get_size()
+                })
+                .collect();
+            assert_eq!(sizes.iter().sum::<usize>(), window);
+            assert_eq!(sizes.len(), window.div_ceil(segment_size));
+            assert!(sizes[..sizes.len() - 1]
+                .iter()
+                .all(|size| *size == segment_size));
+        }
+    }
+
+    /// Creates a new `Notifier` of the conditions delivering to the returned events.
+    fn collect_notifier(conditions: Vec<notify::Condition>) -> (Notifier, Arc<Mutex<Vec<Event>>>) {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events_cloned = Arc::clone(&events);
+        let mut notifier = Notifier::new(conditions);
+        notifier.set_deliver(Box::new(move |event| {
+            events_cloned.lock().unwrap().push(event.clone());
+            Ok(())
+        }));
+
+        (notifier, events)
+    }
+
+    /// Waits until the given number of events are delivered.
+    fn wait_events(events: &Arc<Mutex<Vec<Event>>>, n: usize) {
+        let instant = Instant::now();
+        while events.lock().unwrap().len() < n {
+            assert!(instant.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_udp_degraded() {
+        use std::net::TcpListener;
+        use upstream::{Fallback, Policy};
+
+        // A proxy which is gone
+        let gone = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (mut redirector, _rx) = testing::create_redirector(gone);
+        let (notifier, events) = collect_notifier(vec![notify::Condition::Udp]);
+        redirector
+            .set_notifier(notifier, Duration::from_secs(0))
+            .unwrap();
+        let dst = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 53);
+
+        // Notifies once while associations fail
+        assert!(redirector.bind_datagram(30000, 50000, dst).is_err());
+        assert!(redirector.bind_datagram(30001, 50001, dst).is_err());
+        wait_events(&events, 1);
+        match events.lock().unwrap()[0] {
+            Event::UdpDegraded(_) => {}
+            ref event => panic!("unexpected event {}", event),
+        }
+
+        // And once an association binds again
+        let proxy = testing::MockProxy::spawn(Ipv4Addr::LOCALHOST.into()).unwrap();
+        redirector.upstreams =
+            Upstreams::new(vec![proxy.get_addr()], Policy::First, Fallback::Fail).unwrap();
+        redirector.bind_datagram(30002, 50002, dst).unwrap();
+        redirector.bind_datagram(30003, 50003, dst).unwrap();
+        wait_events(&events, 2);
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(events.lock().unwrap()[1..], [Event::UdpRecovered]);
+    }
+
+    #[test]
+    fn test_quota() {
+        use monitor::Counters;
+
+        let (mut redirector, _rx) = testing::create_redirector("127.0.0.1:1080".parse().unwrap());
+        let (notifier, events) = collect_notifier(vec![notify::Condition::Quota]);
+        redirector
+            .set_notifier(notifier, Duration::from_secs(0))
+            .unwrap();
+        let monitor = Arc::new(Monitor::new());
+        redirector.set_monitor(Arc::clone(&monitor));
+        redirector.set_quota(Some(1000));
+        let counters = Arc::new(Counters::new());
+        let registration =
+            Monitor::register(&monitor, Protocol::Udp, 50000, None, Arc::clone(&counters));
+
+        counters.add_up(600);
+        redirector.check_quota();
+        // Traffic of dropped workers counts
+        counters.add_down(400);
+        drop(registration);
+        redirector.check_quota();
+        redirector.check_quota();
+        wait_events(&events, 1);
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(*events.lock().unwrap(), vec![Event::QuotaExceeded(1000)]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_path_mtu_reconnect() {
+        let (mut redirector, _rx) = testing::create_redirector("127.0.0.1:1080".parse().unwrap());
+        let remote = redirector.upstreams.get_remotes()[0];
+        redirector
+            .set_path_mtu_probe(Some(Duration::from_secs(3600)))
+            .unwrap();
+        let path_mtu = redirector.get_path_mtu();
+        assert!(path_mtu.is_some());
+
+        // Probed again once the upstream is back
+        redirector.path_mtu = Some(MIN_PATH_MTU - 1);
+        redirector.poll_ups();
+        assert_eq!(redirector.path_mtu, Some(MIN_PATH_MTU - 1));
+        redirector.upstreams.flap(remote);
+        let dst = Ipv4Addr::new(192, 0, 2, 1);
+        redirector
+            .upstreams
+            .report(remote, dst, Duration::from_millis(20));
+        redirector.poll_ups();
+        assert_eq!(redirector.get_path_mtu(), path_mtu);
+    }
+
+    #[test]
+    fn test_min_mss_floor() {
+        use upstream::{Fallback, Policy};
+
+        let (forwarder, _) = testing::forwarder(1500);
+        let upstreams = Upstreams::new(
+            vec![SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1080))],
+            Policy::First,
+            Fallback::Fail,
+        )
+        .unwrap();
+        let mut redirector = Redirector::new(
+            Arc::new(Mutex::new(forwarder)),
+            testing::SRC_IP_ADDR,
+            None,
+            upstreams,
+            30000,
+            64,
+        );
+        // The min MSS is never below the absolute floor
+        redirector.set_tcp_min_mss(0);
+        assert_eq!(redirector.get_effective_mss(Some(64)), TCP_MIN_MSS_FLOOR);
+        assert_eq!(redirector.get_effective_mss(Some(0)), TCP_DEFAULT_PEER_MSS);
+        assert_eq!(redirector.get_effective_mss(Some(88)), 88);
+        assert_eq!(redirector.get_tcp_mss_clamped(), 2);
+    }
+
+    #[test]
+    fn test_udp_server_clients_first() {
+        use pnet::packet::ethernet::EthernetPacket;
+        use pnet::packet::ipv4::Ipv4Packet;
+        use pnet::packet::udp::UdpPacket;
+        use pnet::packet::Packet;
+        use rule::Rule;
+        use std::collections::HashSet;
+        use std::net::UdpSocket;
+        use std::thread;
+        use upstream::{Fallback, Policy};
+
+        const CLIENTS: usize = 5;
+
+        let proxy = testing::MockProxy::spawn(Ipv4Addr::LOCALHOST.into()).unwrap();
+        let (forwarder, rx) = testing::forwarder(1500);
+        let upstreams =
+            Upstreams::new(vec![proxy.get_addr()], Policy::First, Fallback::Fail).unwrap();
+        let mut redirector = Redirector::new(
+            Arc::new(Mutex::new(forwarder)),
+            testing::SRC_IP_ADDR,
+            None,
+            upstreams,
+            30000,
+            64,
+        );
+        redirector.set_rules(Rules::new(vec![Rule::parse(
+            "game-server ports=27015 server",
+        )
+        .unwrap()]));
+
+        // The association is bound before the source sends anything
+        redirector.check_udp_servers();
+        let instant = Instant::now();
+        let relay = loop {
+            if let Some(relay) = proxy.get_relays().first() {
+                break *relay;
+            }
+            assert!(instant.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(1));
+        };
+
+        // Clients anywhere send first, and reach the server
+        let clients: Vec<UdpSocket> = (0..CLIENTS)
+            .map(|_| UdpSocket::bind("127.0.0.1:0").unwrap())
+            .collect();
+        for (i, client) in clients.iter().enumerate() {
+            client
+                .send_to(format!("join {}", i).as_bytes(), relay)
+                .unwrap();
+        }
+        let mut remotes = HashSet::new();
+        while remotes.len() < CLIENTS {
+            let frame = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+            let ethernet = EthernetPacket::new(&frame).unwrap();
+            let ipv4 = Ipv4Packet::new(ethernet.payload()).unwrap();
+            let udp = UdpPacket::new(ipv4.payload()).unwrap();
+            assert_eq!(ipv4.get_destination(), testing::SRC_IP_ADDR);
+            assert_eq!(udp.get_destination(), 27015);
+            assert!(udp.payload().starts_with(b"join "));
+            remotes.insert(SocketAddr::new(ipv4.get_source().into(), udp.get_source()));
+        }
+        let addrs: HashSet<SocketAddr> = clients
+            .iter()
+            .map(|client| client.local_addr().unwrap())
+            .collect();
+        assert_eq!(remotes, addrs);
+
+        // Each client is tracked by the association of the server
+        let port = redirector.get_local_udp_port(27015);
+        let index = (port - redirector.udp_initial_port) as usize;
+        let worker = redirector.datagrams[index].as_ref().unwrap();
+        assert_eq!(worker.get_clients().len(), CLIENTS);
+        assert_eq!(redirector.udp_servers[&27015].binds, 1);
+    }
+
+    #[test]
+    fn test_normalize_remote_port() {
+        use pnet::packet::ethernet::EthernetPacket;
+        use pnet::packet::ipv4::Ipv4Packet;
+        use pnet::packet::udp::UdpPacket;
+        use pnet::packet::Packet;
+        use std::net::UdpSocket;
+        use upstream::{Fallback, Policy};
+
+        // Remotes echoing datagrams, of which the relay shifts the port by 1
+        let echo = |ip_addr: Ipv4Addr| {
+            let socket = UdpSocket::bind(SocketAddrV4::new(ip_addr, 0)).unwrap();
+            let addr = socket.local_addr().unwrap();
+            std::thread::spawn(move || {
+                let mut buffer = [0u8; 1500];
+                while let Ok((size, addr)) = socket.recv_from(&mut buffer) {
+                    let _ = socket.send_to(&buffer[..size], addr);
+                }
+            });
+            addr
+        };
+        let server = echo(Ipv4Addr::LOCALHOST);
+        let other_server = echo(Ipv4Addr::LOCALHOST);
+        let proxy = testing::MockProxy::spawn_shifted(Ipv4Addr::LOCALHOST.into(), 1).unwrap();
+        let (forwarder, rx) = testing::forwarder(1500);
+        let upstreams =
+            Upstreams::new(vec![proxy.get_addr()], Policy::First, Fallback::Fail).unwrap();
+        let mut redirector = Redirector::new(
+            Arc::new(Mutex::new(forwarder)),
+            testing::SRC_IP_ADDR,
+            None,
+            upstreams,
+            30000,
+            64,
+        );
+
+        // Sends to the remote from the source
+        let send = |redirector: &mut Redirector, remote: SocketAddr| {
+            let remote = match remote {
+                SocketAddr::V4(remote) => remote,
+                _ => unreachable!(),
+            };
+            let mut builder =
+                Builder::new(testing::SRC_HARDWARE_ADDR, testing::LOCAL_HARDWARE_ADDR);
+            builder.set_ipv4(1, testing::SRC_IP_ADDR, *remote.ip());
+            builder.set_udp(50000, remote.port());
+            let frame = builder.build(b"ping").unwrap();
+            let indicator = Indicator::from(&frame).unwrap();
+            redirector.handle_ipv4(&indicator, &frame).unwrap();
+        };
+        // Receives a datagram to the source, and returns its remote
+        let receive = || {
+            let frame = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+            let ethernet = EthernetPacket::new(&frame).unwrap();
+            let ipv4 = Ipv4Packet::new(ethernet.payload()).unwrap();
+            let udp = UdpPacket::new(ipv4.payload()).unwrap();
+            assert_eq!(udp.get_destination(), 50000);
+            assert_eq!(&udp.payload()[..4], b"ping");
+
+            SocketAddr::new(ipv4.get_source().into(), udp.get_source())
+        };
+
+        // The reply of the remote sent to is from the port it is sent to
+        send(&mut redirector, server);
+        assert_eq!(receive(), server);
+        send(&mut redirector, server);
+        assert_eq!(receive(), server);
+        let local_port = redirector.datagram_map[50000];
+        let index = local_port.wrapping_sub(redirector.udp_initial_port) as usize;
+        let normalized = |redirector: &Redirector| {
+            redirector.datagrams[index]
+                .as_ref()
+                .unwrap()
+                .get_normalized()
+        };
+        assert_eq!(normalized(&redirector), 2);
+
+        // A peer never sent to is left untouched
+        let instant = Instant::now();
+        let relay = loop {
+            if let Some(relay) = proxy.get_relays().first() {
+                break *relay;
+            }
+            assert!(instant.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(1));
+        };
+        let peer = UdpSocket::bind("127.0.0.2:0").unwrap();
+        peer.send_to(b"ping", relay).unwrap();
+        let peer = peer.local_addr().unwrap();
+        assert_eq!(receive(), SocketAddr::new(peer.ip(), peer.port() + 1));
+
+        // So is an IP address sent to on several ports
+        send(&mut redirector, other_server);
+        let other_server = SocketAddr::new(other_server.ip(), other_server.port() + 1);
+        assert_eq!(receive(), other_server);
+        assert_eq!(normalized(&redirector), 2);
+    }
+
+    #[test]
+    fn test_udp_idle_timer() {
+        use std::thread;
+        use upstream::{Fallback, Policy};
+
+        let (forwarder, _) = testing::forwarder(1500);
+        let upstreams = Upstreams::new(
+            vec![SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1080))],
+            Policy::First,
+            Fallback::Fail,
+        )
+        .unwrap();
+        let mut redirector = Redirector::new(
+            Arc::new(Mutex::new(forwarder)),
+            testing::SRC_IP_ADDR,
+            None,
+            upstreams,
+            30000,
+            64,
+        );
+        let timeout = Duration::from_millis(200);
+        let mut worker =
+            DatagramWorker::bind_direct(redirector.get_udp_tx(), &redirector.threads, 50000, 0)
+                .unwrap();
+        worker.set_idle_timeout(Some(timeout));
+        redirector.datagrams[0] = Some(worker);
+        redirector.datagram_map[50000] = 30000;
+        let handle = redirector.timers.schedule(timeout, Timer::UdpIdle(0));
+        redirector.udp_idle_map.insert(0, handle);
+
+        // Activity halfway delays the expiry by the same
+        thread::sleep(timeout / 2);
+        let discard = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9));
+        let instant = Instant::now();
+        redirector.datagrams[0]
+            .as_mut()
+            .unwrap()
+            .send_to(b"active", discard)
+            .unwrap();
+        let mut fires = 0;
+        while redirector.datagrams[0].is_some() {
+            assert!(instant.elapsed() < Duration::from_secs(5));
+            for timer in redirector.timers.advance() {
+                fires += 1;
+                redirector.fire(timer);
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(instant.elapsed() >= timeout);
+        assert_eq!(fires, 2);
+        assert_eq!(redirector.datagram_map[50000], 0);
+        assert!(redirector.udp_idle_map.is_empty());
+        assert_eq!(redirector.get_idle_expired(), (1, 0));
+    }
+
+    #[test]
+    fn test_tcp_idle_timer() {
+        use std::net::TcpListener;
+        use std::thread;
+        use upstream::{Fallback, Policy};
+
+        let (forwarder, _) = testing::forwarder(1500);
+        let upstreams = Upstreams::new(
+            vec![SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1080))],
+            Policy::First,
+            Fallback::Fail,
+        )
+        .unwrap();
+        let mut redirector = Redirector::new(
+            Arc::new(Mutex::new(forwarder)),
+            testing::SRC_IP_ADDR,
+            None,
+            upstreams,
+            30000,
+            64,
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dst = match listener.local_addr().unwrap() {
+            SocketAddr::V4(dst) => dst,
+            _ => unreachable!(),
+        };
+        let timeout = Duration::from_millis(100);
+        let key = (50000, dst);
+        let mut stream = StreamWorker::connect_direct(
+            redirector.get_tx(),
+            &redirector.threads,
+            key.0,
+            SocketAddr::V4(dst),
+        )
+        .unwrap();
+        stream.set_idle_timeout(Some(timeout));
+        let handle = redirector.timers.schedule(timeout, Timer::Idle(key));
+        let flow = redirector.get_tcp_flow(key);
+        flow.stream = Some(stream);
+        flow.idle = Some(handle);
+
+        // A stream connecting is never idle
+        let instant = Instant::now();
+        while redirector.get_stream(&key).is_some() {
+            assert!(instant.elapsed() < Duration::from_secs(5));
+            if let Some(stream) = redirector.get_stream_mut(&key) {
+                let _ = stream.poll_ready();
+            }
+            for timer in redirector.timers.advance() {
+                redirector.fire(timer);
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(instant.elapsed() >= timeout);
+        assert!(redirector.tcp_flows.is_empty());
+        assert_eq!(redirector.get_idle_expired(), (0, 1));
+    }
+
+    #[test]
+    fn test_ipv6_proxy() {
+        use pnet::packet::ethernet::EthernetPacket;
+        use pnet::packet::ipv4::Ipv4Packet;
+        use pnet::packet::udp::UdpPacket;
+        use pnet::packet::Packet;
+        use std::net::{Ipv6Addr, TcpListener, UdpSocket};
+
+        let proxy = testing::MockProxy::spawn(Ipv6Addr::LOCALHOST.into()).unwrap();
+        assert!(proxy.get_addr().is_ipv6());
+        let (mut redirector, rx) = testing::create_redirector(proxy.get_addr());
+
+        // TCP, to an IPv4 destination through the CONNECT
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dst = match listener.local_addr().unwrap() {
+            SocketAddr::V4(dst) => dst,
+            _ => unreachable!(),
+        };
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut cloned = stream.try_clone().unwrap();
+            let _ = io::copy(&mut stream, &mut cloned);
+        });
+        let key = (50000, dst);
+        testing::handle_segment_to(&mut redirector, dst, key.0, 1000, 0, TcpFlags::SYN, &[]);
+        testing::wait_connected_to(&mut redirector, key);
+        let syn = testing::receive_segment(&rx);
+        assert_eq!(syn.flags, TcpFlags::SYN | TcpFlags::ACK);
+        let sequence = syn.sequence.wrapping_add(1);
+        testing::handle_segment_to(
+            &mut redirector,
+            dst,
+            key.0,
+            1001,
+            sequence,
+            TcpFlags::ACK,
+            &[],
+        );
+        let flags = TcpFlags::ACK | TcpFlags::PSH;
+        testing::handle_segment_to(
+            &mut redirector,
+            dst,
+            key.0,
+            1001,
+            sequence,
+            flags,
+            b"over v6",
+        );
+        assert_eq!(testing::receive_payload(&rx, 7), b"over v6".to_vec());
+
+        // UDP, through the relay of the association on the IPv6 address
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let remote = match socket.local_addr().unwrap() {
+            SocketAddr::V4(remote) => remote,
+            _ => unreachable!(),
+        };
+        std::thread::spawn(move || {
+            let mut buffer = [0u8; 1500];
+            let (size, addr) = socket.recv_from(&mut buffer).unwrap();
+            socket.send_to(&buffer[..size], addr).unwrap();
+        });
+        while rx.try_recv().is_ok() {}
+        let mut builder = Builder::new(testing::SRC_HARDWARE_ADDR, testing::LOCAL_HARDWARE_ADDR);
+        builder.set_ipv4(1, testing::SRC_IP_ADDR, *remote.ip());
+        builder.set_udp(50001, remote.port());
+        let frame = builder.build(b"datagram over v6").unwrap();
+        let indicator = Indicator::from(&frame).unwrap();
+        redirector.handle_ipv4(&indicator, &frame).unwrap();
+
+        let frame = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        let ethernet = EthernetPacket::new(&frame).unwrap();
+        let ipv4 = Ipv4Packet::new(ethernet.payload()).unwrap();
+        let udp = UdpPacket::new(ipv4.payload()).unwrap();
+        assert_eq!(ipv4.get_source(), *remote.ip());
+        assert_eq!(
+            (udp.get_source(), udp.get_destination()),
+            (remote.port(), 50001)
+        );
+        assert_eq!(&udp.payload()[..16], b"datagram over v6");
+        assert!(proxy.get_relays().iter().all(|relay| relay.is_ipv6()));
+        assert_eq!(proxy.get_relays().len(), 1);
+    }
+
+    /// Kills a TCP connection through a mock proxy midway of an upload, and returns what the
+    /// server reads after the kill.
+    fn kill_upload(is_abort: bool) -> io::Result<Vec<u8>> {
+        let proxy = testing::MockProxy::spawn(Ipv4Addr::LOCALHOST.into()).unwrap();
+        let (mut redirector, rx) = testing::create_redirector(proxy.get_addr());
+        let (dst, server) = testing::spawn_read_server(6);
+        let key = (50000, dst);
+        let sequence = testing::establish_to(&mut redirector, &rx, dst, key.0);
+        let flags = TcpFlags::ACK | TcpFlags::PSH;
+        testing::handle_segment_to(
+            &mut redirector,
+            dst,
+            key.0,
+            1001,
+            sequence,
+            flags,
+            b"upload",
+        );
+        let timeout = Duration::from_secs(5);
+        assert_eq!(server.recv_timeout(timeout).unwrap().unwrap(), b"upload");
+        while rx.try_recv().is_ok() {}
+
+        assert!(redirector.kill_tcp(key.0, dst, is_abort));
+        // The source is reset regardless of the mode
+        let segment = testing::receive_segment(&rx);
+        assert_ne!(segment.flags & TcpFlags::RST, 0);
+        assert!(redirector.get_stream(&key).is_none());
+        assert!(!redirector.kill_tcp(key.0, dst, is_abort));
+
+        server.recv_timeout(timeout).unwrap()
+    }
+
+    #[test]
+    fn test_kill_tcp() {
+        // The server sees an orderly close
+        assert_eq!(kill_upload(false).unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn test_kill_tcp_abort() {
+        // The server sees a reset
+        let e = kill_upload(true).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn test_kill_udp() {
+        use std::net::UdpSocket;
+
+        let proxy = testing::MockProxy::spawn(Ipv4Addr::LOCALHOST.into()).unwrap();
+        let (mut redirector, rx) = testing::create_redirector(proxy.get_addr());
+        redirector.set_udp_quarantine(Duration::from_millis(300));
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let remote = match socket.local_addr().unwrap() {
+            SocketAddr::V4(remote) => remote,
+            _ => unreachable!(),
+        };
+        std::thread::spawn(move || {
+            let mut buffer = [0u8; 1500];
+            while let Ok((size, addr)) = socket.recv_from(&mut buffer) {
+                let _ = socket.send_to(&buffer[..size], addr);
+            }
+        });
+        let send = |redirector: &mut Redirector| {
+            let mut builder =
+                Builder::new(testing::SRC_HARDWARE_ADDR, testing::LOCAL_HARDWARE_ADDR);
+            builder.set_ipv4(1, testing::SRC_IP_ADDR, *remote.ip());
+            builder.set_udp(50001, remote.port());
+            let frame = builder.build(b"game").unwrap();
+            let indicator = Indicator::from(&frame).unwrap();
+            redirector.handle_ipv4(&indicator, &frame).unwrap();
+        };
+        send(&mut redirector);
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        let local_port = redirector.datagram_map[50001];
+        assert_ne!(local_port, 0);
+        redirector.add_upnp_lease(Lease::new(Protocol::Udp, 3074, 50001, "game", None));
+        redirector.add_upnp_lease(Lease::new(Protocol::Udp, 3075, 50002, "other", None));
+
+        // The local port and the leases of the source port are released
+        assert!(redirector.kill_udp(50001));
+        assert_eq!(redirector.datagram_map[50001], 0);
+        let index = local_port.wrapping_sub(redirector.udp_initial_port) as usize;
+        assert!(redirector.datagrams[index].is_none());
+        assert!(redirector
+            .get_upnp_leases()
+            .get(Protocol::Udp, 3074)
+            .is_none());
+        assert!(redirector
+            .get_upnp_leases()
+            .get(Protocol::Udp, 3075)
+            .is_some());
+        assert!(!redirector.kill_udp(50001));
+
+        // The next datagram is not bound again in the quarantine
+        send(&mut redirector);
+        assert_eq!(redirector.datagram_map[50001], 0);
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        // But is once the quarantine ends
+        std::thread::sleep(Duration::from_millis(300));
+        send(&mut redirector);
+        assert_ne!(redirector.datagram_map[50001], 0);
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn test_tcp_prewarm() {
+        let proxy = testing::MockProxy::spawn(Ipv4Addr::LOCALHOST.into()).unwrap();
+        let (mut redirector, rx) = testing::create_redirector(proxy.get_addr());
+        redirector.set_tcp_prewarm(1).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dst = match listener.local_addr().unwrap() {
+            SocketAddr::V4(dst) => dst,
+            _ => unreachable!(),
+        };
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut cloned = stream.try_clone().unwrap();
+                std::thread::spawn(move || io::copy(&mut stream, &mut cloned));
+            }
+        });
+        let sequence = testing::establish_to(&mut redirector, &rx, dst, 50000);
+        let flags = TcpFlags::ACK | TcpFlags::PSH;
+        testing::handle_segment_to(&mut redirector, dst, 50000, 1001, sequence, flags, b"first");
+        assert_eq!(testing::receive_payload(&rx, 5), b"first".to_vec());
+
+        // The pool fills the proxy of the first connection, whose next one is pre-warmed
+        let pool = redirector.tcp_pool.clone().unwrap();
+        let instant = Instant::now();
+        while pool.is_empty() {
+            assert!(instant.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let sequence = testing::establish_to(&mut redirector, &rx, dst, 50001);
+        testing::handle_segment_to(
+            &mut redirector,
+            dst,
+            50001,
+            1001,
+            sequence,
+            flags,
+            b"second",
+        );
+        assert_eq!(testing::receive_payload(&rx, 6), b"second".to_vec());
+        assert_eq!(pool.get_taken(), 1);
+    }
+
+    #[test]
+    fn test_kill_monitor() {
+        let proxy = testing::MockProxy::spawn(Ipv4Addr::LOCALHOST.into()).unwrap();
+        let (mut redirector, rx) = testing::create_redirector(proxy.get_addr());
+        let monitor = Arc::new(Monitor::new());
+        // Kills requested before the monitor is set are never applied
+        monitor.request_kill(Kill::Tcp(50000, testing::tcp_dst(), false));
+        redirector.set_monitor(Arc::clone(&monitor));
+        let (dst, server) = testing::spawn_read_server(0);
+        let key = (50000, dst);
+        testing::establish_to(&mut redirector, &rx, dst, key.0);
+        server
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap()
+            .unwrap();
+        while rx.try_recv().is_ok() {}
+
+        // A kill of a flow of another source is ignored
+        monitor.request_kill(Kill::Tcp(50001, dst, false));
+        redirector.poll_kills();
+        assert!(redirector.get_stream(&key).is_some());
+
+        monitor.request_kill(Kill::Tcp(key.0, dst, false));
+        redirector.poll_kills();
+        assert!(redirector.get_stream(&key).is_none());
+        assert_ne!(testing::receive_segment(&rx).flags & TcpFlags::RST, 0);
+        // Each kill is applied once
+        redirector.poll_kills();
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_malformed_no_state() {
+        let (mut redirector, rx) = testing::create_redirector(SocketAddr::V4(testing::tcp_dst()));
+        redirector.set_verify_checksum(true);
+
+        let set = |mut frame: Vec<u8>, offset: usize, bytes: &[u8]| {
+            frame[offset..offset + bytes.len()].copy_from_slice(bytes);
+            frame
+        };
+        let udp = testing::udp_frame(50000, 53, b"query");
+        let tcp = testing::tcp_frame(50000, 80);
+        let transport = testing::TRANSPORT_OFFSET;
+        let frames = vec![
+            testing::udp_frame(0, 53, b"query"),
+            testing::udp_frame(50000, 0, b"query"),
+            set(udp.clone(), transport + 4, &[0, 7]),
+            set(udp.clone(), transport + 4, &[0, 8 + 6]),
+            set(udp.clone(), transport + 8, b"Q"),
+            testing::tcp_frame(0, 80),
+            testing::tcp_frame(50000, 0),
+            set(tcp.clone(), transport + 12, &[4 << 4]),
+            set(tcp, transport + 12, &[6 << 4]),
+            set(udp, 16, &[0, 200]),
+        ];
+        for frame in frames {
+            let indicator = Indicator::from(&frame).unwrap();
+            redirector.handle_ipv4(&indicator, &frame).unwrap();
+        }
+
+        // Nothing is bound, connected or answered
+        assert_eq!(redirector.get_stream_count(), 0);
+        assert!(redirector
+            .datagrams
+            .iter()
+            .all(|datagram| datagram.is_none()));
+        assert!(redirector.tx.lock().unwrap().get_tcp_keys().is_empty());
+        assert!(rx.try_recv().is_err());
+        let mut malformed = redirector.get_malformed();
+        malformed.sort();
+        assert_eq!(
+            malformed,
+            vec![
+                (Malformed::Ipv4Length, 1),
+                (Malformed::PortZero, 4),
+                (Malformed::UdpLength, 2),
+                (Malformed::UdpChecksum, 1),
+                (Malformed::TcpDataOffset, 2),
+            ]
+        );
+    }
+
+    /// Establishes a flow from the source port, and transfers the size through the echoing proxy,
+    /// returning the size echoed.
+    fn transfer(
+        redirector: &mut Redirector,
+        rx: &Receiver<Vec<u8>>,
+        src_port: u16,
+        size: usize,
+    ) -> usize {
+        let mut ack = testing::establish(redirector, rx, src_port);
+        let chunk = vec![b'n'; 1400];
+        let mut sequence = 1001u32;
+        let mut sent = 0;
+        let mut echo = 0;
+        while sent < size {
+            let flags = TcpFlags::ACK | TcpFlags::PSH;
+            testing::handle_segment(redirector, src_port, sequence, ack, flags, &chunk);
+            sequence = sequence.wrapping_add(chunk.len() as u32);
+            sent += chunk.len();
+            // The echo is acknowledged as it comes
+            while echo < sent {
+                let segment = testing::receive_segment(rx);
+                if segment.payload.is_empty() || segment.sequence != ack {
+                    continue;
+                }
+                echo += segment.payload.len();
+                ack = ack.wrapping_add(segment.payload.len() as u32);
+                testing::handle_segment(redirector, src_port, sequence, ack, TcpFlags::ACK, &[]);
+            }
+        }
+
+        echo
+    }
+
+    #[test]
+    fn test_scanning_host() {
+        use std::net::TcpListener;
+        use upstream::{Fallback, Policy};
+
+        const SCANNER_IP_ADDR: Ipv4Addr = Ipv4Addr::new(10, 6, 0, 3);
+        const SCANS: usize = 2000;
+        const TRANSFER: usize = 64 * 1024;
+        // A handshake thread and a place in the poller of each flow, and the poller itself
+        const SCANNER_PLACES: usize = 16 * 2 + 1;
+
+        // The normal host through an echoing proxy
+        let proxy = testing::EchoProxy::spawn(false);
+        let (mut redirector, rx) = testing::create_redirector(proxy.addr);
+
+        // The scanning host through a proxy never answering, sharing the threads
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let blackhole = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let streams: Vec<_> = listener.incoming().collect();
+            drop(streams);
+        });
+        let (forwarder, _scanner_rx) = testing::forwarder_of(1500, SCANNER_IP_ADDR);
+        let upstreams = Upstreams::new(vec![blackhole], Policy::First, Fallback::Fail).unwrap();
+        let mut scanner = Redirector::new(
+            Arc::new(Mutex::new(forwarder)),
+            SCANNER_IP_ADDR,
+            None,
+            upstreams,
+            30000,
+            64,
+        );
+        scanner.share_threads(&redirector);
+        scanner.set_host_limits(16, 20);
+        scanner.set_host_ingress_rate(500);
+        let threads = redirector.threads.len();
+
+        // The scan of every port of a network
+        for i in 0..SCANS {
+            let dst = Ipv4Addr::new(192, 0, 2, (i / 250) as u8 + 1);
+            let mut builder =
+                Builder::new(testing::SRC_HARDWARE_ADDR, testing::LOCAL_HARDWARE_ADDR);
+            builder.set_ipv4(1, SCANNER_IP_ADDR, dst);
+            builder.set_tcp(40000, (i % 250) as u16 + 1, 1000, 0, 65535, TcpFlags::SYN);
+            let frame = builder.build(&[]).unwrap();
+            let indicator = Indicator::from(&frame).unwrap();
+            scanner.handle_ipv4(&indicator, &frame).unwrap();
+        }
+        let drops = scanner.get_envelope().get_drops();
+        assert_eq!(drops.ingress, SCANS - 500);
+        // The packets within the budget are still refused flows beyond the limits
+        assert!(drops.flows + drops.rate >= 500 - 16);
+        assert!(scanner.get_stream_count() <= 16);
+        // The scanner takes no more places of the shared threads than its flows allow
+        assert!(redirector.threads.len() - threads <= SCANNER_PLACES);
+
+        // The normal host is admitted at once, and transfers everything
+        let echo = transfer(&mut redirector, &rx, 50000, TRANSFER);
+        assert!(echo >= TRANSFER);
+        assert_eq!(redirector.get_stream_count(), 1);
+        let drops = redirector.get_envelope().get_drops();
+        assert_eq!((drops.flows, drops.rate, drops.ingress), (0, 0, 0));
+    }
+
+    #[test]
+    fn test_arp_storm() {
+        use pnet::util::MacAddr;
+        use upstream::{Fallback, Policy};
+
+        const STORM: usize = 50;
+        const OTHER_IP_ADDR: Ipv4Addr = Ipv4Addr::new(10, 6, 0, 3);
+
+        let (forwarder, rx) = testing::forwarder(1500);
+        let upstreams = Upstreams::new(
+            vec![SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1080))],
+            Policy::First,
+            Fallback::Fail,
+        )
+        .unwrap();
+        let mut redirector = Redirector::new(
+            Arc::new(Mutex::new(forwarder)),
+            testing::SRC_IP_ADDR,
+            Some(testing::LOCAL_IP_ADDR),
+            upstreams,
+            30000,
+            64,
+        );
+        // An ARP request of the gateway, from the reply with the operation of a request
+        let request = |src_ip_addr: Ipv4Addr| {
+            let mut builder = Builder::new(testing::SRC_HARDWARE_ADDR, MacAddr::broadcast());
+            builder.set_arp_reply(src_ip_addr, MacAddr::zero(), testing::LOCAL_IP_ADDR);
+            let mut frame = builder.build(&[]).unwrap();
+            frame[20..22].copy_from_slice(&[0, 1]);
+
+            frame
+        };
+        let replies = || rx.try_iter().count();
+
+        // The storm of a confused console, with the requests of another host between. The first
+        // reply is sent at once
+        let storm = request(testing::SRC_IP_ADDR);
+        let other = request(OTHER_IP_ADDR);
+        redirector
+            .handle_arp(&Indicator::from(&storm).unwrap())
+            .unwrap();
+        assert_eq!(replies(), 1);
+        for i in 1..STORM {
+            redirector
+                .handle_arp(&Indicator::from(&storm).unwrap())
+                .unwrap();
+            if i % 10 == 0 {
+                redirector
+                    .handle_arp(&Indicator::from(&other).unwrap())
+                    .unwrap();
+            }
+        }
+        assert_eq!(replies(), DEFAULT_ARP_RATE - 1);
+        assert_eq!(redirector.get_arp_suppressed(), STORM - DEFAULT_ARP_RATE);
+
+        // The next second answers again at the normal pace
+        let entry = redirector
+            .arp_rate_map
+            .get_mut(&testing::SRC_HARDWARE_ADDR)
+            .unwrap();
+        entry.0 = Instant::now().checked_sub(Duration::from_secs(1)).unwrap();
+        for _ in 0..DEFAULT_ARP_RATE {
+            redirector
+                .handle_arp(&Indicator::from(&storm).unwrap())
+                .unwrap();
+            assert_eq!(replies(), 1);
+        }
+        assert_eq!(redirector.get_arp_suppressed(), STORM - DEFAULT_ARP_RATE);
+    }
+
+    /// Creates a new `Redirector` publishing the gateway, whose proxy is a listener never
+    /// accepting, and the listener.
+    fn create_gateway() -> (Redirector, Receiver<Vec<u8>>, TcpListener) {
+        use upstream::{Fallback, Policy};
+
+        let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+        proxy.set_nonblocking(true).unwrap();
+        let (forwarder, rx) = testing::forwarder(1500);
+        let upstreams = Upstreams::new(
+            vec![proxy.local_addr().unwrap()],
+            Policy::First,
+            Fallback::Fail,
+        )
+        .unwrap();
+        let redirector = Redirector::new(
+            Arc::new(Mutex::new(forwarder)),
+            testing::SRC_IP_ADDR,
+            Some(testing::LOCAL_IP_ADDR),
+            upstreams,
+            30000,
+            64,
+        );
+
+        (redirector, rx, proxy)
+    }
+
+    /// Sends the request to the port of the gateway from the source port, and returns the
+    /// response, which ends with a FIN. The connection is closed by the source afterwards.
+    fn request_gateway(
+        redirector: &mut Redirector,
+        rx: &Receiver<Vec<u8>>,
+        port: u16,
+        src_port: u16,
+        request: &[u8],
+    ) -> String {
+        let dst = SocketAddrV4::new(testing::LOCAL_IP_ADDR, port);
+        let sequence = testing::establish_to(redirector, rx, dst, src_port);
+        let flags = TcpFlags::ACK | TcpFlags::PSH;
+        testing::handle_segment_to(redirector, dst, src_port, 1001, sequence, flags, request);
+
+        let mut response = Vec::new();
+        let acknowledgement = loop {
+            let segment = testing::receive_segment(rx);
+            assert_eq!(segment.flags & TcpFlags::RST, 0);
+            response.extend_from_slice(&segment.payload);
+            if segment.flags & TcpFlags::FIN != 0 {
+                break segment
+                    .sequence
+                    .wrapping_add(segment.payload.len() as u32 + 1);
+            }
+        };
+
+        // Close, until the FIN of the source is acknowledged
+        let sequence = 1001 + request.len() as u32;
+        let flags = TcpFlags::ACK | TcpFlags::FIN;
+        testing::handle_segment_to(
+            redirector,
+            dst,
+            src_port,
+            sequence,
+            acknowledgement,
+            flags,
+            &[],
+        );
+        while testing::receive_segment(rx).acknowledgement != sequence + 1 {}
+
+        String::from_utf8(response).unwrap()
+    }
+
+    #[test]
+    fn test_chaos_flap() {
+        let proxy: SocketAddr = "127.0.0.1:1080".parse().unwrap();
+        let (mut redirector, _rx) = testing::create_redirector(proxy);
+
+        // The upstream is flapped once in a few kills
+        for _ in 1..CHAOS_FLAP_KILLS {
+            redirector.chaos();
+        }
+        assert!(redirector.upstreams.take_downs().is_empty());
+        redirector.chaos();
+        assert_eq!(redirector.upstreams.take_downs(), vec![proxy]);
+        assert!(redirector.upstreams.is_fallback());
+    }
+
+    #[test]
+    fn test_publish_talkers() {
+        let (mut redirector, _rx) = testing::create_redirector("127.0.0.1:1".parse().unwrap());
+        let monitor = Arc::new(Monitor::new());
+        redirector.set_monitor(Arc::clone(&monitor));
+        redirector.set_udp_destination_limits(6, 4, &[]);
+
+        // A source port spraying datagrams beyond its limit, and another one
+        for i in 0..8 {
+            let dst = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, i + 1), 9000);
+            redirector.fanout.admit(50000, dst);
+        }
+        assert!(redirector
+            .fanout
+            .admit(50001, SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 9000)));
+        assert!(monitor.get_talkers(10).is_empty());
+
+        redirector.publish_talkers();
+        assert_eq!(
+            monitor.get_talkers(10),
+            vec![
+                (SocketAddrV4::new(testing::SRC_IP_ADDR, 50000), 4),
+                (SocketAddrV4::new(testing::SRC_IP_ADDR, 50001), 1)
+            ]
+        );
+    }
+
+    /// Handles a UDP datagram of the source port to the port of the gateway.
+    fn handle_gateway_datagram(redirector: &mut Redirector, src_port: u16, port: u16) {
+        let mut builder = Builder::new(testing::SRC_HARDWARE_ADDR, testing::LOCAL_HARDWARE_ADDR);
+        builder.set_ipv4(1, testing::SRC_IP_ADDR, testing::LOCAL_IP_ADDR);
+        builder.set_udp(src_port, port);
+        let frame = builder.build(b"query").unwrap();
+        let indicator = Indicator::from(&frame).unwrap();
+        redirector.handle_ipv4(&indicator, &frame).unwrap();
+    }
+
+    #[test]
+    fn test_gateway_status() {
+        let (mut redirector, rx, proxy) = create_gateway();
+        let monitor = Arc::new(Monitor::new());
+        redirector.set_monitor(Arc::clone(&monitor));
+        let status = gateway::Status::new(monitor);
+        redirector
+            .set_gateway_service(8080, Arc::new(status))
+            .unwrap();
+
+        let response =
+            request_gateway(&mut redirector, &rx, 8080, 50000, b"GET / HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        // The connection to the status is the live stream
+        assert!(response.contains("\"streams\":1"));
+        let response = request_gateway(
+            &mut redirector,
+            &rx,
+            8080,
+            50001,
+            b"GET /missing HTTP/1.1\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        // The proxy is never connected
+        assert_eq!(
+            proxy.accept().unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+    }
+
+    #[test]
+    fn test_gateway_upnp() {
+        let (mut redirector, rx, proxy) = create_gateway();
+        redirector.set_upnp(upnp::control::DEFAULT_PORT).unwrap();
+
+        let body = "<?xml version=\"1.0\"?><s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\"><s:Body><u:AddPortMapping xmlns:u=\"urn:schemas-upnp-org:service:WANIPConnection:1\"><NewRemoteHost></NewRemoteHost><NewExternalPort>3074</NewExternalPort><NewProtocol>UDP</NewProtocol><NewInternalPort>3074</NewInternalPort><NewInternalClient>10.6.0.2</NewInternalClient><NewEnabled>1</NewEnabled><NewPortMappingDescription>game</NewPortMappingDescription><NewLeaseDuration>3600</NewLeaseDuration></u:AddPortMapping></s:Body></s:Envelope>";
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: 10.6.0.1:5000\r\nContent-Type: text/xml\r\nSOAPAction: \"urn:schemas-upnp-org:service:WANIPConnection:1#AddPortMapping\"\r\nContent-Length: {}\r\n\r\n{}",
+            upnp::control::CONTROL_PATH,
+            body.len(),
+            body
+        );
+        let response = request_gateway(
+            &mut redirector,
+            &rx,
+            upnp::control::DEFAULT_PORT,
+            50000,
+            request.as_bytes(),
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("AddPortMappingResponse"));
+
+        // The mapping is a lease of the source
+        {
+            let leases = redirector.get_upnp_leases();
+            let lease = leases.get(Protocol::Udp, 3074).unwrap();
+            assert_eq!(lease.internal_port, 3074);
+            assert!(lease.expires.is_some());
+        }
+        assert_eq!(
+            proxy.accept().unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+    }
+
+    #[test]
+    fn test_gateway_ssdp() {
+        use discovery::DiscoveryPolicy;
+
+        let (mut redirector, rx, proxy) = create_gateway();
+        redirector.set_upnp(upnp::control::DEFAULT_PORT).unwrap();
+        redirector.set_udp_discovery(DiscoveryPolicy::Answer, 64);
+
+        let search = format!(
+            "M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: {}\r\n\r\n",
+            upnp::control::DEVICE_TYPE
+        );
+        let mut builder = Builder::new(testing::SRC_HARDWARE_ADDR, testing::LOCAL_HARDWARE_ADDR);
+        builder.set_ipv4(1, testing::SRC_IP_ADDR, Ipv4Addr::new(239, 255, 255, 250));
+        builder.set_udp(50000, SSDP_PORT);
+        let frame = builder.build(search.as_bytes()).unwrap();
+        let indicator = Indicator::from(&frame).unwrap();
+        redirector.handle_udp(&indicator, &frame).unwrap();
+
+        // The gateway answers with its description, without any association
+        let frame = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        let indicator = Indicator::from(&frame).unwrap();
+        assert_eq!(
+            indicator.get_ipv4().unwrap().get_src(),
+            testing::LOCAL_IP_ADDR
+        );
+        let udp = indicator.get_udp().unwrap();
+        assert_eq!((udp.get_src(), udp.get_dst()), (SSDP_PORT, 50000));
+        let response = String::from_utf8_lossy(&frame[indicator.get_size()..]).to_string();
+        assert!(response.contains(&format!(
+            "\r\nLOCATION: http://{}:{}/rootDesc.xml\r\n",
+            testing::LOCAL_IP_ADDR,
+            upnp::control::DEFAULT_PORT
+        )));
+        assert!(redirector
+            .datagrams
+            .iter()
+            .all(|datagram| datagram.is_none()));
+        assert_eq!(
+            proxy.accept().unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+    }
+
+    #[test]
+    fn test_gateway_probe() {
+        let (mut redirector, rx, _proxy) = create_gateway();
+        let mut console = Console::new();
+        console.set_probe(console::Probe::new(
+            console::probe::DEFAULT_PORT,
+            console::probe::DEFAULT_RESPONSE.as_bytes().to_vec(),
+        ));
+        redirector.set_console(console);
+        let dst = SocketAddrV4::new(testing::LOCAL_IP_ADDR, console::probe::DEFAULT_PORT);
+
+        // The probe answers by itself, without any stream
+        testing::handle_segment_to(&mut redirector, dst, 50000, 1000, 0, TcpFlags::SYN, &[]);
+        let syn = testing::receive_segment(&rx);
+        assert_eq!(syn.flags, TcpFlags::SYN | TcpFlags::ACK);
+        let sequence = syn.sequence.wrapping_add(1);
+        let request = b"GET / HTTP/1.1\r\n\r\n";
+        let flags = TcpFlags::ACK | TcpFlags::PSH;
+        testing::handle_segment_to(&mut redirector, dst, 50000, 1001, sequence, flags, request);
+        let response = testing::receive_segment(&rx);
+        assert_ne!(response.flags & TcpFlags::FIN, 0);
+        assert_eq!(
+            response.payload,
+            console::probe::DEFAULT_RESPONSE.as_bytes()
+        );
+        assert_eq!(redirector.get_stream_count(), 0);
+    }
+
+    #[test]
+    fn test_gateway_ping() {
+        let (mut redirector, rx, _proxy) = create_gateway();
+        redirector.set_ping_ttl(Some(console::DEFAULT_PING_TTL));
+
+        let mut builder = Builder::new(testing::SRC_HARDWARE_ADDR, testing::LOCAL_HARDWARE_ADDR);
+        builder.set_ipv4(1, testing::SRC_IP_ADDR, testing::LOCAL_IP_ADDR);
+        builder.set_icmpv4_echo_reply();
+        let mut frame = builder.build(b"\x00\x01\x00\x01ping").unwrap();
+        // Turns the echo reply into an echo request
+        frame[testing::TRANSPORT_OFFSET] = 8;
+        let indicator = Indicator::from(&frame).unwrap();
+        redirector.handle_ipv4(&indicator, &frame).unwrap();
+
+        let reply = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        let indicator = Indicator::from(&reply).unwrap();
+        let icmpv4 = indicator.get_icmpv4().unwrap();
+        assert_eq!(icmpv4.get_src_ip_addr(), testing::LOCAL_IP_ADDR);
+        assert!(!icmpv4.is_echo_request());
+        let size = indicator.get_ethernet().unwrap().get_size()
+            + indicator.get_ipv4().unwrap().get_total_length() as usize;
+        assert_eq!(&reply[indicator.get_size()..size], b"\x00\x01\x00\x01ping");
+    }
+
+    #[test]
+    fn test_gateway_dns() {
+        use std::net::UdpSocket;
+
+        let proxy = testing::MockProxy::spawn(Ipv4Addr::LOCALHOST.into()).unwrap();
+        let (forwarder, rx) = testing::forwarder(1500);
+        let upstreams = Upstreams::new(
+            vec![proxy.get_addr()],
+            upstream::Policy::First,
+            upstream::Fallback::Fail,
+        )
+        .unwrap();
+        let mut redirector = Redirector::new(
+            Arc::new(Mutex::new(forwarder)),
+            testing::SRC_IP_ADDR,
+            Some(testing::LOCAL_IP_ADDR),
+            upstreams,
+            30000,
+            64,
+        );
+        let resolver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let resolver_addr = match resolver.local_addr().unwrap() {
+            SocketAddr::V4(addr) => addr,
+            _ => unreachable!(),
+        };
+        std::thread::spawn(move || {
+            let mut buffer = [0u8; 1500];
+            while let Ok((size, addr)) = resolver.recv_from(&mut buffer) {
+                let _ = resolver.send_to(&buffer[..size], addr);
+            }
+        });
+        redirector.set_gateway_dns(resolver_addr).unwrap();
+
+        // The query is sent to the resolver, and answered from the gateway
+        handle_gateway_datagram(&mut redirector, 50000, dns::DNS_PORT);
+        let answer = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        let indicator = Indicator::from(&answer).unwrap();
+        let udp = indicator.get_udp().unwrap();
+        assert_eq!(udp.get_src_ip_addr(), testing::LOCAL_IP_ADDR);
+        assert_eq!(udp.get_src(), dns::DNS_PORT);
+        let size = indicator.get_ethernet().unwrap().get_size()
+            + indicator.get_ipv4().unwrap().get_total_length() as usize;
+        assert_eq!(&answer[indicator.get_size()..size], b"query");
+    }
+
+    #[test]
+    fn test_gateway_unhandled() {
+        let (mut redirector, rx, proxy) = create_gateway();
+        redirector
+            .set_gateway_service(
+                8080,
+                Arc::new(gateway::Status::new(Arc::new(Monitor::new()))),
+            )
+            .unwrap();
+
+        // A TCP port without a service is refused with a RST
+        let dst = SocketAddrV4::new(testing::LOCAL_IP_ADDR, 8081);
+        testing::handle_segment_to(&mut redirector, dst, 50000, 1000, 0, TcpFlags::SYN, &[]);
+        let rst = testing::receive_segment(&rx);
+        assert_eq!(rst.flags, TcpFlags::RST | TcpFlags::ACK);
+        assert_eq!(rst.acknowledgement, 1001);
+        assert_eq!(redirector.get_stream_count(), 0);
+
+        // A UDP port without a responder is refused with a port unreachable
+        handle_gateway_datagram(&mut redirector, 50000, 9000);
+        let unreachable = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        let indicator = Indicator::from(&unreachable).unwrap();
+        let icmpv4 = indicator.get_icmpv4().unwrap();
+        assert_eq!(icmpv4.get_src_ip_addr(), testing::LOCAL_IP_ADDR);
+        assert_eq!(unreachable[testing::TRANSPORT_OFFSET], 3);
+        assert_eq!(redirector.gateway_refused, 2);
+        assert_eq!(redirector.datagram_map[50000], 0);
+
+        assert_eq!(
+            proxy.accept().unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+    }
+
+    #[test]
+    fn test_readiness_follows_upstreams() {
+        let proxy = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1080));
+        let (mut redirector, _rx) = testing::create_redirector(proxy);
+        redirector
+            .upstreams
+            .set_fallback_hold(Duration::from_secs(0));
+        let health = Arc::new(Health::new());
+        redirector.health = Some(Arc::clone(&health));
+        redirector.update_health();
+
+        // Not ready before the validation, ready after it
+        assert_eq!(health.get_ready_failures(), vec![health::Check::Validation]);
+        health.set_validated(true);
+        assert!(health.get_ready_failures().is_empty());
+
+        // All the upstreams are down
+        let e = io::Error::from(io::ErrorKind::ConnectionRefused);
+        for _ in 0..3 {
+            redirector.upstreams.report_failure(proxy, &e);
+        }
+        redirector.follow_fallback();
+        assert_eq!(health.get_ready_failures(), vec![health::Check::Upstreams]);
+
+        // The upstream is up again
+        redirector.upstreams.report(
+            proxy,
+            Ipv4Addr::new(192, 0, 2, 1),
+            Duration::from_millis(10),
+        );
+        redirector.follow_fallback();
+        assert!(health.get_ready_failures().is_empty());
+
+        // And down again
+        for _ in 0..3 {
+            redirector.upstreams.report_failure(proxy, &e);
+        }
+        redirector.follow_fallback();
+        assert_eq!(health.get_ready_failures(), vec![health::Check::Upstreams]);
+    }
+
+    /// Represents the interfaces of the host of interface tests, which the test changes.
+    #[derive(Clone)]
+    struct FakeInterfaces(Arc<Mutex<Option<Interface>>>);
+
+    impl Interfaces for FakeInterfaces {
+        fn get(&self, name: &str) -> Option<Interface> {
+            self.0
+                .lock()
+                .unwrap()
+                .clone()
+                .filter(|inter| inter.name == name)
+        }
+    }
+
+    fn create_interface(hardware_addr: HardwareAddr, ip_addr: Ipv4Addr) -> Interface {
+        let mut inter = Interface::new();
+        inter.name = String::from("eth0");
+        inter.hardware_addr = hardware_addr;
+        inter.ip_addrs = vec![ip_addr];
+        inter.is_up = true;
+
+        inter
+    }
+
+    #[test]
+    fn test_interface_change() {
+        let proxy = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1080));
+        let (mut redirector, rx) = testing::create_redirector(proxy);
+        let health = Arc::new(Health::new());
+        redirector.health = Some(Arc::clone(&health));
+        let inter = create_interface(testing::LOCAL_HARDWARE_ADDR, testing::LOCAL_IP_ADDR);
+        let host = FakeInterfaces(Arc::new(Mutex::new(Some(inter.clone()))));
+        redirector.set_interface(inter, 1500);
+        redirector.set_interfaces(Box::new(host.clone()));
+        let set_host = |hardware_addr, ip_addr| {
+            *host.0.lock().unwrap() = Some(create_interface(hardware_addr, ip_addr));
+        };
+
+        // Nothing changes
+        redirector.check_interface();
+        assert!(rx.try_recv().is_err());
+
+        // A new IP address in the same network is the gateway
+        let ip_addr = Ipv4Addr::new(10, 6, 0, 9);
+        set_host(testing::LOCAL_HARDWARE_ADDR, ip_addr);
+        redirector.check_interface();
+        assert_eq!(redirector.tx.lock().unwrap().get_local_ip_addr(), ip_addr);
+        assert_eq!(redirector.inter.as_ref().unwrap().ip_addrs[0], ip_addr);
+
+        // A new hardware address
+        let hardware_addr = HardwareAddr::new(2, 0, 0, 0, 0, 9);
+        set_host(hardware_addr, ip_addr);
+        redirector.check_interface();
+        assert_eq!(
+            redirector.tx.lock().unwrap().get_local_hardware_addr(),
+            hardware_addr
+        );
+        // The gateway is not published, so there is nothing to announce
+        assert!(rx.try_recv().is_err());
+
+        // Lost and back
+        *host.0.lock().unwrap() = None;
+        redirector.check_interface();
+        redirector.update_health();
+        assert!(redirector.is_interface_lost);
+        assert_eq!(health.get_ready_failures()[0], health::Check::Interface);
+        set_host(hardware_addr, ip_addr);
+        redirector.check_interface();
+        redirector.update_health();
+        assert!(!redirector.is_interface_lost);
+        assert!(!health
+            .get_ready_failures()
+            .contains(&health::Check::Interface));
+
+        // A published gateway keeps its IP address, and is announced with the new hardware
+        // address
+        redirector.local_ip_addr = Some(testing::LOCAL_IP_ADDR);
+        redirector
+            .tx
+            .lock()
+            .unwrap()
+            .set_local_ip_addr(testing::LOCAL_IP_ADDR);
+        redirector.is_tx_src_hardware_addr_set = true;
+        let new_hardware_addr = HardwareAddr::new(2, 0, 0, 0, 0, 10);
+        set_host(new_hardware_addr, Ipv4Addr::new(192, 168, 1, 9));
+        redirector.check_interface();
+        assert_eq!(
+            redirector.tx.lock().unwrap().get_local_ip_addr(),
+            testing::LOCAL_IP_ADDR
+        );
+        let frame = rx.try_recv().unwrap();
+        // An ARP reply from the new hardware address
+        assert_eq!(&frame[6..12], &[2, 0, 0, 0, 0, 10]);
+        assert_eq!(&frame[12..14], &[0x08, 0x06]);
+        assert_eq!(&frame[28..32], &testing::LOCAL_IP_ADDR.octets()[..]);
+    }
+
+    #[test]
+    fn test_slow_source_pause() {
+        use std::io::Write;
+
+        const TOTAL: usize = 64 * 1024 * 1024;
+        const MAX_BACKLOG: usize = 1024 * 1024;
+
+        // A server writing as fast as it can
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dst = match listener.local_addr().unwrap() {
+            SocketAddr::V4(addr) => addr,
+            _ => unreachable!(),
+        };
+        let written = Arc::new(AtomicUsize::new(0));
+        let written_cloned = Arc::clone(&written);
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let chunk = [0u8; 64 * 1024];
+            while written_cloned.load(atomic::Ordering::Relaxed) < TOTAL {
+                if stream.write_all(&chunk).is_err() {
+                    break;
+                }
+                written_cloned.fetch_add(chunk.len(), atomic::Ordering::Relaxed);
+            }
+        });
+        let proxy = testing::MockProxy::spawn(Ipv4Addr::LOCALHOST.into()).unwrap();
+        let (mut redirector, rx) = testing::create_redirector(proxy.get_addr());
+        let key = (50000, dst);
+        let sequence = testing::establish_to(&mut redirector, &rx, dst, key.0);
+
+        // The source never acknowledges, so the stream pauses and the server is blocked by the
+        // buffers of the proxy side instead
+        let backlog =
+            |redirector: &Redirector| redirector.tx.lock().unwrap().get_cache_size(dst, key.0);
+        let instant = Instant::now();
+        let mut last = (0, Instant::now());
+        loop {
+            assert!(instant.elapsed() < Duration::from_secs(10));
+            assert!(backlog(&redirector) <= MAX_BACKLOG);
+            let current = written.load(atomic::Ordering::Relaxed);
+            if current != last.0 {
+                last = (current, Instant::now());
+            } else if redirector.get_tcp_pauses() > 0
+                && last.1.elapsed() >= Duration::from_millis(300)
+            {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let stalled = written.load(atomic::Ordering::Relaxed);
+        assert!(stalled < TOTAL);
+        assert!(stalled > backlog(&redirector) + MAX_BACKLOG);
+
+        // The source drains what it is sent, and the stream resumes. The backlog may grow with
+        // the measured drain rate then
+        let mut acknowledged = 0usize;
+        let instant = Instant::now();
+        while written.load(atomic::Ordering::Relaxed) < stalled + MAX_BACKLOG {
+            assert!(instant.elapsed() < Duration::from_secs(10));
+            while let Ok(frame) = rx.try_recv() {
+                let indicator = Indicator::from(&frame).unwrap();
+                let size = indicator.get_ethernet().unwrap().get_size()
+                    + indicator.get_ipv4().unwrap().get_total_length() as usize;
+                acknowledged += size - indicator.get_size();
+            }
+            testing::handle_segment_to(
+                &mut redirector,
+                dst,
+                key.0,
+                1001,
+                sequence.wrapping_add(acknowledged as u32),
+                TcpFlags::ACK,
+                &[],
+            );
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
@@ -0,0 +1,556 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::hooks::Protocol;
+use crate::stats::Snapshot;
+
+/// Represents a flow killed by the operator.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Kill {
+    /// The TCP connection of the source port to the destination, whose stream to the proxy is
+    /// aborted instead of shut down in order if set.
+    Tcp(u16, SocketAddrV4, bool),
+    /// The UDP association of the source port.
+    Udp(u16),
+}
+
+impl Kill {
+    /// Parses a kill like `tcp 50000 192.0.2.1:80`, `tcp 50000 192.0.2.1:80 abort` or
+    /// `udp 50000`, whose ports are the source ports.
+    pub fn parse(s: &str) -> Option<Kill> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        match words.as_slice() {
+            ["tcp", src_port, dst] => {
+                Some(Kill::Tcp(src_port.parse().ok()?, dst.parse().ok()?, false))
+            }
+            ["tcp", src_port, dst, "abort"] => {
+                Some(Kill::Tcp(src_port.parse().ok()?, dst.parse().ok()?, true))
+            }
+            ["udp", src_port] => Some(Kill::Udp(src_port.parse().ok()?)),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Kill {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Kill::Tcp(src_port, dst, false) => write!(f, "TCP {} -> {}", src_port, dst),
+            Kill::Tcp(src_port, dst, true) => write!(f, "TCP {} -> {} (abort)", src_port, dst),
+            Kill::Udp(src_port) => write!(f, "UDP {}", src_port),
+        }
+    }
+}
+
+/// Represents the traffic of a worker exchanged with the proxy.
+#[derive(Clone, Copy, Debug)]
+pub struct WorkerStats {
+    pub bytes_up: usize,
+    pub packets_up: usize,
+    pub bytes_down: usize,
+    pub packets_down: usize,
+    pub created: Instant,
+    /// Represents the last time data is sent or received, or the creation if none is.
+    pub last_active: Instant,
+}
+
+impl Display for WorkerStats {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} B in {} up, {} B in {} down, {} s old, {} s idle",
+            self.bytes_up,
+            self.packets_up,
+            self.bytes_down,
+            self.packets_down,
+            self.created.elapsed().as_secs(),
+            self.last_active.elapsed().as_secs()
+        )
+    }
+}
+
+/// Represents the counters of the traffic of a worker, shared by the worker and its thread. The
+/// counters are relaxed atomics, so counting never locks.
+#[derive(Debug)]
+pub struct Counters {
+    bytes_up: AtomicUsize,
+    packets_up: AtomicUsize,
+    bytes_down: AtomicUsize,
+    packets_down: AtomicUsize,
+    created: Instant,
+    /// Represents the last time data is sent or received in milliseconds since created.
+    last_active: AtomicU64,
+    /// Represents the time the first data is sent in microseconds since created plus 1, or 0 if
+    /// none is.
+    first_sent: AtomicU64,
+    /// Represents the time from the first data sent to the first data received after it in
+    /// microseconds plus 1, or 0 if the round trip is not completed.
+    early_rtt: AtomicU64,
+}
+
+impl Counters {
+    /// Creates a new `Counters`.
+    pub fn new() -> Counters {
+        Counters {
+            bytes_up: AtomicUsize::new(0),
+            packets_up: AtomicUsize::new(0),
+            bytes_down: AtomicUsize::new(0),
+            packets_down: AtomicUsize::new(0),
+            created: Instant::now(),
+            last_active: AtomicU64::new(0),
+            first_sent: AtomicU64::new(0),
+            early_rtt: AtomicU64::new(0),
+        }
+    }
+
+    /// Counts a packet sent to the proxy.
+    pub fn add_up(&self, size: usize) {
+        self.bytes_up.fetch_add(size, Ordering::Relaxed);
+        self.packets_up.fetch_add(1, Ordering::Relaxed);
+        self.touch();
+    }
+
+    /// Counts a packet received from the proxy.
+    pub fn add_down(&self, size: usize) {
+        self.bytes_down.fetch_add(size, Ordering::Relaxed);
+        self.packets_down.fetch_add(1, Ordering::Relaxed);
+        self.touch();
+
+        // The first round trip completes
+        let first_sent = self.first_sent.load(Ordering::Relaxed);
+        if first_sent != 0 && self.early_rtt.load(Ordering::Relaxed) == 0 {
+            let rtt = self.get_micros().saturating_sub(first_sent - 1);
+            let _ =
+                self.early_rtt
+                    .compare_exchange(0, rtt + 1, Ordering::Relaxed, Ordering::Relaxed);
+        }
+    }
+
+    /// Marks data written to the proxy, which starts the first round trip if none is started.
+    pub fn add_sent(&self) {
+        if self.first_sent.load(Ordering::Relaxed) == 0 {
+            let _ = self.first_sent.compare_exchange(
+                0,
+                self.get_micros() + 1,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            );
+        }
+    }
+
+    fn get_micros(&self) -> u64 {
+        self.created.elapsed().as_micros() as u64
+    }
+
+    /// Get the time from the first data written to the proxy to the first data received after
+    /// it, or `None` if the first round trip is not completed.
+    pub fn get_early_rtt(&self) -> Option<Duration> {
+        match self.early_rtt.load(Ordering::Relaxed) {
+            0 => None,
+            rtt => Some(Duration::from_micros(rtt - 1)),
+        }
+    }
+
+    fn touch(&self) {
+        self.last_active
+            .store(self.created.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Get the number of bytes sent and received.
+    pub fn get_bytes(&self) -> (usize, usize) {
+        (
+            self.bytes_up.load(Ordering::Relaxed),
+            self.bytes_down.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Get the last time data is sent or received, or the creation if none is.
+    pub fn get_last_active(&self) -> Instant {
+        self.created + Duration::from_millis(self.last_active.load(Ordering::Relaxed))
+    }
+
+    /// Get the traffic counted.
+    pub fn get_stats(&self) -> WorkerStats {
+        WorkerStats {
+            bytes_up: self.bytes_up.load(Ordering::Relaxed),
+            packets_up: self.packets_up.load(Ordering::Relaxed),
+            bytes_down: self.bytes_down.load(Ordering::Relaxed),
+            packets_down: self.packets_down.load(Ordering::Relaxed),
+            created: self.created,
+            last_active: self.get_last_active(),
+        }
+    }
+}
+
+impl Default for Counters {
+    fn default() -> Counters {
+        Counters::new()
+    }
+}
+
+/// Represents a worker registered in a `Monitor`.
+#[derive(Clone, Copy, Debug)]
+pub struct Entry {
+    pub protocol: Protocol,
+    pub src_port: u16,
+    /// Represents the destination of the worker, or `None` if it sends to many.
+    pub dst: Option<SocketAddrV4>,
+    pub stats: WorkerStats,
+}
+
+impl Display for Entry {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.dst {
+            Some(dst) => write!(
+                f,
+                "{} {} -> {}: {}",
+                self.protocol, self.src_port, dst, self.stats
+            ),
+            None => write!(f, "{} {}: {}", self.protocol, self.src_port, self.stats),
+        }
+    }
+}
+
+/// Represents the aggregate traffic of the workers in a `Monitor`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Summary {
+    /// Represents the bytes sent by all the workers, including the dropped ones.
+    pub bytes_up: usize,
+    /// Represents the bytes received by all the workers, including the dropped ones.
+    pub bytes_down: usize,
+    /// Represents the bytes sent in a second in the last interval.
+    pub rate_up: usize,
+    /// Represents the bytes received in a second in the last interval.
+    pub rate_down: usize,
+    pub streams: usize,
+    pub associations: usize,
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} streams, {} associations, {} B up at {} B/s, {} B down at {} B/s",
+            self.streams,
+            self.associations,
+            self.bytes_up,
+            self.rate_up,
+            self.bytes_down,
+            self.rate_down
+        )
+    }
+}
+
+#[derive(Debug)]
+struct Registered {
+    protocol: Protocol,
+    src_port: u16,
+    dst: Option<SocketAddrV4>,
+    counters: Arc<Counters>,
+}
+
+/// Represents the registry of the live workers, shared by the `Redirector`s, which aggregates
+/// their traffic. Workers are registered once created and removed once dropped, so counting their
+/// traffic never touches the registry.
+#[derive(Debug)]
+pub struct Monitor {
+    workers: Mutex<HashMap<u64, Registered>>,
+    next_id: AtomicU64,
+    /// Represents the bytes sent and received by the workers dropped.
+    dropped: Mutex<(usize, usize)>,
+    /// Represents the time and the bytes sent and received of the last summary.
+    last_summary: Mutex<(Instant, usize, usize)>,
+    /// Represents the number of dumps requested.
+    dumps: AtomicUsize,
+    /// Represents the number of expiries requested, and the rule of the last one.
+    expiries: Mutex<(usize, Option<String>)>,
+    /// Represents the kills requested.
+    kills: Mutex<Vec<Kill>>,
+    /// Represents the source ports of each source with the most new UDP destinations.
+    talkers: Mutex<HashMap<Ipv4Addr, Vec<(u16, usize)>>>,
+    /// Represents the last statistics published.
+    snapshot: Mutex<Option<Snapshot>>,
+}
+
+impl Monitor {
+    /// Creates a new `Monitor`.
+    pub fn new() -> Monitor {
+        Monitor {
+            workers: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            dropped: Mutex::new((0, 0)),
+            last_summary: Mutex::new((Instant::now(), 0, 0)),
+            dumps: AtomicUsize::new(0),
+            expiries: Mutex::new((0, None)),
+            kills: Mutex::new(Vec::new()),
+            talkers: Mutex::new(HashMap::new()),
+            snapshot: Mutex::new(None),
+        }
+    }
+
+    /// Registers the counters of a worker, which stays registered until the `Registration` is
+    /// dropped.
+    pub fn register(
+        monitor: &Arc<Monitor>,
+        protocol: Protocol,
+        src_port: u16,
+        dst: Option<SocketAddrV4>,
+        counters: Arc<Counters>,
+    ) -> Registration {
+        let id = monitor.next_id.fetch_add(1, Ordering::Relaxed);
+        monitor.workers.lock().unwrap().insert(
+            id,
+            Registered {
+                protocol,
+                src_port,
+                dst,
+                counters,
+            },
+        );
+
+        Registration {
+            monitor: Arc::clone(monitor),
+            id,
+        }
+    }
+
+    fn unregister(&self, id: u64) {
+        let registered = self.workers.lock().unwrap().remove(&id);
+        if let Some(registered) = registered {
+            let (bytes_up, bytes_down) = registered.counters.get_bytes();
+            let mut dropped = self.dropped.lock().unwrap();
+            dropped.0 = dropped.0.saturating_add(bytes_up);
+            dropped.1 = dropped.1.saturating_add(bytes_down);
+        }
+    }
+
+    /// Get the bytes sent and received by all the workers, including the dropped ones.
+    pub fn get_bytes(&self) -> (usize, usize) {
+        let (mut bytes_up, mut bytes_down) = *self.dropped.lock().unwrap();
+        for registered in self.workers.lock().unwrap().values() {
+            let (up, down) = registered.counters.get_bytes();
+            bytes_up = bytes_up.saturating_add(up);
+            bytes_down = bytes_down.saturating_add(down);
+        }
+
+        (bytes_up, bytes_down)
+    }
+
+    /// Get the aggregate traffic, whose rates are over the interval since the last summary.
+    pub fn get_summary(&self) -> Summary {
+        let mut summary = Summary::default();
+        for registered in self.workers.lock().unwrap().values() {
+            match registered.protocol {
+                Protocol::Tcp => summary.streams += 1,
+                Protocol::Udp => summary.associations += 1,
+            }
+        }
+        let (bytes_up, bytes_down) = self.get_bytes();
+        summary.bytes_up = bytes_up;
+        summary.bytes_down = bytes_down;
+
+        let mut last = self.last_summary.lock().unwrap();
+        let millis = last.0.elapsed().as_millis() as usize;
+        if let Some(rate_up) = (summary.bytes_up.saturating_sub(last.1) * 1000).checked_div(millis)
+        {
+            summary.rate_up = rate_up;
+            summary.rate_down = summary.bytes_down.saturating_sub(last.2) * 1000 / millis;
+        }
+        *last = (Instant::now(), summary.bytes_up, summary.bytes_down);
+
+        summary
+    }
+
+    /// Get the live workers and their traffic, the busiest first.
+    pub fn get_entries(&self) -> Vec<Entry> {
+        let mut entries: Vec<Entry> = self
+            .workers
+            .lock()
+            .unwrap()
+            .values()
+            .map(|registered| Entry {
+                protocol: registered.protocol,
+                src_port: registered.src_port,
+                dst: registered.dst,
+                stats: registered.counters.get_stats(),
+            })
+            .collect();
+        entries.sort_by(|a, b| {
+            (b.stats.bytes_up + b.stats.bytes_down).cmp(&(a.stats.bytes_up + a.stats.bytes_down))
+        });
+
+        entries
+    }
+
+    /// Requests the `Redirector`s sharing the monitor to log their inventories, like the threads
+    /// and the upstreams chosen for destinations, which they do in their next loop.
+    pub fn request_dump(&self) {
+        self.dumps.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Get the number of dumps requested.
+    pub fn get_dumps(&self) -> usize {
+        self.dumps.load(Ordering::Relaxed)
+    }
+
+    /// Requests the `Redirector`s sharing the monitor to close the flows admitted by the rule, or
+    /// by any rule if it is not given, whose windows do not contain the local time any more,
+    /// which they do in their next loop.
+    pub fn request_expiry(&self, rule: Option<String>) {
+        let mut expiries = self.expiries.lock().unwrap();
+        expiries.0 = expiries.0.wrapping_add(1);
+        expiries.1 = rule;
+    }
+
+    /// Get the number of expiries requested, and the rule of the last one.
+    pub fn get_expiries(&self) -> (usize, Option<String>) {
+        self.expiries.lock().unwrap().clone()
+    }
+
+    /// Requests the `Redirector`s sharing the monitor to kill the flow, which the one having it
+    /// does in its next loop.
+    pub fn request_kill(&self, kill: Kill) {
+        self.kills.lock().unwrap().push(kill);
+    }
+
+    /// Get the number of kills requested.
+    pub fn get_kill_count(&self) -> usize {
+        self.kills.lock().unwrap().len()
+    }
+
+    /// Get the kills requested from the given number on.
+    pub fn get_kills(&self, from: usize) -> Vec<Kill> {
+        let kills = self.kills.lock().unwrap();
+
+        kills[from.min(kills.len())..].to_vec()
+    }
+
+    /// Sets the source ports of the source with the most new UDP destinations in the current
+    /// window, which the `Redirector` of the source publishes.
+    pub fn set_talkers(&self, src_ip_addr: Ipv4Addr, talkers: Vec<(u16, usize)>) {
+        let mut talkers_locked = self.talkers.lock().unwrap();
+        if talkers.is_empty() {
+            talkers_locked.remove(&src_ip_addr);
+        } else {
+            talkers_locked.insert(src_ip_addr, talkers);
+        }
+    }
+
+    /// Sets the statistics of the first capture, which its `Redirector` publishes every second.
+    pub fn set_snapshot(&self, snapshot: Snapshot) {
+        *self.snapshot.lock().unwrap() = Some(snapshot);
+    }
+
+    /// Get the last statistics published, or `None` if none is published yet.
+    pub fn get_snapshot(&self) -> Option<Snapshot> {
+        self.snapshot.lock().unwrap().clone()
+    }
+
+    /// Get the source ports of all sources with the most new UDP destinations, the most first.
+    pub fn get_talkers(&self, n: usize) -> Vec<(SocketAddrV4, usize)> {
+        let mut talkers: Vec<(SocketAddrV4, usize)> = self
+            .talkers
+            .lock()
+            .unwrap()
+            .iter()
+            .flat_map(|(ip_addr, talkers)| {
+                talkers
+                    .iter()
+                    .map(move |(port, count)| (SocketAddrV4::new(*ip_addr, *port), *count))
+            })
+            .collect();
+        talkers.sort_by(|a, b| {
+            b.1.cmp(&a.1)
+                .then(a.0.ip().cmp(b.0.ip()))
+                .then(a.0.port().cmp(&b.0.port()))
+        });
+        talkers.truncate(n);
+
+        talkers
+    }
+}
+
+impl Default for Monitor {
+    fn default() -> Monitor {
+        Monitor::new()
+    }
+}
+
+/// Represents a worker registered in a `Monitor`, which is removed from it when dropped.
+#[derive(Debug)]
+pub struct Registration {
+    monitor: Arc<Monitor>,
+    id: u64,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.monitor.unregister(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kill_parse() {
+        let dst = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 80);
+        assert_eq!(
+            Kill::parse("tcp 50000 192.0.2.1:80"),
+            Some(Kill::Tcp(50000, dst, false))
+        );
+        assert_eq!(
+            Kill::parse("tcp 50000  192.0.2.1:80 abort"),
+            Some(Kill::Tcp(50000, dst, true))
+        );
+        assert_eq!(Kill::parse("udp 3074"), Some(Kill::Udp(3074)));
+        assert_eq!(Kill::parse("tcp 50000"), None);
+        assert_eq!(Kill::parse("tcp 50000 192.0.2.1:80 reset"), None);
+        assert_eq!(Kill::parse("udp 70000"), None);
+        assert_eq!(Kill::parse("icmp 1"), None);
+    }
+
+    #[test]
+    fn test_kills() {
+        let monitor = Monitor::new();
+        monitor.request_kill(Kill::Udp(1));
+        let from = monitor.get_kill_count();
+        monitor.request_kill(Kill::Udp(2));
+        monitor.request_kill(Kill::Udp(3));
+
+        // Only the kills requested after are seen
+        assert_eq!(monitor.get_kills(from), vec![Kill::Udp(2), Kill::Udp(3)]);
+        assert!(monitor.get_kills(monitor.get_kill_count()).is_empty());
+        assert!(monitor.get_kills(usize::MAX).is_empty());
+    }
+
+    #[test]
+    fn test_talkers() {
+        let monitor = Monitor::new();
+        let first = Ipv4Addr::new(10, 6, 0, 2);
+        let second = Ipv4Addr::new(10, 6, 0, 3);
+        monitor.set_talkers(first, vec![(5000, 40), (5001, 2)]);
+        monitor.set_talkers(second, vec![(6000, 100)]);
+
+        // Talkers of all sources are sorted together
+        assert_eq!(
+            monitor.get_talkers(2),
+            vec![
+                (SocketAddrV4::new(second, 6000), 100),
+                (SocketAddrV4::new(first, 5000), 40)
+            ]
+        );
+        // A source publishing again replaces its talkers
+        monitor.set_talkers(second, Vec::new());
+        monitor.set_talkers(first, vec![(5001, 3)]);
+        assert_eq!(
+            monitor.get_talkers(10),
+            vec![(SocketAddrV4::new(first, 5001), 3)]
+        );
+    }
+}
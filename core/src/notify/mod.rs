@@ -0,0 +1,387 @@
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::threads::{Purpose, Threads};
+
+/// Represents the max number of events waiting to be delivered.
+const NOTIFY_QUEUE: usize = 16;
+
+/// Represents the default min time between 2 notifications of the same condition in minutes.
+pub const DEFAULT_NOTIFY_HOLD: u64 = 5;
+
+/// Represents all the conditions.
+pub const CONDITIONS: [Condition; 4] = [
+    Condition::Upstreams,
+    Condition::Udp,
+    Condition::Interface,
+    Condition::Quota,
+];
+
+/// Represents the condition an event changes.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Condition {
+    /// All the upstreams are down or some are up again.
+    Upstreams,
+    /// UDP through the upstreams is degraded or recovered.
+    Udp,
+    /// The interface is lost or reacquired.
+    Interface,
+    /// The traffic exceeds the quota.
+    Quota,
+}
+
+impl Condition {
+    /// Get the condition of the given name.
+    pub fn from_name(name: &str) -> Option<Condition> {
+        CONDITIONS
+            .iter()
+            .find(|condition| condition.to_string() == name)
+            .copied()
+    }
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Condition::Upstreams => write!(f, "upstreams"),
+            Condition::Udp => write!(f, "udp"),
+            Condition::Interface => write!(f, "interface"),
+            Condition::Quota => write!(f, "quota"),
+        }
+    }
+}
+
+/// Represents a critical state change.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Event {
+    /// All the upstreams are down.
+    UpstreamsDown,
+    /// Some upstreams are up again.
+    UpstreamsUp,
+    /// UDP associations through the upstreams fail for the given reason.
+    UdpDegraded(String),
+    /// UDP associations through the upstreams are bound again.
+    UdpRecovered,
+    /// The interface of the given name is lost.
+    InterfaceLost(String),
+    /// The interface of the given name is reacquired.
+    InterfaceReacquired(String),
+    /// The traffic exceeds the quota of the given bytes.
+    QuotaExceeded(u64),
+}
+
+impl Event {
+    /// Get the condition the event changes.
+    pub fn get_condition(&self) -> Condition {
+        match self {
+            Event::UpstreamsDown | Event::UpstreamsUp => Condition::Upstreams,
+            Event::UdpDegraded(_) | Event::UdpRecovered => Condition::Udp,
+            Event::InterfaceLost(_) | Event::InterfaceReacquired(_) => Condition::Interface,
+            Event::QuotaExceeded(_) => Condition::Quota,
+        }
+    }
+
+    /// Get the interface the event is about, if any. Events of different interfaces are held
+    /// back independently.
+    pub fn get_interface(&self) -> Option<&str> {
+        match self {
+            Event::InterfaceLost(name) | Event::InterfaceReacquired(name) => Some(name),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Event {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Event::UpstreamsDown => write!(f, "All proxies are down"),
+            Event::UpstreamsUp => write!(f, "Proxies are up again"),
+            Event::UdpDegraded(reason) => write!(f, "UDP is degraded: {}", reason),
+            Event::UdpRecovered => write!(f, "UDP is recovered"),
+            Event::InterfaceLost(name) => write!(f, "Interface {} is lost", name),
+            Event::InterfaceReacquired(name) => write!(f, "Interface {} is back", name),
+            Event::QuotaExceeded(quota) => {
+                write!(f, "Traffic exceeds the quota of {} Bytes", quota)
+            }
+        }
+    }
+}
+
+/// Represents the notifications in the config file, which has a setting per line as in
+/// `<NAME> = <VALUE>`, like `udp = off`, `hold = 10` or `quota-size = 10000000000`. Empty lines
+/// and lines starting with `#` are skipped.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Config {
+    /// Represents the conditions switched on or off.
+    pub toggles: Vec<(Condition, bool)>,
+    /// Represents the min time between 2 notifications of the same condition in minutes.
+    pub hold: Option<u64>,
+    /// Represents the quota of the traffic in bytes.
+    pub quota_size: Option<u64>,
+}
+
+impl Config {
+    /// Parses a config file, returns the line which is invalid otherwise.
+    pub fn parse(s: &str) -> Result<Config, String> {
+        let mut config = Config::default();
+        for line in s.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(2, '=');
+            let name = parts.next().unwrap().trim();
+            let value = match parts.next() {
+                Some(value) => value.trim(),
+                None => return Err(line.to_string()),
+            };
+            match name {
+                "hold" => match value.parse() {
+                    Ok(hold) if hold >= 1 => config.hold = Some(hold),
+                    _ => return Err(line.to_string()),
+                },
+                "quota-size" => match value.parse() {
+                    Ok(size) if size >= 1 => config.quota_size = Some(size),
+                    _ => return Err(line.to_string()),
+                },
+                name => {
+                    let condition = Condition::from_name(name).ok_or_else(|| line.to_string())?;
+                    let is_on = match value {
+                        "on" => true,
+                        "off" => false,
+                        _ => return Err(line.to_string()),
+                    };
+                    config.toggles.push((condition, is_on));
+                }
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Get the conditions notified, which are the given ones, or all of them if there is none,
+    /// switched on or off by the config.
+    pub fn get_conditions(&self, conditions: &[Condition]) -> Vec<Condition> {
+        let mut conditions = if conditions.is_empty() {
+            CONDITIONS.to_vec()
+        } else {
+            conditions.to_vec()
+        };
+        for (condition, is_on) in &self.toggles {
+            conditions.retain(|c| c != condition);
+            if *is_on {
+                conditions.push(*condition);
+            }
+        }
+
+        conditions
+    }
+}
+
+/// Represents the delivery of a notification, like a desktop notification.
+pub type Deliver = Box<dyn Fn(&Event) -> io::Result<()> + Send>;
+
+/// Represents the notifications of critical state changes. Events are delivered by a thread, at
+/// most one notification for a condition in the hold time, so a flapping condition notifies once
+/// and then its latest state when the hold time is over.
+pub struct Notifier {
+    conditions: Vec<Condition>,
+    deliver: Option<Deliver>,
+    tx: Option<SyncSender<Event>>,
+}
+
+impl Notifier {
+    /// Creates a new `Notifier` of the given conditions, or all of them if there is none.
+    pub fn new(conditions: Vec<Condition>) -> Notifier {
+        let conditions = if conditions.is_empty() {
+            CONDITIONS.to_vec()
+        } else {
+            conditions
+        };
+
+        Notifier {
+            conditions,
+            deliver: None,
+            tx: None,
+        }
+    }
+
+    /// Sets the delivery of notifications.
+    pub fn set_deliver(&mut self, deliver: Deliver) {
+        self.deliver = Some(deliver);
+    }
+
+    /// Creates another `Notifier` of the same conditions delivering through the thread of the
+    /// `Notifier`, for another capture.
+    pub fn share(&self) -> Notifier {
+        Notifier {
+            conditions: self.conditions.clone(),
+            deliver: None,
+            tx: self.tx.clone(),
+        }
+    }
+
+    /// Spawns the thread delivering notifications.
+    pub fn start(&mut self, threads: &Arc<Threads>, hold: Duration) -> io::Result<()> {
+        let deliver = match self.deliver.take() {
+            Some(deliver) => deliver,
+            None => return Err(io::Error::other("notifications are delivered nowhere")),
+        };
+
+        let (tx, rx) = mpsc::sync_channel(NOTIFY_QUEUE);
+        Threads::spawn(
+            threads,
+            String::from("notify"),
+            Purpose::Notify,
+            move || work(rx, hold, deliver),
+        )?;
+        self.tx = Some(tx);
+
+        Ok(())
+    }
+
+    /// Reports an event.
+    pub fn notify(&self, event: Event) {
+        let tx = match self.tx {
+            Some(ref tx) => tx,
+            None => return,
+        };
+        if !self.conditions.contains(&event.get_condition()) {
+            return;
+        }
+
+        match tx.try_send(event) {
+            Ok(_) => {}
+            Err(TrySendError::Full(event)) => debug!("drop notification {}: queue is full", event),
+            Err(TrySendError::Disconnected(event)) => {
+                debug!("drop notification {}: no running thread", event)
+            }
+        }
+    }
+}
+
+fn work(rx: Receiver<Event>, hold: Duration, deliver: Deliver) {
+    // The time of the last notification and the event held back since, of each condition and
+    // interface
+    let mut conditions: HashMap<(Condition, Option<String>), (Instant, Option<Event>)> =
+        HashMap::new();
+    let mut is_failed = false;
+    loop {
+        let mut events = Vec::new();
+        match rx.recv_timeout(Duration::from_secs(1)) {
+            Ok(event) => {
+                let condition = (
+                    event.get_condition(),
+                    event.get_interface().map(String::from),
+                );
+                match conditions.get_mut(&condition) {
+                    Some((instant, held)) if instant.elapsed() < hold => {
+                        *held = Some(event);
+                    }
+                    _ => {
+                        conditions.insert(condition, (Instant::now(), None));
+                        events.push(event);
+                    }
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            // The `Notifier` is dropped
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        // Events held back
+        for (instant, held) in conditions.values_mut() {
+            if instant.elapsed() >= hold {
+                if let Some(event) = held.take() {
+                    *instant = Instant::now();
+                    events.push(event);
+                }
+            }
+        }
+
+        for event in events {
+            match deliver(&event) {
+                Ok(_) => debug!("notify {}", event),
+                Err(ref e) => {
+                    if !is_failed {
+                        is_failed = true;
+                        warn!("notify {}: {}", event, e);
+                    } else {
+                        debug!("notify {}: {}", event, e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::thread;
+
+    #[test]
+    fn test_config() {
+        let config = Config::parse(
+            "# Notifications\n\nudp = off\nquota = on\nhold = 10\nquota-size = 1000\n",
+        )
+        .unwrap();
+        assert_eq!(config.hold, Some(10));
+        assert_eq!(config.quota_size, Some(1000));
+        assert_eq!(
+            config.get_conditions(&[]),
+            vec![Condition::Upstreams, Condition::Interface, Condition::Quota]
+        );
+        assert_eq!(
+            config.get_conditions(&[Condition::Upstreams]),
+            vec![Condition::Upstreams, Condition::Quota]
+        );
+
+        assert_eq!(
+            Config::parse("udp = maybe"),
+            Err(String::from("udp = maybe"))
+        );
+        assert_eq!(Config::parse("disk = on"), Err(String::from("disk = on")));
+        assert_eq!(Config::parse("hold = 0"), Err(String::from("hold = 0")));
+        assert_eq!(Config::parse("udp"), Err(String::from("udp")));
+    }
+
+    #[test]
+    fn test_share() {
+        let delivered = Arc::new(Mutex::new(Vec::new()));
+        let delivered_cloned = Arc::clone(&delivered);
+        let mut notifier = Notifier::new(vec![Condition::Interface]);
+        notifier.set_deliver(Box::new(move |event| {
+            delivered_cloned.lock().unwrap().push(event.clone());
+            Ok(())
+        }));
+        let threads = Arc::new(Threads::new(16));
+        notifier.start(&threads, Duration::from_secs(3600)).unwrap();
+        let shared = notifier.share();
+
+        // Interfaces of different captures are held back independently
+        notifier.notify(Event::InterfaceLost(String::from("eth1")));
+        shared.notify(Event::InterfaceLost(String::from("eth2")));
+        shared.notify(Event::InterfaceReacquired(String::from("eth2")));
+        shared.notify(Event::UpstreamsDown);
+        let instant = Instant::now();
+        while delivered.lock().unwrap().len() < 2 {
+            assert!(instant.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(
+            *delivered.lock().unwrap(),
+            vec![
+                Event::InterfaceLost(String::from("eth1")),
+                Event::InterfaceLost(String::from("eth2"))
+            ]
+        );
+    }
+}
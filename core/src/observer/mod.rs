@@ -0,0 +1,419 @@
+use log::{debug, info};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::hooks::Protocol;
+use crate::packet::layer::LayerTypes;
+use crate::packet::Indicator;
+use crate::pcap::{HardwareAddr, Receiver};
+use crate::rule::Rules;
+use crate::stun;
+use crate::upstream::{Route, Upstreams};
+
+/// Represents the wait time after a `TimedOut` `IoError`.
+const TIMEDOUT_WAIT: u64 = 20;
+
+/// Represents the number of destinations in the report.
+const TOP_DESTINATIONS: usize = 10;
+
+/// Represents a destination of a protocol.
+type Destination = (Protocol, SocketAddrV4);
+
+/// Represents the traffic counted in a class.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Volume {
+    pub packets: usize,
+    pub bytes: usize,
+    pub flows: usize,
+}
+
+impl Volume {
+    fn add(&mut self, size: usize, is_new: bool) {
+        self.packets += 1;
+        self.bytes += size;
+        if is_new {
+            self.flows += 1;
+        }
+    }
+
+    fn to_json(self) -> String {
+        format!(
+            "{{\"packets\": {}, \"bytes\": {}, \"flows\": {}}}",
+            self.packets, self.bytes, self.flows
+        )
+    }
+}
+
+/// Represents an observer which classifies traffic of the source as the `Redirector` would do,
+/// without creating any state or sending any frame.
+pub struct Observer {
+    src_ip_addr: Ipv4Addr,
+    upstreams: Upstreams,
+    rules: Rules,
+    gateway_hardware_addr: Option<HardwareAddr>,
+    start: Instant,
+    elapsed: Duration,
+    other: Volume,
+    unhandled: Volume,
+    tcp: Volume,
+    udp: Volume,
+    proxied: Volume,
+    direct: Volume,
+    rejected: Volume,
+    /// Represents the STUN traffic, which tells the source relies on NAT traversal.
+    stun: Volume,
+    flows: HashSet<(Protocol, u16, SocketAddrV4)>,
+    destinations: HashMap<Destination, (Volume, Route)>,
+    /// Represents the start of the current second and the number of new flows in it.
+    flow_rate: (Instant, usize),
+    peak_flow_rate: usize,
+}
+
+impl Observer {
+    /// Creates a new `Observer`.
+    pub fn new(src_ip_addr: Ipv4Addr, upstreams: Upstreams) -> Observer {
+        Observer {
+            src_ip_addr,
+            upstreams,
+            rules: Rules::default(),
+            gateway_hardware_addr: None,
+            start: Instant::now(),
+            elapsed: Duration::from_secs(0),
+            other: Volume::default(),
+            unhandled: Volume::default(),
+            tcp: Volume::default(),
+            udp: Volume::default(),
+            proxied: Volume::default(),
+            direct: Volume::default(),
+            rejected: Volume::default(),
+            stun: Volume::default(),
+            flows: HashSet::new(),
+            destinations: HashMap::new(),
+            flow_rate: (Instant::now(), 0),
+            peak_flow_rate: 0,
+        }
+    }
+
+    /// Sets the routing rules, which route new flows as they do in the `Redirector`.
+    pub fn set_rules(&mut self, rules: Rules) {
+        self.upstreams.set_rules(&rules);
+        self.rules = rules;
+    }
+
+    /// Sets the hardware address of the real gateway, which new flows are reported bridged to
+    /// when the fallback policy bridges them.
+    pub fn set_gateway_hardware_addr(&mut self, hardware_addr: HardwareAddr) {
+        self.gateway_hardware_addr = Some(hardware_addr);
+    }
+
+    /// Observes an `Interface` for the given duration.
+    pub fn open(&mut self, rx: &mut Receiver, duration: Duration) -> io::Result<()> {
+        self.start = Instant::now();
+        info!("Observe for {} s", duration.as_secs());
+        while self.start.elapsed() < duration {
+            match rx.next() {
+                Ok(frame) => match Indicator::from(frame) {
+                    Some(ref indicator) => {
+                        self.handle(indicator, &frame[indicator.get_size()..], frame.len())
+                    }
+                    None => self.other.add(frame.len(), false),
+                },
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::TimedOut {
+                        thread::sleep(Duration::from_millis(TIMEDOUT_WAIT));
+                        continue;
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        self.elapsed = self.start.elapsed();
+
+        Ok(())
+    }
+
+    fn handle(&mut self, indicator: &Indicator, payload: &[u8], size: usize) {
+        let ipv4 = match indicator.get_ipv4() {
+            Some(ipv4) if ipv4.get_src() == self.src_ip_addr => ipv4,
+            _ => {
+                self.other.add(size, false);
+                return;
+            }
+        };
+
+        let (protocol, src_port, dst, is_syn) = match indicator.get_transport_type() {
+            Some(LayerTypes::Tcp) => {
+                let tcp = indicator.get_tcp().unwrap();
+                (
+                    Protocol::Tcp,
+                    tcp.get_src(),
+                    SocketAddrV4::new(tcp.get_dst_ip_addr(), tcp.get_dst()),
+                    tcp.is_syn(),
+                )
+            }
+            Some(LayerTypes::Udp) => {
+                let udp = indicator.get_udp().unwrap();
+                (
+                    Protocol::Udp,
+                    udp.get_src(),
+                    SocketAddrV4::new(udp.get_dst_ip_addr(), udp.get_dst()),
+                    false,
+                )
+            }
+            // ICMP, fragments and other protocols
+            _ => {
+                debug!("observe unhandled {}", ipv4);
+                self.unhandled.add(size, false);
+                return;
+            }
+        };
+
+        // New flows, TCP flows are new on SYN as the `Redirector` connects
+        let key = (protocol, src_port, dst);
+        let is_new = match protocol {
+            Protocol::Tcp => is_syn && self.flows.insert(key),
+            Protocol::Udp => self.flows.insert(key),
+        };
+        if is_new {
+            if self.flow_rate.0.elapsed().as_secs() >= 1 {
+                self.flow_rate = (Instant::now(), 0);
+            }
+            self.flow_rate.1 += 1;
+            if self.flow_rate.1 > self.peak_flow_rate {
+                self.peak_flow_rate = self.flow_rate.1;
+            }
+        }
+
+        match protocol {
+            Protocol::Tcp => self.tcp.add(size, is_new),
+            Protocol::Udp => {
+                self.udp.add(size, is_new);
+                if stun::is_stun(payload) {
+                    self.stun.add(size, is_new);
+                }
+            }
+        }
+
+        // Route
+        let upstreams = &mut self.upstreams;
+        let rules = &self.rules;
+        let gateway_hardware_addr = self.gateway_hardware_addr;
+        let entry = self.destinations.entry((protocol, dst)).or_insert_with(|| {
+            (
+                Volume::default(),
+                rules.route(upstreams, gateway_hardware_addr, dst),
+            )
+        });
+        entry.0.add(size, is_new);
+        match entry.1 {
+            Route::Proxy(_) => self.proxied.add(size, is_new),
+            Route::Direct | Route::Bridge | Route::Local(_) => self.direct.add(size, is_new),
+            Route::Reject => self.rejected.add(size, is_new),
+        }
+    }
+
+    fn get_top_destinations(&self) -> Vec<(&Destination, &(Volume, Route))> {
+        let mut destinations: Vec<_> = self.destinations.iter().collect();
+        destinations.sort_by_key(|destination| Reverse((destination.1).0.bytes));
+        destinations.truncate(TOP_DESTINATIONS);
+
+        destinations
+    }
+
+    fn get_total_bytes(&self) -> usize {
+        self.other.bytes + self.unhandled.bytes + self.tcp.bytes + self.udp.bytes
+    }
+
+    fn get_flow_rate(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs <= 0.0 {
+            return 0.0;
+        }
+
+        (self.tcp.flows + self.udp.flows) as f64 / secs
+    }
+
+    /// Get the report in text.
+    pub fn report(&self) -> String {
+        let total = self.get_total_bytes();
+        let percent = |bytes: usize| {
+            if total == 0 {
+                0.0
+            } else {
+                bytes as f64 * 100.0 / total as f64
+            }
+        };
+
+        let mut s = String::new();
+        let _ = writeln!(
+            s,
+            "Observed {} for {} s, {} Bytes in total",
+            self.src_ip_addr,
+            self.elapsed.as_secs(),
+            total
+        );
+        let _ = writeln!(s, "Traffic:");
+        for (name, volume) in &[
+            ("proxied", self.proxied),
+            ("direct", self.direct),
+            ("rejected", self.rejected),
+            ("unhandled", self.unhandled),
+            ("other hosts", self.other),
+        ] {
+            let _ = writeln!(
+                s,
+                "    {:<12} {:>6.2}%  {} Bytes, {} packets, {} flows",
+                name,
+                percent(volume.bytes),
+                volume.bytes,
+                volume.packets,
+                volume.flows
+            );
+        }
+        let _ = writeln!(s, "Protocols:");
+        for (name, volume) in &[("TCP", self.tcp), ("UDP", self.udp), ("STUN", self.stun)] {
+            let _ = writeln!(
+                s,
+                "    {:<12} {:>6.2}%  {} Bytes, {} packets, {} flows",
+                name,
+                percent(volume.bytes),
+                volume.bytes,
+                volume.packets,
+                volume.flows
+            );
+        }
+        let _ = writeln!(
+            s,
+            "New flows: {:.2} per second, {} at peak",
+            self.get_flow_rate(),
+            self.peak_flow_rate
+        );
+        if self.stun.flows > 0 {
+            let _ = writeln!(
+                s,
+                "STUN is in use, run `pcap2socks nat-test` to see the NAT type through the proxy"
+            );
+        }
+        let _ = writeln!(s, "Top destinations:");
+        for ((protocol, dst), (volume, route)) in self.get_top_destinations() {
+            let _ = writeln!(
+                s,
+                "    {} {:<21} {} Bytes, {} flows, {}",
+                protocol, dst, volume.bytes, volume.flows, route
+            );
+        }
+
+        s
+    }
+
+    /// Get the report in JSON.
+    pub fn report_json(&self) -> String {
+        let destinations: Vec<String> = self
+            .get_top_destinations()
+            .iter()
+            .map(|((protocol, dst), (volume, route))| {
+                format!(
+                    "{{\"protocol\": \"{}\", \"destination\": \"{}\", \"route\": \"{}\", \"volume\": {}}}",
+                    protocol,
+                    dst,
+                    route,
+                    volume.to_json()
+                )
+            })
+            .collect();
+
+        format!(
+            "{{\"source\": \"{}\", \"seconds\": {}, \"bytes\": {}, \"proxied\": {}, \"direct\": {}, \"rejected\": {}, \"unhandled\": {}, \"other\": {}, \"tcp\": {}, \"udp\": {}, \"stun\": {}, \"flow_rate\": {:.2}, \"peak_flow_rate\": {}, \"destinations\": [{}]}}\n",
+            self.src_ip_addr,
+            self.elapsed.as_secs(),
+            self.get_total_bytes(),
+            self.proxied.to_json(),
+            self.direct.to_json(),
+            self.rejected.to_json(),
+            self.unhandled.to_json(),
+            self.other.to_json(),
+            self.tcp.to_json(),
+            self.udp.to_json(),
+            self.stun.to_json(),
+            self.get_flow_rate(),
+            self.peak_flow_rate,
+            destinations.join(", ")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rule::Rule;
+    use crate::schedule::Schedule;
+    use crate::testing::{self, DST_IP_ADDR, SRC_IP_ADDR};
+    use crate::upstream::{Fallback, Policy};
+    use std::net::SocketAddr;
+
+    fn upstream() -> SocketAddr {
+        SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 1080)
+    }
+
+    fn create_observer() -> Observer {
+        let upstreams = Upstreams::new(vec![upstream()], Policy::First, Fallback::Direct).unwrap();
+        let mut observer = Observer::new(SRC_IP_ADDR, upstreams);
+        observer.set_rules(Rules::new(vec![
+            Rule::parse("updates dst=192.0.2.0/24 ports=80-80 route=direct").unwrap(),
+            Rule::parse("games ports=3074 route=proxy").unwrap(),
+        ]));
+
+        observer
+    }
+
+    fn observe(observer: &mut Observer, frame: &[u8]) {
+        let indicator = Indicator::from(frame).unwrap();
+        observer.handle(&indicator, &frame[indicator.get_size()..], frame.len());
+    }
+
+    fn get_route(observer: &Observer, protocol: Protocol, dst_port: u16) -> Route {
+        observer.destinations[&(protocol, SocketAddrV4::new(DST_IP_ADDR, dst_port))].1
+    }
+
+    #[test]
+    fn test_observe_rules() {
+        let mut observer = create_observer();
+        observe(&mut observer, &testing::tcp_frame(50000, 443));
+        observe(&mut observer, &testing::tcp_frame(50001, 80));
+        observe(&mut observer, &testing::udp_frame(50002, 3074, b"hello"));
+
+        assert_eq!(
+            get_route(&observer, Protocol::Tcp, 443),
+            Route::Proxy(upstream())
+        );
+        assert_eq!(get_route(&observer, Protocol::Tcp, 80), Route::Direct);
+        assert_eq!(
+            get_route(&observer, Protocol::Udp, 3074),
+            Route::Proxy(upstream())
+        );
+        assert_eq!(observer.proxied.flows, 2);
+        assert_eq!(observer.direct.flows, 1);
+    }
+
+    #[test]
+    fn test_observe_rules_outside_schedule() {
+        let mut observer = create_observer();
+        observer.upstreams.set_schedule(Schedule::new(Vec::new()));
+        observe(&mut observer, &testing::tcp_frame(50000, 443));
+        observe(&mut observer, &testing::udp_frame(50001, 3074, b"hello"));
+
+        // Flows admitted by no rule are direct outside the schedule, unlike the ones of the rule
+        assert_eq!(get_route(&observer, Protocol::Tcp, 443), Route::Direct);
+        assert_eq!(
+            get_route(&observer, Protocol::Udp, 3074),
+            Route::Proxy(upstream())
+        );
+        assert_eq!(observer.proxied.flows, 1);
+        assert_eq!(observer.direct.flows, 1);
+    }
+}
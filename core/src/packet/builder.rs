@@ -0,0 +1,485 @@
+use pnet::packet::ethernet::EtherTypes;
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use std::cmp::max;
+use std::io;
+use std::net::Ipv4Addr;
+
+use super::layer::arp::Arp;
+use super::layer::ethernet::Ethernet;
+use super::layer::icmpv4::Icmpv4;
+use super::layer::ipv4::Ipv4;
+use super::layer::tcp::Tcp;
+use super::layer::udp::Udp;
+use super::layer::{Layer, LayerType, LayerTypes, Layers};
+use super::Indicator;
+use crate::pcap::HardwareAddr;
+
+/// Represents the minimum packet size.
+/// Because all traffic is in Ethernet, and the 802.3 specifies the minimum is 64 Bytes.
+/// Exclude the 4 bytes used in FCS, the minimum packet size in pcap2socks is 60 Bytes.
+pub const MINIMUM_PACKET_SIZE: usize = 60;
+
+/// Represents the size of an IPv4 header without options.
+pub const IPV4_HEADER_SIZE: usize = 20;
+
+/// Represents the size of a TCP header without options.
+pub const TCP_HEADER_SIZE: usize = 20;
+
+/// Represents the size of an UDP header.
+pub const UDP_HEADER_SIZE: usize = 8;
+
+/// Represents the TTL of packets never leaving the link, like IGMP messages.
+const LINK_LOCAL_TTL: u8 = 1;
+
+/// Represents a builder of the frames sent to the source. The layers are set from the link to
+/// the transport, and the lengths and the checksums of the layers are computed when the frame is
+/// built.
+#[derive(Clone, Debug)]
+pub struct Builder {
+    ethernet: Ethernet,
+    network: Option<Layers>,
+    transport: Option<Layers>,
+}
+
+impl Builder {
+    /// Creates a new `Builder` of a frame from and to the given hardware addresses.
+    pub fn new(src: HardwareAddr, dst: HardwareAddr) -> Builder {
+        Builder {
+            ethernet: Ethernet::new(LayerTypes::Ipv4, src, dst).unwrap(),
+            network: None,
+            transport: None,
+        }
+    }
+
+    /// Sets if the frame is in IEEE 802.3 with LLC and SNAP framing instead of Ethernet II.
+    pub fn set_llc_snap(&mut self, is_llc_snap: bool) {
+        self.ethernet.set_llc_snap(is_llc_snap);
+    }
+
+    /// Sets an ARP reply of the given IP address at the source hardware address of the frame,
+    /// to the given hardware and IP addresses.
+    pub fn set_arp_reply(
+        &mut self,
+        src_ip_addr: Ipv4Addr,
+        dst_hardware_addr: HardwareAddr,
+        dst_ip_addr: Ipv4Addr,
+    ) {
+        self.ethernet.layer.ethertype = EtherTypes::Arp;
+        self.network = Some(Layers::Arp(Arp::new_reply(
+            self.ethernet.get_src(),
+            src_ip_addr,
+            dst_hardware_addr,
+            dst_ip_addr,
+        )));
+        self.transport = None;
+    }
+
+    /// Sets an IPv4 packet of the given identification, source and destination. Its protocol
+    /// follows the transport layer set after.
+    pub fn set_ipv4(&mut self, identification: u16, src: Ipv4Addr, dst: Ipv4Addr) {
+        self.ethernet.layer.ethertype = EtherTypes::Ipv4;
+        self.network = Some(Layers::Ipv4(Ipv4::new(identification, src, dst)));
+        self.transport = None;
+    }
+
+    /// Sets the TTL of the IPv4 packet.
+    pub fn set_ttl(&mut self, ttl: u8) {
+        if let Some(ipv4) = self.get_ipv4_mut() {
+            ipv4.set_ttl(ttl);
+        }
+    }
+
+    /// Sets the IPv4 packet as a fragment of a datagram of the given type at the given offset in
+    /// 8 Bytes. The payload is the fragment, which has no transport layer of its own.
+    pub fn set_fragment(&mut self, t: LayerType, fragment_offset: u16, is_more_fragment: bool) {
+        if let Some(ipv4) = self.get_ipv4_mut() {
+            if let Some(protocol) = get_protocol(t) {
+                ipv4.set_next_level_protocol(protocol);
+            }
+            ipv4.set_fragment(fragment_offset, is_more_fragment);
+        }
+        self.transport = None;
+    }
+
+    /// Sets the IPv4 packet as an IGMP message, which never leaves the link. The payload is the
+    /// message.
+    pub fn set_igmp(&mut self) {
+        if let Some(ipv4) = self.get_ipv4_mut() {
+            ipv4.set_next_level_protocol(IpNextHeaderProtocols::Igmp);
+            ipv4.set_ttl(LINK_LOCAL_TTL);
+        }
+        self.transport = None;
+    }
+
+    /// Sets a TCP segment of the given flags in the IPv4 packet.
+    #[allow(clippy::too_many_arguments)]
+    pub fn set_tcp(
+        &mut self,
+        src: u16,
+        dst: u16,
+        sequence: u32,
+        acknowledgement: u32,
+        window: u16,
+        flags: u16,
+    ) {
+        let (src_ip_addr, dst_ip_addr) = self.get_ip_addrs(LayerTypes::Tcp);
+        self.transport = Some(Layers::Tcp(Tcp::new(
+            src_ip_addr,
+            dst_ip_addr,
+            src,
+            dst,
+            sequence,
+            acknowledgement,
+            window,
+            flags,
+        )));
+    }
+
+    /// Sets the maximum segment size option of the TCP segment.
+    pub fn set_mss(&mut self, mss: u16) {
+        if let Some(Layers::Tcp(ref mut tcp)) = self.transport {
+            tcp.set_mss(mss);
+        }
+    }
+
+    /// Sets the window scale option of the TCP segment.
+    pub fn set_wscale(&mut self, shift: u8) {
+        if let Some(Layers::Tcp(ref mut tcp)) = self.transport {
+            tcp.set_wscale(shift);
+        }
+    }
+
+    /// Sets an UDP datagram in the IPv4 packet.
+    pub fn set_udp(&mut self, src: u16, dst: u16) {
+        let (src_ip_addr, dst_ip_addr) = self.get_ip_addrs(LayerTypes::Udp);
+        self.transport = Some(Layers::Udp(Udp::new(src_ip_addr, dst_ip_addr, src, dst)));
+    }
+
+    /// Sets an ICMPv4 echo reply in the IPv4 packet. The payload follows the type and the code.
+    pub fn set_icmpv4_echo_reply(&mut self) {
+        let (src_ip_addr, dst_ip_addr) = self.get_ip_addrs(LayerTypes::Icmpv4);
+        self.transport = Some(Layers::Icmpv4(Icmpv4::new_echo_reply(
+            src_ip_addr,
+            dst_ip_addr,
+        )));
+    }
+
+    /// Sets an ICMPv4 port unreachable in the IPv4 packet. The payload follows the type and the
+    /// code.
+    pub fn set_icmpv4_port_unreachable(&mut self) {
+        let (src_ip_addr, dst_ip_addr) = self.get_ip_addrs(LayerTypes::Icmpv4);
+        self.transport = Some(Layers::Icmpv4(Icmpv4::new_port_unreachable(
+            src_ip_addr,
+            dst_ip_addr,
+        )));
+    }
+
+    fn get_ipv4_mut(&mut self) -> Option<&mut Ipv4> {
+        match self.network {
+            Some(Layers::Ipv4(ref mut ipv4)) => Some(ipv4),
+            _ => None,
+        }
+    }
+
+    /// Get the source and the destination of the IPv4 packet, whose protocol is set to the given
+    /// transport layer.
+    fn get_ip_addrs(&mut self, t: LayerType) -> (Ipv4Addr, Ipv4Addr) {
+        match self.get_ipv4_mut() {
+            Some(ipv4) => {
+                if let Some(protocol) = get_protocol(t) {
+                    ipv4.set_next_level_protocol(protocol);
+                }
+                (ipv4.get_src(), ipv4.get_dst())
+            }
+            None => (Ipv4Addr::UNSPECIFIED, Ipv4Addr::UNSPECIFIED),
+        }
+    }
+
+    /// Get the size of the headers of the frame.
+    pub fn get_size(&self) -> usize {
+        let mut size = self.ethernet.get_size();
+        if let Some(ref network) = self.network {
+            size += network.get_size();
+        }
+        if let Some(ref transport) = self.transport {
+            size += transport.get_size();
+        }
+
+        size
+    }
+
+    /// Get the size of the IP packet of the frame with a payload of the given size.
+    pub fn get_ip_size(&self, payload_size: usize) -> usize {
+        self.get_size() - self.ethernet.get_size() + payload_size
+    }
+
+    /// Get the brief of the frame.
+    pub fn brief(&self) -> String {
+        Indicator::new(
+            Layers::Ethernet(self.ethernet.clone()),
+            self.network.clone(),
+            self.transport.clone(),
+        )
+        .brief()
+    }
+
+    /// Builds the frame with the given payload, padded to the minimum packet size.
+    pub fn build(&self, payload: &[u8]) -> io::Result<Vec<u8>> {
+        let size = self.get_size() + payload.len();
+        let mut buffer = vec![0u8; max(size, MINIMUM_PACKET_SIZE)];
+
+        let mut begin = 0;
+        let mut total = size;
+        let m = self
+            .ethernet
+            .serialize_with_payload(&mut buffer[..size], payload, total)?;
+        begin += m;
+        total -= m;
+        if let Some(ref network) = self.network {
+            let m = network.serialize_with_payload(&mut buffer[begin..size], payload, total)?;
+            begin += m;
+            total -= m;
+        }
+        match self.transport {
+            Some(ref transport) => {
+                transport.serialize_with_payload(&mut buffer[begin..size], payload, total)?;
+            }
+            // Copies the payload of a network layer without transport layer
+            None => buffer[begin..size].copy_from_slice(payload),
+        }
+
+        Ok(buffer)
+    }
+
+    /// Builds the transport layer with the given payload without the link and the network
+    /// layers, like a datagram sent in fragments.
+    pub fn build_transport(&self, payload: &[u8]) -> io::Result<Vec<u8>> {
+        let transport = match self.transport {
+            Some(ref transport) => transport,
+            None => return Ok(payload.to_vec()),
+        };
+        let size = transport.get_size() + payload.len();
+        let mut buffer = vec![0u8; size];
+        transport.serialize_with_payload(&mut buffer, payload, size)?;
+
+        Ok(buffer)
+    }
+}
+
+/// Get the IP protocol of the given transport layer.
+fn get_protocol(t: LayerType) -> Option<IpNextHeaderProtocol> {
+    match t {
+        LayerTypes::Tcp => Some(IpNextHeaderProtocols::Tcp),
+        LayerTypes::Udp => Some(IpNextHeaderProtocols::Udp),
+        LayerTypes::Icmpv4 => Some(IpNextHeaderProtocols::Icmp),
+        _ => None,
+    }
+}
+
+/// Computes the Internet checksum of the data, skipping the checksum field at the given 16-bit
+/// word. Data of an odd length is padded with a zero byte.
+pub fn checksum(data: &[u8], skipword: usize) -> u16 {
+    finalize(sum(data, skipword))
+}
+
+/// Computes the checksum of a transport layer over IPv4, which also covers the pseudo-header of
+/// the source, the destination, the protocol and the length. An UDP checksum computed as 0 is
+/// transmitted as all ones, because 0 means no checksum.
+pub fn ipv4_checksum(
+    data: &[u8],
+    skipword: usize,
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    protocol: IpNextHeaderProtocol,
+) -> u16 {
+    let mut pseudo_header = [0u8; 12];
+    pseudo_header[..4].copy_from_slice(&src.octets());
+    pseudo_header[4..8].copy_from_slice(&dst.octets());
+    pseudo_header[9] = protocol.0;
+    pseudo_header[10..].copy_from_slice(&(data.len() as u16).to_be_bytes());
+
+    let checksum = finalize(sum(&pseudo_header, usize::MAX) + sum(data, skipword));
+    if checksum == 0 && protocol == IpNextHeaderProtocols::Udp {
+        0xffff
+    } else {
+        checksum
+    }
+}
+
+/// Sums the 16-bit big-endian words of the data, skipping the word at the given index. The last
+/// byte of data of an odd length is the high byte of its word.
+fn sum(data: &[u8], skipword: usize) -> u32 {
+    data.chunks(2)
+        .enumerate()
+        .filter(|(i, _)| *i != skipword)
+        .map(|(_, word)| match *word {
+            [high, low] => u16::from_be_bytes([high, low]) as u32,
+            [high] => (high as u32) << 8,
+            _ => 0,
+        })
+        .sum()
+}
+
+/// Folds the carries of the sum and complements it.
+fn finalize(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum >> 16) + (sum & 0xffff);
+    }
+
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet::datalink::MacAddr;
+    use pnet::packet::tcp::TcpFlags;
+
+    const SRC_HARDWARE_ADDR: HardwareAddr = MacAddr(2, 0, 0, 0, 0, 1);
+    const DST_HARDWARE_ADDR: HardwareAddr = MacAddr(2, 0, 0, 0, 0, 2);
+    const SRC_IP_ADDR: Ipv4Addr = Ipv4Addr::new(1, 1, 1, 1);
+    const DST_IP_ADDR: Ipv4Addr = Ipv4Addr::new(10, 6, 0, 2);
+
+    // ARP reply of 10.6.0.1 to 10.6.0.2, padded to 60 bytes
+    const ARP_REPLY: [u8; 60] = [
+        0x02, 0x00, 0x00, 0x00, 0x00, 0x02, 0x02, 0x00, 0x00, 0x00, 0x00, 0x01, 0x08, 0x06, 0x00,
+        0x01, 0x08, 0x00, 0x06, 0x04, 0x00, 0x02, 0x02, 0x00, 0x00, 0x00, 0x00, 0x01, 0x0a, 0x06,
+        0x00, 0x01, 0x02, 0x00, 0x00, 0x00, 0x00, 0x02, 0x0a, 0x06, 0x00, 0x02, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+    // TCP ACK/SYN 1.1.1.1:80 -> 10.6.0.2:50000 with MSS 1460 and window scale 7
+    const TCP_ACK_SYN: [u8; 62] = [
+        0x02, 0x00, 0x00, 0x00, 0x00, 0x02, 0x02, 0x00, 0x00, 0x00, 0x00, 0x01, 0x08, 0x00, 0x45,
+        0x00, 0x00, 0x30, 0x00, 0x01, 0x00, 0x00, 0x80, 0x06, 0x2e, 0xbe, 0x01, 0x01, 0x01, 0x01,
+        0x0a, 0x06, 0x00, 0x02, 0x00, 0x50, 0xc3, 0x50, 0x00, 0x00, 0x03, 0xe8, 0x00, 0x00, 0x07,
+        0xd1, 0x70, 0x12, 0xff, 0xff, 0xa8, 0xa5, 0x00, 0x00, 0x02, 0x04, 0x05, 0xb4, 0x01, 0x03,
+        0x03, 0x07,
+    ];
+    // UDP 1.1.1.1:53 -> 10.6.0.2:50000 of 3 bytes, padded to 60 bytes
+    const UDP_ODD: [u8; 60] = [
+        0x02, 0x00, 0x00, 0x00, 0x00, 0x02, 0x02, 0x00, 0x00, 0x00, 0x00, 0x01, 0x08, 0x00, 0x45,
+        0x00, 0x00, 0x1f, 0x00, 0x02, 0x00, 0x00, 0x80, 0x11, 0x2e, 0xc3, 0x01, 0x01, 0x01, 0x01,
+        0x0a, 0x06, 0x00, 0x02, 0x00, 0x35, 0xc3, 0x50, 0x00, 0x0b, 0x5c, 0xe4, 0x6f, 0x64, 0x64,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+    // UDP fragment of 9 bytes at 1480 bytes followed by more fragments, padded to 60 bytes
+    const UDP_FRAGMENT: [u8; 60] = [
+        0x02, 0x00, 0x00, 0x00, 0x00, 0x02, 0x02, 0x00, 0x00, 0x00, 0x00, 0x01, 0x08, 0x00, 0x45,
+        0x00, 0x00, 0x1d, 0x00, 0x03, 0x20, 0xb9, 0x80, 0x11, 0x0e, 0x0b, 0x01, 0x01, 0x01, 0x01,
+        0x0a, 0x06, 0x00, 0x02, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+    // ICMPv4 echo reply of 1.1.1.1 with TTL 64 and 5 bytes, padded to 60 bytes
+    const ICMPV4_ECHO_REPLY: [u8; 60] = [
+        0x02, 0x00, 0x00, 0x00, 0x00, 0x02, 0x02, 0x00, 0x00, 0x00, 0x00, 0x01, 0x08, 0x00, 0x45,
+        0x00, 0x00, 0x1d, 0x00, 0x04, 0x00, 0x00, 0x40, 0x01, 0x6e, 0xd3, 0x01, 0x01, 0x01, 0x01,
+        0x0a, 0x06, 0x00, 0x02, 0x00, 0x00, 0x7d, 0xca, 0x12, 0x34, 0x00, 0x01, 0x70, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    fn new_ipv4_builder(identification: u16) -> Builder {
+        let mut builder = Builder::new(SRC_HARDWARE_ADDR, DST_HARDWARE_ADDR);
+        builder.set_ipv4(identification, SRC_IP_ADDR, DST_IP_ADDR);
+
+        builder
+    }
+
+    #[test]
+    fn test_checksum() {
+        // The odd byte is the high byte of the last word
+        assert_eq!(checksum(&[0x01, 0x02, 0x03], usize::MAX), 0xfbfd);
+        assert_eq!(checksum(&[0x01, 0x02, 0x03], 1), 0xfefd);
+        assert_eq!(checksum(&[], usize::MAX), 0xffff);
+    }
+
+    #[test]
+    fn test_build_arp_reply() {
+        let mut builder = Builder::new(SRC_HARDWARE_ADDR, DST_HARDWARE_ADDR);
+        builder.set_arp_reply(Ipv4Addr::new(10, 6, 0, 1), DST_HARDWARE_ADDR, DST_IP_ADDR);
+        assert_eq!(builder.build(&[]).unwrap()[..], ARP_REPLY[..]);
+    }
+
+    #[test]
+    fn test_build_tcp_options() {
+        let mut builder = new_ipv4_builder(1);
+        builder.set_tcp(80, 50000, 1000, 2001, 65535, TcpFlags::ACK | TcpFlags::SYN);
+        builder.set_mss(1460);
+        builder.set_wscale(7);
+        assert_eq!(builder.get_size(), TCP_ACK_SYN.len());
+        assert_eq!(builder.build(&[]).unwrap()[..], TCP_ACK_SYN[..]);
+    }
+
+    #[test]
+    fn test_build_udp_odd_payload() {
+        let mut builder = new_ipv4_builder(2);
+        builder.set_udp(53, 50000);
+        assert_eq!(builder.get_ip_size(3), 31);
+        let frame = builder.build(b"odd").unwrap();
+        assert_eq!(frame[..], UDP_ODD[..]);
+
+        let indicator = Indicator::from(&frame).unwrap();
+        assert!(indicator.validate(&frame[..45], true).is_ok());
+    }
+
+    #[test]
+    fn test_build_udp_zero_checksum() {
+        // The payload sums the datagram to a checksum of 0, which is sent as all ones
+        let mut builder = new_ipv4_builder(0);
+        builder.set_udp(53, 50000);
+        let frame = builder.build(&[0x30, 0x4b]).unwrap();
+        assert_eq!(frame[40..42], [0xff, 0xff]);
+
+        let indicator = Indicator::from(&frame).unwrap();
+        assert!(indicator.validate(&frame[..44], true).is_ok());
+    }
+
+    #[test]
+    fn test_build_fragment() {
+        let mut builder = new_ipv4_builder(3);
+        builder.set_fragment(LayerTypes::Udp, 185, true);
+        let payload: Vec<u8> = (0..9).collect();
+        assert_eq!(builder.build(&payload).unwrap()[..], UDP_FRAGMENT[..]);
+
+        // The last fragment clears the more fragments flag
+        builder.set_fragment(LayerTypes::Udp, 185, false);
+        let frame = builder.build(&payload).unwrap();
+        assert_eq!(frame[20..22], [0x00, 0xb9]);
+        let indicator = Indicator::from(&frame).unwrap();
+        let ipv4 = indicator.get_ipv4().unwrap();
+        assert!(ipv4.is_fragment() && !ipv4.is_more_fragment());
+    }
+
+    #[test]
+    fn test_build_icmpv4_odd_payload() {
+        let mut builder = new_ipv4_builder(4);
+        builder.set_ttl(64);
+        builder.set_icmpv4_echo_reply();
+        let frame = builder.build(&[0x12, 0x34, 0x00, 0x01, 0x70]).unwrap();
+        assert_eq!(frame[..], ICMPV4_ECHO_REPLY[..]);
+    }
+
+    #[test]
+    fn test_build_igmp() {
+        let mut builder = new_ipv4_builder(5);
+        builder.set_igmp();
+        let message = [0x11, 0x64, 0xee, 0x9b, 0x00, 0x00, 0x00, 0x00];
+        let frame = builder.build(&message).unwrap();
+        // TTL and protocol
+        assert_eq!(frame[22..24], [1, 2]);
+        assert_eq!(
+            checksum(&frame[14..34], 5),
+            u16::from_be_bytes([frame[24], frame[25]])
+        );
+        assert_eq!(frame[34..42], message);
+    }
+
+    #[test]
+    fn test_build_transport() {
+        let mut builder = new_ipv4_builder(2);
+        builder.set_udp(53, 50000);
+        assert_eq!(
+            builder.build_transport(b"odd").unwrap()[..],
+            UDP_ODD[34..45]
+        );
+    }
+}
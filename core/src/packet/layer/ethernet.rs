@@ -0,0 +1,176 @@
+use super::{Layer, LayerType, LayerTypes};
+use pnet::packet::ethernet::{self, EtherType, EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::{MutablePacket, Packet};
+use pnet::util::MacAddr;
+use std::clone::Clone;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+
+/// Represents the size of the LLC and SNAP headers.
+pub const LLC_SNAP_SIZE: usize = 8;
+
+/// Represents the LLC header of a SNAP frame, DSAP, SSAP and control, followed by the SNAP
+/// header with a zero OUI.
+const LLC_SNAP_HEADER: [u8; 6] = [0xaa, 0xaa, 0x03, 0x00, 0x00, 0x00];
+
+/// Represents the min value of the EtherType field, smaller values are the length of IEEE 802.3
+/// frames.
+const MIN_ETHERTYPE: u16 = 1536;
+
+/// Represents an Ethernet layer, in Ethernet II or IEEE 802.3 with LLC and SNAP framing.
+#[derive(Clone, Debug)]
+pub struct Ethernet {
+    pub layer: ethernet::Ethernet,
+    is_llc_snap: bool,
+}
+
+impl Ethernet {
+    /// Creates an `Ethernet`.
+    pub fn new(t: LayerType, src: MacAddr, dst: MacAddr) -> Option<Ethernet> {
+        match t {
+            LayerTypes::Arp => Some(Ethernet {
+                layer: ethernet::Ethernet {
+                    destination: dst,
+                    source: src,
+                    ethertype: EtherTypes::Arp,
+                    payload: vec![],
+                },
+                is_llc_snap: false,
+            }),
+            LayerTypes::Ipv4 => Some(Ethernet {
+                layer: ethernet::Ethernet {
+                    destination: dst,
+                    source: src,
+                    ethertype: EtherTypes::Ipv4,
+                    payload: vec![],
+                },
+                is_llc_snap: false,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Creates an `Ethernet` according to the given `Ethernet`.
+    pub fn from(ethernet: ethernet::Ethernet) -> Ethernet {
+        Ethernet {
+            layer: ethernet,
+            is_llc_snap: false,
+        }
+    }
+
+    /// Creates an `Ethernet` according to the given Ethernet packet. The EtherType of an IEEE
+    /// 802.3 frame with LLC and SNAP headers is recovered from the SNAP header.
+    pub fn parse(packet: &EthernetPacket) -> Ethernet {
+        let mut ethertype = packet.get_ethertype();
+        let mut is_llc_snap = false;
+        if ethertype.0 < MIN_ETHERTYPE {
+            let payload = packet.payload();
+            if payload.len() >= LLC_SNAP_SIZE && payload[..LLC_SNAP_HEADER.len()] == LLC_SNAP_HEADER
+            {
+                ethertype = EtherType::new(u16::from_be_bytes([payload[6], payload[7]]));
+                is_llc_snap = true;
+            }
+        }
+
+        Ethernet {
+            layer: ethernet::Ethernet {
+                destination: packet.get_destination(),
+                source: packet.get_source(),
+                ethertype,
+                payload: vec![],
+            },
+            is_llc_snap,
+        }
+    }
+
+    /// Get the payload of the given Ethernet packet after the LLC and SNAP headers if any.
+    pub fn get_payload<'a>(&self, packet: &'a EthernetPacket) -> &'a [u8] {
+        let payload = packet.payload();
+        if self.is_llc_snap {
+            return &payload[LLC_SNAP_SIZE..];
+        }
+
+        payload
+    }
+
+    /// Get the source of the layer.
+    pub fn get_src(&self) -> MacAddr {
+        self.layer.source
+    }
+
+    /// Get the destination of the layer.
+    pub fn get_dst(&self) -> MacAddr {
+        self.layer.destination
+    }
+
+    /// Get the EtherType of the layer.
+    pub fn get_ethertype(&self) -> EtherType {
+        self.layer.ethertype
+    }
+
+    /// Sets if the layer uses IEEE 802.3 with LLC and SNAP framing.
+    pub fn set_llc_snap(&mut self, is_llc_snap: bool) {
+        self.is_llc_snap = is_llc_snap;
+    }
+
+    /// Returns if the layer uses IEEE 802.3 with LLC and SNAP framing.
+    pub fn is_llc_snap(&self) -> bool {
+        self.is_llc_snap
+    }
+}
+
+impl Display for Ethernet {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} -> {}{}",
+            LayerTypes::Ethernet,
+            self.layer.source,
+            self.layer.destination,
+            if self.is_llc_snap { " (LLC/SNAP)" } else { "" }
+        )
+    }
+}
+
+impl Layer for Ethernet {
+    fn get_type(&self) -> LayerType {
+        LayerTypes::Ethernet
+    }
+
+    fn get_size(&self) -> usize {
+        let size = EthernetPacket::packet_size(&self.layer);
+        if self.is_llc_snap {
+            return size + LLC_SNAP_SIZE;
+        }
+
+        size
+    }
+
+    fn serialize(&self, buffer: &mut [u8], n: usize) -> io::Result<usize> {
+        if self.is_llc_snap && buffer.len() < self.get_size() {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "buffer too small"));
+        }
+        let mut packet = MutableEthernetPacket::new(buffer)
+            .ok_or(io::Error::new(io::ErrorKind::WriteZero, "buffer too small"))?;
+
+        packet.populate(&self.layer);
+
+        if self.is_llc_snap {
+            // The length of the frame following the header
+            let length = n - EthernetPacket::packet_size(&self.layer);
+            packet.set_ethertype(EtherType::new(length as u16));
+
+            // LLC and SNAP
+            let payload = packet.payload_mut();
+            payload[..LLC_SNAP_HEADER.len()].copy_from_slice(&LLC_SNAP_HEADER);
+            payload[LLC_SNAP_HEADER.len()..LLC_SNAP_SIZE]
+                .copy_from_slice(&self.layer.ethertype.0.to_be_bytes());
+        }
+
+        Ok(self.get_size())
+    }
+
+    fn serialize_with_payload(&self, buffer: &mut [u8], _: &[u8], n: usize) -> io::Result<usize> {
+        self.serialize(buffer, n)
+    }
+}
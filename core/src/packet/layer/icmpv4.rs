@@ -0,0 +1,145 @@
+use super::{Layer, LayerType, LayerTypes};
+use crate::packet::builder;
+use pnet::packet::icmp::{self, IcmpCode, IcmpPacket, IcmpType, IcmpTypes, MutableIcmpPacket};
+use pnet::packet::Packet;
+use std::clone::Clone;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::Ipv4Addr;
+
+/// Represents an ICMPv4 packet. The rest of the header is treated as part of the payload.
+#[derive(Clone, Debug)]
+pub struct Icmpv4 {
+    pub layer: icmp::Icmp,
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+}
+
+impl Icmpv4 {
+    /// Creates an `Icmpv4` represents an ICMPv4 echo reply.
+    pub fn new_echo_reply(src_ip_addr: Ipv4Addr, dst_ip_addr: Ipv4Addr) -> Icmpv4 {
+        Icmpv4 {
+            layer: icmp::Icmp {
+                icmp_type: IcmpTypes::EchoReply,
+                icmp_code: IcmpCode::new(0),
+                checksum: 0,
+                payload: vec![],
+            },
+            src: src_ip_addr,
+            dst: dst_ip_addr,
+        }
+    }
+
+    /// Creates an `Icmpv4` represents an ICMPv4 port unreachable. The unused rest of the header
+    /// and the quoted datagram are the payload.
+    pub fn new_port_unreachable(src_ip_addr: Ipv4Addr, dst_ip_addr: Ipv4Addr) -> Icmpv4 {
+        Icmpv4 {
+            layer: icmp::Icmp {
+                icmp_type: IcmpTypes::DestinationUnreachable,
+                icmp_code: IcmpCode::new(3),
+                checksum: 0,
+                payload: vec![],
+            },
+            src: src_ip_addr,
+            dst: dst_ip_addr,
+        }
+    }
+
+    /// Creates an `Icmpv4` according to the given ICMPv4 packet, source and destination.
+    pub fn parse(packet: &IcmpPacket, src: Ipv4Addr, dst: Ipv4Addr) -> Icmpv4 {
+        Icmpv4 {
+            layer: icmp::Icmp {
+                icmp_type: packet.get_icmp_type(),
+                icmp_code: packet.get_icmp_code(),
+                checksum: packet.get_checksum(),
+                payload: vec![],
+            },
+            src,
+            dst,
+        }
+    }
+
+    /// Get the source IP address of the layer.
+    pub fn get_src_ip_addr(&self) -> Ipv4Addr {
+        self.src
+    }
+
+    /// Get the destination IP address of the layer.
+    pub fn get_dst_ip_addr(&self) -> Ipv4Addr {
+        self.dst
+    }
+
+    /// Get the ICMPv4 type of the layer.
+    pub fn get_icmp_type(&self) -> IcmpType {
+        self.layer.icmp_type
+    }
+
+    /// Returns if the `Icmpv4` is an ICMPv4 echo request.
+    pub fn is_echo_request(&self) -> bool {
+        self.layer.icmp_type == IcmpTypes::EchoRequest && self.layer.icmp_code == IcmpCode::new(0)
+    }
+}
+
+impl Display for Icmpv4 {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} -> {}, Type = {}, Code = {}",
+            LayerTypes::Icmpv4,
+            self.src,
+            self.dst,
+            self.layer.icmp_type.0,
+            self.layer.icmp_code.0
+        )
+    }
+}
+
+impl Layer for Icmpv4 {
+    fn get_type(&self) -> LayerType {
+        LayerTypes::Icmpv4
+    }
+
+    fn get_size(&self) -> usize {
+        IcmpPacket::packet_size(&self.layer)
+    }
+
+    fn serialize(&self, buffer: &mut [u8], n: usize) -> io::Result<usize> {
+        if buffer.len() < n {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "buffer too small"));
+        }
+        let mut packet = MutableIcmpPacket::new(&mut buffer[..n])
+            .ok_or_else(|| io::Error::new(io::ErrorKind::WriteZero, "buffer too small"))?;
+
+        packet.populate(&self.layer);
+
+        // Compute checksum
+        let checksum = builder::checksum(packet.packet(), 1);
+        packet.set_checksum(checksum);
+
+        Ok(self.get_size())
+    }
+
+    fn serialize_with_payload(
+        &self,
+        buffer: &mut [u8],
+        payload: &[u8],
+        n: usize,
+    ) -> io::Result<usize> {
+        if buffer.len() < n {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "buffer too small"));
+        }
+        let mut packet = MutableIcmpPacket::new(&mut buffer[..n])
+            .ok_or_else(|| io::Error::new(io::ErrorKind::WriteZero, "buffer too small"))?;
+
+        packet.populate(&self.layer);
+
+        // Copies payload
+        packet.set_payload(payload);
+
+        // Compute checksum
+        let checksum = builder::checksum(packet.packet(), 1);
+        packet.set_checksum(checksum);
+
+        Ok(self.get_size() + payload.len())
+    }
+}
@@ -1,6 +1,8 @@
 use super::{Layer, LayerType, LayerTypes};
-use pnet::packet::ip::IpNextHeaderProtocols;
+use crate::packet::builder;
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
 use pnet::packet::ipv4::{self, Ipv4Flags, Ipv4OptionPacket, Ipv4Packet, MutableIpv4Packet};
+use pnet::packet::Packet;
 use std::clone::Clone;
 use std::fmt::{self, Display, Formatter};
 use std::io;
@@ -13,14 +15,10 @@ pub struct Ipv4 {
 }
 
 impl Ipv4 {
-    /// Creates an `Ipv4`.
-    pub fn new(identification: u16, t: LayerType, src: Ipv4Addr, dst: Ipv4Addr) -> Option<Ipv4> {
-        let next_level_protocol = match t {
-            LayerTypes::Tcp => IpNextHeaderProtocols::Tcp,
-            LayerTypes::Udp => IpNextHeaderProtocols::Udp,
-            _ => return None,
-        };
-        Some(Ipv4 {
+    /// Creates an `Ipv4` of the given identification, source and destination, whose protocol is
+    /// set by `set_next_level_protocol`.
+    pub fn new(identification: u16, src: Ipv4Addr, dst: Ipv4Addr) -> Ipv4 {
+        Ipv4 {
             layer: ipv4::Ipv4 {
                 version: 4,
                 header_length: 5,
@@ -31,49 +29,14 @@ impl Ipv4 {
                 flags: 0,
                 fragment_offset: 0,
                 ttl: 128,
-                next_level_protocol,
+                next_level_protocol: IpNextHeaderProtocols::Reserved,
                 checksum: 0,
                 source: src,
                 destination: dst,
                 options: vec![],
                 payload: vec![],
             },
-        })
-    }
-
-    /// Creates an `Ipv4` represents an IPv4 fragment.
-    pub fn new_more_fragment(
-        identification: u16,
-        t: LayerType,
-        fragment_offset: u16,
-        src: Ipv4Addr,
-        dst: Ipv4Addr,
-    ) -> Option<Ipv4> {
-        let ipv4 = Ipv4::new(identification, t, src, dst);
-        if let Some(mut ipv4) = ipv4 {
-            ipv4.layer.flags = Ipv4Flags::MoreFragments;
-            ipv4.layer.fragment_offset = fragment_offset;
-            return Some(ipv4);
-        };
-
-        None
-    }
-
-    /// Creates an `Ipv4` represents an IPv4 last fragment.
-    pub fn new_last_fragment(
-        identification: u16,
-        t: LayerType,
-        fragment_offset: u16,
-        src: Ipv4Addr,
-        dst: Ipv4Addr,
-    ) -> Option<Ipv4> {
-        let ipv4 = Ipv4::new(identification, t, src, dst);
-        if let Some(mut ipv4) = ipv4 {
-            ipv4.layer.fragment_offset = fragment_offset;
-            return Some(ipv4);
-        };
-
-        None
+        }
     }
 
     /// Creates an `Ipv4` according to the given `Ipv4`.
@@ -127,6 +90,27 @@ impl Ipv4 {
         }
     }
 
+    /// Sets the TTL of the layer.
+    pub fn set_ttl(&mut self, ttl: u8) {
+        self.layer.ttl = ttl;
+    }
+
+    /// Sets the protocol of the payload of the layer.
+    pub fn set_next_level_protocol(&mut self, protocol: IpNextHeaderProtocol) {
+        self.layer.next_level_protocol = protocol;
+    }
+
+    /// Sets the layer as a fragment at the given offset in 8 Bytes, followed by more fragments
+    /// if `is_more_fragment` is set.
+    pub fn set_fragment(&mut self, fragment_offset: u16, is_more_fragment: bool) {
+        self.layer.flags = if is_more_fragment {
+            Ipv4Flags::MoreFragments
+        } else {
+            0
+        };
+        self.layer.fragment_offset = fragment_offset;
+    }
+
     /// Get the total length of the layer.
     pub fn get_total_length(&self) -> u16 {
         self.layer.total_length
@@ -152,6 +136,11 @@ impl Ipv4 {
         self.is_more_fragment() || self.get_fragment_offset() > 0
     }
 
+    /// Returns if the `Ipv4` carries an IGMP message.
+    pub fn is_igmp(&self) -> bool {
+        self.layer.next_level_protocol == IpNextHeaderProtocols::Igmp
+    }
+
     /// Get the source of the layer.
     pub fn get_src(&self) -> Ipv4Addr {
         self.layer.source
@@ -207,7 +196,7 @@ impl Layer for Ipv4 {
         // Fix length
         let header_length = self.get_size();
         if header_length / 4 > u8::MAX as usize {
-            return Err(io::Error::new(io::ErrorKind::Other, "IPv4 too big"));
+            return Err(io::Error::other("IPv4 too big"));
         }
         packet.set_header_length((header_length / 4) as u8);
         if n > u16::MAX as usize {
@@ -219,7 +208,7 @@ impl Layer for Ipv4 {
         packet.set_total_length(n as u16);
 
         // Compute checksum
-        let checksum = ipv4::checksum(&packet.to_immutable());
+        let checksum = builder::checksum(&packet.packet()[..header_length], 5);
         packet.set_checksum(checksum);
 
         Ok(header_length)
@@ -6,6 +6,7 @@ use std::io;
 
 pub mod arp;
 pub mod ethernet;
+pub mod icmpv4;
 pub mod ipv4;
 pub mod tcp;
 pub mod udp;
@@ -25,6 +26,7 @@ impl Display for LayerType {
                 LayerTypes::Ipv4 => "IPv4",
                 LayerTypes::Tcp => "TCP",
                 LayerTypes::Udp => "UDP",
+                LayerTypes::Icmpv4 => "ICMPv4",
                 _ => "unknown",
             }
         )
@@ -46,6 +48,8 @@ pub mod LayerTypes {
     pub const Tcp: LayerType = LayerType(3);
     // UDP
     pub const Udp: LayerType = LayerType(4);
+    // ICMPv4
+    pub const Icmpv4: LayerType = LayerType(5);
 }
 
 /// Represents a layer.
@@ -75,6 +79,7 @@ pub enum Layers {
     Ipv4(ipv4::Ipv4),
     Tcp(tcp::Tcp),
     Udp(udp::Udp),
+    Icmpv4(icmpv4::Icmpv4),
 }
 
 impl Display for Layers {
@@ -85,6 +90,7 @@ impl Display for Layers {
             Layers::Ipv4(ref layer) => layer.fmt(f),
             Layers::Tcp(ref layer) => layer.fmt(f),
             Layers::Udp(ref layer) => layer.fmt(f),
+            Layers::Icmpv4(ref layer) => layer.fmt(f),
         }
     }
 }
@@ -97,6 +103,7 @@ impl Layer for Layers {
             Layers::Ipv4(ref layer) => layer.get_type(),
             Layers::Tcp(ref layer) => layer.get_type(),
             Layers::Udp(ref layer) => layer.get_type(),
+            Layers::Icmpv4(ref layer) => layer.get_type(),
         }
     }
 
@@ -107,6 +114,7 @@ impl Layer for Layers {
             Layers::Ipv4(ref layer) => layer.get_size(),
             Layers::Tcp(ref layer) => layer.get_size(),
             Layers::Udp(ref layer) => layer.get_size(),
+            Layers::Icmpv4(ref layer) => layer.get_size(),
         }
     }
 
@@ -117,6 +125,7 @@ impl Layer for Layers {
             Layers::Ipv4(ref layer) => layer.serialize(buffer, n),
             Layers::Tcp(ref layer) => layer.serialize(buffer, n),
             Layers::Udp(ref layer) => layer.serialize(buffer, n),
+            Layers::Icmpv4(ref layer) => layer.serialize(buffer, n),
         }
     }
 
@@ -132,6 +141,7 @@ impl Layer for Layers {
             Layers::Ipv4(ref layer) => layer.serialize_with_payload(buffer, payload, n),
             Layers::Tcp(ref layer) => layer.serialize_with_payload(buffer, payload, n),
             Layers::Udp(ref layer) => layer.serialize_with_payload(buffer, payload, n),
+            Layers::Icmpv4(ref layer) => layer.serialize_with_payload(buffer, payload, n),
         }
     }
 }
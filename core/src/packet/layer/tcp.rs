@@ -1,5 +1,11 @@
 use super::{Layer, LayerType, LayerTypes};
-use pnet::packet::tcp::{self, MutableTcpPacket, TcpFlags, TcpOptionPacket, TcpPacket};
+use crate::packet::builder;
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::tcp::{
+    self, MutableTcpOptionPacket, MutableTcpPacket, TcpFlags, TcpOption, TcpOptionNumber,
+    TcpOptionNumbers, TcpOptionPacket, TcpPacket,
+};
+use pnet::packet::Packet;
 use std::clone::Clone;
 use std::fmt::{self, Display, Formatter};
 use std::io;
@@ -14,8 +20,9 @@ pub struct Tcp {
 }
 
 impl Tcp {
-    /// Creates a `Tcp` represents a TCP ACK.
-    pub fn new_ack(
+    /// Creates a `Tcp` of the given flags.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
         src_ip_addr: Ipv4Addr,
         dst_ip_addr: Ipv4Addr,
         src: u16,
@@ -23,6 +30,7 @@ impl Tcp {
         sequence: u32,
         acknowledgement: u32,
         window: u16,
+        flags: u16,
     ) -> Tcp {
         Tcp {
             layer: tcp::Tcp {
@@ -32,7 +40,7 @@ impl Tcp {
                 acknowledgement,
                 data_offset: 5,
                 reserved: 0,
-                flags: TcpFlags::ACK,
+                flags,
                 window,
                 checksum: 0,
                 urgent_ptr: 0,
@@ -44,98 +52,6 @@ impl Tcp {
         }
     }
 
-    /// Creates a `Tcp` represents a TCP ACK/SYN.
-    pub fn new_ack_syn(
-        src_ip_addr: Ipv4Addr,
-        dst_ip_addr: Ipv4Addr,
-        src: u16,
-        dst: u16,
-        sequence: u32,
-        acknowledgement: u32,
-        window: u16,
-    ) -> Tcp {
-        let mut tcp = Tcp::new_ack(
-            src_ip_addr,
-            dst_ip_addr,
-            src,
-            dst,
-            sequence,
-            acknowledgement,
-            window,
-        );
-        tcp.layer.flags |= TcpFlags::SYN;
-        tcp
-    }
-
-    /// Creates a `Tcp` represents a TCP ACK/RST.
-    pub fn new_ack_rst(
-        src_ip_addr: Ipv4Addr,
-        dst_ip_addr: Ipv4Addr,
-        src: u16,
-        dst: u16,
-        sequence: u32,
-        acknowledgement: u32,
-        window: u16,
-    ) -> Tcp {
-        let mut tcp = Tcp::new_rst(
-            src_ip_addr,
-            dst_ip_addr,
-            src,
-            dst,
-            sequence,
-            acknowledgement,
-            window,
-        );
-        tcp.layer.flags |= TcpFlags::ACK;
-        tcp
-    }
-
-    /// Creates a `Tcp` represents a TCP ACK/FIN.
-    pub fn new_ack_fin(
-        src_ip_addr: Ipv4Addr,
-        dst_ip_addr: Ipv4Addr,
-        src: u16,
-        dst: u16,
-        sequence: u32,
-        acknowledgement: u32,
-        window: u16,
-    ) -> Tcp {
-        let mut tcp = Tcp::new_ack(
-            src_ip_addr,
-            dst_ip_addr,
-            src,
-            dst,
-            sequence,
-            acknowledgement,
-            window,
-        );
-        tcp.layer.flags |= TcpFlags::FIN;
-        tcp
-    }
-
-    /// Creates a `Tcp` represents a TCP RST.
-    pub fn new_rst(
-        src_ip_addr: Ipv4Addr,
-        dst_ip_addr: Ipv4Addr,
-        src: u16,
-        dst: u16,
-        sequence: u32,
-        acknowledgement: u32,
-        window: u16,
-    ) -> Tcp {
-        let mut tcp = Tcp::new_ack(
-            src_ip_addr,
-            dst_ip_addr,
-            src,
-            dst,
-            sequence,
-            acknowledgement,
-            window,
-        );
-        tcp.layer.flags = TcpFlags::RST;
-        tcp
-    }
-
     /// Creates a `Tcp` according to the given `Tcp`.
     pub fn from(tcp: tcp::Tcp, src: Ipv4Addr, dst: Ipv4Addr) -> Tcp {
         Tcp {
@@ -197,22 +113,35 @@ impl Tcp {
         self.layer.acknowledgement
     }
 
+    /// Get the flags of the layer.
+    pub fn get_flags(&self) -> u16 {
+        self.layer.flags
+    }
+
+    /// Get the data offset of the layer in 32-bit words.
+    pub fn get_data_offset(&self) -> u8 {
+        self.layer.data_offset
+    }
+
     /// Get the string represents the flags of the layer.
     pub fn get_flag_string(&self) -> String {
         let mut flags = String::from("[");
         if self.is_syn() {
-            flags = flags + "S";
+            flags += "S";
         }
         if self.is_rst() {
-            flags = flags + "R";
+            flags += "R";
         }
         if self.is_fin() {
-            flags = flags + "F";
+            flags += "F";
+        }
+        if self.is_psh() {
+            flags += "P";
         }
         if self.is_ack() {
-            flags = flags + ".";
+            flags += ".";
         }
-        flags = flags + "]";
+        flags += "]";
 
         flags
     }
@@ -227,6 +156,11 @@ impl Tcp {
         self.layer.flags & TcpFlags::ACK != 0
     }
 
+    /// Returns if the `Tcp` is a TCP push.
+    pub fn is_psh(&self) -> bool {
+        self.layer.flags & TcpFlags::PSH != 0
+    }
+
     /// Returns if the `Tcp` is a TCP acknowledgement and finish.
     pub fn is_ack_fin(&self) -> bool {
         self.is_ack() && self.is_fin()
@@ -237,6 +171,58 @@ impl Tcp {
         self.layer.flags & TcpFlags::RST != 0
     }
 
+    /// Get the payload of the option of the given number of the layer.
+    fn get_option(&self, number: TcpOptionNumber) -> Option<Vec<u8>> {
+        for option in &self.layer.options {
+            let mut buffer = vec![0u8; TcpOptionPacket::packet_size(option)];
+            let mut packet = match MutableTcpOptionPacket::new(&mut buffer) {
+                Some(packet) => packet,
+                None => continue,
+            };
+            packet.populate(option);
+            let packet = packet.to_immutable();
+            if packet.get_number() == number {
+                return Some(packet.payload().to_vec());
+            }
+        }
+
+        None
+    }
+
+    /// Get the maximum segment size option of the layer, or `None` if there is no such option.
+    pub fn get_mss(&self) -> Option<u16> {
+        match self.get_option(TcpOptionNumbers::MSS) {
+            Some(ref payload) if payload.len() == 2 => {
+                Some(u16::from_be_bytes([payload[0], payload[1]]))
+            }
+            _ => None,
+        }
+    }
+
+    /// Get the window scale option of the layer, or `None` if there is no such option.
+    pub fn get_wscale(&self) -> Option<u8> {
+        match self.get_option(TcpOptionNumbers::WSCALE) {
+            Some(ref payload) if payload.len() == 1 => Some(payload[0]),
+            _ => None,
+        }
+    }
+
+    /// Sets the maximum segment size option of the layer. The layer is expected to have no
+    /// option, like those created by `new`.
+    pub fn set_mss(&mut self, mss: u16) {
+        self.layer.options.push(TcpOption::mss(mss));
+        // The data offset covers the option, or the option overflows the header when populated
+        self.layer.data_offset = (self.get_size() / 4) as u8;
+    }
+
+    /// Sets the window scale option of the layer after a NOP, which keeps the options aligned to
+    /// 4 bytes.
+    pub fn set_wscale(&mut self, shift: u8) {
+        self.layer.options.push(TcpOption::nop());
+        self.layer.options.push(TcpOption::wscale(shift));
+        self.layer.data_offset = (self.get_size() / 4) as u8;
+    }
+
     /// Returns if the `Tcp` is a TCP synchronization.
     pub fn is_syn(&self) -> bool {
         self.layer.flags & TcpFlags::SYN != 0
@@ -296,15 +282,17 @@ impl Layer for Tcp {
         // Fix length
         let header_length = self.get_size();
         if header_length / 4 > u8::MAX as usize {
-            return Err(io::Error::new(io::ErrorKind::Other, "TCP too big"));
+            return Err(io::Error::other("TCP too big"));
         }
         packet.set_data_offset((header_length / 4) as u8);
 
         // Compute checksum
-        let checksum = tcp::ipv4_checksum(
-            &packet.to_immutable(),
-            &self.get_src_ip_addr(),
-            &self.get_dst_ip_addr(),
+        let checksum = builder::ipv4_checksum(
+            packet.packet(),
+            8,
+            self.get_src_ip_addr(),
+            self.get_dst_ip_addr(),
+            IpNextHeaderProtocols::Tcp,
         );
         packet.set_checksum(checksum);
 
@@ -315,7 +303,7 @@ impl Layer for Tcp {
         &self,
         buffer: &mut [u8],
         payload: &[u8],
-        n: usize,
+        _: usize,
     ) -> io::Result<usize> {
         let mut packet = MutableTcpPacket::new(buffer)
             .ok_or(io::Error::new(io::ErrorKind::WriteZero, "buffer too small"))?;
@@ -328,18 +316,81 @@ impl Layer for Tcp {
         // Fix length
         let header_length = self.get_size();
         if header_length / 4 > u8::MAX as usize {
-            return Err(io::Error::new(io::ErrorKind::Other, "TCP too big"));
+            return Err(io::Error::other("TCP too big"));
         }
         packet.set_data_offset((header_length / 4) as u8);
 
         // Compute checksum
-        let checksum = tcp::ipv4_checksum(
-            &packet.to_immutable(),
-            &self.get_src_ip_addr(),
-            &self.get_dst_ip_addr(),
+        let checksum = builder::ipv4_checksum(
+            packet.packet(),
+            8,
+            self.get_src_ip_addr(),
+            self.get_dst_ip_addr(),
+            IpNextHeaderProtocols::Tcp,
         );
         packet.set_checksum(checksum);
 
-        Ok(header_length + n)
+        Ok(header_length + payload.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_mss() {
+        let src = Ipv4Addr::new(1, 1, 1, 1);
+        let dst = Ipv4Addr::new(10, 6, 0, 2);
+        for mss in [0, 64, 536, 1460, u16::MAX].iter() {
+            let mut tcp = Tcp::new(
+                src,
+                dst,
+                80,
+                50000,
+                1000,
+                2000,
+                65535,
+                TcpFlags::ACK | TcpFlags::SYN,
+            );
+            tcp.set_mss(*mss);
+            assert_eq!(tcp.get_size(), 24);
+
+            let mut buffer = vec![0u8; tcp.get_size()];
+            assert_eq!(tcp.serialize(&mut buffer, 24).unwrap(), 24);
+            let packet = TcpPacket::new(&buffer).unwrap();
+            let parsed = Tcp::parse(&packet, src, dst);
+            assert_eq!(parsed.get_data_offset(), 6);
+            assert_eq!(parsed.get_mss(), Some(*mss));
+        }
+    }
+
+    #[test]
+    fn test_set_wscale() {
+        let src = Ipv4Addr::new(1, 1, 1, 1);
+        let dst = Ipv4Addr::new(10, 6, 0, 2);
+        let mut tcp = Tcp::new(
+            src,
+            dst,
+            80,
+            50000,
+            1000,
+            2000,
+            65535,
+            TcpFlags::ACK | TcpFlags::SYN,
+        );
+        assert_eq!(tcp.get_wscale(), None);
+        tcp.set_mss(1460);
+        tcp.set_wscale(7);
+        assert_eq!(tcp.get_size(), 28);
+
+        let mut buffer = vec![0u8; tcp.get_size()];
+        assert_eq!(tcp.serialize(&mut buffer, 28).unwrap(), 28);
+        assert_eq!(buffer[24..], [1, 3, 3, 7]);
+        let packet = TcpPacket::new(&buffer).unwrap();
+        let parsed = Tcp::parse(&packet, src, dst);
+        assert_eq!(parsed.get_data_offset(), 7);
+        assert_eq!(parsed.get_mss(), Some(1460));
+        assert_eq!(parsed.get_wscale(), Some(7));
     }
 }
@@ -1,5 +1,8 @@
 use super::{Layer, LayerType, LayerTypes};
+use crate::packet::builder;
+use pnet::packet::ip::IpNextHeaderProtocols;
 use pnet::packet::udp::{self, MutableUdpPacket, UdpPacket};
+use pnet::packet::Packet;
 use std::clone::Clone;
 use std::fmt::{self, Display, Formatter};
 use std::io;
@@ -77,6 +80,11 @@ impl Udp {
     pub fn get_length(&self) -> u16 {
         self.layer.length
     }
+
+    /// Get the checksum of the layer.
+    pub fn get_checksum(&self) -> u16 {
+        self.layer.checksum
+    }
 }
 
 impl Display for Udp {
@@ -117,10 +125,12 @@ impl Layer for Udp {
         packet.set_length(n as u16);
 
         // Compute checksum
-        let checksum = udp::ipv4_checksum(
-            &packet.to_immutable(),
-            &self.get_src_ip_addr(),
-            &self.get_dst_ip_addr(),
+        let checksum = builder::ipv4_checksum(
+            packet.packet(),
+            3,
+            self.get_src_ip_addr(),
+            self.get_dst_ip_addr(),
+            IpNextHeaderProtocols::Udp,
         );
         packet.set_checksum(checksum);
 
@@ -151,13 +161,15 @@ impl Layer for Udp {
         packet.set_length(n as u16);
 
         // Compute checksum
-        let checksum = udp::ipv4_checksum(
-            &packet.to_immutable(),
-            &self.get_src_ip_addr(),
-            &self.get_dst_ip_addr(),
+        let checksum = builder::ipv4_checksum(
+            packet.packet(),
+            3,
+            self.get_src_ip_addr(),
+            self.get_dst_ip_addr(),
+            IpNextHeaderProtocols::Udp,
         );
         packet.set_checksum(checksum);
 
-        Ok(self.get_size() + n)
+        Ok(self.get_size() + payload.len())
     }
 }
@@ -0,0 +1,769 @@
+#![allow(dead_code)]
+use pnet::packet::arp::ArpPacket;
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
+use pnet::packet::icmp::IcmpPacket;
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::{Ipv4Flags, Ipv4Packet};
+use pnet::packet::tcp::TcpPacket;
+use pnet::packet::udp::UdpPacket;
+use pnet::packet::Packet;
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::Ipv4Addr;
+use std::time::Instant;
+
+pub mod builder;
+pub mod layer;
+use layer::arp::Arp;
+use layer::ethernet::Ethernet;
+use layer::icmpv4::Icmpv4;
+use layer::ipv4::Ipv4;
+use layer::tcp::Tcp;
+use layer::udp::Udp;
+use layer::{Layer, LayerType, LayerTypes, Layers};
+
+/// Represents the reason a packet is malformed.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Malformed {
+    /// The total length of the IPv4 packet is shorter than its header or longer than the frame.
+    Ipv4Length,
+    /// The source or the destination port is 0.
+    PortZero,
+    /// The length of the UDP datagram is shorter than its header or longer than the IPv4 payload.
+    UdpLength,
+    /// The checksum of the UDP datagram fails.
+    UdpChecksum,
+    /// The data offset of the TCP segment is shorter than its header or longer than the segment.
+    TcpDataOffset,
+}
+
+impl Display for Malformed {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Malformed::Ipv4Length => write!(f, "IPv4 length"),
+            Malformed::PortZero => write!(f, "port 0"),
+            Malformed::UdpLength => write!(f, "UDP length"),
+            Malformed::UdpChecksum => write!(f, "UDP checksum"),
+            Malformed::TcpDataOffset => write!(f, "TCP data offset"),
+        }
+    }
+}
+
+/// Represents a packet indicator.
+#[derive(Debug)]
+pub struct Indicator {
+    pub link: Layers,
+    pub network: Option<Layers>,
+    pub transport: Option<Layers>,
+}
+
+impl Indicator {
+    /// Creates a `Indicator`.
+    pub fn new(link: Layers, network: Option<Layers>, transport: Option<Layers>) -> Indicator {
+        Indicator {
+            link,
+            network,
+            transport,
+        }
+    }
+
+    /// Creates a `Indicator` by the given Ethernet packet.
+    pub fn parse(packet: &EthernetPacket) -> Indicator {
+        let mut transport = None;
+
+        let ethernet = Ethernet::parse(packet);
+        let payload = ethernet.get_payload(packet);
+        let ethertype = ethernet.get_ethertype();
+        let link = Layers::Ethernet(ethernet);
+        let network = match ethertype {
+            EtherTypes::Arp => ArpPacket::new(payload)
+                .as_ref()
+                .map(|arp_packet| Layers::Arp(Arp::parse(arp_packet))),
+            EtherTypes::Ipv4 => match Ipv4Packet::new(payload) {
+                Some(ref ipv4_packet) => {
+                    let this_ipv4 = Ipv4::parse(ipv4_packet);
+                    let src = this_ipv4.get_src();
+                    let dst = this_ipv4.get_dst();
+                    let this_ipv4 = Some(Layers::Ipv4(this_ipv4));
+                    // Fragment
+                    if ipv4_packet.get_flags() & Ipv4Flags::MoreFragments == 0
+                        && ipv4_packet.get_fragment_offset() == 0
+                    {
+                        transport = match ipv4_packet.get_next_level_protocol() {
+                            IpNextHeaderProtocols::Tcp => TcpPacket::new(ipv4_packet.payload())
+                                .as_ref()
+                                .map(|tcp_packet| Layers::Tcp(Tcp::parse(tcp_packet, src, dst))),
+                            IpNextHeaderProtocols::Udp => UdpPacket::new(ipv4_packet.payload())
+                                .as_ref()
+                                .map(|udp_packet| Layers::Udp(Udp::parse(udp_packet, src, dst))),
+                            IpNextHeaderProtocols::Icmp => IcmpPacket::new(ipv4_packet.payload())
+                                .as_ref()
+                                .map(|icmp_packet| {
+                                    Layers::Icmpv4(Icmpv4::parse(icmp_packet, src, dst))
+                                }),
+                            _ => None,
+                        };
+                    }
+
+                    this_ipv4
+                }
+                None => None,
+            },
+            _ => None,
+        };
+
+        Indicator {
+            link,
+            network,
+            transport,
+        }
+    }
+
+    /// Creates a `Indicator` by the given frame.
+    pub fn from(frame: &[u8]) -> Option<Indicator> {
+        EthernetPacket::new(frame)
+            .as_ref()
+            .map(|packet| Indicator::parse(packet))
+    }
+
+    /// Get the brief of the `Indicator`.
+    pub fn brief(&self) -> String {
+        match self.get_network_type() {
+            Some(t) => match t {
+                LayerTypes::Arp => {
+                    let layer = self.get_arp().unwrap();
+                    format!("{}", layer)
+                }
+                LayerTypes::Ipv4 => match self.get_transport_type() {
+                    Some(t) => match t {
+                        LayerTypes::Tcp => {
+                            let layer = self.get_tcp().unwrap();
+                            format!(
+                                "{}: {}:{} -> {}:{} {}",
+                                layer.get_type(),
+                                layer.get_src_ip_addr(),
+                                layer.get_src(),
+                                layer.get_dst_ip_addr(),
+                                layer.get_dst(),
+                                layer.get_flag_string(),
+                            )
+                        }
+                        LayerTypes::Udp => {
+                            let layer = self.get_udp().unwrap();
+                            format!(
+                                "{}: {}:{} -> {}:{}, Length = {}",
+                                layer.get_type(),
+                                layer.get_src_ip_addr(),
+                                layer.get_src(),
+                                layer.get_dst_ip_addr(),
+                                layer.get_dst(),
+                                layer.get_length(),
+                            )
+                        }
+                        LayerTypes::Icmpv4 => {
+                            let layer = self.get_icmpv4().unwrap();
+                            format!("{}", layer)
+                        }
+                        _ => unreachable!(),
+                    },
+                    None => {
+                        let layer = self.get_ipv4().unwrap();
+                        format!("{}", layer)
+                    }
+                },
+                _ => unreachable!(),
+            },
+            None => match self.get_link_type() {
+                LayerTypes::Ethernet => {
+                    let layer = self.get_ethernet().unwrap();
+                    format!("{}", layer)
+                }
+                _ => unreachable!(),
+            },
+        }
+    }
+
+    /// Get The size of the `Indicator` when converted into a byte-array.
+    pub fn get_size(&self) -> usize {
+        let mut size = 0;
+
+        // Link
+        size += self.get_link().get_size();
+        // Network
+        if let Some(network) = self.get_network() {
+            size += network.get_size();
+        }
+        // Transport
+        if let Some(transport) = self.get_transport() {
+            size += transport.get_size();
+        }
+
+        size
+    }
+
+    /// Serialize the `Indicator` into a byte-array.
+    pub fn serialize(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        let mut begin = 0;
+        let mut total = self.get_size();
+
+        // Link
+        let m = self.get_link().serialize(&mut buffer[begin..], total)?;
+        begin += m;
+        total -= m;
+        // Network
+        if let Some(network) = self.get_network() {
+            let m = network.serialize(&mut buffer[begin..], total)?;
+            begin += m;
+            total -= m;
+        };
+        // Transport
+        if let Some(transport) = self.get_transport() {
+            let m = transport.serialize(&mut buffer[begin..], total)?;
+            begin += m;
+        };
+
+        Ok(begin)
+    }
+
+    /// Serialize the `Indicator` into a byte-array with payload.
+    pub fn serialize_with_payload(&self, buffer: &mut [u8], payload: &[u8]) -> io::Result<usize> {
+        let mut begin = 0;
+        let mut total = self.get_size() + payload.len();
+
+        // Link
+        let m = self
+            .get_link()
+            .serialize_with_payload(&mut buffer[begin..], payload, total)?;
+        begin += m;
+        total -= m;
+        // Network
+        if let Some(network) = self.get_network() {
+            let m = network.serialize_with_payload(&mut buffer[begin..], payload, total)?;
+            begin += m;
+            total -= m;
+        };
+        // Transport
+        match self.get_transport() {
+            Some(transport) => {
+                let m = transport.serialize_with_payload(&mut buffer[begin..], payload, total)?;
+                begin += m;
+            }
+            // Copies payload of an IPv4 fragment
+            None => {
+                if buffer.len() < begin + payload.len() {
+                    return Err(io::Error::new(io::ErrorKind::WriteZero, "buffer too small"));
+                }
+                buffer[begin..begin + payload.len()].copy_from_slice(payload);
+                begin += payload.len();
+            }
+        };
+
+        Ok(begin)
+    }
+
+    /// Get the link layer.
+    pub fn get_link(&self) -> &Layers {
+        &self.link
+    }
+
+    /// Get the link layer type.
+    pub fn get_link_type(&self) -> LayerType {
+        self.get_link().get_type()
+    }
+
+    /// Get the `Ethernet`.
+    pub fn get_ethernet(&self) -> Option<&Ethernet> {
+        if let Layers::Ethernet(layer) = &self.get_link() {
+            return Some(layer);
+        }
+
+        None
+    }
+
+    /// Get the network layer.
+    pub fn get_network(&self) -> Option<&Layers> {
+        if let Some(layer) = &self.network {
+            return Some(layer);
+        }
+
+        None
+    }
+
+    /// Get the network layer type.
+    pub fn get_network_type(&self) -> Option<LayerType> {
+        if let Some(layer) = self.get_network() {
+            return Some(layer.get_type());
+        }
+
+        None
+    }
+
+    /// Get the ARP.
+    pub fn get_arp(&self) -> Option<&Arp> {
+        if let Some(Layers::Arp(layer)) = self.get_network() {
+            return Some(layer);
+        }
+
+        None
+    }
+
+    /// Get the IPv4.
+    pub fn get_ipv4(&self) -> Option<&Ipv4> {
+        if let Some(Layers::Ipv4(layer)) = self.get_network() {
+            return Some(layer);
+        }
+
+        None
+    }
+
+    /// Get the transport layer.
+    pub fn get_transport(&self) -> Option<&Layers> {
+        if let Some(layer) = &self.transport {
+            return Some(layer);
+        }
+
+        None
+    }
+
+    /// Get the transport layer type.
+    pub fn get_transport_type(&self) -> Option<LayerType> {
+        if let Some(layer) = self.get_transport() {
+            return Some(layer.get_type());
+        }
+
+        None
+    }
+
+    /// Get the TCP.
+    pub fn get_tcp(&self) -> Option<&Tcp> {
+        if let Some(Layers::Tcp(layer)) = self.get_transport() {
+            return Some(layer);
+        }
+
+        None
+    }
+
+    /// Get the UDP.
+    pub fn get_udp(&self) -> Option<&Udp> {
+        if let Some(Layers::Udp(layer)) = self.get_transport() {
+            return Some(layer);
+        }
+
+        None
+    }
+
+    /// Get the ICMPv4.
+    pub fn get_icmpv4(&self) -> Option<&Icmpv4> {
+        if let Some(Layers::Icmpv4(layer)) = self.get_transport() {
+            return Some(layer);
+        }
+
+        None
+    }
+
+    /// Validates the TCP or UDP layer of the `Indicator` against the given buffer without
+    /// padding, which starts with the link layer. The UDP checksum is verified if it is present
+    /// and `is_verify_checksum` is set.
+    pub fn validate(&self, buffer: &[u8], is_verify_checksum: bool) -> Result<(), Malformed> {
+        let begin = match self.get_network() {
+            Some(network) => self.get_link().get_size() + network.get_size(),
+            None => return Ok(()),
+        };
+        let transport = match buffer.get(begin..) {
+            Some(transport) => transport,
+            None => return Err(Malformed::Ipv4Length),
+        };
+
+        if let Some(udp) = self.get_udp() {
+            if udp.get_src() == 0 || udp.get_dst() == 0 {
+                return Err(Malformed::PortZero);
+            }
+            let length = udp.get_length() as usize;
+            if length < 8 || length > transport.len() {
+                return Err(Malformed::UdpLength);
+            }
+            // A checksum of 0 means no checksum
+            if is_verify_checksum && udp.get_checksum() != 0 {
+                let packet = match UdpPacket::new(&transport[..length]) {
+                    Some(packet) => packet,
+                    None => return Err(Malformed::UdpLength),
+                };
+                let checksum = builder::ipv4_checksum(
+                    packet.packet(),
+                    3,
+                    udp.get_src_ip_addr(),
+                    udp.get_dst_ip_addr(),
+                    IpNextHeaderProtocols::Udp,
+                );
+                if checksum != udp.get_checksum() {
+                    return Err(Malformed::UdpChecksum);
+                }
+            }
+        } else if let Some(tcp) = self.get_tcp() {
+            if tcp.get_src() == 0 || tcp.get_dst() == 0 {
+                return Err(Malformed::PortZero);
+            }
+            let data_offset = tcp.get_data_offset() as usize;
+            if data_offset < 5 || data_offset * 4 > transport.len() {
+                return Err(Malformed::TcpDataOffset);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for Indicator {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let link_string = format!("\n- {} ({} Bytes)", self.link, self.link.get_size());
+        let mut network_string = String::new();
+        if let Some(network) = &self.network {
+            network_string = format!("\n- {} ({} Bytes)", network, network.get_size());
+        }
+        let mut transport_string = String::new();
+        if let Some(transport) = &self.transport {
+            transport_string = format!("\n- {} ({} Bytes)", transport, transport.get_size());
+        }
+
+        write!(
+            f,
+            "Indicator{}{}{}",
+            link_string, network_string, transport_string
+        )
+    }
+}
+
+const EXPIRE_TIME: u128 = 10000;
+
+/// Represents a fragmentation.
+#[derive(Debug)]
+pub struct Fragmentation {
+    ethernet: Ethernet,
+    ipv4: Ipv4,
+    transport: Option<Layers>,
+    buffer: Vec<u8>,
+    last_seen: Instant,
+    length: usize,
+}
+
+impl Fragmentation {
+    /// Creates a `Fragmentation`.
+    pub fn new(indicator: &Indicator) -> Option<Fragmentation> {
+        let new_ipv4 = match indicator.get_ipv4() {
+            Some(ipv4) => Ipv4::defrag(ipv4),
+            None => return None,
+        };
+        let ethernet = indicator.get_ethernet()?;
+
+        let mut frag = Fragmentation {
+            ethernet: ethernet.clone(),
+            ipv4: new_ipv4.clone(),
+            transport: None,
+            // TODO: u16 is not safe
+            buffer: vec![0; u16::MAX as usize],
+            last_seen: Instant::now(),
+            length: 0,
+        };
+
+        // Indicator
+        let new_indicator = Indicator::new(
+            Layers::Ethernet(ethernet.clone()),
+            Some(Layers::Ipv4(new_ipv4)),
+            None,
+        );
+
+        // Serialize
+        if new_indicator.serialize(&mut frag.buffer[0..]).is_err() {
+            return None;
+        }
+
+        Some(frag)
+    }
+
+    /// Adds a fragmentation.
+    pub fn add(&mut self, indicator: &Indicator, payload: &[u8]) {
+        // Transport
+        if self.transport.is_none() {
+            if let Some(transport) = indicator.get_transport() {
+                self.transport = Some(transport.clone());
+            }
+        }
+
+        // Payload
+        let ipv4 = match indicator.get_ipv4() {
+            Some(ipv4) => ipv4,
+            None => return,
+        };
+        let offset = (ipv4.get_fragment_offset() as usize) * 8;
+        let header_size = self.ethernet.get_size() + self.ipv4.get_size();
+
+        self.buffer[header_size + offset..].copy_from_slice(payload);
+        self.length += payload.len();
+    }
+
+    /// Concatenates fragmentations and returns an indicator of the buffer and the buffer itself.
+    pub fn concatenate(&self) -> (Indicator, &[u8]) {
+        let new_indicator = Indicator::new(
+            Layers::Ethernet(self.ethernet.clone()),
+            Some(Layers::Ipv4(self.ipv4.clone())),
+            self.transport.clone(),
+        );
+
+        let header_size = self.ethernet.get_size() + self.ipv4.get_size();
+
+        (new_indicator, &self.buffer[0..header_size + self.length])
+    }
+
+    /// Returns if the `Fragmentation` is completed.
+    pub fn is_completed(&self) -> bool {
+        self.length == self.ipv4.get_total_length() as usize - self.ipv4.get_size()
+    }
+
+    /// Returns if the `Fragmentation` is expired.
+    pub fn is_expired(&self) -> bool {
+        self.last_seen.elapsed().as_millis() > EXPIRE_TIME
+    }
+}
+
+/// Represents a defragmentation machine.
+#[derive(Debug)]
+pub struct Defraggler {
+    frags: HashMap<(Ipv4Addr, Ipv4Addr, u16), Fragmentation>,
+}
+
+impl Defraggler {
+    /// Creates a new empty `Defraggler`.
+    pub fn new() -> Defraggler {
+        Defraggler {
+            frags: HashMap::new(),
+        }
+    }
+
+    /// Get the number of fragmentations being reassembled, including the expired ones.
+    pub fn get_count(&self) -> usize {
+        self.frags.len()
+    }
+
+    /// Adds a fragmentation and returns the fragmentation if it is completed.
+    pub fn add(&mut self, indicator: &Indicator, buffer: &[u8]) -> Option<Fragmentation> {
+        let ipv4 = indicator.get_ipv4()?;
+
+        let key = (ipv4.get_src(), ipv4.get_dst(), ipv4.get_identification());
+
+        let mut is_create = false;
+        if self.frags.contains_key(&key) {
+            if self.frags.get(&key).unwrap().is_expired() {
+                // Expired
+                is_create = true;
+            }
+        } else {
+            is_create = true;
+        }
+
+        if is_create {
+            let frag = Fragmentation::new(indicator)?;
+
+            self.frags.insert(key, frag);
+        }
+
+        let frag = self.frags.get_mut(&key).unwrap();
+
+        // Add fragmentation
+        let header_size = indicator.get_ethernet().unwrap().get_size() + ipv4.get_size();
+        frag.add(indicator, &buffer[header_size..]);
+        if frag.is_completed() {
+            self.frags.remove(&key)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use pnet::packet::ethernet::EtherType;
+
+    // ARP request of 10.6.0.1 from 10.6.0.2 in IEEE 802.3 with LLC and SNAP, padded to 60 bytes
+    const LLC_SNAP_ARP: [u8; 60] = [
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x24, 0xaa,
+        0xaa, 0x03, 0x00, 0x00, 0x00, 0x08, 0x06, 0x00, 0x01, 0x08, 0x00, 0x06, 0x04, 0x00, 0x01,
+        0x02, 0x00, 0x00, 0x00, 0x00, 0x02, 0x0a, 0x06, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x0a, 0x06, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+    // UDP datagram 10.6.0.2:50000 -> 1.1.1.1:53 of 13 bytes in IEEE 802.3 with LLC and SNAP
+    const LLC_SNAP_IPV4: [u8; 63] = [
+        0x02, 0x00, 0x00, 0x00, 0x00, 0x01, 0x02, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x31, 0xaa,
+        0xaa, 0x03, 0x00, 0x00, 0x00, 0x08, 0x00, 0x45, 0x00, 0x00, 0x29, 0x1c, 0x46, 0x00, 0x00,
+        0x40, 0x11, 0x52, 0x75, 0x0a, 0x06, 0x00, 0x02, 0x01, 0x01, 0x01, 0x01, 0xc3, 0x50, 0x00,
+        0x35, 0x00, 0x15, 0x1d, 0x00, 0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00,
+    ];
+    /// Represents the size of the Ethernet and the LLC and SNAP headers.
+    const LLC_SNAP_HEADERS_SIZE: usize = 22;
+
+    #[test]
+    fn test_parse_llc_snap_arp() {
+        let indicator = Indicator::from(&LLC_SNAP_ARP).unwrap();
+        let ethernet = indicator.get_ethernet().unwrap();
+        assert!(ethernet.is_llc_snap());
+        assert_eq!(ethernet.get_ethertype(), EtherTypes::Arp);
+        assert_eq!(ethernet.get_size(), LLC_SNAP_HEADERS_SIZE);
+
+        let arp = indicator.get_arp().unwrap();
+        assert!(arp.is_request_of(Ipv4Addr::new(10, 6, 0, 2), Ipv4Addr::new(10, 6, 0, 1)));
+        assert_eq!(arp.get_src_hardware_addr(), ethernet.get_src());
+    }
+
+    #[test]
+    fn test_parse_llc_snap_ipv4() {
+        let indicator = Indicator::from(&LLC_SNAP_IPV4).unwrap();
+        let ethernet = indicator.get_ethernet().unwrap();
+        assert!(ethernet.is_llc_snap());
+        assert_eq!(ethernet.get_ethertype(), EtherTypes::Ipv4);
+
+        let udp = indicator.get_udp().unwrap();
+        assert_eq!(udp.get_src(), 50000);
+        assert_eq!(udp.get_dst(), 53);
+        assert_eq!(udp.get_src_ip_addr(), Ipv4Addr::new(10, 6, 0, 2));
+        assert_eq!(indicator.get_size(), LLC_SNAP_HEADERS_SIZE + 20 + 8);
+        assert!(indicator.validate(&LLC_SNAP_IPV4, true).is_ok());
+    }
+
+    #[test]
+    fn test_parse_ethernet_length() {
+        // An IEEE 802.3 frame without SNAP keeps its length as the EtherType
+        let mut frame = LLC_SNAP_ARP;
+        frame[14] = 0x42;
+        frame[15] = 0x42;
+        let indicator = Indicator::from(&frame).unwrap();
+        let ethernet = indicator.get_ethernet().unwrap();
+        assert!(!ethernet.is_llc_snap());
+        assert_eq!(ethernet.get_ethertype(), EtherType::new(0x24));
+        assert!(indicator.get_network().is_none());
+    }
+
+    /// Validates the frame without its padding.
+    fn validate(frame: &[u8], is_verify_checksum: bool) -> Result<(), Malformed> {
+        let indicator = Indicator::from(frame).unwrap();
+        let size = indicator.get_ethernet().unwrap().get_size()
+            + indicator.get_ipv4().unwrap().get_total_length() as usize;
+
+        indicator.validate(&frame[..size], is_verify_checksum)
+    }
+
+    /// Sets the length of the UDP datagram in the frame.
+    fn set_udp_length(frame: &mut [u8], length: u16) {
+        let offset = testing::TRANSPORT_OFFSET + 4;
+        frame[offset..offset + 2].copy_from_slice(&length.to_be_bytes());
+    }
+
+    /// Sets the data offset of the TCP segment in the frame.
+    fn set_tcp_data_offset(frame: &mut [u8], data_offset: u8) {
+        frame[testing::TRANSPORT_OFFSET + 12] = data_offset << 4;
+    }
+
+    #[test]
+    fn test_validate_udp_src_port_zero() {
+        let frame = testing::udp_frame(0, 53, b"query");
+        assert_eq!(validate(&frame, false), Err(Malformed::PortZero));
+    }
+
+    #[test]
+    fn test_validate_udp_dst_port_zero() {
+        let frame = testing::udp_frame(50000, 0, b"query");
+        assert_eq!(validate(&frame, false), Err(Malformed::PortZero));
+    }
+
+    #[test]
+    fn test_validate_udp_length_short() {
+        let mut frame = testing::udp_frame(50000, 53, b"query");
+        assert_eq!(validate(&frame, true), Ok(()));
+        set_udp_length(&mut frame, 7);
+        assert_eq!(validate(&frame, false), Err(Malformed::UdpLength));
+    }
+
+    #[test]
+    fn test_validate_udp_length_long() {
+        let mut frame = testing::udp_frame(50000, 53, b"query");
+        set_udp_length(&mut frame, 8 + 5);
+        assert_eq!(validate(&frame, false), Ok(()));
+        set_udp_length(&mut frame, 8 + 6);
+        assert_eq!(validate(&frame, false), Err(Malformed::UdpLength));
+    }
+
+    #[test]
+    fn test_validate_udp_checksum() {
+        let mut frame = testing::udp_frame(50000, 53, b"query");
+        frame[testing::TRANSPORT_OFFSET + 8] ^= 0xff;
+        // Verified only if enabled
+        assert_eq!(validate(&frame, false), Ok(()));
+        assert_eq!(validate(&frame, true), Err(Malformed::UdpChecksum));
+        // A checksum of 0 is absent
+        let offset = testing::TRANSPORT_OFFSET + 6;
+        frame[offset..offset + 2].copy_from_slice(&[0, 0]);
+        assert_eq!(validate(&frame, true), Ok(()));
+    }
+
+    #[test]
+    fn test_validate_tcp_src_port_zero() {
+        let frame = testing::tcp_frame(0, 80);
+        assert_eq!(validate(&frame, false), Err(Malformed::PortZero));
+    }
+
+    #[test]
+    fn test_validate_tcp_dst_port_zero() {
+        let frame = testing::tcp_frame(50000, 0);
+        assert_eq!(validate(&frame, false), Err(Malformed::PortZero));
+    }
+
+    #[test]
+    fn test_validate_tcp_data_offset_short() {
+        let mut frame = testing::tcp_frame(50000, 80);
+        assert_eq!(validate(&frame, false), Ok(()));
+        set_tcp_data_offset(&mut frame, 4);
+        assert_eq!(validate(&frame, false), Err(Malformed::TcpDataOffset));
+    }
+
+    #[test]
+    fn test_validate_tcp_data_offset_long() {
+        let mut frame = testing::tcp_frame(50000, 80);
+        set_tcp_data_offset(&mut frame, 6);
+        assert_eq!(validate(&frame, false), Err(Malformed::TcpDataOffset));
+    }
+
+    #[test]
+    fn test_validate_ipv4_length() {
+        // The transport layer is beyond the buffer
+        let frame = testing::udp_frame(50000, 53, b"query");
+        let indicator = Indicator::from(&frame).unwrap();
+        let buffer = &frame[..testing::TRANSPORT_OFFSET - 1];
+        assert_eq!(
+            indicator.validate(buffer, false),
+            Err(Malformed::Ipv4Length)
+        );
+    }
+
+    #[test]
+    fn test_llc_snap_round_trip() {
+        // ARP, whose length excludes the padding
+        let indicator = Indicator::from(&LLC_SNAP_ARP).unwrap();
+        let mut buffer = vec![0u8; indicator.get_size()];
+        assert_eq!(indicator.serialize(&mut buffer).unwrap(), 50);
+        assert_eq!(buffer[..], LLC_SNAP_ARP[..50]);
+
+        // IPv4 and UDP, with the checksums computed again
+        let indicator = Indicator::from(&LLC_SNAP_IPV4).unwrap();
+        let payload = &LLC_SNAP_IPV4[indicator.get_size()..];
+        let mut buffer = vec![0u8; indicator.get_size() + payload.len()];
+        assert_eq!(
+            indicator
+                .serialize_with_payload(&mut buffer, payload)
+                .unwrap(),
+            LLC_SNAP_IPV4.len()
+        );
+        assert_eq!(buffer[..], LLC_SNAP_IPV4[..]);
+    }
+}
@@ -0,0 +1,270 @@
+use pnet::datalink::{self, Channel, Config, DataLinkReceiver, DataLinkSender, MacAddr};
+use std::clone::Clone;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::io;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+pub type HardwareAddr = pnet::datalink::MacAddr;
+
+pub const HARDWARE_ADDR_UNSPECIFIED: HardwareAddr = pnet::datalink::MacAddr(0, 0, 0, 0, 0, 0);
+
+pub type Sender = Box<dyn DataLinkSender>;
+pub type Receiver = Box<dyn DataLinkReceiver>;
+
+/// Represents the buffer size of pcap channels.
+const BUFFER_SIZE: usize = 256 * 1024;
+
+/// Represents a network interface and its associated addresses.
+#[derive(Clone, Debug)]
+pub struct Interface {
+    pub name: String,
+    pub alias: Option<String>,
+    pub hardware_addr: MacAddr,
+    pub ip_addrs: Vec<Ipv4Addr>,
+    pub is_up: bool,
+    pub is_loopback: bool,
+}
+
+impl Interface {
+    /// Construct a new empty `Interface`.
+    pub fn new() -> Interface {
+        Interface {
+            name: String::new(),
+            alias: None,
+            hardware_addr: MacAddr::zero(),
+            ip_addrs: vec![],
+            is_up: false,
+            is_loopback: false,
+        }
+    }
+
+    // Opens the network interface for sending and receiving data.
+    pub fn open(&self) -> io::Result<(Sender, Receiver)> {
+        self.open_with_read_timeout(None)
+    }
+
+    // Opens the network interface with a read timeout, after which receiving returns a
+    // `TimedOut` `IoError`.
+    pub fn open_with_read_timeout(
+        &self,
+        read_timeout: Option<Duration>,
+    ) -> io::Result<(Sender, Receiver)> {
+        let inters = datalink::interfaces();
+        let inter = inters
+            .into_iter()
+            .find(|current_inter| current_inter.name == self.name)
+            .ok_or(io::Error::new(
+                io::ErrorKind::NotFound,
+                "interface not found",
+            ))?;
+
+        let config = Config {
+            write_buffer_size: BUFFER_SIZE,
+            read_buffer_size: BUFFER_SIZE,
+            read_timeout,
+            ..Default::default()
+        };
+        let channel = datalink::channel(&inter, config)?;
+        let channel = match channel {
+            Channel::Ethernet(tx, rx) => (tx, rx),
+            _ => return Err(io::Error::other("unknown link type")),
+        };
+
+        Ok(channel)
+    }
+}
+
+impl Default for Interface {
+    fn default() -> Interface {
+        Interface::new()
+    }
+}
+
+impl Display for Interface {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let name;
+        if let Some(alias) = &self.alias {
+            name = format!("{} ({})", self.name, alias);
+        } else {
+            name = self.name.clone();
+        }
+
+        let hardware_addr = format!(" [{}]", self.hardware_addr);
+
+        let ip_addrs = self
+            .ip_addrs
+            .iter()
+            .map(|ip_addr| ip_addr.to_string())
+            .collect::<Vec<String>>()
+            .join(", ")
+            .to_string();
+
+        let mut flags = String::new();
+        if self.is_loopback {
+            flags = String::from(" (Loopback)");
+        }
+
+        write!(f, "{}{}{}: {}", name, hardware_addr, flags, ip_addrs)
+    }
+}
+
+/// Gets a list of available network interfaces for the current machine.
+pub fn interfaces() -> Vec<Interface> {
+    let inters = datalink::interfaces();
+
+    let ifs: Vec<Interface> = inters
+        .iter()
+        .map(|inter| {
+            /* Cannot get flags using WinPcap in Windows
+            if !inter.is_up() {
+                return Err(());
+            }
+            */
+
+            let mut i = Interface::new();
+            i.name = inter.name.clone();
+            i.hardware_addr = match inter.mac {
+                Some(mac) => mac,
+                None => return Err(()),
+            };
+            i.ip_addrs = inter
+                .ips
+                .iter()
+                .map(|ip| match ip {
+                    ipnetwork::IpNetwork::V4(ref ipv4) => Ok(ipv4.ip()),
+                    _ => Err(()),
+                })
+                .filter_map(Result::ok)
+                .collect();
+
+            // Exclude interface without any IPv4 address
+            if i.ip_addrs.is_empty() {
+                return Err(());
+            }
+
+            i.is_up = inter.is_up();
+            i.is_loopback = inter.is_loopback();
+
+            Ok(i)
+        })
+        .filter_map(Result::ok)
+        .collect();
+
+    ifs
+}
+
+/// Represents the interfaces of the host, in which the `Redirector` follows the changes of the
+/// capture interface.
+pub trait Interfaces: Send {
+    /// Get the interface of the given name, or `None` if it is gone or has no IPv4 address.
+    fn get(&self, name: &str) -> Option<Interface>;
+}
+
+/// Represents the interfaces of the host found by pcap.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HostInterfaces;
+
+impl Interfaces for HostInterfaces {
+    fn get(&self, name: &str) -> Option<Interface> {
+        interfaces().into_iter().find(|inter| inter.name == name)
+    }
+}
+
+/// Represents the capture backend and what is found about it in runtime.
+#[derive(Clone, Debug)]
+pub struct Probe {
+    pub backend: &'static str,
+    pub library: Option<String>,
+    pub interfaces: usize,
+    pub is_privileged: Option<bool>,
+}
+
+/// Probes the capture backend. Missing components are reported as unknown, never as errors.
+pub fn probe() -> Probe {
+    Probe {
+        backend: get_backend(),
+        library: get_library(),
+        interfaces: interfaces().len(),
+        is_privileged: is_privileged(),
+    }
+}
+
+fn get_backend() -> &'static str {
+    if cfg!(target_os = "linux") {
+        "packet socket"
+    } else if cfg!(windows) {
+        "pcap"
+    } else if cfg!(any(
+        target_os = "macos",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd"
+    )) {
+        "bpf"
+    } else {
+        "unknown"
+    }
+}
+
+#[cfg(windows)]
+fn get_library() -> Option<String> {
+    let root = std::env::var("SystemRoot").unwrap_or_else(|_| String::from("C:\\Windows"));
+    if fs::metadata(format!("{}\\System32\\Npcap\\wpcap.dll", root)).is_ok() {
+        Some(String::from("Npcap"))
+    } else if fs::metadata(format!("{}\\System32\\wpcap.dll", root)).is_ok() {
+        Some(String::from("WinPcap"))
+    } else {
+        None
+    }
+}
+
+#[cfg(not(windows))]
+fn get_library() -> Option<String> {
+    // The kernel captures directly
+    Some(String::from("kernel"))
+}
+
+/// Returns if the process may capture, or `None` if unknown. In Linux, this checks the
+/// `CAP_NET_RAW` capability.
+fn is_privileged() -> Option<bool> {
+    if !cfg!(target_os = "linux") {
+        return None;
+    }
+    get_privileged(&fs::read_to_string("/proc/self/status").ok()?)
+}
+
+/// Get if the `CAP_NET_RAW` capability is effective in the status of a process in Linux, or
+/// `None` if the status has no effective capabilities.
+fn get_privileged(status: &str) -> Option<bool> {
+    let caps = status
+        .lines()
+        .find(|line| line.starts_with("CapEff:"))?
+        .split_whitespace()
+        .nth(1)?;
+    let caps = u64::from_str_radix(caps, 16).ok()?;
+
+    Some(caps & (1 << 13) != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probe() {
+        // Probing never panics, whatever is missing
+        let probe = probe();
+        assert!(!probe.backend.is_empty());
+
+        assert_eq!(
+            get_privileged("Name:\tpcap2socks\nCapEff:\t0000000000002000\n"),
+            Some(true)
+        );
+        assert_eq!(get_privileged("CapEff:\t0000000000000000"), Some(false));
+        assert_eq!(get_privileged(""), None);
+        assert_eq!(get_privileged("CapEff:"), None);
+        assert_eq!(get_privileged("CapEff:\tcap_net_raw"), None);
+    }
+}
@@ -0,0 +1,133 @@
+use std::fmt::{self, Display, Formatter};
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use crate::random::derive_seed;
+
+/// Represents how destinations of flows are recorded.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Privacy {
+    /// Records destinations as they are.
+    Full,
+    /// Records destinations truncated to /24.
+    Truncated,
+    /// Records pseudonyms of destinations hashed with a local salt.
+    Hashed,
+    /// Records no destination.
+    Omitted,
+}
+
+impl Privacy {
+    /// Creates a `Privacy` by its name.
+    pub fn from_name(name: &str) -> Option<Privacy> {
+        match name {
+            "full" => Some(Privacy::Full),
+            "truncated" => Some(Privacy::Truncated),
+            "hashed" => Some(Privacy::Hashed),
+            "omitted" => Some(Privacy::Omitted),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Privacy {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Privacy::Full => write!(f, "full"),
+            Privacy::Truncated => write!(f, "truncated"),
+            Privacy::Hashed => write!(f, "hashed"),
+            Privacy::Omitted => write!(f, "omitted"),
+        }
+    }
+}
+
+/// Represents the redaction of destinations of flows, applied when records of flows are created,
+/// like the environment of hooks and the flows of the statistics export, so raw destinations
+/// never leave the process when they are redacted.
+#[derive(Clone, Copy, Debug)]
+pub struct Redactor {
+    privacy: Privacy,
+    salt: u64,
+}
+
+impl Redactor {
+    /// Creates a new `Redactor`. The salt should be random and never recorded, so pseudonyms
+    /// cannot be reversed by hashing all the addresses, nor linked across runs.
+    pub fn new(privacy: Privacy, salt: u64) -> Redactor {
+        Redactor { privacy, salt }
+    }
+
+    /// Get the `Redactor` of the privacy with the same salt, or the `Redactor` itself if no privacy
+    /// is given, like for flows of rules without a privacy of their own.
+    pub fn with_privacy(&self, privacy: Option<Privacy>) -> Redactor {
+        match privacy {
+            Some(privacy) => Redactor::new(privacy, self.salt),
+            None => *self,
+        }
+    }
+
+    /// Get the privacy of the `Redactor`.
+    pub fn get_privacy(&self) -> Privacy {
+        self.privacy
+    }
+
+    /// Redacts a destination. Truncated destinations keep the port, pseudonyms are in 240.0.0.0/4
+    /// which no real destination is in, and omitted destinations are `0.0.0.0:0`.
+    pub fn redact(&self, dst: SocketAddrV4) -> SocketAddrV4 {
+        match self.privacy {
+            Privacy::Full => dst,
+            Privacy::Truncated => {
+                let octets = dst.ip().octets();
+                SocketAddrV4::new(
+                    Ipv4Addr::new(octets[0], octets[1], octets[2], 0),
+                    dst.port(),
+                )
+            }
+            Privacy::Hashed => {
+                let hash = derive_seed(self.salt, &dst.ip().to_string()) as u32;
+                SocketAddrV4::new(Ipv4Addr::from(0xf000_0000 | (hash >> 4)), dst.port())
+            }
+            Privacy::Omitted => SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0),
+        }
+    }
+}
+
+impl Default for Redactor {
+    fn default() -> Redactor {
+        Redactor::new(Privacy::Full, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        let dst = SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 7), 3074);
+        let redactor = Redactor::new(Privacy::Full, 1);
+        assert_eq!(redactor.redact(dst), dst);
+        assert_eq!(
+            redactor.with_privacy(Some(Privacy::Truncated)).redact(dst),
+            SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 0), 3074)
+        );
+        assert_eq!(
+            redactor.with_privacy(Some(Privacy::Omitted)).redact(dst),
+            SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)
+        );
+        assert_eq!(redactor.with_privacy(None).get_privacy(), Privacy::Full);
+
+        // Pseudonyms of a rule are the same as the global ones with the same salt
+        let hashed = Redactor::new(Privacy::Hashed, 1).redact(dst);
+        assert_eq!(
+            redactor.with_privacy(Some(Privacy::Hashed)).redact(dst),
+            hashed
+        );
+        assert!(hashed.ip().octets()[0] >= 240);
+        assert_ne!(Redactor::new(Privacy::Hashed, 2).redact(dst), hashed);
+
+        for name in ["full", "truncated", "hashed", "omitted"].iter() {
+            assert_eq!(Privacy::from_name(name).unwrap().to_string(), *name);
+        }
+        assert_eq!(Privacy::from_name("partial"), None);
+    }
+}
@@ -0,0 +1,267 @@
+use std::convert::TryInto;
+
+/// Represents a 128-bit key of `siphash`.
+pub type Key = [u64; 2];
+
+/// Represents a fast non-cryptographic pseudorandom number generator (xorshift64*).
+#[derive(Debug)]
+pub struct Random {
+    state: u64,
+}
+
+impl Random {
+    /// Creates a new `Random` with the given seed.
+    pub fn new(seed: u64) -> Random {
+        // The state of xorshift must not be zero
+        Random {
+            state: if seed == 0 {
+                0x9e37_79b9_7f4a_7c15
+            } else {
+                seed
+            },
+        }
+    }
+
+    /// Creates a new `Random` of the given component from the global seed. Each component has its
+    /// own sequence, so decisions of a component do not change the ones of the others.
+    pub fn derive(seed: u64, component: &str) -> Random {
+        Random::new(derive_seed(seed, component))
+    }
+
+    /// Get the next random `u64`.
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Get a random `usize` in [0, n).
+    pub fn next_below(&mut self, n: usize) -> usize {
+        if n == 0 {
+            return 0;
+        }
+
+        (self.next_u64() % n as u64) as usize
+    }
+
+    /// Get a random `f64` in [0, 1).
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Get the seed of a component from the global seed. The hash is predictable, secrets are keyed
+/// by `siphash` instead.
+pub fn derive_seed(seed: u64, component: &str) -> u64 {
+    // FNV-1a
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in component.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+
+    // splitmix64
+    let mut z = (seed ^ hash).wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+
+    z ^ (z >> 31)
+}
+
+/// Get a random global seed from the random source of the system.
+///
+/// # Panics
+///
+/// Panics if the random source of the system is unavailable, there is no fallback since secrets
+/// would become guessable.
+pub fn generate_seed() -> u64 {
+    let mut buffer = [0u8; 8];
+    fill(&mut buffer);
+
+    u64::from_ne_bytes(buffer)
+}
+
+/// Get a random key of `siphash` from the random source of the system.
+///
+/// # Panics
+///
+/// Panics if the random source of the system is unavailable.
+pub fn generate_key() -> Key {
+    let mut buffer = [0u8; 16];
+    fill(&mut buffer);
+
+    [
+        u64::from_ne_bytes(buffer[..8].try_into().unwrap()),
+        u64::from_ne_bytes(buffer[8..].try_into().unwrap()),
+    ]
+}
+
+fn fill(buffer: &mut [u8]) {
+    if let Err(ref e) = os::fill(buffer) {
+        panic!("random source of the system: {}", e);
+    }
+}
+
+/// Get the SipHash-2-4 of the data with the key, a pseudorandom function of the data which is
+/// unpredictable without the key.
+pub fn siphash(key: Key, data: &[u8]) -> u64 {
+    let mut v = [
+        key[0] ^ 0x736f_6d65_7073_6575,
+        key[1] ^ 0x646f_7261_6e64_6f6d,
+        key[0] ^ 0x6c79_6765_6e65_7261,
+        key[1] ^ 0x7465_6462_7974_6573,
+    ];
+
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        let m = u64::from_le_bytes(chunk.try_into().unwrap());
+        v[3] ^= m;
+        sip_round(&mut v);
+        sip_round(&mut v);
+        v[0] ^= m;
+    }
+
+    // The last block holds the remaining bytes and the length of the data
+    let remainder = chunks.remainder();
+    let mut last = [0u8; 8];
+    last[..remainder.len()].copy_from_slice(remainder);
+    last[7] = data.len() as u8;
+    let m = u64::from_le_bytes(last);
+    v[3] ^= m;
+    sip_round(&mut v);
+    sip_round(&mut v);
+    v[0] ^= m;
+
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        sip_round(&mut v);
+    }
+
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+fn sip_round(v: &mut [u64; 4]) {
+    v[0] = v[0].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(13);
+    v[1] ^= v[0];
+    v[0] = v[0].rotate_left(32);
+    v[2] = v[2].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(16);
+    v[3] ^= v[2];
+    v[0] = v[0].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(21);
+    v[3] ^= v[0];
+    v[2] = v[2].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(17);
+    v[1] ^= v[2];
+    v[2] = v[2].rotate_left(32);
+}
+
+#[cfg(unix)]
+mod os {
+    use std::fs::File;
+    use std::io::{self, Read};
+
+    /// Fills the buffer from `/dev/urandom`.
+    pub fn fill(buffer: &mut [u8]) -> io::Result<()> {
+        File::open("/dev/urandom")?.read_exact(buffer)
+    }
+}
+
+#[cfg(windows)]
+mod os {
+    use std::io;
+    use winapi::um::ntsecapi::RtlGenRandom;
+
+    /// Fills the buffer by `RtlGenRandom`.
+    pub fn fill(buffer: &mut [u8]) -> io::Result<()> {
+        for chunk in buffer.chunks_mut(u32::MAX as usize) {
+            let is_filled =
+                unsafe { RtlGenRandom(chunk.as_mut_ptr() as *mut _, chunk.len() as u32) != 0 };
+            if !is_filled {
+                return Err(io::Error::other("RtlGenRandom failed"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn draw(random: &mut Random) -> Vec<u64> {
+        (0..16).map(|_| random.next_u64()).collect()
+    }
+
+    #[test]
+    fn test_seed() {
+        // The same seed draws the same sequence, another seed another one
+        assert_eq!(draw(&mut Random::new(42)), draw(&mut Random::new(42)));
+        assert_ne!(draw(&mut Random::new(42)), draw(&mut Random::new(43)));
+        // The zero seed is valid
+        let sequence = draw(&mut Random::new(0));
+        assert!(sequence.iter().all(|value| *value != 0));
+    }
+
+    #[test]
+    fn test_derive() {
+        // Components of the same seed draw the same sequences in each run, and their own ones
+        let mut chaos = Random::derive(42, "chaos");
+        let mut upstreams = Random::derive(42, "upstreams");
+        let chaos_sequence = draw(&mut chaos);
+        let upstreams_sequence = draw(&mut upstreams);
+        assert_eq!(chaos_sequence, draw(&mut Random::derive(42, "chaos")));
+        assert_eq!(
+            upstreams_sequence,
+            draw(&mut Random::derive(42, "upstreams"))
+        );
+        assert_ne!(chaos_sequence, upstreams_sequence);
+        assert_ne!(chaos_sequence, draw(&mut Random::derive(43, "chaos")));
+
+        // Decisions of a component do not change the ones of the others
+        let mut upstreams = Random::derive(42, "upstreams");
+        let _ = draw(&mut Random::derive(42, "chaos"));
+        assert_eq!(draw(&mut upstreams), upstreams_sequence);
+    }
+
+    #[test]
+    fn test_siphash() {
+        // Vectors of the reference implementation, the key and the data are 00 01 02 ...
+        let key = [0x0706_0504_0302_0100, 0x0f0e_0d0c_0b0a_0908];
+        let data: Vec<u8> = (0..64).collect();
+        assert_eq!(siphash(key, &data[..0]), 0x726f_db47_dd0e_0e31);
+        assert_eq!(siphash(key, &data[..1]), 0x74f8_39c5_93dc_67fd);
+        assert_eq!(siphash(key, &data[..8]), 0x93f5_f579_9a93_2462);
+        assert_eq!(siphash(key, &data[..15]), 0xa129_ca61_49be_45e5);
+        assert_eq!(siphash(key, &data[..63]), 0x958a_324c_eb06_4572);
+        assert_ne!(
+            siphash([key[1], key[0]], &data[..15]),
+            siphash(key, &data[..15])
+        );
+    }
+
+    #[test]
+    fn test_generate_key() {
+        assert_ne!(generate_key(), generate_key());
+        assert_ne!(generate_seed(), generate_seed());
+    }
+
+    #[test]
+    fn test_next_below() {
+        let mut random = Random::new(42);
+        let decisions: Vec<usize> = (0..64).map(|_| random.next_below(3)).collect();
+        assert!(decisions.iter().all(|decision| *decision < 3));
+        let mut random = Random::new(42);
+        assert_eq!(
+            decisions,
+            (0..64).map(|_| random.next_below(3)).collect::<Vec<_>>()
+        );
+        assert_eq!(random.next_below(0), 0);
+        let value = random.next_f64();
+        assert!((0.0..1.0).contains(&value));
+    }
+}
@@ -0,0 +1,588 @@
+use ipnetwork::Ipv4Network;
+use log::{debug, info, warn};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddrV4, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::privacy::{Privacy, Redactor};
+
+/// Represents the magic at the start of record files.
+pub const RECORD_MAGIC: &[u8; 4] = b"P2SR";
+
+/// Represents the version of the format of record files.
+pub const RECORD_VERSION: u8 = 1;
+
+/// Represents the default max size of a record file in KB.
+pub const DEFAULT_RECORD_MAX_SIZE: usize = 16 * 1024;
+
+/// Represents the size of the header of a record file.
+const HEADER_SIZE: usize = 20;
+
+/// Represents the size of the header of an entry.
+const ENTRY_HEADER_SIZE: usize = 19;
+
+/// Represents the max size of the payload of an entry, entries are cut into pieces of it.
+const MAX_ENTRY_SIZE: usize = u16::MAX as usize;
+
+/// Represents the kind of a flow recorded.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Kind {
+    /// A SOCKS5 TCP stream.
+    Stream,
+    /// A SOCKS5 UDP association.
+    Datagram,
+}
+
+impl Kind {
+    fn from_u8(b: u8) -> Option<Kind> {
+        match b {
+            0 => Some(Kind::Stream),
+            1 => Some(Kind::Datagram),
+            _ => None,
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            Kind::Stream => 0,
+            Kind::Datagram => 1,
+        }
+    }
+}
+
+/// Represents the direction of an entry.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Direction {
+    /// Sent to the proxy.
+    Up,
+    /// Received from the proxy.
+    Down,
+}
+
+impl Direction {
+    fn from_u8(b: u8) -> Option<Direction> {
+        match b {
+            0 => Some(Direction::Up),
+            1 => Some(Direction::Down),
+            _ => None,
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            Direction::Up => 0,
+            Direction::Down => 1,
+        }
+    }
+}
+
+/// Represents a piece of data exchanged with the proxy.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Entry {
+    pub direction: Direction,
+    /// Represents the time since the start of the record.
+    pub elapsed: Duration,
+    /// Represents the destination, or the remote of a datagram.
+    pub addr: SocketAddrV4,
+    pub payload: Vec<u8>,
+}
+
+/// Represents a record of the data exchanged with the proxy in a flow, after the handshake.
+///
+/// A record file is a header of the magic `P2SR`, the version `1`, the kind (`0` TCP, `1` UDP),
+/// the destination in 4 octets and a 16-bit port, and the start in milliseconds since the Unix
+/// epoch in 64 bits, followed by entries. An entry is the direction (`0` to the proxy, `1` from
+/// the proxy), the time since the start in microseconds in 64 bits, the destination or the remote
+/// of a datagram in 4 octets and a 16-bit port, and the length of the payload in 32 bits, followed
+/// by the payload. All integers are big-endian.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Record {
+    pub kind: Kind,
+    pub dst: SocketAddrV4,
+    /// Represents the start in milliseconds since the Unix epoch.
+    pub start: u64,
+    pub entries: Vec<Entry>,
+}
+
+impl Record {
+    /// Reads a record from the given path. A truncated entry at the end is ignored, since the
+    /// process may be killed while recording.
+    pub fn read(path: &PathBuf) -> io::Result<Record> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let mut header = [0u8; HEADER_SIZE];
+        reader.read_exact(&mut header)?;
+        if &header[..4] != RECORD_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a record file",
+            ));
+        }
+        if header[4] != RECORD_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported version {}", header[4]),
+            ));
+        }
+        let kind = Kind::from_u8(header[5])
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unknown kind"))?;
+        let dst = read_addr(&header[6..12]);
+        let start = read_u64(&header[12..20]);
+
+        let mut entries = Vec::new();
+        loop {
+            let mut entry_header = [0u8; ENTRY_HEADER_SIZE];
+            match reader.read_exact(&mut entry_header) {
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+            let direction = Direction::from_u8(entry_header[0])
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unknown direction"))?;
+            let elapsed = Duration::from_micros(read_u64(&entry_header[1..9]));
+            let addr = read_addr(&entry_header[9..15]);
+            let size = read_u32(&entry_header[15..19]) as usize;
+            if size > MAX_ENTRY_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("entry of {} Bytes", size),
+                ));
+            }
+            let mut payload = vec![0u8; size];
+            match reader.read_exact(&mut payload) {
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+
+            entries.push(Entry {
+                direction,
+                elapsed,
+                addr,
+                payload,
+            });
+        }
+
+        Ok(Record {
+            kind,
+            dst,
+            start,
+            entries,
+        })
+    }
+}
+
+fn read_addr(b: &[u8]) -> SocketAddrV4 {
+    SocketAddrV4::new(
+        Ipv4Addr::new(b[0], b[1], b[2], b[3]),
+        ((b[4] as u16) << 8) | b[5] as u16,
+    )
+}
+
+fn read_u32(b: &[u8]) -> u32 {
+    b.iter().fold(0, |n, b| (n << 8) | *b as u32)
+}
+
+fn read_u64(b: &[u8]) -> u64 {
+    b.iter().fold(0, |n, b| (n << 8) | *b as u64)
+}
+
+fn write_addr(buffer: &mut Vec<u8>, addr: SocketAddrV4) {
+    buffer.extend_from_slice(&addr.ip().octets());
+    buffer.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+/// Represents a writer of a record file. Destinations are redacted, and nothing is written after
+/// the file reaches the max size.
+pub struct Recorder {
+    writer: BufWriter<File>,
+    path: PathBuf,
+    start: Instant,
+    size: usize,
+    max_size: usize,
+    redactor: Redactor,
+    is_full: bool,
+}
+
+impl Recorder {
+    /// Creates a new `Recorder` writing to the given path.
+    pub fn create(
+        path: PathBuf,
+        kind: Kind,
+        dst: SocketAddrV4,
+        max_size: usize,
+        redactor: Redactor,
+    ) -> io::Result<Recorder> {
+        let mut writer = BufWriter::new(File::create(&path)?);
+        let start = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as u64)
+            .unwrap_or(0);
+
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend_from_slice(RECORD_MAGIC);
+        header.push(RECORD_VERSION);
+        header.push(kind.to_u8());
+        write_addr(&mut header, redactor.redact(dst));
+        header.extend_from_slice(&start.to_be_bytes());
+        writer.write_all(&header)?;
+
+        Ok(Recorder {
+            writer,
+            path,
+            start: Instant::now(),
+            size: HEADER_SIZE,
+            max_size,
+            redactor,
+            is_full: false,
+        })
+    }
+
+    /// Records data exchanged with the proxy.
+    pub fn record(
+        &mut self,
+        direction: Direction,
+        addr: SocketAddrV4,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        if self.is_full {
+            return Ok(());
+        }
+
+        let elapsed = self.start.elapsed().as_micros() as u64;
+        let addr = self.redactor.redact(addr);
+        for chunk in payload.chunks(MAX_ENTRY_SIZE) {
+            if self.size + ENTRY_HEADER_SIZE + chunk.len() > self.max_size {
+                self.is_full = true;
+                debug!("record {} is full", self.path.display());
+                break;
+            }
+
+            let mut header = Vec::with_capacity(ENTRY_HEADER_SIZE);
+            header.push(direction.to_u8());
+            header.extend_from_slice(&elapsed.to_be_bytes());
+            write_addr(&mut header, addr);
+            header.extend_from_slice(&(chunk.len() as u32).to_be_bytes());
+            self.writer.write_all(&header)?;
+            self.writer.write_all(chunk)?;
+            self.size += ENTRY_HEADER_SIZE + chunk.len();
+        }
+
+        // Entries are kept even if the process is killed
+        self.writer.flush()
+    }
+}
+
+/// Represents the recording of flows to destinations in the filters into a directory.
+#[derive(Clone, Debug)]
+pub struct Recording {
+    dir: PathBuf,
+    filters: Vec<Ipv4Network>,
+    max_size: usize,
+    redactor: Redactor,
+    retention: Option<Duration>,
+}
+
+impl Recording {
+    /// Creates a new `Recording` into the given directory, which is created if it does not
+    /// exist. Flows to all destinations are recorded if there is no filter.
+    pub fn new(
+        dir: PathBuf,
+        filters: Vec<Ipv4Network>,
+        max_size: usize,
+        redactor: Redactor,
+    ) -> io::Result<Recording> {
+        fs::create_dir_all(&dir)?;
+
+        Ok(Recording {
+            dir,
+            filters,
+            max_size,
+            redactor,
+            retention: None,
+        })
+    }
+
+    /// Sets the max age of records, older ones are removed by `prune`.
+    pub fn set_retention(&mut self, retention: Option<Duration>) {
+        self.retention = retention;
+    }
+
+    /// Removes the records in the directory which started earlier than the retention before the
+    /// given time, and returns the number of records removed. Each flow is recorded in its own
+    /// file named by its start, so a record is removed as a whole, and files not named like a
+    /// record are never removed.
+    pub fn prune(&self, now: SystemTime) -> io::Result<usize> {
+        let retention = match self.retention {
+            Some(retention) => retention,
+            None => return Ok(0),
+        };
+        let now = now
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as u64)
+            .unwrap_or(0);
+        let cutoff = now.saturating_sub(retention.as_millis() as u64);
+
+        let mut count = 0;
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let start = match path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(get_start)
+            {
+                Some(start) => start,
+                None => continue,
+            };
+            if start < cutoff {
+                match fs::remove_file(&path) {
+                    Ok(_) => count += 1,
+                    Err(ref e) => warn!("prune record {}: {}", path.display(), e),
+                }
+            }
+        }
+        if count > 0 {
+            info!("Prune {} records in {}", count, self.dir.display());
+        }
+
+        Ok(count)
+    }
+
+    /// Returns if flows to the destination are recorded.
+    pub fn is_recorded(&self, dst: Ipv4Addr) -> bool {
+        self.filters.is_empty() || self.filters.iter().any(|filter| filter.contains(dst))
+    }
+
+    /// Creates a `Recorder` of a flow, named by the start, the kind, the source port and the
+    /// destination redacted with the given privacy if any.
+    pub fn create(
+        &self,
+        kind: Kind,
+        src_port: u16,
+        dst: SocketAddrV4,
+        privacy: Option<Privacy>,
+    ) -> io::Result<Recorder> {
+        let redactor = self.redactor.with_privacy(privacy);
+        let start = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis())
+            .unwrap_or(0);
+        let redacted = redactor.redact(dst);
+        let name = format!(
+            "{}-{}-{}-{}-{}.rec",
+            start,
+            match kind {
+                Kind::Stream => "tcp",
+                Kind::Datagram => "udp",
+            },
+            src_port,
+            redacted.ip(),
+            redacted.port()
+        );
+        let path = self.dir.join(name);
+        debug!("record {} -> {} to {}", src_port, dst, path.display());
+
+        Recorder::create(path, kind, dst, self.max_size, redactor)
+    }
+}
+
+/// Get the start in milliseconds since the Unix epoch of a record by its file name.
+fn get_start(name: &str) -> Option<u64> {
+    if !name.ends_with(".rec") {
+        return None;
+    }
+
+    name.split('-').next()?.parse().ok()
+}
+
+/// Plays the proxy side of a recorded TCP stream back to the first client connecting to the
+/// listener, as a SOCKS5 proxy without checking credentials. Data from the proxy is sent at its
+/// original time since the handshake, or as soon as possible if `is_compressed` is set, and data
+/// to the proxy is awaited and compared with the record.
+pub fn replay(path: &PathBuf, listener: TcpListener, is_compressed: bool) -> io::Result<()> {
+    let record = Record::read(path)?;
+    if record.kind != Kind::Stream {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "only TCP records can be replayed",
+        ));
+    }
+    info!(
+        "Replay {} entries of {} on {}",
+        record.entries.len(),
+        record.dst,
+        listener.local_addr()?
+    );
+
+    let (mut stream, addr) = listener.accept()?;
+    accept_socks(&mut stream)?;
+    info!("Replay to {}", addr);
+
+    let start = Instant::now();
+    let mut diverged = 0;
+    for (i, entry) in record.entries.iter().enumerate() {
+        match entry.direction {
+            Direction::Up => {
+                let mut buffer = vec![0u8; entry.payload.len()];
+                match stream.read_exact(&mut buffer) {
+                    Ok(_) => {}
+                    Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                        info!("Client closes at entry {}", i);
+                        break;
+                    }
+                    Err(e) => return Err(e),
+                }
+                if buffer != entry.payload {
+                    diverged += 1;
+                    warn!("entry {}: client sends different {} Bytes", i, buffer.len());
+                }
+            }
+            Direction::Down => {
+                if !is_compressed {
+                    if let Some(wait) = entry.elapsed.checked_sub(start.elapsed()) {
+                        thread::sleep(wait);
+                    }
+                }
+                stream.write_all(&entry.payload)?;
+            }
+        }
+    }
+    let _ = stream.shutdown(Shutdown::Both);
+    info!(
+        "Replay ends after {} ms, {} entries diverged",
+        start.elapsed().as_millis(),
+        diverged
+    );
+
+    Ok(())
+}
+
+/// Answers the SOCKS5 handshake of a client, accepting any method and any destination.
+fn accept_socks(stream: &mut TcpStream) -> io::Result<()> {
+    let invalid = |s: &str| io::Error::new(io::ErrorKind::InvalidData, format!("SOCKS: {}", s));
+
+    // Methods
+    let mut head = [0u8; 2];
+    stream.read_exact(&mut head)?;
+    if head[0] != 5 {
+        return Err(invalid("not SOCKS5"));
+    }
+    let mut methods = vec![0u8; head[1] as usize];
+    stream.read_exact(&mut methods)?;
+    if methods.contains(&0) {
+        stream.write_all(&[5, 0])?;
+    } else if methods.contains(&2) {
+        stream.write_all(&[5, 2])?;
+        // Username and password, which are ignored
+        let mut version = [0u8; 2];
+        stream.read_exact(&mut version)?;
+        let mut username = vec![0u8; version[1] as usize];
+        stream.read_exact(&mut username)?;
+        let mut size = [0u8; 1];
+        stream.read_exact(&mut size)?;
+        let mut password = vec![0u8; size[0] as usize];
+        stream.read_exact(&mut password)?;
+        stream.write_all(&[1, 0])?;
+    } else {
+        stream.write_all(&[5, 0xff])?;
+        return Err(invalid("no acceptable method"));
+    }
+
+    // Request
+    let mut request = [0u8; 4];
+    stream.read_exact(&mut request)?;
+    let size = match request[3] {
+        1 => 4,
+        3 => {
+            let mut size = [0u8; 1];
+            stream.read_exact(&mut size)?;
+            size[0] as usize
+        }
+        4 => 16,
+        _ => return Err(invalid("unknown address type")),
+    };
+    let mut addr = vec![0u8; size + 2];
+    stream.read_exact(&mut addr)?;
+    if request[1] != 1 {
+        // Command not supported
+        stream.write_all(&[5, 7, 0, 1, 0, 0, 0, 0, 0, 0])?;
+        return Err(invalid("not a CONNECT"));
+    }
+    stream.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    const DAY: u64 = 24 * 60 * 60;
+
+    #[test]
+    fn test_prune() {
+        let dir = env::temp_dir().join(format!("pcap2socks-record-{}", process::id()));
+        let mut recording = Recording::new(
+            dir.clone(),
+            Vec::new(),
+            DEFAULT_RECORD_MAX_SIZE,
+            Redactor::new(Privacy::Full, 0),
+        )
+        .unwrap();
+        recording.set_retention(Some(Duration::from_secs(DAY)));
+
+        // Records of 3 days, and files which are not records
+        let now = SystemTime::now();
+        let start = |days: u64| {
+            (now - Duration::from_secs(days * DAY))
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis()
+        };
+        let names = [
+            format!("{}-tcp-50000-192.0.2.1-443.rec", start(2)),
+            format!("{}-udp-50001-192.0.2.1-53.rec", start(1) + 1000),
+            String::from("notes.txt"),
+            String::from("old-tcp-50002-192.0.2.1-443.rec"),
+        ];
+        for name in names.iter() {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+        let dst = "192.0.2.1:80".parse().unwrap();
+        let recorder = recording.create(Kind::Stream, 50003, dst, None).unwrap();
+
+        let exists = |name: &str| dir.join(name).exists();
+        // Only the record older than the retention is removed
+        assert_eq!(recording.prune(now).unwrap(), 1);
+        assert!(!exists(&names[0]));
+        assert!(exists(&names[1]));
+        assert!(recorder.path.exists());
+        // A day later, the record of yesterday is removed
+        assert_eq!(recording.prune(now + Duration::from_secs(DAY)).unwrap(), 1);
+        assert!(!exists(&names[1]));
+        assert!(recorder.path.exists());
+        // And then the one of today, but never a file which is not a record
+        assert_eq!(
+            recording
+                .prune(now + Duration::from_secs(DAY + 60))
+                .unwrap(),
+            1
+        );
+        assert!(!recorder.path.exists());
+        assert!(exists(&names[2]));
+        assert!(exists(&names[3]));
+
+        // Nothing is removed without a retention
+        recording.set_retention(None);
+        fs::write(dir.join(&names[0]), b"").unwrap();
+        assert_eq!(recording.prune(now).unwrap(), 0);
+
+        drop(recorder);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
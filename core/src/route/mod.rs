@@ -0,0 +1,467 @@
+use log::{debug, info, warn};
+use std::collections::BTreeMap;
+use std::io;
+use std::net::SocketAddrV4;
+use std::time::{Duration, Instant};
+
+use crate::notify::Event;
+use crate::packet::layer::Layer;
+use crate::packet::Indicator;
+use crate::privacy::Privacy;
+use crate::record::{Kind, Recorder};
+use crate::rule::Admission;
+use crate::upstream::Route;
+use crate::{Redirector, Timer};
+
+/// Represents the time without packets after which a flow bridged to the real gateway is
+/// forgotten, and routed again as a new flow.
+const BRIDGE_IDLE_TIMEOUT: u64 = 300;
+
+impl Redirector {
+    /// Notifies and updates the health once the upstreams enter or leave the fallback mode.
+    pub(crate) fn follow_fallback(&mut self) {
+        if self.upstreams.is_fallback() != self.is_fallback_notified {
+            self.is_fallback_notified = self.upstreams.is_fallback();
+            self.notifier.notify(if self.is_fallback_notified {
+                Event::UpstreamsDown
+            } else {
+                Event::UpstreamsUp
+            });
+            self.update_health();
+        }
+    }
+
+    /// Forgets the TCP connection bridged to the real gateway idle for longer than its timeout.
+    pub(crate) fn expire_tcp_bridge(&mut self, key: (u16, SocketAddrV4)) {
+        let timeout = Duration::from_secs(BRIDGE_IDLE_TIMEOUT);
+        let idle = match self.tcp_flows.get(&key).and_then(|flow| flow.bridge) {
+            Some((instant, _)) => instant.elapsed(),
+            None => return,
+        };
+        if idle < timeout {
+            let handle = self.timers.schedule(timeout - idle, Timer::TcpBridge(key));
+            self.tcp_flows
+                .get_mut(&key)
+                .unwrap()
+                .bridge
+                .as_mut()
+                .unwrap()
+                .1 = handle;
+            return;
+        }
+
+        debug!("TCP {} -> {} bridged to the gateway is idle", key.0, key.1);
+        self.update_tcp_flow(key, |flow| flow.bridge = None);
+    }
+
+    /// Forgets the source port whose UDP datagrams are bridged to the real gateway idle for
+    /// longer than its timeout.
+    pub(crate) fn expire_udp_bridge(&mut self, src_port: u16) {
+        let timeout = Duration::from_secs(BRIDGE_IDLE_TIMEOUT);
+        let idle = match self.udp_bridge_map.get(&src_port) {
+            Some((instant, _)) => instant.elapsed(),
+            None => return,
+        };
+        if idle < timeout {
+            let handle = self
+                .timers
+                .schedule(timeout - idle, Timer::UdpBridge(src_port));
+            self.udp_bridge_map.get_mut(&src_port).unwrap().1 = handle;
+            return;
+        }
+
+        debug!("UDP {} bridged to the gateway is idle", src_port);
+        self.udp_bridge_map.remove(&src_port);
+    }
+
+    /// Bridges the packet to the real gateway if its flow is bridged, or if it opens a new flow
+    /// which the fallback policy bridges, and returns if it is bridged. A flow bridged stays
+    /// bridged until it is reset or idle, even if the upstreams are up again.
+    pub(crate) fn bridge(&mut self, indicator: &Indicator, buffer: &[u8]) -> io::Result<bool> {
+        let hardware_addr = match self.gateway_hardware_addr {
+            Some(hardware_addr) => hardware_addr,
+            None => return Ok(false),
+        };
+        let now = Instant::now();
+        let timeout = Duration::from_secs(BRIDGE_IDLE_TIMEOUT);
+        if let Some(tcp) = indicator.get_tcp() {
+            let dst = SocketAddrV4::new(tcp.get_dst_ip_addr(), tcp.get_dst());
+            let key = (tcp.get_src(), dst);
+            let bridge = self
+                .tcp_flows
+                .get_mut(&key)
+                .and_then(|flow| flow.bridge.as_mut());
+            if tcp.is_rst() {
+                match self
+                    .update_tcp_flow(key, |flow| flow.bridge.take())
+                    .flatten()
+                {
+                    Some((_, handle)) => {
+                        self.timers.cancel(handle);
+                    }
+                    None => return Ok(false),
+                }
+            } else if let Some(entry) = bridge {
+                entry.0 = now;
+            } else if tcp.is_syn()
+                && !tcp.is_ack()
+                && self.get_stream(&key).is_none()
+                && self.select_bridge(dst)
+            {
+                debug!("Bridge TCP {} -> {} to the gateway", key.0, key.1);
+                let handle = self.timers.schedule(timeout, Timer::TcpBridge(key));
+                self.get_tcp_flow(key).bridge = Some((now, handle));
+            } else {
+                return Ok(false);
+            }
+        } else if let Some(udp) = indicator.get_udp() {
+            let dst = SocketAddrV4::new(udp.get_dst_ip_addr(), udp.get_dst());
+            let src_port = udp.get_src();
+            if let Some(entry) = self.udp_bridge_map.get_mut(&src_port) {
+                entry.0 = now;
+            } else if self.datagram_map[src_port as usize] == 0 && self.select_bridge(dst) {
+                debug!("Bridge UDP {} to the gateway", src_port);
+                let handle = self.timers.schedule(timeout, Timer::UdpBridge(src_port));
+                self.udp_bridge_map.insert(src_port, (now, handle));
+            } else {
+                return Ok(false);
+            }
+        } else {
+            return Ok(false);
+        }
+
+        let start = indicator.get_ethernet().unwrap().get_size();
+        self.tx
+            .lock()
+            .unwrap()
+            .send_bridged(hardware_addr, &buffer[start..])?;
+
+        Ok(true)
+    }
+
+    /// Returns if a new flow to the given destination is bridged to the real gateway by the
+    /// fallback policy of the rule admitting it, or the global one if no rule admits it.
+    fn select_bridge(&mut self, dst: SocketAddrV4) -> bool {
+        self.rules.select_bridge(&mut self.upstreams, dst)
+    }
+
+    /// Selects the route of a new flow by the rule admitting it, or by the upstreams if no rule
+    /// admits it.
+    pub(crate) fn select_route(
+        &mut self,
+        dst: SocketAddrV4,
+        admission: Option<Admission>,
+    ) -> Route {
+        if let Some(admission) = admission {
+            self.rule_admitted[admission.rule] =
+                self.rule_admitted[admission.rule].saturating_add(1);
+        }
+
+        self.rules.select_route(&mut self.upstreams, dst, admission)
+    }
+
+    /// Creates the recorder of a new flow into the directory of the rule admitting it, or into the
+    /// global one if the destination is recorded.
+    pub(crate) fn create_recorder(
+        &self,
+        kind: Kind,
+        src_port: u16,
+        dst: SocketAddrV4,
+        admission: Option<Admission>,
+    ) -> Option<Recorder> {
+        let rule_recording =
+            admission.and_then(|admission| self.rule_recordings[admission.rule].as_ref());
+        let recording = match rule_recording {
+            Some(recording) => recording,
+            None => match self.recording {
+                Some(ref recording) if recording.is_recorded(*dst.ip()) => recording,
+                _ => return None,
+            },
+        };
+
+        match recording.create(
+            kind,
+            src_port,
+            dst,
+            self.get_rule_privacy(admission.as_ref()),
+        ) {
+            Ok(recorder) => Some(recorder),
+            Err(ref e) => {
+                warn!("record: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Get the privacy of the rule admitting a flow, if the rule has one.
+    pub(crate) fn get_rule_privacy(&self, admission: Option<&Admission>) -> Option<Privacy> {
+        admission.and_then(|admission| self.rules.get(admission.rule).get_privacy())
+    }
+
+    /// Closes the flows admitted by the rule of the name, or by any rule if it is not given, whose
+    /// windows do not contain the local time any more. TCP connections are reset to the source.
+    /// Flows of rules without windows are never expired.
+    pub(crate) fn expire_rules(&mut self, name: Option<&str>) {
+        let rule = match name {
+            Some(name) => match self.rules.position(name) {
+                Some(rule) => Some(rule),
+                None => {
+                    warn!(
+                        "Expire flows of {}: unknown rule {}",
+                        self.src_ip_addr, name
+                    );
+                    return;
+                }
+            },
+            None => None,
+        };
+        let (weekday, minute) = crate::schedule::now();
+        let rules = &self.rules;
+        let is_expired = |admission: Admission| {
+            rule.is_none_or(|rule| rule == admission.rule)
+                && !rules.is_active_at(admission, weekday, minute)
+        };
+
+        let keys: Vec<(u16, SocketAddrV4, Admission)> = self
+            .tcp_flows
+            .iter()
+            .filter_map(|(key, flow)| flow.rule.map(|admission| (key, admission)))
+            .filter(|(_, admission)| is_expired(*admission))
+            .map(|(key, admission)| (key.0, key.1, admission))
+            .collect();
+        let mut indexes = Vec::new();
+        for src_port in 0..self.datagram_map.len() {
+            let local_port = self.datagram_map[src_port];
+            if local_port == 0 {
+                continue;
+            }
+            let index = (local_port - self.udp_initial_port) as usize;
+            if self.datagrams[index].is_none() {
+                continue;
+            }
+            if let Some(admission) = self.datagram_rules[index] {
+                if is_expired(admission) {
+                    indexes.push((src_port, index, admission));
+                }
+            }
+        }
+
+        for (src_port, dst, admission) in keys.iter() {
+            let key = (*src_port, *dst);
+            debug!(
+                "TCP {} -> {} of rule {} is expired, reset",
+                key.0,
+                key.1,
+                self.rules.get_label(*admission)
+            );
+            if let Some(stream) = self.get_stream_mut(&key) {
+                stream.abort();
+            }
+            self.remove_key(key, "expired");
+            let mut tx_locked = self.tx.lock().unwrap();
+            if let Err(ref e) = tx_locked.send_tcp_ack_rst(key.1, key.0) {
+                warn!("handle {}: {}", "TCP", e);
+            }
+            tx_locked.remove(key.1, key.0);
+            self.rule_expired[admission.rule] = self.rule_expired[admission.rule].saturating_add(1);
+        }
+        for (src_port, index, admission) in indexes.iter() {
+            debug!(
+                "UDP {} of rule {} is expired, close",
+                src_port,
+                self.rules.get_label(*admission)
+            );
+            self.close_datagram_flow(*index, "expired");
+            self.datagrams[*index] = None;
+            self.datagram_rules[*index] = None;
+            self.datagram_map[*src_port] = 0;
+            self.udp_lru.put(*index as u16, 0);
+            self.rule_expired[admission.rule] = self.rule_expired[admission.rule].saturating_add(1);
+        }
+
+        info!(
+            "Expire flows of {} of {}: {} streams and {} associations",
+            self.src_ip_addr,
+            name.unwrap_or("all rules"),
+            keys.len(),
+            indexes.len()
+        );
+    }
+
+    /// Get the fallback mode of the upstreams and the number of times it changed.
+    pub fn dump_fallback(&self) -> String {
+        format!(
+            "{} ({} changes)",
+            if self.upstreams.is_fallback() {
+                self.upstreams.get_fallback().to_string()
+            } else {
+                String::from("proxy")
+            },
+            self.upstreams.get_mode_changes()
+        )
+    }
+
+    /// Get the rules with the number of new flows they admit and the flows closed by expiries,
+    /// and the open flows of each rule and window.
+    pub fn dump_rules(&self) -> String {
+        let mut open: BTreeMap<String, usize> = BTreeMap::new();
+        let admissions = self
+            .tcp_flows
+            .values()
+            .filter_map(|flow| flow.rule.as_ref())
+            .chain(
+                self.datagrams
+                    .iter()
+                    .zip(self.datagram_rules.iter())
+                    .filter_map(|(datagram, admission)| match datagram {
+                        Some(_) => admission.as_ref(),
+                        None => None,
+                    }),
+            );
+        for admission in admissions {
+            *open.entry(self.rules.get_label(*admission)).or_insert(0) += 1;
+        }
+
+        let mut lines: Vec<String> = (0..self.rules.len())
+            .map(|index| {
+                format!(
+                    "{}: {} admitted, {} expired",
+                    self.rules.get(index),
+                    self.rule_admitted[index],
+                    self.rule_expired[index]
+                )
+            })
+            .collect();
+        lines.extend(
+            open.iter()
+                .map(|(label, count)| format!("{}: {} open", label, count)),
+        );
+
+        lines.join("\n")
+    }
+
+    /// Get the time windows of the schedule and the number of new flows connected directly
+    /// outside them.
+    pub fn dump_schedule(&self) -> String {
+        match self.upstreams.get_schedule() {
+            Some(schedule) => format!(
+                "{} ({} flows direct, in window {})",
+                schedule,
+                self.upstreams.get_unscheduled(),
+                match schedule.find() {
+                    Some(window) => window.to_string(),
+                    None => String::from("none"),
+                }
+            ),
+            None => String::from("none"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::builder::Builder;
+    use crate::pcap::HardwareAddr;
+    use crate::testing;
+    use crate::upstream::Upstreams;
+    use pnet::packet::tcp::TcpFlags;
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_bridge() {
+        use crate::upstream::{Fallback, Policy};
+
+        const GATEWAY_HARDWARE_ADDR: HardwareAddr = pnet::util::MacAddr(2, 0, 0, 0, 0, 3);
+
+        let (forwarder, rx) = testing::forwarder(1500);
+        let remote = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1080));
+        let mut upstreams = Upstreams::new(vec![remote], Policy::First, Fallback::Gateway).unwrap();
+        let e = io::Error::from(io::ErrorKind::ConnectionRefused);
+        for _ in 0..3 {
+            upstreams.report_failure(remote, &e);
+        }
+        let mut redirector = Redirector::new(
+            Arc::new(Mutex::new(forwarder)),
+            testing::SRC_IP_ADDR,
+            None,
+            upstreams,
+            30000,
+            64,
+        );
+        redirector.set_gateway_hardware_addr(GATEWAY_HARDWARE_ADDR);
+        let dst = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 80);
+        let frame = |src_port: u16, flags: Option<u16>| {
+            let mut builder =
+                Builder::new(testing::SRC_HARDWARE_ADDR, testing::LOCAL_HARDWARE_ADDR);
+            builder.set_ipv4(1, testing::SRC_IP_ADDR, *dst.ip());
+            match flags {
+                Some(flags) => builder.set_tcp(src_port, dst.port(), 1, 0, 65535, flags),
+                None => builder.set_udp(src_port, dst.port()),
+            }
+            let frame = builder.build(b"bridged").unwrap();
+            let indicator = Indicator::from(&frame).unwrap();
+            let size = indicator.get_ethernet().unwrap().get_size()
+                + indicator.get_ipv4().unwrap().get_total_length() as usize;
+
+            frame[..size].to_vec()
+        };
+        let handle = |redirector: &mut Redirector, frame: &[u8]| {
+            let indicator = Indicator::from(frame).unwrap();
+            if indicator.get_tcp().is_some() {
+                redirector.handle_tcp(&indicator, frame).unwrap();
+            } else {
+                redirector.handle_udp(&indicator, frame).unwrap();
+            }
+        };
+        let bridged = || -> Vec<Vec<u8>> {
+            rx.try_iter()
+                .filter(|frame| frame[..6] == [2, 0, 0, 0, 0, 3])
+                .collect()
+        };
+
+        // A new flow probing the down upstream is never bridged, the next one is
+        let mut src_port = 50000;
+        let syn = loop {
+            assert!(src_port < 50010);
+            let syn = frame(src_port, Some(TcpFlags::SYN));
+            handle(&mut redirector, &syn);
+            if redirector
+                .tcp_flows
+                .get(&(src_port, dst))
+                .is_some_and(|flow| flow.bridge.is_some())
+            {
+                break syn;
+            }
+            src_port += 1;
+        };
+        let frames = bridged();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0][6..12], [2, 0, 0, 0, 0, 1]);
+        assert_eq!(frames[0][14..], syn[14..]);
+        assert!(redirector.get_stream(&(src_port, dst)).is_none());
+
+        // Later segments of the flow are bridged, until it is reset
+        let ack = frame(src_port, Some(TcpFlags::ACK));
+        handle(&mut redirector, &ack);
+        let rst = frame(src_port, Some(TcpFlags::RST));
+        handle(&mut redirector, &rst);
+        let frames = bridged();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0][14..], ack[14..]);
+        assert_eq!(frames[1][14..], rst[14..]);
+        assert!(!redirector.tcp_flows.contains_key(&(src_port, dst)));
+
+        // UDP of a new source port, whose datagrams are bridged without any association
+        let datagram = frame(50100, None);
+        handle(&mut redirector, &datagram);
+        handle(&mut redirector, &datagram);
+        let frames = bridged();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[1][14..], datagram[14..]);
+        assert!(redirector.udp_bridge_map.contains_key(&50100));
+        assert_eq!(redirector.datagram_map[50100], 0);
+        assert_eq!(redirector.upstreams.get_fallbacks(None), 2);
+    }
+}
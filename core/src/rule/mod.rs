@@ -0,0 +1,641 @@
+use ipnetwork::Ipv4Network;
+use log::trace;
+use std::fmt::{self, Display, Formatter};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::PathBuf;
+
+use crate::pcap::HardwareAddr;
+use crate::privacy::Privacy;
+use crate::schedule::{self, Window};
+use crate::upstream::{Fallback, Route, Upstreams};
+use crate::ResumeStrategy;
+
+/// Represents the action of a rule on the flows it admits.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Action {
+    /// Through the upstreams, regardless of the schedule.
+    Proxy,
+    /// To the destination directly.
+    Direct,
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Action::Proxy => write!(f, "proxy"),
+            Action::Direct => write!(f, "direct"),
+        }
+    }
+}
+
+/// Represents a routing rule, which admits new flows to destinations in its network and ports
+/// while one of its time windows contains the local time. A rule without windows is always
+/// active. Options of a rule apply to the flows it admits instead of the global ones.
+///
+/// A rule of servers serves the UDP ports of the source in its ports instead, whose clients send
+/// first, and admits no flows.
+#[derive(Clone, Debug)]
+pub struct Rule {
+    name: String,
+    dst: Ipv4Network,
+    ports: Option<(u16, u16)>,
+    action: Action,
+    windows: Vec<Window>,
+    is_game: bool,
+    is_server: bool,
+    normalize_remote_port: Option<bool>,
+    resume_strategy: Option<ResumeStrategy>,
+    privacy: Option<Privacy>,
+    record_upstream: Option<PathBuf>,
+    fallback: Option<Fallback>,
+}
+
+impl Rule {
+    /// Creates a `Rule` according to the given string in `NAME [KEY=VALUE]...`, where keys are
+    /// `dst` of a network in CIDR, `ports` of a port or a range of ports, `route` of `proxy` or
+    /// `direct`, `active` of a window in `HH:MM-HH:MM[@DAYS]`, which can be given multiple times,
+    /// `normalize-remote-port` of `on` or `off`, `resume-strategy` of `freeze`, `fin` or `rst`,
+    /// `privacy` of `full`, `truncated`, `hashed` or `omitted`, `record-upstream` of a directory,
+    /// and `fallback` of `direct`, `fail` or `gateway`. The word `game` marks a rule of games, e.g.
+    /// `games dst=0.0.0.0/0 ports=3074-3075 active=18:00-01:00@fri,sat game`. The word `server`
+    /// marks a rule of servers, which takes no key other than `ports` of the source, e.g.
+    /// `game-server ports=27015 server`.
+    pub fn parse(s: &str) -> Option<Rule> {
+        let mut words = s.split_whitespace();
+        let name = words.next()?;
+        if name.contains('=') {
+            return None;
+        }
+        let mut rule = Rule {
+            name: String::from(name),
+            dst: Ipv4Network::new(Ipv4Addr::UNSPECIFIED, 0).unwrap(),
+            ports: None,
+            action: Action::Proxy,
+            windows: Vec::new(),
+            is_game: false,
+            is_server: false,
+            normalize_remote_port: None,
+            resume_strategy: None,
+            privacy: None,
+            record_upstream: None,
+            fallback: None,
+        };
+
+        let mut keys = Vec::new();
+        for word in words {
+            let mut parts = word.splitn(2, '=');
+            let key = parts.next()?;
+            let value = match parts.next() {
+                Some(value) => value,
+                None => {
+                    match key {
+                        "game" => rule.is_game = true,
+                        "server" => rule.is_server = true,
+                        _ => return None,
+                    }
+                    continue;
+                }
+            };
+            keys.push(key);
+            match key {
+                "dst" => rule.dst = value.parse().ok()?,
+                "ports" => rule.ports = Some(parse_ports(value)?),
+                "route" => {
+                    rule.action = match value {
+                        "proxy" => Action::Proxy,
+                        "direct" => Action::Direct,
+                        _ => return None,
+                    }
+                }
+                "active" => rule.windows.push(Window::parse(value)?),
+                "normalize-remote-port" => rule.normalize_remote_port = Some(parse_switch(value)?),
+                "resume-strategy" => rule.resume_strategy = Some(ResumeStrategy::from_name(value)?),
+                "privacy" => rule.privacy = Some(Privacy::from_name(value)?),
+                "record-upstream" if !value.is_empty() => {
+                    rule.record_upstream = Some(PathBuf::from(value))
+                }
+                "fallback" => rule.fallback = Some(Fallback::from_name(value)?),
+                _ => return None,
+            }
+        }
+        // Servers are bound on the ports of the source, which is never port 0
+        if rule.is_server {
+            match rule.ports {
+                Some((first, _)) if first > 0 => {}
+                _ => return None,
+            }
+            if rule.is_game || keys.iter().any(|key| *key != "ports") {
+                return None;
+            }
+        }
+
+        Some(rule)
+    }
+
+    /// Get the name of the rule.
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    /// Get the action of the rule.
+    pub fn get_action(&self) -> Action {
+        self.action
+    }
+
+    /// Returns if the rule is of games.
+    pub fn is_game(&self) -> bool {
+        self.is_game
+    }
+
+    /// Returns if the rule is of servers of the source.
+    pub fn is_server(&self) -> bool {
+        self.is_server
+    }
+
+    /// Get if the remote port of UDP replies is normalized in associations of the rule, which is
+    /// on for rules of games by default, or `None` if the global option applies.
+    pub fn get_normalize_remote_port(&self) -> Option<bool> {
+        match self.normalize_remote_port {
+            Some(is_normalize) => Some(is_normalize),
+            None if self.is_game => Some(true),
+            None => None,
+        }
+    }
+
+    /// Get the handling of TCP connections of the rule whose upstream goes down, or `None` if the
+    /// global one applies.
+    pub fn get_resume_strategy(&self) -> Option<ResumeStrategy> {
+        self.resume_strategy
+    }
+
+    /// Get how destinations of flows of the rule are recorded, or `None` if the global privacy
+    /// applies.
+    pub fn get_privacy(&self) -> Option<Privacy> {
+        self.privacy
+    }
+
+    /// Get the directory recording the data exchanged with the proxy in flows of the rule.
+    pub fn get_record_upstream(&self) -> Option<&PathBuf> {
+        self.record_upstream.as_ref()
+    }
+
+    /// Get the policy for new flows of the rule when all the upstreams are down, or `None` if the
+    /// global one applies.
+    pub fn get_fallback(&self) -> Option<Fallback> {
+        self.fallback
+    }
+
+    /// Returns if the rule covers the destination, regardless of its windows.
+    pub fn is_covered(&self, dst: SocketAddrV4) -> bool {
+        self.dst.contains(*dst.ip())
+            && match self.ports {
+                Some((first, last)) => dst.port() >= first && dst.port() <= last,
+                None => true,
+            }
+    }
+
+    /// Get the index of the first window containing the given day of the week and minute of the
+    /// day, or `Some(None)` if the rule has no windows and is always active.
+    fn find_window(&self, weekday: u8, minute: u16) -> Option<Option<usize>> {
+        if self.windows.is_empty() {
+            return Some(None);
+        }
+
+        self.windows
+            .iter()
+            .position(|window| window.contains(weekday, minute))
+            .map(Some)
+    }
+}
+
+impl Display for Rule {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if self.is_server {
+            let (first, last) = self.ports.unwrap_or_default();
+            if first == last {
+                return write!(f, "{} ports={} server", self.name, first);
+            }
+            return write!(f, "{} ports={}-{} server", self.name, first, last);
+        }
+        write!(f, "{} dst={}", self.name, self.dst)?;
+        if let Some((first, last)) = self.ports {
+            if first == last {
+                write!(f, " ports={}", first)?;
+            } else {
+                write!(f, " ports={}-{}", first, last)?;
+            }
+        }
+        write!(f, " route={}", self.action)?;
+        for window in &self.windows {
+            write!(f, " active={}", window)?;
+        }
+        if self.is_game {
+            write!(f, " game")?;
+        }
+        if let Some(is_normalize) = self.normalize_remote_port {
+            write!(
+                f,
+                " normalize-remote-port={}",
+                if is_normalize { "on" } else { "off" }
+            )?;
+        }
+        if let Some(strategy) = self.resume_strategy {
+            write!(f, " resume-strategy={}", strategy)?;
+        }
+        if let Some(privacy) = self.privacy {
+            write!(f, " privacy={}", privacy)?;
+        }
+        if let Some(ref dir) = self.record_upstream {
+            write!(f, " record-upstream={}", dir.display())?;
+        }
+        if let Some(fallback) = self.fallback {
+            write!(f, " fallback={}", fallback)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_switch(s: &str) -> Option<bool> {
+    match s {
+        "on" => Some(true),
+        "off" => Some(false),
+        _ => None,
+    }
+}
+
+fn parse_ports(s: &str) -> Option<(u16, u16)> {
+    let mut range = s.splitn(2, '-');
+    let first: u16 = range.next()?.parse().ok()?;
+    let last: u16 = match range.next() {
+        Some(last) => last.parse().ok()?,
+        None => first,
+    };
+    if first > last {
+        return None;
+    }
+
+    Some((first, last))
+}
+
+/// Represents the rule admitting a flow, and the window of the rule it is admitted in, if the
+/// rule has windows.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Admission {
+    pub rule: usize,
+    pub window: Option<usize>,
+}
+
+/// Represents the routing rules, which are evaluated in order against the local time when a flow
+/// is created. Flows covered by no active rule are routed by the upstreams and their schedule.
+/// Windows starting or ending do not affect flows already open, unless they are expired
+/// explicitly.
+#[derive(Clone, Debug, Default)]
+pub struct Rules {
+    rules: Vec<Rule>,
+}
+
+impl Rules {
+    /// Creates a new `Rules`.
+    pub fn new(rules: Vec<Rule>) -> Rules {
+        Rules { rules }
+    }
+
+    /// Returns if there is no rule.
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Get the number of rules.
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// Get the rule of the index.
+    pub fn get(&self, index: usize) -> &Rule {
+        &self.rules[index]
+    }
+
+    /// Get the index of the rule of the name.
+    pub fn position(&self, name: &str) -> Option<usize> {
+        self.rules.iter().position(|rule| rule.name == name)
+    }
+
+    /// Get the first active rule covering the destination at the current local time.
+    pub fn find(&self, dst: SocketAddrV4) -> Option<Admission> {
+        if self.is_empty() {
+            return None;
+        }
+        let (weekday, minute) = schedule::now();
+
+        self.find_at(dst, weekday, minute)
+    }
+
+    /// Get the UDP ports of the source served by the rules of servers, in order.
+    pub fn get_server_ports(&self) -> Vec<u16> {
+        let mut ports: Vec<u16> = self
+            .rules
+            .iter()
+            .filter(|rule| rule.is_server)
+            .filter_map(|rule| rule.ports)
+            .flat_map(|(first, last)| first..=last)
+            .collect();
+        ports.sort_unstable();
+        ports.dedup();
+
+        ports
+    }
+
+    /// Get the first active rule covering the destination at the given day of the week and
+    /// minute of the day. Rules of servers admit no flows.
+    pub fn find_at(&self, dst: SocketAddrV4, weekday: u8, minute: u16) -> Option<Admission> {
+        self.rules.iter().enumerate().find_map(|(index, rule)| {
+            if rule.is_server || !rule.is_covered(dst) {
+                return None;
+            }
+
+            rule.find_window(weekday, minute).map(|window| Admission {
+                rule: index,
+                window,
+            })
+        })
+    }
+
+    /// Returns if the window admitting a flow contains the given day of the week and minute of
+    /// the day. Flows admitted by rules without windows are always active.
+    pub fn is_active_at(&self, admission: Admission, weekday: u8, minute: u16) -> bool {
+        match admission.window {
+            Some(window) => self.rules[admission.rule].windows[window].contains(weekday, minute),
+            None => true,
+        }
+    }
+
+    /// Get the label of an admission, the name of the rule and the window, e.g.
+    /// `games 18:00-01:00@fri,sat`.
+    pub fn get_label(&self, admission: Admission) -> String {
+        let rule = &self.rules[admission.rule];
+        match admission.window {
+            Some(window) => format!("{} {}", rule.name, rule.windows[window]),
+            None => rule.name.clone(),
+        }
+    }
+
+    /// Returns if a new flow to the given destination is bridged to the real gateway by the
+    /// fallback policy of the rule admitting it, or the global one if no rule admits it.
+    pub fn select_bridge(&self, upstreams: &mut Upstreams, dst: SocketAddrV4) -> bool {
+        match self.find(dst) {
+            Some(admission) => match self.get(admission.rule).get_action() {
+                Action::Proxy => upstreams.select_bridge(Some(admission.rule)),
+                Action::Direct => false,
+            },
+            None => upstreams.select_bridge(None),
+        }
+    }
+
+    /// Selects the route of a new flow by the rule admitting it, or by the upstreams if no rule
+    /// admits it.
+    pub fn select_route(
+        &self,
+        upstreams: &mut Upstreams,
+        dst: SocketAddrV4,
+        admission: Option<Admission>,
+    ) -> Route {
+        match admission {
+            Some(admission) => {
+                trace!("admit {} by rule {}", dst, self.get_label(admission));
+                match self.get(admission.rule).get_action() {
+                    Action::Proxy => upstreams.select_upstream(*dst.ip(), Some(admission.rule)),
+                    Action::Direct => Route::Direct,
+                }
+            }
+            None => upstreams.select(*dst.ip()),
+        }
+    }
+
+    /// Selects the route of a new flow to the given destination as the `Redirector` does, which
+    /// bridges it to the real gateway if the hardware address of the gateway is known.
+    pub fn route(
+        &self,
+        upstreams: &mut Upstreams,
+        gateway: Option<HardwareAddr>,
+        dst: SocketAddrV4,
+    ) -> Route {
+        if gateway.is_some() && self.select_bridge(upstreams, dst) {
+            return Route::Bridge;
+        }
+
+        self.select_route(upstreams, dst, self.find(dst))
+    }
+}
+
+impl Display for Rules {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            self.rules
+                .iter()
+                .map(|rule| rule.to_string())
+                .collect::<Vec<String>>()
+                .join(", ")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THU: u8 = 4;
+    const FRI: u8 = 5;
+    const SAT: u8 = 6;
+
+    fn dst(port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 1), port)
+    }
+
+    fn rules() -> Rules {
+        Rules::new(vec![
+            Rule::parse("updates dst=203.0.113.0/24 ports=80-443 route=direct").unwrap(),
+            Rule::parse("games ports=3074 active=06:00-08:00 active=18:00-01:00@fri,sat").unwrap(),
+        ])
+    }
+
+    #[test]
+    fn test_parse() {
+        let rule = Rule::parse("games ports=3074 active=18:00-01:00@fri,sat").unwrap();
+        assert_eq!(
+            rule.to_string(),
+            "games dst=0.0.0.0/0 ports=3074 route=proxy active=18:00-01:00@fri,sat"
+        );
+        let rule = Rule::parse("updates  dst=203.0.113.0/24 ports=80-443 route=direct").unwrap();
+        assert_eq!(
+            rule.to_string(),
+            "updates dst=203.0.113.0/24 ports=80-443 route=direct"
+        );
+
+        assert!(Rule::parse("").is_none());
+        assert!(Rule::parse("dst=203.0.113.0/24").is_none());
+        assert!(Rule::parse("games ports=443-80").is_none());
+        assert!(Rule::parse("games route=reject").is_none());
+        assert!(Rule::parse("games active=18:00").is_none());
+        assert!(Rule::parse("games color=red").is_none());
+        assert!(Rule::parse("games dst").is_none());
+    }
+
+    #[test]
+    fn test_normalize_remote_port() {
+        let rule = Rule::parse("games ports=3074 game").unwrap();
+        assert!(rule.is_game());
+        assert_eq!(rule.get_normalize_remote_port(), Some(true));
+        assert_eq!(
+            rule.to_string(),
+            "games dst=0.0.0.0/0 ports=3074 route=proxy game"
+        );
+
+        let rule = Rule::parse("games ports=3074 game normalize-remote-port=off").unwrap();
+        assert_eq!(rule.get_normalize_remote_port(), Some(false));
+        let rule = Rule::parse("voice normalize-remote-port=on").unwrap();
+        assert_eq!(rule.get_normalize_remote_port(), Some(true));
+        // Other rules take the global option
+        let rule = Rule::parse("updates ports=443").unwrap();
+        assert_eq!(rule.get_normalize_remote_port(), None);
+
+        assert!(Rule::parse("games casual").is_none());
+        assert!(Rule::parse("games normalize-remote-port=yes").is_none());
+    }
+
+    #[test]
+    fn test_resume_strategy() {
+        let rule = Rule::parse("web ports=80-443 resume-strategy=fin").unwrap();
+        assert_eq!(rule.get_resume_strategy(), Some(ResumeStrategy::Fin));
+        assert_eq!(
+            rule.to_string(),
+            "web dst=0.0.0.0/0 ports=80-443 route=proxy resume-strategy=fin"
+        );
+        let rule = Rule::parse("games game resume-strategy=freeze").unwrap();
+        assert_eq!(rule.get_resume_strategy(), Some(ResumeStrategy::Freeze));
+        assert_eq!(Rule::parse("games").unwrap().get_resume_strategy(), None);
+        assert!(Rule::parse("web resume-strategy=reconnect").is_none());
+    }
+
+    #[test]
+    fn test_privacy() {
+        let rule = Rule::parse("home dst=192.168.0.0/16 privacy=omitted").unwrap();
+        assert_eq!(rule.get_privacy(), Some(Privacy::Omitted));
+        assert_eq!(
+            rule.to_string(),
+            "home dst=192.168.0.0/16 route=proxy privacy=omitted"
+        );
+        assert_eq!(Rule::parse("home").unwrap().get_privacy(), None);
+        assert!(Rule::parse("home privacy=partial").is_none());
+    }
+
+    #[test]
+    fn test_record_upstream() {
+        let rule = Rule::parse("broken dst=198.51.100.7/32 record-upstream=records/").unwrap();
+        assert_eq!(rule.get_record_upstream(), Some(&PathBuf::from("records/")));
+        assert_eq!(
+            rule.to_string(),
+            "broken dst=198.51.100.7/32 route=proxy record-upstream=records/"
+        );
+        assert_eq!(Rule::parse("broken").unwrap().get_record_upstream(), None);
+        assert!(Rule::parse("broken record-upstream=").is_none());
+    }
+
+    #[test]
+    fn test_fallback() {
+        let rule = Rule::parse("games ports=3074 game fallback=gateway").unwrap();
+        assert_eq!(rule.get_fallback(), Some(Fallback::Gateway));
+        assert_eq!(
+            rule.to_string(),
+            "games dst=0.0.0.0/0 ports=3074 route=proxy game fallback=gateway"
+        );
+        let rule = Rule::parse("web ports=80-443 fallback=fail").unwrap();
+        assert_eq!(rule.get_fallback(), Some(Fallback::Fail));
+        assert_eq!(Rule::parse("web").unwrap().get_fallback(), None);
+        assert!(Rule::parse("web fallback=retry").is_none());
+    }
+
+    #[test]
+    fn test_server() {
+        let rule = Rule::parse("game-server ports=27015 server").unwrap();
+        assert!(rule.is_server());
+        assert_eq!(rule.to_string(), "game-server ports=27015 server");
+        let rule = Rule::parse("voice server ports=9987-9988").unwrap();
+        assert_eq!(rule.to_string(), "voice ports=9987-9988 server");
+
+        // Servers take their ports only
+        assert!(Rule::parse("game-server server").is_none());
+        assert!(Rule::parse("game-server ports=0 server").is_none());
+        assert!(Rule::parse("game-server ports=27015 route=direct server").is_none());
+        assert!(Rule::parse("game-server ports=27015 active=18:00-01:00 server").is_none());
+        assert!(Rule::parse("game-server ports=27015 game server").is_none());
+
+        // Servers admit no flows to their ports
+        let rules = Rules::new(vec![
+            Rule::parse("game-server ports=27015-27016 server").unwrap(),
+            Rule::parse("voice ports=27016 server").unwrap(),
+            Rule::parse("all").unwrap(),
+        ]);
+        assert_eq!(rules.get_server_ports(), vec![27015, 27016]);
+        let admission = rules.find_at(dst(27015), FRI, 0).unwrap();
+        assert_eq!(admission.rule, 2);
+    }
+
+    #[test]
+    fn test_find_at() {
+        let rules = rules();
+        // Rules without windows are always active
+        let admission = rules.find_at(dst(443), THU, 4 * 60).unwrap();
+        assert_eq!(
+            admission,
+            Admission {
+                rule: 0,
+                window: None
+            }
+        );
+        assert_eq!(rules.get_label(admission), "updates");
+
+        let admission = rules.find_at(dst(3074), FRI, 7 * 60).unwrap();
+        assert_eq!(
+            admission,
+            Admission {
+                rule: 1,
+                window: Some(0)
+            }
+        );
+        assert_eq!(rules.get_label(admission), "games 06:00-08:00");
+
+        // Outside the windows, the flow is not admitted by any rule
+        assert_eq!(rules.find_at(dst(3074), FRI, 12 * 60), None);
+        assert_eq!(rules.find_at(dst(53), FRI, 7 * 60), None);
+    }
+
+    #[test]
+    fn test_across_midnight() {
+        let rules = rules();
+        let admission = rules.find_at(dst(3074), FRI, 23 * 60 + 59).unwrap();
+        assert_eq!(rules.get_label(admission), "games 18:00-01:00@fri,sat");
+        // The window started on Friday still admits flows after midnight
+        let after_midnight = rules.find_at(dst(3074), SAT, 0).unwrap();
+        assert_eq!(after_midnight, admission);
+        assert!(rules.is_active_at(admission, SAT, 59));
+        assert!(!rules.is_active_at(admission, SAT, 60));
+        assert_eq!(rules.find_at(dst(3074), SAT, 60), None);
+        // Nor before the first day
+        assert_eq!(rules.find_at(dst(3074), FRI, 30), None);
+
+        let always = rules.find_at(dst(80), SAT, 60).unwrap();
+        assert!(rules.is_active_at(always, THU, 12 * 60));
+    }
+
+    #[test]
+    fn test_position() {
+        let rules = rules();
+        assert_eq!(rules.position("games"), Some(1));
+        assert_eq!(rules.position("movies"), None);
+        assert_eq!(rules.len(), 2);
+        assert!(Rules::default().find(dst(3074)).is_none());
+    }
+}
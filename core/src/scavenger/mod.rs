@@ -0,0 +1,304 @@
+use log::{debug, trace, warn};
+use std::collections::HashSet;
+use std::net::SocketAddrV4;
+use std::time::{Duration, Instant};
+
+use crate::threads::Purpose;
+use crate::{Redirector, Timer, STALE_TIMEOUT};
+
+impl Redirector {
+    /// Cross-validates the TCP and UDP tables and the thread registry, and repairs
+    /// inconsistent entries.
+    pub(crate) fn scavenge(&mut self) {
+        self.last_scavenge = Instant::now();
+        let mut repairs = 0;
+
+        // ARP rates of past seconds
+        self.arp_rate_map
+            .retain(|_, (instant, _)| instant.elapsed().as_secs() < 1);
+
+        // UDP destinations of past windows
+        self.fanout.scavenge();
+
+        // DNS queries whose answers never come
+        if let Some(ref dns) = self.dns {
+            dns.lock().unwrap().scavenge();
+        }
+
+        // Expired quarantines
+        let now = Instant::now();
+        self.udp_quarantine_map.retain(|_, instant| now < *instant);
+
+        // Expired UPnP leases
+        for lease in self.upnp_leases.lock().unwrap().scavenge(now) {
+            debug!("Expire UPnP lease {}", lease);
+        }
+
+        // Streams whose workers are closed for long
+        let timers = &mut self.timers;
+        for (key, flow) in self.tcp_flows.iter_mut() {
+            let is_closed = flow
+                .stream
+                .as_ref()
+                .is_some_and(|stream| stream.is_closed());
+            if is_closed {
+                flow.closed.get_or_insert_with(|| {
+                    timers.schedule(Duration::from_secs(STALE_TIMEOUT), Timer::Stale(*key))
+                });
+            } else if let Some(handle) = flow.closed.take() {
+                timers.cancel(handle);
+            }
+        }
+
+        // TCP information without streams, whose source is reset since nothing serves the flow.
+        // Flows bridged to the real gateway have no stream by design
+        let keys = self.tx.lock().unwrap().get_tcp_keys();
+        let orphans: HashSet<(u16, SocketAddrV4)> = self
+            .tcp_flows
+            .iter()
+            .filter(|(_, flow)| flow.bridge.is_none())
+            .map(|(key, _)| key)
+            .chain(keys.iter())
+            .filter(|key| self.get_stream(key).is_none())
+            .cloned()
+            .collect();
+        for key in orphans {
+            warn!(
+                "scavenge: TCP information of {} -> {} without a stream, reset",
+                key.0, key.1
+            );
+            self.remove_key(key, "orphan");
+            self.reset_source(key);
+            repairs += 1;
+        }
+
+        // Streams without forwarding information, whose workers are leaked since nothing of the
+        // source reaches them
+        let leaked: Vec<(u16, SocketAddrV4)> = self
+            .get_stream_keys()
+            .into_iter()
+            .filter(|key| !keys.contains(key))
+            .collect();
+        for key in leaked {
+            warn!(
+                "scavenge: stream {} -> {} without forwarding information, reset",
+                key.0, key.1
+            );
+            if let Some(stream) = self.get_stream_mut(&key) {
+                stream.abort();
+            }
+            self.remove_key(key, "leaked");
+            self.reset_source(key);
+            repairs += 1;
+        }
+
+        // UDP ports out of the LRU, which are never used again and whose workers are leaked
+        for index in 0..self.datagrams.len() {
+            if self.udp_lru.peek(&(index as u16)).is_some() {
+                continue;
+            }
+            warn!(
+                "scavenge: UDP port {} is out of the LRU, reclaim",
+                self.udp_initial_port + index as u16
+            );
+            if self.datagrams[index].is_some() {
+                self.close_datagram_flow(index, "leaked");
+                self.datagrams[index] = None;
+            }
+            self.udp_lru.put(index as u16, 0);
+            repairs += 1;
+        }
+
+        // UDP port mappings, the LRU is the source of truth
+        let pairs: Vec<(u16, u16)> = self
+            .udp_lru
+            .iter()
+            .map(|(index, src_port)| (*index, *src_port))
+            .collect();
+        for &(index, src_port) in &pairs {
+            let local_port = self.udp_initial_port + index;
+            if src_port != 0 && self.datagram_map[src_port as usize] != local_port {
+                warn!(
+                    "scavenge: UDP port {} = {} is mapped to {}, repair",
+                    src_port, local_port, self.datagram_map[src_port as usize]
+                );
+                self.datagram_map[src_port as usize] = local_port;
+                repairs += 1;
+            }
+        }
+        for src_port in 0..self.datagram_map.len() {
+            let local_port = self.datagram_map[src_port];
+            if local_port == 0 {
+                continue;
+            }
+            let index = local_port.wrapping_sub(self.udp_initial_port);
+            if self.udp_lru.peek(&index) != Some(&(src_port as u16)) {
+                warn!(
+                    "scavenge: UDP port {} = {} is not in use, release",
+                    src_port, local_port
+                );
+                self.datagram_map[src_port] = 0;
+                repairs += 1;
+            }
+        }
+        for &(index, src_port) in &pairs {
+            let is_orphan = match self.datagrams[index as usize] {
+                Some(ref worker) => src_port == 0 || worker.get_src_port() != src_port,
+                None => false,
+            };
+            if is_orphan {
+                warn!(
+                    "scavenge: UDP port {} is bound without a source, close",
+                    self.udp_initial_port + index
+                );
+                self.close_datagram_flow(index as usize, "orphan");
+                self.datagrams[index as usize] = None;
+                repairs += 1;
+            }
+        }
+
+        // Threads, which cannot be stopped from outside
+        let threads = self.threads.dump();
+        let stream_threads = threads
+            .iter()
+            .filter(|info| info.purpose == Purpose::Stream)
+            .count();
+        let datagram_threads = threads
+            .iter()
+            .filter(|info| info.purpose == Purpose::Datagram)
+            .count();
+        let datagrams = self
+            .datagrams
+            .iter()
+            .filter(|datagram| datagram.is_some())
+            .count();
+        let streams = self.get_stream_count();
+        if stream_threads > streams || datagram_threads > datagrams {
+            warn!(
+                "scavenge: {} stream threads for {} streams, {} datagram threads for {} datagrams",
+                stream_threads, streams, datagram_threads, datagrams
+            );
+        }
+
+        if repairs > 0 {
+            self.repairs = self.repairs.saturating_add(repairs);
+            warn!("scavenge: {} repairs ({} in total)", repairs, self.repairs);
+        } else {
+            trace!("scavenge: no repair");
+        }
+    }
+
+    /// Resets the source of the TCP connection and removes its forwarding information.
+    fn reset_source(&mut self, key: (u16, SocketAddrV4)) {
+        let mut tx_locked = self.tx.lock().unwrap();
+        if let Err(ref e) = tx_locked.send_tcp_ack_rst(key.1, key.0) {
+            warn!("handle {}: {}", "TCP", e);
+        }
+        tx_locked.remove(key.1, key.0);
+    }
+
+    /// Get the number of inconsistent entries repaired by the scavenger.
+    pub fn get_repairs(&self) -> usize {
+        self.repairs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::Indicator;
+    use crate::testing;
+    use crate::TcpStats;
+    use pnet::packet::tcp::TcpFlags;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_scavenge_orphans() {
+        let (mut redirector, rx) = testing::create_redirector("127.0.0.1:1080".parse().unwrap());
+        // TCP information of the redirector and of the forwarder without streams
+        let dst = testing::tcp_dst();
+        let flow = redirector.get_tcp_flow((50000, dst));
+        flow.sequence = Some(1000);
+        flow.stats = Some(TcpStats::default());
+        redirector
+            .tx
+            .lock()
+            .unwrap()
+            .set_tcp_acknowledgement(dst, 50001, 2001);
+        // A connecting flow whose stream is gone, with its handshake timer
+        let timers = redirector.timers.len();
+        let handle = redirector
+            .timers
+            .schedule(Duration::from_secs(10), Timer::HalfOpen((50002, dst)));
+        let flow = redirector.get_tcp_flow((50002, dst));
+        flow.is_connecting = true;
+        flow.remote = Some("127.0.0.1:1080".parse().unwrap());
+        flow.half_open = Some(handle);
+        // A flow bridged to the gateway has no stream by design
+        let handle = redirector
+            .timers
+            .schedule(Duration::from_secs(60), Timer::TcpBridge((50003, dst)));
+        redirector.get_tcp_flow((50003, dst)).bridge = Some((Instant::now(), handle));
+
+        redirector.scavenge();
+        assert_eq!(redirector.get_repairs(), 3);
+
+        // Each flow is reset once
+        let mut resets = Vec::new();
+        while let Ok(frame) = rx.try_recv() {
+            let indicator = Indicator::from(&frame).unwrap();
+            let tcp = indicator.get_tcp().unwrap();
+            assert_eq!(tcp.get_flags(), TcpFlags::ACK | TcpFlags::RST);
+            assert_eq!(tcp.get_src(), dst.port());
+            resets.push((tcp.get_dst(), tcp.get_acknowledgement()));
+        }
+        resets.sort_unstable();
+        assert_eq!(resets, vec![(50000, 0), (50001, 2001), (50002, 0)]);
+        let keys: Vec<&(u16, SocketAddrV4)> = redirector.tcp_flows.keys().collect();
+        assert_eq!(keys, vec![&(50003, dst)]);
+        assert_eq!(redirector.timers.len(), timers + 1);
+        assert!(redirector.tx.lock().unwrap().get_tcp_keys().is_empty());
+
+        // Nothing is left to repair
+        redirector.scavenge();
+        assert_eq!(redirector.get_repairs(), 3);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_scavenge_leaked() {
+        let proxy = testing::MockProxy::spawn(Ipv4Addr::LOCALHOST.into()).unwrap();
+        let (mut redirector, rx) = testing::create_redirector(proxy.get_addr());
+        let (dst, _server) = testing::spawn_read_server(6);
+        let key = (50000, dst);
+        testing::establish_to(&mut redirector, &rx, dst, key.0);
+        let dst = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 53);
+        redirector.bind_datagram(30002, 50002, dst).unwrap();
+        while rx.try_recv().is_ok() {}
+
+        // A stream losing its forwarding information, and a port falling out of the LRU
+        redirector.tx.lock().unwrap().remove(key.1, key.0);
+        redirector.udp_lru.pop(&2);
+        redirector.scavenge();
+
+        let segment = testing::receive_segment(&rx);
+        assert_eq!(segment.flags, TcpFlags::ACK | TcpFlags::RST);
+        assert!(redirector.tcp_flows.is_empty());
+        assert!(redirector.tx.lock().unwrap().get_tcp_keys().is_empty());
+        assert!(redirector.datagrams[2].is_none());
+        assert_eq!(redirector.udp_lru.peek(&2), Some(&0));
+        assert_eq!(redirector.get_repairs(), 2);
+
+        // The threads of the workers exit
+        let instant = Instant::now();
+        while redirector
+            .threads
+            .dump()
+            .iter()
+            .any(|info| info.purpose == Purpose::Stream || info.purpose == Purpose::Datagram)
+        {
+            assert!(instant.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
@@ -0,0 +1,386 @@
+use std::cmp::Ordering;
+use std::fmt::{self, Display, Formatter};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Represents the names of days of the week, from Sunday.
+const DAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// Represents the mask of all days of the week.
+const ALL_DAYS: u8 = 0x7f;
+
+/// Represents the number of minutes in a day.
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+/// Represents a time window of a day in local time, started on the days in the mask. A window
+/// ends on the next day if it ends before it starts, and lasts the whole day if both are equal.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Window {
+    start: u16,
+    end: u16,
+    days: u8,
+}
+
+impl Window {
+    /// Creates a `Window` according to the given string in `HH:MM-HH:MM[@DAYS]`, where `DAYS` is
+    /// a list of days or ranges of days separated by commas, e.g. `18:00-01:00@mon-fri,sun`.
+    pub fn parse(s: &str) -> Option<Window> {
+        let mut parts = s.splitn(2, '@');
+        let mut times = parts.next()?.splitn(2, '-');
+        let start = parse_time(times.next()?)?;
+        let end = parse_time(times.next()?)?;
+        let days = match parts.next() {
+            Some(days) => parse_days(days)?,
+            None => ALL_DAYS,
+        };
+
+        Some(Window { start, end, days })
+    }
+
+    fn is_day(&self, weekday: u8) -> bool {
+        self.days & (1 << weekday) != 0
+    }
+
+    /// Returns if the window contains the given day of the week, from Sunday, and minute of the
+    /// day.
+    pub fn contains(&self, weekday: u8, minute: u16) -> bool {
+        let yesterday = (weekday + 6) % 7;
+        match self.start.cmp(&self.end) {
+            Ordering::Less => self.is_day(weekday) && minute >= self.start && minute < self.end,
+            Ordering::Greater => {
+                (self.is_day(weekday) && minute >= self.start)
+                    || (self.is_day(yesterday) && minute < self.end)
+            }
+            Ordering::Equal => self.is_day(weekday),
+        }
+    }
+}
+
+impl Display for Window {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )?;
+        if self.days != ALL_DAYS {
+            let days: Vec<&str> = (0..7)
+                .filter(|weekday| self.is_day(*weekday))
+                .map(|weekday| DAYS[weekday as usize])
+                .collect();
+            write!(f, "@{}", days.join(","))?;
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_time(s: &str) -> Option<u16> {
+    let mut parts = s.splitn(2, ':');
+    let hour: u16 = parts.next()?.parse().ok()?;
+    let minute: u16 = parts.next()?.parse().ok()?;
+    if hour > 24 || minute >= 60 || hour * 60 + minute > MINUTES_PER_DAY {
+        return None;
+    }
+
+    // 24:00 is the same as 00:00
+    Some((hour * 60 + minute) % MINUTES_PER_DAY)
+}
+
+fn parse_day(s: &str) -> Option<u8> {
+    let s = s.to_lowercase();
+    DAYS.iter()
+        .position(|day| s.starts_with(day))
+        .map(|weekday| weekday as u8)
+}
+
+fn parse_days(s: &str) -> Option<u8> {
+    let mut days = 0;
+    for part in s.split(',') {
+        let mut range = part.splitn(2, '-');
+        let first = parse_day(range.next()?)?;
+        let last = match range.next() {
+            Some(last) => parse_day(last)?,
+            None => first,
+        };
+        // Ranges may wrap, e.g. `fri-mon`
+        let mut weekday = first;
+        loop {
+            days |= 1 << weekday;
+            if weekday == last {
+                break;
+            }
+            weekday = (weekday + 1) % 7;
+        }
+    }
+
+    if days == 0 {
+        None
+    } else {
+        Some(days)
+    }
+}
+
+/// Represents the time windows in which new flows are proxied.
+#[derive(Clone, Debug)]
+pub struct Schedule {
+    windows: Vec<Window>,
+}
+
+impl Schedule {
+    /// Creates a new `Schedule`.
+    pub fn new(windows: Vec<Window>) -> Schedule {
+        Schedule { windows }
+    }
+
+    /// Get the first window containing the current local time.
+    pub fn find(&self) -> Option<&Window> {
+        let (weekday, minute) = now();
+
+        self.find_at(weekday, minute)
+    }
+
+    /// Get the first window containing the given day of the week and minute of the day.
+    pub fn find_at(&self, weekday: u8, minute: u16) -> Option<&Window> {
+        self.windows
+            .iter()
+            .find(|window| window.contains(weekday, minute))
+    }
+}
+
+impl Display for Schedule {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            self.windows
+                .iter()
+                .map(|window| window.to_string())
+                .collect::<Vec<String>>()
+                .join(", ")
+        )
+    }
+}
+
+/// Get the current day of the week and minute of the day in local time. The local time is read
+/// again on every call, so changes of the daylight saving time apply immediately.
+pub fn now() -> (u8, u16) {
+    let secs = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs() as i64,
+        Err(_) => 0,
+    };
+
+    at(secs)
+}
+
+/// Get the day of the week and minute of the day in local time at the given time in seconds since
+/// the UNIX epoch.
+fn at(secs: i64) -> (u8, u16) {
+    match local::get_offset(secs) {
+        Some(offset) => from_secs(secs + offset),
+        // Use UTC if the local time is unknown
+        None => from_secs(secs),
+    }
+}
+
+fn from_secs(secs: i64) -> (u8, u16) {
+    let days = secs.div_euclid(86400);
+    // 1970-01-01 is a Thursday
+    let weekday = (days + 4).rem_euclid(7) as u8;
+    let minute = (secs.rem_euclid(86400) / 60) as u16;
+
+    (weekday, minute)
+}
+
+#[cfg(unix)]
+mod local {
+    use std::mem;
+
+    /// Get the offset of the local time to UTC in seconds at the given time.
+    pub fn get_offset(secs: i64) -> Option<i64> {
+        let time = secs as libc::time_t;
+        let mut tm: libc::tm = unsafe { mem::zeroed() };
+        let result = unsafe { libc::localtime_r(&time, &mut tm) };
+        if result.is_null() {
+            return None;
+        }
+
+        Some(tm.tm_gmtoff as i64)
+    }
+}
+
+#[cfg(windows)]
+mod local {
+    use std::mem;
+    use std::ptr;
+    use winapi::shared::minwindef::FILETIME;
+    use winapi::um::minwinbase::SYSTEMTIME;
+    use winapi::um::timezoneapi::{
+        FileTimeToSystemTime, SystemTimeToFileTime, SystemTimeToTzSpecificLocalTime,
+    };
+
+    /// Represents the seconds from 1601-01-01, the epoch of `FILETIME`, to the UNIX epoch.
+    const EPOCH_OFFSET: i64 = 11_644_473_600;
+
+    /// Represents the number of 100 ns intervals of `FILETIME` in a second.
+    const INTERVALS_PER_SEC: i64 = 10_000_000;
+
+    fn to_file_time(secs: i64) -> FILETIME {
+        let intervals = ((secs + EPOCH_OFFSET) * INTERVALS_PER_SEC) as u64;
+
+        FILETIME {
+            dwLowDateTime: intervals as u32,
+            dwHighDateTime: (intervals >> 32) as u32,
+        }
+    }
+
+    fn from_file_time(file_time: &FILETIME) -> i64 {
+        let intervals = (file_time.dwHighDateTime as u64) << 32 | file_time.dwLowDateTime as u64;
+
+        intervals as i64 / INTERVALS_PER_SEC - EPOCH_OFFSET
+    }
+
+    /// Get the offset of the local time to UTC in seconds at the given time, in the time zone and
+    /// the daylight saving time rules of the system.
+    pub fn get_offset(secs: i64) -> Option<i64> {
+        let utc = to_file_time(secs);
+        let mut utc_system: SYSTEMTIME = unsafe { mem::zeroed() };
+        let mut local_system: SYSTEMTIME = unsafe { mem::zeroed() };
+        let mut local: FILETIME = unsafe { mem::zeroed() };
+        let is_converted = unsafe {
+            FileTimeToSystemTime(&utc, &mut utc_system) != 0
+                && SystemTimeToTzSpecificLocalTime(ptr::null(), &utc_system, &mut local_system) != 0
+                && SystemTimeToFileTime(&local_system, &mut local) != 0
+        };
+        if !is_converted {
+            return None;
+        }
+
+        Some(from_file_time(&local) - secs)
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod local {
+    /// Get the offset of the local time to UTC in seconds at the given time.
+    pub fn get_offset(_: i64) -> Option<i64> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SUN: u8 = 0;
+    const FRI: u8 = 5;
+    const SAT: u8 = 6;
+
+    fn minute(hour: u16, minute: u16) -> u16 {
+        hour * 60 + minute
+    }
+
+    #[test]
+    fn test_parse() {
+        let window = Window::parse("18:00-01:00@fri-sun").unwrap();
+        assert_eq!(window.to_string(), "18:00-01:00@sun,fri,sat");
+        assert_eq!(
+            Window::parse("00:00-24:00").unwrap().to_string(),
+            "00:00-00:00"
+        );
+        assert_eq!(Window::parse("18:00"), None);
+        assert_eq!(Window::parse("25:00-01:00"), None);
+        assert_eq!(Window::parse("18:00-01:60"), None);
+        assert_eq!(Window::parse("18:00-01:00@"), None);
+        assert_eq!(Window::parse("18:00-01:00@someday"), None);
+    }
+
+    #[test]
+    fn test_across_midnight() {
+        let window = Window::parse("18:00-01:00@fri").unwrap();
+        assert!(!window.contains(FRI, minute(17, 59)));
+        assert!(window.contains(FRI, minute(18, 0)));
+        assert!(window.contains(FRI, minute(23, 59)));
+        // The window started on Friday goes on after midnight on Saturday
+        assert!(window.contains(SAT, minute(0, 0)));
+        assert!(window.contains(SAT, minute(0, 59)));
+        assert!(!window.contains(SAT, minute(1, 0)));
+        assert!(!window.contains(SAT, minute(18, 0)));
+        // Nor is it open after midnight of Thursday, which is not in the days
+        assert!(!window.contains(FRI, minute(0, 30)));
+
+        // Ranges of days wrap over the end of the week
+        let window = Window::parse("23:00-02:00@sat").unwrap();
+        assert!(window.contains(SUN, minute(1, 0)));
+        let window = Window::parse("23:00-02:00@sun").unwrap();
+        assert!(!window.contains(SUN, minute(1, 0)));
+        assert!(window.contains(1, minute(1, 0)));
+
+        // Whole days
+        let window = Window::parse("00:00-24:00@sun").unwrap();
+        assert!(window.contains(SUN, 0));
+        assert!(window.contains(SUN, MINUTES_PER_DAY - 1));
+        assert!(!window.contains(SAT, MINUTES_PER_DAY - 1));
+    }
+
+    #[test]
+    fn test_find_at() {
+        let schedule = Schedule::new(vec![
+            Window::parse("06:00-08:00").unwrap(),
+            Window::parse("18:00-01:00").unwrap(),
+        ]);
+        assert_eq!(
+            schedule.find_at(FRI, minute(0, 30)).unwrap().to_string(),
+            "18:00-01:00"
+        );
+        assert_eq!(
+            schedule.find_at(FRI, minute(7, 0)).unwrap().to_string(),
+            "06:00-08:00"
+        );
+        assert_eq!(schedule.find_at(FRI, minute(4, 0)), None);
+    }
+
+    #[test]
+    fn test_from_secs() {
+        assert_eq!(from_secs(0), (4, 0));
+        assert_eq!(from_secs(86400 - 60), (4, MINUTES_PER_DAY - 1));
+        assert_eq!(from_secs(86400), (FRI, 0));
+        // Before the UNIX epoch, as with a large negative offset
+        assert_eq!(from_secs(-60), (3, MINUTES_PER_DAY - 1));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_daylight_saving_time() {
+        // The time zone of New York, with the daylight saving time from the second Sunday of
+        // March to the first Sunday of November, which needs no time zone database
+        std::env::set_var("TZ", "EST5EDT,M3.2.0,M11.1.0");
+        // Unlike `localtime_r`, `localtime` reads `TZ` again
+        let _ = unsafe { libc::localtime(&0) };
+
+        // 2020-03-08 06:59:59 UTC is 01:59:59 EST, and a second later is 03:00:00 EDT
+        let spring = 1_583_650_799;
+        assert_eq!(local::get_offset(spring), Some(-5 * 3600));
+        assert_eq!(local::get_offset(spring + 1), Some(-4 * 3600));
+        assert_eq!(at(spring), (SUN, minute(1, 59)));
+        assert_eq!(at(spring + 1), (SUN, minute(3, 0)));
+        // The skipped hour is never in a window, and windows around it are still found
+        let skipped = Window::parse("02:00-03:00@sun").unwrap();
+        for secs in (spring - 3600..spring + 3600).step_by(60) {
+            let (weekday, minute) = at(secs);
+            assert!(!skipped.contains(weekday, minute));
+        }
+        let window = Window::parse("01:30-03:30@sun").unwrap();
+        let (weekday, minute) = at(spring + 1);
+        assert!(window.contains(weekday, minute));
+
+        // 2020-11-01 05:30:00 UTC is 01:30 EDT, and an hour later is 01:30 EST again
+        let autumn = 1_604_208_600;
+        assert_eq!(at(autumn), at(autumn + 3600));
+        let window = Window::parse("01:00-02:00@sun").unwrap();
+        let (weekday, minute) = at(autumn + 3600);
+        assert!(window.contains(weekday, minute));
+    }
+}
@@ -0,0 +1,77 @@
+use std::fmt::Write;
+use std::fs;
+
+/// Represents a snapshot of the resources held by the process and the entries of its tables, for
+/// finding leaks in long runs.
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    /// Represents the number of open file descriptors, or `None` if unknown.
+    pub fds: Option<usize>,
+    /// Represents the resident set size in KiB, or `None` if unknown.
+    pub rss: Option<usize>,
+    /// Represents the number of threads of the process, or `None` if unknown.
+    pub threads: Option<usize>,
+    /// Represents the name and the number of entries of each table.
+    pub entries: Vec<(&'static str, usize)>,
+}
+
+impl Snapshot {
+    /// Creates a new `Snapshot` of the process with the given table entries. Only a few files in
+    /// `/proc` are read, so it is cheap enough to be taken every minute.
+    pub fn new(entries: Vec<(&'static str, usize)>) -> Snapshot {
+        let fds = fs::read_dir("/proc/self/fd").ok().map(|dir| dir.count());
+        let status = fs::read_to_string("/proc/self/status").unwrap_or_default();
+
+        Snapshot {
+            fds,
+            rss: get_status(&status, "VmRSS:"),
+            threads: get_status(&status, "Threads:"),
+            entries,
+        }
+    }
+
+    /// Get the snapshot in text, with the changes since the previous snapshot.
+    pub fn diff(&self, prev: Option<&Snapshot>) -> String {
+        let mut s = String::new();
+        let mut push = |name: &str, value: Option<usize>, prev_value: Option<usize>| {
+            if !s.is_empty() {
+                s.push_str(", ");
+            }
+            match value {
+                Some(value) => {
+                    let _ = write!(s, "{} {}", name, value);
+                    if let Some(prev_value) = prev_value {
+                        let _ = write!(s, " ({:+})", value as i64 - prev_value as i64);
+                    }
+                }
+                None => {
+                    let _ = write!(s, "{} ?", name);
+                }
+            }
+        };
+
+        push("fds", self.fds, prev.and_then(|prev| prev.fds));
+        push("rss KiB", self.rss, prev.and_then(|prev| prev.rss));
+        push("threads", self.threads, prev.and_then(|prev| prev.threads));
+        for (name, value) in &self.entries {
+            let prev_value = prev.and_then(|prev| {
+                prev.entries
+                    .iter()
+                    .find(|(prev_name, _)| prev_name == name)
+                    .map(|(_, prev_value)| *prev_value)
+            });
+            push(name, Some(*value), prev_value);
+        }
+
+        s
+    }
+}
+
+/// Get the value of a field in `/proc/self/status`.
+fn get_status(status: &str, field: &str) -> Option<usize> {
+    status
+        .lines()
+        .find(|line| line.starts_with(field))
+        .and_then(|line| line[field.len()..].split_whitespace().next())
+        .and_then(|value| value.parse().ok())
+}
@@ -0,0 +1,412 @@
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::str;
+use std::time::Duration;
+
+use super::socks::{self, Auth};
+
+/// Represents the max size of the head of a response to a CONNECT request.
+const MAX_HEAD_SIZE: usize = 8192;
+
+/// Represents the timeout of reading the head of a response to a CONNECT request.
+const HEAD_TIMEOUT: u64 = 30;
+
+/// Represents the protocol of proxies.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ProxyType {
+    /// SOCKS5, which tunnels TCP and relays UDP.
+    Socks5,
+    /// HTTP CONNECT, which tunnels TCP only.
+    Http,
+}
+
+impl Display for ProxyType {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            ProxyType::Socks5 => write!(f, "SOCKS5"),
+            ProxyType::Http => write!(f, "HTTP"),
+        }
+    }
+}
+
+/// Represents the error of a proxy not supporting UDP.
+#[derive(Debug)]
+pub struct Unsupported {
+    proxy_type: ProxyType,
+    remote: SocketAddr,
+}
+
+impl Display for Unsupported {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} proxy {} cannot relay UDP",
+            self.proxy_type, self.remote
+        )
+    }
+}
+
+impl Error for Unsupported {}
+
+/// Returns if the error is a proxy not supporting UDP. The error is `Other` like those replied by
+/// proxies, so it never marks the proxy down.
+pub fn is_unsupported(e: &io::Error) -> bool {
+    match e.get_ref() {
+        Some(inner) => inner.is::<Unsupported>(),
+        None => false,
+    }
+}
+
+/// Represents a connector of streams tunneled to destinations through a proxy.
+#[derive(Clone, Debug)]
+pub enum ProxyConnector {
+    Socks5 {
+        remote: SocketAddr,
+        auth: Option<Auth>,
+    },
+    Http {
+        remote: SocketAddr,
+        auth: Option<Auth>,
+    },
+}
+
+impl ProxyConnector {
+    /// Creates a new `ProxyConnector` of the given protocol.
+    pub fn new(proxy_type: ProxyType, remote: SocketAddr, auth: Option<&Auth>) -> ProxyConnector {
+        let auth = auth.cloned();
+        match proxy_type {
+            ProxyType::Socks5 => ProxyConnector::Socks5 { remote, auth },
+            ProxyType::Http => ProxyConnector::Http { remote, auth },
+        }
+    }
+
+    /// Get the protocol of the proxy.
+    pub fn get_type(&self) -> ProxyType {
+        match self {
+            ProxyConnector::Socks5 { .. } => ProxyType::Socks5,
+            ProxyConnector::Http { .. } => ProxyType::Http,
+        }
+    }
+
+    /// Get the address of the proxy.
+    pub fn get_remote(&self) -> SocketAddr {
+        match *self {
+            ProxyConnector::Socks5 { remote, .. } => remote,
+            ProxyConnector::Http { remote, .. } => remote,
+        }
+    }
+
+    /// Get the authentication of the proxy.
+    pub fn get_auth(&self) -> Option<&Auth> {
+        match self {
+            ProxyConnector::Socks5 { auth, .. } => auth.as_ref(),
+            ProxyConnector::Http { auth, .. } => auth.as_ref(),
+        }
+    }
+
+    /// Connects to a target server of IPv4 or IPv6 through the proxy.
+    pub fn connect(&self, dst: SocketAddr) -> io::Result<TcpStream> {
+        let stream = TcpStream::connect(self.get_remote())?;
+
+        self.connect_over(stream, dst)
+    }
+
+    /// Connects to a target server of IPv4 or IPv6 through the proxy over a stream already
+    /// connected to the proxy, like one of a pool.
+    pub fn connect_over(&self, stream: TcpStream, dst: SocketAddr) -> io::Result<TcpStream> {
+        match self {
+            ProxyConnector::Socks5 { remote, auth } => {
+                socks::connect_over(stream, *remote, dst, auth.as_ref())
+            }
+            ProxyConnector::Http { remote, auth } => {
+                connect_http_over(stream, *remote, dst, auth.as_ref())
+            }
+        }
+    }
+
+    /// Returns an `Unsupported` error for the UDP of the proxy if it cannot relay UDP.
+    pub fn check_udp(&self) -> io::Result<()> {
+        match self.get_type() {
+            ProxyType::Socks5 => Ok(()),
+            proxy_type => Err(io::Error::other(Unsupported {
+                proxy_type,
+                remote: self.get_remote(),
+            })),
+        }
+    }
+}
+
+/// Connects to a target server through an HTTP proxy with a CONNECT request over a stream already
+/// connected to the proxy. The stream is returned right after the head of the response, so no
+/// tunneled byte is consumed.
+fn connect_http_over(
+    mut stream: TcpStream,
+    remote: SocketAddr,
+    dst: SocketAddr,
+    auth: Option<&Auth>,
+) -> io::Result<TcpStream> {
+    let mut request = format!(
+        "CONNECT {} HTTP/1.1\r\nHost: {}\r\nProxy-Connection: keep-alive\r\n",
+        dst, dst
+    )
+    .into_bytes();
+    if let Some(auth) = auth {
+        request.extend_from_slice(b"Proxy-Authorization: Basic ");
+        request.extend_from_slice(auth.get_basic().as_str().as_bytes());
+        request.extend_from_slice(b"\r\n");
+    }
+    request.extend_from_slice(b"\r\n");
+    stream.write_all(&request)?;
+    // The request holds the credentials
+    socks::zero(&mut request);
+
+    stream.set_read_timeout(Some(Duration::from_secs(HEAD_TIMEOUT)))?;
+    let head = read_head(&mut stream)?;
+    stream.set_read_timeout(None)?;
+
+    let (status, reason) = parse_status(&head)?;
+    match status {
+        200..=299 => Ok(stream),
+        407 => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            match auth {
+                Some(auth) => format!(
+                    "proxy {} rejects the username {}",
+                    remote,
+                    auth.get_username()
+                ),
+                None => format!("proxy {} requires authentication", remote),
+            },
+        )),
+        // Refusals of the proxy are not faults of the proxy, like those of SOCKS5
+        _ => Err(io::Error::other(format!(
+            "proxy {} answers CONNECT to {} with {} {}",
+            remote, dst, status, reason
+        ))),
+    }
+}
+
+/// Reads the head of a response up to and including the blank line. The head is read byte by
+/// byte, so the tunneled bytes sent right after it are left in the stream.
+fn read_head<R: Read>(stream: &mut R) -> io::Result<Vec<u8>> {
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    loop {
+        match stream.read(&mut byte) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "proxy closes before the end of the response",
+                ))
+            }
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+        head.push(byte[0]);
+        // Lines end with CRLF, a bare LF is accepted as well
+        if head.ends_with(b"\n\r\n") || head.ends_with(b"\n\n") {
+            return Ok(head);
+        }
+        if head.len() >= MAX_HEAD_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "response of the proxy is too large",
+            ));
+        }
+    }
+}
+
+/// Parses the status code and the reason in the status line of the head of a response.
+fn parse_status(head: &[u8]) -> io::Result<(u16, String)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid response of the proxy");
+
+    let line = head.split(|b| *b == b'\n').next().ok_or_else(invalid)?;
+    let line = str::from_utf8(line).map_err(|_| invalid())?.trim_end();
+    let mut parts = line.splitn(3, ' ');
+    let version = parts.next().ok_or_else(invalid)?;
+    if !version.starts_with("HTTP/1.") {
+        return Err(invalid());
+    }
+    let status = parts.next().ok_or_else(invalid)?;
+    if status.len() != 3 {
+        return Err(invalid());
+    }
+    let status = status.parse().map_err(|_| invalid())?;
+    let reason = parts.next().unwrap_or("").to_string();
+
+    Ok((status, reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::super::socks::Secret;
+
+    /// Represents a stream returning the chunks given in order, one per read at most.
+    struct Chunks(Vec<Vec<u8>>);
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return Ok(0);
+            }
+            let size = buf.len().min(self.0[0].len());
+            buf[..size].copy_from_slice(&self.0[0][..size]);
+            self.0[0].drain(..size);
+            if self.0[0].is_empty() {
+                self.0.remove(0);
+            }
+
+            Ok(size)
+        }
+    }
+
+    /// Spawns an HTTP proxy answering the CONNECT request with the response, and returns the
+    /// request it reads.
+    fn spawn_proxy(response: &'static [u8]) -> (SocketAddr, thread::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0u8; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let size = stream.read(&mut buffer).unwrap();
+                assert_ne!(size, 0);
+                request.extend_from_slice(&buffer[..size]);
+            }
+            // The response and the tunneled bytes in a single write
+            stream.write_all(response).unwrap();
+            stream.flush().unwrap();
+            thread::sleep(Duration::from_millis(100));
+
+            request
+        });
+
+        (addr, handle)
+    }
+
+    fn dst() -> SocketAddr {
+        "192.0.2.1:443".parse().unwrap()
+    }
+
+    fn connect_http(
+        remote: SocketAddr,
+        dst: SocketAddr,
+        auth: Option<&Auth>,
+    ) -> io::Result<TcpStream> {
+        ProxyConnector::new(ProxyType::Http, remote, auth).connect(dst)
+    }
+
+    #[test]
+    fn test_read_head_split() {
+        let mut stream = Chunks(vec![
+            b"HTTP/1.1 20".to_vec(),
+            b"0 Connection established\r".to_vec(),
+            b"\n\r".to_vec(),
+            b"\n".to_vec(),
+        ]);
+        let head = read_head(&mut stream).unwrap();
+        assert_eq!(
+            head,
+            b"HTTP/1.1 200 Connection established\r\n\r\n".to_vec()
+        );
+        assert_eq!(
+            parse_status(&head).unwrap(),
+            (200, String::from("Connection established"))
+        );
+    }
+
+    #[test]
+    fn test_read_head_headers() {
+        let response = b"HTTP/1.0 200 OK\r\nProxy-Agent: squid\r\nVia: 1.1 proxy\r\n\r\n";
+        let mut stream = Chunks(vec![response.to_vec()]);
+        let head = read_head(&mut stream).unwrap();
+        assert_eq!(head, response.to_vec());
+        assert_eq!(parse_status(&head).unwrap().0, 200);
+
+        // A bare LF ends lines as well
+        let mut stream = Chunks(vec![b"HTTP/1.1 200 OK\nVia: proxy\n\n".to_vec()]);
+        assert_eq!(
+            parse_status(&read_head(&mut stream).unwrap()).unwrap().0,
+            200
+        );
+    }
+
+    #[test]
+    fn test_read_head_tunneled() {
+        // Tunneled bytes in the same read as the head are left in the stream
+        let mut stream = Chunks(vec![b"HTTP/1.1 200 OK\r\nVia: proxy\r\n\r\nhello".to_vec()]);
+        read_head(&mut stream).unwrap();
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"hello".to_vec());
+    }
+
+    #[test]
+    fn test_read_head_invalid() {
+        let mut stream = Chunks(vec![b"HTTP/1.1 200 OK\r\n".to_vec()]);
+        let e = read_head(&mut stream).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+
+        let mut stream = Chunks(vec![vec![b'a'; MAX_HEAD_SIZE + 1]]);
+        let e = read_head(&mut stream).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        for head in &[
+            &b"SSH-2.0\r\n\r\n"[..],
+            b"HTTP/1.1 2000 OK\r\n\r\n",
+            b"\r\n\r\n",
+        ] {
+            let e = parse_status(head).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn test_connect_http() {
+        let (remote, handle) =
+            spawn_proxy(b"HTTP/1.1 200 Connection established\r\nProxy-Agent: mock\r\n\r\nhello");
+        let auth = Auth::new(String::from("user"), Secret::new(String::from("pass")));
+        let mut stream = connect_http(remote, dst(), Some(&auth)).unwrap();
+
+        // The tunneled bytes sent with the response are left to the tunnel
+        let mut buffer = [0u8; 5];
+        stream.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"hello");
+        let request = String::from_utf8(handle.join().unwrap()).unwrap();
+        assert!(request.starts_with("CONNECT 192.0.2.1:443 HTTP/1.1\r\n"));
+        assert!(request.contains("\r\nProxy-Authorization: Basic dXNlcjpwYXNz\r\n"));
+    }
+
+    #[test]
+    fn test_connect_http_refused() {
+        // Statuses other than 2xx are errors naming the status
+        let (remote, handle) = spawn_proxy(b"HTTP/1.1 403 Forbidden\r\nVia: mock\r\n\r\n");
+        let e = connect_http(remote, dst(), None).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::Other);
+        assert!(e.to_string().contains("403 Forbidden"), "{}", e);
+        handle.join().unwrap();
+
+        let (remote, handle) = spawn_proxy(b"HTTP/1.1 502 Bad Gateway\r\n\r\n");
+        let e = connect_http(remote, dst(), None).unwrap_err();
+        assert!(e.to_string().contains("502 Bad Gateway"), "{}", e);
+        handle.join().unwrap();
+
+        let (remote, handle) = spawn_proxy(b"HTTP/1.1 301 Moved Permanently\r\n\r\n");
+        let e = connect_http(remote, dst(), None).unwrap_err();
+        assert!(e.to_string().contains("301"), "{}", e);
+        handle.join().unwrap();
+
+        let (remote, handle) = spawn_proxy(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n");
+        let e = connect_http(remote, dst(), None).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        handle.join().unwrap();
+    }
+}
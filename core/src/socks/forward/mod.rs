@@ -0,0 +1,602 @@
+use log::warn;
+use std::io;
+use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+
+use crate::hooks::Protocol;
+
+/// Trait for forwarding transport layer payload.
+pub trait Forward: Send {
+    /// Forward TCP payload.
+    fn forward_tcp(&mut self, dst: SocketAddr, src_port: u16, payload: &[u8]) -> io::Result<()>;
+
+    /// Forward UDP payload.
+    fn forward_udp(&mut self, dst: SocketAddr, src_port: u16, payload: &[u8]) -> io::Result<()>;
+
+    /// Get the TCP payload forwarded but not acknowledged by the source yet, and the window
+    /// advertised by the source in bytes, scaled by its window scale. By default, there is no
+    /// backlog, so reads from the proxy never pause.
+    fn get_tcp_backlog(&mut self, _dst: SocketAddr, _src_port: u16) -> (usize, u32) {
+        (0, 0)
+    }
+
+    /// Forward the result of connecting a TCP stream.
+    fn forward_tcp_connect(
+        &mut self,
+        dst: SocketAddr,
+        src_port: u16,
+        is_connected: bool,
+    ) -> io::Result<()>;
+
+    /// Forward the end of a TCP stream from the proxy, once, which is reset for an error or closed
+    /// in order for an EOF otherwise. By default, the end is ignored.
+    fn forward_tcp_close(
+        &mut self,
+        _dst: SocketAddr,
+        _src_port: u16,
+        _is_reset: bool,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A shared `Forward` forwards through its lock, so a `Forward` also used elsewhere, like the
+/// `Forwarder` of a `Redirector`, can be wrapped by the combinators.
+impl<F: Forward + ?Sized> Forward for Arc<Mutex<F>> {
+    fn forward_tcp(&mut self, dst: SocketAddr, src_port: u16, payload: &[u8]) -> io::Result<()> {
+        self.lock().unwrap().forward_tcp(dst, src_port, payload)
+    }
+
+    fn forward_udp(&mut self, dst: SocketAddr, src_port: u16, payload: &[u8]) -> io::Result<()> {
+        self.lock().unwrap().forward_udp(dst, src_port, payload)
+    }
+
+    fn get_tcp_backlog(&mut self, dst: SocketAddr, src_port: u16) -> (usize, u32) {
+        self.lock().unwrap().get_tcp_backlog(dst, src_port)
+    }
+
+    fn forward_tcp_connect(
+        &mut self,
+        dst: SocketAddr,
+        src_port: u16,
+        is_connected: bool,
+    ) -> io::Result<()> {
+        self.lock()
+            .unwrap()
+            .forward_tcp_connect(dst, src_port, is_connected)
+    }
+
+    fn forward_tcp_close(
+        &mut self,
+        dst: SocketAddr,
+        src_port: u16,
+        is_reset: bool,
+    ) -> io::Result<()> {
+        self.lock()
+            .unwrap()
+            .forward_tcp_close(dst, src_port, is_reset)
+    }
+}
+
+/// Represents a `Forward` which forwards to the primary and duplicates everything to the observer.
+/// The primary decides the result and the backlog. Errors of the observer are counted and never
+/// fail the primary, and an observer which panics is disabled.
+pub struct TeeForward<P: Forward, O: Forward> {
+    primary: P,
+    observer: Option<O>,
+    failures: usize,
+}
+
+impl<P: Forward, O: Forward> TeeForward<P, O> {
+    /// Creates a new `TeeForward`.
+    pub fn new(primary: P, observer: O) -> TeeForward<P, O> {
+        TeeForward {
+            primary,
+            observer: Some(observer),
+            failures: 0,
+        }
+    }
+
+    /// Get the number of errors and panics of the observer.
+    pub fn get_failures(&self) -> usize {
+        self.failures
+    }
+
+    /// Returns if the observer is disabled for panicking.
+    pub fn is_observer_disabled(&self) -> bool {
+        self.observer.is_none()
+    }
+
+    fn observe<F>(&mut self, f: F)
+    where
+        F: FnOnce(&mut O) -> io::Result<()>,
+    {
+        let observer = match self.observer {
+            Some(ref mut observer) => observer,
+            None => return,
+        };
+
+        match panic::catch_unwind(AssertUnwindSafe(|| f(observer))) {
+            Ok(Ok(_)) => {}
+            Ok(Err(ref e)) => {
+                self.failures = self.failures.saturating_add(1);
+                warn!("observe: {}", e);
+            }
+            Err(_) => {
+                // The observer may be left in any state
+                self.failures = self.failures.saturating_add(1);
+                self.observer = None;
+                warn!("observe: observer panicked and is disabled");
+            }
+        }
+    }
+}
+
+impl<P: Forward, O: Forward> Forward for TeeForward<P, O> {
+    fn forward_tcp(&mut self, dst: SocketAddr, src_port: u16, payload: &[u8]) -> io::Result<()> {
+        let result = self.primary.forward_tcp(dst, src_port, payload);
+        self.observe(|observer| observer.forward_tcp(dst, src_port, payload));
+
+        result
+    }
+
+    fn forward_udp(&mut self, dst: SocketAddr, src_port: u16, payload: &[u8]) -> io::Result<()> {
+        let result = self.primary.forward_udp(dst, src_port, payload);
+        self.observe(|observer| observer.forward_udp(dst, src_port, payload));
+
+        result
+    }
+
+    fn get_tcp_backlog(&mut self, dst: SocketAddr, src_port: u16) -> (usize, u32) {
+        self.primary.get_tcp_backlog(dst, src_port)
+    }
+
+    fn forward_tcp_connect(
+        &mut self,
+        dst: SocketAddr,
+        src_port: u16,
+        is_connected: bool,
+    ) -> io::Result<()> {
+        let result = self
+            .primary
+            .forward_tcp_connect(dst, src_port, is_connected);
+        self.observe(|observer| observer.forward_tcp_connect(dst, src_port, is_connected));
+
+        result
+    }
+
+    fn forward_tcp_close(
+        &mut self,
+        dst: SocketAddr,
+        src_port: u16,
+        is_reset: bool,
+    ) -> io::Result<()> {
+        let result = self.primary.forward_tcp_close(dst, src_port, is_reset);
+        self.observe(|observer| observer.forward_tcp_close(dst, src_port, is_reset));
+
+        result
+    }
+}
+
+/// Represents the verdict of a filter on a payload.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Verdict {
+    /// Forwards the payload as is.
+    Pass,
+    /// Drops the payload, which is reported as forwarded.
+    Drop,
+    /// Forwards the given payload instead.
+    Replace(Vec<u8>),
+}
+
+/// Represents a `Forward` which forwards payload by the verdict of the predicate. Results of
+/// connecting TCP streams and their ends are always forwarded.
+pub struct FilterForward<F: Forward, P>
+where
+    P: FnMut(Protocol, SocketAddr, u16, &[u8]) -> Verdict + Send,
+{
+    inner: F,
+    predicate: P,
+    dropped: usize,
+}
+
+impl<F: Forward, P> FilterForward<F, P>
+where
+    P: FnMut(Protocol, SocketAddr, u16, &[u8]) -> Verdict + Send,
+{
+    /// Creates a new `FilterForward`.
+    pub fn new(inner: F, predicate: P) -> FilterForward<F, P> {
+        FilterForward {
+            inner,
+            predicate,
+            dropped: 0,
+        }
+    }
+
+    /// Get the number of payloads dropped.
+    pub fn get_dropped(&self) -> usize {
+        self.dropped
+    }
+}
+
+impl<F: Forward, P> Forward for FilterForward<F, P>
+where
+    P: FnMut(Protocol, SocketAddr, u16, &[u8]) -> Verdict + Send,
+{
+    fn forward_tcp(&mut self, dst: SocketAddr, src_port: u16, payload: &[u8]) -> io::Result<()> {
+        match (self.predicate)(Protocol::Tcp, dst, src_port, payload) {
+            Verdict::Pass => self.inner.forward_tcp(dst, src_port, payload),
+            Verdict::Drop => {
+                self.dropped = self.dropped.saturating_add(1);
+                Ok(())
+            }
+            Verdict::Replace(ref payload) => self.inner.forward_tcp(dst, src_port, payload),
+        }
+    }
+
+    fn forward_udp(&mut self, dst: SocketAddr, src_port: u16, payload: &[u8]) -> io::Result<()> {
+        match (self.predicate)(Protocol::Udp, dst, src_port, payload) {
+            Verdict::Pass => self.inner.forward_udp(dst, src_port, payload),
+            Verdict::Drop => {
+                self.dropped = self.dropped.saturating_add(1);
+                Ok(())
+            }
+            Verdict::Replace(ref payload) => self.inner.forward_udp(dst, src_port, payload),
+        }
+    }
+
+    fn get_tcp_backlog(&mut self, dst: SocketAddr, src_port: u16) -> (usize, u32) {
+        self.inner.get_tcp_backlog(dst, src_port)
+    }
+
+    fn forward_tcp_connect(
+        &mut self,
+        dst: SocketAddr,
+        src_port: u16,
+        is_connected: bool,
+    ) -> io::Result<()> {
+        self.inner.forward_tcp_connect(dst, src_port, is_connected)
+    }
+
+    fn forward_tcp_close(
+        &mut self,
+        dst: SocketAddr,
+        src_port: u16,
+        is_reset: bool,
+    ) -> io::Result<()> {
+        self.inner.forward_tcp_close(dst, src_port, is_reset)
+    }
+}
+
+/// Represents a `Forward` which forwards to the first and then to the second, both of which must
+/// succeed. The second is not forwarded to if the first fails. The backlog is the larger one with
+/// the smaller window, so the stream pauses for the slower of both.
+pub struct ChainForward<A: Forward, B: Forward> {
+    first: A,
+    second: B,
+}
+
+impl<A: Forward, B: Forward> ChainForward<A, B> {
+    /// Creates a new `ChainForward`.
+    pub fn new(first: A, second: B) -> ChainForward<A, B> {
+        ChainForward { first, second }
+    }
+}
+
+impl<A: Forward, B: Forward> Forward for ChainForward<A, B> {
+    fn forward_tcp(&mut self, dst: SocketAddr, src_port: u16, payload: &[u8]) -> io::Result<()> {
+        self.first.forward_tcp(dst, src_port, payload)?;
+        self.second.forward_tcp(dst, src_port, payload)
+    }
+
+    fn forward_udp(&mut self, dst: SocketAddr, src_port: u16, payload: &[u8]) -> io::Result<()> {
+        self.first.forward_udp(dst, src_port, payload)?;
+        self.second.forward_udp(dst, src_port, payload)
+    }
+
+    fn get_tcp_backlog(&mut self, dst: SocketAddr, src_port: u16) -> (usize, u32) {
+        let (first_backlog, first_window) = self.first.get_tcp_backlog(dst, src_port);
+        let (second_backlog, second_window) = self.second.get_tcp_backlog(dst, src_port);
+
+        (
+            first_backlog.max(second_backlog),
+            first_window.min(second_window),
+        )
+    }
+
+    fn forward_tcp_connect(
+        &mut self,
+        dst: SocketAddr,
+        src_port: u16,
+        is_connected: bool,
+    ) -> io::Result<()> {
+        self.first
+            .forward_tcp_connect(dst, src_port, is_connected)?;
+        self.second.forward_tcp_connect(dst, src_port, is_connected)
+    }
+
+    fn forward_tcp_close(
+        &mut self,
+        dst: SocketAddr,
+        src_port: u16,
+        is_reset: bool,
+    ) -> io::Result<()> {
+        self.first.forward_tcp_close(dst, src_port, is_reset)?;
+        self.second.forward_tcp_close(dst, src_port, is_reset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    /// Represents an event forwarded.
+    #[derive(Clone, Debug, Eq, PartialEq)]
+    enum Event {
+        Tcp(Vec<u8>),
+        Udp(Vec<u8>),
+        Connect(bool),
+        Close(bool),
+    }
+
+    /// Represents a `Forward` which records the events and the addresses of the payloads, and
+    /// fails or panics on demand.
+    #[derive(Default)]
+    struct Recorder {
+        events: Vec<Event>,
+        addrs: Vec<usize>,
+        backlog: (usize, u32),
+        is_failing: bool,
+        is_panicking: bool,
+    }
+
+    impl Recorder {
+        fn record(&mut self, event: Event) -> io::Result<()> {
+            if self.is_panicking {
+                panic!("recorder panicked");
+            }
+            if self.is_failing {
+                return Err(io::Error::other("recorder failed"));
+            }
+            self.events.push(event);
+
+            Ok(())
+        }
+    }
+
+    impl Forward for Recorder {
+        fn forward_tcp(&mut self, _: SocketAddr, _: u16, payload: &[u8]) -> io::Result<()> {
+            self.addrs.push(payload.as_ptr() as usize);
+            self.record(Event::Tcp(payload.to_vec()))
+        }
+
+        fn forward_udp(&mut self, _: SocketAddr, _: u16, payload: &[u8]) -> io::Result<()> {
+            self.addrs.push(payload.as_ptr() as usize);
+            self.record(Event::Udp(payload.to_vec()))
+        }
+
+        fn get_tcp_backlog(&mut self, _: SocketAddr, _: u16) -> (usize, u32) {
+            self.backlog
+        }
+
+        fn forward_tcp_connect(
+            &mut self,
+            _: SocketAddr,
+            _: u16,
+            is_connected: bool,
+        ) -> io::Result<()> {
+            self.record(Event::Connect(is_connected))
+        }
+
+        fn forward_tcp_close(&mut self, _: SocketAddr, _: u16, is_reset: bool) -> io::Result<()> {
+            self.record(Event::Close(is_reset))
+        }
+    }
+
+    fn get_dst() -> SocketAddr {
+        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 80))
+    }
+
+    /// Forwards one of each event.
+    fn forward_all<F: Forward>(forward: &mut F) -> Vec<io::Result<()>> {
+        vec![
+            forward.forward_tcp_connect(get_dst(), 50000, true),
+            forward.forward_tcp(get_dst(), 50000, b"tcp"),
+            forward.forward_udp(get_dst(), 50000, b"udp"),
+            forward.forward_tcp_close(get_dst(), 50000, false),
+        ]
+    }
+
+    fn get_all() -> Vec<Event> {
+        vec![
+            Event::Connect(true),
+            Event::Tcp(b"tcp".to_vec()),
+            Event::Udp(b"udp".to_vec()),
+            Event::Close(false),
+        ]
+    }
+
+    fn new_shared(recorder: Recorder) -> Arc<Mutex<Recorder>> {
+        Arc::new(Mutex::new(recorder))
+    }
+
+    #[test]
+    fn test_tee() {
+        let primary = new_shared(Recorder {
+            backlog: (100, 200),
+            ..Default::default()
+        });
+        let observer = new_shared(Recorder::default());
+        let mut tee = TeeForward::new(primary.clone(), observer.clone());
+        assert!(forward_all(&mut tee).iter().all(|result| result.is_ok()));
+        assert_eq!(tee.get_tcp_backlog(get_dst(), 50000), (100, 200));
+        assert_eq!(primary.lock().unwrap().events, get_all());
+        assert_eq!(observer.lock().unwrap().events, get_all());
+        assert_eq!(tee.get_failures(), 0);
+
+        // Both see the payload in place
+        let payload = b"in place".to_vec();
+        tee.forward_tcp(get_dst(), 50000, &payload).unwrap();
+        let addr = payload.as_ptr() as usize;
+        assert_eq!(primary.lock().unwrap().addrs.last(), Some(&addr));
+        assert_eq!(observer.lock().unwrap().addrs.last(), Some(&addr));
+    }
+
+    #[test]
+    fn test_tee_errors() {
+        // Errors of the observer are counted only
+        let observer = new_shared(Recorder {
+            is_failing: true,
+            ..Default::default()
+        });
+        let mut tee = TeeForward::new(Recorder::default(), observer);
+        assert!(forward_all(&mut tee).iter().all(|result| result.is_ok()));
+        assert_eq!(tee.get_failures(), 4);
+        assert!(!tee.is_observer_disabled());
+
+        // Errors of the primary are returned, and the observer still observes
+        let primary = Recorder {
+            is_failing: true,
+            ..Default::default()
+        };
+        let observer = new_shared(Recorder::default());
+        let mut tee = TeeForward::new(primary, observer.clone());
+        assert!(forward_all(&mut tee).iter().all(|result| result.is_err()));
+        assert_eq!(observer.lock().unwrap().events, get_all());
+        assert_eq!(tee.get_failures(), 0);
+    }
+
+    #[test]
+    fn test_tee_panic() {
+        let primary = new_shared(Recorder::default());
+        let observer = Recorder {
+            is_panicking: true,
+            ..Default::default()
+        };
+        let mut tee = TeeForward::new(primary.clone(), observer);
+        assert!(forward_all(&mut tee).iter().all(|result| result.is_ok()));
+
+        // The observer is disabled after its first panic
+        assert!(tee.is_observer_disabled());
+        assert_eq!(tee.get_failures(), 1);
+        assert_eq!(primary.lock().unwrap().events, get_all());
+
+        // A panic in an observer behind a lock does not poison the primary
+        let observer = new_shared(Recorder {
+            is_panicking: true,
+            ..Default::default()
+        });
+        let mut tee = TeeForward::new(primary.clone(), observer);
+        tee.forward_udp(get_dst(), 50000, b"after").unwrap();
+        assert!(tee.is_observer_disabled());
+        assert!(!primary.is_poisoned());
+        assert_eq!(
+            primary.lock().unwrap().events.last(),
+            Some(&Event::Udp(b"after".to_vec()))
+        );
+    }
+
+    #[test]
+    fn test_filter() {
+        let inner = new_shared(Recorder::default());
+        let mut filter =
+            FilterForward::new(inner.clone(), |protocol, _, _, payload: &[u8]| {
+                match (protocol, payload) {
+                    (Protocol::Udp, b"drop") => Verdict::Drop,
+                    (Protocol::Tcp, b"replace") => Verdict::Replace(b"replaced".to_vec()),
+                    _ => Verdict::Pass,
+                }
+            });
+
+        filter.forward_udp(get_dst(), 50000, b"drop").unwrap();
+        // The same payload over TCP passes
+        filter.forward_tcp(get_dst(), 50000, b"drop").unwrap();
+        filter.forward_tcp(get_dst(), 50000, b"replace").unwrap();
+        assert_eq!(filter.get_dropped(), 1);
+        assert!(forward_all(&mut filter).iter().all(|result| result.is_ok()));
+
+        let mut events = vec![
+            Event::Tcp(b"drop".to_vec()),
+            Event::Tcp(b"replaced".to_vec()),
+        ];
+        events.extend(get_all());
+        assert_eq!(inner.lock().unwrap().events, events);
+
+        // Passed payloads are not copied
+        let payload = b"pass".to_vec();
+        filter.forward_udp(get_dst(), 50000, &payload).unwrap();
+        assert_eq!(
+            inner.lock().unwrap().addrs.last(),
+            Some(&(payload.as_ptr() as usize))
+        );
+    }
+
+    #[test]
+    fn test_filter_errors() {
+        let inner = Recorder {
+            is_failing: true,
+            ..Default::default()
+        };
+        let mut filter = FilterForward::new(inner, |_, _, _, _: &[u8]| Verdict::Drop);
+
+        // Dropped payloads never reach the inner, but the rest does
+        assert!(filter.forward_tcp(get_dst(), 50000, b"tcp").is_ok());
+        assert!(filter.forward_tcp_connect(get_dst(), 50000, true).is_err());
+        assert!(filter.forward_tcp_close(get_dst(), 50000, true).is_err());
+    }
+
+    #[test]
+    fn test_chain() {
+        let first = new_shared(Recorder {
+            backlog: (100, 400),
+            ..Default::default()
+        });
+        let second = new_shared(Recorder {
+            backlog: (300, 200),
+            ..Default::default()
+        });
+        let mut chain = ChainForward::new(first.clone(), second.clone());
+        assert!(forward_all(&mut chain).iter().all(|result| result.is_ok()));
+        assert_eq!(first.lock().unwrap().events, get_all());
+        assert_eq!(second.lock().unwrap().events, get_all());
+        assert_eq!(chain.get_tcp_backlog(get_dst(), 50000), (300, 200));
+
+        // The second is not forwarded to if the first fails
+        first.lock().unwrap().is_failing = true;
+        assert!(forward_all(&mut chain).iter().all(|result| result.is_err()));
+        assert_eq!(second.lock().unwrap().events.len(), 4);
+
+        // Errors of the second are returned
+        first.lock().unwrap().is_failing = false;
+        second.lock().unwrap().is_failing = true;
+        assert!(forward_all(&mut chain).iter().all(|result| result.is_err()));
+        assert_eq!(first.lock().unwrap().events.len(), 8);
+    }
+
+    #[test]
+    fn test_compose() {
+        // A tee of a filtered chain, as boxed into the handle of the workers
+        let first = new_shared(Recorder::default());
+        let second = new_shared(Recorder::default());
+        let observer = new_shared(Recorder::default());
+        let chain = ChainForward::new(first.clone(), second.clone());
+        let filter = FilterForward::new(chain, |_, _, _, payload: &[u8]| {
+            if payload == b"udp" {
+                Verdict::Drop
+            } else {
+                Verdict::Pass
+            }
+        });
+        let mut tee: Arc<Mutex<dyn Forward>> =
+            Arc::new(Mutex::new(TeeForward::new(filter, observer.clone())));
+        assert!(forward_all(&mut tee).iter().all(|result| result.is_ok()));
+
+        let mut filtered = get_all();
+        filtered.remove(2);
+        assert_eq!(first.lock().unwrap().events, filtered);
+        assert_eq!(second.lock().unwrap().events, filtered);
+        assert_eq!(observer.lock().unwrap().events, get_all());
+    }
+}
@@ -0,0 +1,370 @@
+use std::fmt::{self, Display, Formatter};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::sync::{AtomicBool, Mutex, Ordering};
+
+/// Represents the wait between 2 checks of a worker thread finishing.
+const FINISH_WAIT: u64 = 10;
+
+/// Represents the reason a worker is closed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CloseReason {
+    /// Closed by its owner.
+    Closed,
+    /// Aborted by its owner, which resets the stream to the proxy.
+    Aborted,
+    /// Sent and received nothing for longer than its idle timeout.
+    Idle,
+    /// Failed in connecting, sending or receiving.
+    Error,
+    /// Dropped by its owner without closing.
+    Dropped,
+}
+
+impl Display for CloseReason {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            CloseReason::Closed => write!(f, "closed"),
+            CloseReason::Aborted => write!(f, "aborted"),
+            CloseReason::Idle => write!(f, "idle"),
+            CloseReason::Error => write!(f, "error"),
+            CloseReason::Dropped => write!(f, "dropped"),
+        }
+    }
+}
+
+/// Represents the lifecycle of a worker, shared by the worker and its thread, which is the thread
+/// of its handshake or its receiving side in the poller. A worker is closed once by whichever of
+/// them comes first, and the reason of the first close is kept. A close is released to the loads
+/// of `is_closed`, so the other side sees everything done before it.
+///
+/// The thread forwards to the source through `forward` and `close_and_forward`, which are
+/// serialized with closes: once a close returns, no forward of the thread is in flight and none
+/// starts later, so the owner may release the state of the source right after closing.
+///
+/// Locks are taken in the order of the handler of the entry in the poller, the reason, then `tx`,
+/// the source the thread forwards to. A forward runs holding the reason, so it must not close the
+/// worker or deregister it from the poller, and a close must not be called holding `tx`.
+#[derive(Debug)]
+pub struct Lifecycle {
+    is_closed: AtomicBool,
+    reason: Mutex<Option<CloseReason>>,
+    is_finished: AtomicBool,
+}
+
+impl Lifecycle {
+    /// Creates a new `Lifecycle`.
+    pub fn new() -> Lifecycle {
+        Lifecycle {
+            is_closed: AtomicBool::new(false),
+            reason: Mutex::new(None),
+            is_finished: AtomicBool::new(false),
+        }
+    }
+
+    /// Closes the worker for the reason, returns `true` for the first close only, whose caller
+    /// releases what the worker holds. Later closes are ignored.
+    pub fn close(&self, reason: CloseReason) -> bool {
+        self.close_and_forward(reason, || {})
+    }
+
+    /// Closes the worker for the reason and runs the forward before any other close returns,
+    /// returns `true` for the first close only. The forward is not run for later closes.
+    pub fn close_and_forward<F: FnOnce()>(&self, reason: CloseReason, forward: F) -> bool {
+        let mut reason_locked = self.reason.lock().unwrap();
+        if reason_locked.is_some() {
+            return false;
+        }
+        *reason_locked = Some(reason);
+        self.is_closed.store(true, Ordering::Release);
+        forward();
+
+        true
+    }
+
+    /// Runs the forward if the worker is not closed, returns `false` if it is closed, where the
+    /// forward is not run. A close waits for the forward in flight.
+    pub fn forward<F: FnOnce()>(&self, forward: F) -> bool {
+        let reason_locked = self.reason.lock().unwrap();
+        if reason_locked.is_some() {
+            return false;
+        }
+        forward();
+
+        true
+    }
+
+    /// Returns if the worker is closed.
+    pub fn is_closed(&self) -> bool {
+        self.is_closed.load(Ordering::Acquire)
+    }
+
+    /// Get the reason of the first close of the worker.
+    pub fn get_close_reason(&self) -> Option<CloseReason> {
+        *self.reason.lock().unwrap()
+    }
+
+    /// Waits for the thread of the worker to finish and joins it, returns `false` if it does not
+    /// finish in the timeout, where the thread is detached and exits by itself later.
+    pub fn join(&self, thread: JoinHandle<()>, timeout: Duration) -> bool {
+        let instant = Instant::now();
+        while !self.is_finished.load(Ordering::Acquire) {
+            if instant.elapsed() >= timeout {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(FINISH_WAIT));
+        }
+        // A thread which panicked is finished as well, and is reported by the panic already
+        let _ = thread.join();
+
+        true
+    }
+}
+
+/// Represents the thread of a worker running, which finishes when dropped, including on a panic.
+pub struct Running(Arc<Lifecycle>);
+
+impl Running {
+    /// Creates a new `Running`.
+    pub fn new(lifecycle: Arc<Lifecycle>) -> Running {
+        Running(lifecycle)
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.is_finished.store(true, Ordering::Release);
+    }
+}
+
+#[cfg(all(test, loom))]
+mod tests {
+    use super::*;
+    use loom::sync::atomic::AtomicUsize;
+    use loom::sync::Arc;
+
+    #[test]
+    fn test_loom_close_during_forward() {
+        loom::model(|| {
+            let lifecycle = Arc::new(Lifecycle::new());
+            let is_released = Arc::new(AtomicBool::new(false));
+
+            let receiver = {
+                let lifecycle = Arc::clone(&lifecycle);
+                let is_released = Arc::clone(&is_released);
+                loom::thread::spawn(move || {
+                    lifecycle.forward(|| assert!(!is_released.load(Ordering::SeqCst)));
+                })
+            };
+
+            // The owner releases the source right after closing
+            assert!(lifecycle.close(CloseReason::Closed));
+            is_released.store(true, Ordering::SeqCst);
+
+            receiver.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn test_loom_drop_during_error() {
+        loom::model(|| {
+            let lifecycle = Arc::new(Lifecycle::new());
+            let is_released = Arc::new(AtomicBool::new(false));
+            let closes = Arc::new(AtomicUsize::new(0));
+
+            let receiver = {
+                let lifecycle = Arc::clone(&lifecycle);
+                let is_released = Arc::clone(&is_released);
+                let closes = Arc::clone(&closes);
+                loom::thread::spawn(move || {
+                    lifecycle.close_and_forward(CloseReason::Error, || {
+                        assert!(!is_released.load(Ordering::SeqCst));
+                        closes.fetch_add(1, Ordering::SeqCst);
+                    })
+                })
+            };
+
+            let is_dropped = lifecycle.close(CloseReason::Dropped);
+            is_released.store(true, Ordering::SeqCst);
+
+            let is_error = receiver.join().unwrap();
+            assert_ne!(is_dropped, is_error);
+            assert_eq!(closes.load(Ordering::SeqCst), is_error as usize);
+            let reason = if is_error {
+                CloseReason::Error
+            } else {
+                CloseReason::Dropped
+            };
+            assert_eq!(lifecycle.get_close_reason(), Some(reason));
+        });
+    }
+
+    #[test]
+    fn test_loom_simultaneous_close() {
+        loom::model(|| {
+            let lifecycle = Arc::new(Lifecycle::new());
+            let closes = Arc::new(AtomicUsize::new(0));
+
+            let reaper = {
+                let lifecycle = Arc::clone(&lifecycle);
+                loom::thread::spawn(move || lifecycle.close(CloseReason::Idle))
+            };
+            let is_error = {
+                let closes = Arc::clone(&closes);
+                lifecycle.close_and_forward(CloseReason::Error, move || {
+                    closes.fetch_add(1, Ordering::SeqCst);
+                })
+            };
+
+            let is_idle = reaper.join().unwrap();
+            assert_ne!(is_idle, is_error);
+            assert!(lifecycle.is_closed());
+            assert_eq!(closes.load(Ordering::SeqCst), is_error as usize);
+            let reason = if is_idle {
+                CloseReason::Idle
+            } else {
+                CloseReason::Error
+            };
+            assert_eq!(lifecycle.get_close_reason(), Some(reason));
+        });
+    }
+
+    #[test]
+    fn test_loom_remove_during_forward() {
+        loom::model(|| {
+            let lifecycle = Arc::new(Lifecycle::new());
+            // The state of the source in the registry of the owner
+            let registry = Arc::new(Mutex::new(Some(0usize)));
+
+            let receiver = {
+                let lifecycle = Arc::clone(&lifecycle);
+                let registry = Arc::clone(&registry);
+                loom::thread::spawn(move || {
+                    let is_forwarded = lifecycle.forward(|| {
+                        let mut registry_locked = registry.lock().unwrap();
+                        *registry_locked.as_mut().expect("forward after removal") += 1;
+                    });
+                    // A late forward is refused
+                    if !is_forwarded {
+                        assert!(lifecycle.is_closed());
+                    }
+                })
+            };
+
+            lifecycle.close(CloseReason::Closed);
+            let state = registry.lock().unwrap().take();
+            assert!(state.is_some());
+
+            receiver.join().unwrap();
+            assert!(registry.lock().unwrap().is_none());
+        });
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Barrier;
+
+    /// Represents the rounds of the stress tests.
+    const ROUNDS: usize = 200;
+
+    /// Represents the threads racing in each round.
+    const THREADS: usize = 8;
+
+    const REASONS: [CloseReason; 5] = [
+        CloseReason::Closed,
+        CloseReason::Aborted,
+        CloseReason::Idle,
+        CloseReason::Error,
+        CloseReason::Dropped,
+    ];
+
+    #[test]
+    fn test_double_close() {
+        for _ in 0..ROUNDS {
+            let lifecycle = Arc::new(Lifecycle::new());
+            let barrier = Arc::new(Barrier::new(THREADS));
+            let forwards = Arc::new(AtomicUsize::new(0));
+            let threads: Vec<_> = (0..THREADS)
+                .map(|i| {
+                    let (lifecycle, barrier) = (Arc::clone(&lifecycle), Arc::clone(&barrier));
+                    let forwards = Arc::clone(&forwards);
+                    thread::spawn(move || {
+                        let reason = REASONS[i % REASONS.len()];
+                        barrier.wait();
+                        let is_first = lifecycle.close_and_forward(reason, || {
+                            forwards.fetch_add(1, Ordering::Relaxed);
+                        });
+                        (reason, is_first)
+                    })
+                })
+                .collect();
+            let results: Vec<_> = threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap())
+                .collect();
+
+            // Exactly one close takes effect, forwards once, and keeps its reason
+            let firsts: Vec<_> = results.iter().filter(|(_, is_first)| *is_first).collect();
+            assert_eq!(firsts.len(), 1);
+            assert_eq!(forwards.load(Ordering::Relaxed), 1);
+            assert_eq!(lifecycle.get_close_reason(), Some(firsts[0].0));
+            assert!(lifecycle.is_closed());
+            assert!(!lifecycle.close(CloseReason::Closed));
+        }
+    }
+
+    #[test]
+    fn test_close_racing_forward() {
+        for _ in 0..ROUNDS {
+            let lifecycle = Arc::new(Lifecycle::new());
+            let barrier = Arc::new(Barrier::new(THREADS + 1));
+            let forwards = Arc::new(AtomicUsize::new(0));
+            let threads: Vec<_> = (0..THREADS)
+                .map(|_| {
+                    let (lifecycle, barrier) = (Arc::clone(&lifecycle), Arc::clone(&barrier));
+                    let forwards = Arc::clone(&forwards);
+                    thread::spawn(move || {
+                        barrier.wait();
+                        while lifecycle.forward(|| {
+                            forwards.fetch_add(1, Ordering::Relaxed);
+                        }) {}
+                        // A forward refused is refused for good
+                        assert!(!lifecycle.forward(|| panic!("forward after close")));
+                    })
+                })
+                .collect();
+
+            barrier.wait();
+            thread::yield_now();
+            assert!(lifecycle.close(CloseReason::Closed));
+            // No forward is in flight once the close returns
+            let forwarded = forwards.load(Ordering::Relaxed);
+            for thread in threads {
+                thread.join().unwrap();
+            }
+            assert_eq!(forwards.load(Ordering::Relaxed), forwarded);
+        }
+    }
+
+    #[test]
+    fn test_join_timeout() {
+        let lifecycle = Arc::new(Lifecycle::new());
+        let lifecycle_cloned = Arc::clone(&lifecycle);
+        let (tx, rx) = std::sync::mpsc::channel::<()>();
+        let thread = thread::spawn(move || {
+            let _running = Running::new(lifecycle_cloned);
+            let _ = rx.recv();
+        });
+
+        // A thread which does not finish is detached after the timeout
+        let instant = Instant::now();
+        assert!(!lifecycle.join(thread, Duration::from_millis(50)));
+        assert!(instant.elapsed() < Duration::from_secs(1));
+        drop(tx);
+    }
+}
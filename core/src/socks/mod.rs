@@ -0,0 +1,2569 @@
+use log::{debug, trace, warn};
+use lru::LruCache;
+use std::cmp::{max, Reverse};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, SocketAddrV4, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+mod connector;
+mod forward;
+mod lifecycle;
+mod poller;
+mod pool;
+#[allow(clippy::module_inception)]
+mod socks;
+pub use self::connector::{is_unsupported, ProxyConnector, ProxyType};
+pub use self::forward::{ChainForward, FilterForward, Forward, TeeForward, Verdict};
+pub use self::lifecycle::CloseReason;
+use self::lifecycle::{Lifecycle, Running};
+use self::poller::{Handler, Poller, Wait};
+pub use self::pool::Pool;
+use self::socks::SocksDatagram;
+pub use self::socks::{Auth, Secret};
+use crate::backoff::{self, Site};
+use crate::hooks::Protocol;
+use crate::monitor::{Counters, Monitor, Registration, WorkerStats};
+use crate::record::{Direction, Recorder};
+use crate::stun::{self, NatReport, Transport};
+use crate::sync::{
+    self,
+    mpsc::{self, Receiver, TryRecvError},
+};
+use crate::threads::{Purpose, Slot, Threads};
+
+/// Represents the min interval between 2 checks of the password file in seconds.
+const RELOAD_INTERVAL: u64 = 1;
+
+/// Represents the credentials of proxies, the password may be read from a file which is
+/// re-read when modified.
+#[derive(Clone, Debug)]
+pub struct Credentials {
+    auth: Option<Auth>,
+    path: Option<PathBuf>,
+    modified: Option<SystemTime>,
+    last_check: Option<Instant>,
+}
+
+impl Credentials {
+    /// Creates a new `Credentials` without authentication.
+    pub fn new() -> Credentials {
+        Credentials {
+            auth: None,
+            path: None,
+            modified: None,
+            last_check: None,
+        }
+    }
+
+    /// Creates a new `Credentials` with the given username and password.
+    pub fn from_password(username: String, password: Secret) -> Credentials {
+        Credentials {
+            auth: Some(Auth::new(username, password)),
+            path: None,
+            modified: None,
+            last_check: None,
+        }
+    }
+
+    /// Creates a new `Credentials` with the given username and the password in the first line of
+    /// the given file.
+    pub fn from_file(username: String, path: PathBuf) -> io::Result<Credentials> {
+        let mut credentials = Credentials {
+            auth: Some(Auth::new(username, Secret::new(String::new()))),
+            path: Some(path),
+            modified: None,
+            last_check: None,
+        };
+        credentials.reload()?;
+
+        Ok(credentials)
+    }
+
+    /// Re-reads the password file if it is modified, which is checked at most once in a second.
+    /// Returns if the password is changed.
+    pub fn reload(&mut self) -> io::Result<bool> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(false),
+        };
+        if let Some(last_check) = self.last_check {
+            if last_check.elapsed().as_secs() < RELOAD_INTERVAL {
+                return Ok(false);
+            }
+        }
+        self.last_check = Some(Instant::now());
+        let modified = fs::metadata(path)?.modified()?;
+        if self.modified == Some(modified) {
+            return Ok(false);
+        }
+
+        let content = Secret::new(fs::read_to_string(path)?);
+        let line = content.as_str().lines().next().unwrap_or("");
+        let password = Secret::from_line(String::from(line));
+        let username = match self.auth {
+            Some(ref auth) => String::from(auth.get_username()),
+            None => String::new(),
+        };
+        self.auth = Some(Auth::new(username, password));
+        self.modified = Some(modified);
+        debug!("load password from {}", path.display());
+
+        Ok(true)
+    }
+
+    /// Get the authentication for new connections.
+    pub fn get_auth(&self) -> Option<&Auth> {
+        self.auth.as_ref()
+    }
+}
+
+impl Default for Credentials {
+    fn default() -> Self {
+        Credentials::new()
+    }
+}
+
+/// Represents the max wait time for the thread of a closing worker to exit.
+const CLOSE_WAIT: u64 = 1000;
+
+/// Represents the min backlog of a stream before pausing reads from the proxy.
+const MIN_HIGH_WATER: usize = 64 * 1024;
+
+/// Represents the time of the measured drain rate the backlog of a stream may hold.
+const DRAIN_TIME: u128 = 500;
+
+/// Represents the default max size of data queued by a stream for sending to the proxy.
+pub const DEFAULT_SEND_CAPACITY: usize = 256 * 1024;
+
+/// Represents the max time a closed stream keeps sending its queued data to the proxy.
+const FLUSH_WAIT: u64 = 1000;
+
+/// Represents the base of the time new connects back off after the local ports are exhausted in
+/// milliseconds.
+const PORT_EXHAUSTION_BACKOFF: u64 = 1000;
+
+/// Represents the back off of new connects for the exhaustion of local ports. The local ports are
+/// shared by all the captures, so is the back off.
+static PORT_BACKOFF: PortBackoff = PortBackoff::new(PORT_EXHAUSTION_BACKOFF);
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Represents the back off of new connects after the local ports are exhausted.
+#[derive(Debug)]
+pub struct PortBackoff {
+    /// Represents the base of the back off in milliseconds.
+    base: u64,
+    /// Represents the end of the back off in milliseconds since the epoch.
+    until: AtomicU64,
+    /// Represents the number of connects failed for the exhaustion of local ports.
+    exhaustions: AtomicUsize,
+    /// Represents the number of consecutive connects failed for the exhaustion of local ports,
+    /// which doubles the back off.
+    streak: AtomicUsize,
+}
+
+impl PortBackoff {
+    /// Creates a new `PortBackoff` of the base in milliseconds.
+    pub const fn new(base: u64) -> PortBackoff {
+        PortBackoff {
+            base,
+            until: AtomicU64::new(0),
+            exhaustions: AtomicUsize::new(0),
+            streak: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns if the error of a connect is caused by the exhaustion of local ports, and backs
+    /// off new connects if so. The back off is at least half the base, so a jitter of about zero
+    /// still leaves the ports some time to be freed. A warning with the tuning of the system is
+    /// logged for the first time.
+    fn check(&self, e: &io::Error) -> bool {
+        if e.kind() != io::ErrorKind::AddrNotAvailable {
+            return false;
+        }
+
+        let streak = self.streak.fetch_add(1, Ordering::Relaxed);
+        let delay = backoff::get_delay(Site::Ports, Duration::from_millis(self.base), streak);
+        let delay = max(delay.as_millis() as u64, self.base / 2);
+        self.until.store(now_millis() + delay, Ordering::Relaxed);
+        if self.exhaustions.fetch_add(1, Ordering::Relaxed) == 0 {
+            warn_port_exhaustion(self.base);
+        }
+
+        true
+    }
+
+    /// Records a connect succeeded, which ends the streak of failures.
+    fn succeed(&self) {
+        self.streak.store(0, Ordering::Relaxed);
+    }
+
+    /// Returns if new connects back off.
+    pub fn is_exhausted(&self) -> bool {
+        now_millis() < self.until.load(Ordering::Relaxed)
+    }
+
+    /// Get the number of connects failed for the exhaustion of local ports.
+    pub fn get_exhaustions(&self) -> usize {
+        self.exhaustions.load(Ordering::Relaxed)
+    }
+}
+
+/// Logs the warning of the exhaustion of local ports with the tuning of the system.
+fn warn_port_exhaustion(base: u64) {
+    let fds = match fs::read_dir("/proc/self/fd") {
+        Ok(dir) => dir.count().to_string(),
+        Err(_) => String::from("?"),
+    };
+    let tuning = if cfg!(target_os = "windows") {
+        "raise the dynamic port range with `netsh int ipv4 set dynamicport tcp` or lower TcpTimedWaitDelay in the registry"
+    } else if cfg!(target_os = "macos") {
+        "widen net.inet.ip.portrange.first and net.inet.ip.portrange.last with sysctl"
+    } else {
+        "widen net.ipv4.ip_local_port_range or enable net.ipv4.tcp_tw_reuse with sysctl"
+    };
+    warn!(
+        "Local ports are exhausted with {} open files, new connections back off from {} ms, {}",
+        fds, base, tuning
+    );
+}
+
+/// Returns if new connects back off for the exhaustion of local ports.
+pub fn is_port_exhausted() -> bool {
+    PORT_BACKOFF.is_exhausted()
+}
+
+/// Get the number of connects failed for the exhaustion of local ports.
+pub fn get_port_exhaustions() -> usize {
+    PORT_BACKOFF.get_exhaustions()
+}
+
+/// Records data exchanged with the proxy, and stops recording if the record cannot be written.
+fn record(
+    recorder: &sync::Mutex<Option<Recorder>>,
+    direction: Direction,
+    addr: SocketAddr,
+    payload: &[u8],
+) {
+    // Records hold IPv4 addresses only
+    let addr = match addr {
+        SocketAddr::V4(addr) => addr,
+        SocketAddr::V6(_) => return,
+    };
+    let mut recorder_locked = recorder.lock().unwrap();
+    if let Some(ref mut r) = *recorder_locked {
+        if let Err(ref e) = r.record(direction, addr, payload) {
+            warn!("record: {}", e);
+            *recorder_locked = None;
+        }
+    }
+}
+
+/// Represents the recording of the data forwarded from the proxy to the source, as the observer
+/// of a `TeeForward`.
+struct RecordForward {
+    recorder: Arc<sync::Mutex<Option<Recorder>>>,
+}
+
+impl Forward for RecordForward {
+    fn forward_tcp(&mut self, dst: SocketAddr, _: u16, payload: &[u8]) -> io::Result<()> {
+        record(&self.recorder, Direction::Down, dst, payload);
+        Ok(())
+    }
+
+    fn forward_udp(&mut self, dst: SocketAddr, _: u16, payload: &[u8]) -> io::Result<()> {
+        record(&self.recorder, Direction::Down, dst, payload);
+        Ok(())
+    }
+
+    fn forward_tcp_connect(&mut self, _: SocketAddr, _: u16, _: bool) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Represents the forwarding of a worker to the source, which is recorded once the worker has a
+/// recorder.
+type RecordedForward = TeeForward<Arc<Mutex<dyn Forward>>, RecordForward>;
+
+/// Represents the result of the handshake of a stream, the stream, the time of the handshake and
+/// the token of the stream in the poller.
+type Handshake = io::Result<(TcpStream, Duration, usize)>;
+
+/// Represents a worker of a SOCKS5 TCP stream.
+pub struct StreamWorker {
+    dst: SocketAddr,
+    stream: Option<TcpStream>,
+    rx: Option<Receiver<Handshake>>,
+    latency: Option<Duration>,
+    queue: Arc<sync::Mutex<SendQueue>>,
+    send_capacity: usize,
+    is_write_shutdown: bool,
+    thread: Option<JoinHandle<()>>,
+    lifecycle: Arc<Lifecycle>,
+    poller: &'static Poller,
+    token: Option<usize>,
+    _slot: Arc<Slot>,
+    src_port: u16,
+    counters: Arc<Counters>,
+    _registration: Option<Registration>,
+    pauses: Arc<sync::AtomicUsize>,
+    recorder: Arc<sync::Mutex<Option<Recorder>>>,
+    idle_timeout: Option<Duration>,
+}
+
+impl StreamWorker {
+    /// Opens a new `StreamWorker`. The worker returns immediately in connecting and the handshake
+    /// runs in its own thread, the result is forwarded and reported by `poll_ready`. Once connected,
+    /// the stream is received by the poller. A connection of the pool is preferred to a new one.
+    pub fn connect(
+        tx: Arc<Mutex<dyn Forward>>,
+        threads: &Arc<Threads>,
+        src_port: u16,
+        dst: SocketAddr,
+        connector: ProxyConnector,
+        pool: Option<Arc<Pool>>,
+    ) -> io::Result<StreamWorker> {
+        StreamWorker::open(tx, threads, src_port, dst, &PORT_BACKOFF, move || {
+            // At most some handshakes run at once, so flows connecting again after a restart of
+            // the proxy do not hammer it together
+            let _permit = backoff::enter()?;
+            match pool.and_then(|pool| pool.take(connector.get_remote())) {
+                Some(stream) => connector.connect_over(stream, dst),
+                None => connector.connect(dst),
+            }
+        })
+    }
+
+    /// Opens a new `StreamWorker` connects to the destination directly without a proxy. The
+    /// worker returns immediately in connecting like `connect`.
+    pub fn connect_direct(
+        tx: Arc<Mutex<dyn Forward>>,
+        threads: &Arc<Threads>,
+        src_port: u16,
+        dst: SocketAddr,
+    ) -> io::Result<StreamWorker> {
+        StreamWorker::open(tx, threads, src_port, dst, &PORT_BACKOFF, move || {
+            TcpStream::connect(dst)
+        })
+    }
+
+    /// Opens a new `StreamWorker` connects to the local address of a service in place of the
+    /// destination, which stays the destination the source sees. The worker returns immediately
+    /// in connecting like `connect`.
+    pub fn connect_local(
+        tx: Arc<Mutex<dyn Forward>>,
+        threads: &Arc<Threads>,
+        src_port: u16,
+        dst: SocketAddr,
+        local: SocketAddr,
+    ) -> io::Result<StreamWorker> {
+        StreamWorker::open(tx, threads, src_port, dst, &PORT_BACKOFF, move || {
+            TcpStream::connect(local)
+        })
+    }
+
+    fn open<F>(
+        tx: Arc<Mutex<dyn Forward>>,
+        threads: &Arc<Threads>,
+        src_port: u16,
+        dst: SocketAddr,
+        ports: &'static PortBackoff,
+        handshake: F,
+    ) -> io::Result<StreamWorker>
+    where
+        F: FnOnce() -> io::Result<TcpStream> + Send + 'static,
+    {
+        let poller = Poller::get(threads)?;
+        // The place is taken until both the worker and the handshake are gone
+        let slot = Arc::new(Threads::reserve(
+            threads,
+            format!("stream {} -> {}", src_port, dst),
+            Purpose::Stream,
+        )?);
+        let (ready_tx, ready_rx) = mpsc::channel();
+
+        let a_lifecycle = Arc::new(Lifecycle::new());
+        let a_lifecycle_cloned = Arc::clone(&a_lifecycle);
+        let a_counters = Arc::new(Counters::new());
+        let a_counters_cloned = Arc::clone(&a_counters);
+        let a_pauses = Arc::new(sync::AtomicUsize::new(0));
+        let a_pauses_cloned = Arc::clone(&a_pauses);
+        let a_recorder = Arc::new(sync::Mutex::new(None));
+        let a_recorder_cloned = Arc::clone(&a_recorder);
+        let a_queue = Arc::new(sync::Mutex::new(SendQueue::new()));
+        let a_queue_cloned = Arc::clone(&a_queue);
+        let slot_cloned = Arc::clone(&slot);
+        let name = format!("handshake {} -> {}", src_port, dst);
+        let thread = Threads::spawn(threads, name, Purpose::Handshake, move || {
+            let _running = Running::new(Arc::clone(&a_lifecycle_cloned));
+            let _slot = slot_cloned;
+
+            // Handshake
+            let instant = Instant::now();
+            let result = handshake().and_then(|stream| {
+                // Neither side blocks the poller or the owner once connected
+                stream.set_nonblocking(true)?;
+                let stream_cloned = stream.try_clone()?;
+                Ok((stream, stream_cloned))
+            });
+            match result {
+                Ok((stream, stream_cloned)) => {
+                    ports.succeed();
+                    // The worker may be closed during the handshake, the stream is published
+                    // and registered in the poller before a close or never
+                    let lifecycle = Arc::clone(&a_lifecycle_cloned);
+                    let raw = poller::get_raw(&stream_cloned);
+                    let receiver = StreamReceiver {
+                        stream: stream_cloned,
+                        dst,
+                        src_port,
+                        tx: TeeForward::new(
+                            Arc::clone(&tx),
+                            RecordForward {
+                                recorder: a_recorder_cloned,
+                            },
+                        ),
+                        lifecycle: a_lifecycle_cloned,
+                        counters: a_counters_cloned,
+                        pauses: a_pauses_cloned,
+                        queue: a_queue_cloned,
+                        drain_rate: 0,
+                        pause: None,
+                        is_eof: false,
+                    };
+                    let is_published = lifecycle.forward(|| {
+                        let token = poller.register(raw, Box::new(receiver));
+                        let _ = ready_tx.send(Ok((stream, instant.elapsed(), token)));
+                        trace!("connect stream {} -> {}", 0, dst);
+                        if let Err(ref e) =
+                            tx.lock().unwrap().forward_tcp_connect(dst, src_port, true)
+                        {
+                            warn!("handle {}: {}", "TCP", e);
+                        }
+                    });
+                    if !is_published {
+                        trace!("stream {} -> {} is closed in connecting", 0, dst);
+                    }
+                }
+                Err(e) => {
+                    // The source retransmits the SYN without ACK/RST, which connects again once the
+                    // back off is over
+                    let _ = a_lifecycle_cloned.close_and_forward(CloseReason::Error, || {
+                        let is_exhausted = ports.check(&e);
+                        let _ = ready_tx.send(Err(e));
+                        if !is_exhausted {
+                            if let Err(ref e) =
+                                tx.lock().unwrap().forward_tcp_connect(dst, src_port, false)
+                            {
+                                warn!("handle {}: {}", "TCP", e);
+                            }
+                        }
+                    });
+                }
+            }
+        })?;
+
+        trace!("open stream {} -> {}", 0, dst);
+
+        Ok(StreamWorker {
+            dst,
+            stream: None,
+            rx: Some(ready_rx),
+            latency: None,
+            queue: a_queue,
+            send_capacity: DEFAULT_SEND_CAPACITY,
+            is_write_shutdown: false,
+            thread: Some(thread),
+            lifecycle: a_lifecycle,
+            poller,
+            token: None,
+            _slot: slot,
+            src_port,
+            counters: a_counters,
+            _registration: None,
+            pauses: a_pauses,
+            recorder: a_recorder,
+            idle_timeout: None,
+        })
+    }
+
+    /// Returns if the worker is connected, or the error if the handshake failed. Data queued
+    /// before connected is sent once the worker is connected.
+    pub fn poll_ready(&mut self) -> io::Result<bool> {
+        if self.stream.is_some() {
+            return Ok(true);
+        }
+        let result = match self.rx {
+            Some(ref rx) => rx.try_recv(),
+            None => return Err(io::Error::from(io::ErrorKind::NotConnected)),
+        };
+        match result {
+            Ok(handshake) => self.complete(handshake),
+            Err(TryRecvError::Empty) => Ok(false),
+            Err(TryRecvError::Disconnected) => self.fail_handshake(),
+        }
+    }
+
+    /// Takes the result of the handshake.
+    fn complete(&mut self, handshake: Handshake) -> io::Result<bool> {
+        match handshake {
+            Ok((stream, latency, token)) => {
+                self.rx = None;
+                self.stream = Some(stream);
+                self.latency = Some(latency);
+                self.token = Some(token);
+
+                // Send queued data, and shut down the stream for sending after it if the source
+                // closed before the stream is connected
+                self.flush()?;
+
+                Ok(true)
+            }
+            Err(e) => {
+                self.rx = None;
+                Err(e)
+            }
+        }
+    }
+
+    /// Gives up the handshake whose thread is gone without a result.
+    fn fail_handshake(&mut self) -> io::Result<bool> {
+        self.rx = None;
+
+        Err(io::Error::other("handshake aborted"))
+    }
+
+    /// Get the time of the handshake if the worker is connected.
+    pub fn get_latency(&self) -> Option<Duration> {
+        self.latency
+    }
+
+    /// Get the time of the first round trip of data through the proxy, from the first data
+    /// written to the first data received after it, or `None` if it is not completed.
+    pub fn get_early_rtt(&self) -> Option<Duration> {
+        self.counters.get_early_rtt()
+    }
+
+    /// Sends data on the SOCKS5 in TCP to the destination. Data is queued and sent without
+    /// blocking, including when the worker is connecting, and refused with `WouldBlock` if the
+    /// queue is full. Data is sent immediately without waiting for coalescing if `is_push` is set.
+    pub fn send(&mut self, buffer: &[u8], is_push: bool) -> io::Result<()> {
+        if buffer.is_empty() {
+            return Ok(());
+        }
+        let is_connected = self.poll_ready()?;
+        {
+            let mut queue_locked = self.queue.lock().unwrap();
+            if queue_locked.buffer.len() + buffer.len() > self.send_capacity {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "send queue is full",
+                ));
+            }
+            queue_locked.buffer.extend_from_slice(buffer);
+            queue_locked.is_push |= is_push;
+        }
+
+        debug!(
+            "send to SOCKS {}: {} -> {} ({} Bytes)",
+            "TCP",
+            "0",
+            self.dst,
+            buffer.len()
+        );
+        self.counters.add_up(buffer.len());
+        record(&self.recorder, Direction::Up, self.dst, buffer);
+
+        if is_connected {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends the queued data as far as the stream takes it without blocking, the poller sends the
+    /// rest once the stream is writable.
+    fn flush(&mut self) -> io::Result<()> {
+        let is_drained = match self.stream {
+            Some(ref stream) => drain(stream, &mut self.queue.lock().unwrap(), &self.counters)?,
+            None => return Ok(()),
+        };
+        if !is_drained {
+            self.poller.wake();
+        }
+
+        Ok(())
+    }
+
+    /// Get the size of the data queued for sending to the proxy.
+    pub fn get_queue_len(&self) -> usize {
+        self.queue.lock().unwrap().buffer.len()
+    }
+
+    /// Get the size of the data which may be queued before the queue is full.
+    pub fn get_send_room(&self) -> usize {
+        self.send_capacity.saturating_sub(self.get_queue_len())
+    }
+
+    /// Returns if the queue is above its high-water mark of half the capacity, where the source
+    /// should be slowed down.
+    pub fn is_congested(&self) -> bool {
+        self.get_queue_len() > self.send_capacity / 2
+    }
+
+    /// Sets the max size of data queued for sending to the proxy.
+    pub fn set_send_capacity(&mut self, capacity: usize) {
+        self.send_capacity = capacity;
+    }
+
+    /// Closes the stream to the proxy for sending after the source closes in order, the worker
+    /// keeps receiving the data of the proxy until it closes as well. The stream is closed for
+    /// sending after the queued data, once connected if the worker is connecting.
+    pub fn shutdown_write(&mut self) -> io::Result<()> {
+        if self.is_write_shutdown {
+            return Ok(());
+        }
+        self.is_write_shutdown = true;
+        trace!("shutdown stream {} -> {} for sending", 0, self.dst);
+        self.queue.lock().unwrap().shutdown = Some(Shutdown::Write);
+        if !self.poll_ready()? {
+            return Ok(());
+        }
+
+        self.flush()
+    }
+
+    /// Returns if the stream to the proxy is closed for sending.
+    pub fn is_write_shutdown(&self) -> bool {
+        self.is_write_shutdown
+    }
+
+    fn shutdown_stream(&self, how: Shutdown) -> io::Result<()> {
+        if let Some(ref stream) = self.stream {
+            if let Err(e) = stream.shutdown(how) {
+                // The proxy may be gone already
+                if e.kind() != io::ErrorKind::NotConnected {
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sets the recorder of the data exchanged with the proxy after the handshake.
+    pub fn set_recorder(&mut self, recorder: Recorder) {
+        *self.recorder.lock().unwrap() = Some(recorder);
+    }
+
+    /// Get the number of bytes sent and received by the worker.
+    pub fn get_bytes(&self) -> (usize, usize) {
+        self.counters.get_bytes()
+    }
+
+    /// Get the traffic of the worker exchanged with the proxy.
+    pub fn get_stats(&self) -> WorkerStats {
+        self.counters.get_stats()
+    }
+
+    /// Registers the worker in the monitor with the destination shown, which may be redacted. The
+    /// worker is removed from the monitor once dropped.
+    pub fn set_monitor(&mut self, monitor: &Arc<Monitor>, dst: SocketAddrV4) {
+        self._registration = Some(Monitor::register(
+            monitor,
+            Protocol::Tcp,
+            self.src_port,
+            Some(dst),
+            Arc::clone(&self.counters),
+        ));
+    }
+
+    /// Get the number of times the worker paused reading for the backlog.
+    pub fn get_pauses(&self) -> usize {
+        self.pauses.load(Ordering::Relaxed)
+    }
+
+    /// Sets the time without data in either direction after which the worker is idle, or `None`
+    /// if the worker is never idle.
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.idle_timeout = timeout;
+    }
+
+    /// Get the time left until the worker is idle, which is zero once it sent and received no
+    /// data for longer than its idle timeout, or `None` if it has no idle timeout. A worker still
+    /// connecting is never idle, which is left to the timeout of the handshake.
+    pub fn get_idle_remaining(&self) -> Option<Duration> {
+        let timeout = self.idle_timeout?;
+        if self.stream.is_none() {
+            return Some(timeout);
+        }
+
+        Some(get_remaining(&self.counters, timeout))
+    }
+
+    /// Closes the worker for the reason, removes the stream from the poller and shuts it down at
+    /// once. Only the first close takes effect, later ones are ignored.
+    pub fn close(&mut self, reason: CloseReason) {
+        // Receive the stream if the handshake is just completed
+        let _ = self.poll_ready();
+        if !self.lifecycle.close(reason) {
+            return;
+        }
+        // The thread may publish the stream until the close, and never after it
+        let _ = self.poll_ready();
+        // Send the queued data before shutting down if possible, the poller keeps sending the rest
+        // for a while and shuts down the stream after it
+        if reason != CloseReason::Aborted {
+            if let Some(ref stream) = self.stream {
+                let mut queue_locked = self.queue.lock().unwrap();
+                if let Ok(false) = drain(stream, &mut queue_locked, &self.counters) {
+                    queue_locked.shutdown = Some(Shutdown::Both);
+                    queue_locked.deadline =
+                        Some(Instant::now() + Duration::from_millis(FLUSH_WAIT));
+                    drop(queue_locked);
+                    self.token.take();
+                    self.poller.wake();
+                    trace!(
+                        "close stream {} -> {}: {}, flushing {} Bytes",
+                        0,
+                        self.dst,
+                        reason,
+                        self.get_queue_len()
+                    );
+                    return;
+                }
+            }
+        }
+        self.queue.lock().unwrap().buffer.clear();
+        if let Some(token) = self.token.take() {
+            self.poller.deregister(token);
+        }
+        // An aborted stream is only closed for receiving, and is reset when dropped, including one
+        // published just now
+        let how = if reason == CloseReason::Aborted {
+            if let Some(ref stream) = self.stream {
+                if let Err(ref e) = set_linger_zero(stream) {
+                    warn!("handle {}: {}", "TCP", e);
+                }
+            }
+            Shutdown::Read
+        } else {
+            Shutdown::Both
+        };
+        if let Err(ref e) = self.shutdown_stream(how) {
+            warn!("handle {}: {}", "TCP", e);
+        }
+        trace!("close stream {} -> {}: {}", 0, self.dst, reason);
+    }
+
+    /// Returns if the worker is closed.
+    pub fn is_closed(&self) -> bool {
+        self.lifecycle.is_closed()
+    }
+
+    /// Get the reason of the first close of the worker.
+    pub fn get_close_reason(&self) -> Option<CloseReason> {
+        self.lifecycle.get_close_reason()
+    }
+
+    /// Closes the worker and resets the stream to the proxy when dropped, instead of shutting it
+    /// down in order.
+    pub fn abort(&mut self) {
+        // Receive the stream if the handshake is just completed
+        let _ = self.poll_ready();
+        if let Some(ref stream) = self.stream {
+            if let Err(ref e) = set_linger_zero(stream) {
+                warn!("handle {}: {}", "TCP", e);
+            }
+        }
+        self.close(CloseReason::Aborted);
+    }
+}
+
+impl Drop for StreamWorker {
+    fn drop(&mut self) {
+        self.close(CloseReason::Dropped);
+        // Detach, the thread exits after the handshake
+        if self.stream.is_none() && self.rx.is_some() {
+            trace!("drop stream {} -> {} in connecting", 0, self.dst);
+            return;
+        }
+        if let Some(thread) = self.thread.take() {
+            if !self
+                .lifecycle
+                .join(thread, Duration::from_millis(CLOSE_WAIT))
+            {
+                warn!(
+                    "stream {} -> {} does not exit in {} ms",
+                    0, self.dst, CLOSE_WAIT
+                );
+            }
+        }
+        trace!("drop stream {} -> {}", 0, self.dst);
+    }
+}
+
+/// Represents the data queued by a `StreamWorker` for sending to the proxy, shared by the worker
+/// and the poller.
+struct SendQueue {
+    buffer: Vec<u8>,
+    is_push: bool,
+    // Applied once the queue is drained
+    shutdown: Option<Shutdown>,
+    // The end of flushing a closed worker
+    deadline: Option<Instant>,
+    is_shutdown: bool,
+}
+
+impl SendQueue {
+    /// Creates a new `SendQueue`.
+    fn new() -> SendQueue {
+        SendQueue {
+            buffer: Vec::new(),
+            is_push: false,
+            shutdown: None,
+            deadline: None,
+            is_shutdown: false,
+        }
+    }
+}
+
+/// Sends the queued data to the non-blocking stream until it would block, returns if the queue is
+/// drained. A drained queue pushes the data and applies its shutdown.
+fn drain(stream: &TcpStream, queue: &mut SendQueue, counters: &Counters) -> io::Result<bool> {
+    let mut stream = stream;
+    while !queue.buffer.is_empty() {
+        match stream.write(&queue.buffer) {
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+            Ok(size) => {
+                queue.buffer.drain(..size);
+                counters.add_sent();
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    if queue.is_push {
+        queue.is_push = false;
+        // Enabling no delay pushes the data waiting for coalescing
+        stream.set_nodelay(true)?;
+        stream.set_nodelay(false)?;
+    }
+    if let Some(how) = queue.shutdown.take() {
+        if let Err(e) = stream.shutdown(how) {
+            // The proxy may be gone already
+            if e.kind() != io::ErrorKind::NotConnected {
+                return Err(e);
+            }
+        }
+        queue.is_shutdown = true;
+    }
+
+    Ok(true)
+}
+
+/// Represents the receiving and sending side of a `StreamWorker` in the poller.
+struct StreamReceiver {
+    stream: TcpStream,
+    dst: SocketAddr,
+    src_port: u16,
+    tx: RecordedForward,
+    lifecycle: Arc<Lifecycle>,
+    counters: Arc<Counters>,
+    pauses: Arc<sync::AtomicUsize>,
+    queue: Arc<sync::Mutex<SendQueue>>,
+    // Bytes per second
+    drain_rate: usize,
+    pause: Option<Pause>,
+    // Received EOF from the proxy, the stream is still sending
+    is_eof: bool,
+}
+
+impl StreamReceiver {
+    /// Returns if the worker is closed with queued data left to send.
+    fn is_flushing(&self) -> bool {
+        self.queue.lock().unwrap().deadline.is_some()
+    }
+
+    /// Returns if the queue is drained and the stream is shut down after it.
+    fn is_sent(&self) -> bool {
+        let queue_locked = self.queue.lock().unwrap();
+        queue_locked.buffer.is_empty() && queue_locked.is_shutdown
+    }
+}
+
+/// Represents a pause of reading a stream until the source drains the backlog.
+struct Pause {
+    instant: Instant,
+    last_backlog: usize,
+    drained: usize,
+}
+
+impl Handler for StreamReceiver {
+    fn readable(&mut self, buffer: &mut [u8]) -> Wait {
+        if self.lifecycle.is_closed() {
+            // A worker flushing receives no more
+            if self.is_flushing() {
+                return Wait::Paused;
+            }
+            return Wait::Finished;
+        }
+        let (dst, src_port) = (self.dst, self.src_port);
+        match self.stream.read(buffer) {
+            Ok(size) => {
+                // The proxy closes in order, the stream stays open for sending until the source
+                // closes as well
+                if size == 0 {
+                    trace!("receive EOF from SOCKS: {}: {} -> {}", "TCP", dst, 0);
+                    let tx = &mut self.tx;
+                    let _ = self.lifecycle.forward(|| {
+                        if let Err(ref e) = tx.forward_tcp_close(dst, src_port, false) {
+                            warn!("handle {}: {}", "TCP", e);
+                        }
+                    });
+                    // Keep sending the queued data and the data of the source until the stream is
+                    // shut down after it
+                    self.is_eof = true;
+                    if self.is_sent() {
+                        return Wait::Finished;
+                    }
+                    return Wait::Paused;
+                }
+                debug!(
+                    "receive from SOCKS: {}: {} -> {} ({} Bytes)",
+                    "TCP", dst, 0, size
+                );
+
+                // Send, the source may be released once the worker is closed
+                let (tx, counters) = (&mut self.tx, &self.counters);
+                let is_forwarded = self.lifecycle.forward(|| {
+                    counters.add_down(size);
+                    if let Err(ref e) = tx.forward_tcp(dst, src_port, &buffer[..size]) {
+                        warn!("handle {}: {}", "TCP", e);
+                    }
+                });
+                if !is_forwarded {
+                    return Wait::Finished;
+                }
+
+                // Pause reading until the source drains the backlog, the proxy side socket
+                // buffers the data then
+                let (backlog, window) = self.tx.get_tcp_backlog(dst, src_port);
+                let high_water = get_high_water(window, self.drain_rate);
+                if backlog > high_water {
+                    self.pauses.fetch_add(1, Ordering::Relaxed);
+                    trace!("pause stream {} -> {}: backlog {} Bytes", 0, dst, backlog);
+                    self.pause = Some(Pause {
+                        instant: Instant::now(),
+                        last_backlog: backlog,
+                        drained: 0,
+                    });
+
+                    return Wait::Paused;
+                }
+
+                Wait::Readable
+            }
+            Err(ref e)
+                if e.kind() == io::ErrorKind::WouldBlock
+                    || e.kind() == io::ErrorKind::TimedOut
+                    || e.kind() == io::ErrorKind::Interrupted =>
+            {
+                Wait::Readable
+            }
+            Err(ref e) => {
+                // Closed by the owner, which shuts down the stream
+                let tx = &mut self.tx;
+                let _ = self.lifecycle.close_and_forward(CloseReason::Error, || {
+                    warn!("SOCKS: {}: {} -> {}: {}", "TCP", 0, dst, e);
+                    if let Err(ref e) = tx.forward_tcp_close(dst, src_port, true) {
+                        warn!("handle {}: {}", "TCP", e);
+                    }
+                });
+
+                Wait::Finished
+            }
+        }
+    }
+
+    fn tick(&mut self, wait: Wait) -> Wait {
+        if let Some(deadline) = self.queue.lock().unwrap().deadline {
+            // Drop the data the proxy does not take in time
+            if Instant::now() >= deadline {
+                trace!("stream {} -> {} does not flush in time", 0, self.dst);
+                return Wait::Finished;
+            }
+            return Wait::Paused;
+        }
+        if self.is_eof {
+            if self.lifecycle.is_closed() || self.is_sent() {
+                return Wait::Finished;
+            }
+            return Wait::Paused;
+        }
+        if wait != Wait::Paused {
+            return wait;
+        }
+        if self.lifecycle.is_closed() {
+            return Wait::Finished;
+        }
+        let (backlog, window) = self.tx.get_tcp_backlog(self.dst, self.src_port);
+        let pause = match self.pause {
+            Some(ref mut pause) => pause,
+            None => return Wait::Readable,
+        };
+        pause.drained += pause.last_backlog.saturating_sub(backlog);
+        pause.last_backlog = backlog;
+        let elapsed = pause.instant.elapsed().as_millis();
+        if let Some(rate) = (pause.drained as u128 * 1000).checked_div(elapsed) {
+            self.drain_rate = rate as usize;
+        }
+        // Low-water mark
+        if backlog > get_high_water(window, self.drain_rate) / 2 {
+            return Wait::Paused;
+        }
+        trace!("resume stream {} -> {} after {} ms", 0, self.dst, elapsed);
+        self.pause = None;
+
+        Wait::Readable
+    }
+
+    fn is_writing(&self) -> bool {
+        !self.queue.lock().unwrap().buffer.is_empty()
+    }
+
+    fn writable(&mut self, wait: Wait) -> Wait {
+        let (dst, src_port) = (self.dst, self.src_port);
+        let (result, is_shutdown) = {
+            let mut queue_locked = self.queue.lock().unwrap();
+            let result = drain(&self.stream, &mut queue_locked, &self.counters);
+            (result, queue_locked.is_shutdown)
+        };
+        match result {
+            // A worker flushing or received EOF is finished once the stream is shut down after
+            // the queue
+            Ok(is_drained) => {
+                if is_drained && (self.lifecycle.is_closed() || (self.is_eof && is_shutdown)) {
+                    Wait::Finished
+                } else {
+                    wait
+                }
+            }
+            Err(ref e) => {
+                let tx = &mut self.tx;
+                let _ = self.lifecycle.close_and_forward(CloseReason::Error, || {
+                    warn!("SOCKS: {}: {} -> {}: {}", "TCP", 0, dst, e);
+                    if let Err(ref e) = tx.forward_tcp_close(dst, src_port, true) {
+                        warn!("handle {}: {}", "TCP", e);
+                    }
+                });
+
+                Wait::Finished
+            }
+        }
+    }
+}
+
+/// Sets the linger of the stream to zero, so closing the stream sends a RST.
+#[cfg(unix)]
+pub(crate) fn set_linger_zero(stream: &TcpStream) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let linger = libc::linger {
+        l_onoff: 1,
+        l_linger: 0,
+    };
+    let result = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_LINGER,
+            &linger as *const libc::linger as *const libc::c_void,
+            mem::size_of::<libc::linger>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Sets the linger of the stream to zero, so closing the stream sends a RST.
+#[cfg(windows)]
+pub(crate) fn set_linger_zero(stream: &TcpStream) -> io::Result<()> {
+    use std::os::raw::{c_char, c_int};
+    use std::os::windows::io::AsRawSocket;
+    use winapi::um::winsock2::{self, linger, SOCKET};
+
+    let linger = linger {
+        l_onoff: 1,
+        l_linger: 0,
+    };
+    let result = unsafe {
+        winsock2::setsockopt(
+            stream.as_raw_socket() as SOCKET,
+            winsock2::SOL_SOCKET,
+            winsock2::SO_LINGER,
+            &linger as *const linger as *const c_char,
+            mem::size_of::<linger>() as c_int,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+impl Transport for SocksDatagram {
+    fn send_to(&self, buffer: &[u8], dst: SocketAddrV4) -> io::Result<usize> {
+        SocksDatagram::send_to(self, buffer, dst.into())
+    }
+
+    fn recv_from(&self, buffer: &mut [u8], timeout: Duration) -> io::Result<(usize, SocketAddrV4)> {
+        self.set_read_timeout(Some(timeout))?;
+        match SocksDatagram::recv_from(self, buffer)? {
+            (size, SocketAddr::V4(addr)) => Ok((size, addr)),
+            (_, SocketAddr::V6(addr)) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected STUN response from IPv6 {}", addr),
+            )),
+        }
+    }
+}
+
+/// Tests the NAT between the STUN server and a UDP association of the proxy, or a local socket if
+/// no proxy is given.
+pub fn test_nat(
+    remote: Option<SocketAddr>,
+    credentials: &Credentials,
+    server: SocketAddrV4,
+) -> io::Result<NatReport> {
+    let local = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0);
+    let datagram = match remote {
+        Some(remote) => SocksDatagram::bind(local, remote, credentials.get_auth())?,
+        None => SocksDatagram::bind_direct(local)?,
+    };
+
+    stun::test(&datagram, server)
+}
+
+/// Probes the path MTU to the proxy. A probe of the given size is sent with DF set in a throwaway
+/// UDP socket to the proxy, and the path MTU known by the system is returned. ICMP messages
+/// caused by the probe update the path MTU in the system, so a smaller path is learned by the
+/// next probe.
+#[cfg(target_os = "linux")]
+pub fn probe_path_mtu(remote: SocketAddr, size: u16) -> io::Result<u16> {
+    use libc::{c_int, c_void, socklen_t};
+    use std::net::UdpSocket;
+    use std::os::unix::io::AsRawFd;
+
+    /// Represents `IP_MTU`, which is missing in `libc`.
+    const IP_MTU: c_int = 14;
+
+    let (local, level, discover, mtu, header_size) = match remote {
+        SocketAddr::V4(_) => (
+            SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            IP_MTU,
+            28,
+        ),
+        SocketAddr::V6(_) => (
+            SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_MTU,
+            48,
+        ),
+    };
+    // Both `IP_PMTUDISC_DO` and `IPV6_PMTUDISC_DO`
+    let value: c_int = libc::IP_PMTUDISC_DO;
+
+    let socket = UdpSocket::bind(local)?;
+    socket.connect(remote)?;
+    let fd = socket.as_raw_fd();
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            discover,
+            &value as *const c_int as *const c_void,
+            mem::size_of::<c_int>() as socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // A probe larger than the known path MTU fails with `EMSGSIZE`, which is expected
+    let payload = vec![0u8; (size as usize).saturating_sub(header_size)];
+    let _ = socket.send(&payload);
+
+    let mut value: c_int = 0;
+    let mut len = mem::size_of::<c_int>() as socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            level,
+            mtu,
+            &mut value as *mut c_int as *mut c_void,
+            &mut len,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(value as u16)
+}
+
+/// Probes the path MTU to the proxy. A probe of the given size is sent with DF set in a throwaway
+/// UDP socket to the proxy, and the path MTU known by the system is returned, which needs Windows
+/// 10 1703 or later.
+#[cfg(windows)]
+pub fn probe_path_mtu(remote: SocketAddr, size: u16) -> io::Result<u16> {
+    use std::net::UdpSocket;
+    use std::os::raw::{c_char, c_int};
+    use std::os::windows::io::AsRawSocket;
+    use winapi::um::winsock2::{self, SOCKET};
+
+    /// Represents `IPPROTO_IP` and `IPPROTO_IPV6`.
+    const IPPROTO_IP: c_int = 0;
+    const IPPROTO_IPV6: c_int = 41;
+    /// Represents `IP_DONTFRAGMENT` and `IPV6_DONTFRAG`.
+    const DONTFRAG: c_int = 14;
+    /// Represents `IP_MTU` and `IPV6_MTU`, which are missing in `winapi`.
+    const IP_MTU: c_int = 73;
+    const IPV6_MTU: c_int = 72;
+
+    let (local, level, mtu, header_size) = match remote {
+        SocketAddr::V4(_) => (
+            SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+            IPPROTO_IP,
+            IP_MTU,
+            28,
+        ),
+        SocketAddr::V6(_) => (
+            SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+            IPPROTO_IPV6,
+            IPV6_MTU,
+            48,
+        ),
+    };
+    let value: c_int = 1;
+
+    let socket = UdpSocket::bind(local)?;
+    socket.connect(remote)?;
+    let s = socket.as_raw_socket() as SOCKET;
+    let result = unsafe {
+        winsock2::setsockopt(
+            s,
+            level,
+            DONTFRAG,
+            &value as *const c_int as *const c_char,
+            mem::size_of::<c_int>() as c_int,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // A probe larger than the known path MTU fails with `WSAEMSGSIZE`, which is expected
+    let payload = vec![0u8; (size as usize).saturating_sub(header_size)];
+    let _ = socket.send(&payload);
+
+    let mut value: c_int = 0;
+    let mut len = mem::size_of::<c_int>() as c_int;
+    let result = unsafe {
+        winsock2::getsockopt(
+            s,
+            level,
+            mtu,
+            &mut value as *mut c_int as *mut c_char,
+            &mut len,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(value as u16)
+}
+
+/// Probes the path MTU to the proxy, which is not supported in the platform.
+#[cfg(not(any(target_os = "linux", windows)))]
+pub fn probe_path_mtu(_: SocketAddr, _: u16) -> io::Result<u16> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "path MTU probing is only supported on Linux and Windows",
+    ))
+}
+
+/// Returns if probing the path MTU is supported in the platform.
+pub fn is_path_mtu_probe_supported() -> bool {
+    cfg!(any(target_os = "linux", windows))
+}
+
+/// Get the backlog of a stream above which reads from the proxy pause, according to the window
+/// advertised by the source and the measured drain rate.
+fn get_high_water(window: u32, drain_rate: usize) -> usize {
+    let by_rate = (drain_rate as u128 * DRAIN_TIME / 1000) as usize;
+
+    max(max(2 * window as usize, by_rate), MIN_HIGH_WATER)
+}
+
+/// Represents the interval between 2 checks of a `DatagramWorker` being idle.
+const DATAGRAM_IDLE_CHECK: u64 = 500;
+
+/// Represents the max number of destinations remembered by a `DatagramWorker` for normalizing
+/// remote ports.
+const ENDPOINTS_COUNT: usize = 256;
+
+/// Represents the destinations sent by a `DatagramWorker`. A destination records the only port
+/// sent to the IP address, or `None` if multiple ports are used.
+type Endpoints = LruCache<IpAddr, Option<u16>>;
+
+fn is_idle(counters: &Counters, idle_timeout: &sync::Mutex<Option<Duration>>) -> bool {
+    match *idle_timeout.lock().unwrap() {
+        Some(timeout) => counters.get_last_active().elapsed() >= timeout,
+        None => false,
+    }
+}
+
+/// Get the time left until the worker of the counters is idle for the timeout.
+fn get_remaining(counters: &Counters, timeout: Duration) -> Duration {
+    timeout
+        .checked_sub(counters.get_last_active().elapsed())
+        .unwrap_or_default()
+}
+
+/// Represents the max number of remote clients tracked by a `DatagramWorker` of a server.
+const CLIENTS_COUNT: usize = 1024;
+
+/// Represents the traffic exchanged with a remote client of a server behind a `DatagramWorker`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ClientStats {
+    pub datagrams_up: usize,
+    pub bytes_up: usize,
+    pub datagrams_down: usize,
+    pub bytes_down: usize,
+}
+
+/// Represents the remote clients of a `DatagramWorker`.
+type Clients = LruCache<SocketAddr, ClientStats>;
+
+/// Represents a worker of a SOCKS5 UDP client.
+pub struct DatagramWorker {
+    src_port: Arc<sync::AtomicU16>,
+    local_port: u16,
+    datagram: Option<Arc<SocksDatagram>>,
+    lifecycle: Arc<Lifecycle>,
+    poller: &'static Poller,
+    token: Option<usize>,
+    _slot: Slot,
+    is_normalize: Arc<sync::AtomicBool>,
+    endpoints: Arc<sync::Mutex<Endpoints>>,
+    normalized: Arc<sync::AtomicUsize>,
+    counters: Arc<Counters>,
+    _registration: Option<Registration>,
+    recorder: Arc<sync::Mutex<Option<Recorder>>>,
+    clients: Arc<sync::Mutex<Option<Clients>>>,
+    idle_timeout: Arc<sync::Mutex<Option<Duration>>>,
+}
+
+impl DatagramWorker {
+    /// Creates a new `DatagramWorker`. Proxies which cannot relay UDP fail with an error, see
+    /// `is_unsupported`.
+    pub fn bind(
+        tx: Arc<Mutex<dyn Forward>>,
+        threads: &Arc<Threads>,
+        src_port: u16,
+        local_port: u16,
+        connector: &ProxyConnector,
+    ) -> io::Result<DatagramWorker> {
+        connector.check_udp()?;
+        let remote = connector.get_remote();
+        // Bind in the family of the proxy, an IPv6 socket is dual-stack and also accepts an IPv4
+        // relay returned by the proxy
+        let local = match remote {
+            SocketAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), local_port),
+            SocketAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), local_port),
+        };
+        let datagram = SocksDatagram::bind(local, remote, connector.get_auth())?;
+
+        DatagramWorker::open(tx, threads, src_port, local_port, datagram)
+    }
+
+    /// Creates a new `DatagramWorker` sends to destinations directly without a proxy.
+    pub fn bind_direct(
+        tx: Arc<Mutex<dyn Forward>>,
+        threads: &Arc<Threads>,
+        src_port: u16,
+        local_port: u16,
+    ) -> io::Result<DatagramWorker> {
+        let datagram =
+            SocksDatagram::bind_direct(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), local_port))?;
+
+        DatagramWorker::open(tx, threads, src_port, local_port, datagram)
+    }
+
+    fn open(
+        tx: Arc<Mutex<dyn Forward>>,
+        threads: &Arc<Threads>,
+        src_port: u16,
+        local_port: u16,
+        datagram: SocksDatagram,
+    ) -> io::Result<DatagramWorker> {
+        let poller = Poller::get(threads)?;
+        let slot = Threads::reserve(
+            threads,
+            format!("datagram {} = {}", src_port, local_port),
+            Purpose::Datagram,
+        )?;
+        // A read woken up spuriously in the poller, like by a datagram with a bad checksum, would
+        // block otherwise
+        datagram.set_nonblocking(true)?;
+
+        let a_src_port = Arc::new(sync::AtomicU16::new(src_port));
+        let a_datagram = Arc::new(datagram);
+        let a_lifecycle = Arc::new(Lifecycle::new());
+        let a_is_normalize = Arc::new(sync::AtomicBool::new(true));
+        let a_endpoints = Arc::new(sync::Mutex::new(LruCache::new(ENDPOINTS_COUNT)));
+        let a_normalized = Arc::new(sync::AtomicUsize::new(0));
+        let a_counters = Arc::new(Counters::new());
+        let a_recorder = Arc::new(sync::Mutex::new(None));
+        let a_clients: Arc<sync::Mutex<Option<Clients>>> = Arc::new(sync::Mutex::new(None));
+        let a_idle_timeout = Arc::new(sync::Mutex::new(None));
+        let receiver = DatagramReceiver {
+            datagram: Arc::clone(&a_datagram),
+            local_port,
+            src_port: Arc::clone(&a_src_port),
+            tx: TeeForward::new(
+                tx,
+                RecordForward {
+                    recorder: Arc::clone(&a_recorder),
+                },
+            ),
+            lifecycle: Arc::clone(&a_lifecycle),
+            is_normalize: Arc::clone(&a_is_normalize),
+            endpoints: Arc::clone(&a_endpoints),
+            normalized: Arc::clone(&a_normalized),
+            counters: Arc::clone(&a_counters),
+            clients: Arc::clone(&a_clients),
+            idle_timeout: Arc::clone(&a_idle_timeout),
+            last_idle_check: Instant::now(),
+        };
+        let token = poller.register(poller::get_raw(a_datagram.get_socket()), Box::new(receiver));
+
+        trace!("create datagram {} = {}", src_port, local_port);
+
+        Ok(DatagramWorker {
+            src_port: a_src_port,
+            local_port,
+            datagram: Some(a_datagram),
+            lifecycle: a_lifecycle,
+            poller,
+            token: Some(token),
+            _slot: slot,
+            is_normalize: a_is_normalize,
+            endpoints: a_endpoints,
+            normalized: a_normalized,
+            counters: a_counters,
+            _registration: None,
+            recorder: a_recorder,
+            clients: a_clients,
+            idle_timeout: a_idle_timeout,
+        })
+    }
+
+    /// Sends data on the SOCKS5 in UDP to the destination.
+    pub fn send_to(&mut self, buffer: &[u8], dst: SocketAddr) -> io::Result<usize> {
+        debug!(
+            "send to SOCKS {}: {} -> {} ({} Bytes)",
+            "UDP",
+            self.local_port,
+            dst,
+            buffer.len()
+        );
+
+        // Record destination
+        {
+            let mut endpoints_locked = self.endpoints.lock().unwrap();
+            let endpoint = endpoints_locked.get(&dst.ip()).cloned();
+            match endpoint {
+                Some(Some(port)) => {
+                    if port != dst.port() {
+                        endpoints_locked.put(dst.ip(), None);
+                    }
+                }
+                Some(None) => {}
+                None => {
+                    endpoints_locked.put(dst.ip(), Some(dst.port()));
+                }
+            }
+        }
+
+        // Send
+        let datagram = match self.datagram {
+            Some(ref datagram) => datagram,
+            None => return Err(io::Error::from(io::ErrorKind::NotConnected)),
+        };
+        let size = datagram.send_to(buffer, dst)?;
+        self.counters.add_up(size);
+        self.counters.add_sent();
+        record(&self.recorder, Direction::Up, dst, &buffer[..size]);
+        if let Some(ref mut clients) = *self.clients.lock().unwrap() {
+            let mut stats = clients.get(&dst).cloned().unwrap_or_default();
+            stats.datagrams_up += 1;
+            stats.bytes_up += size;
+            clients.put(dst, stats);
+        }
+
+        Ok(size)
+    }
+
+    /// Sets the recorder of the datagrams exchanged with the proxy.
+    pub fn set_recorder(&mut self, recorder: Recorder) {
+        *self.recorder.lock().unwrap() = Some(recorder);
+    }
+
+    /// Tracks the traffic of each remote client, for a worker serving clients which send first.
+    /// The proxy does not know the address of the association before a datagram is sent, as the
+    /// association is requested from the unspecified address, so one is sent at once.
+    pub fn set_track_clients(&mut self) -> io::Result<()> {
+        *self.clients.lock().unwrap() = Some(LruCache::new(CLIENTS_COUNT));
+        match self.datagram {
+            Some(ref datagram) => datagram.prime(),
+            None => Ok(()),
+        }
+    }
+
+    /// Get the traffic of the remote clients tracked, the busiest first.
+    pub fn get_clients(&self) -> Vec<(SocketAddr, ClientStats)> {
+        let mut clients: Vec<(SocketAddr, ClientStats)> = match *self.clients.lock().unwrap() {
+            Some(ref clients) => clients
+                .iter()
+                .map(|(addr, stats)| (*addr, *stats))
+                .collect(),
+            None => Vec::new(),
+        };
+        clients.sort_by_key(|client| Reverse(client.1.bytes_up + client.1.bytes_down));
+
+        clients
+    }
+
+    /// Get the number of bytes sent and received by the worker.
+    pub fn get_bytes(&self) -> (usize, usize) {
+        self.counters.get_bytes()
+    }
+
+    /// Get the traffic of the worker exchanged with the proxy.
+    pub fn get_stats(&self) -> WorkerStats {
+        self.counters.get_stats()
+    }
+
+    /// Registers the worker in the monitor, which shows its source port only as it sends to many
+    /// destinations. The worker is removed from the monitor once dropped.
+    pub fn set_monitor(&mut self, monitor: &Arc<Monitor>) {
+        self._registration = Some(Monitor::register(
+            monitor,
+            Protocol::Udp,
+            self.src_port.load(Ordering::Relaxed),
+            None,
+            Arc::clone(&self.counters),
+        ));
+    }
+
+    /// Sets if the `DatagramWorker` rewrites the source of datagrams from the proxy back to the
+    /// destination sent to when only the port differs.
+    pub fn set_normalize_remote_port(&mut self, is_normalize: bool) {
+        self.is_normalize.store(is_normalize, Ordering::Relaxed);
+    }
+
+    /// Sets the time without datagrams in either direction after which the worker is idle, or
+    /// `None` if the worker is never idle.
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        *self.idle_timeout.lock().unwrap() = timeout;
+    }
+
+    /// Get the time left until the worker is idle, which is zero once it sent and received no
+    /// datagrams for longer than its idle timeout, or `None` if it has no idle timeout. An idle
+    /// worker closes itself soon after.
+    pub fn get_idle_remaining(&self) -> Option<Duration> {
+        let timeout = (*self.idle_timeout.lock().unwrap())?;
+
+        Some(get_remaining(&self.counters, timeout))
+    }
+
+    /// Get the number of datagrams whose remote port is normalized.
+    pub fn get_normalized(&self) -> usize {
+        self.normalized.load(Ordering::Relaxed)
+    }
+
+    /// Sets the source port of the `DatagramWorker`.
+    pub fn set_src_port(&mut self, src_port: u16) {
+        self.src_port.store(src_port, Ordering::Relaxed);
+        trace!("set datagram {} = {}", src_port, self.local_port);
+    }
+
+    /// Get the source port of the `DatagramWorker`.
+    pub fn get_src_port(&self) -> u16 {
+        self.src_port.load(Ordering::Relaxed)
+    }
+
+    /// Get the time of the first round trip of datagrams through the proxy, from the first
+    /// datagram sent to the first one received after it, or `None` if it is not completed.
+    pub fn get_early_rtt(&self) -> Option<Duration> {
+        self.counters.get_early_rtt()
+    }
+
+    /// Closes the worker for the reason, removes the UDP socket from the poller, and releases it
+    /// and the association with the proxy, including its control connection, at once. Only the
+    /// reason of the first close is kept, and a worker which closed itself is released by a close
+    /// as well.
+    pub fn close(&mut self, reason: CloseReason) {
+        if self.lifecycle.close(reason) {
+            trace!(
+                "close datagram {} = {}: {}",
+                self.get_src_port(),
+                self.local_port,
+                reason
+            );
+        }
+        // The poller drops its side of the socket once removed
+        if let Some(token) = self.token.take() {
+            self.poller.deregister(token);
+        }
+        self.datagram.take();
+    }
+
+    /// Returns if the worker is closed.
+    pub fn is_closed(&self) -> bool {
+        self.lifecycle.is_closed()
+    }
+
+    /// Get the reason of the first close of the worker.
+    pub fn get_close_reason(&self) -> Option<CloseReason> {
+        self.lifecycle.get_close_reason()
+    }
+}
+
+impl Drop for DatagramWorker {
+    fn drop(&mut self) {
+        self.close(CloseReason::Dropped);
+        trace!(
+            "drop datagram {} = {}",
+            self.get_src_port(),
+            self.local_port
+        );
+    }
+}
+
+/// Represents the receiving side of a `DatagramWorker` in the poller.
+struct DatagramReceiver {
+    datagram: Arc<SocksDatagram>,
+    local_port: u16,
+    src_port: Arc<sync::AtomicU16>,
+    tx: RecordedForward,
+    lifecycle: Arc<Lifecycle>,
+    is_normalize: Arc<sync::AtomicBool>,
+    endpoints: Arc<sync::Mutex<Endpoints>>,
+    normalized: Arc<sync::AtomicUsize>,
+    counters: Arc<Counters>,
+    clients: Arc<sync::Mutex<Option<Clients>>>,
+    idle_timeout: Arc<sync::Mutex<Option<Duration>>>,
+    last_idle_check: Instant,
+}
+
+impl Handler for DatagramReceiver {
+    fn readable(&mut self, buffer: &mut [u8]) -> Wait {
+        if self.lifecycle.is_closed() {
+            return Wait::Finished;
+        }
+        let local_port = self.local_port;
+        match self.datagram.recv_from(buffer) {
+            Ok((size, addr)) => {
+                debug!(
+                    "receive from SOCKS: {}: {} -> {} ({} Bytes)",
+                    "UDP", addr, local_port, size
+                );
+                self.counters.add_down(size);
+                // Clients may send before the source sends to them
+                if let Some(ref mut clients) = *self.clients.lock().unwrap() {
+                    let mut stats = clients.get(&addr).cloned().unwrap_or_default();
+                    stats.datagrams_down += 1;
+                    stats.bytes_down += size;
+                    clients.put(addr, stats);
+                }
+
+                // Normalize remote port
+                let mut addr = addr;
+                if self.is_normalize.load(Ordering::Relaxed) {
+                    let endpoint = self.endpoints.lock().unwrap().get(&addr.ip()).cloned();
+                    if let Some(Some(port)) = endpoint {
+                        if port != addr.port() {
+                            trace!(
+                                "normalize datagram {} remote {} to port {}",
+                                local_port,
+                                addr,
+                                port
+                            );
+                            addr = SocketAddr::new(addr.ip(), port);
+                            self.normalized.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                }
+
+                // Send, the source may be released once the worker is closed
+                let (tx, src_port) = (&mut self.tx, &self.src_port);
+                let is_forwarded = self.lifecycle.forward(|| {
+                    if let Err(ref e) =
+                        tx.forward_udp(addr, src_port.load(Ordering::Relaxed), &buffer[..size])
+                    {
+                        warn!("handle {}: {}", "UDP", e);
+                    }
+                });
+                if !is_forwarded {
+                    return Wait::Finished;
+                }
+
+                Wait::Readable
+            }
+            Err(ref e)
+                if e.kind() == io::ErrorKind::WouldBlock
+                    || e.kind() == io::ErrorKind::Interrupted =>
+            {
+                Wait::Readable
+            }
+            Err(ref e) => {
+                if self.lifecycle.close(CloseReason::Error) {
+                    warn!(
+                        "SOCKS: {}: {} = {}: {}",
+                        "UDP",
+                        local_port,
+                        self.src_port.load(Ordering::Relaxed),
+                        e
+                    );
+                }
+
+                Wait::Finished
+            }
+        }
+    }
+
+    fn tick(&mut self, wait: Wait) -> Wait {
+        if self.last_idle_check.elapsed() < Duration::from_millis(DATAGRAM_IDLE_CHECK) {
+            return wait;
+        }
+        self.last_idle_check = Instant::now();
+        // Finish by itself once idle, so the owner releases it without waiting
+        if is_idle(&self.counters, &self.idle_timeout) && self.lifecycle.close(CloseReason::Idle) {
+            trace!(
+                "datagram {} = {} is idle",
+                self.src_port.load(Ordering::Relaxed),
+                self.local_port
+            );
+            return Wait::Finished;
+        }
+
+        wait
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::Random;
+    use std::env;
+    use std::process;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::mpsc;
+    use std::thread;
+
+    /// Represents a `Forward` recording the results of connecting.
+    #[derive(Default)]
+    struct ConnectForward {
+        connects: Vec<(u16, bool)>,
+        closes: Vec<bool>,
+    }
+
+    impl Forward for ConnectForward {
+        fn forward_tcp(&mut self, _: SocketAddr, _: u16, _: &[u8]) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn forward_udp(&mut self, _: SocketAddr, _: u16, _: &[u8]) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn forward_tcp_connect(
+            &mut self,
+            _: SocketAddr,
+            src_port: u16,
+            is_connected: bool,
+        ) -> io::Result<()> {
+            self.connects.push((src_port, is_connected));
+
+            Ok(())
+        }
+
+        fn forward_tcp_close(&mut self, _: SocketAddr, _: u16, is_reset: bool) -> io::Result<()> {
+            self.closes.push(is_reset);
+
+            Ok(())
+        }
+    }
+
+    /// Represents a `Forward` counting the payload forwarded.
+    #[derive(Default)]
+    struct CountForward {
+        tcp: usize,
+        udp: usize,
+        closes: usize,
+    }
+
+    impl CountForward {
+        fn get_counts(&self) -> (usize, usize, usize) {
+            (self.tcp, self.udp, self.closes)
+        }
+    }
+
+    impl Forward for CountForward {
+        fn forward_tcp(&mut self, _: SocketAddr, _: u16, payload: &[u8]) -> io::Result<()> {
+            self.tcp += payload.len();
+
+            Ok(())
+        }
+
+        fn forward_udp(&mut self, _: SocketAddr, _: u16, _: &[u8]) -> io::Result<()> {
+            self.udp += 1;
+
+            Ok(())
+        }
+
+        fn forward_tcp_connect(&mut self, _: SocketAddr, _: u16, _: bool) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn forward_tcp_close(&mut self, _: SocketAddr, _: u16, _: bool) -> io::Result<()> {
+            self.closes += 1;
+
+            Ok(())
+        }
+    }
+
+    /// Represents a `Forward` which takes a while in each forward, and records forwards after the
+    /// worker is released by its owner.
+    struct SlowForward {
+        entered: mpsc::Sender<()>,
+        is_released: Arc<AtomicBool>,
+        is_late: Arc<AtomicBool>,
+    }
+
+    impl SlowForward {
+        fn forward(&self) {
+            if self.is_released.load(Ordering::SeqCst) {
+                self.is_late.store(true, Ordering::SeqCst);
+            }
+            let _ = self.entered.send(());
+            thread::sleep(Duration::from_millis(50));
+            if self.is_released.load(Ordering::SeqCst) {
+                self.is_late.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+
+    impl Forward for SlowForward {
+        fn forward_tcp(&mut self, _: SocketAddr, _: u16, _: &[u8]) -> io::Result<()> {
+            self.forward();
+
+            Ok(())
+        }
+
+        fn forward_udp(&mut self, _: SocketAddr, _: u16, _: &[u8]) -> io::Result<()> {
+            self.forward();
+
+            Ok(())
+        }
+
+        fn forward_tcp_connect(&mut self, _: SocketAddr, _: u16, _: bool) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn forward_tcp_close(&mut self, _: SocketAddr, _: u16, _: bool) -> io::Result<()> {
+            self.forward();
+
+            Ok(())
+        }
+    }
+
+    /// Represents the rounds of the stress tests.
+    const ROUNDS: usize = 20;
+
+    /// Waits until the condition holds, for at most 5 seconds.
+    fn wait_for<F: FnMut() -> bool>(mut f: F) {
+        let instant = Instant::now();
+        while !f() {
+            assert!(instant.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Get the number of places and handshake threads of streams in the registry, which excludes
+    /// the poller shared by all tests.
+    fn get_flow_threads(threads: &Threads) -> usize {
+        threads
+            .dump()
+            .iter()
+            .filter(|info| matches!(info.purpose, Purpose::Stream | Purpose::Handshake))
+            .count()
+    }
+
+    fn basic(username: &str, password: &str) -> String {
+        let auth = Auth::new(String::from(username), Secret::new(String::from(password)));
+
+        String::from(auth.get_basic().as_str())
+    }
+
+    #[test]
+    fn test_credentials_rotation() {
+        let path = env::temp_dir().join(format!("pcap2socks-password-{}", process::id()));
+        fs::write(&path, "first\n").unwrap();
+        let mut credentials = Credentials::from_file(String::from("user"), path.clone()).unwrap();
+        let before = credentials.get_auth().cloned().unwrap();
+        assert_eq!(before.get_basic().as_str(), basic("user", "first"));
+
+        // The file is checked at most once in a second
+        fs::write(&path, "second\r\nignored\n").unwrap();
+        assert!(!credentials.reload().unwrap());
+        credentials.last_check = Some(Instant::now() - Duration::from_secs(RELOAD_INTERVAL));
+        credentials.modified = None;
+        assert!(credentials.reload().unwrap());
+
+        // Only new connections use the new password
+        let after = credentials.get_auth().unwrap();
+        assert_eq!(after.get_basic().as_str(), basic("user", "second"));
+        assert_eq!(before.get_basic().as_str(), basic("user", "first"));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_handshake_failure_after_return() {
+        let forward = Arc::new(Mutex::new(ConnectForward::default()));
+        let tx: Arc<Mutex<dyn Forward>> = forward.clone();
+        let threads = Arc::new(Threads::new(16));
+        let dst = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 80));
+        let (go_tx, go_rx) = mpsc::channel::<()>();
+
+        let mut stream = StreamWorker::open(tx, &threads, 50000, dst, &PORT_BACKOFF, move || {
+            let _ = go_rx.recv();
+            Err(io::Error::from(io::ErrorKind::ConnectionRefused))
+        })
+        .unwrap();
+        // The caller holds the worker in connecting, whose data is queued
+        assert!(!stream.poll_ready().unwrap());
+        stream.send(b"early", true).unwrap();
+        assert_eq!(get_flow_threads(&threads), 2);
+
+        go_tx.send(()).unwrap();
+        let instant = Instant::now();
+        let e = loop {
+            match stream.poll_ready() {
+                Ok(false) => {
+                    assert!(instant.elapsed() < Duration::from_secs(5));
+                    thread::sleep(Duration::from_millis(1));
+                }
+                Ok(true) => panic!("connected"),
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(
+            stream.lifecycle.get_close_reason(),
+            Some(CloseReason::Error)
+        );
+        assert_eq!(forward.lock().unwrap().connects, vec![(50000, false)]);
+        // The failure is reported once
+        assert_eq!(
+            stream.poll_ready().unwrap_err().kind(),
+            io::ErrorKind::NotConnected
+        );
+
+        // Both the place of the flow and the handshake thread are gone with the worker
+        drop(stream);
+        let instant = Instant::now();
+        while get_flow_threads(&threads) > 0 {
+            assert!(instant.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(forward.lock().unwrap().connects.len(), 1);
+    }
+
+    #[test]
+    fn test_connect_ready() {
+        use crate::testing::MockProxy;
+        use std::net::TcpListener;
+
+        let proxy = MockProxy::spawn(Ipv4Addr::LOCALHOST.into()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dst = listener.local_addr().unwrap();
+        let threads = Arc::new(Threads::new(16));
+        let forward = Arc::new(Mutex::new(ConnectForward::default()));
+        let tx: Arc<Mutex<dyn Forward>> = forward.clone();
+        let connector = ProxyConnector::new(ProxyType::Socks5, proxy.get_addr(), None);
+
+        // The worker is connected once the handshake ends, and data is sent at once
+        let mut stream = StreamWorker::connect(tx, &threads, 50016, dst, connector, None).unwrap();
+        assert!(wait_ready(&mut stream).unwrap());
+        assert!(stream.get_latency().is_some());
+        assert_eq!(forward.lock().unwrap().connects, vec![(50016, true)]);
+        stream.send(b"hello", true).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        let mut buffer = [0u8; 5];
+        server.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"hello");
+    }
+
+    #[test]
+    fn test_connect_failure() {
+        use std::net::TcpListener;
+
+        let threads = Arc::new(Threads::new(16));
+        let forward = Arc::new(Mutex::new(ConnectForward::default()));
+        let dst = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 80));
+
+        // A proxy refusing the connection fails the handshake
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap();
+        let remote = closed.local_addr().unwrap();
+        drop(closed);
+        let connector = ProxyConnector::new(ProxyType::Socks5, remote, None);
+        let tx: Arc<Mutex<dyn Forward>> = forward.clone();
+        let mut stream = StreamWorker::connect(tx, &threads, 50017, dst, connector, None).unwrap();
+        let e = wait_ready(&mut stream).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(forward.lock().unwrap().connects, vec![(50017, false)]);
+        drop(stream);
+
+        // A proxy never answering the greeting leaves the worker connecting, and the handshake
+        // fails once it closes
+        let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+        let connector = ProxyConnector::new(ProxyType::Socks5, silent.local_addr().unwrap(), None);
+        let tx: Arc<Mutex<dyn Forward>> = forward.clone();
+        let mut stream = StreamWorker::connect(tx, &threads, 50018, dst, connector, None).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert!(!stream.poll_ready().unwrap());
+        drop(silent);
+        wait_ready(&mut stream).unwrap_err();
+        drop(stream);
+        wait_for(|| get_flow_threads(&threads) == 0);
+        assert_eq!(forward.lock().unwrap().connects.len(), 2);
+    }
+
+    #[test]
+    fn test_send_after_eof() {
+        use std::io::Read;
+        use std::net::TcpListener;
+
+        let forward = Arc::new(Mutex::new(ConnectForward::default()));
+        let tx: Arc<Mutex<dyn Forward>> = forward.clone();
+        let threads = Arc::new(Threads::new(16));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let dst = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 80));
+
+        let mut stream = StreamWorker::open(tx, &threads, 50001, dst, &PORT_BACKOFF, move || {
+            TcpStream::connect(proxy)
+        })
+        .unwrap();
+        let (peer, _) = listener.accept().unwrap();
+        // The proxy closes in order first
+        peer.shutdown(Shutdown::Write).unwrap();
+        let instant = Instant::now();
+        while forward.lock().unwrap().closes.is_empty() {
+            assert!(instant.elapsed() < Duration::from_secs(5));
+            let _ = stream.poll_ready();
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(forward.lock().unwrap().closes, vec![false]);
+
+        // The source keeps sending until the socket buffers are full and data is queued, and
+        // closes in order after it, so only the poller sends the rest
+        let chunk = [0x5au8; 1 << 16];
+        let mut sent = 0;
+        while stream.get_queue_len() == 0 {
+            assert!(instant.elapsed() < Duration::from_secs(5));
+            stream.send(&chunk, true).unwrap();
+            sent += chunk.len();
+        }
+        stream.shutdown_write().unwrap();
+
+        let mut peer = peer;
+        peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut received = Vec::new();
+        peer.read_to_end(&mut received).unwrap();
+        assert_eq!(received.len(), sent);
+        assert!(!stream.is_closed());
+    }
+
+    #[test]
+    fn test_stream_close_racing_receive() {
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let threads = Arc::new(Threads::new(16));
+        let dst = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 80));
+        let mut random = Random::new(254);
+
+        for round in 0..ROUNDS {
+            let forward = Arc::new(Mutex::new(CountForward::default()));
+            let tx: Arc<Mutex<dyn Forward>> = forward.clone();
+            let mut stream =
+                StreamWorker::open(tx, &threads, 50002, dst, &PORT_BACKOFF, move || {
+                    TcpStream::connect(proxy)
+                })
+                .unwrap();
+            let (mut peer, _) = listener.accept().unwrap();
+            peer.set_write_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            // The proxy sends until the stream is gone
+            let sender = thread::spawn(move || {
+                let chunk = [0x5au8; 1 << 12];
+                while peer.write_all(&chunk).is_ok() {}
+            });
+            wait_for(|| {
+                let _ = stream.poll_ready();
+                forward.lock().unwrap().tcp > 0
+            });
+            thread::sleep(Duration::from_micros(random.next_below(2000) as u64));
+
+            let reason = if round % 2 == 0 {
+                CloseReason::Closed
+            } else {
+                CloseReason::Aborted
+            };
+            stream.close(reason);
+            // Nothing is forwarded once the close returns
+            let counts = forward.lock().unwrap().get_counts();
+            thread::sleep(Duration::from_millis(20));
+            assert_eq!(forward.lock().unwrap().get_counts(), counts);
+            assert_eq!(counts.2, 0);
+
+            // Closing again keeps the first reason
+            stream.close(CloseReason::Error);
+            assert_eq!(stream.get_close_reason(), Some(reason));
+            drop(stream);
+            assert_eq!(forward.lock().unwrap().get_counts(), counts);
+            sender.join().unwrap();
+        }
+        wait_for(|| get_flow_threads(&threads) == 0);
+    }
+
+    /// Waits until the handshake of the stream ends, and returns its result.
+    fn wait_ready(stream: &mut StreamWorker) -> io::Result<bool> {
+        let instant = Instant::now();
+        loop {
+            match stream.poll_ready() {
+                Ok(false) => {
+                    assert!(instant.elapsed() < Duration::from_secs(5));
+                    thread::sleep(Duration::from_millis(1));
+                }
+                result => return result,
+            }
+        }
+    }
+
+    #[test]
+    fn test_port_exhaustion() {
+        use std::net::TcpListener;
+
+        // A back off of its own, so flows of other tests never back off
+        static PORTS: PortBackoff = PortBackoff::new(200);
+
+        let threads = Arc::new(Threads::new(16));
+        let dst = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 80));
+        let exhausted = || Err(io::Error::from(io::ErrorKind::AddrNotAvailable));
+
+        // The transport runs out of local ports
+        let forward = Arc::new(Mutex::new(ConnectForward::default()));
+        let tx: Arc<Mutex<dyn Forward>> = forward.clone();
+        let mut stream = StreamWorker::open(tx, &threads, 50012, dst, &PORTS, exhausted).unwrap();
+        let e = wait_ready(&mut stream).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AddrNotAvailable);
+        assert!(PORTS.is_exhausted());
+        assert_eq!(PORTS.get_exhaustions(), 1);
+        // The source is not reset, so it retransmits its SYN after the back off
+        assert!(forward.lock().unwrap().connects.is_empty());
+        drop(stream);
+
+        // Other errors neither back off nor spare the source
+        let tx: Arc<Mutex<dyn Forward>> = forward.clone();
+        let refused = || Err(io::Error::from(io::ErrorKind::ConnectionRefused));
+        let mut stream = StreamWorker::open(tx, &threads, 50013, dst, &PORTS, refused).unwrap();
+        wait_ready(&mut stream).unwrap_err();
+        assert_eq!(PORTS.get_exhaustions(), 1);
+        assert_eq!(forward.lock().unwrap().connects, vec![(50013, false)]);
+        drop(stream);
+
+        // The back off ends within its base, and a second failure backs off again
+        let instant = Instant::now();
+        wait_for(|| !PORTS.is_exhausted());
+        assert!(instant.elapsed() < Duration::from_millis(300));
+        let tx: Arc<Mutex<dyn Forward>> = forward.clone();
+        let mut stream = StreamWorker::open(tx, &threads, 50014, dst, &PORTS, exhausted).unwrap();
+        wait_ready(&mut stream).unwrap_err();
+        assert!(PORTS.is_exhausted());
+        assert_eq!(PORTS.streak.load(Ordering::Relaxed), 2);
+        drop(stream);
+        wait_for(|| !PORTS.is_exhausted());
+
+        // Recovery, the streak ends with a connect succeeded
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let remote = listener.local_addr().unwrap();
+        let tx: Arc<Mutex<dyn Forward>> = forward.clone();
+        let mut stream = StreamWorker::open(tx, &threads, 50015, dst, &PORTS, move || {
+            TcpStream::connect(remote)
+        })
+        .unwrap();
+        assert!(wait_ready(&mut stream).unwrap());
+        assert_eq!(PORTS.streak.load(Ordering::Relaxed), 0);
+        assert_eq!(
+            forward.lock().unwrap().connects,
+            vec![(50013, false), (50015, true)]
+        );
+    }
+
+    #[test]
+    fn test_datagram_drop_closes_association() {
+        use crate::testing::MockProxy;
+
+        let proxy = MockProxy::spawn(Ipv4Addr::LOCALHOST.into()).unwrap();
+        let connector = ProxyConnector::new(ProxyType::Socks5, proxy.get_addr(), None);
+        let threads = Arc::new(Threads::new(16));
+        let forward = Arc::new(Mutex::new(CountForward::default()));
+
+        // Dropped
+        let tx: Arc<Mutex<dyn Forward>> = forward.clone();
+        let datagram = DatagramWorker::bind(tx, &threads, 50010, 0, &connector).unwrap();
+        wait_for(|| proxy.get_associations() == 1);
+        drop(datagram);
+        wait_for(|| proxy.get_associations() == 0);
+
+        // Closed, the worker kept by the owner
+        let tx: Arc<Mutex<dyn Forward>> = forward;
+        let mut datagram = DatagramWorker::bind(tx, &threads, 50011, 0, &connector).unwrap();
+        wait_for(|| proxy.get_associations() == 1);
+        datagram.close(CloseReason::Idle);
+        wait_for(|| proxy.get_associations() == 0);
+        assert_eq!(datagram.get_close_reason(), Some(CloseReason::Idle));
+        // The places of both are released once dropped
+        drop(datagram);
+        assert!(threads
+            .dump()
+            .iter()
+            .all(|info| info.purpose != Purpose::Datagram));
+    }
+
+    #[test]
+    fn test_datagram_close_racing_receive() {
+        use std::net::UdpSocket;
+
+        let threads = Arc::new(Threads::new(16));
+        let mut random = Random::new(254);
+
+        for round in 0..ROUNDS {
+            let forward = Arc::new(Mutex::new(CountForward::default()));
+            let tx: Arc<Mutex<dyn Forward>> = forward.clone();
+            let mut datagram = DatagramWorker::bind_direct(tx, &threads, 50003, 0).unwrap();
+            let port = datagram
+                .datagram
+                .as_ref()
+                .unwrap()
+                .get_socket()
+                .local_addr()
+                .unwrap()
+                .port();
+
+            let is_stopped = Arc::new(AtomicBool::new(false));
+            let is_stopped_cloned = Arc::clone(&is_stopped);
+            let sender = thread::spawn(move || {
+                let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+                while !is_stopped_cloned.load(Ordering::Relaxed) {
+                    let _ = socket.send_to(b"datagram", ("127.0.0.1", port));
+                }
+            });
+            wait_for(|| forward.lock().unwrap().udp > 0);
+            thread::sleep(Duration::from_micros(random.next_below(2000) as u64));
+
+            // The owner and the receiver may close at once, by a drop or a close
+            let counts = if round % 2 == 0 {
+                datagram.close(CloseReason::Closed);
+                let counts = forward.lock().unwrap().get_counts();
+                datagram.close(CloseReason::Error);
+                assert_eq!(datagram.get_close_reason(), Some(CloseReason::Closed));
+                drop(datagram);
+                counts
+            } else {
+                drop(datagram);
+                forward.lock().unwrap().get_counts()
+            };
+            thread::sleep(Duration::from_millis(20));
+            assert_eq!(forward.lock().unwrap().get_counts(), counts);
+
+            is_stopped.store(true, Ordering::Relaxed);
+            sender.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_drop_during_send() {
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let threads = Arc::new(Threads::new(16));
+        let dst = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 80));
+
+        for round in 0..ROUNDS / 4 {
+            let forward = Arc::new(Mutex::new(CountForward::default()));
+            let tx: Arc<Mutex<dyn Forward>> = forward.clone();
+            let mut stream =
+                StreamWorker::open(tx, &threads, 50004, dst, &PORT_BACKOFF, move || {
+                    TcpStream::connect(proxy)
+                })
+                .unwrap();
+            let (mut peer, _) = listener.accept().unwrap();
+            wait_for(|| stream.poll_ready().unwrap());
+
+            // The proxy does not read, so the poller is left sending the queued data
+            let chunk = [0x5au8; 1 << 16];
+            let mut sent = 0;
+            while stream.get_queue_len() == 0 {
+                assert!(sent < 1 << 28);
+                stream.send(&chunk, true).unwrap();
+                sent += chunk.len();
+            }
+
+            let reader = thread::spawn(move || {
+                // The proxy reads only after the drop, or never in the odd rounds
+                thread::sleep(Duration::from_millis(100));
+                if round % 2 == 1 {
+                    return 0;
+                }
+                peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+                let mut received = Vec::new();
+                let _ = peer.read_to_end(&mut received);
+                received.len()
+            });
+
+            // A drop neither hangs on the poller flushing the queue nor waits for the proxy
+            let instant = Instant::now();
+            drop(stream);
+            assert!(instant.elapsed() < Duration::from_millis(CLOSE_WAIT));
+            let received = reader.join().unwrap();
+            assert!(received <= sent);
+            wait_for(|| get_flow_threads(&threads) == 0);
+            assert_eq!(forward.lock().unwrap().get_counts(), (0, 0, 0));
+        }
+    }
+
+    #[test]
+    fn test_drop_during_dispatch() {
+        use std::net::{TcpListener, UdpSocket};
+
+        let threads = Arc::new(Threads::new(16));
+        let dst = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 80));
+        let (entered_tx, entered_rx) = mpsc::channel();
+        let is_released = Arc::new(AtomicBool::new(false));
+        let is_late = Arc::new(AtomicBool::new(false));
+        let forward = Arc::new(Mutex::new(SlowForward {
+            entered: entered_tx,
+            is_released: Arc::clone(&is_released),
+            is_late: Arc::clone(&is_late),
+        }));
+
+        // The stream is dropped while the poller forwards what it receives
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let tx: Arc<Mutex<dyn Forward>> = forward.clone();
+        let mut stream = StreamWorker::open(tx, &threads, 50007, dst, &PORT_BACKOFF, move || {
+            TcpStream::connect(proxy)
+        })
+        .unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        wait_for(|| stream.poll_ready().unwrap());
+        peer.write_all(b"data").unwrap();
+        entered_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        drop(stream);
+        is_released.store(true, Ordering::SeqCst);
+        // The receiver is dropped with the worker, so the source is freed
+        wait_for(|| Arc::strong_count(&forward) == 1);
+        let _ = peer.write_all(b"more");
+        drop(peer);
+        thread::sleep(Duration::from_millis(20));
+        assert!(!is_late.load(Ordering::SeqCst));
+
+        // The same for datagrams, whose receiver is dropped by the time the drop returns
+        is_released.store(false, Ordering::SeqCst);
+        let tx: Arc<Mutex<dyn Forward>> = forward.clone();
+        let datagram = DatagramWorker::bind_direct(tx, &threads, 50008, 0).unwrap();
+        let port = datagram
+            .datagram
+            .as_ref()
+            .unwrap()
+            .get_socket()
+            .local_addr()
+            .unwrap()
+            .port();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.send_to(b"datagram", ("127.0.0.1", port)).unwrap();
+        entered_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        drop(datagram);
+        is_released.store(true, Ordering::SeqCst);
+        assert_eq!(Arc::strong_count(&forward), 1);
+        let _ = socket.send_to(b"datagram", ("127.0.0.1", port));
+        thread::sleep(Duration::from_millis(20));
+        assert!(!is_late.load(Ordering::SeqCst));
+
+        // The poller keeps dispatching the others
+        let counter = Arc::new(Mutex::new(CountForward::default()));
+        let tx: Arc<Mutex<dyn Forward>> = counter.clone();
+        let datagram = DatagramWorker::bind_direct(tx, &threads, 50009, 0).unwrap();
+        let port = datagram
+            .datagram
+            .as_ref()
+            .unwrap()
+            .get_socket()
+            .local_addr()
+            .unwrap()
+            .port();
+        socket.send_to(b"datagram", ("127.0.0.1", port)).unwrap();
+        wait_for(|| counter.lock().unwrap().udp == 1);
+        wait_for(|| get_flow_threads(&threads) == 0);
+    }
+
+    #[test]
+    fn test_datagram_record() {
+        use crate::privacy::{Privacy, Redactor};
+        use crate::record::{Kind, Record, DEFAULT_RECORD_MAX_SIZE};
+        use std::net::UdpSocket;
+
+        let threads = Arc::new(Threads::new(16));
+        let counter = Arc::new(Mutex::new(CountForward::default()));
+        let tx: Arc<Mutex<dyn Forward>> = counter.clone();
+        let mut datagram = DatagramWorker::bind_direct(tx, &threads, 50016, 0).unwrap();
+        let path = env::temp_dir().join(format!("pcap2socks-datagram-{}.rec", process::id()));
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = match socket.local_addr().unwrap() {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => unreachable!(),
+        };
+        let redactor = Redactor::new(Privacy::Full, 0);
+        let recorder = Recorder::create(
+            path.clone(),
+            Kind::Datagram,
+            addr,
+            DEFAULT_RECORD_MAX_SIZE,
+            redactor,
+        )
+        .unwrap();
+        datagram.set_recorder(recorder);
+        let port = datagram
+            .datagram
+            .as_ref()
+            .unwrap()
+            .get_socket()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        // The datagram forwarded to the source is recorded by the tee
+        socket.send_to(b"datagram", ("127.0.0.1", port)).unwrap();
+        wait_for(|| counter.lock().unwrap().udp == 1);
+        let record = Record::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(record.entries.len(), 1);
+        assert_eq!(record.entries[0].direction, Direction::Down);
+        assert_eq!(record.entries[0].addr, addr);
+        assert_eq!(record.entries[0].payload, b"datagram");
+    }
+
+    #[test]
+    fn test_early_rtt() {
+        use std::io::Read;
+        use std::net::{TcpListener, UdpSocket};
+
+        const DELAY: Duration = Duration::from_millis(30);
+
+        let forward = Arc::new(Mutex::new(CountForward::default()));
+        let tx: Arc<Mutex<dyn Forward>> = forward.clone();
+        let threads = Arc::new(Threads::new(16));
+        let dst = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 80));
+
+        // The proxy answers each request after the delay
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let mut stream = StreamWorker::open(
+            Arc::clone(&tx),
+            &threads,
+            50005,
+            dst,
+            &PORT_BACKOFF,
+            move || TcpStream::connect(proxy),
+        )
+        .unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        let server = thread::spawn(move || {
+            let mut buffer = [0u8; 4];
+            for _ in 0..2 {
+                peer.read_exact(&mut buffer).unwrap();
+                thread::sleep(DELAY);
+                peer.write_all(&buffer).unwrap();
+            }
+        });
+        wait_for(|| stream.poll_ready().unwrap());
+        assert_eq!(stream.get_early_rtt(), None);
+        stream.send(b"ping", true).unwrap();
+        wait_for(|| stream.get_early_rtt().is_some());
+        let rtt = stream.get_early_rtt().unwrap();
+        assert!(rtt >= DELAY);
+        assert!(rtt < stream.counters.get_stats().created.elapsed());
+
+        // Later round trips are not sampled
+        stream.send(b"ping", true).unwrap();
+        wait_for(|| forward.lock().unwrap().get_counts().0 == 8);
+        assert_eq!(stream.get_early_rtt(), Some(rtt));
+        server.join().unwrap();
+
+        // The same for datagrams, once the first is sent
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let remote = peer.local_addr().unwrap();
+        let mut datagram = DatagramWorker::bind_direct(tx, &threads, 50006, 0).unwrap();
+        let server = thread::spawn(move || {
+            let mut buffer = [0u8; 4];
+            let (size, addr) = peer.recv_from(&mut buffer).unwrap();
+            thread::sleep(DELAY);
+            peer.send_to(&buffer[..size], addr).unwrap();
+        });
+        assert_eq!(datagram.get_early_rtt(), None);
+        datagram.send_to(b"ping", remote).unwrap();
+        wait_for(|| datagram.get_early_rtt().is_some());
+        assert!(datagram.get_early_rtt().unwrap() >= DELAY);
+        assert_eq!(forward.lock().unwrap().get_counts().1, 1);
+        server.join().unwrap();
+    }
+}
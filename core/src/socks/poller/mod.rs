@@ -0,0 +1,868 @@
+use log::{trace, warn};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::UdpSocket;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::sync::atomic::{self, AtomicPtr};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::sync::{AtomicUsize, Mutex, Ordering};
+use crate::threads::{Purpose, Threads};
+
+/// Represents the interval between 2 ticks of the poller, which checks paused and idle sources.
+pub const TICK: u64 = 10;
+
+/// Represents what a source waits for next.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Wait {
+    /// Waits for being readable.
+    Readable,
+    /// Waits for ticks only, without being polled.
+    Paused,
+    /// Finished, the source is removed from the poller.
+    Finished,
+}
+
+/// Represents a handler of a source in the poller.
+pub trait Handler: Send {
+    /// Handles the source being readable or hung up with the buffer of the poller, and returns
+    /// what the source waits for next. A handler reads once, so a busy source cannot starve the
+    /// others.
+    fn readable(&mut self, buffer: &mut [u8]) -> Wait;
+
+    /// Handles a tick of the poller, and returns what the source waits for next.
+    fn tick(&mut self, wait: Wait) -> Wait;
+
+    /// Returns if the source has data to send, where it is polled for being writable as well,
+    /// including when paused.
+    fn is_writing(&self) -> bool {
+        false
+    }
+
+    /// Handles the source being writable or hung up, and returns what the source waits for next.
+    fn writable(&mut self, wait: Wait) -> Wait {
+        wait
+    }
+}
+
+#[cfg(unix)]
+pub type RawSource = std::os::unix::io::RawFd;
+#[cfg(windows)]
+pub type RawSource = std::os::windows::io::RawSocket;
+
+/// Get the raw socket of a source.
+#[cfg(unix)]
+pub fn get_raw<T: std::os::unix::io::AsRawFd>(source: &T) -> RawSource {
+    source.as_raw_fd()
+}
+
+/// Get the raw socket of a source.
+#[cfg(windows)]
+pub fn get_raw<T: std::os::windows::io::AsRawSocket>(source: &T) -> RawSource {
+    source.as_raw_socket()
+}
+
+/// Represents a source registered in the poller.
+struct Entry {
+    raw: RawSource,
+    handler: Mutex<Option<(Box<dyn Handler>, Wait)>>,
+}
+
+/// Represents the sources registered in the poller by their tokens.
+struct Registry {
+    next_token: AtomicUsize,
+    entries: Mutex<HashMap<usize, Arc<Entry>>>,
+}
+
+impl Registry {
+    /// Creates a new `Registry`.
+    fn new() -> Registry {
+        Registry {
+            next_token: AtomicUsize::new(0),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn register(&self, raw: RawSource, handler: Box<dyn Handler>) -> usize {
+        let token = self.next_token.fetch_add(1, Ordering::Relaxed);
+        let entry = Entry {
+            raw,
+            handler: Mutex::new(Some((handler, Wait::Readable))),
+        };
+        self.entries.lock().unwrap().insert(token, Arc::new(entry));
+
+        token
+    }
+
+    /// Removes the source of the token, and drops its handler once its event in flight is done.
+    /// Returns `false` if the source is removed already.
+    fn deregister(&self, token: usize) -> bool {
+        let entry = self.entries.lock().unwrap().remove(&token);
+        match entry {
+            Some(entry) => {
+                entry.handler.lock().unwrap().take();
+
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes the source of the token which cannot be polled, and drops its handler. The owner
+    /// deregistering it later finds it removed already.
+    fn remove_invalid(&self, token: usize) {
+        let entry = self.entries.lock().unwrap().remove(&token);
+        if let Some(entry) = entry {
+            entry.handler.lock().unwrap().take();
+            warn!("source {} in poller is invalid, remove", token);
+        }
+    }
+
+    /// Get the sources registered, which are kept by the caller even if deregistered meanwhile.
+    fn get_entries(&self) -> Vec<(usize, Arc<Entry>)> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(token, entry)| (*token, Arc::clone(entry)))
+            .collect()
+    }
+
+    /// Dispatches the events of a source to its handler, and removes the source if it is finished.
+    /// A source deregistered meanwhile is skipped.
+    fn dispatch(
+        &self,
+        token: usize,
+        entry: &Entry,
+        is_readable: bool,
+        is_writable: bool,
+        is_tick: bool,
+        buffer: &mut [u8],
+    ) {
+        let mut handler_locked = entry.handler.lock().unwrap();
+        let next = match *handler_locked {
+            Some((ref mut handler, wait)) => {
+                let is_readable = wait == Wait::Readable && is_readable;
+                // A handler which panics is finished, and is reported by the panic already
+                panic::catch_unwind(AssertUnwindSafe(|| {
+                    let mut next = if is_readable {
+                        handler.readable(buffer)
+                    } else {
+                        wait
+                    };
+                    if is_writable && next != Wait::Finished && handler.is_writing() {
+                        next = handler.writable(next);
+                    }
+                    if is_tick && next != Wait::Finished {
+                        handler.tick(next)
+                    } else {
+                        next
+                    }
+                }))
+                .unwrap_or(Wait::Finished)
+            }
+            // Deregistered
+            None => return,
+        };
+        match next {
+            Wait::Finished => {
+                handler_locked.take();
+                drop(handler_locked);
+                self.entries.lock().unwrap().remove(&token);
+                trace!("source {} in poller is finished", token);
+            }
+            wait => {
+                if let Some((_, ref mut w)) = *handler_locked {
+                    *w = wait;
+                }
+            }
+        }
+    }
+}
+
+/// Represents the poller receiving from and sending to the sources of all the workers in a single
+/// thread. The sockets of the local ports are shared by all the captures, so is the poller.
+///
+/// The poller is started once in the process and never stopped. Its thread is recorded in the
+/// thread registry of the first caller of `get` only, which is the registry of the process in the
+/// binary, while the registries of other callers, like the ones of tests, do not list it.
+pub struct Poller {
+    registry: Registry,
+    waker: UdpSocket,
+}
+
+/// Represents the state of the poller, which is stopped, starting or running.
+static STATE: atomic::AtomicUsize = atomic::AtomicUsize::new(STOPPED);
+static POLLER: AtomicPtr<Poller> = AtomicPtr::new(ptr::null_mut());
+
+const STOPPED: usize = 0;
+const STARTING: usize = 1;
+const RUNNING: usize = 2;
+
+impl Poller {
+    /// Get the poller, which is started in the thread registry on the first call. A failed start
+    /// returns its error, and is retried on the next call.
+    pub fn get(threads: &Arc<Threads>) -> io::Result<&'static Poller> {
+        loop {
+            match STATE.compare_exchange(STOPPED, STARTING, Ordering::Acquire, Ordering::Acquire) {
+                Ok(_) => {
+                    return match Poller::start(threads) {
+                        Ok(poller) => {
+                            POLLER.store(poller as *const Poller as *mut Poller, Ordering::Release);
+                            STATE.store(RUNNING, Ordering::Release);
+
+                            Ok(poller)
+                        }
+                        Err(e) => {
+                            warn!("start poller: {}", e);
+                            STATE.store(STOPPED, Ordering::Release);
+
+                            Err(e)
+                        }
+                    };
+                }
+                // The pointer is stored before the state, and is never released
+                Err(RUNNING) => return Ok(unsafe { &*POLLER.load(Ordering::Acquire) }),
+                // Being started by another caller
+                Err(_) => thread::yield_now(),
+            }
+        }
+    }
+
+    fn start(threads: &Arc<Threads>) -> io::Result<&'static Poller> {
+        let waker = UdpSocket::bind("127.0.0.1:0")?;
+        waker.connect(waker.local_addr()?)?;
+        waker.set_nonblocking(true)?;
+        let ptr = Box::into_raw(Box::new(Poller {
+            registry: Registry::new(),
+            waker,
+        }));
+        let poller: &'static Poller = unsafe { &*ptr };
+        if let Err(e) = Threads::spawn(threads, String::from("poll"), Purpose::Poll, move || {
+            poller.work()
+        }) {
+            // The closure is dropped if the thread cannot be spawned, so nothing refers to it
+            drop(unsafe { Box::from_raw(ptr) });
+            return Err(e);
+        }
+
+        Ok(poller)
+    }
+
+    /// Registers the source with its handler, which waits for being readable, and returns the
+    /// token of the source.
+    pub fn register(&self, raw: RawSource, handler: Box<dyn Handler>) -> usize {
+        let token = self.registry.register(raw, handler);
+        // Wake up the poll for the new source
+        self.wake();
+        trace!("register source {} in poller", token);
+
+        token
+    }
+
+    /// Wakes up the poll, so a source which has data to send is polled for being writable at once.
+    pub fn wake(&self) {
+        let _ = self.waker.send(&[0]);
+    }
+
+    /// Removes the source of the token from the poller, and drops its handler. An event of the
+    /// source in flight is waited for, so the handler runs no more once removed. The poll is woken
+    /// up, so the kernel releases the source closed by the caller at once.
+    ///
+    /// The handler of the entry is locked first, before the reason of the lifecycle of the worker
+    /// and `tx`, see `Lifecycle`, so it must not be called holding any of them.
+    pub fn deregister(&self, token: usize) {
+        if self.registry.deregister(token) {
+            self.wake();
+            trace!("deregister source {} in poller", token);
+        }
+    }
+
+    fn work(&self) {
+        let mut buffer = vec![0u8; u16::MAX as usize];
+        let mut last_tick = Instant::now();
+        loop {
+            let entries = self.registry.get_entries();
+            let mut polled = Vec::with_capacity(entries.len());
+            let mut interests = vec![(get_raw(&self.waker), true, false)];
+            for (token, entry) in entries.iter() {
+                if let Some((ref handler, wait)) = *entry.handler.lock().unwrap() {
+                    let is_read = wait == Wait::Readable;
+                    let is_write = handler.is_writing();
+                    if is_read || is_write {
+                        polled.push(*token);
+                        interests.push((entry.raw, is_read, is_write));
+                    }
+                }
+            }
+
+            let events = match poll(&interests, Duration::from_millis(TICK)) {
+                Ok(events) => events,
+                Err(ref e) => {
+                    warn!("poll: {}", e);
+                    if is_invalid(e) {
+                        self.remove_invalid(&polled, &interests[1..]);
+                    }
+                    // The error may persist, so do not spin on it
+                    thread::sleep(Duration::from_millis(TICK));
+                    continue;
+                }
+            };
+            if events[0].0 {
+                let mut wake = [0u8; 64];
+                while self.waker.recv(&mut wake).is_ok() {}
+            }
+            let mut readable = HashSet::new();
+            let mut writable = HashSet::new();
+            for (token, (is_readable, is_writable)) in polled.iter().zip(events.into_iter().skip(1))
+            {
+                if is_readable {
+                    readable.insert(*token);
+                }
+                if is_writable {
+                    writable.insert(*token);
+                }
+            }
+            let is_tick = last_tick.elapsed() >= Duration::from_millis(TICK);
+            if is_tick {
+                last_tick = Instant::now();
+            }
+
+            for (token, entry) in entries {
+                self.registry.dispatch(
+                    token,
+                    &entry,
+                    readable.contains(&token),
+                    writable.contains(&token),
+                    is_tick,
+                    &mut buffer,
+                );
+            }
+        }
+    }
+
+    /// Polls the sources one by one, and removes the ones which cannot be polled, so a closed
+    /// source does not fail the poll of all the others.
+    fn remove_invalid(&self, polled: &[usize], interests: &[(RawSource, bool, bool)]) {
+        for (token, interest) in polled.iter().zip(interests.iter()) {
+            if let Err(ref e) = poll(&[*interest], Duration::from_millis(0)) {
+                if is_invalid(e) {
+                    self.registry.remove_invalid(*token);
+                }
+            }
+        }
+    }
+}
+
+/// Returns if the error of a poll is for a source which is not a valid socket.
+#[cfg(unix)]
+fn is_invalid(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::EBADF)
+}
+
+/// Returns if the error of a poll is for a source which is not a valid socket.
+#[cfg(windows)]
+fn is_invalid(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(winapi::shared::winerror::WSAENOTSOCK as i32)
+}
+
+/// Waits for the sources to be readable or writable as interested, or hung up in the timeout, and
+/// returns if each source is readable and if it is writable. A source which is not a valid
+/// socket fails the poll with `EBADF`, like `WSAPoll` does with `WSAENOTSOCK`.
+#[cfg(unix)]
+fn poll(interests: &[(RawSource, bool, bool)], timeout: Duration) -> io::Result<Vec<(bool, bool)>> {
+    use libc::{pollfd, POLLIN, POLLNVAL, POLLOUT};
+
+    let mut fds: Vec<pollfd> = interests
+        .iter()
+        .map(|(raw, is_read, is_write)| pollfd {
+            fd: *raw,
+            events: if *is_read { POLLIN } else { 0 } | if *is_write { POLLOUT } else { 0 },
+            revents: 0,
+        })
+        .collect();
+    let result = unsafe {
+        libc::poll(
+            fds.as_mut_ptr(),
+            fds.len() as libc::nfds_t,
+            timeout.as_millis() as libc::c_int,
+        )
+    };
+    if result < 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() == io::ErrorKind::Interrupted {
+            return Ok(vec![(false, false); interests.len()]);
+        }
+        return Err(e);
+    }
+    if fds.iter().any(|fd| fd.revents & POLLNVAL != 0) {
+        return Err(io::Error::from_raw_os_error(libc::EBADF));
+    }
+
+    // Errors and hang ups are readable and writable as well, where the read or the write reports
+    // them
+    Ok(fds
+        .iter()
+        .map(|fd| (fd.revents & !POLLOUT != 0, fd.revents & !POLLIN != 0))
+        .collect())
+}
+
+/// Waits for the sources to be readable or writable as interested, or hung up in the timeout, and
+/// returns if each source is readable and if it is writable.
+#[cfg(windows)]
+fn poll(interests: &[(RawSource, bool, bool)], timeout: Duration) -> io::Result<Vec<(bool, bool)>> {
+    use std::os::raw::{c_int, c_ulong};
+    use winapi::um::winsock2::{self, POLLRDNORM, POLLWRNORM, SOCKET, WSAPOLLFD};
+
+    let mut fds: Vec<WSAPOLLFD> = interests
+        .iter()
+        .map(|(raw, is_read, is_write)| WSAPOLLFD {
+            fd: *raw as SOCKET,
+            events: if *is_read { POLLRDNORM } else { 0 } | if *is_write { POLLWRNORM } else { 0 },
+            revents: 0,
+        })
+        .collect();
+    let result = unsafe {
+        winsock2::WSAPoll(
+            fds.as_mut_ptr(),
+            fds.len() as c_ulong,
+            timeout.as_millis() as c_int,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Errors and hang ups are readable and writable as well, where the read or the write reports
+    // them
+    Ok(fds
+        .iter()
+        .map(|fd| (fd.revents & !POLLWRNORM != 0, fd.revents & !POLLRDNORM != 0))
+        .collect())
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::atomic::AtomicBool;
+    use std::sync::mpsc::{self, Sender};
+
+    fn get_poller() -> &'static Poller {
+        Poller::get(&Arc::new(Threads::new(usize::MAX))).unwrap()
+    }
+
+    /// Represents a handler which takes a while in its read, and records being dropped.
+    struct SlowHandler {
+        socket: UdpSocket,
+        entered: Sender<()>,
+        is_done: Arc<AtomicBool>,
+        is_dropped: Arc<AtomicBool>,
+    }
+
+    impl Handler for SlowHandler {
+        fn readable(&mut self, buffer: &mut [u8]) -> Wait {
+            let _ = self.socket.recv(buffer);
+            let _ = self.entered.send(());
+            thread::sleep(Duration::from_millis(200));
+            self.is_done.store(true, Ordering::SeqCst);
+
+            Wait::Readable
+        }
+
+        fn tick(&mut self, wait: Wait) -> Wait {
+            wait
+        }
+    }
+
+    impl Drop for SlowHandler {
+        fn drop(&mut self) {
+            self.is_dropped.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_deregister_in_flight() {
+        let poller = get_poller();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let addr = socket.local_addr().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let is_done = Arc::new(AtomicBool::new(false));
+        let is_dropped = Arc::new(AtomicBool::new(false));
+        let token = poller.register(
+            get_raw(&socket),
+            Box::new(SlowHandler {
+                socket,
+                entered: tx,
+                is_done: Arc::clone(&is_done),
+                is_dropped: Arc::clone(&is_dropped),
+            }),
+        );
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(&[0], addr).unwrap();
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // The worker is dropped while its read is in flight
+        poller.deregister(token);
+        assert!(is_done.load(Ordering::SeqCst));
+        assert!(is_dropped.load(Ordering::SeqCst));
+    }
+
+    /// Represents a handler of a source which is never read, and records being dropped.
+    struct IdleHandler {
+        is_dropped: Arc<AtomicBool>,
+    }
+
+    impl Handler for IdleHandler {
+        fn readable(&mut self, _: &mut [u8]) -> Wait {
+            Wait::Readable
+        }
+
+        fn tick(&mut self, wait: Wait) -> Wait {
+            wait
+        }
+    }
+
+    impl Drop for IdleHandler {
+        fn drop(&mut self) {
+            self.is_dropped.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_remove_invalid() {
+        let poller = get_poller();
+        let is_dropped = Arc::new(AtomicBool::new(false));
+        // A descriptor which is never open
+        let token = poller.register(
+            RawSource::MAX,
+            Box::new(IdleHandler {
+                is_dropped: Arc::clone(&is_dropped),
+            }),
+        );
+
+        let instant = Instant::now();
+        while !is_dropped.load(Ordering::SeqCst) {
+            assert!(instant.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(TICK));
+        }
+
+        // Other sources are still polled
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let addr = socket.local_addr().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let other = poller.register(
+            get_raw(&socket),
+            Box::new(ReadHandler {
+                source: Datagram(socket),
+                read: tx,
+            }),
+        );
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(&[0; 4], addr).unwrap();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), 4);
+
+        poller.deregister(token);
+        poller.deregister(other);
+    }
+
+    /// Represents a handler which reads once and reports the size read.
+    struct ReadHandler<T: Read + Send> {
+        source: T,
+        read: Sender<usize>,
+    }
+
+    impl<T: Read + Send> Handler for ReadHandler<T> {
+        fn readable(&mut self, buffer: &mut [u8]) -> Wait {
+            match self.source.read(buffer) {
+                Ok(0) => Wait::Finished,
+                Ok(size) => {
+                    let _ = self.read.send(size);
+
+                    Wait::Readable
+                }
+                Err(_) => Wait::Readable,
+            }
+        }
+
+        fn tick(&mut self, wait: Wait) -> Wait {
+            wait
+        }
+    }
+
+    /// Represents a UDP socket read as a source.
+    struct Datagram(UdpSocket);
+
+    impl Read for Datagram {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            self.0.recv(buffer)
+        }
+    }
+
+    #[test]
+    fn test_udp_not_starving_tcp() {
+        let poller = get_poller();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let addr = socket.local_addr().unwrap();
+        let (udp_tx, udp_rx) = mpsc::channel();
+        let udp_token = poller.register(
+            get_raw(&socket),
+            Box::new(ReadHandler {
+                source: Datagram(socket),
+                read: udp_tx,
+            }),
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let (tcp_tx, tcp_rx) = mpsc::channel();
+        let tcp_token = poller.register(
+            get_raw(&stream),
+            Box::new(ReadHandler {
+                source: stream,
+                read: tcp_tx,
+            }),
+        );
+
+        // Flood the UDP source, so it is always readable
+        let is_flooding = Arc::new(AtomicBool::new(true));
+        let flood = {
+            let is_flooding = Arc::clone(&is_flooding);
+            thread::spawn(move || {
+                let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+                while is_flooding.load(Ordering::Relaxed) {
+                    let _ = sender.send_to(&[0u8; 1024], addr);
+                }
+            })
+        };
+        udp_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        for _ in 0..10 {
+            client.write_all(b"ping").unwrap();
+            let mut size = 0;
+            while size < 4 {
+                size += tcp_rx.recv_timeout(Duration::from_secs(1)).unwrap();
+            }
+        }
+
+        is_flooding.store(false, Ordering::Relaxed);
+        flood.join().unwrap();
+        poller.deregister(udp_token);
+        poller.deregister(tcp_token);
+    }
+}
+
+#[cfg(all(test, loom))]
+mod tests {
+    use super::*;
+    use crate::socks::lifecycle::{CloseReason, Lifecycle};
+    use crate::sync::{mpsc, AtomicBool};
+    use loom::sync::Arc;
+
+    /// Represents the table of the owner, keyed by the source port like the tables of the
+    /// redirector, with the state of each worker.
+    type Table = Arc<Mutex<HashMap<u16, usize>>>;
+
+    const SRC_PORT: u16 = 1;
+
+    /// Represents the receiving side of a worker in the poller, which forwards to the table
+    /// through its lifecycle, or closes for an error if it fails.
+    struct ModelReceiver {
+        lifecycle: Arc<Lifecycle>,
+        table: Table,
+        is_failing: bool,
+        is_late: Arc<AtomicBool>,
+        drops: Arc<AtomicUsize>,
+    }
+
+    impl ModelReceiver {
+        fn forward_to(table: &Table, is_late: &AtomicBool) {
+            match table.lock().unwrap().get_mut(&SRC_PORT) {
+                Some(state) => *state += 1,
+                None => is_late.store(true, Ordering::SeqCst),
+            }
+        }
+    }
+
+    impl Handler for ModelReceiver {
+        fn readable(&mut self, _: &mut [u8]) -> Wait {
+            if self.lifecycle.is_closed() {
+                return Wait::Finished;
+            }
+            let (table, is_late) = (&self.table, &self.is_late);
+            if self.is_failing {
+                self.lifecycle
+                    .close_and_forward(CloseReason::Error, || Self::forward_to(table, is_late));
+                return Wait::Finished;
+            }
+            if !self.lifecycle.forward(|| Self::forward_to(table, is_late)) {
+                return Wait::Finished;
+            }
+
+            Wait::Readable
+        }
+
+        fn tick(&mut self, wait: Wait) -> Wait {
+            wait
+        }
+    }
+
+    impl Drop for ModelReceiver {
+        fn drop(&mut self) {
+            self.drops.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Represents the state shared by the owner, the poller and the handshake in a model.
+    struct Model {
+        registry: Arc<Registry>,
+        lifecycle: Arc<Lifecycle>,
+        table: Table,
+        is_late: Arc<AtomicBool>,
+        drops: Arc<AtomicUsize>,
+    }
+
+    impl Model {
+        fn new() -> Model {
+            let table = Arc::new(Mutex::new(HashMap::new()));
+            table.lock().unwrap().insert(SRC_PORT, 0);
+
+            Model {
+                registry: Arc::new(Registry::new()),
+                lifecycle: Arc::new(Lifecycle::new()),
+                table,
+                is_late: Arc::new(AtomicBool::new(false)),
+                drops: Arc::new(AtomicUsize::new(0)),
+            }
+        }
+
+        fn receiver(&self, is_failing: bool) -> Box<dyn Handler> {
+            Box::new(ModelReceiver {
+                lifecycle: Arc::clone(&self.lifecycle),
+                table: Arc::clone(&self.table),
+                is_failing,
+                is_late: Arc::clone(&self.is_late),
+                drops: Arc::clone(&self.drops),
+            })
+        }
+
+        /// Spawns the poller dispatching a readable event to every source once.
+        fn spawn_poller(&self) -> loom::thread::JoinHandle<()> {
+            let registry = Arc::clone(&self.registry);
+            loom::thread::spawn(move || {
+                let mut buffer = [0u8; 1];
+                for (token, entry) in registry.get_entries() {
+                    registry.dispatch(token, &entry, true, false, false, &mut buffer);
+                }
+            })
+        }
+
+        /// Closes the worker like its owner, deregisters it and releases its state in the table.
+        fn close(&self, reason: CloseReason, token: Option<usize>) -> bool {
+            let is_first = self.lifecycle.close(reason);
+            if let Some(token) = token {
+                self.registry.deregister(token);
+                // The handler runs no more once deregistered
+                assert_eq!(self.drops.load(Ordering::SeqCst), 1);
+            }
+            assert!(self.table.lock().unwrap().remove(&SRC_PORT).is_some());
+
+            is_first
+        }
+
+        fn check(&self) {
+            assert!(
+                !self.is_late.load(Ordering::SeqCst),
+                "forward after removal"
+            );
+            assert!(self.registry.get_entries().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_loom_close_during_dispatch() {
+        loom::model(|| {
+            let model = Model::new();
+            let token = model.registry.register(0, model.receiver(false));
+
+            let poller = model.spawn_poller();
+            assert!(model.close(CloseReason::Closed, Some(token)));
+
+            poller.join().unwrap();
+            model.check();
+            assert_eq!(model.drops.load(Ordering::SeqCst), 1);
+        });
+    }
+
+    #[test]
+    fn test_loom_finish_during_deregister() {
+        loom::model(|| {
+            let model = Model::new();
+            let token = model.registry.register(0, model.receiver(true));
+
+            // The receiver fails and finishes while the owner drops the worker
+            let poller = model.spawn_poller();
+            let is_dropped = model.close(CloseReason::Dropped, Some(token));
+
+            poller.join().unwrap();
+            model.check();
+            assert_eq!(model.drops.load(Ordering::SeqCst), 1);
+            let reason = if is_dropped {
+                CloseReason::Dropped
+            } else {
+                CloseReason::Error
+            };
+            assert_eq!(model.lifecycle.get_close_reason(), Some(reason));
+        });
+    }
+
+    #[test]
+    fn test_loom_publish_during_close() {
+        loom::model(|| {
+            let model = Model::new();
+            let (ready_tx, ready_rx) = mpsc::channel();
+
+            // The handshake registers the stream and publishes it, unless closed before
+            let handshake = {
+                let (registry, lifecycle) = (Arc::clone(&model.registry), &model.lifecycle);
+                let receiver = model.receiver(false);
+                let lifecycle = Arc::clone(lifecycle);
+                loom::thread::spawn(move || {
+                    let is_published = lifecycle.forward(|| {
+                        let token = registry.register(0, receiver);
+                        let _ = ready_tx.send(token);
+                    });
+                    // The receiver is dropped with the closure if never registered
+                    is_published
+                })
+            };
+
+            // The owner receives the stream published until the close, and never after it
+            let mut token = ready_rx.try_recv().ok();
+            assert!(model.lifecycle.close(CloseReason::Closed));
+            token = token.or_else(|| ready_rx.try_recv().ok());
+            if let Some(token) = token {
+                model.registry.deregister(token);
+                assert_eq!(model.drops.load(Ordering::SeqCst), 1);
+            }
+            assert!(model.table.lock().unwrap().remove(&SRC_PORT).is_some());
+
+            let is_published = handshake.join().unwrap();
+            assert_eq!(is_published, token.is_some());
+            model.check();
+            assert_eq!(model.drops.load(Ordering::SeqCst), 1);
+        });
+    }
+}
@@ -0,0 +1,224 @@
+use log::{debug, trace};
+use std::collections::VecDeque;
+use std::io;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{PortBackoff, PORT_BACKOFF};
+use crate::backoff::{self, Site};
+use crate::threads::{Purpose, Threads};
+
+/// Represents the max age of a pre-warmed connection in seconds, after which the proxy may have
+/// closed it for idle, so it is connected again.
+const MAX_AGE: u64 = 10;
+
+/// Represents the interval between 2 fills of a pool in milliseconds, which is the base of the
+/// backoff after failed fills.
+const FILL_INTERVAL: u64 = 100;
+
+/// Represents a pool of connections to a proxy which are connected but have not started any
+/// handshake. A new flow taking one skips the connect, and still connects while the local ports
+/// are exhausted, since the connection already has its local port.
+#[derive(Debug)]
+pub struct Pool {
+    size: usize,
+    state: Mutex<State>,
+    taken: AtomicUsize,
+}
+
+#[derive(Debug)]
+struct State {
+    /// Represents the proxy the pool fills, which is the one of the last take.
+    remote: Option<SocketAddr>,
+    streams: VecDeque<(TcpStream, Instant)>,
+}
+
+impl Pool {
+    /// Creates a new `Pool` of the given size.
+    pub fn new(size: usize) -> Pool {
+        Pool {
+            size,
+            state: Mutex::new(State {
+                remote: None,
+                streams: VecDeque::new(),
+            }),
+            taken: AtomicUsize::new(0),
+        }
+    }
+
+    /// Spawns the thread filling the pool, which exits once the pool is dropped. The pool is not
+    /// filled while new connects back off, and fills back off after failed connects.
+    pub fn start(pool: &Arc<Pool>, threads: &Arc<Threads>) -> io::Result<()> {
+        let weak = Arc::downgrade(pool);
+        Threads::spawn(
+            threads,
+            String::from("prewarm"),
+            Purpose::Prewarm,
+            move || {
+                let base = Duration::from_millis(FILL_INTERVAL);
+                let mut failures = 0;
+                loop {
+                    let delay = match failures {
+                        0 => base,
+                        failures => base + backoff::get_delay(Site::Prewarm, base, failures - 1),
+                    };
+                    thread::sleep(delay);
+                    let pool = match weak.upgrade() {
+                        Some(pool) => pool,
+                        None => break,
+                    };
+                    if !PORT_BACKOFF.is_exhausted() {
+                        match pool.fill(&PORT_BACKOFF) {
+                            Ok(_) => failures = 0,
+                            Err(_) => failures += 1,
+                        }
+                    }
+                }
+            },
+        )?;
+
+        Ok(())
+    }
+
+    /// Takes a connection to the proxy which is still open, and makes the proxy the one the pool
+    /// fills. Connections to the previous proxy are closed.
+    pub fn take(&self, remote: SocketAddr) -> Option<TcpStream> {
+        let mut state_locked = self.state.lock().unwrap();
+        if state_locked.remote != Some(remote) {
+            debug!("Pre-warm connections to {}", remote);
+            state_locked.remote = Some(remote);
+            state_locked.streams.clear();
+
+            return None;
+        }
+        while let Some((stream, instant)) = state_locked.streams.pop_front() {
+            if instant.elapsed() < Duration::from_secs(MAX_AGE) && is_open(&stream) {
+                self.taken.fetch_add(1, Ordering::Relaxed);
+                trace!("take pre-warmed connection to {}", remote);
+
+                return Some(stream);
+            }
+        }
+
+        None
+    }
+
+    /// Connects to the proxy until the pool is full, and discards the connections which are too
+    /// old. A connect failing for the exhaustion of local ports backs off new connects. Returns
+    /// the number of connections made, or the error of the connect which failed, after which the
+    /// connections made before it are still kept.
+    pub fn fill(&self, ports: &PortBackoff) -> io::Result<usize> {
+        let (remote, missing) = {
+            let mut state_locked = self.state.lock().unwrap();
+            let max_age = Duration::from_secs(MAX_AGE);
+            state_locked
+                .streams
+                .retain(|(_, instant)| instant.elapsed() < max_age);
+            match state_locked.remote {
+                Some(remote) => (remote, self.size.saturating_sub(state_locked.streams.len())),
+                None => return Ok(0),
+            }
+        };
+
+        // Connect without holding the lock, so takes never wait for a connect
+        let mut streams = Vec::new();
+        let mut result = Ok(());
+        for _ in 0..missing {
+            match TcpStream::connect(remote) {
+                Ok(stream) => streams.push((stream, Instant::now())),
+                Err(e) => {
+                    ports.check(&e);
+                    debug!("pre-warm connection to {}: {}", remote, e);
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+
+        let count = streams.len();
+        let mut state_locked = self.state.lock().unwrap();
+        // The proxy may change in the connects
+        if state_locked.remote == Some(remote) {
+            state_locked.streams.extend(streams);
+        }
+
+        result.map(|_| count)
+    }
+
+    /// Get the number of connections in the pool.
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().streams.len()
+    }
+
+    /// Returns if there is no connection in the pool.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the number of connections taken from the pool.
+    pub fn get_taken(&self) -> usize {
+        self.taken.load(Ordering::Relaxed)
+    }
+}
+
+/// Returns if the proxy has not closed the connection, which has nothing to read before the
+/// handshake.
+fn is_open(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let is_open = match stream.peek(&mut [0u8; 1]) {
+        Err(ref e) => e.kind() == io::ErrorKind::WouldBlock,
+        // Closed, or sent something unexpected
+        Ok(_) => false,
+    };
+
+    is_open && stream.set_nonblocking(false).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_take_fill() {
+        static PORTS: PortBackoff = PortBackoff::new(1000);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let remote = listener.local_addr().unwrap();
+        let pool = Pool::new(2);
+
+        // The pool fills the proxy of the first take
+        assert_eq!(pool.fill(&PORTS).unwrap(), 0);
+        assert!(pool.take(remote).is_none());
+        assert_eq!(pool.fill(&PORTS).unwrap(), 2);
+        assert_eq!(pool.fill(&PORTS).unwrap(), 0);
+        assert_eq!(pool.len(), 2);
+        let (_accepted, _) = listener.accept().unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+
+        assert!(pool.take(remote).is_some());
+        assert_eq!(pool.get_taken(), 1);
+        // A connection closed by the proxy is never taken
+        drop(accepted);
+        thread::sleep(Duration::from_millis(50));
+        assert!(pool.take(remote).is_none());
+        assert!(pool.is_empty());
+
+        // Another proxy discards the connections to the previous one
+        assert_eq!(pool.fill(&PORTS).unwrap(), 2);
+        let other = TcpListener::bind("127.0.0.1:0").unwrap();
+        assert!(pool.take(other.local_addr().unwrap()).is_none());
+        assert!(pool.is_empty());
+
+        // A proxy which is gone fails the fill, so the filling backs off
+        drop(listener);
+        assert!(pool.take(remote).is_none());
+        assert!(pool.fill(&PORTS).is_err());
+        assert!(pool.is_empty());
+    }
+}
@@ -0,0 +1,562 @@
+use socks::Socks5Datagram;
+use socks::{self, TargetAddr};
+use std::error::Error;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpStream, UdpSocket};
+use std::ptr;
+use std::time::Duration;
+
+/// Represents a secret which is zeroed when dropped and never printed.
+#[derive(Clone)]
+pub struct Secret(String);
+
+impl Secret {
+    /// Creates a new `Secret`.
+    pub fn new(s: String) -> Secret {
+        Secret(s)
+    }
+
+    /// Creates a new `Secret` from a line, the trailing line break is removed.
+    pub fn from_line(mut s: String) -> Secret {
+        let len = s.trim_end_matches(&['\r', '\n'][..]).len();
+        // Zero the line break before truncating
+        zero(unsafe { &mut s.as_bytes_mut()[len..] });
+        s.truncate(len);
+
+        Secret(s)
+    }
+
+    /// Get the secret as a `&str`.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Zeroes the buffer, which may hold a secret.
+pub fn zero(buffer: &mut [u8]) {
+    for b in buffer.iter_mut() {
+        // Volatile writes are not optimized out
+        unsafe { ptr::write_volatile(b, 0) };
+    }
+}
+
+/// Encodes the data in Base64 with padding.
+fn encode_base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut s = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                s.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                s.push('=');
+            }
+        }
+    }
+
+    s
+}
+
+impl Debug for Secret {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Secret(***)")
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        // Zeroes are valid UTF-8
+        zero(unsafe { self.0.as_bytes_mut() });
+    }
+}
+
+/// Represents an error of the authentication with a proxy, which is carried in an `io::Error`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum AuthError {
+    /// The proxy rejects the credentials with a non-zero status of the sub-negotiation of RFC
+    /// 1929.
+    Rejected,
+    /// The proxy selects no method offered.
+    NoAcceptableMethod,
+    /// The proxy selects a method not offered.
+    UnknownMethod,
+}
+
+impl AuthError {
+    /// Get the `AuthError` carried in the error.
+    fn from_error(e: &io::Error) -> Option<AuthError> {
+        e.get_ref()
+            .and_then(|inner| inner.downcast_ref::<AuthError>())
+            .copied()
+    }
+
+    /// Types the error of the `socks` crate, which tells the errors of the authentication by
+    /// their messages only.
+    fn from_socks(e: io::Error) -> io::Error {
+        let error = match e.kind() {
+            io::ErrorKind::PermissionDenied => AuthError::Rejected,
+            io::ErrorKind::Other if e.to_string() == "no acceptable auth methods" => {
+                AuthError::NoAcceptableMethod
+            }
+            io::ErrorKind::Other if e.to_string() == "unknown auth method" => {
+                AuthError::UnknownMethod
+            }
+            _ => return e,
+        };
+
+        error.into()
+    }
+}
+
+impl Display for AuthError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            AuthError::Rejected => write!(f, "password authentication failed"),
+            AuthError::NoAcceptableMethod => write!(f, "no acceptable auth methods"),
+            AuthError::UnknownMethod => write!(f, "unknown auth method"),
+        }
+    }
+}
+
+impl Error for AuthError {}
+
+impl From<AuthError> for io::Error {
+    fn from(error: AuthError) -> io::Error {
+        let kind = match error {
+            AuthError::Rejected => io::ErrorKind::PermissionDenied,
+            AuthError::NoAcceptableMethod | AuthError::UnknownMethod => io::ErrorKind::Other,
+        };
+
+        io::Error::new(kind, error)
+    }
+}
+
+/// Represents the username/password authentication of a proxy.
+#[derive(Clone, Debug)]
+pub struct Auth {
+    username: String,
+    password: Secret,
+}
+
+impl Auth {
+    /// Creates a new `Auth`.
+    pub fn new(username: String, password: Secret) -> Auth {
+        Auth { username, password }
+    }
+
+    /// Get the username of the `Auth`.
+    pub fn get_username(&self) -> &str {
+        &self.username
+    }
+
+    /// Get the credentials of the `Auth` in Base64 for the HTTP Basic authentication.
+    pub fn get_basic(&self) -> Secret {
+        let mut credentials = Vec::with_capacity(self.username.len() + 1 + self.password.0.len());
+        credentials.extend_from_slice(self.username.as_bytes());
+        credentials.push(b':');
+        credentials.extend_from_slice(self.password.as_str().as_bytes());
+        let basic = encode_base64(&credentials);
+        zero(&mut credentials);
+
+        Secret(basic)
+    }
+
+    /// Explains an error of the handshake with the proxy. The proxy rejects the credentials with
+    /// a non-zero status of the sub-negotiation of RFC 1929, or selects no method offered.
+    fn map_err(&self, remote: SocketAddr, e: io::Error) -> io::Error {
+        match AuthError::from_error(&e) {
+            Some(AuthError::Rejected) => io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("proxy {} rejects the username {}", remote, self.username),
+            ),
+            Some(AuthError::NoAcceptableMethod) => io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "proxy {} accepts no username/password authentication",
+                    remote
+                ),
+            ),
+            Some(AuthError::UnknownMethod) => io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "proxy {} selects an authentication method not offered",
+                    remote
+                ),
+            ),
+            None => e,
+        }
+    }
+}
+
+/// Connects to a target server of IPv4 or IPv6 through a SOCKS5 proxy over a stream already
+/// connected to the proxy, like one of a pool. The handshake and its errors are those of the
+/// `socks` crate, except that the reply is read exactly, so no tunneled byte is consumed.
+pub fn connect_over(
+    mut stream: TcpStream,
+    remote: SocketAddr,
+    dst: SocketAddr,
+    auth: Option<&Auth>,
+) -> io::Result<TcpStream> {
+    handshake(&mut stream, dst, auth).map_err(|e| match auth {
+        Some(auth) => auth.map_err(remote, e),
+        None => e,
+    })?;
+
+    Ok(stream)
+}
+
+fn handshake<S: Read + Write>(
+    stream: &mut S,
+    dst: SocketAddr,
+    auth: Option<&Auth>,
+) -> io::Result<()> {
+    // Methods, no authentication is always offered
+    match auth {
+        Some(_) => stream.write_all(&[5, 2, 2, 0])?,
+        None => stream.write_all(&[5, 1, 0])?,
+    }
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply)?;
+    if reply[0] != 5 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid response version",
+        ));
+    }
+    match (reply[1], auth) {
+        (0, _) => {}
+        (2, Some(auth)) => authenticate(stream, auth)?,
+        (0xff, _) => return Err(AuthError::NoAcceptableMethod.into()),
+        _ => return Err(AuthError::UnknownMethod.into()),
+    }
+
+    // Request
+    let mut request = vec![5, 1, 0];
+    write_addr(&mut request, dst);
+    stream.write_all(&request)?;
+    read_reply(stream)
+}
+
+/// Authenticates with the username and the password of RFC 1929.
+fn authenticate<S: Read + Write>(stream: &mut S, auth: &Auth) -> io::Result<()> {
+    let (username, password) = (auth.username.as_bytes(), auth.password.as_str().as_bytes());
+    if username.is_empty() || username.len() > 255 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid username",
+        ));
+    }
+    if password.is_empty() || password.len() > 255 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid password",
+        ));
+    }
+
+    let mut request = Vec::with_capacity(3 + username.len() + password.len());
+    request.push(1);
+    request.push(username.len() as u8);
+    request.extend_from_slice(username);
+    request.push(password.len() as u8);
+    request.extend_from_slice(password);
+    let result = stream.write_all(&request);
+    // The request holds the credentials
+    zero(&mut request);
+    result?;
+
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply)?;
+    if reply[0] != 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid response version",
+        ));
+    }
+    if reply[1] != 0 {
+        return Err(AuthError::Rejected.into());
+    }
+
+    Ok(())
+}
+
+/// Reads the reply to a request up to and including the bound address.
+fn read_reply<R: Read>(stream: &mut R) -> io::Result<()> {
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head)?;
+    if head[0] != 5 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid response version",
+        ));
+    }
+    let message = match head[1] {
+        0 => None,
+        1 => Some("general SOCKS server failure"),
+        2 => Some("connection not allowed by ruleset"),
+        3 => Some("network unreachable"),
+        4 => Some("host unreachable"),
+        5 => Some("connection refused"),
+        6 => Some("TTL expired"),
+        7 => Some("command not supported"),
+        8 => Some("address kind not supported"),
+        _ => Some("unknown error"),
+    };
+    if let Some(message) = message {
+        return Err(io::Error::other(message));
+    }
+    if head[2] != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid reserved byte",
+        ));
+    }
+
+    // The bound address and port
+    let size = match head[3] {
+        1 => 4 + 2,
+        3 => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len)?;
+            len[0] as usize + 2
+        }
+        4 => 16 + 2,
+        _ => return Err(io::Error::other("unsupported address type")),
+    };
+    let mut addr = vec![0u8; size];
+    stream.read_exact(&mut addr)?;
+
+    Ok(())
+}
+
+fn write_addr(buffer: &mut Vec<u8>, addr: SocketAddr) {
+    match addr {
+        SocketAddr::V4(addr) => {
+            buffer.push(1);
+            buffer.extend_from_slice(&addr.ip().octets());
+        }
+        SocketAddr::V6(addr) => {
+            buffer.push(4);
+            buffer.extend_from_slice(&addr.ip().octets());
+        }
+    }
+    buffer.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+/// Represents the socket of a `SocksDatagram`.
+#[derive(Debug)]
+enum Datagram {
+    Socks(Socks5Datagram),
+    Direct(UdpSocket),
+}
+
+/// Represents a SOCKS5 UDP client.
+#[derive(Debug)]
+pub struct SocksDatagram {
+    datagram: Datagram,
+}
+
+impl SocksDatagram {
+    /// Creates a UDP socket bound to the specified address which will have its traffic routed through the specified proxy.
+    pub fn bind(
+        local_src: SocketAddr,
+        remote: SocketAddr,
+        auth: Option<&Auth>,
+    ) -> io::Result<SocksDatagram> {
+        let datagram = match auth {
+            Some(auth) => Socks5Datagram::bind_with_password(
+                remote,
+                local_src,
+                &auth.username,
+                auth.password.as_str(),
+            )
+            .map_err(|e| auth.map_err(remote, AuthError::from_socks(e)))?,
+            None => Socks5Datagram::bind(remote, local_src)?,
+        };
+
+        Ok(SocksDatagram {
+            datagram: Datagram::Socks(datagram),
+        })
+    }
+
+    /// Creates a UDP socket bound to the specified address which will send its traffic directly without a proxy.
+    pub fn bind_direct(local_src: SocketAddr) -> io::Result<SocksDatagram> {
+        let datagram = UdpSocket::bind(local_src)?;
+
+        Ok(SocksDatagram {
+            datagram: Datagram::Direct(datagram),
+        })
+    }
+
+    /// Sends data on the socket to the given address.
+    pub fn send_to(&self, buffer: &[u8], dst: SocketAddr) -> io::Result<usize> {
+        match self.datagram {
+            // The size sent through the proxy includes its header
+            Datagram::Socks(ref datagram) => datagram.send_to(buffer, dst).map(|_| buffer.len()),
+            Datagram::Direct(ref datagram) => datagram.send_to(buffer, dst),
+        }
+    }
+
+    /// Sends an empty datagram to the unspecified address through the proxy, which the proxy may
+    /// drop, so the proxy learns the address of the socket before anything is sent.
+    pub fn prime(&self) -> io::Result<()> {
+        match self.datagram {
+            Datagram::Socks(ref datagram) => datagram
+                .send_to(&[], SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0))
+                .map(|_| ()),
+            Datagram::Direct(_) => Ok(()),
+        }
+    }
+
+    /// Get the underlying UDP socket.
+    pub fn get_socket(&self) -> &UdpSocket {
+        match self.datagram {
+            Datagram::Socks(ref datagram) => datagram.get_ref(),
+            Datagram::Direct(ref datagram) => datagram,
+        }
+    }
+
+    /// Sets the read timeout of the socket.
+    pub fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        match self.datagram {
+            Datagram::Socks(ref datagram) => datagram.get_ref().set_read_timeout(dur),
+            Datagram::Direct(ref datagram) => datagram.set_read_timeout(dur),
+        }
+    }
+
+    /// Moves the socket into or out of nonblocking mode.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.get_socket().set_nonblocking(nonblocking)
+    }
+
+    /// Receives a single datagram message on the socket, from an IPv4 or IPv6 address.
+    pub fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (size, addr) = match self.datagram {
+            Datagram::Socks(ref datagram) => {
+                let (size, addr) = datagram.recv_from(buffer)?;
+                match addr {
+                    TargetAddr::Ip(addr) => (size, addr),
+                    _ => return Err(io::Error::other("invalid address type")),
+                }
+            }
+            Datagram::Direct(ref datagram) => datagram.recv_from(buffer)?,
+        };
+
+        Ok((size, addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Represents a proxy replying the script in order, which records what is written to it.
+    struct Script {
+        reply: Cursor<Vec<u8>>,
+        written: Vec<u8>,
+    }
+
+    impl Script {
+        fn new(reply: &[u8]) -> Script {
+            Script {
+                reply: Cursor::new(reply.to_vec()),
+                written: Vec::new(),
+            }
+        }
+    }
+
+    impl Read for Script {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reply.read(buf)
+        }
+    }
+
+    impl Write for Script {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn dst() -> SocketAddr {
+        "192.0.2.1:443".parse().unwrap()
+    }
+
+    #[test]
+    fn test_handshake() {
+        // The tunneled bytes right after the reply are left in the stream
+        let mut script = Script::new(&[5, 0, 5, 0, 0, 1, 10, 0, 0, 1, 0, 80, b'h', b'i']);
+        handshake(&mut script, dst(), None).unwrap();
+        assert_eq!(
+            script.written,
+            vec![5, 1, 0, 5, 1, 0, 1, 192, 0, 2, 1, 1, 187]
+        );
+        let mut rest = Vec::new();
+        script.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"hi".to_vec());
+
+        // A bound address of a domain
+        let mut script = Script::new(&[5, 0, 5, 0, 0, 3, 1, b'a', 0, 80]);
+        handshake(&mut script, dst(), None).unwrap();
+    }
+
+    #[test]
+    fn test_handshake_auth() {
+        let auth = Auth::new(String::from("user"), Secret::new(String::from("pass")));
+        let mut script = Script::new(&[5, 2, 1, 0, 5, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+        handshake(&mut script, dst(), Some(&auth)).unwrap();
+        assert_eq!(&script.written[..4], &[5, 2, 2, 0]);
+        assert_eq!(&script.written[4..15], b"\x01\x04user\x04pass");
+
+        // Rejected
+        let mut script = Script::new(&[5, 2, 1, 1]);
+        let e = handshake(&mut script, dst(), Some(&auth)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        let e = auth.map_err("127.0.0.1:1080".parse().unwrap(), e);
+        assert!(e.to_string().contains("rejects the username user"), "{}", e);
+
+        // No method offered is accepted
+        let mut script = Script::new(&[5, 0xff]);
+        let e = handshake(&mut script, dst(), Some(&auth)).unwrap_err();
+        let e = auth.map_err("127.0.0.1:1080".parse().unwrap(), e);
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+
+        // A method not offered
+        let mut script = Script::new(&[5, 2]);
+        let e = handshake(&mut script, dst(), None).unwrap_err();
+        assert_eq!(AuthError::from_error(&e), Some(AuthError::UnknownMethod));
+
+        // Errors of the socks crate
+        let e = io::Error::other("no acceptable auth methods");
+        let e = AuthError::from_socks(e);
+        assert_eq!(
+            AuthError::from_error(&e),
+            Some(AuthError::NoAcceptableMethod)
+        );
+        let e = io::Error::other("connection refused");
+        assert_eq!(AuthError::from_error(&AuthError::from_socks(e)), None);
+    }
+
+    #[test]
+    fn test_handshake_refused() {
+        let mut script = Script::new(&[5, 0, 5, 5, 0, 1, 0, 0, 0, 0, 0, 0]);
+        let e = handshake(&mut script, dst(), None).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::Other);
+        assert_eq!(e.to_string(), "connection refused");
+
+        let mut script = Script::new(&[4, 0]);
+        let e = handshake(&mut script, dst(), None).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+}
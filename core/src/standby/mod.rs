@@ -0,0 +1,572 @@
+use log::{debug, info, warn};
+use std::cmp::min;
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::backoff::{self, Site};
+use crate::frame::{Message, MessageType, Value};
+use crate::threads::{Purpose, Threads};
+
+/// Represents the interval between 2 heartbeats, which is also the max lag of the replicated
+/// state.
+pub const HEARTBEAT_INTERVAL: u64 = 1;
+
+/// Represents the time without heartbeats after which the peer is considered failed.
+pub const PEER_HOLD: u64 = 3;
+
+/// Represents the time after a takeover in which an ARP conflict stands the instance down, since
+/// the previous active instance is still alive then.
+const TAKEOVER_GUARD: u64 = 10;
+
+/// Represents the time after standing down for an ARP conflict in which the instance never takes
+/// over without the heartbeats of the peer.
+const CONFLICT_HOLD: u64 = 60;
+
+/// Represents the role of an instance in the warm standby.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Role {
+    /// Owns the gateway and serves the source.
+    Active,
+    /// Follows the state of the active instance and takes over when it fails.
+    Standby,
+}
+
+impl Display for Role {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Role::Active => write!(f, "active"),
+            Role::Standby => write!(f, "standby"),
+        }
+    }
+}
+
+/// Represents a change of the role.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Transition {
+    /// Becomes the active instance, with the latest state replicated from the peer if any.
+    TakeOver(Option<String>),
+    /// Becomes the standby instance.
+    StandDown,
+}
+
+/// Represents the last heartbeat from the peer.
+#[derive(Debug)]
+struct Peer {
+    seen: Instant,
+    role: Role,
+    rank: (u32, u64),
+    states: BTreeMap<String, String>,
+}
+
+/// Represents the election shared by all the captures of the instance.
+#[derive(Debug)]
+struct Shared {
+    role: Role,
+    states: BTreeMap<String, String>,
+    peer: Option<Peer>,
+    /// Represents the states of the peer taken over and not restored by their captures yet.
+    taken: BTreeMap<String, String>,
+    /// Represents the time before which the instance never takes over without a live peer.
+    hold_until: Instant,
+    took_over: Option<Instant>,
+}
+
+/// Represents the warm standby of 2 instances serving the same sources. The instances send
+/// heartbeats to each other in binary frames, and the one of the higher priority, or of the higher
+/// nonce for the same priority, is active. The active instance replicates its state in its
+/// heartbeats, and the standby takes over with the state once the heartbeats stop.
+///
+/// Each capture of an instance is a member of the election of the instance, created by `join`, and
+/// follows the role elected with its own state under its key.
+pub struct Standby {
+    priority: u32,
+    nonce: u64,
+    key: String,
+    role: Role,
+    shared: Arc<Mutex<Shared>>,
+    takeovers: usize,
+    stand_downs: usize,
+}
+
+impl Standby {
+    /// Creates a new `Standby`, which starts in the standby role. The key identifies the capture
+    /// of the member in the replicated state.
+    pub fn new(priority: u32, nonce: u64, key: String) -> Standby {
+        Standby {
+            priority,
+            nonce,
+            key,
+            role: Role::Standby,
+            shared: Arc::new(Mutex::new(Shared {
+                role: Role::Standby,
+                states: BTreeMap::new(),
+                peer: None,
+                taken: BTreeMap::new(),
+                hold_until: Instant::now() + Duration::from_secs(PEER_HOLD),
+                took_over: None,
+            })),
+            takeovers: 0,
+            stand_downs: 0,
+        }
+    }
+
+    /// Creates another member of the election of the `Standby` for another capture, which starts
+    /// in the standby role and follows the heartbeats of the `Standby`.
+    pub fn join(&self, key: String) -> Standby {
+        Standby {
+            priority: self.priority,
+            nonce: self.nonce,
+            key,
+            role: Role::Standby,
+            shared: Arc::clone(&self.shared),
+            takeovers: 0,
+            stand_downs: 0,
+        }
+    }
+
+    /// Binds the address and spawns the threads receiving heartbeats on it and sending
+    /// heartbeats to the peer.
+    pub fn start(
+        &mut self,
+        threads: &Arc<Threads>,
+        listen: SocketAddr,
+        peer: SocketAddr,
+    ) -> io::Result<()> {
+        let listener = TcpListener::bind(listen)?;
+        let shared = Arc::clone(&self.shared);
+        Threads::spawn(
+            threads,
+            String::from("standby receive"),
+            Purpose::Standby,
+            move || {
+                for stream in listener.incoming() {
+                    let result = stream.and_then(|stream| receive(stream, &shared));
+                    if let Err(ref e) = result {
+                        debug!("standby receive: {}", e);
+                    }
+                }
+            },
+        )?;
+
+        let shared = Arc::clone(&self.shared);
+        let rank = (self.priority, self.nonce);
+        Threads::spawn(
+            threads,
+            String::from("standby send"),
+            Purpose::Standby,
+            move || send(peer, rank, &shared),
+        )?;
+        self.shared.lock().unwrap().hold_until = Instant::now() + Duration::from_secs(PEER_HOLD);
+        info!("Pair with {} on {}", peer, listen);
+
+        Ok(())
+    }
+
+    /// Get the role of the member, which follows the role of the instance once elected.
+    pub fn get_role(&self) -> Role {
+        self.role
+    }
+
+    /// Sets the state of the member replicated to the peer.
+    pub fn set_state(&self, state: String) {
+        self.shared
+            .lock()
+            .unwrap()
+            .states
+            .insert(self.key.clone(), state);
+    }
+
+    /// Get the number of times the instance took over and stood down.
+    pub fn get_transitions(&self) -> (usize, usize) {
+        (self.takeovers, self.stand_downs)
+    }
+
+    /// Decides the role of the instance from the heartbeats of the peer, and returns the change of
+    /// the member if any. An instance without a live peer takes over once the hold time after
+    /// starting, or after standing down for an ARP conflict, is over. Whichever member elects first
+    /// changes the role of the instance, and the others follow when they elect.
+    pub fn elect(&mut self) -> Option<Transition> {
+        let mut shared = self.shared.lock().unwrap();
+        let rank = (self.priority, self.nonce);
+        let peer = shared
+            .peer
+            .as_ref()
+            .filter(|peer| peer.seen.elapsed().as_secs() < PEER_HOLD)
+            .map(|peer| (peer.role, peer.rank));
+
+        match (shared.role, peer) {
+            (Role::Standby, None) if Instant::now() >= shared.hold_until => {
+                shared.taken = shared
+                    .peer
+                    .take()
+                    .map(|peer| peer.states)
+                    .unwrap_or_default();
+                shared.role = Role::Active;
+                shared.took_over = Some(Instant::now());
+            }
+            (Role::Standby, Some((Role::Standby, peer_rank))) if rank > peer_rank => {
+                shared.taken.clear();
+                shared.role = Role::Active;
+                shared.took_over = Some(Instant::now());
+            }
+            (Role::Active, Some((Role::Active, peer_rank))) if rank < peer_rank => {
+                shared.role = Role::Standby;
+            }
+            _ => {}
+        };
+
+        if self.role == shared.role {
+            return None;
+        }
+        self.role = shared.role;
+        match self.role {
+            Role::Active => {
+                self.takeovers += 1;
+                Some(Transition::TakeOver(shared.taken.remove(&self.key)))
+            }
+            Role::Standby => {
+                self.stand_downs += 1;
+                Some(Transition::StandDown)
+            }
+        }
+    }
+
+    /// Reports another hardware address claiming the gateway of the member. The instance stands
+    /// down if it took over recently, as the previous active instance still answers, or keeps the
+    /// gateway and should announce it again otherwise.
+    pub fn report_conflict(&mut self) -> Option<Transition> {
+        let mut shared = self.shared.lock().unwrap();
+        if self.role != Role::Active || shared.role != Role::Active {
+            return None;
+        }
+        match shared.took_over {
+            Some(instant) if instant.elapsed().as_secs() < TAKEOVER_GUARD => {
+                shared.role = Role::Standby;
+                shared.hold_until = Instant::now() + Duration::from_secs(CONFLICT_HOLD);
+                self.role = Role::Standby;
+                self.stand_downs += 1;
+
+                Some(Transition::StandDown)
+            }
+            _ => None,
+        }
+    }
+}
+
+fn receive(mut stream: TcpStream, shared: &Mutex<Shared>) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(PEER_HOLD)))?;
+    loop {
+        let message = Message::read(&mut stream)?;
+        if message.message_type != MessageType::Heartbeat {
+            continue;
+        }
+        let payload = &message.payload;
+        let role = match payload.get("role").and_then(|role| role.as_str()) {
+            Some("active") => Role::Active,
+            Some("standby") => Role::Standby,
+            _ => continue,
+        };
+        let priority = payload
+            .get("priority")
+            .and_then(|priority| priority.as_u64())
+            .unwrap_or(0);
+        let nonce = payload
+            .get("nonce")
+            .and_then(|nonce| nonce.as_u64())
+            .unwrap_or(0);
+        let states = match payload.get("state") {
+            Some(Value::Map(map)) => map
+                .iter()
+                .filter_map(|(key, state)| {
+                    state
+                        .as_str()
+                        .map(|state| (key.clone(), String::from(state)))
+                })
+                .collect(),
+            _ => BTreeMap::new(),
+        };
+
+        let mut shared = shared.lock().unwrap();
+        // The state of a standby peer may be stale, the last one of an active peer is kept
+        let states = match (role, shared.peer.take()) {
+            (Role::Standby, Some(prev)) => prev.states,
+            _ => states,
+        };
+        shared.peer = Some(Peer {
+            seen: Instant::now(),
+            role,
+            rank: (priority as u32, nonce),
+            states,
+        });
+    }
+}
+
+fn send(peer: SocketAddr, rank: (u32, u64), shared: &Mutex<Shared>) {
+    let mut stream: Option<TcpStream> = None;
+    let mut is_failed = false;
+    let mut failures = 0;
+    let mut retry_at = Instant::now();
+    loop {
+        thread::sleep(Duration::from_secs(HEARTBEAT_INTERVAL));
+
+        let message = {
+            let shared = shared.lock().unwrap();
+            let mut map = BTreeMap::new();
+            map.insert(String::from("role"), Value::Str(shared.role.to_string()));
+            map.insert(String::from("priority"), Value::Uint(rank.0 as u64));
+            map.insert(String::from("nonce"), Value::Uint(rank.1));
+            if shared.role == Role::Active {
+                let states = shared
+                    .states
+                    .iter()
+                    .map(|(key, state)| (key.clone(), Value::Str(state.clone())))
+                    .collect();
+                map.insert(String::from("state"), Value::Map(states));
+            }
+
+            Message::new(MessageType::Heartbeat, Value::Map(map))
+        };
+
+        if stream.is_none() {
+            if Instant::now() < retry_at {
+                continue;
+            }
+            match TcpStream::connect_timeout(&peer, Duration::from_secs(HEARTBEAT_INTERVAL)) {
+                Ok(s) => {
+                    let _ = s.set_write_timeout(Some(Duration::from_secs(PEER_HOLD)));
+                    let _ = s.set_nodelay(true);
+                    stream = Some(s);
+                    failures = 0;
+                    if is_failed {
+                        is_failed = false;
+                        info!("Peer {} is reachable", peer);
+                    }
+                }
+                Err(ref e) => {
+                    // Connects to a peer which is down back off, but never longer than the hold,
+                    // or the peer back would miss the heartbeats and take over as well
+                    let delay = backoff::get_delay(
+                        Site::Standby,
+                        Duration::from_secs(HEARTBEAT_INTERVAL),
+                        failures,
+                    );
+                    retry_at = Instant::now()
+                        + min(delay, Duration::from_secs(PEER_HOLD - HEARTBEAT_INTERVAL));
+                    failures += 1;
+                    if !is_failed {
+                        is_failed = true;
+                        warn!("standby send to {}: {}", peer, e);
+                    }
+                    continue;
+                }
+            }
+        }
+        if let Some(ref mut s) = stream {
+            if let Err(ref e) = message.write(s) {
+                debug!("standby send to {}: {}", peer, e);
+                stream = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    fn new_peer(role: Role, rank: (u32, u64), states: &[(&str, &str)]) -> Peer {
+        Peer {
+            seen: Instant::now(),
+            role,
+            rank,
+            states: states
+                .iter()
+                .map(|(key, state)| (String::from(*key), String::from(*state)))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_members_follow_election() {
+        let mut first = Standby::new(1, 0, String::from("10.6.0.2"));
+        let mut second = first.join(String::from("10.6.1.2"));
+        assert_eq!(first.elect(), None);
+        assert_eq!(second.elect(), None);
+
+        // A higher active peer fails with the states of both captures replicated
+        first.shared.lock().unwrap().peer = Some(new_peer(
+            Role::Active,
+            (2, 0),
+            &[("10.6.0.2", "first"), ("10.6.1.2", "second")],
+        ));
+        assert_eq!(first.elect(), None);
+        {
+            let mut shared = first.shared.lock().unwrap();
+            shared.peer.as_mut().unwrap().seen -= Duration::from_secs(PEER_HOLD);
+            shared.hold_until = Instant::now();
+        }
+        assert_eq!(
+            second.elect(),
+            Some(Transition::TakeOver(Some(String::from("second"))))
+        );
+        assert_eq!(
+            first.elect(),
+            Some(Transition::TakeOver(Some(String::from("first"))))
+        );
+        assert_eq!(first.elect(), None);
+        assert_eq!(second.get_role(), Role::Active);
+
+        // Both members replicate their states
+        first.set_state(String::from("first 2"));
+        second.set_state(String::from("second 2"));
+        assert_eq!(first.shared.lock().unwrap().states.len(), 2);
+
+        // The peer back and preferred stands the instance down
+        first.shared.lock().unwrap().peer = Some(new_peer(Role::Active, (2, 0), &[]));
+        assert_eq!(first.elect(), Some(Transition::StandDown));
+        assert_eq!(second.elect(), Some(Transition::StandDown));
+        assert_eq!(first.get_transitions(), (1, 1));
+        assert_eq!(second.get_transitions(), (1, 1));
+    }
+
+    #[test]
+    fn test_member_conflict() {
+        let mut first = Standby::new(1, 0, String::from("10.6.0.2"));
+        let mut second = first.join(String::from("10.6.1.2"));
+        first.shared.lock().unwrap().hold_until = Instant::now();
+        assert_eq!(first.elect(), Some(Transition::TakeOver(None)));
+        // A member still in standby has no gateway in conflict
+        assert_eq!(second.report_conflict(), None);
+        assert_eq!(second.elect(), Some(Transition::TakeOver(None)));
+
+        // A conflict of a capture soon after the takeover stands all of them down
+        assert_eq!(second.report_conflict(), Some(Transition::StandDown));
+        assert_eq!(first.elect(), Some(Transition::StandDown));
+        assert_eq!(first.elect(), None);
+        assert_eq!(first.get_role(), Role::Standby);
+    }
+
+    /// Relays the heartbeats to the target until the relay fails, where the connections are
+    /// dropped and new ones are refused, like the instance sending them failed.
+    fn relay(target: SocketAddr, is_failed: Arc<AtomicBool>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        listener.set_nonblocking(true).unwrap();
+        thread::spawn(move || loop {
+            let mut inbound = match listener.accept() {
+                Ok((inbound, _)) if !is_failed.load(Ordering::SeqCst) => inbound,
+                Ok(_) => continue,
+                Err(_) => {
+                    thread::sleep(Duration::from_millis(10));
+                    continue;
+                }
+            };
+            let is_failed = Arc::clone(&is_failed);
+            thread::spawn(move || {
+                let mut outbound = TcpStream::connect(target).unwrap();
+                inbound.set_nonblocking(false).unwrap();
+                inbound
+                    .set_read_timeout(Some(Duration::from_millis(10)))
+                    .unwrap();
+                let mut buffer = [0u8; 4096];
+                while !is_failed.load(Ordering::SeqCst) {
+                    match inbound.read(&mut buffer) {
+                        Ok(0) => break,
+                        Ok(size) => {
+                            if outbound.write_all(&buffer[..size]).is_err() {
+                                break;
+                            }
+                        }
+                        Err(ref e)
+                            if e.kind() == io::ErrorKind::WouldBlock
+                                || e.kind() == io::ErrorKind::TimedOut => {}
+                        Err(_) => break,
+                    }
+                }
+            });
+        });
+
+        addr
+    }
+
+    /// Elects both instances until the condition holds, for at most the given seconds, and
+    /// returns the transitions of the second instance.
+    fn elect_until<F: Fn(&Standby, &Standby) -> bool>(
+        first: &mut Standby,
+        second: &mut Standby,
+        secs: u64,
+        f: F,
+    ) -> Vec<Transition> {
+        let instant = Instant::now();
+        let mut transitions = Vec::new();
+        while !f(first, second) {
+            assert!(instant.elapsed() < Duration::from_secs(secs));
+            thread::sleep(Duration::from_millis(50));
+            let _ = first.elect();
+            transitions.extend(second.elect());
+        }
+
+        transitions
+    }
+
+    #[test]
+    fn test_takeover() {
+        let threads = Arc::new(Threads::new(usize::MAX));
+        let key = String::from("10.6.0.2");
+        let first_listen = TcpListener::bind("127.0.0.1:0").unwrap();
+        let first_addr = first_listen.local_addr().unwrap();
+        let second_listen = TcpListener::bind("127.0.0.1:0").unwrap();
+        let second_addr = second_listen.local_addr().unwrap();
+        drop((first_listen, second_listen));
+
+        // The heartbeats of the first instance reach the second through the relay
+        let is_failed = Arc::new(AtomicBool::new(false));
+        let relay_addr = relay(second_addr, Arc::clone(&is_failed));
+        let mut first = Standby::new(2, 0, key.clone());
+        let mut second = Standby::new(1, 0, key);
+        first.start(&threads, first_addr, relay_addr).unwrap();
+        second.start(&threads, second_addr, first_addr).unwrap();
+
+        // The higher priority is elected, and replicates its state
+        first.set_state(String::from("replicated"));
+        let transitions = elect_until(&mut first, &mut second, 10, |first, second| {
+            let shared = second.shared.lock().unwrap();
+            first.get_role() == Role::Active
+                && shared
+                    .peer
+                    .as_ref()
+                    .is_some_and(|peer| peer.states.contains_key("10.6.0.2"))
+        });
+        assert!(transitions.is_empty());
+        assert_eq!(second.get_role(), Role::Standby);
+
+        // The first instance fails, and the second takes over with the replicated state
+        is_failed.store(true, Ordering::SeqCst);
+        let instant = Instant::now();
+        let transitions = elect_until(&mut first, &mut second, 10, |_, second| {
+            second.get_role() == Role::Active
+        });
+        assert!(instant.elapsed() >= Duration::from_secs(PEER_HOLD - HEARTBEAT_INTERVAL));
+        assert_eq!(
+            transitions,
+            vec![Transition::TakeOver(Some(String::from("replicated")))]
+        );
+
+        // The first instance back keeps the gateway, and the second stands down
+        is_failed.store(false, Ordering::SeqCst);
+        let transitions = elect_until(&mut first, &mut second, 10, |_, second| {
+            second.get_role() == Role::Standby
+        });
+        assert_eq!(transitions, vec![Transition::StandDown]);
+        assert_eq!(first.get_role(), Role::Active);
+        assert_eq!(first.get_transitions(), (1, 0));
+        assert_eq!(second.get_transitions(), (1, 1));
+    }
+}
@@ -0,0 +1,461 @@
+use std::fmt::Write;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{self, AtomicU64, Ordering};
+
+/// Represents the magic at the start of the region.
+const MAGIC: &[u8; 8] = b"P2SSTATS";
+
+/// Represents the version of the layout of the region.
+pub const VERSION: u64 = 3;
+
+/// Represents the names of the aggregate counters, in the order of the layout.
+pub const COUNTERS: [&str; 18] = [
+    "tcp streams",
+    "udp associations",
+    "tcp out of order",
+    "tcp duplicates",
+    "tcp fast retransmissions",
+    "tcp retransmission timeouts",
+    "tcp retransmitted bytes",
+    "tcp keep-alives",
+    "tcp dropped duplicates",
+    "tcp simultaneous opens",
+    "tcp pauses",
+    "arp suppressed",
+    "repairs",
+    "refused by flows",
+    "refused by rate",
+    "quarantines",
+    "dropped before flows",
+    "threads",
+];
+
+/// Represents the max number of flows in the region.
+pub const TOP_FLOWS: usize = 32;
+
+/// Represents the offset of the sequence of the seqlock, odd while the region is written.
+const SEQUENCE_OFFSET: usize = 16;
+
+/// Represents the offset of the time of the last update in seconds since the UNIX epoch.
+const UPDATED_OFFSET: usize = 24;
+
+/// Represents the offset of the aggregate counters.
+const COUNTERS_OFFSET: usize = 32;
+
+/// Represents the offset of the number of flows.
+const FLOW_COUNT_OFFSET: usize = COUNTERS_OFFSET + 8 * COUNTERS.len();
+
+/// Represents the offset of the flows.
+const FLOWS_OFFSET: usize = FLOW_COUNT_OFFSET + 8;
+
+/// Represents the size of a flow.
+const FLOW_SIZE: usize = 32;
+
+/// Represents the size of the region.
+pub const REGION_SIZE: usize = FLOWS_OFFSET + FLOW_SIZE * TOP_FLOWS;
+
+/// Represents the max number of retries of a read racing with writes.
+const READ_RETRIES: usize = 1000;
+
+/// Represents the statistics of a flow in the region.
+#[derive(Clone, Copy, Debug)]
+pub struct FlowStats {
+    /// Represents the protocol, 6 for TCP and 17 for UDP.
+    pub protocol: u8,
+    pub src_port: u16,
+    pub dst: SocketAddrV4,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    /// Represents the index of the rule admitting the flow, in the order of `--rule`.
+    pub rule: Option<u16>,
+    /// Represents the index of the window of the rule the flow is admitted in.
+    pub window: Option<u16>,
+}
+
+impl FlowStats {
+    fn encode(&self) -> [u64; 4] {
+        let ip = u32::from(*self.dst.ip()) as u64;
+        // Indexes are stored from 1, so 0 is none
+        let rule = self.rule.map_or(0, |rule| rule as u64 + 1);
+        let window = self.window.map_or(0, |window| window as u64 + 1);
+        [
+            (self.protocol as u64) << 48 | (self.src_port as u64) << 32 | ip,
+            window << 32 | rule << 16 | self.dst.port() as u64,
+            self.bytes_up,
+            self.bytes_down,
+        ]
+    }
+
+    fn decode(words: [u64; 4]) -> FlowStats {
+        let index = |bits: u64| match bits as u16 {
+            0 => None,
+            index => Some(index - 1),
+        };
+        FlowStats {
+            protocol: (words[0] >> 48) as u8,
+            src_port: (words[0] >> 32) as u16,
+            dst: SocketAddrV4::new(Ipv4Addr::from(words[0] as u32), words[1] as u16),
+            bytes_up: words[2],
+            bytes_down: words[3],
+            rule: index(words[1] >> 16),
+            window: index(words[1] >> 32),
+        }
+    }
+}
+
+/// Represents a snapshot read from the region.
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    pub version: u64,
+    pub updated: u64,
+    pub counters: Vec<u64>,
+    pub flows: Vec<FlowStats>,
+}
+
+impl Snapshot {
+    /// Get the snapshot in text.
+    pub fn report(&self) -> String {
+        format!("{}{}", self.report_counters(), self.report_flows())
+    }
+
+    /// Get the counters of the snapshot in text.
+    pub fn report_counters(&self) -> String {
+        let mut s = String::new();
+        let _ = writeln!(s, "Version {}, updated at {}", self.version, self.updated);
+        for (name, value) in COUNTERS.iter().zip(self.counters.iter()) {
+            let _ = writeln!(s, "    {:<26} {}", name, value);
+        }
+
+        s
+    }
+
+    /// Get the top flows of the snapshot in text.
+    pub fn report_flows(&self) -> String {
+        let mut s = String::new();
+        let _ = writeln!(s, "Top flows:");
+        for flow in &self.flows {
+            let _ = write!(
+                s,
+                "    {} {:<5} -> {:<21} {} Bytes up, {} Bytes down",
+                match flow.protocol {
+                    6 => "tcp",
+                    17 => "udp",
+                    _ => "?",
+                },
+                flow.src_port,
+                flow.dst,
+                flow.bytes_up,
+                flow.bytes_down
+            );
+            // Rules and windows are numbered from 1 as in the command line
+            if let Some(rule) = flow.rule {
+                let _ = write!(s, ", rule #{}", rule + 1);
+                if let Some(window) = flow.window {
+                    let _ = write!(s, " window #{}", window + 1);
+                }
+            }
+            s.push('\n');
+        }
+
+        s
+    }
+}
+
+/// Represents a memory-mapped file exporting statistics to external readers. The region has a
+/// fixed layout of native-endian 64-bit words, see `README.md`, and is protected by a seqlock, so
+/// writes never wait for readers and readers retry reads racing with writes.
+pub struct Export {
+    region: Region,
+}
+
+impl Export {
+    /// Creates a new `Export` at the given path, which is truncated and mapped.
+    pub fn create(path: &Path) -> io::Result<Export> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        file.set_len(REGION_SIZE as u64)?;
+        let region = Region::map(file, true)?;
+
+        unsafe {
+            ptr::copy_nonoverlapping(MAGIC.as_ptr(), region.ptr as *mut u8, MAGIC.len());
+            region.write(8, VERSION);
+        }
+        // A region left by a process killed in writing has an odd sequence
+        region.get_sequence().store(0, Ordering::Release);
+
+        Ok(Export { region })
+    }
+
+    /// Writes the counters and the flows to the region. Flows beyond `TOP_FLOWS` are ignored.
+    pub fn write(&mut self, updated: u64, counters: &[u64], flows: &[FlowStats]) {
+        let region = &self.region;
+        let sequence = region.get_sequence();
+        let begin = sequence.load(Ordering::Relaxed).wrapping_add(1);
+        sequence.store(begin, Ordering::Relaxed);
+        atomic::fence(Ordering::Release);
+
+        unsafe {
+            region.write(UPDATED_OFFSET, updated);
+            for i in 0..COUNTERS.len() {
+                region.write(COUNTERS_OFFSET + 8 * i, *counters.get(i).unwrap_or(&0));
+            }
+            let count = flows.len().min(TOP_FLOWS);
+            region.write(FLOW_COUNT_OFFSET, count as u64);
+            for (i, flow) in flows.iter().take(count).enumerate() {
+                for (j, word) in flow.encode().iter().enumerate() {
+                    region.write(FLOWS_OFFSET + FLOW_SIZE * i + 8 * j, *word);
+                }
+            }
+        }
+
+        sequence.store(begin.wrapping_add(1), Ordering::Release);
+    }
+}
+
+/// Reads a consistent snapshot from the region at the given path.
+pub fn read(path: &Path) -> io::Result<Snapshot> {
+    let file = File::open(path)?;
+    if file.metadata()?.len() < REGION_SIZE as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "region is too small",
+        ));
+    }
+    let region = Region::map(file, false)?;
+
+    let mut magic = [0u8; 8];
+    unsafe { ptr::copy_nonoverlapping(region.ptr as *const u8, magic.as_mut_ptr(), magic.len()) };
+    if &magic != MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a region"));
+    }
+    let version = unsafe { region.read(8) };
+    if version != VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported version {}", version),
+        ));
+    }
+
+    let sequence = region.get_sequence();
+    for _ in 0..READ_RETRIES {
+        let begin = sequence.load(Ordering::Acquire);
+        if begin % 2 == 1 {
+            // Being written
+            std::thread::yield_now();
+            continue;
+        }
+
+        let (updated, counters, flows) = unsafe {
+            let updated = region.read(UPDATED_OFFSET);
+            let counters: Vec<u64> = (0..COUNTERS.len())
+                .map(|i| region.read(COUNTERS_OFFSET + 8 * i))
+                .collect();
+            let count = (region.read(FLOW_COUNT_OFFSET) as usize).min(TOP_FLOWS);
+            let flows: Vec<FlowStats> = (0..count)
+                .map(|i| {
+                    let offset = FLOWS_OFFSET + FLOW_SIZE * i;
+                    FlowStats::decode([
+                        region.read(offset),
+                        region.read(offset + 8),
+                        region.read(offset + 16),
+                        region.read(offset + 24),
+                    ])
+                })
+                .collect();
+
+            (updated, counters, flows)
+        };
+
+        atomic::fence(Ordering::Acquire);
+        if sequence.load(Ordering::Relaxed) == begin {
+            return Ok(Snapshot {
+                version,
+                updated,
+                counters,
+                flows,
+            });
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        "region is always being written",
+    ))
+}
+
+/// Represents a mapped file.
+struct Region {
+    /// Represents the start of the region, which is aligned to a page.
+    ptr: *mut u64,
+    // Keeps the file open while mapped
+    _file: File,
+}
+
+// The region is only written by its owner
+unsafe impl Send for Region {}
+
+impl Region {
+    fn get_sequence(&self) -> &AtomicU64 {
+        unsafe { &*(self.ptr.add(SEQUENCE_OFFSET / 8) as *const AtomicU64) }
+    }
+
+    unsafe fn write(&self, offset: usize, value: u64) {
+        ptr::write_volatile(self.ptr.add(offset / 8), value);
+    }
+
+    unsafe fn read(&self, offset: usize) -> u64 {
+        ptr::read_volatile(self.ptr.add(offset / 8))
+    }
+}
+
+#[cfg(unix)]
+impl Region {
+    fn map(file: File, is_write: bool) -> io::Result<Region> {
+        use std::os::unix::io::AsRawFd;
+
+        let prot = if is_write {
+            libc::PROT_READ | libc::PROT_WRITE
+        } else {
+            libc::PROT_READ
+        };
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                REGION_SIZE,
+                prot,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Region {
+            ptr: ptr as *mut u64,
+            _file: file,
+        })
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Region {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, REGION_SIZE) };
+    }
+}
+
+#[cfg(not(unix))]
+impl Region {
+    fn map(_: File, _: bool) -> io::Result<Region> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "memory-mapped statistics are not supported",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flow_label() {
+        let mut flow = FlowStats {
+            protocol: 17,
+            src_port: 3074,
+            dst: SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 1), 65535),
+            bytes_up: 1,
+            bytes_down: 2,
+            rule: None,
+            window: None,
+        };
+        let words = flow.encode();
+        // Flows without rules are encoded as before
+        assert_eq!(words[1], 65535);
+        let decoded = FlowStats::decode(words);
+        assert_eq!(decoded.rule, None);
+        assert_eq!(decoded.window, None);
+
+        flow.rule = Some(0);
+        flow.window = Some(2);
+        let decoded = FlowStats::decode(flow.encode());
+        assert_eq!(decoded.dst, flow.dst);
+        assert_eq!(decoded.rule, Some(0));
+        assert_eq!(decoded.window, Some(2));
+
+        let snapshot = Snapshot {
+            version: VERSION,
+            updated: 0,
+            counters: vec![0; COUNTERS.len()],
+            flows: vec![decoded],
+        };
+        assert!(snapshot
+            .report()
+            .contains("1 Bytes up, 2 Bytes down, rule #1 window #3\n"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_torn_read() {
+        use std::sync::atomic::AtomicBool;
+        use std::sync::Arc;
+        use std::thread;
+
+        let path = std::env::temp_dir().join(format!("pcap2socks-stats-{}", std::process::id()));
+        let mut export = Export::create(&path).unwrap();
+        let is_done = Arc::new(AtomicBool::new(false));
+
+        // Every write stores the same value in all words, so a torn read mixes values
+        let writer = {
+            let is_done = Arc::clone(&is_done);
+            thread::spawn(move || {
+                let mut value = 0u64;
+                while !is_done.load(Ordering::Relaxed) {
+                    value = value.wrapping_add(1);
+                    let flow = FlowStats {
+                        protocol: 6,
+                        src_port: value as u16,
+                        dst: SocketAddrV4::new(Ipv4Addr::from(value as u32), value as u16),
+                        bytes_up: value,
+                        bytes_down: value,
+                        rule: None,
+                        window: None,
+                    };
+                    export.write(value, &vec![value; COUNTERS.len()], &[flow; TOP_FLOWS]);
+                }
+            })
+        };
+
+        let mut reads = 0;
+        while reads < 2000 {
+            let snapshot = match read(&path) {
+                Ok(snapshot) => snapshot,
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(e) => panic!("{}", e),
+            };
+            let value = snapshot.updated;
+            assert!(snapshot.counters.iter().all(|counter| *counter == value));
+            for flow in &snapshot.flows {
+                assert_eq!(flow.bytes_up, value);
+                assert_eq!(flow.bytes_down, value);
+                assert_eq!(flow.src_port, value as u16);
+                assert_eq!(*flow.dst.ip(), Ipv4Addr::from(value as u32));
+            }
+            reads += 1;
+        }
+
+        is_done.store(true, Ordering::Relaxed);
+        writer.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+}
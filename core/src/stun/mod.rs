@@ -0,0 +1,661 @@
+use log::debug;
+use std::cmp::min;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::Duration;
+
+use crate::random::{generate_seed, Random};
+
+/// Represents the default port of STUN servers.
+pub const STUN_PORT: u16 = 3478;
+
+/// Represents the magic cookie of STUN messages.
+const MAGIC_COOKIE: u32 = 0x2112_a442;
+
+/// Represents the size of the header of STUN messages.
+const HEADER_SIZE: usize = 20;
+
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_RESPONSE: u16 = 0x0101;
+
+const ATTRIBUTE_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTRIBUTE_CHANGE_REQUEST: u16 = 0x0003;
+/// Represents the alternative address of servers of RFC 3489.
+const ATTRIBUTE_CHANGED_ADDRESS: u16 = 0x0005;
+const ATTRIBUTE_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+/// Represents the alternative address of servers of RFC 5780.
+const ATTRIBUTE_OTHER_ADDRESS: u16 = 0x802c;
+
+const FAMILY_IPV4: u8 = 0x01;
+
+/// Represents the time waiting for a response before a retransmission.
+const RESPONSE_TIMEOUT: u64 = 1;
+
+/// Represents the number of times a request is sent.
+const ATTEMPTS: usize = 3;
+
+/// Parses a STUN server in the form of `<IP>` or `<IP:PORT>` with port 3478 by default.
+pub fn parse_server(s: &str) -> Option<SocketAddrV4> {
+    match s.parse::<Ipv4Addr>() {
+        Ok(ip_addr) => Some(SocketAddrV4::new(ip_addr, STUN_PORT)),
+        Err(_) => s.parse().ok(),
+    }
+}
+
+/// Returns if the datagram is a STUN message, which is checked by its header only. Messages of
+/// RFC 3489 without the magic cookie are not recognized.
+pub fn is_stun(buffer: &[u8]) -> bool {
+    buffer.len() >= HEADER_SIZE
+        && buffer[0] & 0xc0 == 0
+        && (read_u16(buffer, 2) as usize).is_multiple_of(4)
+        && read_u32(buffer, 4) == MAGIC_COOKIE
+}
+
+/// Creates a binding request of the transaction, which asks the server to respond from its
+/// alternative IP address or port if they are set.
+pub fn new_binding_request(transaction: &[u8; 12], change_ip: bool, change_port: bool) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(HEADER_SIZE + 8);
+    buffer.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+    buffer.extend_from_slice(&0u16.to_be_bytes());
+    buffer.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    buffer.extend_from_slice(transaction);
+    if change_ip || change_port {
+        let flags = (if change_ip { 0x4u32 } else { 0 }) | (if change_port { 0x2 } else { 0 });
+        buffer.extend_from_slice(&ATTRIBUTE_CHANGE_REQUEST.to_be_bytes());
+        buffer.extend_from_slice(&4u16.to_be_bytes());
+        buffer.extend_from_slice(&flags.to_be_bytes());
+    }
+    let length = (buffer.len() - HEADER_SIZE) as u16;
+    buffer[2..4].copy_from_slice(&length.to_be_bytes());
+
+    buffer
+}
+
+/// Creates a binding response of the transaction with the mapped address of the client and the
+/// alternative address of the server, like servers of RFC 5780.
+pub fn new_binding_response(
+    transaction: &[u8; 12],
+    mapped: SocketAddrV4,
+    other: Option<SocketAddrV4>,
+) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(HEADER_SIZE + 24);
+    buffer.extend_from_slice(&BINDING_RESPONSE.to_be_bytes());
+    buffer.extend_from_slice(&0u16.to_be_bytes());
+    buffer.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    buffer.extend_from_slice(transaction);
+    write_address(&mut buffer, ATTRIBUTE_XOR_MAPPED_ADDRESS, mapped, true);
+    if let Some(other) = other {
+        write_address(&mut buffer, ATTRIBUTE_OTHER_ADDRESS, other, false);
+    }
+    let length = (buffer.len() - HEADER_SIZE) as u16;
+    buffer[2..4].copy_from_slice(&length.to_be_bytes());
+
+    buffer
+}
+
+/// Represents a binding request.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BindingRequest {
+    pub transaction: [u8; 12],
+    /// Represents if the response is asked from the alternative IP address.
+    pub change_ip: bool,
+    /// Represents if the response is asked from the alternative port.
+    pub change_port: bool,
+}
+
+impl BindingRequest {
+    /// Parses a binding request, returns `None` if the datagram is not one.
+    pub fn parse(buffer: &[u8]) -> Option<BindingRequest> {
+        if !is_stun(buffer) || read_u16(buffer, 0) != BINDING_REQUEST {
+            return None;
+        }
+        let mut request = BindingRequest {
+            transaction: [0u8; 12],
+            change_ip: false,
+            change_port: false,
+        };
+        request.transaction.copy_from_slice(&buffer[8..HEADER_SIZE]);
+        let end = min(HEADER_SIZE + read_u16(buffer, 2) as usize, buffer.len());
+        let mut pos = HEADER_SIZE;
+        while pos + 4 <= end {
+            let t = read_u16(buffer, pos);
+            let length = read_u16(buffer, pos + 2) as usize;
+            if t == ATTRIBUTE_CHANGE_REQUEST && length == 4 && pos + 8 <= end {
+                let flags = read_u32(buffer, pos + 4);
+                request.change_ip = flags & 0x4 != 0;
+                request.change_port = flags & 0x2 != 0;
+            }
+            pos += 4 + length.div_ceil(4) * 4;
+        }
+
+        Some(request)
+    }
+}
+
+/// Represents a binding response.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BindingResponse {
+    pub transaction: [u8; 12],
+    /// Represents the address of the client observed by the server.
+    pub mapped: Option<SocketAddrV4>,
+    /// Represents the alternative address of the server.
+    pub other: Option<SocketAddrV4>,
+}
+
+impl BindingResponse {
+    /// Parses a binding response, returns `None` if the datagram is not one.
+    pub fn parse(buffer: &[u8]) -> Option<BindingResponse> {
+        if !is_stun(buffer) || read_u16(buffer, 0) != BINDING_RESPONSE {
+            return None;
+        }
+        let mut transaction = [0u8; 12];
+        transaction.copy_from_slice(&buffer[8..HEADER_SIZE]);
+        let end = HEADER_SIZE + read_u16(buffer, 2) as usize;
+        if end > buffer.len() {
+            return None;
+        }
+
+        let mut response = BindingResponse {
+            transaction,
+            mapped: None,
+            other: None,
+        };
+        let mut mapped = None;
+        let mut pos = HEADER_SIZE;
+        while pos + 4 <= end {
+            let t = read_u16(buffer, pos);
+            let length = read_u16(buffer, pos + 2) as usize;
+            let value = &buffer[pos + 4..end.min(pos + 4 + length)];
+            match t {
+                ATTRIBUTE_XOR_MAPPED_ADDRESS => response.mapped = parse_address(value, true),
+                ATTRIBUTE_MAPPED_ADDRESS => mapped = parse_address(value, false),
+                ATTRIBUTE_OTHER_ADDRESS | ATTRIBUTE_CHANGED_ADDRESS => {
+                    response.other = parse_address(value, false)
+                }
+                _ => {}
+            }
+            // Values are padded to 4 Bytes
+            pos += 4 + length.div_ceil(4) * 4;
+        }
+        // Servers of RFC 3489 respond with the mapped address only
+        if response.mapped.is_none() {
+            response.mapped = mapped;
+        }
+
+        Some(response)
+    }
+}
+
+fn parse_address(value: &[u8], is_xor: bool) -> Option<SocketAddrV4> {
+    if value.len() < 8 || value[1] != FAMILY_IPV4 {
+        return None;
+    }
+    let mut port = read_u16(value, 2);
+    let mut ip_addr = read_u32(value, 4);
+    if is_xor {
+        port ^= (MAGIC_COOKIE >> 16) as u16;
+        ip_addr ^= MAGIC_COOKIE;
+    }
+
+    Some(SocketAddrV4::new(Ipv4Addr::from(ip_addr), port))
+}
+
+fn write_address(buffer: &mut Vec<u8>, attribute: u16, addr: SocketAddrV4, is_xor: bool) {
+    let mut port = addr.port();
+    let mut ip_addr = u32::from(*addr.ip());
+    if is_xor {
+        port ^= (MAGIC_COOKIE >> 16) as u16;
+        ip_addr ^= MAGIC_COOKIE;
+    }
+    buffer.extend_from_slice(&attribute.to_be_bytes());
+    buffer.extend_from_slice(&8u16.to_be_bytes());
+    buffer.extend_from_slice(&[0, FAMILY_IPV4]);
+    buffer.extend_from_slice(&port.to_be_bytes());
+    buffer.extend_from_slice(&ip_addr.to_be_bytes());
+}
+
+fn read_u16(buffer: &[u8], pos: usize) -> u16 {
+    u16::from_be_bytes([buffer[pos], buffer[pos + 1]])
+}
+
+fn read_u32(buffer: &[u8], pos: usize) -> u32 {
+    u32::from_be_bytes([
+        buffer[pos],
+        buffer[pos + 1],
+        buffer[pos + 2],
+        buffer[pos + 3],
+    ])
+}
+
+/// Trait for a UDP socket the NAT test is performed through.
+pub trait Transport {
+    /// Sends a datagram to the destination.
+    fn send_to(&self, buffer: &[u8], dst: SocketAddrV4) -> io::Result<usize>;
+
+    /// Receives a datagram and its source, with a timeout.
+    fn recv_from(&self, buffer: &mut [u8], timeout: Duration) -> io::Result<(usize, SocketAddrV4)>;
+}
+
+/// Represents the behavior of a NAT in mapping or filtering, in the terms of RFC 4787.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Behavior {
+    EndpointIndependent,
+    AddressDependent,
+    AddressAndPortDependent,
+    /// The server has no alternative address to tell the behavior.
+    Unknown,
+}
+
+impl Display for Behavior {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Behavior::EndpointIndependent => write!(f, "endpoint-independent"),
+            Behavior::AddressDependent => write!(f, "address-dependent"),
+            Behavior::AddressAndPortDependent => write!(f, "address and port-dependent"),
+            Behavior::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// Represents the result of a NAT test.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct NatReport {
+    /// Represents the external address observed by the server.
+    pub external: SocketAddrV4,
+    pub mapping: Behavior,
+    pub filtering: Behavior,
+    /// Represents if a datagram to the external address comes back.
+    pub hairpinning: bool,
+}
+
+impl NatReport {
+    /// Get the recommendation for the result.
+    pub fn get_recommendation(&self) -> &'static str {
+        match (self.mapping, self.filtering) {
+            (Behavior::Unknown, _) | (_, Behavior::Unknown) => {
+                "use a STUN server with an alternative address, like one of RFC 5780, to classify the NAT"
+            }
+            (Behavior::EndpointIndependent, Behavior::EndpointIndependent) => {
+                "the UDP relay is a full cone, peer-to-peer games and calls reach the device directly"
+            }
+            (Behavior::EndpointIndependent, _) => {
+                "the UDP relay keeps mappings but filters unknown peers, most peer-to-peer applications work after hole punching"
+            }
+            _ => {
+                "the UDP relay maps each destination apart, peer-to-peer applications fall back to relays, use a proxy with endpoint-independent mapping"
+            }
+        }
+    }
+}
+
+impl Display for NatReport {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        writeln!(f, "External address: {}", self.external)?;
+        writeln!(f, "Mapping: {}", self.mapping)?;
+        writeln!(f, "Filtering: {}", self.filtering)?;
+        writeln!(
+            f,
+            "Hairpinning: {}",
+            if self.hairpinning { "yes" } else { "no" }
+        )?;
+        write!(f, "Recommendation: {}", self.get_recommendation())
+    }
+}
+
+/// Represents a NAT test of RFC 5780 against a STUN server through a `Transport`. All the tests
+/// are from the same socket, so they are from the same association of the proxy.
+struct NatTest<'a, T: Transport> {
+    transport: &'a T,
+    rng: Random,
+}
+
+impl<'a, T: Transport> NatTest<'a, T> {
+    /// Sends a binding request and waits for its response, returns the response and its source,
+    /// or `None` if no response comes.
+    fn request(
+        &mut self,
+        server: SocketAddrV4,
+        change_ip: bool,
+        change_port: bool,
+    ) -> io::Result<Option<(BindingResponse, SocketAddrV4)>> {
+        let mut transaction = [0u8; 12];
+        transaction[..8].copy_from_slice(&self.rng.next_u64().to_be_bytes());
+        transaction[8..].copy_from_slice(&(self.rng.next_u64() as u32).to_be_bytes());
+        let request = new_binding_request(&transaction, change_ip, change_port);
+
+        let mut buffer = [0u8; 1500];
+        for _ in 0..ATTEMPTS {
+            self.transport.send_to(&request, server)?;
+            match self
+                .transport
+                .recv_from(&mut buffer, Duration::from_secs(RESPONSE_TIMEOUT))
+            {
+                Ok((size, src)) => {
+                    // Responses of earlier transactions are ignored
+                    if let Some(response) = BindingResponse::parse(&buffer[..size]) {
+                        if response.transaction == transaction {
+                            return Ok(Some((response, src)));
+                        }
+                    }
+                }
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => return Err(e),
+            }
+        }
+        debug!("STUN request to {} is not responded", server);
+
+        Ok(None)
+    }
+
+    /// Sends a binding request to the external address and returns if it comes back.
+    fn hairpin(&mut self, external: SocketAddrV4) -> io::Result<bool> {
+        let mut transaction = [0u8; 12];
+        transaction[..8].copy_from_slice(&self.rng.next_u64().to_be_bytes());
+        let request = new_binding_request(&transaction, false, false);
+
+        let mut buffer = [0u8; 1500];
+        for _ in 0..ATTEMPTS {
+            self.transport.send_to(&request, external)?;
+            match self
+                .transport
+                .recv_from(&mut buffer, Duration::from_secs(RESPONSE_TIMEOUT))
+            {
+                Ok((size, _)) => {
+                    if buffer[..size] == request[..] {
+                        return Ok(true);
+                    }
+                }
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(false)
+    }
+}
+
+/// Tests the NAT between the transport and the STUN server, with the tests of the mapping and the
+/// filtering of RFC 5780 and a test of hairpinning.
+pub fn test<T: Transport>(transport: &T, server: SocketAddrV4) -> io::Result<NatReport> {
+    let mut test = NatTest {
+        transport,
+        // Transaction IDs must be unpredictable, never reproduced by the global seed
+        rng: Random::new(generate_seed()),
+    };
+
+    // Test I
+    let (response, _) = match test.request(server, false, false)? {
+        Some(response) => response,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "STUN server does not respond, UDP may be blocked",
+            ))
+        }
+    };
+    let external = response.mapped.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "STUN response has no address")
+    })?;
+    let other = response.other.filter(|other| other.ip() != server.ip());
+
+    let (mapping, filtering) = match other {
+        Some(other) => {
+            // Filtering tests II and III, responses from the alternative IP address and port, or
+            // from the alternative port. They run before the mapping tests, which would open the
+            // filter of an endpoint-independent mapping to the alternative IP address
+            let filtering = if test.request(server, true, true)?.is_some() {
+                Behavior::EndpointIndependent
+            } else if test.request(server, false, true)?.is_some() {
+                Behavior::AddressDependent
+            } else {
+                Behavior::AddressAndPortDependent
+            };
+
+            // Mapping test II, to the alternative IP address and the primary port
+            let mapping =
+                match test.request(SocketAddrV4::new(*other.ip(), server.port()), false, false)? {
+                    Some((response, _)) if response.mapped == Some(external) => {
+                        Behavior::EndpointIndependent
+                    }
+                    Some((response, _)) => {
+                        // Mapping test III, to the alternative IP address and port
+                        let mapped = response.mapped;
+                        match test.request(other, false, false)? {
+                            Some((response, _)) if response.mapped == mapped => {
+                                Behavior::AddressDependent
+                            }
+                            Some(_) => Behavior::AddressAndPortDependent,
+                            None => Behavior::Unknown,
+                        }
+                    }
+                    None => Behavior::Unknown,
+                };
+
+            (mapping, filtering)
+        }
+        None => (Behavior::Unknown, Behavior::Unknown),
+    };
+
+    let hairpinning = test.hairpin(external)?;
+
+    Ok(NatReport {
+        external,
+        mapping,
+        filtering,
+        hairpinning,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::{HashMap, HashSet, VecDeque};
+
+    const EXTERNAL_IP_ADDR: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 7);
+
+    /// Get the primary address of the fake STUN server.
+    fn primary() -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 1), 3478)
+    }
+
+    /// Get the alternative address of the fake STUN server.
+    fn alternative() -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 2), 3479)
+    }
+
+    /// Represents a scripted NAT of the given behaviors in front of a fake STUN server of RFC
+    /// 5780, at the primary and the other address. The NAT maps the transport to external ports
+    /// from 40000, and filters the responses of the server by the destinations sent to.
+    struct FakeNat {
+        mapping: Behavior,
+        filtering: Behavior,
+        hairpinning: bool,
+        /// Represents if the server tells its alternative address.
+        has_other: bool,
+        mappings: RefCell<HashMap<SocketAddrV4, u16>>,
+        sent: RefCell<HashSet<SocketAddrV4>>,
+        queue: RefCell<VecDeque<(Vec<u8>, SocketAddrV4)>>,
+    }
+
+    impl FakeNat {
+        fn new(mapping: Behavior, filtering: Behavior, hairpinning: bool) -> FakeNat {
+            FakeNat {
+                mapping,
+                filtering,
+                hairpinning,
+                has_other: true,
+                mappings: RefCell::new(HashMap::new()),
+                sent: RefCell::new(HashSet::new()),
+                queue: RefCell::new(VecDeque::new()),
+            }
+        }
+
+        /// Get the external port the NAT maps the transport to for the destination.
+        fn map(&self, dst: SocketAddrV4) -> u16 {
+            let key = match self.mapping {
+                Behavior::EndpointIndependent => SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0),
+                Behavior::AddressDependent => SocketAddrV4::new(*dst.ip(), 0),
+                _ => dst,
+            };
+            let mut mappings = self.mappings.borrow_mut();
+            let next = 40000 + mappings.len() as u16;
+            *mappings.entry(key).or_insert(next)
+        }
+
+        /// Returns if the NAT lets a datagram from the source in.
+        fn is_allowed(&self, src: SocketAddrV4) -> bool {
+            let sent = self.sent.borrow();
+            match self.filtering {
+                Behavior::EndpointIndependent => true,
+                Behavior::AddressDependent => sent.iter().any(|dst| dst.ip() == src.ip()),
+                _ => sent.contains(&src),
+            }
+        }
+    }
+
+    impl Transport for FakeNat {
+        fn send_to(&self, buffer: &[u8], dst: SocketAddrV4) -> io::Result<usize> {
+            let external = SocketAddrV4::new(EXTERNAL_IP_ADDR, self.map(dst));
+            self.sent.borrow_mut().insert(dst);
+
+            if *dst.ip() == EXTERNAL_IP_ADDR {
+                if self.hairpinning {
+                    self.queue
+                        .borrow_mut()
+                        .push_back((buffer.to_vec(), external));
+                }
+            } else if dst == primary() || dst.ip() == alternative().ip() {
+                let request = BindingRequest::parse(buffer).unwrap();
+                let ip_addr = if (dst.ip() == alternative().ip()) != request.change_ip {
+                    *alternative().ip()
+                } else {
+                    *primary().ip()
+                };
+                let port = if (dst.port() == alternative().port()) != request.change_port {
+                    alternative().port()
+                } else {
+                    primary().port()
+                };
+                let src = SocketAddrV4::new(ip_addr, port);
+                let other = if self.has_other {
+                    Some(alternative())
+                } else {
+                    None
+                };
+                let response = new_binding_response(&request.transaction, external, other);
+                if self.is_allowed(src) {
+                    self.queue.borrow_mut().push_back((response, src));
+                }
+            }
+
+            Ok(buffer.len())
+        }
+
+        fn recv_from(&self, buffer: &mut [u8], _: Duration) -> io::Result<(usize, SocketAddrV4)> {
+            match self.queue.borrow_mut().pop_front() {
+                Some((datagram, src)) => {
+                    buffer[..datagram.len()].copy_from_slice(&datagram);
+                    Ok((datagram.len(), src))
+                }
+                None => Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")),
+            }
+        }
+    }
+
+    #[test]
+    fn test_message() {
+        let transaction = [7u8; 12];
+        let request = BindingRequest::parse(&new_binding_request(&transaction, true, false));
+        assert_eq!(
+            request,
+            Some(BindingRequest {
+                transaction,
+                change_ip: true,
+                change_port: false,
+            })
+        );
+
+        let mapped = SocketAddrV4::new(EXTERNAL_IP_ADDR, 40000);
+        let response = new_binding_response(&transaction, mapped, Some(alternative()));
+        assert!(BindingRequest::parse(&response).is_none());
+        assert_eq!(
+            BindingResponse::parse(&response),
+            Some(BindingResponse {
+                transaction,
+                mapped: Some(mapped),
+                other: Some(alternative()),
+            })
+        );
+        assert_eq!(parse_server("203.0.113.1"), Some(primary()));
+        assert_eq!(parse_server("203.0.113.2:3479"), Some(alternative()));
+        assert_eq!(parse_server("stun.example.com"), None);
+    }
+
+    #[test]
+    fn test_classification() {
+        let behaviors = [
+            Behavior::EndpointIndependent,
+            Behavior::AddressDependent,
+            Behavior::AddressAndPortDependent,
+        ];
+        for &mapping in behaviors.iter() {
+            for &filtering in behaviors.iter() {
+                let nat = FakeNat::new(mapping, filtering, false);
+                let report = test(&nat, primary()).unwrap();
+                assert_eq!(
+                    (report.mapping, report.filtering),
+                    (mapping, filtering),
+                    "NAT of {} mapping and {} filtering",
+                    mapping,
+                    filtering
+                );
+                // The first mapping is to the primary address
+                assert_eq!(report.external, SocketAddrV4::new(EXTERNAL_IP_ADDR, 40000));
+            }
+        }
+    }
+
+    #[test]
+    fn test_hairpinning() {
+        let nat = FakeNat::new(
+            Behavior::EndpointIndependent,
+            Behavior::EndpointIndependent,
+            true,
+        );
+        let report = test(&nat, primary()).unwrap();
+        assert!(report.hairpinning);
+        assert!(report.get_recommendation().contains("full cone"));
+
+        let nat = FakeNat::new(
+            Behavior::EndpointIndependent,
+            Behavior::EndpointIndependent,
+            false,
+        );
+        assert!(!test(&nat, primary()).unwrap().hairpinning);
+    }
+
+    #[test]
+    fn test_unknown() {
+        // A server without an alternative address cannot tell the behaviors
+        let mut nat = FakeNat::new(
+            Behavior::AddressAndPortDependent,
+            Behavior::AddressAndPortDependent,
+            false,
+        );
+        nat.has_other = false;
+        let report = test(&nat, primary()).unwrap();
+        assert_eq!(report.mapping, Behavior::Unknown);
+        assert_eq!(report.filtering, Behavior::Unknown);
+        assert!(report.get_recommendation().contains("alternative address"));
+
+        // A server which never responds is an error
+        let e = test(&nat, SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 3478)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+    }
+}
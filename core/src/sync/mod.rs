@@ -0,0 +1,102 @@
+//! Synchronization primitives shared by workers, their threads and the poller, which are the
+//! types of `loom` when built with `--cfg loom`, so their models explore every interleaving. The
+//! `tx` of the source stays in `std`, since `loom` has no mutex of an unsized `Forward`, and so do
+//! the statics, which `loom` cannot create in constant expressions.
+//!
+//! Models are run with `RUSTFLAGS="--cfg loom" cargo test -p pcap2socks-core --release loom`.
+
+#[cfg(loom)]
+pub use loom::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, AtomicUsize, Ordering};
+#[cfg(loom)]
+pub use loom::sync::{Arc, Mutex};
+
+#[cfg(not(loom))]
+pub use std::sync::atomic::{AtomicBool, AtomicU16, AtomicUsize, Ordering};
+#[cfg(not(loom))]
+pub use std::sync::Mutex;
+
+/// Channels between threads.
+#[cfg(not(loom))]
+pub mod mpsc {
+    pub use std::sync::mpsc::{channel, Receiver, TryRecvError};
+}
+
+/// Channels between threads. The channel of `loom` cannot be polled without blocking, so the one
+/// for models is a queue in a mutex of `loom`, with the API of `std` used by the workers.
+#[cfg(loom)]
+pub mod mpsc {
+    use std::collections::VecDeque;
+    pub use std::sync::mpsc::{SendError, TryRecvError};
+
+    use super::{Arc, Mutex};
+
+    struct Channel<T> {
+        queue: VecDeque<T>,
+        senders: usize,
+        is_receiving: bool,
+    }
+
+    /// Represents the sending side of a channel.
+    pub struct Sender<T>(Arc<Mutex<Channel<T>>>);
+
+    /// Represents the receiving side of a channel.
+    pub struct Receiver<T>(Arc<Mutex<Channel<T>>>);
+
+    /// Creates a new channel.
+    pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+        let channel = Arc::new(Mutex::new(Channel {
+            queue: VecDeque::new(),
+            senders: 1,
+            is_receiving: true,
+        }));
+
+        (Sender(Arc::clone(&channel)), Receiver(channel))
+    }
+
+    impl<T> Sender<T> {
+        /// Sends the value, which is returned if the receiver is dropped.
+        pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+            let mut channel_locked = self.0.lock().unwrap();
+            if !channel_locked.is_receiving {
+                return Err(SendError(value));
+            }
+            channel_locked.queue.push_back(value);
+
+            Ok(())
+        }
+    }
+
+    impl<T> Clone for Sender<T> {
+        fn clone(&self) -> Sender<T> {
+            self.0.lock().unwrap().senders += 1;
+
+            Sender(Arc::clone(&self.0))
+        }
+    }
+
+    impl<T> Drop for Sender<T> {
+        fn drop(&mut self) {
+            self.0.lock().unwrap().senders -= 1;
+        }
+    }
+
+    impl<T> Receiver<T> {
+        /// Receives a value without blocking.
+        pub fn try_recv(&self) -> Result<T, TryRecvError> {
+            let mut channel_locked = self.0.lock().unwrap();
+            match channel_locked.queue.pop_front() {
+                Some(value) => Ok(value),
+                None if channel_locked.senders == 0 => Err(TryRecvError::Disconnected),
+                None => Err(TryRecvError::Empty),
+            }
+        }
+    }
+
+    impl<T> Drop for Receiver<T> {
+        fn drop(&mut self) {
+            let mut channel_locked = self.0.lock().unwrap();
+            channel_locked.is_receiving = false;
+            channel_locked.queue.clear();
+        }
+    }
+}
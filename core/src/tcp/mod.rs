@@ -0,0 +1,1905 @@
+use log::{debug, info, trace, warn};
+use std::cmp::{min, Ordering};
+use std::collections::BTreeMap;
+use std::io;
+use std::mem;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::time::{Duration, Instant};
+
+use crate::cacher::RandomCacher;
+use crate::drops::DropReason;
+use crate::hooks::Protocol;
+use crate::packet::Indicator;
+use crate::record::Kind;
+use crate::socks::{CloseReason, ProxyConnector, StreamWorker};
+use crate::upstream::Route;
+use crate::{
+    HalfOpenOverflow, Redirector, ResumeStrategy, TcpFlow, TcpStats, Timer, MAX_U32_WINDOW_SIZE,
+    STALE_TIMEOUT, TCP_DEFAULT_PEER_MSS, TCP_HEADERS_SIZE,
+};
+
+/// Represents the TCP ACK duplicates before trigger a fast retransmission.
+const DUPLICATES_BEFORE_FAST_RETRANSMISSION: usize = 3;
+/// Represents the cool down time between 2 retransmissions.
+const RETRANSMISSION_COOL_DOWN: u128 = 1000;
+/// Represents the interval between 2 checks of the retransmission timers in milliseconds.
+const RETRANSMISSION_CHECK_INTERVAL: u64 = 20;
+/// Represents the max pure TCP ACK duplicates handled in a second before drop.
+const MAX_DUPLICATES_PER_SECOND: usize = 64;
+/// Represents the interval between 2 checks of a half-open TCP connection still connecting to
+/// its proxy.
+const HALF_OPEN_CHECK_INTERVAL: u64 = 1;
+
+/// Get the window the send queue of a stream can take, which limits the window advertised to the
+/// source.
+fn get_send_window(stream: &StreamWorker) -> u16 {
+    min(stream.get_send_room(), u16::MAX as usize) as u16
+}
+
+impl Redirector {
+    pub(crate) fn handle_tcp(&mut self, indicator: &Indicator, buffer: &[u8]) -> io::Result<()> {
+        if self.is_malformed(indicator, buffer) {
+            return Ok(());
+        }
+        if let Some(tcp) = indicator.get_tcp() {
+            // Local delivery, except the ports of services, which are redirected like others
+            let dst = SocketAddrV4::new(tcp.get_dst_ip_addr(), tcp.get_dst());
+            if tcp.get_dst_ip_addr() == self.tx.lock().unwrap().get_local_ip_addr() {
+                if self.get_gateway_service(dst).is_none() {
+                    return self.deliver_local(indicator, buffer);
+                }
+            } else if self.bridge(indicator, buffer)? {
+                // Bridge
+                return Ok(());
+            }
+
+            if tcp.is_rst() {
+                self.handle_tcp_rst(indicator);
+            } else if tcp.is_ack() {
+                return self.handle_tcp_ack(indicator, buffer);
+            } else if tcp.is_syn() {
+                // Pure TCP SYN
+                return self.handle_tcp_syn(indicator);
+            } else if tcp.is_fin() {
+                // Pure TCP FIN
+                return self.handle_tcp_fin(indicator);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_tcp_ack(&mut self, indicator: &Indicator, buffer: &[u8]) -> io::Result<()> {
+        if let Some(tcp) = indicator.get_tcp() {
+            let dst = SocketAddrV4::new(tcp.get_dst_ip_addr(), tcp.get_dst());
+            let key = (tcp.get_src(), dst);
+            let is_exist = self.get_stream(&key).is_some();
+            let is_alive = match self.get_stream(&key) {
+                Some(stream) => !stream.is_closed(),
+                None => false,
+            };
+
+            if is_exist {
+                if is_alive && tcp.is_syn() {
+                    // Simultaneous open, the source answers a SYN with ACK/SYN in its
+                    // SYN_RECEIVED state. SOCKS cannot express it, so it maps onto the CONNECT
+                    // started by the SYN of the source and the ACK/SYN is never forwarded.
+                    self.tcp_simultaneous_opens = self.tcp_simultaneous_opens.saturating_add(1);
+                    debug!(
+                        "TCP simultaneous open of {} -> {} at {}",
+                        tcp.get_src(),
+                        dst,
+                        tcp.get_sequence()
+                    );
+                    if self.tcp_flows[&key].is_connecting {
+                        // The worker sends ACK/SYN when the CONNECT completes, which also
+                        // acknowledges the SYN of the source
+                        return Ok(());
+                    }
+
+                    // Acknowledge the SYN of the source again
+                    return self.tx.lock().unwrap().send_tcp_ack_0(dst, tcp.get_src());
+                }
+                if is_alive && !self.tcp_flows[&key].is_connecting {
+                    // The first ACK after the ACK/SYN completes the handshake
+                    if let Some(handle) = self.tcp_flows.get_mut(&key).unwrap().half_open.take() {
+                        self.timers.cancel(handle);
+                    }
+                }
+                if is_alive {
+                    // Keep-alive
+                    if !tcp.is_fin() && self.is_tcp_keepalive(indicator, buffer) {
+                        self.tcp_keepalives = self.tcp_keepalives.saturating_add(1);
+                        trace!(
+                            "TCP keep-alive of {} -> {} at {}",
+                            tcp.get_src(),
+                            dst,
+                            tcp.get_sequence()
+                        );
+
+                        // Send ACK0
+                        return self.tx.lock().unwrap().send_tcp_ack_0(dst, tcp.get_src());
+                    }
+
+                    // ACK
+                    self.update_tcp_sequence(indicator);
+                    self.update_tcp_acknowledgement(indicator);
+                    let is_window_changed;
+                    {
+                        let mut tx_locked = self.tx.lock().unwrap();
+                        tx_locked.invalidate_cache_to(
+                            dst,
+                            tcp.get_src(),
+                            tcp.get_acknowledgement(),
+                        );
+                        is_window_changed =
+                            tx_locked.set_tcp_send_window(dst, tcp.get_src(), tcp.get_window());
+                    }
+
+                    if buffer.len() > indicator.get_size() {
+                        // ACK
+                        // Append to cache
+                        let flow = self.tcp_flows.get_mut(&key).unwrap();
+                        let cache = flow
+                            .cache
+                            .get_or_insert_with(|| RandomCacher::new(tcp.get_sequence()));
+                        let expected = cache.get_sequence();
+                        let payload =
+                            cache.append(tcp.get_sequence(), &buffer[indicator.get_size()..])?;
+
+                        // Statistics
+                        let distance = tcp.get_sequence().wrapping_sub(expected) as i32;
+                        let stats = flow.stats.get_or_insert_with(TcpStats::default);
+                        match distance.cmp(&0) {
+                            Ordering::Greater => stats.out_of_order += 1,
+                            Ordering::Less => stats.duplicates += 1,
+                            Ordering::Equal => {}
+                        }
+
+                        match payload {
+                            Some(payload) => {
+                                // Send
+                                let stream = flow.stream.as_mut().unwrap();
+                                match stream.send(payload.as_slice(), tcp.is_psh()) {
+                                    Ok(_) => {
+                                        // Update window size, which is limited by the send queue
+                                        let window = min(
+                                            cache.get_remaining_size(),
+                                            get_send_window(stream),
+                                        );
+                                        if stream.is_congested() {
+                                            flow.is_congested = true;
+                                        }
+                                        let mut tx_locked = self.tx.lock().unwrap();
+                                        tx_locked.set_tcp_window(dst, tcp.get_src(), window);
+
+                                        // Update TCP acknowledgement
+                                        tx_locked.add_tcp_acknowledgement(
+                                            dst,
+                                            tcp.get_src(),
+                                            payload.len() as u32,
+                                        );
+
+                                        // Send ACK0
+                                        // If there is a heavy traffic, the ACK reported may be inaccurate, which would results in retransmission
+                                        tx_locked.send_tcp_ack_0(dst, tcp.get_src())?;
+                                    }
+                                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                                        // The send queue is full, drop the payload without
+                                        // acknowledging it, the source retransmits it from the
+                                        // expected sequence, while the segments buffered after it
+                                        // are kept
+                                        trace!(
+                                            "drop TCP {} -> {} at {} ({} Bytes): {}",
+                                            tcp.get_src(),
+                                            dst,
+                                            expected,
+                                            payload.len(),
+                                            e
+                                        );
+                                        cache.rewind(payload.len());
+                                        flow.is_congested = true;
+
+                                        // Advertise the remaining room of the send queue
+                                        let window = min(
+                                            cache.get_remaining_size(),
+                                            get_send_window(stream),
+                                        );
+                                        let mut tx_locked = self.tx.lock().unwrap();
+                                        tx_locked.set_tcp_window(dst, tcp.get_src(), window);
+
+                                        // Send ACK0
+                                        tx_locked.send_tcp_ack_0(dst, tcp.get_src())?;
+                                    }
+                                    Err(e) => {
+                                        // Clean up
+                                        self.remove(indicator, "error");
+
+                                        // Send ACK/RST
+                                        let mut tx_locked = self.tx.lock().unwrap();
+                                        tx_locked.send_tcp_ack_rst(dst, tcp.get_src())?;
+
+                                        // Clean up
+                                        tx_locked.remove(dst, tcp.get_src());
+
+                                        return Err(e);
+                                    }
+                                }
+                            }
+                            None => {
+                                // Retransmission or unordered
+                                // Update window size
+                                let window = match flow.stream {
+                                    Some(ref stream) => {
+                                        min(cache.get_remaining_size(), get_send_window(stream))
+                                    }
+                                    None => cache.get_remaining_size(),
+                                };
+                                let mut tx_locked = self.tx.lock().unwrap();
+                                tx_locked.set_tcp_window(dst, tcp.get_src(), window);
+
+                                // Send ACK0
+                                tx_locked.send_tcp_ack_0(dst, tcp.get_src())?;
+                            }
+                        }
+                    } else {
+                        // ACK0 or FIN
+                        if !tcp.is_fin()
+                            && !is_window_changed
+                            && self.is_tcp_duplicate_flood(indicator)
+                        {
+                            self.tcp_dropped_duplicates =
+                                self.tcp_dropped_duplicates.saturating_add(1);
+                            trace!(
+                                "drop TCP acknowledgement duplicate of {} -> {} at {}",
+                                tcp.get_src(),
+                                dst,
+                                tcp.get_acknowledgement()
+                            );
+
+                            return Ok(());
+                        }
+
+                        if self.tcp_flows[&key].duplicates >= DUPLICATES_BEFORE_FAST_RETRANSMISSION
+                            && !tcp.is_zero_window()
+                        {
+                            let is_cooled_down = match self.tcp_flows[&key].last_retransmission {
+                                Some(instant) => {
+                                    instant.elapsed().as_millis() < RETRANSMISSION_COOL_DOWN
+                                }
+                                None => false,
+                            };
+                            if !is_cooled_down {
+                                // Fast retransmit
+                                // TODO: the procedure is in back N
+                                let size = self
+                                    .tx
+                                    .lock()
+                                    .unwrap()
+                                    .fast_retransmit_tcp(dst, tcp.get_src())?;
+                                let flow = self.tcp_flows.get_mut(&key).unwrap();
+                                let stats = flow.stats.get_or_insert_with(TcpStats::default);
+                                stats.fast_retransmissions += 1;
+                                stats.retransmitted += size;
+
+                                flow.duplicates = 0;
+                                flow.last_retransmission = Some(Instant::now());
+                            }
+                        }
+                    }
+
+                    // FIN
+                    if tcp.is_fin() {
+                        self.shutdown_tcp(indicator, buffer.len() - indicator.get_size())?;
+                    }
+                    // Both sides are closed once the source acknowledges the FIN
+                    let is_write_shutdown = self.get_stream(&key).unwrap().is_write_shutdown();
+                    if is_write_shutdown
+                        && self.tx.lock().unwrap().is_tcp_fin_acknowledged(
+                            dst,
+                            tcp.get_src(),
+                            tcp.get_acknowledgement(),
+                        )
+                    {
+                        // Clean up
+                        self.remove(indicator, "close");
+                        self.tx.lock().unwrap().remove(dst, tcp.get_src());
+
+                        return Ok(());
+                    }
+
+                    // Trigger sending remaining data
+                    self.tx.lock().unwrap().send_tcp_ack(dst, tcp.get_src())?;
+                } else {
+                    // Expect in LAST_ACK state (or the stream met an error)
+                    if tcp.is_fin() {
+                        let mut tx_locked = self.tx.lock().unwrap();
+                        tx_locked.set_tcp_acknowledgement(
+                            dst,
+                            tcp.get_src(),
+                            tcp.get_sequence().checked_add(1).unwrap_or(0),
+                        );
+                        // Send ACK/FIN
+                        tx_locked.send_tcp_ack_fin(dst, tcp.get_src())?;
+                    } else {
+                        self.remove(indicator, "close");
+                        self.tx.lock().unwrap().remove(dst, tcp.get_src());
+                    }
+                }
+            } else if !tcp.is_syn()
+                && self
+                    .handoff
+                    .as_ref()
+                    .is_some_and(|handoff| handoff.is_guarding())
+            {
+                // The connection may be drained by the old process
+                self.drop_packet(indicator, DropReason::Handoff);
+            } else {
+                if tcp.is_fin() {
+                    // Though a RST is enough, reply with respect
+                    let mut tx_locked = self.tx.lock().unwrap();
+                    tx_locked.resume_tcp_sequence(dst, tcp.get_src(), tcp.get_acknowledgement());
+                    tx_locked.set_tcp_acknowledgement(
+                        dst,
+                        tcp.get_src(),
+                        tcp.get_sequence().checked_add(1).unwrap_or(0),
+                    );
+                    // Send ACK/FIN
+                    tx_locked.send_tcp_ack_fin(dst, tcp.get_src())?;
+
+                    // Clean up
+                    tx_locked.remove(dst, tcp.get_src());
+                } else if !tcp.is_syn()
+                    && ((self.tcp_half_open_overflow == HalfOpenOverflow::Cookie
+                        && self.accept_tcp_cookie(indicator)?)
+                        || (self.is_pickup_established && self.pickup_tcp(indicator, buffer)?))
+                {
+                    // Handle the segment in the connection opened from a SYN cookie or picked up
+                    return self.handle_tcp_ack(indicator, buffer);
+                } else {
+                    let mut tx_locked = self.tx.lock().unwrap();
+                    tx_locked.resume_tcp_sequence(dst, tcp.get_src(), tcp.get_acknowledgement());
+                    tx_locked.set_tcp_acknowledgement(
+                        dst,
+                        tcp.get_src(),
+                        tcp.get_sequence().checked_add(1).unwrap_or(0),
+                    );
+                    // Send ACK/RST
+                    tx_locked.send_tcp_ack_rst(dst, tcp.get_src())?;
+
+                    // Clean up
+                    tx_locked.remove(dst, tcp.get_src());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_tcp_syn(&mut self, indicator: &Indicator) -> io::Result<()> {
+        if let Some(tcp) = indicator.get_tcp() {
+            let dst = SocketAddrV4::new(tcp.get_dst_ip_addr(), tcp.get_dst());
+            let key = (tcp.get_src(), dst);
+            let is_exist = self.get_stream(&key).is_some();
+
+            // Connect if not connected, drop if established
+            if !is_exist {
+                // Clean up
+                self.remove(indicator, "reconnect");
+
+                // The source retransmits the SYN after the back off
+                if self.is_connect_backed_off() {
+                    self.drop_packet(indicator, DropReason::PortExhaustion);
+
+                    return Ok(());
+                }
+
+                // Half-open connections
+                if self.get_tcp_half_open_count() >= self.tcp_max_half_open {
+                    self.tcp_half_open_refused = self.tcp_half_open_refused.saturating_add(1);
+                    self.drop_packet(indicator, DropReason::HalfOpen);
+                    if self.tcp_half_open_overflow == HalfOpenOverflow::Drop {
+                        return Ok(());
+                    }
+                    let effective_mss = self.get_effective_mss(tcp.get_mss());
+                    let mut tx_locked = self.tx.lock().unwrap();
+                    tx_locked.set_tcp_acknowledgement(
+                        dst,
+                        tcp.get_src(),
+                        tcp.get_sequence().checked_add(1).unwrap_or(0),
+                    );
+                    if self.tcp_half_open_overflow == HalfOpenOverflow::Cookie {
+                        if let Some(path_mtu) = self.path_mtu {
+                            tx_locked.set_tcp_mss(dst, tcp.get_src(), path_mtu - TCP_HEADERS_SIZE);
+                        }
+                        // Send ACK/SYN, without the window scale which needs a state
+                        tx_locked.send_tcp_ack_syn_cookie(
+                            dst,
+                            tcp.get_src(),
+                            tcp.get_sequence(),
+                            effective_mss,
+                        )?;
+                    } else {
+                        // Send ACK/RST
+                        tx_locked.send_tcp_ack_rst(dst, tcp.get_src())?;
+                    }
+                    // Clean up
+                    tx_locked.remove(dst, tcp.get_src());
+
+                    return Ok(());
+                }
+
+                // Envelope
+                let flows = self.get_flow_count();
+                if let Err(refusal) = self.envelope.admit(flows) {
+                    self.drop_packet(indicator, DropReason::from(refusal));
+                    let mut tx_locked = self.tx.lock().unwrap();
+                    tx_locked.set_tcp_acknowledgement(
+                        dst,
+                        tcp.get_src(),
+                        tcp.get_sequence().checked_add(1).unwrap_or(0),
+                    );
+                    // Send ACK/RST
+                    tx_locked.send_tcp_ack_rst(dst, tcp.get_src())?;
+                    // Clean up
+                    tx_locked.remove(dst, tcp.get_src());
+
+                    return Ok(());
+                }
+
+                self.get_tcp_flow(key).sequence = Some(tcp.get_sequence());
+
+                // The worker forwards ACK/SYN or ACK/RST when the handshake completes
+                {
+                    let mut tx_locked = self.tx.lock().unwrap();
+                    // Clean up
+                    tx_locked.remove(dst, tcp.get_src());
+
+                    if let Some(shift) = tcp.get_wscale() {
+                        tx_locked.set_tcp_send_window_scale(dst, tcp.get_src(), shift);
+                    }
+                    tx_locked.set_tcp_acknowledgement(
+                        dst,
+                        tcp.get_src(),
+                        tcp.get_sequence().checked_add(1).unwrap_or(0),
+                    );
+                    // Clamp the MSS to the path MTU probed, which stays the same for the
+                    // connection
+                    if let Some(path_mtu) = self.path_mtu {
+                        tx_locked.set_tcp_mss(dst, tcp.get_src(), path_mtu - TCP_HEADERS_SIZE);
+                    }
+                }
+                let advertised_mss = tcp.get_mss();
+                let effective_mss = self.get_effective_mss(advertised_mss);
+                if advertised_mss != Some(effective_mss) {
+                    debug!(
+                        "TCP {} -> {}: MSS {} of the source is taken as {}",
+                        tcp.get_src(),
+                        dst,
+                        advertised_mss.map_or(String::from("absent"), |mss| mss.to_string()),
+                        effective_mss
+                    );
+                }
+                self.tx
+                    .lock()
+                    .unwrap()
+                    .set_tcp_peer_mss(dst, tcp.get_src(), effective_mss);
+                self.get_tcp_flow(key).peer_mss = Some((advertised_mss, effective_mss));
+
+                self.connect_tcp(indicator)?;
+                let handle = self
+                    .timers
+                    .schedule(self.tcp_handshake_timeout, Timer::HalfOpen(key));
+                if let Some(handle) = self.get_tcp_flow(key).half_open.replace(handle) {
+                    self.timers.cancel(handle);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Connects the TCP connection of the segment through its route. The worker forwards ACK/SYN
+    /// or ACK/RST when the handshake completes, the source is reset at once if the worker fails to
+    /// start.
+    fn connect_tcp(&mut self, indicator: &Indicator) -> io::Result<()> {
+        if let Some(tcp) = indicator.get_tcp() {
+            let dst = SocketAddrV4::new(tcp.get_dst_ip_addr(), tcp.get_dst());
+            let key = (tcp.get_src(), dst);
+
+            // Services of the gateway are never admitted by rules
+            let (admission, route) = match self.get_gateway_service(dst) {
+                Some(local) => (None, Route::Local(local)),
+                None => {
+                    let admission = self.rules.find(dst);
+                    (admission, self.select_route(dst, admission))
+                }
+            };
+            let stream = match route {
+                Route::Proxy(remote) => {
+                    self.reload_credentials();
+                    let connector =
+                        ProxyConnector::new(self.proxy_type, remote, self.credentials.get_auth());
+                    let stream = StreamWorker::connect(
+                        self.get_tx(),
+                        &self.threads,
+                        tcp.get_src(),
+                        dst.into(),
+                        connector,
+                        self.tcp_pool.clone(),
+                    );
+                    if let Err(ref e) = stream {
+                        self.upstreams.report_failure(remote, e);
+                    }
+
+                    stream
+                }
+                Route::Direct => {
+                    debug!("Connect to {} directly", dst);
+                    StreamWorker::connect_direct(
+                        self.get_tx(),
+                        &self.threads,
+                        tcp.get_src(),
+                        dst.into(),
+                    )
+                }
+                Route::Local(local) => {
+                    debug!("Connect to {} locally", dst);
+                    StreamWorker::connect_local(
+                        self.get_tx(),
+                        &self.threads,
+                        tcp.get_src(),
+                        dst.into(),
+                        local,
+                    )
+                }
+                Route::Reject | Route::Bridge => Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    "all destinations are down",
+                )),
+            };
+
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    // Clean up
+                    self.remove(indicator, "error");
+
+                    let mut tx_locked = self.tx.lock().unwrap();
+                    // Send ACK/RST
+                    tx_locked.send_tcp_ack_rst(dst, tcp.get_src())?;
+
+                    // Clean up
+                    tx_locked.remove(dst, tcp.get_src());
+
+                    return Err(e);
+                }
+            };
+            stream.set_idle_timeout(self.tcp_idle_timeout);
+            stream.set_send_capacity(self.tcp_send_buffer);
+            if let Some(ref monitor) = self.monitor {
+                stream.set_monitor(monitor, dst);
+            }
+
+            if let Some(recorder) =
+                self.create_recorder(Kind::Stream, tcp.get_src(), dst, admission)
+            {
+                stream.set_recorder(recorder);
+            }
+
+            debug_assert!(self.get_stream(&key).is_none());
+            let idle = self
+                .tcp_idle_timeout
+                .map(|timeout| self.timers.schedule(timeout, Timer::Idle(key)));
+            let flow = self.get_tcp_flow(key);
+            flow.idle = idle;
+            flow.stream = Some(stream);
+            flow.rule = admission;
+            flow.remote = match route {
+                Route::Proxy(remote) => Some(remote),
+                _ => None,
+            };
+            flow.is_connecting = true;
+        }
+
+        Ok(())
+    }
+
+    /// Picks up the TCP connection established before the `Redirector` starts from a segment of
+    /// the source, returns `false` if it is refused, where the source should be reset. The state
+    /// is taken from the sequences of the segment, and the proxy connects to the destination anew,
+    /// so the data in flight before is lost and the application may or may not recover. The
+    /// window scale and the MSS of the source are unknown, the window is taken as unscaled and
+    /// the MSS as the default, which are both the smallest.
+    fn pickup_tcp(&mut self, indicator: &Indicator, buffer: &[u8]) -> io::Result<bool> {
+        if let Some(tcp) = indicator.get_tcp() {
+            let dst = SocketAddrV4::new(tcp.get_dst_ip_addr(), tcp.get_dst());
+            let key = (tcp.get_src(), dst);
+
+            // Clean up
+            self.remove(indicator, "reconnect");
+
+            if self.is_connect_backed_off() {
+                self.tcp_pickup_failures = self.tcp_pickup_failures.saturating_add(1);
+                self.drop_packet(indicator, DropReason::PortExhaustion);
+
+                return Ok(false);
+            }
+            // Envelope
+            let flows = self.get_flow_count();
+            if let Err(refusal) = self.envelope.admit(flows) {
+                self.tcp_pickup_failures = self.tcp_pickup_failures.saturating_add(1);
+                self.drop_packet(indicator, DropReason::from(refusal));
+
+                return Ok(false);
+            }
+            debug!(
+                "pick up TCP {} -> {} at {}, acknowledging {}",
+                tcp.get_src(),
+                dst,
+                tcp.get_sequence(),
+                tcp.get_acknowledgement()
+            );
+
+            // A keep-alive probe of an idle connection carries 1 byte already acknowledged
+            let mut sequence = tcp.get_sequence();
+            if buffer.len() - indicator.get_size() == 1 {
+                sequence = sequence.wrapping_add(1);
+            }
+            self.get_tcp_flow(key).sequence = Some(sequence);
+            {
+                let mut tx_locked = self.tx.lock().unwrap();
+                // Clean up
+                tx_locked.remove(dst, tcp.get_src());
+
+                // The source expects the sequence it acknowledges
+                tx_locked.resume_tcp_sequence(dst, tcp.get_src(), tcp.get_acknowledgement());
+                tx_locked.set_tcp_acknowledgement(dst, tcp.get_src(), sequence);
+                // The window scale of the source is unknown without its SYN, so the window is
+                // taken unscaled, which is at most smaller than it is
+                tx_locked.set_tcp_send_window(dst, tcp.get_src(), tcp.get_window());
+                if let Some(path_mtu) = self.path_mtu {
+                    tx_locked.set_tcp_mss(dst, tcp.get_src(), path_mtu - TCP_HEADERS_SIZE);
+                }
+                tx_locked.set_tcp_peer_mss(dst, tcp.get_src(), TCP_DEFAULT_PEER_MSS);
+                tx_locked.set_tcp_pickup(dst, tcp.get_src());
+            }
+            self.get_tcp_flow(key).peer_mss = Some((None, TCP_DEFAULT_PEER_MSS));
+
+            if let Err(e) = self.connect_tcp(indicator) {
+                // The source is reset already
+                self.tcp_pickup_failures = self.tcp_pickup_failures.saturating_add(1);
+
+                return Err(e);
+            }
+            self.get_tcp_flow(key).is_picking_up = true;
+
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+
+    /// Opens the TCP connection whose ACK acknowledges a SYN cookie, returns `false` if it is not
+    /// a valid cookie or the connection is refused, where the source should be reset. The
+    /// handshake completes with the source before the proxy connects, and like a connection picked
+    /// up, the source is answered with an ACK once connected, or reset even if it has sent data if
+    /// the proxy fails. The MSS of the source is the one encoded, and the window is unscaled.
+    fn accept_tcp_cookie(&mut self, indicator: &Indicator) -> io::Result<bool> {
+        if let Some(tcp) = indicator.get_tcp() {
+            let dst = SocketAddrV4::new(tcp.get_dst_ip_addr(), tcp.get_dst());
+            let key = (tcp.get_src(), dst);
+
+            let mss = match self.tx.lock().unwrap().check_tcp_cookie(
+                dst,
+                tcp.get_src(),
+                tcp.get_sequence(),
+                tcp.get_acknowledgement(),
+            ) {
+                Some(mss) => mss,
+                None => return Ok(false),
+            };
+
+            // Clean up
+            self.remove(indicator, "reconnect");
+
+            if self.is_connect_backed_off() {
+                self.drop_packet(indicator, DropReason::PortExhaustion);
+
+                return Ok(false);
+            }
+            // Envelope
+            let flows = self.get_flow_count();
+            if let Err(refusal) = self.envelope.admit(flows) {
+                self.drop_packet(indicator, DropReason::from(refusal));
+
+                return Ok(false);
+            }
+            debug!(
+                "TCP {} -> {} acknowledges the SYN cookie {}",
+                tcp.get_src(),
+                dst,
+                tcp.get_acknowledgement().wrapping_sub(1)
+            );
+
+            self.get_tcp_flow(key).sequence = Some(tcp.get_sequence());
+            {
+                let mut tx_locked = self.tx.lock().unwrap();
+                // Clean up
+                tx_locked.remove(dst, tcp.get_src());
+
+                tx_locked.resume_tcp_sequence(dst, tcp.get_src(), tcp.get_acknowledgement());
+                tx_locked.set_tcp_acknowledgement(dst, tcp.get_src(), tcp.get_sequence());
+                tx_locked.set_tcp_send_window(dst, tcp.get_src(), tcp.get_window());
+                if let Some(path_mtu) = self.path_mtu {
+                    tx_locked.set_tcp_mss(dst, tcp.get_src(), path_mtu - TCP_HEADERS_SIZE);
+                }
+                tx_locked.set_tcp_peer_mss(dst, tcp.get_src(), mss);
+                tx_locked.set_tcp_pickup(dst, tcp.get_src());
+            }
+            self.get_tcp_flow(key).peer_mss = Some((Some(mss), mss));
+
+            // The source is reset already if the connection fails
+            self.connect_tcp(indicator)?;
+            self.tcp_cookies_accepted = self.tcp_cookies_accepted.saturating_add(1);
+
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+
+    /// Resets the TCP connection whose ACK/SYN is not acknowledged by the source in time. A
+    /// connection still connecting to its proxy is left to the timeout of the worker, and checked
+    /// again later.
+    pub(crate) fn expire_half_open(&mut self, key: (u16, SocketAddrV4)) {
+        if self
+            .tcp_flows
+            .get(&key)
+            .is_some_and(|flow| flow.is_connecting)
+        {
+            let handle = self.timers.schedule(
+                Duration::from_secs(HALF_OPEN_CHECK_INTERVAL),
+                Timer::HalfOpen(key),
+            );
+            self.get_tcp_flow(key).half_open = Some(handle);
+            return;
+        }
+
+        self.tcp_half_open_expired = self.tcp_half_open_expired.saturating_add(1);
+        debug!(
+            "TCP handshake of {} -> {} is not completed in {} s",
+            key.0,
+            key.1,
+            self.tcp_handshake_timeout.as_secs()
+        );
+        {
+            let mut tx_locked = self.tx.lock().unwrap();
+            // Send ACK/RST
+            if let Err(ref e) = tx_locked.send_tcp_ack_rst(key.1, key.0) {
+                warn!("handle {}: {} -> {}: {}", "TCP", key.0, key.1, e);
+            }
+            // Clean up
+            tx_locked.remove(key.1, key.0);
+        }
+        self.remove_key(key, "half-open");
+    }
+
+    /// Resets the TCP connection idle for longer than its timeout to the source, and closes it,
+    /// which releases its place in the thread registry, its socket and its connection with the
+    /// proxy. A connection active since the timer was scheduled is checked again once its idle
+    /// timeout from the last activity is over.
+    pub(crate) fn expire_idle_stream(&mut self, key: (u16, SocketAddrV4)) {
+        self.update_tcp_flow(key, |flow| flow.idle = None);
+        match self
+            .get_stream(&key)
+            .and_then(|stream| stream.get_idle_remaining())
+        {
+            Some(remaining) if remaining > Duration::from_secs(0) => {
+                let handle = self.timers.schedule(remaining, Timer::Idle(key));
+                self.get_tcp_flow(key).idle = Some(handle);
+                return;
+            }
+            Some(_) => {}
+            None => return,
+        }
+
+        self.tcp_idle_expired = self.tcp_idle_expired.saturating_add(1);
+        debug!(
+            "TCP {} -> {} is idle for {} s, reset",
+            key.0,
+            key.1,
+            self.tcp_idle_timeout.unwrap_or_default().as_secs()
+        );
+        self.remove_key(key, "idle");
+        let mut tx_locked = self.tx.lock().unwrap();
+        if let Err(ref e) = tx_locked.send_tcp_ack_rst(key.1, key.0) {
+            warn!("handle {}: {}", "TCP", e);
+        }
+        tx_locked.remove(key.1, key.0);
+    }
+
+    /// Resets the stream whose worker is closed for long.
+    pub(crate) fn expire_stale(&mut self, key: (u16, SocketAddrV4)) {
+        self.update_tcp_flow(key, |flow| flow.closed = None);
+        match self.get_stream(&key) {
+            Some(stream) if stream.is_closed() => {}
+            _ => return,
+        }
+
+        let reason = self
+            .get_stream(&key)
+            .unwrap()
+            .get_close_reason()
+            .unwrap_or(CloseReason::Closed);
+        self.repairs = self.repairs.saturating_add(1);
+        warn!(
+            "scavenge: stream {} -> {} is closed for {} s for {}, reset ({} repairs in total)",
+            key.0, key.1, STALE_TIMEOUT, reason, self.repairs
+        );
+        self.remove_key(key, "stale");
+        let mut tx_locked = self.tx.lock().unwrap();
+        if let Err(ref e) = tx_locked.send_tcp_ack_rst(key.1, key.0) {
+            warn!("handle {}: {}", "TCP", e);
+        }
+        tx_locked.remove(key.1, key.0);
+    }
+
+    /// Applies the resume strategy to the established TCP connections through the upstream which
+    /// goes down, the one of the rule admitting each connection or the global one. Connections
+    /// still connecting are left to the timeout of the worker, and new connections avoid the
+    /// upstream since it is down.
+    pub(crate) fn fail_over(&mut self, remote: SocketAddr) {
+        let keys: Vec<(u16, SocketAddrV4)> = self
+            .tcp_flows
+            .iter()
+            .filter(|(_, flow)| flow.remote == Some(remote) && !flow.is_connecting)
+            .map(|(key, _)| *key)
+            .collect();
+        let mut counts: BTreeMap<ResumeStrategy, usize> = BTreeMap::new();
+        for key in keys.iter() {
+            let strategy = self.tcp_flows[key]
+                .rule
+                .and_then(|admission| self.rules.get(admission.rule).get_resume_strategy())
+                .unwrap_or(self.resume_strategy);
+            *counts.entry(strategy).or_insert(0) += 1;
+            let flow = self.tcp_flows.get_mut(key).unwrap();
+            let stream = match flow.stream {
+                Some(ref mut stream) => stream,
+                None => continue,
+            };
+            match strategy {
+                ResumeStrategy::Freeze => {}
+                ResumeStrategy::Fin => {
+                    // The stream to the proxy is useless, the source closes the connection in
+                    // order then
+                    stream.abort();
+                    flow.remote = None;
+                    if let Err(ref e) = self.tx.lock().unwrap().send_tcp_ack_fin(key.1, key.0) {
+                        warn!("handle {}: {} -> {}: {}", "TCP", key.0, key.1, e);
+                    }
+                }
+                ResumeStrategy::Rst => {
+                    stream.abort();
+                    self.remove_key(*key, "failover");
+                    let mut tx_locked = self.tx.lock().unwrap();
+                    // Send ACK/RST
+                    if let Err(ref e) = tx_locked.send_tcp_ack_rst(key.1, key.0) {
+                        warn!("handle {}: {} -> {}: {}", "TCP", key.0, key.1, e);
+                    }
+                    // Clean up
+                    tx_locked.remove(key.1, key.0);
+                }
+            }
+        }
+
+        for (strategy, n) in counts.iter() {
+            let count = self.tcp_failovers.entry(*strategy).or_insert(0);
+            *count = count.saturating_add(*n);
+        }
+        // Report the global strategy even without streams
+        let counts: Vec<String> = if counts.is_empty() {
+            vec![format!("0 streams {}", self.resume_strategy)]
+        } else {
+            counts
+                .iter()
+                .map(|(strategy, n)| format!("{} streams {}", n, strategy))
+                .collect()
+        };
+        info!(
+            "Fail over {} from {}: {}",
+            self.src_ip_addr,
+            remote,
+            counts.join(", ")
+        );
+    }
+
+    /// Retransmits the TCP payload in flight to the sources which acknowledged nothing in the
+    /// retransmission timeout, like the last segments of a burst lost without duplicates.
+    pub(crate) fn poll_retransmissions(&mut self) {
+        let now = Instant::now();
+        if now.duration_since(self.last_retransmission_check)
+            < Duration::from_millis(RETRANSMISSION_CHECK_INTERVAL)
+        {
+            return;
+        }
+        self.last_retransmission_check = now;
+
+        let result = self.tx.lock().unwrap().retransmit_timed_out(now);
+        match result {
+            Ok(retransmitted) => {
+                for (key, size) in retransmitted {
+                    let stats = self
+                        .get_tcp_flow(key)
+                        .stats
+                        .get_or_insert_with(TcpStats::default);
+                    stats.timeouts += 1;
+                    stats.retransmitted += size;
+                }
+            }
+            Err(ref e) => warn!("handle {}: {}", "TCP", e),
+        }
+    }
+
+    /// Polls connecting TCP streams, reports the connected ones and removes the failed ones.
+    /// Tells the sources of TCP connections whose send queue drains below the high-water mark
+    /// that the window opens again, so they do not wait for probing a zero window.
+    pub(crate) fn poll_congested(&mut self) {
+        let keys: Vec<(u16, SocketAddrV4)> = self
+            .tcp_flows
+            .iter()
+            .filter(|(_, flow)| flow.is_congested)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in keys {
+            let flow = self.tcp_flows.get_mut(&key).unwrap();
+            let window = match flow.stream {
+                Some(ref stream) => {
+                    if stream.is_congested() {
+                        continue;
+                    }
+                    get_send_window(stream)
+                }
+                None => {
+                    self.update_tcp_flow(key, |flow| flow.is_congested = false);
+                    continue;
+                }
+            };
+            flow.is_congested = false;
+            let window = match flow.cache {
+                Some(ref cache) => min(cache.get_remaining_size(), window),
+                None => window,
+            };
+            trace!("TCP {} -> {} is not congested", key.0, key.1);
+            let mut tx_locked = self.tx.lock().unwrap();
+            tx_locked.set_tcp_window(key.1, key.0, window);
+            // Window update
+            if let Err(ref e) = tx_locked.send_tcp_ack_0(key.1, key.0) {
+                warn!("handle {}: {} -> {}: {}", "TCP", key.0, key.1, e);
+            }
+        }
+    }
+
+    /// Reports the first round trip of data of the flows through proxies to the upstreams once
+    /// completed, a sample of the latency beside the handshake.
+    pub(crate) fn poll_early_rtt(&mut self) {
+        let keys: Vec<(u16, SocketAddrV4)> = self
+            .tcp_flows
+            .iter()
+            .filter(|(_, flow)| flow.is_rtt_pending)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in keys {
+            let flow = &self.tcp_flows[&key];
+            let rtt = match (flow.stream.as_ref(), flow.remote) {
+                (Some(stream), Some(remote)) if !stream.is_closed() => {
+                    match stream.get_early_rtt() {
+                        Some(rtt) => Some((remote, rtt)),
+                        None => continue,
+                    }
+                }
+                _ => None,
+            };
+            self.update_tcp_flow(key, |flow| flow.is_rtt_pending = false);
+            if let Some((remote, rtt)) = rtt {
+                trace!(
+                    "Early RTT to {} via {}: {} ms",
+                    key.1,
+                    remote,
+                    rtt.as_millis()
+                );
+                self.upstreams.report_early_rtt(remote, *key.1.ip(), rtt);
+            }
+        }
+
+        let indexes: Vec<usize> = self.udp_rtt_pending.keys().cloned().collect();
+        for index in indexes {
+            let rtt = match self.datagrams[index] {
+                Some(ref datagram) if !datagram.is_closed() => match datagram.get_early_rtt() {
+                    Some(rtt) => Some(rtt),
+                    None => continue,
+                },
+                _ => None,
+            };
+            let (remote, dst) = self.udp_rtt_pending.remove(&index).unwrap();
+            if let Some(rtt) = rtt {
+                trace!(
+                    "Early RTT to {} via {}: {} ms",
+                    dst,
+                    remote,
+                    rtt.as_millis()
+                );
+                self.upstreams.report_early_rtt(remote, *dst.ip(), rtt);
+            }
+        }
+    }
+
+    pub(crate) fn poll_connecting(&mut self) {
+        let keys: Vec<(u16, SocketAddrV4)> = self
+            .tcp_flows
+            .iter()
+            .filter(|(_, flow)| flow.is_connecting)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in keys {
+            let result = match self.get_stream_mut(&key) {
+                Some(stream) => stream
+                    .poll_ready()
+                    .map(|is_ready| (is_ready, stream.get_latency())),
+                None => {
+                    self.update_tcp_flow(key, |flow| flow.is_connecting = false);
+                    continue;
+                }
+            };
+            match result {
+                Ok((false, _)) => {}
+                Ok((true, latency)) => {
+                    let flow = self.tcp_flows.get_mut(&key).unwrap();
+                    flow.is_connecting = false;
+                    if mem::take(&mut flow.is_picking_up) {
+                        self.tcp_pickups = self.tcp_pickups.saturating_add(1);
+                        debug!("TCP {} -> {} is picked up", key.0, key.1);
+                    }
+                    // The handshake timeout starts from the ACK/SYN
+                    if let Some(handle) = flow.half_open {
+                        self.timers.reschedule(handle, self.tcp_handshake_timeout);
+                    }
+                    if let Some(remote) = flow.remote {
+                        // Latency test result (not accurate)
+                        let latency = latency.unwrap_or_default();
+                        debug!(
+                            "Latency to {} via {}: {} ms (RTT)",
+                            key.1,
+                            remote,
+                            latency.as_millis()
+                        );
+                        self.upstreams.report(remote, *key.1.ip(), latency);
+                        flow.is_rtt_pending = true;
+                    }
+                    let rules = &self.rules;
+                    let rule = flow.rule.map(|admission| rules.get(admission.rule));
+                    flow.flow = Some(self.hooks.open(
+                        Protocol::Tcp,
+                        SocketAddrV4::new(self.src_ip_addr, key.0),
+                        key.1,
+                        rule,
+                    ));
+                }
+                Err(ref e) => {
+                    let flow = self.tcp_flows.get_mut(&key).unwrap();
+                    flow.is_connecting = false;
+                    if let Some(remote) = flow.remote {
+                        self.upstreams.report_failure(remote, e);
+                    }
+                    if mem::take(&mut flow.is_picking_up) {
+                        self.tcp_pickup_failures = self.tcp_pickup_failures.saturating_add(1);
+                    }
+                    if e.kind() == io::ErrorKind::AddrNotAvailable {
+                        // Warned once by the worker, the source retransmits the SYN
+                        debug!("handle {}: {} -> {}: {}", "TCP", key.0, key.1, e);
+                        self.remove_key(key, "error");
+                        self.tx.lock().unwrap().remove(key.1, key.0);
+                    } else {
+                        warn!("handle {}: {} -> {}: {}", "TCP", key.0, key.1, e);
+                        // The worker has forwarded ACK/RST
+                        self.remove_key(key, "error");
+                    }
+                }
+            }
+        }
+    }
+
+    fn handle_tcp_rst(&mut self, indicator: &Indicator) {
+        if let Some(tcp) = indicator.get_tcp() {
+            let dst = SocketAddrV4::new(tcp.get_dst_ip_addr(), tcp.get_dst());
+            let key = (tcp.get_src(), dst);
+            let is_exist = self.get_stream(&key).is_some();
+
+            if is_exist {
+                // Clean up
+                self.remove(indicator, "reset");
+                self.tx.lock().unwrap().remove(dst, tcp.get_src());
+            }
+        }
+    }
+
+    fn handle_tcp_fin(&mut self, indicator: &Indicator) -> io::Result<()> {
+        if let Some(tcp) = indicator.get_tcp() {
+            let dst = SocketAddrV4::new(tcp.get_dst_ip_addr(), tcp.get_dst());
+            let key = (tcp.get_src(), dst);
+            let is_exist = self.get_stream(&key).is_some();
+
+            if is_exist {
+                self.shutdown_tcp(indicator, 0)?;
+
+                // Trigger sending remaining data
+                self.tx.lock().unwrap().send_tcp_ack(dst, tcp.get_src())?;
+            } else {
+                // Though a RST is enough, reply with respect
+                let mut tx_locked = self.tx.lock().unwrap();
+                tx_locked.resume_tcp_sequence(dst, tcp.get_src(), tcp.get_acknowledgement());
+                tx_locked.set_tcp_acknowledgement(
+                    dst,
+                    tcp.get_src(),
+                    tcp.get_sequence().checked_add(1).unwrap_or(0),
+                );
+                // Send ACK/FIN
+                tx_locked.send_tcp_ack_fin(dst, tcp.get_src())?;
+
+                // Clean up
+                tx_locked.remove(dst, tcp.get_src());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Closes the stream for sending after the FIN of the source, once all the payload before the
+    /// FIN is received, or the source retransmits it later. The FIN takes a sequence, which is
+    /// acknowledged at once while the stream keeps receiving from the proxy.
+    fn shutdown_tcp(&mut self, indicator: &Indicator, size: usize) -> io::Result<()> {
+        if let Some(tcp) = indicator.get_tcp() {
+            let dst = SocketAddrV4::new(tcp.get_dst_ip_addr(), tcp.get_dst());
+            let key = (tcp.get_src(), dst);
+            let sequence = tcp.get_sequence().wrapping_add(size as u32);
+
+            let mut tx_locked = self.tx.lock().unwrap();
+            let acknowledgement = match tx_locked.get_tcp_acknowledgement(dst, tcp.get_src()) {
+                Some(acknowledgement) => acknowledgement,
+                None => return Ok(()),
+            };
+            if acknowledgement == sequence {
+                let stream = self
+                    .tcp_flows
+                    .get_mut(&key)
+                    .unwrap()
+                    .stream
+                    .as_mut()
+                    .unwrap();
+                if let Err(ref e) = stream.shutdown_write() {
+                    warn!("handle {}: {}", "TCP", e);
+                }
+                tx_locked.set_tcp_acknowledgement(dst, tcp.get_src(), sequence.wrapping_add(1));
+                trace!("receive FIN {} -> {} at {}", tcp.get_src(), dst, sequence);
+
+                // Send ACK0
+                tx_locked.send_tcp_ack_0(dst, tcp.get_src())?;
+            } else if acknowledgement == sequence.wrapping_add(1) {
+                // Retransmission, acknowledge again
+                tx_locked.send_tcp_ack_0(dst, tcp.get_src())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn update_tcp_sequence(&mut self, indicator: &Indicator) {
+        if let Some(tcp) = indicator.get_tcp() {
+            let dst = SocketAddrV4::new(tcp.get_dst_ip_addr(), tcp.get_dst());
+            let key = (tcp.get_src(), dst);
+
+            let record_sequence = self.tcp_flows[&key].sequence.unwrap_or(0);
+            let sub_sequence = tcp
+                .get_sequence()
+                .checked_sub(record_sequence)
+                .unwrap_or_else(|| tcp.get_sequence() + (u32::MAX - record_sequence));
+
+            if sub_sequence == 0 {
+                // Duplicate
+                trace!(
+                    "TCP retransmission of {} -> {} at {}",
+                    tcp.get_src(),
+                    dst,
+                    tcp.get_sequence()
+                );
+            } else if sub_sequence < MAX_U32_WINDOW_SIZE as u32 {
+                self.get_tcp_flow(key).sequence = Some(tcp.get_sequence());
+
+                trace!(
+                    "set TCP sequence of {} -> {} to {}",
+                    tcp.get_src(),
+                    dst,
+                    tcp.get_sequence()
+                );
+            } else {
+                trace!(
+                    "TCP out of order of {} -> {} at {}",
+                    tcp.get_src(),
+                    dst,
+                    tcp.get_sequence()
+                );
+            }
+        }
+    }
+
+    fn update_tcp_acknowledgement(&mut self, indicator: &Indicator) {
+        if let Some(tcp) = indicator.get_tcp() {
+            let dst = SocketAddrV4::new(tcp.get_dst_ip_addr(), tcp.get_dst());
+            let key = (tcp.get_src(), dst);
+
+            let record_acknowledgement = self.tcp_flows[&key].acknowledgement.unwrap_or(0);
+            let sub_acknowledgement = tcp
+                .get_acknowledgement()
+                .checked_sub(record_acknowledgement)
+                .unwrap_or_else(|| tcp.get_acknowledgement() + (u32::MAX - record_acknowledgement));
+
+            if sub_acknowledgement == 0 {
+                // Duplicate
+                let flow = self.get_tcp_flow(key);
+                flow.duplicates = flow.duplicates.saturating_add(1);
+                trace!(
+                    "duplicate TCP acknowledgement of {} -> {} at {}",
+                    tcp.get_src(),
+                    dst,
+                    tcp.get_acknowledgement()
+                );
+            } else if sub_acknowledgement < MAX_U32_WINDOW_SIZE as u32 {
+                let flow = self.get_tcp_flow(key);
+                flow.acknowledgement = Some(tcp.get_acknowledgement());
+                flow.duplicates = 0;
+                trace!(
+                    "set TCP acknowledgement of {} -> {} to {}",
+                    tcp.get_src(),
+                    dst,
+                    tcp.get_acknowledgement()
+                );
+            }
+        }
+    }
+
+    fn is_tcp_keepalive(&self, indicator: &Indicator, buffer: &[u8]) -> bool {
+        if let Some(tcp) = indicator.get_tcp() {
+            let dst = SocketAddrV4::new(tcp.get_dst_ip_addr(), tcp.get_dst());
+
+            // A keep-alive has a sequence 1 below the next sequence expected, and has 0 or 1 byte
+            // payload which is already acknowledged
+            if buffer.len() - indicator.get_size() > 1 {
+                return false;
+            }
+            let acknowledgement = self
+                .tx
+                .lock()
+                .unwrap()
+                .get_tcp_acknowledgement(dst, tcp.get_src());
+            if let Some(acknowledgement) = acknowledgement {
+                return tcp.get_sequence().wrapping_add(1) == acknowledgement;
+            }
+        }
+
+        false
+    }
+
+    fn is_tcp_duplicate_flood(&mut self, indicator: &Indicator) -> bool {
+        if let Some(tcp) = indicator.get_tcp() {
+            let dst = SocketAddrV4::new(tcp.get_dst_ip_addr(), tcp.get_dst());
+            let key = (tcp.get_src(), dst);
+
+            let flow = match self.tcp_flows.get_mut(&key) {
+                Some(flow) if flow.duplicates > 0 => flow,
+                _ => return false,
+            };
+
+            let entry = flow.duplicate_rate.get_or_insert((Instant::now(), 0));
+            if entry.0.elapsed().as_secs() >= 1 {
+                *entry = (Instant::now(), 0);
+            }
+            entry.1 = entry.1.saturating_add(1);
+
+            return entry.1 > MAX_DUPLICATES_PER_SECOND;
+        }
+
+        false
+    }
+
+    fn remove(&mut self, indicator: &Indicator, reason: &str) {
+        if let Some(tcp) = indicator.get_tcp() {
+            let dst = SocketAddrV4::new(tcp.get_dst_ip_addr(), tcp.get_dst());
+
+            self.remove_key((tcp.get_src(), dst), reason);
+        }
+    }
+
+    pub(crate) fn remove_key(&mut self, key: (u16, SocketAddrV4), reason: &str) {
+        let flow = match self.tcp_flows.remove(&key) {
+            Some(flow) => flow,
+            None => return,
+        };
+        let (bytes_up, bytes_down) = match flow.stream {
+            Some(ref stream) => {
+                debug!(
+                    "TCP {} -> {} is removed for {}: {}",
+                    key.0,
+                    key.1,
+                    reason,
+                    stream.get_stats()
+                );
+                stream.get_bytes()
+            }
+            None => (0, 0),
+        };
+        // A flow opened is closed even if its stream is gone
+        if let Some(ref hook_flow) = flow.flow {
+            self.hooks.close(hook_flow, bytes_up, bytes_down, reason);
+        }
+        if let Some(ref stats) = flow.stats {
+            self.tcp_closed_stats.add(stats);
+        }
+        let bridge = flow.bridge.map(|(_, handle)| handle);
+        for handle in [flow.closed, flow.half_open, flow.idle, bridge]
+            .iter()
+            .flatten()
+        {
+            self.timers.cancel(*handle);
+        }
+        trace!("remove {} -> {}", key.0, key.1);
+    }
+
+    /// Get the stream of the TCP connection.
+    pub(crate) fn get_stream(&self, key: &(u16, SocketAddrV4)) -> Option<&StreamWorker> {
+        self.tcp_flows
+            .get(key)
+            .and_then(|flow| flow.stream.as_ref())
+    }
+
+    /// Get the mutable stream of the TCP connection.
+    pub(crate) fn get_stream_mut(
+        &mut self,
+        key: &(u16, SocketAddrV4),
+    ) -> Option<&mut StreamWorker> {
+        self.tcp_flows
+            .get_mut(key)
+            .and_then(|flow| flow.stream.as_mut())
+    }
+
+    /// Get the number of TCP connections not acknowledged by the source yet.
+    pub(crate) fn get_tcp_half_open_count(&self) -> usize {
+        self.tcp_flows
+            .values()
+            .filter(|flow| flow.half_open.is_some())
+            .count()
+    }
+
+    /// Get the TCP connections with streams.
+    pub(crate) fn get_stream_keys(&self) -> Vec<(u16, SocketAddrV4)> {
+        self.tcp_flows
+            .iter()
+            .filter(|(_, flow)| flow.stream.is_some())
+            .map(|(key, _)| *key)
+            .collect()
+    }
+
+    /// Get the number of TCP connections with streams.
+    pub(crate) fn get_stream_count(&self) -> usize {
+        self.tcp_flows
+            .values()
+            .filter(|flow| flow.stream.is_some())
+            .count()
+    }
+
+    /// Get the state of the TCP connection, which is created if it does not exist.
+    pub(crate) fn get_tcp_flow(&mut self, key: (u16, SocketAddrV4)) -> &mut TcpFlow {
+        self.tcp_flows.entry(key).or_default()
+    }
+
+    /// Updates the state of the TCP connection if it exists, which is removed once it keeps
+    /// nothing.
+    pub(crate) fn update_tcp_flow<T>(
+        &mut self,
+        key: (u16, SocketAddrV4),
+        update: impl FnOnce(&mut TcpFlow) -> T,
+    ) -> Option<T> {
+        let flow = self.tcp_flows.get_mut(&key)?;
+        let result = update(flow);
+        if flow.is_empty() {
+            self.tcp_flows.remove(&key);
+        }
+
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hooks::Hooks;
+    use crate::rule::Rules;
+    use crate::testing;
+    use pnet::packet::tcp::TcpFlags;
+    use std::sync::mpsc::Receiver;
+
+    #[test]
+    fn test_simultaneous_open() {
+        let proxy = testing::EchoProxy::spawn(true);
+        let (mut redirector, rx) = testing::create_redirector(proxy.addr);
+        let key = (50000, testing::tcp_dst());
+
+        // SYN-SENT, the SYN of the source opens the CONNECT
+        testing::handle_segment(&mut redirector, key.0, 1000, 0, TcpFlags::SYN, &[]);
+        assert!(redirector.tcp_flows[&key].is_connecting);
+        assert!(redirector.tcp_flows[&key].half_open.is_some());
+
+        // SYN-RECEIVED, the source answers the SYN of its peer with ACK/SYN, which is neither
+        // reset nor forwarded while connecting
+        let flags = TcpFlags::SYN | TcpFlags::ACK;
+        testing::handle_segment(&mut redirector, key.0, 1000, 5000, flags, &[]);
+        assert_eq!(redirector.get_tcp_simultaneous_opens(), 1);
+        assert!(redirector.get_stream(&key).is_some());
+        assert!(rx.try_recv().is_err());
+
+        // The CONNECT completes with ACK/SYN acknowledging the SYN of the source
+        proxy.release.send(()).unwrap();
+        testing::wait_connected(&mut redirector, key.0);
+        let syn = testing::receive_segment(&rx);
+        assert_eq!(syn.flags, TcpFlags::SYN | TcpFlags::ACK);
+        assert_eq!(syn.acknowledgement, 1001);
+        let sequence = syn.sequence.wrapping_add(1);
+
+        // A retransmitted ACK/SYN of the source is acknowledged again
+        testing::handle_segment(&mut redirector, key.0, 1000, 5000, flags, &[]);
+        assert_eq!(redirector.get_tcp_simultaneous_opens(), 2);
+        let ack = testing::receive_segment(&rx);
+        assert_eq!(ack.flags, TcpFlags::ACK);
+        assert_eq!((ack.sequence, ack.acknowledgement), (sequence, 1001));
+
+        // ESTABLISHED on the ACK, data goes through the CONNECT
+        testing::handle_segment(&mut redirector, key.0, 1001, sequence, TcpFlags::ACK, &[]);
+        assert!(redirector.tcp_flows[&key].half_open.is_none());
+        let flags = TcpFlags::ACK | TcpFlags::PSH;
+        testing::handle_segment(&mut redirector, key.0, 1001, sequence, flags, b"punched");
+        assert_eq!(testing::receive_payload(&rx, 7), b"punched".to_vec());
+        assert!(redirector.get_stream(&key).is_some());
+    }
+
+    #[test]
+    fn test_keepalive_storm() {
+        let proxy = testing::EchoProxy::spawn(false);
+        let (mut redirector, rx) = testing::create_redirector(proxy.addr);
+        let key = (50000, testing::tcp_dst());
+        let mut sequence = testing::establish(&mut redirector, &rx, key.0);
+        let flags = TcpFlags::ACK | TcpFlags::PSH;
+        testing::handle_segment(&mut redirector, key.0, 1001, sequence, flags, b"hello");
+        assert_eq!(testing::receive_payload(&rx, 5), b"hello".to_vec());
+        sequence = sequence.wrapping_add(5);
+        testing::handle_segment(&mut redirector, key.0, 1006, sequence, TcpFlags::ACK, &[]);
+        let packets_up = redirector.get_stream(&key).unwrap().get_stats().packets_up;
+        assert_eq!(packets_up, 1);
+        // The acknowledgements before the storm
+        let _ = rx.try_iter().count();
+
+        // The storm of a smart TV, keep-alive probes of 0 and 1 byte 1 below the next sequence,
+        // and floods of pure ACKs of the same acknowledgement
+        let mut probes = 0;
+        for i in 0..300 {
+            match i % 3 {
+                0 => {
+                    testing::handle_segment(
+                        &mut redirector,
+                        key.0,
+                        1005,
+                        sequence,
+                        TcpFlags::ACK,
+                        &[],
+                    );
+                    probes += 1;
+                }
+                1 => {
+                    testing::handle_segment(
+                        &mut redirector,
+                        key.0,
+                        1005,
+                        sequence,
+                        TcpFlags::ACK,
+                        b"o",
+                    );
+                    probes += 1;
+                }
+                _ => testing::handle_segment(
+                    &mut redirector,
+                    key.0,
+                    1006,
+                    sequence,
+                    TcpFlags::ACK,
+                    &[],
+                ),
+            }
+        }
+
+        // Each probe is answered with the next sequence expected, without touching the worker
+        let answers: Vec<testing::Segment> = rx
+            .try_iter()
+            .map(|frame| {
+                let indicator = Indicator::from(&frame).unwrap();
+                let tcp = indicator.get_tcp().unwrap();
+
+                testing::Segment {
+                    flags: tcp.get_flags(),
+                    sequence: tcp.get_sequence(),
+                    acknowledgement: tcp.get_acknowledgement(),
+                    payload: Vec::new(),
+                }
+            })
+            .collect();
+        assert_eq!(answers.len(), probes);
+        assert!(answers.iter().all(|answer| answer.flags == TcpFlags::ACK
+            && answer.sequence == sequence
+            && answer.acknowledgement == 1006));
+        assert_eq!(redirector.tcp_keepalives, probes);
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(
+            redirector.get_stream(&key).unwrap().get_stats().packets_up,
+            packets_up
+        );
+        assert_eq!(proxy.get_bytes(), 5);
+
+        // The connection is still in sync
+        testing::handle_segment(&mut redirector, key.0, 1006, sequence, flags, b"world");
+        assert_eq!(testing::receive_payload(&rx, 5), b"world".to_vec());
+        assert_eq!(proxy.get_bytes(), 10);
+    }
+
+    #[test]
+    fn test_push_mid_transfer() {
+        const SEGMENT: usize = 1400;
+        const BULK: usize = 20;
+
+        let proxy = testing::EchoProxy::spawn(false);
+        let (mut redirector, rx) = testing::create_redirector(proxy.addr);
+        let key = (50000, testing::tcp_dst());
+        let ack = testing::establish(&mut redirector, &rx, key.0);
+        let bulk = vec![b'b'; SEGMENT];
+        let message = b"fire at 12,34 now!!!";
+        assert_eq!(message.len(), 20);
+
+        // A bulk transfer without PSH, the message with PSH in the middle
+        let mut sequence = 1001u32;
+        let mut sent = 0;
+        for i in 0..BULK * 2 {
+            if i == BULK {
+                let flags = TcpFlags::ACK | TcpFlags::PSH;
+                testing::handle_segment(&mut redirector, key.0, sequence, ack, flags, message);
+                sequence = sequence.wrapping_add(message.len() as u32);
+                sent += message.len();
+
+                // The message reaches the worker in the same pass, without any poll
+                let stats = redirector.get_stream(&key).unwrap().get_stats();
+                assert_eq!(stats.bytes_up, sent);
+                assert_eq!(stats.packets_up, BULK + 1);
+                continue;
+            }
+            testing::handle_segment(&mut redirector, key.0, sequence, ack, TcpFlags::ACK, &bulk);
+            sequence = sequence.wrapping_add(SEGMENT as u32);
+            sent += SEGMENT;
+        }
+        let instant = Instant::now();
+        while proxy.get_bytes() < sent {
+            assert!(instant.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        // The echo is forwarded downstream with PSH on the last segment of each payload
+        let mut echo = Vec::new();
+        let mut last_flags = 0;
+        while echo.len() < sent {
+            let segment = testing::receive_segment(&rx);
+            assert_eq!(segment.flags & TcpFlags::RST, 0);
+            if !segment.payload.is_empty() {
+                last_flags = segment.flags;
+                echo.extend_from_slice(&segment.payload);
+                // Acknowledge to open the congestion window
+                let acknowledgement = segment.sequence.wrapping_add(segment.payload.len() as u32);
+                testing::handle_segment(
+                    &mut redirector,
+                    key.0,
+                    sequence,
+                    acknowledgement,
+                    TcpFlags::ACK,
+                    &[],
+                );
+            }
+        }
+        assert_eq!(last_flags & TcpFlags::PSH, TcpFlags::PSH);
+        let position = BULK * SEGMENT;
+        assert_eq!(&echo[position..position + message.len()], &message[..]);
+    }
+
+    /// Establishes a TCP connection through an echoing proxy with the rules, which fails over once
+    /// the proxy is reported down, and returns the next sequence of the `Redirector`.
+    fn fail_over_stream(
+        proxy: &testing::EchoProxy,
+        strategy: ResumeStrategy,
+        rules: Vec<&str>,
+    ) -> (Redirector, Receiver<Vec<u8>>, u32) {
+        use crate::rule::Rule;
+
+        let (mut redirector, rx) = testing::create_redirector(proxy.addr);
+        redirector.set_resume_strategy(strategy);
+        let rules = rules.into_iter().map(|s| Rule::parse(s).unwrap()).collect();
+        redirector.set_rules(Rules::new(rules));
+        let key = (50000, testing::tcp_dst());
+        let sequence = testing::establish(&mut redirector, &rx, key.0);
+        let flags = TcpFlags::ACK | TcpFlags::PSH;
+        testing::handle_segment(&mut redirector, key.0, 1001, sequence, flags, b"before");
+        assert_eq!(testing::receive_payload(&rx, 6), b"before".to_vec());
+        let sequence = sequence.wrapping_add(6);
+        while rx.try_recv().is_ok() {}
+
+        // The connects through the proxy fail until it is down
+        let e = io::Error::from(io::ErrorKind::ConnectionRefused);
+        for _ in 0..3 {
+            redirector.upstreams.report_failure(proxy.addr, &e);
+        }
+        let downs = redirector.upstreams.take_downs();
+        assert_eq!(downs, vec![proxy.addr]);
+        for remote in downs {
+            redirector.fail_over(remote);
+        }
+
+        (redirector, rx, sequence)
+    }
+
+    #[test]
+    fn test_fail_over_freeze() {
+        let proxy = testing::EchoProxy::spawn(false);
+        let (mut redirector, rx, sequence) =
+            fail_over_stream(&proxy, ResumeStrategy::Freeze, vec![]);
+        let key = (50000, testing::tcp_dst());
+
+        // The connection is left as it is, and keeps going if the proxy does
+        assert!(rx.try_recv().is_err());
+        assert!(redirector.tcp_flows[&key].remote.is_some());
+        let flags = TcpFlags::ACK | TcpFlags::PSH;
+        testing::handle_segment(&mut redirector, key.0, 1007, sequence, flags, b"after");
+        assert_eq!(testing::receive_payload(&rx, 5), b"after".to_vec());
+        assert_eq!(
+            redirector.get_tcp_failovers(),
+            vec![(ResumeStrategy::Freeze, 1)]
+        );
+    }
+
+    #[test]
+    fn test_fail_over_fin() {
+        let proxy = testing::EchoProxy::spawn(false);
+        // The strategy of the rule admitting the connection takes precedence
+        let rules = vec!["web ports=80 resume-strategy=fin"];
+        let (mut redirector, rx, sequence) =
+            fail_over_stream(&proxy, ResumeStrategy::Freeze, rules);
+        let key = (50000, testing::tcp_dst());
+
+        // The source is told at once, and closes the connection in order
+        let fin = testing::receive_segment(&rx);
+        assert_eq!(fin.flags, TcpFlags::ACK | TcpFlags::FIN);
+        assert_eq!((fin.sequence, fin.acknowledgement), (sequence, 1007));
+        assert!(redirector.tcp_flows[&key].remote.is_none());
+        assert!(redirector.get_stream(&key).is_some());
+        assert_eq!(
+            redirector.get_tcp_failovers(),
+            vec![(ResumeStrategy::Fin, 1)]
+        );
+
+        // Another failover leaves the connection alone
+        let e = io::Error::from(io::ErrorKind::ConnectionRefused);
+        redirector.upstreams.report_failure(proxy.addr, &e);
+        redirector.fail_over(proxy.addr);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_fail_over_rst() {
+        let proxy = testing::EchoProxy::spawn(false);
+        let (redirector, rx, sequence) = fail_over_stream(&proxy, ResumeStrategy::Rst, vec![]);
+        let key = (50000, testing::tcp_dst());
+
+        // The source is reset, and the connection is gone
+        let rst = testing::receive_segment(&rx);
+        assert_eq!(rst.flags, TcpFlags::ACK | TcpFlags::RST);
+        assert_eq!(rst.sequence, sequence);
+        assert!(redirector.get_stream(&key).is_none());
+        assert!(!redirector.tcp_flows.contains_key(&key));
+        assert_eq!(
+            redirector.get_tcp_failovers(),
+            vec![(ResumeStrategy::Rst, 1)]
+        );
+    }
+
+    /// Passes the time of the timer wheel of the `Redirector`, and fires the timers due.
+    fn pass_time(redirector: &mut Redirector, duration: Duration) {
+        redirector.timers.skip(duration);
+        for timer in redirector.timers.advance() {
+            redirector.fire(timer);
+        }
+    }
+
+    #[test]
+    fn test_remove_without_stream() {
+        let (mut redirector, _rx) = testing::create_redirector("127.0.0.1:1080".parse().unwrap());
+        let path =
+            std::env::temp_dir().join(format!("pcap2socks-tcp-close-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let command = format!("echo $REASON $BYTES_UP >> {}", path.display());
+        redirector.hooks = Hooks::new(None, Some(command), Vec::new());
+        redirector.hooks.start(&redirector.threads).unwrap();
+        let key = (50000, testing::tcp_dst());
+        let src = SocketAddrV4::new(testing::SRC_IP_ADDR, key.0);
+        let timers = redirector.timers.len();
+
+        // A flow opened whose stream is gone, and bridged to the gateway
+        let hook_flow = redirector.hooks.open(Protocol::Tcp, src, key.1, None);
+        let handle = redirector
+            .timers
+            .schedule(Duration::from_secs(60), Timer::TcpBridge(key));
+        let flow = redirector.get_tcp_flow(key);
+        flow.flow = Some(hook_flow);
+        flow.bridge = Some((Instant::now(), handle));
+
+        // The flow is closed and the timer of the bridge is cancelled
+        redirector.remove_key(key, "reset");
+        assert!(redirector.tcp_flows.is_empty());
+        assert_eq!(redirector.timers.len(), timers);
+        let instant = Instant::now();
+        while std::fs::read_to_string(&path).unwrap_or_default() != "reset 0\n" {
+            assert!(instant.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_half_open_expiry() {
+        let proxy = testing::EchoProxy::spawn(false);
+        let (mut redirector, rx) = testing::create_redirector(proxy.addr);
+        let key = (50000, testing::tcp_dst());
+        testing::handle_segment(&mut redirector, key.0, 1000, 0, TcpFlags::SYN, &[]);
+        testing::wait_connected(&mut redirector, key.0);
+        let syn = testing::receive_segment(&rx);
+        assert_eq!(syn.flags, TcpFlags::SYN | TcpFlags::ACK);
+
+        // The ACK/SYN is never acknowledged
+        pass_time(&mut redirector, Duration::from_millis(9990));
+        assert!(rx.try_recv().is_err());
+        pass_time(&mut redirector, Duration::from_millis(20));
+        let rst = testing::receive_segment(&rx);
+        assert_eq!(rst.flags, TcpFlags::ACK | TcpFlags::RST);
+        assert_eq!(rst.acknowledgement, 1001);
+        assert!(redirector.get_stream(&key).is_none());
+        assert_eq!(redirector.get_tcp_half_open_count(), 0);
+        assert_eq!(redirector.get_tcp_half_open_expired(), 1);
+    }
+
+    #[test]
+    fn test_half_open_slow_client() {
+        let proxy = testing::EchoProxy::spawn(false);
+        let (mut redirector, rx) = testing::create_redirector(proxy.addr);
+        let key = (50000, testing::tcp_dst());
+        testing::handle_segment(&mut redirector, key.0, 1000, 0, TcpFlags::SYN, &[]);
+        testing::wait_connected(&mut redirector, key.0);
+        let syn = testing::receive_segment(&rx);
+        let sequence = syn.sequence.wrapping_add(1);
+
+        // The source acknowledges the ACK/SYN after 9 seconds
+        pass_time(&mut redirector, Duration::from_secs(9));
+        testing::handle_segment(&mut redirector, key.0, 1001, sequence, TcpFlags::ACK, &[]);
+        assert!(redirector.tcp_flows[&key].half_open.is_none());
+        pass_time(&mut redirector, Duration::from_secs(2));
+        assert!(rx.try_recv().is_err());
+
+        let flags = TcpFlags::ACK | TcpFlags::PSH;
+        testing::handle_segment(&mut redirector, key.0, 1001, sequence, flags, b"slow");
+        assert_eq!(testing::receive_payload(&rx, 4), b"slow".to_vec());
+        assert_eq!(redirector.get_tcp_half_open_expired(), 0);
+    }
+
+    #[test]
+    fn test_half_open_cap() {
+        let proxy = testing::EchoProxy::spawn(true);
+        let (mut redirector, rx) = testing::create_redirector(proxy.addr);
+        redirector.set_tcp_half_open(Duration::from_secs(10), 2, HalfOpenOverflow::Reset);
+
+        // Connections whose proxy never answers stay half-open
+        testing::handle_segment(&mut redirector, 50000, 1000, 0, TcpFlags::SYN, &[]);
+        testing::handle_segment(&mut redirector, 50001, 1000, 0, TcpFlags::SYN, &[]);
+        assert_eq!(redirector.get_tcp_half_open_count(), 2);
+
+        // Reset
+        testing::handle_segment(&mut redirector, 50002, 1000, 0, TcpFlags::SYN, &[]);
+        let rst = testing::receive_segment(&rx);
+        assert_eq!(rst.flags, TcpFlags::ACK | TcpFlags::RST);
+        assert_eq!(rst.acknowledgement, 1001);
+
+        // Drop
+        redirector.set_tcp_half_open(Duration::from_secs(10), 2, HalfOpenOverflow::Drop);
+        testing::handle_segment(&mut redirector, 50003, 1000, 0, TcpFlags::SYN, &[]);
+        assert!(rx.try_recv().is_err());
+        assert_eq!(redirector.get_tcp_half_open_refused(), 2);
+        assert_eq!(redirector.get_stream_count(), 2);
+        assert_eq!(redirector.get_tcp_half_open_count(), 2);
+
+        // Connections still connecting are left to the connect timeout of their workers
+        pass_time(&mut redirector, Duration::from_secs(11));
+        assert!(rx.try_recv().is_err());
+        assert_eq!(redirector.get_tcp_half_open_count(), 2);
+        assert_eq!(redirector.get_tcp_half_open_expired(), 0);
+    }
+
+    #[test]
+    fn test_tcp_cookie() {
+        let (mut forwarder, rx) = testing::forwarder(1500);
+        let dst = testing::tcp_dst();
+        forwarder
+            .send_tcp_ack_syn_cookie(dst, 50000, 1000, 1400)
+            .unwrap();
+        forwarder.remove(dst, 50000);
+        assert!(forwarder.get_tcp_keys().is_empty());
+
+        let frame = rx.try_recv().unwrap();
+        let indicator = Indicator::from(&frame).unwrap();
+        let tcp = indicator.get_tcp().unwrap();
+        assert_eq!(tcp.get_flags(), TcpFlags::SYN | TcpFlags::ACK);
+        assert_eq!(tcp.get_wscale(), None);
+        let cookie = tcp.get_sequence();
+
+        // The MSS is rounded down to the one encoded
+        let ack = cookie.wrapping_add(1);
+        assert_eq!(
+            forwarder.check_tcp_cookie(dst, 50000, 1001, ack),
+            Some(1300)
+        );
+        assert_eq!(forwarder.check_tcp_cookie(dst, 50000, 1001, ack ^ 4), None);
+        assert_eq!(forwarder.check_tcp_cookie(dst, 50000, 1002, ack), None);
+        assert_eq!(forwarder.check_tcp_cookie(dst, 50001, 1001, ack), None);
+    }
+
+    #[test]
+    fn test_half_open_cookie() {
+        let proxy = testing::EchoProxy::spawn(false);
+        let (mut redirector, rx) = testing::create_redirector(proxy.addr);
+        redirector.set_tcp_half_open(Duration::from_secs(10), 0, HalfOpenOverflow::Cookie);
+        let key = (50000, testing::tcp_dst());
+
+        // The SYN is answered without any state
+        testing::handle_segment(&mut redirector, key.0, 1000, 0, TcpFlags::SYN, &[]);
+        let syn = testing::receive_segment(&rx);
+        assert_eq!(syn.flags, TcpFlags::SYN | TcpFlags::ACK);
+        assert_eq!(syn.acknowledgement, 1001);
+        assert_eq!(redirector.get_stream_count(), 0);
+        assert!(redirector.tx.lock().unwrap().get_tcp_keys().is_empty());
+        let sequence = syn.sequence.wrapping_add(1);
+
+        // A forged acknowledgement is reset
+        testing::handle_segment(&mut redirector, 50001, 1001, sequence, TcpFlags::ACK, &[]);
+        let rst = testing::receive_segment(&rx);
+        assert_eq!(rst.flags, TcpFlags::ACK | TcpFlags::RST);
+        assert_eq!(redirector.get_stream_count(), 0);
+
+        // The acknowledgement of the cookie opens the connection, which takes data before the
+        // proxy connects
+        testing::handle_segment(&mut redirector, key.0, 1001, sequence, TcpFlags::ACK, &[]);
+        assert!(redirector.get_stream(&key).is_some());
+        assert!(redirector.tcp_flows[&key].half_open.is_none());
+        let flags = TcpFlags::ACK | TcpFlags::PSH;
+        testing::handle_segment(&mut redirector, key.0, 1001, sequence, flags, b"cookie");
+        testing::wait_connected(&mut redirector, key.0);
+        assert_eq!(testing::receive_payload(&rx, 6), b"cookie".to_vec());
+        assert_eq!(redirector.get_tcp_cookies_accepted(), 1);
+        assert_eq!(redirector.get_tcp_half_open_refused(), 1);
+    }
+}
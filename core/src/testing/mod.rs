@@ -0,0 +1,577 @@
+//! Helpers shared by the unit tests, which stand in for the capture of the source.
+
+use pnet::datalink::{DataLinkSender, MacAddr, NetworkInterface};
+use pnet::packet::tcp::TcpFlags;
+use std::io::{self, Read, Write};
+use std::net::{
+    IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, SocketAddrV4, TcpListener, TcpStream,
+    UdpSocket,
+};
+use std::sync::atomic::{self, AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::packet::builder::Builder;
+use crate::packet::layer::Layer;
+use crate::packet::Indicator;
+use crate::pcap::HardwareAddr;
+use crate::socks::set_linger_zero;
+use crate::upstream::Upstreams;
+use crate::{Forwarder, Redirector};
+
+pub const SRC_HARDWARE_ADDR: HardwareAddr = MacAddr(2, 0, 0, 0, 0, 2);
+pub const LOCAL_HARDWARE_ADDR: HardwareAddr = MacAddr(2, 0, 0, 0, 0, 1);
+pub const SRC_IP_ADDR: Ipv4Addr = Ipv4Addr::new(10, 6, 0, 2);
+pub const LOCAL_IP_ADDR: Ipv4Addr = Ipv4Addr::new(10, 6, 0, 1);
+/// Represents the destination of the frames of the source created by the helpers.
+pub const DST_IP_ADDR: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+/// Represents the offset of the transport layer in the frames created by the helpers.
+pub const TRANSPORT_OFFSET: usize = 34;
+
+/// Represents the sending side of a capture, which hands the frames to a channel.
+pub struct FrameSender(mpsc::Sender<Vec<u8>>);
+
+impl DataLinkSender for FrameSender {
+    fn build_and_send(
+        &mut self,
+        num_packets: usize,
+        packet_size: usize,
+        func: &mut dyn FnMut(&mut [u8]),
+    ) -> Option<io::Result<()>> {
+        for _ in 0..num_packets {
+            let mut frame = vec![0u8; packet_size];
+            func(&mut frame);
+            let _ = self.0.send(frame);
+        }
+
+        Some(Ok(()))
+    }
+
+    fn send_to(&mut self, packet: &[u8], _: Option<NetworkInterface>) -> Option<io::Result<()>> {
+        let _ = self.0.send(packet.to_vec());
+
+        Some(Ok(()))
+    }
+}
+
+/// Creates a `Forwarder` of the given MTU to the source, and the channel of the frames it sends.
+pub fn forwarder(mtu: u16) -> (Forwarder, Receiver<Vec<u8>>) {
+    forwarder_of(mtu, SRC_IP_ADDR)
+}
+
+/// Creates a `Forwarder` of the given MTU to another source of the given address, and the channel
+/// of the frames it sends.
+pub fn forwarder_of(mtu: u16, src_ip_addr: Ipv4Addr) -> (Forwarder, Receiver<Vec<u8>>) {
+    let (tx, rx) = mpsc::channel();
+    let mut forwarder = Forwarder::new(
+        Box::new(FrameSender(tx)),
+        mtu,
+        LOCAL_HARDWARE_ADDR,
+        src_ip_addr,
+        LOCAL_IP_ADDR,
+    );
+    forwarder.set_src_hardware_addr(SRC_HARDWARE_ADDR);
+
+    (forwarder, rx)
+}
+
+/// Creates the frame of a UDP datagram of the source with the given ports and payload.
+pub fn udp_frame(src_port: u16, dst_port: u16, payload: &[u8]) -> Vec<u8> {
+    let mut builder = Builder::new(SRC_HARDWARE_ADDR, LOCAL_HARDWARE_ADDR);
+    builder.set_ipv4(1, SRC_IP_ADDR, DST_IP_ADDR);
+    builder.set_udp(src_port, dst_port);
+
+    builder.build(payload).unwrap()
+}
+
+/// Creates the frame of a TCP SYN of the source with the given ports.
+pub fn tcp_frame(src_port: u16, dst_port: u16) -> Vec<u8> {
+    let mut builder = Builder::new(SRC_HARDWARE_ADDR, LOCAL_HARDWARE_ADDR);
+    builder.set_ipv4(1, SRC_IP_ADDR, DST_IP_ADDR);
+    builder.set_tcp(src_port, dst_port, 1000, 0, 65535, TcpFlags::SYN);
+
+    builder.build(&[]).unwrap()
+}
+
+/// Represents a mock SOCKS5 proxy without authentication, which connects to the destinations of
+/// CONNECT and relays the datagrams of UDP ASSOCIATE. The client of an association is the sender
+/// of its first datagram, and datagrams of any other remote are relayed to the client, like most
+/// proxies do. The relay may answer from a shifted port, like relays of load balanced backends. A
+/// client closing a CONNECT in order closes the destination in order, and one resetting it resets
+/// the destination.
+pub struct MockProxy {
+    addr: SocketAddr,
+    relays: Arc<Mutex<Vec<Relay>>>,
+}
+
+/// Represents the relay of an association of a `MockProxy`.
+#[derive(Clone)]
+struct Relay {
+    addr: SocketAddr,
+    client: Arc<Mutex<Option<SocketAddr>>>,
+    /// Represents if the control connection of the association is open.
+    is_open: Arc<AtomicBool>,
+}
+
+impl MockProxy {
+    /// Spawns a new `MockProxy` listening on the IP address.
+    pub fn spawn(ip_addr: IpAddr) -> io::Result<MockProxy> {
+        MockProxy::spawn_shifted(ip_addr, 0)
+    }
+
+    /// Spawns a new `MockProxy` listening on the IP address, whose relays answer datagrams of a
+    /// remote as from its port plus the shift.
+    pub fn spawn_shifted(ip_addr: IpAddr, port_shift: u16) -> io::Result<MockProxy> {
+        let listener = TcpListener::bind(SocketAddr::new(ip_addr, 0))?;
+        let addr = listener.local_addr()?;
+        let relays = Arc::new(Mutex::new(Vec::new()));
+        let relays_cloned = Arc::clone(&relays);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let relays = Arc::clone(&relays_cloned);
+                thread::spawn(move || serve(stream, &relays, port_shift));
+            }
+        });
+
+        Ok(MockProxy { addr, relays })
+    }
+
+    /// Get the address of the proxy.
+    pub fn get_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Get the addresses of the relays of the associations whose client is known.
+    pub fn get_relays(&self) -> Vec<SocketAddr> {
+        self.relays
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|relay| relay.client.lock().unwrap().is_some())
+            .map(|relay| relay.addr)
+            .collect()
+    }
+
+    /// Get the number of associations whose control connection is open.
+    pub fn get_associations(&self) -> usize {
+        self.relays
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|relay| relay.is_open.load(Ordering::Relaxed))
+            .count()
+    }
+}
+
+fn serve(mut stream: TcpStream, relays: &Mutex<Vec<Relay>>, port_shift: u16) -> io::Result<()> {
+    // Methods
+    let mut head = [0u8; 2];
+    stream.read_exact(&mut head)?;
+    let mut methods = vec![0u8; head[1] as usize];
+    stream.read_exact(&mut methods)?;
+    stream.write_all(&[5, 0])?;
+
+    // Request
+    let mut request = [0u8; 3];
+    stream.read_exact(&mut request)?;
+    let dst = read_addr(&mut stream)?;
+    match request[1] {
+        // CONNECT
+        1 => {
+            let mut remote = match TcpStream::connect(dst) {
+                Ok(remote) => remote,
+                Err(_) => {
+                    // Connection refused
+                    stream.write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0])?;
+                    return Ok(());
+                }
+            };
+            let mut reply = vec![5, 0, 0];
+            write_addr(&mut reply, remote.local_addr()?);
+            stream.write_all(&reply)?;
+
+            let (mut stream_cloned, mut remote_cloned) = (stream.try_clone()?, remote.try_clone()?);
+            let up = thread::spawn(move || {
+                match io::copy(&mut stream_cloned, &mut remote_cloned) {
+                    Ok(_) => {
+                        let _ = remote_cloned.shutdown(Shutdown::Write);
+                    }
+                    // Reset the destination once both sides are dropped
+                    Err(_) => {
+                        let _ = set_linger_zero(&remote_cloned);
+                        let _ = remote_cloned.shutdown(Shutdown::Read);
+                    }
+                }
+            });
+            let _ = io::copy(&mut remote, &mut stream);
+            let _ = stream.shutdown(Shutdown::Write);
+            let _ = up.join();
+        }
+        // UDP ASSOCIATE
+        3 => {
+            // A relay on IPv6 is dual-stack, so it reaches IPv4 destinations
+            let ip_addr = stream.local_addr()?.ip();
+            let socket = match ip_addr {
+                IpAddr::V4(_) => UdpSocket::bind(SocketAddr::new(ip_addr, 0))?,
+                IpAddr::V6(_) => UdpSocket::bind(SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0))?,
+            };
+            socket.set_read_timeout(Some(Duration::from_millis(10)))?;
+            let relay = Relay {
+                addr: SocketAddr::new(ip_addr, socket.local_addr()?.port()),
+                client: Arc::new(Mutex::new(None)),
+                is_open: Arc::new(AtomicBool::new(true)),
+            };
+            relays.lock().unwrap().push(relay.clone());
+            let mut reply = vec![5, 0, 0];
+            write_addr(&mut reply, relay.addr);
+            stream.write_all(&reply)?;
+
+            // The association ends with the control connection
+            let is_open = Arc::clone(&relay.is_open);
+            let relaying = thread::spawn(move || relay_datagrams(socket, &relay, port_shift));
+            let _ = stream.read(&mut [0u8; 1]);
+            is_open.store(false, Ordering::Relaxed);
+            let _ = relaying.join();
+        }
+        _ => {
+            // Command not supported
+            stream.write_all(&[5, 7, 0, 1, 0, 0, 0, 0, 0, 0])?;
+        }
+    }
+
+    Ok(())
+}
+
+fn relay_datagrams(socket: UdpSocket, relay: &Relay, port_shift: u16) {
+    let mut buffer = [0u8; u16::MAX as usize];
+    while relay.is_open.load(Ordering::Relaxed) {
+        let (size, addr) = match socket.recv_from(&mut buffer) {
+            Ok((size, addr)) => (size, unmap(addr)),
+            Err(_) => continue,
+        };
+        let mut client_locked = relay.client.lock().unwrap();
+        let client = *client_locked.get_or_insert(addr);
+        drop(client_locked);
+        if addr == client {
+            // From the client, with the header of the destination
+            let mut datagram = &buffer[3..size];
+            let dst = match read_addr(&mut datagram) {
+                Ok(dst) => dst,
+                Err(_) => continue,
+            };
+            // The datagram the client learns the relay with
+            if dst.ip().is_unspecified() {
+                continue;
+            }
+            let _ = socket.send_to(datagram, map(&socket, dst));
+        } else {
+            let mut datagram = vec![0, 0, 0];
+            let port = addr.port().wrapping_add(port_shift);
+            write_addr(&mut datagram, SocketAddr::new(addr.ip(), port));
+            datagram.extend_from_slice(&buffer[..size]);
+            let _ = socket.send_to(&datagram, map(&socket, client));
+        }
+    }
+}
+
+/// Maps the IPv4 address to IPv6 if the socket is on IPv6.
+fn map(socket: &UdpSocket, addr: SocketAddr) -> SocketAddr {
+    match (socket.local_addr(), addr) {
+        (Ok(SocketAddr::V6(_)), SocketAddr::V4(addr)) => {
+            SocketAddr::new(addr.ip().to_ipv6_mapped().into(), addr.port())
+        }
+        _ => addr,
+    }
+}
+
+/// Maps the IPv4-mapped IPv6 address back to IPv4.
+fn unmap(addr: SocketAddr) -> SocketAddr {
+    if let SocketAddr::V6(addr_v6) = addr {
+        let octets = addr_v6.ip().octets();
+        if octets[..12] == [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff] {
+            let ip_addr = Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]);
+            return SocketAddr::new(ip_addr.into(), addr.port());
+        }
+    }
+
+    addr
+}
+
+fn read_addr<R: Read>(reader: &mut R) -> io::Result<SocketAddr> {
+    let mut kind = [0u8; 1];
+    reader.read_exact(&mut kind)?;
+    let ip_addr = match kind[0] {
+        1 => {
+            let mut octets = [0u8; 4];
+            reader.read_exact(&mut octets)?;
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        4 => {
+            let mut octets = [0u8; 16];
+            reader.read_exact(&mut octets)?;
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return Err(io::Error::from(io::ErrorKind::InvalidData)),
+    };
+    let mut port = [0u8; 2];
+    reader.read_exact(&mut port)?;
+
+    Ok(SocketAddr::new(ip_addr, u16::from_be_bytes(port)))
+}
+
+fn write_addr(buffer: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip_addr) => {
+            buffer.push(1);
+            buffer.extend_from_slice(&ip_addr.octets());
+        }
+        IpAddr::V6(ip_addr) => {
+            buffer.push(4);
+            buffer.extend_from_slice(&ip_addr.octets());
+        }
+    }
+    buffer.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+/// Represents a TCP segment to the source.
+pub struct Segment {
+    pub flags: u16,
+    pub sequence: u32,
+    pub acknowledgement: u32,
+    pub payload: Vec<u8>,
+}
+
+/// Represents a SOCKS5 proxy echoing what it receives after the CONNECT.
+pub struct EchoProxy {
+    pub addr: SocketAddr,
+    bytes: Arc<AtomicUsize>,
+    pub release: mpsc::Sender<()>,
+}
+
+impl EchoProxy {
+    /// Spawns a new `EchoProxy`, which answers the CONNECT once released if it is held.
+    pub fn spawn(is_held: bool) -> EchoProxy {
+        use std::io::{Read, Write};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let bytes = Arc::new(AtomicUsize::new(0));
+        let (release, release_rx) = mpsc::channel::<()>();
+        let received = Arc::clone(&bytes);
+        thread::spawn(move || -> io::Result<()> {
+            let (mut stream, _) = listener.accept()?;
+            let mut buffer = [0u8; 1024];
+            stream.read_exact(&mut buffer[..3])?;
+            stream.write_all(&[5, 0])?;
+            stream.read_exact(&mut buffer[..10])?;
+            if is_held {
+                let _ = release_rx.recv();
+            }
+            stream.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])?;
+            loop {
+                let size = stream.read(&mut buffer)?;
+                if size == 0 {
+                    return Ok(());
+                }
+                received.fetch_add(size, atomic::Ordering::SeqCst);
+                stream.write_all(&buffer[..size])?;
+            }
+        });
+
+        EchoProxy {
+            addr,
+            bytes,
+            release,
+        }
+    }
+
+    /// Get the bytes received after the CONNECT.
+    pub fn get_bytes(&self) -> usize {
+        self.bytes.load(atomic::Ordering::SeqCst)
+    }
+}
+
+/// Get the destination of TCP tests, which the proxy answers by itself.
+pub fn tcp_dst() -> SocketAddrV4 {
+    SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 80)
+}
+
+/// Creates a new `Redirector` of the proxy, and the receiver of frames to the source.
+pub fn create_redirector(proxy: SocketAddr) -> (Redirector, Receiver<Vec<u8>>) {
+    use crate::upstream::{Fallback, Policy};
+
+    let (forwarder, rx) = forwarder(1500);
+    let upstreams = Upstreams::new(vec![proxy], Policy::First, Fallback::Fail).unwrap();
+    let redirector = Redirector::new(
+        Arc::new(Mutex::new(forwarder)),
+        SRC_IP_ADDR,
+        None,
+        upstreams,
+        30000,
+        64,
+    );
+
+    (redirector, rx)
+}
+
+/// Handles a TCP segment of the source port to the destination of TCP tests.
+pub fn handle_segment(
+    redirector: &mut Redirector,
+    src_port: u16,
+    sequence: u32,
+    acknowledgement: u32,
+    flags: u16,
+    payload: &[u8],
+) {
+    let dst = tcp_dst();
+    handle_segment_to(
+        redirector,
+        dst,
+        src_port,
+        sequence,
+        acknowledgement,
+        flags,
+        payload,
+    );
+}
+
+/// Handles a TCP segment of the source port to the destination.
+pub fn handle_segment_to(
+    redirector: &mut Redirector,
+    dst: SocketAddrV4,
+    src_port: u16,
+    sequence: u32,
+    acknowledgement: u32,
+    flags: u16,
+    payload: &[u8],
+) {
+    let mut builder = Builder::new(SRC_HARDWARE_ADDR, LOCAL_HARDWARE_ADDR);
+    builder.set_ipv4(1, SRC_IP_ADDR, *dst.ip());
+    builder.set_tcp(
+        src_port,
+        dst.port(),
+        sequence,
+        acknowledgement,
+        65535,
+        flags,
+    );
+    let frame = builder.build(payload).unwrap();
+    let indicator = Indicator::from(&frame).unwrap();
+    let size = indicator.get_ethernet().unwrap().get_size()
+        + indicator.get_ipv4().unwrap().get_total_length() as usize;
+    let frame = frame[..size].to_vec();
+    let indicator = Indicator::from(&frame).unwrap();
+    redirector.handle_tcp(&indicator, &frame).unwrap();
+}
+
+/// Waits for the next TCP segment to the source.
+pub fn receive_segment(rx: &Receiver<Vec<u8>>) -> Segment {
+    let frame = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    let indicator = Indicator::from(&frame).unwrap();
+    let tcp = indicator.get_tcp().unwrap();
+    let size = indicator.get_ethernet().unwrap().get_size()
+        + indicator.get_ipv4().unwrap().get_total_length() as usize;
+
+    Segment {
+        flags: tcp.get_flags(),
+        sequence: tcp.get_sequence(),
+        acknowledgement: tcp.get_acknowledgement(),
+        payload: frame[indicator.get_size()..size].to_vec(),
+    }
+}
+
+/// Waits until the connection of the source port is connected through the proxy.
+pub fn wait_connected(redirector: &mut Redirector, src_port: u16) {
+    wait_connected_to(redirector, (src_port, tcp_dst()));
+}
+
+/// Waits until the connection is connected through the proxy.
+pub fn wait_connected_to(redirector: &mut Redirector, key: (u16, SocketAddrV4)) {
+    let instant = Instant::now();
+    while redirector
+        .tcp_flows
+        .get(&key)
+        .is_some_and(|flow| flow.is_connecting)
+    {
+        assert!(instant.elapsed() < Duration::from_secs(5));
+        redirector.poll_connecting();
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// Opens a TCP connection of the source port at the sequence 1000, and returns the next
+/// sequence of the `Redirector`.
+pub fn establish(redirector: &mut Redirector, rx: &Receiver<Vec<u8>>, src_port: u16) -> u32 {
+    establish_to(redirector, rx, tcp_dst(), src_port)
+}
+
+/// Opens a TCP connection of the source port to the destination at the sequence 1000, and
+/// returns the next sequence of the `Redirector`.
+pub fn establish_to(
+    redirector: &mut Redirector,
+    rx: &Receiver<Vec<u8>>,
+    dst: SocketAddrV4,
+    src_port: u16,
+) -> u32 {
+    handle_segment_to(redirector, dst, src_port, 1000, 0, TcpFlags::SYN, &[]);
+    wait_connected_to(redirector, (src_port, dst));
+    let syn = receive_segment(rx);
+    assert_eq!(syn.flags, TcpFlags::SYN | TcpFlags::ACK);
+    assert_eq!(syn.acknowledgement, 1001);
+    let sequence = syn.sequence.wrapping_add(1);
+    handle_segment_to(
+        redirector,
+        dst,
+        src_port,
+        1001,
+        sequence,
+        TcpFlags::ACK,
+        &[],
+    );
+
+    sequence
+}
+
+/// Receives segments to the source until their payloads have the given size, and returns
+/// the payloads joined.
+pub fn receive_payload(rx: &Receiver<Vec<u8>>, size: usize) -> Vec<u8> {
+    let mut payload = Vec::new();
+    while payload.len() < size {
+        let segment = receive_segment(rx);
+        assert_eq!(segment.flags & TcpFlags::RST, 0);
+        payload.extend_from_slice(&segment.payload);
+    }
+
+    payload
+}
+
+/// Spawns a server reading the size of data from a connection, and then the remaining until
+/// the connection closes, and returns its address and the results of both reads.
+pub fn spawn_read_server(size: usize) -> (SocketAddrV4, Receiver<io::Result<Vec<u8>>>) {
+    use std::io::Read;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = match listener.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        _ => unreachable!(),
+    };
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut data = vec![0u8; size];
+        let _ = tx.send(stream.read_exact(&mut data).map(|_| data));
+        let mut data = Vec::new();
+        let _ = tx.send(stream.read_to_end(&mut data).map(|_| data));
+    });
+
+    (addr, rx)
+}
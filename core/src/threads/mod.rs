@@ -0,0 +1,345 @@
+use log::{debug, trace, warn};
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+/// Represents the stack size of a per-flow thread. The handshakes and the poller keep their
+/// buffers in the heap, so only the forwarding call chain lives in the stack.
+pub const FLOW_STACK_SIZE: usize = 128 * 1024;
+
+/// Represents the stack size of a thread running a whole capture or commands of the control,
+/// which is the default of Rust.
+pub const DEFAULT_STACK_SIZE: usize = 2 * 1024 * 1024;
+
+/// Represents the purpose of a thread.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Purpose {
+    /// Serves a SOCKS5 TCP stream, which takes a place without a thread of its own.
+    Stream,
+    /// Serves a SOCKS5 UDP client, which takes a place without a thread of its own.
+    Datagram,
+    /// Connects a SOCKS5 TCP stream, in the place of the stream.
+    Handshake,
+    /// Receives from all the SOCKS5 TCP streams and UDP clients.
+    Poll,
+    /// Runs hooks.
+    Hook,
+    /// Delivers notifications.
+    Notify,
+    /// Serves HTTP endpoints.
+    Http,
+    /// Exchanges heartbeats with the peer in the warm standby.
+    Standby,
+    /// Saves files off the capture.
+    Save,
+    /// Fills the pool of pre-warmed connections to the proxy.
+    Prewarm,
+    /// Redirects a capture other than the first one, which runs in the main thread.
+    Capture,
+    /// Applies commands of the control file or the control socket.
+    Control,
+    /// Logs summaries of the monitor.
+    Summary,
+    /// Hands off to a new process.
+    Handoff,
+}
+
+impl Purpose {
+    /// Returns if the thread serves a single flow.
+    pub fn is_per_flow(&self) -> bool {
+        match self {
+            Purpose::Stream | Purpose::Datagram => true,
+            Purpose::Handshake
+            | Purpose::Poll
+            | Purpose::Hook
+            | Purpose::Notify
+            | Purpose::Http
+            | Purpose::Standby
+            | Purpose::Save
+            | Purpose::Prewarm
+            | Purpose::Capture
+            | Purpose::Control
+            | Purpose::Summary
+            | Purpose::Handoff => false,
+        }
+    }
+
+    /// Get the stack size of threads of this purpose.
+    pub fn get_stack_size(&self) -> usize {
+        match self {
+            Purpose::Stream
+            | Purpose::Datagram
+            | Purpose::Handshake
+            | Purpose::Poll
+            | Purpose::Hook
+            | Purpose::Notify
+            | Purpose::Http
+            | Purpose::Standby
+            | Purpose::Save
+            | Purpose::Prewarm
+            | Purpose::Summary => FLOW_STACK_SIZE,
+            Purpose::Capture | Purpose::Control | Purpose::Handoff => DEFAULT_STACK_SIZE,
+        }
+    }
+}
+
+impl Display for Purpose {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Purpose::Stream => write!(f, "stream"),
+            Purpose::Datagram => write!(f, "datagram"),
+            Purpose::Handshake => write!(f, "handshake"),
+            Purpose::Poll => write!(f, "poll"),
+            Purpose::Hook => write!(f, "hook"),
+            Purpose::Notify => write!(f, "notify"),
+            Purpose::Http => write!(f, "http"),
+            Purpose::Standby => write!(f, "standby"),
+            Purpose::Save => write!(f, "save"),
+            Purpose::Prewarm => write!(f, "prewarm"),
+            Purpose::Capture => write!(f, "capture"),
+            Purpose::Control => write!(f, "control"),
+            Purpose::Summary => write!(f, "summary"),
+            Purpose::Handoff => write!(f, "handoff"),
+        }
+    }
+}
+
+/// Represents the information of a thread in the registry.
+#[derive(Clone, Debug)]
+pub struct ThreadInfo {
+    pub name: String,
+    pub purpose: Purpose,
+    pub spawned: Instant,
+}
+
+impl Display for ThreadInfo {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}, {} s)",
+            self.name,
+            self.purpose,
+            self.spawned.elapsed().as_secs()
+        )
+    }
+}
+
+/// Represents the registry of all the threads spawned by pcap2socks, and of the flows served by
+/// the poller, which take a place each like a thread.
+#[derive(Debug)]
+pub struct Threads {
+    max: usize,
+    next_id: AtomicUsize,
+    threads: Mutex<HashMap<usize, ThreadInfo>>,
+}
+
+impl Threads {
+    /// Creates a new `Threads` with the given max number of threads.
+    pub fn new(max: usize) -> Threads {
+        Threads {
+            max,
+            next_id: AtomicUsize::new(0),
+            threads: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records a flow without a thread of its own in the registry, which is removed once the
+    /// returned `Slot` is dropped. Flows are refused if the registry is full.
+    pub fn reserve(registry: &Arc<Threads>, name: String, purpose: Purpose) -> io::Result<Slot> {
+        let guard = Threads::record(registry, &name, purpose)?;
+        trace!("reserve place {} ({})", name, purpose);
+
+        Ok(Slot(guard))
+    }
+
+    /// Spawns a new thread and records it in the registry. Per-flow threads are refused if the
+    /// registry is full.
+    pub fn spawn<F>(
+        registry: &Arc<Threads>,
+        name: String,
+        purpose: Purpose,
+        f: F,
+    ) -> io::Result<JoinHandle<()>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let guard = Threads::record(registry, &name, purpose)?;
+        let result = thread::Builder::new()
+            .name(name.clone())
+            .stack_size(purpose.get_stack_size())
+            .spawn(move || {
+                let _guard = guard;
+                f();
+            });
+        // The guard is dropped with the closure if the thread cannot be spawned
+
+        trace!("spawn thread {} ({})", name, purpose);
+
+        result
+    }
+
+    fn record(registry: &Arc<Threads>, name: &str, purpose: Purpose) -> io::Result<Guard> {
+        let id = registry.next_id.fetch_add(1, Ordering::Relaxed);
+        let is_full = {
+            let mut threads_locked = registry.threads.lock().unwrap();
+            let is_full = purpose.is_per_flow() && threads_locked.len() >= registry.max;
+            if !is_full {
+                threads_locked.insert(
+                    id,
+                    ThreadInfo {
+                        name: String::from(name),
+                        purpose,
+                        spawned: Instant::now(),
+                    },
+                );
+            }
+
+            is_full
+        };
+        if is_full {
+            warn!(
+                "refuse thread {}: reach the limit of {} threads",
+                name, registry.max
+            );
+            debug!("{}", registry);
+            return Err(io::Error::other("too many threads"));
+        }
+
+        Ok(Guard {
+            registry: Arc::clone(registry),
+            id,
+        })
+    }
+
+    /// Get the number of threads and places in the registry.
+    pub fn len(&self) -> usize {
+        self.threads.lock().unwrap().len()
+    }
+
+    /// Returns if there is no thread or place in the registry.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the information of all threads in the registry, sorted by the spawn time.
+    pub fn dump(&self) -> Vec<ThreadInfo> {
+        let mut threads: Vec<ThreadInfo> = self.threads.lock().unwrap().values().cloned().collect();
+        threads.sort_by_key(|info| info.spawned);
+
+        threads
+    }
+}
+
+impl Display for Threads {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let threads = self.dump();
+        write!(f, "{} / {} threads", threads.len(), self.max)?;
+        for info in threads {
+            write!(f, "\n    {}", info)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Represents the place of a flow without a thread of its own in the registry.
+pub struct Slot(#[allow(dead_code)] Guard);
+
+/// Represents a guard removes the thread from the registry when it exits.
+struct Guard {
+    registry: Arc<Threads>,
+    id: usize,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if let Ok(mut threads_locked) = self.registry.threads.lock() {
+            threads_locked.remove(&self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream};
+    use std::sync::mpsc;
+
+    use crate::socks::Forward;
+    use crate::testing;
+
+    #[test]
+    fn test_cap() {
+        let registry = Arc::new(Threads::new(2));
+        let first = Threads::reserve(&registry, String::from("first"), Purpose::Stream).unwrap();
+        let _second =
+            Threads::reserve(&registry, String::from("second"), Purpose::Datagram).unwrap();
+
+        // Per-flow places and threads are refused once full
+        assert!(Threads::reserve(&registry, String::from("third"), Purpose::Stream).is_err());
+        assert!(Threads::spawn(&registry, String::from("third"), Purpose::Stream, || {}).is_err());
+        assert_eq!(registry.dump().len(), 2);
+        assert_eq!(registry.len(), 2);
+
+        // Other threads are not
+        let (tx, rx) = mpsc::channel();
+        Threads::spawn(&registry, String::from("hook"), Purpose::Hook, move || {
+            tx.send(()).unwrap();
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+        rx.recv().unwrap();
+
+        // A place is taken again once released
+        drop(first);
+        assert_eq!(registry.dump().len(), 1);
+        let _third = Threads::reserve(&registry, String::from("third"), Purpose::Stream).unwrap();
+        assert_eq!(registry.dump().len(), 2);
+    }
+
+    #[test]
+    fn test_stack_max_read() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        client.write_all(&vec![0x5a; u16::MAX as usize]).unwrap();
+        drop(client);
+
+        // Read like the poller and forward the read to the source in the smallest stack
+        let registry = Arc::new(Threads::new(1));
+        let (tx, rx) = mpsc::channel();
+        Threads::spawn(&registry, String::from("poll"), Purpose::Poll, move || {
+            let dst = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 80);
+            let (mut forwarder, frames) = testing::forwarder(1500);
+            forwarder.set_tcp_send_window(dst, 40000, u16::MAX);
+
+            let mut buffer = vec![0u8; u16::MAX as usize];
+            let mut size = 0;
+            while size < buffer.len() {
+                match server.read(&mut buffer[size..]).unwrap() {
+                    0 => break,
+                    n => size += n,
+                }
+            }
+            forwarder
+                .forward_tcp(SocketAddr::V4(dst), 40000, &buffer[..size])
+                .unwrap();
+
+            tx.send((size, frames.try_iter().count())).unwrap();
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+
+        let (size, frames) = rx.recv().unwrap();
+        assert_eq!(size, u16::MAX as usize);
+        // Segments of 1460 Bytes in the initial congestion window
+        assert_eq!(frames, crate::congestion::INITIAL_WINDOW);
+    }
+}
@@ -0,0 +1,263 @@
+use std::cmp::min;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Represents the granularity of the timer wheel in milliseconds.
+pub const TICK: u64 = 10;
+
+/// Represents the number of slots of the timer wheel, which covers 5.12 seconds in a round.
+const SLOTS: u64 = 512;
+
+/// Represents a timer scheduled in a `TimerWheel`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct TimerHandle(u64);
+
+/// Represents a hashed timer wheel of coarse ticks, owned by the processing loop so timers fire
+/// without locking. A timer is in the slot of its deadline tick, timers of later rounds stay in
+/// the slot. Rescheduling and cancelling only update the live deadline of the timer, and entries
+/// in slots which do not match it are discarded when their slot is processed, so a timer fires
+/// once at its latest deadline and never after it is cancelled.
+#[derive(Debug)]
+pub struct TimerWheel<T> {
+    start: Instant,
+    /// Represents the next tick to process.
+    tick: u64,
+    slots: Vec<Vec<(u64, u64)>>,
+    /// Represents the map mapping a timer to its deadline tick and its value.
+    timers: HashMap<u64, (u64, T)>,
+    next_id: u64,
+}
+
+impl<T> TimerWheel<T> {
+    /// Creates a new `TimerWheel`.
+    pub fn new() -> TimerWheel<T> {
+        TimerWheel {
+            start: Instant::now(),
+            tick: 0,
+            slots: (0..SLOTS).map(|_| Vec::new()).collect(),
+            timers: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    fn get_deadline(&self, delay: Duration) -> u64 {
+        let nanos = (self.start.elapsed() + delay).as_nanos() as u64;
+        // Never before the next tick, timers fire late rather than early
+        let tick = TICK * 1_000_000;
+        let deadline = nanos.div_ceil(tick);
+
+        deadline.max(self.tick)
+    }
+
+    fn insert(&mut self, id: u64, deadline: u64) {
+        self.slots[(deadline % SLOTS) as usize].push((id, deadline));
+    }
+
+    /// Schedules a timer with the value firing after the delay.
+    pub fn schedule(&mut self, delay: Duration, value: T) -> TimerHandle {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let deadline = self.get_deadline(delay);
+        self.timers.insert(id, (deadline, value));
+        self.insert(id, deadline);
+
+        TimerHandle(id)
+    }
+
+    /// Reschedules the timer to fire after the delay from now, returns `false` if the timer has
+    /// fired or is cancelled.
+    pub fn reschedule(&mut self, handle: TimerHandle, delay: Duration) -> bool {
+        let deadline = self.get_deadline(delay);
+        match self.timers.get_mut(&handle.0) {
+            Some(timer) => {
+                timer.0 = deadline;
+                self.insert(handle.0, deadline);
+
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Cancels the timer, returns its value if it has not fired.
+    pub fn cancel(&mut self, handle: TimerHandle) -> Option<T> {
+        self.timers.remove(&handle.0).map(|(_, value)| value)
+    }
+
+    /// Processes the ticks until now, returns the values of the timers fired in the order of
+    /// their deadlines.
+    pub fn advance(&mut self) -> Vec<T> {
+        let target = self.start.elapsed().as_millis() as u64 / TICK;
+        if target < self.tick {
+            return Vec::new();
+        }
+
+        // A whole round covers all the slots if the loop stalls for long
+        let mut fired: Vec<(u64, u64)> = Vec::new();
+        let end = min(target, self.tick + SLOTS - 1);
+        for tick in self.tick..=end {
+            let slot = &mut self.slots[(tick % SLOTS) as usize];
+            if slot.is_empty() {
+                continue;
+            }
+            let timers = &self.timers;
+            slot.retain(|(id, deadline)| match timers.get(id) {
+                Some((live, _)) if live == deadline => {
+                    if *deadline <= target {
+                        fired.push((*deadline, *id));
+                        false
+                    } else {
+                        true
+                    }
+                }
+                // Rescheduled or cancelled
+                _ => false,
+            });
+        }
+        self.tick = target + 1;
+
+        fired.sort();
+        fired
+            .into_iter()
+            .filter_map(|(_, id)| self.timers.remove(&id).map(|(_, value)| value))
+            .collect()
+    }
+
+    /// Get the number of timers scheduled.
+    pub fn len(&self) -> usize {
+        self.timers.len()
+    }
+
+    /// Moves the clock of the timer wheel forward by the duration, as if the time has passed.
+    #[cfg(test)]
+    pub fn skip(&mut self, duration: Duration) {
+        self.start = self.start.checked_sub(duration).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::Random;
+    use std::collections::HashSet;
+
+    /// Represents the number of flows of the load test.
+    const FLOWS: usize = 10000;
+
+    /// Represents the number of timers of a flow, like RTO, delayed ACK, idle and keep-alive.
+    const TIMERS_PER_FLOW: usize = 4;
+
+    /// Moves the time of the wheel forward without waiting.
+    fn elapse<T>(wheel: &mut TimerWheel<T>, delay: Duration) {
+        wheel.start -= delay;
+    }
+
+    #[test]
+    fn test_fire_in_order() {
+        let mut wheel = TimerWheel::new();
+        wheel.schedule(Duration::from_millis(30), 3);
+        wheel.schedule(Duration::from_millis(10), 1);
+        wheel.schedule(Duration::from_millis(20), 2);
+        // Beyond a round of the wheel
+        wheel.schedule(Duration::from_millis(TICK * SLOTS + 20), 4);
+        assert!(wheel.advance().is_empty());
+
+        elapse(&mut wheel, Duration::from_millis(40));
+        assert_eq!(wheel.advance(), vec![1, 2, 3]);
+
+        // The timer of the next round is not fired in passing its slot
+        elapse(&mut wheel, Duration::from_millis(TICK * SLOTS - 40));
+        assert!(wheel.advance().is_empty());
+        elapse(&mut wheel, Duration::from_millis(40));
+        assert_eq!(wheel.advance(), vec![4]);
+        assert_eq!(wheel.len(), 0);
+    }
+
+    #[test]
+    fn test_cancel_and_reschedule_when_due() {
+        let mut wheel = TimerWheel::new();
+        let cancelled = wheel.schedule(Duration::from_millis(10), 1);
+        let rescheduled = wheel.schedule(Duration::from_millis(10), 2);
+        let fired = wheel.schedule(Duration::from_millis(10), 3);
+
+        // Both are due, but not processed yet
+        elapse(&mut wheel, Duration::from_millis(20));
+        assert_eq!(wheel.cancel(cancelled), Some(1));
+        assert!(wheel.reschedule(rescheduled, Duration::from_millis(50)));
+        assert_eq!(wheel.advance(), vec![3]);
+
+        // Timers which fired are neither rescheduled nor cancelled
+        assert!(!wheel.reschedule(fired, Duration::from_millis(10)));
+        assert_eq!(wheel.cancel(fired), None);
+        assert_eq!(wheel.cancel(cancelled), None);
+
+        elapse(&mut wheel, Duration::from_millis(60));
+        assert_eq!(wheel.advance(), vec![2]);
+        assert_eq!(wheel.len(), 0);
+    }
+
+    #[test]
+    fn test_load() {
+        let mut wheel = TimerWheel::new();
+        let mut random = Random::new(1);
+        let random_delay = |random: &mut Random| {
+            // Up to 2 rounds of the wheel
+            Duration::from_millis(random.next_below((2 * TICK * SLOTS) as usize) as u64)
+        };
+
+        // The handle and the earliest time to fire of each timer
+        let mut timers = Vec::with_capacity(FLOWS * TIMERS_PER_FLOW);
+        for i in 0..FLOWS * TIMERS_PER_FLOW {
+            let delay = random_delay(&mut random);
+            let due = wheel.start.elapsed() + delay;
+            timers.push((wheel.schedule(delay, i), due));
+        }
+        assert_eq!(wheel.len(), FLOWS * TIMERS_PER_FLOW);
+
+        let mut cancelled = HashSet::new();
+        let mut fired = HashSet::new();
+        let mut ticks = 0u32;
+        let mut spent = Duration::from_secs(0);
+        while wheel.len() > 0 {
+            elapse(&mut wheel, Duration::from_millis(TICK));
+            // Flows reschedule and cancel their timers between ticks, including timers due
+            for _ in 0..50 {
+                let i = random.next_below(timers.len());
+                if cancelled.contains(&i) || fired.contains(&i) {
+                    continue;
+                }
+                if random.next_below(4) == 0 {
+                    assert_eq!(wheel.cancel(timers[i].0), Some(i));
+                    cancelled.insert(i);
+                } else if ticks < 256 {
+                    let delay = random_delay(&mut random);
+                    timers[i].1 = wheel.start.elapsed() + delay;
+                    assert!(wheel.reschedule(timers[i].0, delay));
+                }
+            }
+
+            let instant = Instant::now();
+            let values = wheel.advance();
+            spent += instant.elapsed();
+            ticks += 1;
+
+            let now = wheel.start.elapsed();
+            for i in values {
+                assert!(now >= timers[i].1, "timer {} fires early", i);
+                assert!(!cancelled.contains(&i), "cancelled timer {} fires", i);
+                assert!(fired.insert(i), "timer {} fires twice", i);
+            }
+            assert!(ticks < 4 * SLOTS as u32);
+        }
+
+        // No timer is lost
+        assert_eq!(fired.len() + cancelled.len(), FLOWS * TIMERS_PER_FLOW);
+        let average = spent / ticks;
+        assert!(
+            average < Duration::from_millis(1),
+            "tick takes {:?} in average",
+            average
+        );
+    }
+}
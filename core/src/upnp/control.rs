@@ -0,0 +1,272 @@
+use log::info;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::{Lease, Leases};
+use crate::gateway::{Request, Response, Service};
+use crate::hooks::Protocol;
+
+/// Represents the default port of the UPnP control.
+pub const DEFAULT_PORT: u16 = 5000;
+
+/// Represents the path of the description of the device.
+pub const DESCRIPTION_PATH: &str = "/rootDesc.xml";
+
+/// Represents the path of the control of the WAN IP connection.
+pub const CONTROL_PATH: &str = "/ctl/IPConn";
+
+/// Represents the type of the device.
+pub const DEVICE_TYPE: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+
+/// Represents the unique device name of the device.
+pub const DEVICE_UDN: &str = "uuid:7063-6170-3273-6f636b73";
+
+/// Represents the type of the WAN IP connection service.
+pub const SERVICE_TYPE: &str = "urn:schemas-upnp-org:service:WANIPConnection:1";
+
+/// Represents the UPnP control of the gateway, an Internet gateway device whose WAN IP connection
+/// leases port mappings to the source. The mappings are the leases of the `Redirector`, so they
+/// are released with the flows they forward to.
+#[derive(Debug)]
+pub struct Control {
+    leases: Arc<Mutex<Leases>>,
+    external_ip_addr: Ipv4Addr,
+}
+
+impl Control {
+    /// Creates a new `Control` of the leases, which reports the given external IP address.
+    pub fn new(leases: Arc<Mutex<Leases>>, external_ip_addr: Ipv4Addr) -> Control {
+        Control {
+            leases,
+            external_ip_addr,
+        }
+    }
+
+    /// Runs the SOAP action with the arguments of the body, returns the arguments of the response
+    /// or the UPnP error.
+    fn run(&self, action: &str, body: &str) -> Result<String, (u16, &'static str)> {
+        match action {
+            "GetExternalIPAddress" => Ok(format!(
+                "<NewExternalIPAddress>{}</NewExternalIPAddress>",
+                self.external_ip_addr
+            )),
+            "AddPortMapping" => {
+                let (protocol, external_port) = get_mapping(body)?;
+                let internal_port = get_arg(body, "NewInternalPort")
+                    .and_then(|port| port.parse().ok())
+                    .ok_or((402, "Invalid Args"))?;
+                let duration: u64 = get_arg(body, "NewLeaseDuration")
+                    .and_then(|duration| duration.parse().ok())
+                    .unwrap_or(0);
+                let expires = match duration {
+                    0 => None,
+                    duration => Some(Instant::now() + Duration::from_secs(duration)),
+                };
+                let description = get_arg(body, "NewPortMappingDescription").unwrap_or("");
+                let lease =
+                    Lease::new(protocol, external_port, internal_port, description, expires);
+                info!("Lease UPnP {}", lease);
+                self.leases.lock().unwrap().add(lease);
+
+                Ok(String::new())
+            }
+            "DeletePortMapping" => {
+                let (protocol, external_port) = get_mapping(body)?;
+                match self.leases.lock().unwrap().remove(protocol, external_port) {
+                    Some(lease) => {
+                        info!("Delete UPnP lease {}", lease);
+                        Ok(String::new())
+                    }
+                    None => Err((714, "NoSuchEntryInArray")),
+                }
+            }
+            "GetSpecificPortMappingEntry" => {
+                let (protocol, external_port) = get_mapping(body)?;
+                let leases = self.leases.lock().unwrap();
+                let lease = leases
+                    .get(protocol, external_port)
+                    .ok_or((714, "NoSuchEntryInArray"))?;
+                let duration = lease.expires.map_or(0, |expires| {
+                    expires.saturating_duration_since(Instant::now()).as_secs()
+                });
+                Ok(format!(
+                    "<NewInternalPort>{}</NewInternalPort><NewEnabled>1</NewEnabled><NewPortMappingDescription>{}</NewPortMappingDescription><NewLeaseDuration>{}</NewLeaseDuration>",
+                    lease.internal_port, lease.description, duration
+                ))
+            }
+            _ => Err((401, "Invalid Action")),
+        }
+    }
+}
+
+impl Service for Control {
+    fn get_name(&self) -> &str {
+        "upnp"
+    }
+
+    fn handle(&self, request: &Request) -> Response {
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", DESCRIPTION_PATH) | ("HEAD", DESCRIPTION_PATH) => {
+                Response::xml(200, get_description())
+            }
+            ("POST", CONTROL_PATH) => {
+                let body = String::from_utf8_lossy(&request.body);
+                // The action is named by the header, as in `"<SERVICE_TYPE>#<ACTION>"`
+                let action = request
+                    .get_header("SOAPAction")
+                    .and_then(|value| value.trim_matches('"').rsplit('#').next())
+                    .unwrap_or("");
+                match self.run(action, &body) {
+                    Ok(args) => Response::xml(
+                        200,
+                        get_envelope(&format!(
+                            "<u:{}Response xmlns:u=\"{}\">{}</u:{}Response>",
+                            action, SERVICE_TYPE, args, action
+                        )),
+                    ),
+                    Err((code, description)) => Response::xml(
+                        500,
+                        get_envelope(&format!(
+                            "<s:Fault><faultcode>s:Client</faultcode><faultstring>UPnPError</faultstring><detail><UPnPError xmlns=\"urn:schemas-upnp-org:control-1-0\"><errorCode>{}</errorCode><errorDescription>{}</errorDescription></UPnPError></detail></s:Fault>",
+                            code, description
+                        )),
+                    ),
+                }
+            }
+            (_, DESCRIPTION_PATH) | (_, CONTROL_PATH) => Response::method_not_allowed(),
+            _ => Response::not_found(),
+        }
+    }
+}
+
+/// Get the protocol and the external port of the mapping the arguments name.
+fn get_mapping(body: &str) -> Result<(Protocol, u16), (u16, &'static str)> {
+    let protocol = match get_arg(body, "NewProtocol") {
+        Some(protocol) if protocol.eq_ignore_ascii_case("TCP") => Protocol::Tcp,
+        Some(protocol) if protocol.eq_ignore_ascii_case("UDP") => Protocol::Udp,
+        _ => return Err((402, "Invalid Args")),
+    };
+    let external_port = get_arg(body, "NewExternalPort")
+        .and_then(|port| port.parse().ok())
+        .ok_or((402, "Invalid Args"))?;
+
+    Ok((protocol, external_port))
+}
+
+/// Get the argument of the given name in the body of a SOAP action.
+fn get_arg<'a>(body: &'a str, name: &str) -> Option<&'a str> {
+    let start = body.find(&format!("<{}>", name))? + name.len() + 2;
+    let end = start + body[start..].find(&format!("</{}>", name))?;
+
+    Some(body[start..end].trim())
+}
+
+fn get_envelope(content: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\"?><s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body>{}</s:Body></s:Envelope>",
+        content
+    )
+}
+
+fn get_description() -> String {
+    format!(
+        "<?xml version=\"1.0\"?><root xmlns=\"urn:schemas-upnp-org:device-1-0\"><specVersion><major>1</major><minor>0</minor></specVersion><device><deviceType>{}</deviceType><friendlyName>pcap2socks</friendlyName><manufacturer>pcap2socks</manufacturer><modelName>pcap2socks</modelName><UDN>{}</UDN><deviceList><device><deviceType>urn:schemas-upnp-org:device:WANDevice:1</deviceType><friendlyName>WAN Device</friendlyName><UDN>uuid:7063-6170-3273-6f636b74</UDN><deviceList><device><deviceType>urn:schemas-upnp-org:device:WANConnectionDevice:1</deviceType><friendlyName>WAN Connection Device</friendlyName><UDN>uuid:7063-6170-3273-6f636b75</UDN><serviceList><service><serviceType>{}</serviceType><serviceId>urn:upnp-org:serviceId:WANIPConn1</serviceId><controlURL>{}</controlURL><eventSubURL>{}</eventSubURL><SCPDURL>{}</SCPDURL></service></serviceList></device></deviceList></device></deviceList></device></root>",
+        DEVICE_TYPE, DEVICE_UDN, SERVICE_TYPE, CONTROL_PATH, CONTROL_PATH, DESCRIPTION_PATH
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post(action: &str, args: &str) -> Request {
+        let body = get_envelope(&format!(
+            "<u:{} xmlns:u=\"{}\">{}</u:{}>",
+            action, SERVICE_TYPE, args, action
+        ));
+        Request {
+            method: String::from("POST"),
+            path: CONTROL_PATH.to_string(),
+            headers: vec![(
+                String::from("SOAPAction"),
+                format!("\"{}#{}\"", SERVICE_TYPE, action),
+            )],
+            body: body.into_bytes(),
+        }
+    }
+
+    const MAPPING: &str = "<NewRemoteHost></NewRemoteHost><NewExternalPort>3074</NewExternalPort><NewProtocol>UDP</NewProtocol><NewInternalPort>3074</NewInternalPort><NewInternalClient>10.6.0.2</NewInternalClient><NewEnabled>1</NewEnabled><NewPortMappingDescription>game</NewPortMappingDescription><NewLeaseDuration>0</NewLeaseDuration>";
+
+    #[test]
+    fn test_add_delete() {
+        let leases = Arc::new(Mutex::new(Leases::new()));
+        let control = Control::new(Arc::clone(&leases), Ipv4Addr::new(10, 6, 0, 1));
+
+        let response = control.handle(&post("AddPortMapping", MAPPING));
+        assert_eq!(response.status, 200);
+        assert!(String::from_utf8(response.body)
+            .unwrap()
+            .contains("<u:AddPortMappingResponse"));
+        {
+            let leases_locked = leases.lock().unwrap();
+            let lease = leases_locked.get(Protocol::Udp, 3074).unwrap();
+            assert_eq!(lease.internal_port, 3074);
+            assert_eq!(lease.description, "game");
+            assert_eq!(lease.expires, None);
+        }
+
+        let entry = control.handle(&post(
+            "GetSpecificPortMappingEntry",
+            "<NewExternalPort>3074</NewExternalPort><NewProtocol>UDP</NewProtocol>",
+        ));
+        assert!(String::from_utf8(entry.body)
+            .unwrap()
+            .contains("<NewInternalPort>3074</NewInternalPort>"));
+
+        let args = "<NewRemoteHost></NewRemoteHost><NewExternalPort>3074</NewExternalPort><NewProtocol>UDP</NewProtocol>";
+        assert_eq!(control.handle(&post("DeletePortMapping", args)).status, 200);
+        assert!(leases.lock().unwrap().is_empty());
+        // A mapping deleted again does not exist
+        let response = control.handle(&post("DeletePortMapping", args));
+        assert_eq!(response.status, 500);
+        assert!(String::from_utf8(response.body)
+            .unwrap()
+            .contains("<errorCode>714</errorCode>"));
+    }
+
+    #[test]
+    fn test_external_ip_addr() {
+        let control = Control::new(
+            Arc::new(Mutex::new(Leases::new())),
+            Ipv4Addr::new(10, 6, 0, 1),
+        );
+        let response = control.handle(&post("GetExternalIPAddress", ""));
+        assert_eq!(response.status, 200);
+        assert!(String::from_utf8(response.body)
+            .unwrap()
+            .contains("<NewExternalIPAddress>10.6.0.1</NewExternalIPAddress>"));
+    }
+
+    #[test]
+    fn test_invalid() {
+        let leases = Arc::new(Mutex::new(Leases::new()));
+        let control = Control::new(Arc::clone(&leases), Ipv4Addr::new(10, 6, 0, 1));
+
+        let response = control.handle(&post("AddPortMapping", "<NewProtocol>ICMP</NewProtocol>"));
+        assert!(String::from_utf8(response.body)
+            .unwrap()
+            .contains("<errorCode>402</errorCode>"));
+        let response = control.handle(&post("ForceTermination", ""));
+        assert!(String::from_utf8(response.body)
+            .unwrap()
+            .contains("<errorCode>401</errorCode>"));
+        assert!(leases.lock().unwrap().is_empty());
+
+        let mut request = post("GetExternalIPAddress", "");
+        request.method = String::from("GET");
+        assert_eq!(control.handle(&request).status, 405);
+        request.path = String::from(DESCRIPTION_PATH);
+        assert_eq!(control.handle(&request).status, 200);
+    }
+}
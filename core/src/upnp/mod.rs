@@ -0,0 +1,197 @@
+//! Port mappings leased to the source through UPnP, which are released once the flow of the
+//! internal port is killed, so the mapping never outlives the association it forwards to.
+
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::time::Instant;
+
+use crate::hooks::Protocol;
+
+pub mod control;
+pub mod ssdp;
+
+pub use control::Control;
+pub use ssdp::Ssdp;
+
+/// Represents a port mapping leased to the source.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Lease {
+    pub protocol: Protocol,
+    pub external_port: u16,
+    pub internal_port: u16,
+    pub description: String,
+    /// Represents the instant the lease expires, or `None` if it is permanent.
+    pub expires: Option<Instant>,
+}
+
+impl Lease {
+    /// Creates a new `Lease`.
+    pub fn new(
+        protocol: Protocol,
+        external_port: u16,
+        internal_port: u16,
+        description: &str,
+        expires: Option<Instant>,
+    ) -> Lease {
+        Lease {
+            protocol,
+            external_port,
+            internal_port,
+            description: description.to_string(),
+            expires,
+        }
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        match self.expires {
+            Some(expires) => now >= expires,
+            None => false,
+        }
+    }
+}
+
+impl Display for Lease {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} -> {}",
+            self.protocol, self.external_port, self.internal_port
+        )?;
+        if !self.description.is_empty() {
+            write!(f, " ({})", self.description)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Represents the port mappings leased to the source, keyed by the protocol and the external
+/// port.
+#[derive(Debug, Default)]
+pub struct Leases {
+    leases: HashMap<(Protocol, u16), Lease>,
+}
+
+impl Leases {
+    /// Creates a new `Leases`.
+    pub fn new() -> Leases {
+        Leases {
+            leases: HashMap::new(),
+        }
+    }
+
+    /// Adds the lease, and returns the one of the same external port it replaces, if any.
+    pub fn add(&mut self, lease: Lease) -> Option<Lease> {
+        self.leases
+            .insert((lease.protocol, lease.external_port), lease)
+    }
+
+    /// Removes the lease of the external port.
+    pub fn remove(&mut self, protocol: Protocol, external_port: u16) -> Option<Lease> {
+        self.leases.remove(&(protocol, external_port))
+    }
+
+    /// Releases the leases forwarding to the internal port, and returns them.
+    pub fn release(&mut self, protocol: Protocol, internal_port: u16) -> Vec<Lease> {
+        let keys: Vec<_> = self
+            .leases
+            .iter()
+            .filter(|(_, lease)| lease.protocol == protocol && lease.internal_port == internal_port)
+            .map(|(key, _)| *key)
+            .collect();
+
+        keys.iter()
+            .filter_map(|key| self.leases.remove(key))
+            .collect()
+    }
+
+    /// Removes the expired leases, and returns them.
+    pub fn scavenge(&mut self, now: Instant) -> Vec<Lease> {
+        let keys: Vec<_> = self
+            .leases
+            .iter()
+            .filter(|(_, lease)| lease.is_expired(now))
+            .map(|(key, _)| *key)
+            .collect();
+
+        keys.iter()
+            .filter_map(|key| self.leases.remove(key))
+            .collect()
+    }
+
+    /// Get the lease of the external port.
+    pub fn get(&self, protocol: Protocol, external_port: u16) -> Option<&Lease> {
+        self.leases.get(&(protocol, external_port))
+    }
+
+    /// Get the number of leases.
+    pub fn len(&self) -> usize {
+        self.leases.len()
+    }
+
+    /// Returns if there is no lease.
+    pub fn is_empty(&self) -> bool {
+        self.leases.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_release() {
+        let mut leases = Leases::new();
+        leases.add(Lease::new(Protocol::Udp, 3074, 3074, "game", None));
+        leases.add(Lease::new(Protocol::Udp, 3075, 3074, "game", None));
+        leases.add(Lease::new(Protocol::Tcp, 3074, 3074, "game", None));
+        leases.add(Lease::new(Protocol::Udp, 9000, 9000, "", None));
+
+        // Only the leases of the protocol forwarding to the internal port are released
+        let mut released = leases.release(Protocol::Udp, 3074);
+        released.sort_by_key(|lease| lease.external_port);
+        let ports: Vec<_> = released.iter().map(|lease| lease.external_port).collect();
+        assert_eq!(ports, vec![3074, 3075]);
+        assert_eq!(leases.len(), 2);
+        assert!(leases.get(Protocol::Tcp, 3074).is_some());
+        assert!(leases.get(Protocol::Udp, 9000).is_some());
+        assert!(leases.release(Protocol::Udp, 3074).is_empty());
+    }
+
+    #[test]
+    fn test_add_replace() {
+        let mut leases = Leases::new();
+        assert!(leases
+            .add(Lease::new(Protocol::Udp, 3074, 3074, "old", None))
+            .is_none());
+        let replaced = leases
+            .add(Lease::new(Protocol::Udp, 3074, 3075, "new", None))
+            .unwrap();
+        assert_eq!(replaced.description, "old");
+        assert_eq!(leases.get(Protocol::Udp, 3074).unwrap().internal_port, 3075);
+        assert!(leases.remove(Protocol::Udp, 3074).is_some());
+        assert!(leases.is_empty());
+    }
+
+    #[test]
+    fn test_scavenge() {
+        let now = Instant::now();
+        let mut leases = Leases::new();
+        leases.add(Lease::new(
+            Protocol::Udp,
+            3074,
+            3074,
+            "",
+            Some(now + Duration::from_secs(60)),
+        ));
+        leases.add(Lease::new(Protocol::Udp, 9000, 9000, "", None));
+
+        assert!(leases.scavenge(now).is_empty());
+        let expired = leases.scavenge(now + Duration::from_secs(60));
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].external_port, 3074);
+        // Permanent leases never expire
+        assert!(leases.get(Protocol::Udp, 9000).is_some());
+    }
+}
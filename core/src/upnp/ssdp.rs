@@ -0,0 +1,110 @@
+use std::net::Ipv4Addr;
+
+use super::control::{DESCRIPTION_PATH, DEVICE_TYPE, DEVICE_UDN, SERVICE_TYPE};
+use crate::gateway::Request;
+
+/// Represents the port of SSDP.
+pub const SSDP_PORT: u16 = 1900;
+
+/// Represents the time the response is valid for in seconds.
+const MAX_AGE: u64 = 1800;
+
+/// Represents the SSDP responder of the gateway, which answers searches of the source for an
+/// Internet gateway device with the description of the UPnP control, so the source finds the
+/// gateway without its searches reaching the proxy.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Ssdp {
+    location: String,
+}
+
+impl Ssdp {
+    /// Creates a new `Ssdp` of the UPnP control listening on the port of the gateway.
+    pub fn new(gateway: Ipv4Addr, port: u16) -> Ssdp {
+        Ssdp {
+            location: format!("http://{}:{}{}", gateway, port, DESCRIPTION_PATH),
+        }
+    }
+
+    /// Get the response to the payload, or `None` if it is not a search of the gateway.
+    pub fn answer(&self, payload: &[u8]) -> Option<Vec<u8>> {
+        let request = Request::parse_head(&String::from_utf8_lossy(payload))?;
+        if request.method != "M-SEARCH" || request.path != "*" {
+            return None;
+        }
+        if request.get_header("MAN")?.trim_matches('"') != "ssdp:discover" {
+            return None;
+        }
+
+        let st = request.get_header("ST")?;
+        let usn = match st {
+            // A search of all is answered as the device
+            "ssdp:all" => return self.answer_target(DEVICE_TYPE, &get_usn(DEVICE_TYPE)),
+            "upnp:rootdevice" | DEVICE_TYPE | SERVICE_TYPE => get_usn(st),
+            DEVICE_UDN => String::from(DEVICE_UDN),
+            _ => return None,
+        };
+
+        self.answer_target(st, &usn)
+    }
+
+    fn answer_target(&self, st: &str, usn: &str) -> Option<Vec<u8>> {
+        Some(
+            format!(
+                "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age={}\r\nEXT:\r\nLOCATION: {}\r\nSERVER: pcap2socks/{} UPnP/1.0\r\nST: {}\r\nUSN: {}\r\n\r\n",
+                MAX_AGE,
+                self.location,
+                env!("CARGO_PKG_VERSION"),
+                st,
+                usn
+            )
+            .into_bytes(),
+        )
+    }
+}
+
+fn get_usn(st: &str) -> String {
+    format!("{}::{}", DEVICE_UDN, st)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn search(st: &str) -> Vec<u8> {
+        format!(
+            "M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: {}\r\n\r\n",
+            st
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn test_answer() {
+        let ssdp = Ssdp::new(Ipv4Addr::new(10, 6, 0, 1), 5000);
+
+        let response = String::from_utf8(ssdp.answer(&search(DEVICE_TYPE)).unwrap()).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\r\nLOCATION: http://10.6.0.1:5000/rootDesc.xml\r\n"));
+        assert!(response.contains(&format!("\r\nST: {}\r\n", DEVICE_TYPE)));
+        assert!(response.contains(&format!("\r\nUSN: {}::{}\r\n", DEVICE_UDN, DEVICE_TYPE)));
+
+        let response = String::from_utf8(ssdp.answer(&search("ssdp:all")).unwrap()).unwrap();
+        assert!(response.contains(&format!("\r\nST: {}\r\n", DEVICE_TYPE)));
+        let response = String::from_utf8(ssdp.answer(&search(DEVICE_UDN)).unwrap()).unwrap();
+        assert!(response.contains(&format!("\r\nUSN: {}\r\n", DEVICE_UDN)));
+    }
+
+    #[test]
+    fn test_answer_other() {
+        let ssdp = Ssdp::new(Ipv4Addr::new(10, 6, 0, 1), 5000);
+
+        // Other devices, notifications and other payloads
+        assert_eq!(
+            ssdp.answer(&search("urn:dial-multiscreen-org:service:dial:1")),
+            None
+        );
+        let notify = b"NOTIFY * HTTP/1.1\r\nNT: upnp:rootdevice\r\nNTS: ssdp:alive\r\n\r\n";
+        assert_eq!(ssdp.answer(notify), None);
+        assert_eq!(ssdp.answer(b"\x00\x01query"), None);
+    }
+}
@@ -0,0 +1,1095 @@
+use log::{debug, info, trace, warn};
+use std::cmp::{Ordering, Reverse};
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::backoff::{self, Site};
+use crate::random::{generate_seed, Random};
+use crate::rule::Rules;
+use crate::schedule::Schedule;
+use crate::threads::{Purpose, Threads};
+
+/// Represents the smoothing factor of the EWMA of latencies.
+const EWMA_ALPHA: f64 = 0.2;
+
+/// Represents the probability of exploring a random upstream in the latency-aware policy.
+const EXPLORATION_RATE: f64 = 0.1;
+
+/// Represents the interval between 2 saves of the latency map.
+const SAVE_INTERVAL: u64 = 60;
+
+/// Represents the half-life of the samples count of a latency in the saved map.
+const DECAY_HALF_LIFE: u64 = 6 * 60 * 60;
+
+/// Represents the age after which a latency in the saved map is discarded.
+const DECAY_EXPIRE: u64 = 24 * 60 * 60;
+
+/// Represents the consecutive connect failures before an upstream is considered down.
+const FAILURES_BEFORE_DOWN: usize = 3;
+
+/// Represents the base of the interval between 2 probes of a down upstream.
+const PROBE_INTERVAL: u64 = 10;
+
+/// Represents the min time the fallback mode lasts before new flows return to upstreams.
+const FALLBACK_HOLD: u64 = 30;
+
+/// Represents the policy selecting an upstream proxy for a new flow.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Policy {
+    /// Always uses the first upstream.
+    First,
+    /// Uses the upstream with the lowest latency to the destination prefix.
+    LatencyAware,
+}
+
+impl Display for Policy {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Policy::First => write!(f, "first"),
+            Policy::LatencyAware => write!(f, "latency-aware"),
+        }
+    }
+}
+
+/// Represents the policy for new flows when all the upstreams are down.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Fallback {
+    /// Connects to destinations directly.
+    Direct,
+    /// Rejects new flows.
+    Fail,
+    /// Bridges new flows to the real gateway of the network.
+    Gateway,
+}
+
+impl Fallback {
+    /// Get the `Fallback` of the given name, `direct`, `fail` or `gateway`.
+    pub fn from_name(name: &str) -> Option<Fallback> {
+        match name {
+            "direct" => Some(Fallback::Direct),
+            "fail" => Some(Fallback::Fail),
+            "gateway" => Some(Fallback::Gateway),
+            _ => None,
+        }
+    }
+
+    fn get_route(&self) -> Route {
+        match self {
+            Fallback::Direct => Route::Direct,
+            Fallback::Fail => Route::Reject,
+            Fallback::Gateway => Route::Bridge,
+        }
+    }
+}
+
+impl Display for Fallback {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Fallback::Direct => write!(f, "direct"),
+            Fallback::Fail => write!(f, "fail"),
+            Fallback::Gateway => write!(f, "gateway"),
+        }
+    }
+}
+
+/// Represents the route of a new flow.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Route {
+    /// Through the upstream.
+    Proxy(SocketAddr),
+    /// To the destination directly.
+    Direct,
+    /// Rejected.
+    Reject,
+    /// Bridged to the real gateway.
+    Bridge,
+    /// To a service of the gateway on the local address.
+    Local(SocketAddr),
+}
+
+impl Display for Route {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Route::Proxy(remote) => write!(f, "{}", remote),
+            Route::Direct => write!(f, "direct"),
+            Route::Reject => write!(f, "reject"),
+            Route::Bridge => write!(f, "gateway"),
+            Route::Local(local) => write!(f, "local {}", local),
+        }
+    }
+}
+
+/// Represents the fallback mode of new flows of a rule, or of the flows admitted by no rule. The
+/// mode of each follows the health of the upstreams when one of its flows is routed, so a rule
+/// without new flows never announces a change.
+#[derive(Clone, Debug)]
+struct Mode {
+    rule: Option<String>,
+    fallback: Fallback,
+    is_fallback: bool,
+    changed: Instant,
+    changes: usize,
+    /// Represents the number of new flows routed by the fallback policy.
+    fallbacks: usize,
+}
+
+impl Mode {
+    fn new(rule: Option<String>, fallback: Fallback) -> Mode {
+        Mode {
+            rule,
+            fallback,
+            is_fallback: false,
+            changed: Instant::now(),
+            changes: 0,
+            fallbacks: 0,
+        }
+    }
+
+    /// Follows if all the upstreams are down. The fallback mode lasts for at least the hold once
+    /// entered, so a flapping upstream does not move new flows back and forth.
+    fn update(&mut self, is_down: bool, hold: Duration) {
+        let of_rule = match self.rule {
+            Some(ref rule) => format!(" of rule {}", rule),
+            None => String::new(),
+        };
+        if !self.is_fallback && is_down {
+            self.is_fallback = true;
+            self.changed = Instant::now();
+            self.changes += 1;
+            warn!(
+                "All destinations are down, new connections{} will {}",
+                of_rule,
+                match self.fallback {
+                    Fallback::Direct => "go direct",
+                    Fallback::Fail => "fail",
+                    Fallback::Gateway => "be bridged to the gateway",
+                }
+            );
+        } else if self.is_fallback && !is_down && self.changed.elapsed() >= hold {
+            self.is_fallback = false;
+            self.changed = Instant::now();
+            self.changes += 1;
+            info!(
+                "Destinations are up, new connections{} will be proxied again",
+                of_rule
+            );
+        }
+    }
+}
+
+/// Represents the health of an upstream learned from connects.
+#[derive(Clone, Copy, Debug)]
+struct Health {
+    failures: usize,
+    last_try: Option<Instant>,
+    /// Represents the time after the last try before the next probe of a down upstream.
+    probe_delay: Duration,
+}
+
+impl Health {
+    fn is_down(&self) -> bool {
+        self.failures >= FAILURES_BEFORE_DOWN
+    }
+
+    fn is_probe_due(&self) -> bool {
+        match self.last_try {
+            Some(instant) => instant.elapsed() >= self.probe_delay,
+            None => true,
+        }
+    }
+
+    fn is_available(&self) -> bool {
+        !self.is_down() || self.is_probe_due()
+    }
+}
+
+/// Returns if a connect error is caused by the upstream rather than the destination.
+fn is_upstream_error(e: &io::Error) -> bool {
+    // Errors replied by the SOCKS server are `Other`, and the exhaustion of local ports or a local
+    // port still in use is not a fault of the upstream
+    e.kind() != io::ErrorKind::Other
+        && e.kind() != io::ErrorKind::AddrNotAvailable
+        && e.kind() != io::ErrorKind::AddrInUse
+}
+
+/// Represents the prefix of a destination in /16.
+type Prefix = [u8; 2];
+
+fn prefix_of(ip_addr: Ipv4Addr) -> Prefix {
+    let octets = ip_addr.octets();
+
+    [octets[0], octets[1]]
+}
+
+/// Returns if a latency updated at the given time is kept within the retention.
+fn is_retained(retention: Option<Duration>, updated: u64, now: u64) -> bool {
+    match retention {
+        Some(retention) => now.saturating_sub(updated) <= retention.as_secs(),
+        None => true,
+    }
+}
+
+fn now_secs() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs(),
+        Err(_) => 0,
+    }
+}
+
+/// Represents the learned latency of an upstream to a destination prefix.
+#[derive(Clone, Copy, Debug)]
+struct Latency {
+    /// Represents the EWMA of the latency in milliseconds.
+    ewma: f64,
+    samples: usize,
+    updated: u64,
+}
+
+impl Latency {
+    fn update(&mut self, latency: f64) {
+        self.samples = self.samples.saturating_add(1);
+        // Use the plain average for the first samples
+        let alpha = f64::max(1.0 / self.samples as f64, EWMA_ALPHA);
+        self.ewma = self.ewma * (1.0 - alpha) + latency * alpha;
+        self.updated = now_secs();
+    }
+}
+
+/// Represents an entry of the latency map dump.
+#[derive(Clone, Debug)]
+pub struct LatencyEntry {
+    pub prefix: Ipv4Addr,
+    pub remote: SocketAddr,
+    pub latency: f64,
+    pub samples: usize,
+}
+
+impl Display for LatencyEntry {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}/16 -> {}: {:.1} ms ({} samples)",
+            self.prefix, self.remote, self.latency, self.samples
+        )
+    }
+}
+
+/// Represents the channel of saves to the thread writing the latency map, and the thread.
+type Saver = (Sender<(PathBuf, String)>, JoinHandle<()>);
+
+/// Represents the upstream proxies and the policy selecting them.
+#[derive(Debug)]
+pub struct Upstreams {
+    remotes: Vec<SocketAddr>,
+    policy: Policy,
+    latencies: HashMap<(SocketAddr, Prefix), Latency>,
+    random: Random,
+    path: Option<PathBuf>,
+    last_save: Instant,
+    /// Represents the thread writing the latency map, or `None` if the map is written in place.
+    saver: Option<Saver>,
+    health: Vec<Health>,
+    /// Represents the fallback modes of the flows admitted by no rule, and of each rule after it.
+    modes: Vec<Mode>,
+    fallback_hold: Duration,
+    schedule: Option<Schedule>,
+    unscheduled: usize,
+    downs: Vec<SocketAddr>,
+    ups: Vec<SocketAddr>,
+    retention: Option<Duration>,
+}
+
+impl Upstreams {
+    /// Creates a new `Upstreams`, which needs at least an upstream.
+    pub fn new(
+        remotes: Vec<SocketAddr>,
+        policy: Policy,
+        fallback: Fallback,
+    ) -> io::Result<Upstreams> {
+        if remotes.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "no upstream"));
+        }
+        let health = remotes
+            .iter()
+            .map(|_| Health {
+                failures: 0,
+                last_try: None,
+                probe_delay: Duration::from_secs(PROBE_INTERVAL),
+            })
+            .collect();
+        Ok(Upstreams {
+            remotes,
+            policy,
+            latencies: HashMap::new(),
+            random: Random::derive(generate_seed(), "upstreams"),
+            path: None,
+            last_save: Instant::now(),
+            saver: None,
+            health,
+            modes: vec![Mode::new(None, fallback)],
+            fallback_hold: Duration::from_secs(FALLBACK_HOLD),
+            schedule: None,
+            unscheduled: 0,
+            downs: Vec::new(),
+            ups: Vec::new(),
+            retention: None,
+        })
+    }
+
+    /// Loads the latency map from the given path and saves to it periodically. A missing file is
+    /// not an error.
+    pub fn load(&mut self, path: PathBuf) -> io::Result<()> {
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) => {
+                self.path = Some(path);
+                if e.kind() == io::ErrorKind::NotFound {
+                    return Ok(());
+                }
+                return Err(e);
+            }
+        };
+
+        let now = now_secs();
+        for line in content.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 5 {
+                continue;
+            }
+            let remote: SocketAddr = match fields[0].parse() {
+                Ok(remote) => remote,
+                Err(_) => continue,
+            };
+            if !self.remotes.contains(&remote) {
+                continue;
+            }
+            let prefix: Ipv4Addr = match fields[1].parse() {
+                Ok(prefix) => prefix,
+                Err(_) => continue,
+            };
+            let (ewma, samples, updated) = match (
+                fields[2].parse::<f64>(),
+                fields[3].parse::<usize>(),
+                fields[4].parse::<u64>(),
+            ) {
+                (Ok(ewma), Ok(samples), Ok(updated)) => (ewma, samples, updated),
+                _ => continue,
+            };
+
+            // Decay
+            let age = now.saturating_sub(updated);
+            if age > DECAY_EXPIRE || !is_retained(self.retention, updated, now) {
+                continue;
+            }
+            let samples = samples >> (age / DECAY_HALF_LIFE);
+            if samples == 0 {
+                continue;
+            }
+
+            self.latencies.insert(
+                (remote, prefix_of(prefix)),
+                Latency {
+                    ewma,
+                    samples,
+                    updated,
+                },
+            );
+        }
+        debug!(
+            "load {} latencies from {}",
+            self.latencies.len(),
+            path.display()
+        );
+        self.path = Some(path);
+
+        Ok(())
+    }
+
+    /// Spawns the thread writing the latency map, so saves never wait for the file system. The
+    /// map is written in place until then, and once the thread is gone.
+    pub fn start(&mut self, threads: &Arc<Threads>) -> io::Result<()> {
+        if self.path.is_none() || self.saver.is_some() {
+            return Ok(());
+        }
+
+        let (tx, rx) = mpsc::channel::<(PathBuf, String)>();
+        let handle = Threads::spawn(
+            threads,
+            String::from("latency save"),
+            Purpose::Save,
+            move || {
+                for (path, content) in rx {
+                    if let Err(ref e) = fs::write(&path, content) {
+                        warn!("save latencies: {}", e);
+                    }
+                }
+            },
+        )?;
+        self.saver = Some((tx, handle));
+
+        Ok(())
+    }
+
+    /// Saves the latency map to the path it was loaded from, in the thread writing the map if it
+    /// is spawned.
+    pub fn save(&mut self) -> io::Result<()> {
+        self.last_save = Instant::now();
+        if self.path.is_none() {
+            return Ok(());
+        }
+
+        // Expired latencies
+        let now = now_secs();
+        let retention = self.retention;
+        self.latencies
+            .retain(|_, latency| is_retained(retention, latency.updated, now));
+
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        let mut content = String::new();
+        for ((remote, prefix), latency) in &self.latencies {
+            content.push_str(&format!(
+                "{} {} {} {} {}\n",
+                remote,
+                Ipv4Addr::new(prefix[0], prefix[1], 0, 0),
+                latency.ewma,
+                latency.samples,
+                latency.updated
+            ));
+        }
+        let content = match self.saver {
+            Some((ref saver, _)) => match saver.send((path.clone(), content)) {
+                Ok(_) => None,
+                Err(e) => Some((e.0).1),
+            },
+            None => Some(content),
+        };
+        if let Some(content) = content {
+            self.saver = None;
+            fs::write(path, content)?;
+        }
+        trace!(
+            "save {} latencies to {}",
+            self.latencies.len(),
+            path.display()
+        );
+
+        Ok(())
+    }
+
+    /// Sets the max age of latencies persisted, older ones are pruned on loading and saving.
+    pub fn set_retention(&mut self, retention: Option<Duration>) {
+        self.retention = retention;
+    }
+
+    /// Sets the seed of the random decisions of the `Upstreams`, which are reproducible with the
+    /// same seed.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.random = Random::derive(seed, "upstreams");
+    }
+
+    /// Get all the upstreams.
+    pub fn get_remotes(&self) -> &[SocketAddr] {
+        &self.remotes
+    }
+
+    /// Get the policy of the `Upstreams`.
+    pub fn get_policy(&self) -> Policy {
+        self.policy
+    }
+
+    /// Get the fallback policy of the `Upstreams`.
+    pub fn get_fallback(&self) -> Fallback {
+        self.modes[0].fallback
+    }
+
+    /// Sets the rules whose flows follow their own fallback policy, or the global one if they
+    /// have none.
+    pub fn set_rules(&mut self, rules: &Rules) {
+        let fallback = self.get_fallback();
+        self.modes.truncate(1);
+        for index in 0..rules.len() {
+            let rule = rules.get(index);
+            self.modes.push(Mode::new(
+                Some(String::from(rule.get_name())),
+                rule.get_fallback().unwrap_or(fallback),
+            ));
+        }
+    }
+
+    /// Returns if the flows admitted by no rule or of any rule may be bridged to the gateway.
+    pub fn is_bridge_used(&self) -> bool {
+        self.modes
+            .iter()
+            .any(|mode| mode.fallback == Fallback::Gateway)
+    }
+
+    /// Returns if all the upstreams are down and new flows follow the fallback policy.
+    pub fn is_fallback(&self) -> bool {
+        self.modes[0].is_fallback
+    }
+
+    /// Get the number of times the `Upstreams` entered or left the fallback mode.
+    pub fn get_mode_changes(&self) -> usize {
+        self.modes[0].changes
+    }
+
+    /// Get the number of new flows of the rule of the index, or admitted by no rule, routed by the
+    /// fallback policy.
+    pub fn get_fallbacks(&self, rule: Option<usize>) -> usize {
+        self.modes[self.get_mode_index(rule)].fallbacks
+    }
+
+    fn get_mode_index(&self, rule: Option<usize>) -> usize {
+        match rule {
+            Some(rule) if rule + 1 < self.modes.len() => rule + 1,
+            _ => 0,
+        }
+    }
+
+    /// Sets the schedule of the `Upstreams`. New flows outside the time windows of the schedule
+    /// connect to destinations directly, flows already open are not affected.
+    pub fn set_schedule(&mut self, schedule: Schedule) {
+        self.schedule = Some(schedule);
+    }
+
+    /// Get the schedule of the `Upstreams`.
+    pub fn get_schedule(&self) -> Option<&Schedule> {
+        self.schedule.as_ref()
+    }
+
+    /// Get the number of new flows connected directly for being outside the schedule.
+    pub fn get_unscheduled(&self) -> usize {
+        self.unscheduled
+    }
+
+    /// Selects a route for a new flow to the given destination.
+    pub fn select(&mut self, dst: Ipv4Addr) -> Route {
+        if !self.is_scheduled(dst) {
+            return Route::Direct;
+        }
+
+        self.select_upstream(dst, None)
+    }
+
+    /// Returns if a new flow admitted by no rule is inside the schedule, and counts the ones
+    /// outside which connect to destinations directly.
+    fn is_scheduled(&mut self, dst: Ipv4Addr) -> bool {
+        if let Some(ref schedule) = self.schedule {
+            match schedule.find() {
+                Some(window) => trace!("admit {} in window {}", dst, window),
+                None => {
+                    self.unscheduled = self.unscheduled.saturating_add(1);
+                    trace!("connect to {} directly outside the schedule", dst);
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+
+    /// Returns if a new flow of the rule of the index, or admitted by no rule, is bridged to the
+    /// gateway by the fallback policy, before anything else of the flow is handled. A flow which
+    /// probes a down upstream is never bridged, nor a flow admitted by no rule outside the
+    /// schedule.
+    pub fn select_bridge(&mut self, rule: Option<usize>) -> bool {
+        let index = self.get_mode_index(rule);
+        if index == 0 {
+            if let Some(ref schedule) = self.schedule {
+                if schedule.find().is_none() {
+                    return false;
+                }
+            }
+        }
+        self.update_mode(index);
+        let mode = &mut self.modes[index];
+        if !mode.is_fallback
+            || mode.fallback != Fallback::Gateway
+            || self.health.iter().any(|health| health.is_probe_due())
+        {
+            return false;
+        }
+        mode.fallbacks = mode.fallbacks.saturating_add(1);
+
+        true
+    }
+
+    /// Selects a route through the upstreams for a new flow of the rule of the index, or admitted
+    /// by no rule, to the given destination, regardless of the schedule.
+    pub fn select_upstream(&mut self, dst: Ipv4Addr, rule: Option<usize>) -> Route {
+        let mode = self.get_mode_index(rule);
+        self.update_mode(mode);
+
+        if self.modes[mode].is_fallback {
+            // Probe a down upstream occasionally
+            if let Some(index) = self.health.iter().position(|health| health.is_probe_due()) {
+                self.health[index].last_try = Some(Instant::now());
+                trace!("probe upstream {} for {}", self.remotes[index], dst);
+                return Route::Proxy(self.remotes[index]);
+            }
+
+            self.modes[mode].fallbacks = self.modes[mode].fallbacks.saturating_add(1);
+            return self.modes[mode].fallback.get_route();
+        }
+
+        let candidates: Vec<usize> = (0..self.remotes.len())
+            .filter(|index| self.health[*index].is_available())
+            .collect();
+        let index = match candidates.len() {
+            // Cannot happen since the fallback mode is not entered, use the first one anyway
+            0 => 0,
+            1 => candidates[0],
+            _ => self.select_from(&candidates, dst),
+        };
+        self.health[index].last_try = Some(Instant::now());
+
+        Route::Proxy(self.remotes[index])
+    }
+
+    fn select_from(&mut self, candidates: &[usize], dst: Ipv4Addr) -> usize {
+        match self.policy {
+            Policy::First => candidates[0],
+            Policy::LatencyAware => {
+                // Explore
+                if self.random.next_f64() < EXPLORATION_RATE {
+                    let index = candidates[self.random.next_below(candidates.len())];
+                    trace!("explore upstream {} for {}", self.remotes[index], dst);
+                    return index;
+                }
+
+                let prefix = prefix_of(dst);
+                // Measure upstreams which have never been used for the prefix first
+                if let Some(index) = candidates.iter().find(|index| {
+                    !self
+                        .latencies
+                        .contains_key(&(self.remotes[**index], prefix))
+                }) {
+                    return *index;
+                }
+
+                candidates
+                    .iter()
+                    .filter_map(|index| {
+                        self.latencies
+                            .get(&(self.remotes[*index], prefix))
+                            .map(|latency| (*index, latency.ewma))
+                    })
+                    .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal))
+                    .map(|(index, _)| index)
+                    .unwrap_or(candidates[0])
+            }
+        }
+    }
+
+    /// Updates the global fallback mode, and the one of the given index.
+    fn update_mode(&mut self, index: usize) {
+        let is_down = self.health.iter().all(|health| health.is_down());
+        let hold = self.fallback_hold;
+        self.modes[0].update(is_down, hold);
+        if index != 0 {
+            self.modes[index].update(is_down, hold);
+        }
+    }
+
+    /// Sets the min time the fallback mode lasts before new flows return to upstreams.
+    pub fn set_fallback_hold(&mut self, hold: Duration) {
+        self.fallback_hold = hold;
+    }
+
+    /// Reports a failed connect through an upstream.
+    pub fn report_failure(&mut self, remote: SocketAddr, e: &io::Error) {
+        if !is_upstream_error(e) {
+            return;
+        }
+        if let Some(index) = self.remotes.iter().position(|r| *r == remote) {
+            let health = &mut self.health[index];
+            let is_down = health.is_down();
+            health.failures = health.failures.saturating_add(1);
+            health.last_try = Some(Instant::now());
+            if health.is_down() {
+                health.probe_delay = backoff::get_delay(
+                    Site::Probe,
+                    Duration::from_secs(PROBE_INTERVAL),
+                    health.failures - FAILURES_BEFORE_DOWN,
+                );
+            }
+            if !is_down && health.is_down() {
+                warn!("destination {} is down: {}", remote, e);
+                self.downs.push(remote);
+            }
+        }
+        self.update_mode(0);
+    }
+
+    /// Takes an upstream down as if its host left the network, so its flows fail over and it is
+    /// probed again like one whose connects failed. This is for testing only.
+    pub fn flap(&mut self, remote: SocketAddr) {
+        if let Some(index) = self.remotes.iter().position(|r| *r == remote) {
+            let health = &mut self.health[index];
+            if health.is_down() {
+                return;
+            }
+            health.failures = FAILURES_BEFORE_DOWN;
+            health.last_try = Some(Instant::now());
+            health.probe_delay =
+                backoff::get_delay(Site::Probe, Duration::from_secs(PROBE_INTERVAL), 0);
+            warn!("destination {} is down: flapped", remote);
+            self.downs.push(remote);
+        }
+        self.update_mode(0);
+    }
+
+    /// Takes the upstreams which are down since the last call.
+    pub fn take_downs(&mut self) -> Vec<SocketAddr> {
+        std::mem::take(&mut self.downs)
+    }
+
+    /// Takes the upstreams which are up again since the last call.
+    pub fn take_ups(&mut self) -> Vec<SocketAddr> {
+        std::mem::take(&mut self.ups)
+    }
+
+    fn get_best(&self, prefix: Prefix) -> Option<SocketAddr> {
+        self.remotes
+            .iter()
+            .filter_map(|remote| {
+                self.latencies
+                    .get(&(*remote, prefix))
+                    .map(|latency| (*remote, latency.ewma))
+            })
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal))
+            .map(|(remote, _)| remote)
+    }
+
+    /// Reports the connect latency of an upstream to the given destination.
+    pub fn report(&mut self, remote: SocketAddr, dst: Ipv4Addr, latency: Duration) {
+        if let Some(index) = self.remotes.iter().position(|r| *r == remote) {
+            if self.health[index].is_down() {
+                info!("destination {} is up", remote);
+                self.ups.push(remote);
+            }
+            self.health[index].failures = 0;
+        }
+        self.update_mode(0);
+
+        self.update_latency(remote, dst, latency);
+    }
+
+    /// Reports the early RTT of an upstream to the given destination, the first round trip of
+    /// data of a flow through it, which is a sample of the same latency as the connect latency.
+    pub fn report_early_rtt(&mut self, remote: SocketAddr, dst: Ipv4Addr, rtt: Duration) {
+        if !self.remotes.contains(&remote) {
+            return;
+        }
+
+        self.update_latency(remote, dst, rtt);
+    }
+
+    fn update_latency(&mut self, remote: SocketAddr, dst: Ipv4Addr, latency: Duration) {
+        let latency = latency.as_secs_f64() * 1000.0;
+        let entry = self
+            .latencies
+            .entry((remote, prefix_of(dst)))
+            .or_insert(Latency {
+                ewma: 0.0,
+                samples: 0,
+                updated: 0,
+            });
+        entry.update(latency);
+        trace!(
+            "update latency of {} to {}/16 to {:.1} ms",
+            remote,
+            dst,
+            entry.ewma
+        );
+
+        if self.path.is_some() && self.last_save.elapsed().as_secs() >= SAVE_INTERVAL {
+            if let Err(ref e) = self.save() {
+                warn!("save latencies: {}", e);
+            }
+        }
+    }
+
+    /// Get the prefixes with the most samples and the upstream chosen for them.
+    pub fn dump(&self, n: usize) -> Vec<LatencyEntry> {
+        let mut prefixes: HashMap<Prefix, usize> = HashMap::new();
+        for ((_, prefix), latency) in &self.latencies {
+            *prefixes.entry(*prefix).or_insert(0) += latency.samples;
+        }
+        let mut prefixes: Vec<(Prefix, usize)> = prefixes.into_iter().collect();
+        prefixes.sort_by_key(|prefix| Reverse(prefix.1));
+
+        prefixes
+            .into_iter()
+            .take(n)
+            .filter_map(|(prefix, _)| {
+                let remote = self.get_best(prefix)?;
+                let latency = self.latencies.get(&(remote, prefix))?;
+
+                Some(LatencyEntry {
+                    prefix: Ipv4Addr::new(prefix[0], prefix[1], 0, 0),
+                    remote,
+                    latency: latency.ewma,
+                    samples: latency.samples,
+                })
+            })
+            .collect()
+    }
+}
+
+impl Display for Upstreams {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            self.remotes
+                .iter()
+                .map(|remote| remote.to_string())
+                .collect::<Vec<String>>()
+                .join(", ")
+        )
+    }
+}
+
+impl Drop for Upstreams {
+    fn drop(&mut self) {
+        // The last save is written in place once the thread writes the ones before
+        if let Some((tx, handle)) = self.saver.take() {
+            drop(tx);
+            let _ = handle.join();
+        }
+        if let Err(ref e) = self.save() {
+            warn!("save latencies: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rule::Rule;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn test_new_without_upstream() {
+        let result = Upstreams::new(Vec::new(), Policy::First, Fallback::Fail);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_save_in_thread() {
+        let path = env::temp_dir().join(format!("pcap2socks-latency-{}", process::id()));
+        let _ = fs::remove_file(&path);
+        let remote: SocketAddr = "127.0.0.1:1080".parse().unwrap();
+        let dst = Ipv4Addr::new(192, 0, 2, 1);
+
+        let threads = Arc::new(Threads::new(1));
+        let mut upstreams =
+            Upstreams::new(vec![remote], Policy::LatencyAware, Fallback::Fail).unwrap();
+        upstreams.load(path.clone()).unwrap();
+        upstreams.start(&threads).unwrap();
+        assert_eq!(threads.dump().len(), 1);
+        upstreams.report(remote, dst, Duration::from_millis(40));
+        upstreams.save().unwrap();
+        // The last save is written once dropped
+        upstreams.report(remote, dst, Duration::from_millis(20));
+        drop(upstreams);
+
+        let mut upstreams =
+            Upstreams::new(vec![remote], Policy::LatencyAware, Fallback::Fail).unwrap();
+        upstreams.load(path.clone()).unwrap();
+        let entries = upstreams.dump(1);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].remote, remote);
+        assert_eq!(entries[0].samples, 2);
+        assert!((entries[0].latency - 30.0).abs() < 0.001);
+        upstreams.path = None;
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_early_rtt() {
+        let remote: SocketAddr = "127.0.0.1:1080".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:1081".parse().unwrap();
+        let dst = Ipv4Addr::new(192, 0, 2, 1);
+        let mut upstreams =
+            Upstreams::new(vec![remote], Policy::LatencyAware, Fallback::Fail).unwrap();
+
+        // Early RTTs are samples of the same EWMA as connect latencies
+        upstreams.report(remote, dst, Duration::from_millis(40));
+        upstreams.report_early_rtt(remote, dst, Duration::from_millis(20));
+        upstreams.report_early_rtt(other, dst, Duration::from_millis(10));
+        let entries = upstreams.dump(1);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].remote, remote);
+        assert_eq!(entries[0].samples, 2);
+        assert!((entries[0].latency - 30.0).abs() < 0.001);
+
+        // An early RTT alone never brings a down upstream up
+        let e = io::Error::from(io::ErrorKind::ConnectionRefused);
+        for _ in 0..FAILURES_BEFORE_DOWN {
+            upstreams.report_failure(remote, &e);
+        }
+        upstreams.report_early_rtt(remote, dst, Duration::from_millis(20));
+        assert!(upstreams.health[0].is_down());
+        assert_eq!(upstreams.dump(1)[0].samples, 3);
+    }
+
+    /// Takes the only upstream down, whose next probe is far away.
+    fn take_down(upstreams: &mut Upstreams) {
+        let remote = upstreams.remotes[0];
+        let e = io::Error::from(io::ErrorKind::ConnectionRefused);
+        for _ in 0..FAILURES_BEFORE_DOWN {
+            upstreams.report_failure(remote, &e);
+        }
+        upstreams.health[0].probe_delay = Duration::from_secs(3600);
+    }
+
+    #[test]
+    fn test_fallback_transitions() {
+        let remote: SocketAddr = "127.0.0.1:1080".parse().unwrap();
+        let dst = Ipv4Addr::new(192, 0, 2, 1);
+        let mut upstreams = Upstreams::new(vec![remote], Policy::First, Fallback::Fail).unwrap();
+        upstreams.fallback_hold = Duration::from_secs(0);
+        upstreams.set_rules(&Rules::new(vec![
+            Rule::parse("a fallback=direct").unwrap(),
+            Rule::parse("b fallback=gateway").unwrap(),
+            Rule::parse("c").unwrap(),
+        ]));
+        let routes = [
+            Route::Direct,
+            Route::Bridge,
+            // The rule without its own policy follows the global one
+            Route::Reject,
+        ];
+        assert!(upstreams.is_bridge_used());
+
+        // Up
+        assert_eq!(upstreams.select(dst), Route::Proxy(remote));
+        for rule in 0..routes.len() {
+            assert!(!upstreams.select_bridge(Some(rule)));
+            assert_eq!(
+                upstreams.select_upstream(dst, Some(rule)),
+                Route::Proxy(remote)
+            );
+        }
+        assert!(!upstreams.is_fallback());
+        assert_eq!(upstreams.get_mode_changes(), 0);
+
+        // Down
+        take_down(&mut upstreams);
+        assert!(upstreams.is_fallback());
+        assert_eq!(upstreams.get_mode_changes(), 1);
+        assert_eq!(upstreams.select(dst), Route::Reject);
+        for (rule, route) in routes.iter().enumerate() {
+            assert_eq!(upstreams.select_bridge(Some(rule)), *route == Route::Bridge);
+            assert_eq!(upstreams.select_upstream(dst, Some(rule)), *route);
+            assert!(upstreams.modes[rule + 1].is_fallback);
+        }
+        assert!(!upstreams.select_bridge(None));
+        assert_eq!(upstreams.get_fallbacks(None), 1);
+        assert_eq!(upstreams.get_fallbacks(Some(0)), 1);
+        assert_eq!(upstreams.get_fallbacks(Some(1)), 2);
+        assert_eq!(upstreams.get_fallbacks(Some(2)), 1);
+
+        // A flow probing the upstream is proxied, and never bridged
+        upstreams.health[0].probe_delay = Duration::from_secs(0);
+        assert!(!upstreams.select_bridge(Some(1)));
+        assert_eq!(
+            upstreams.select_upstream(dst, Some(1)),
+            Route::Proxy(remote)
+        );
+        upstreams.health[0].probe_delay = Duration::from_secs(3600);
+        assert_eq!(upstreams.select_upstream(dst, Some(1)), Route::Bridge);
+        assert_eq!(upstreams.get_fallbacks(Some(1)), 3);
+
+        // Up again, the mode of a rule follows once a flow of it is routed
+        upstreams.report(remote, dst, Duration::from_millis(20));
+        assert!(!upstreams.is_fallback());
+        assert_eq!(upstreams.get_mode_changes(), 2);
+        assert!(upstreams.modes[2].is_fallback);
+        for rule in 0..routes.len() {
+            assert!(!upstreams.select_bridge(Some(rule)));
+            assert_eq!(
+                upstreams.select_upstream(dst, Some(rule)),
+                Route::Proxy(remote)
+            );
+            assert!(!upstreams.modes[rule + 1].is_fallback);
+            assert_eq!(upstreams.modes[rule + 1].changes, 2);
+        }
+        assert_eq!(upstreams.select(dst), Route::Proxy(remote));
+
+        // Down again
+        take_down(&mut upstreams);
+        assert_eq!(upstreams.get_mode_changes(), 3);
+        assert_eq!(upstreams.select_upstream(dst, Some(0)), Route::Direct);
+        assert!(upstreams.select_bridge(Some(1)));
+        assert_eq!(upstreams.select(dst), Route::Reject);
+    }
+
+    #[test]
+    fn test_flap() {
+        let remotes: Vec<SocketAddr> = vec![
+            "127.0.0.1:1080".parse().unwrap(),
+            "127.0.0.1:1081".parse().unwrap(),
+        ];
+        let dst = Ipv4Addr::new(192, 0, 2, 1);
+        let mut upstreams = Upstreams::new(remotes.clone(), Policy::First, Fallback::Fail).unwrap();
+        upstreams.fallback_hold = Duration::from_secs(0);
+
+        // A flapped upstream is failed over once, and the others take its flows
+        upstreams.flap(remotes[0]);
+        upstreams.flap(remotes[0]);
+        assert_eq!(upstreams.take_downs(), vec![remotes[0]]);
+        upstreams.health[0].probe_delay = Duration::from_secs(3600);
+        assert_eq!(upstreams.select(dst), Route::Proxy(remotes[1]));
+        assert!(!upstreams.is_fallback());
+
+        // It is back once a probe connects
+        upstreams.health[0].probe_delay = Duration::from_secs(0);
+        assert_eq!(upstreams.select(dst), Route::Proxy(remotes[0]));
+        upstreams.report(remotes[0], dst, Duration::from_millis(20));
+        assert!(!upstreams.health[0].is_down());
+        assert_eq!(upstreams.take_ups(), vec![remotes[0]]);
+        upstreams.report(remotes[0], dst, Duration::from_millis(20));
+        assert!(upstreams.take_ups().is_empty());
+        upstreams.flap(remotes[1]);
+        upstreams.flap(remotes[0]);
+        assert!(upstreams.is_fallback());
+    }
+
+    #[test]
+    fn test_fallback_hold() {
+        let remote: SocketAddr = "127.0.0.1:1080".parse().unwrap();
+        let dst = Ipv4Addr::new(192, 0, 2, 1);
+        let mut upstreams = Upstreams::new(vec![remote], Policy::First, Fallback::Gateway).unwrap();
+        take_down(&mut upstreams);
+        assert!(upstreams.select_bridge(None));
+
+        // An upstream up within the hold leaves new flows bridged
+        upstreams.report(remote, dst, Duration::from_millis(20));
+        assert!(upstreams.is_fallback());
+        assert!(upstreams.select_bridge(None));
+        assert_eq!(upstreams.select(dst), Route::Bridge);
+        assert_eq!(upstreams.get_mode_changes(), 1);
+
+        // And proxied after the hold
+        upstreams.fallback_hold = Duration::from_secs(0);
+        assert!(!upstreams.select_bridge(None));
+        assert_eq!(upstreams.select(dst), Route::Proxy(remote));
+        assert_eq!(upstreams.get_mode_changes(), 2);
+        assert_eq!(upstreams.get_fallbacks(None), 3);
+    }
+}
@@ -0,0 +1,66 @@
+//! Drives `Redirector`s with a scripted device instead of a capture.
+
+use std::io;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+
+use pcap2socks_core::{DataLinkReceiver, DataLinkSender, NetworkInterface};
+
+/// Represents the sending side of the capture, which hands frames to the device.
+pub struct DeviceSender(pub Sender<Vec<u8>>);
+
+impl DataLinkSender for DeviceSender {
+    fn build_and_send(
+        &mut self,
+        num_packets: usize,
+        packet_size: usize,
+        func: &mut dyn FnMut(&mut [u8]),
+    ) -> Option<io::Result<()>> {
+        for _ in 0..num_packets {
+            let mut frame = vec![0u8; packet_size];
+            func(&mut frame);
+            let _ = self.0.send(frame);
+        }
+
+        Some(Ok(()))
+    }
+
+    fn send_to(&mut self, packet: &[u8], _: Option<NetworkInterface>) -> Option<io::Result<()>> {
+        let _ = self.0.send(packet.to_vec());
+
+        Some(Ok(()))
+    }
+}
+
+/// Represents the receiving side of the capture, which takes frames from the device. It times
+/// out like a capture with a read timeout, and ends once the device is gone.
+pub struct DeviceReceiver {
+    rx: Receiver<Vec<u8>>,
+    frame: Vec<u8>,
+}
+
+impl DeviceReceiver {
+    /// Creates a new `DeviceReceiver` taking frames from the receiver.
+    pub fn new(rx: Receiver<Vec<u8>>) -> DeviceReceiver {
+        DeviceReceiver {
+            rx,
+            frame: Vec::new(),
+        }
+    }
+}
+
+impl DataLinkReceiver for DeviceReceiver {
+    fn next(&mut self) -> io::Result<&[u8]> {
+        match self.rx.recv_timeout(Duration::from_millis(20)) {
+            Ok(frame) => {
+                self.frame = frame;
+
+                Ok(&self.frame)
+            }
+            Err(RecvTimeoutError::Timeout) => Err(io::Error::from(io::ErrorKind::TimedOut)),
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "device is gone"))
+            }
+        }
+    }
+}
@@ -0,0 +1,283 @@
+//! Hands off a capture from an old `Redirector` to a new one under UDP traffic of a scripted
+//! device. Both `Redirector`s see the frames of the device like 2 processes capturing the same
+//! interface, and a mock SOCKS5 proxy on the loopback echoes the datagrams of each UDP
+//! association. The new `Redirector` associates again with the same local port. The device sends
+//! each datagram once the last one is echoed, and hands off between 2 datagrams, so no datagram
+//! is in flight during the handoff and none may be lost.
+//!
+//! Run with `cargo test -p pcap2socks-core --test handoff`.
+#![cfg(unix)]
+
+use pnet::datalink::MacAddr;
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::{self, Ipv4Packet, MutableIpv4Packet};
+use pnet::packet::udp::{self, MutableUdpPacket, UdpPacket};
+use pnet::packet::Packet;
+use std::collections::HashSet;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use pcap2socks_core::handoff::{Channel, Handoff, Phase};
+use pcap2socks_core::upstream::{Fallback, Policy, Upstreams};
+use pcap2socks_core::{DataLinkReceiver, Forwarder, HardwareAddr, Redirector};
+
+mod common;
+
+use common::{DeviceReceiver, DeviceSender};
+
+const DEVICE_HARDWARE_ADDR: HardwareAddr = MacAddr(2, 0, 0, 0, 0, 2);
+const LOCAL_HARDWARE_ADDR: HardwareAddr = MacAddr(2, 0, 0, 0, 0, 1);
+const DEVICE_IP_ADDR: Ipv4Addr = Ipv4Addr::new(10, 6, 0, 2);
+const LOCAL_IP_ADDR: Ipv4Addr = Ipv4Addr::new(10, 6, 0, 1);
+const DEVICE_PORT: u16 = 40000;
+const INITIAL_PORT: u16 = 47000;
+/// Represents the number of datagrams the device sends before the handoff and after it.
+const DATAGRAMS: u32 = 100;
+/// Represents the max time an echo takes, which only bounds a failing test.
+const ECHO_TIMEOUT: u64 = 5;
+
+/// Spawns a SOCKS5 proxy without authentication answering each UDP ASSOCIATE with a relay of its
+/// own, which echoes the datagrams until the control connection closes. The local addresses of
+/// the associations are recorded.
+fn spawn_proxy(associations: Arc<Mutex<Vec<SocketAddr>>>) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            let associations = Arc::clone(&associations);
+            thread::spawn(move || associate(stream, associations));
+        }
+    });
+
+    Ok(addr)
+}
+
+fn associate(mut stream: TcpStream, associations: Arc<Mutex<Vec<SocketAddr>>>) -> io::Result<()> {
+    let mut buffer = [0u8; 1024];
+    // Methods
+    stream.read_exact(&mut buffer[..2])?;
+    let methods = buffer[1] as usize;
+    stream.read_exact(&mut buffer[..methods])?;
+    stream.write_all(&[5, 0])?;
+    // UDP ASSOCIATE from an IPv4 address
+    stream.read_exact(&mut buffer[..10])?;
+    assert_eq!(buffer[1], 3);
+    let relay = UdpSocket::bind("127.0.0.1:0")?;
+    relay.set_read_timeout(Some(Duration::from_millis(10)))?;
+    let port = relay.local_addr()?.port().to_be_bytes();
+    stream.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, port[0], port[1]])?;
+
+    let is_closed = Arc::new(AtomicBool::new(false));
+    let a_is_closed = Arc::clone(&is_closed);
+    thread::spawn(move || {
+        let mut buffer = [0u8; 1024];
+        while !a_is_closed.load(Ordering::Relaxed) {
+            // The header of the destination is the header of the source of the echo
+            if let Ok((size, addr)) = relay.recv_from(&mut buffer) {
+                if size >= 10 {
+                    associations.lock().unwrap().push(addr);
+                    let _ = relay.send_to(&buffer[..size], addr);
+                }
+            }
+        }
+    });
+    let result = stream.read(&mut buffer).map(|_| ());
+    is_closed.store(true, Ordering::Relaxed);
+
+    result
+}
+
+/// Builds a UDP datagram of the device in an Ethernet frame.
+fn build_frame(dst: SocketAddrV4, payload: &[u8]) -> Vec<u8> {
+    let udp_size = 8 + payload.len();
+    let ipv4_size = 20 + udp_size;
+    let mut frame = vec![0u8; 14 + ipv4_size];
+
+    let mut ethernet = MutableEthernetPacket::new(&mut frame).unwrap();
+    ethernet.set_source(DEVICE_HARDWARE_ADDR);
+    ethernet.set_destination(LOCAL_HARDWARE_ADDR);
+    ethernet.set_ethertype(EtherTypes::Ipv4);
+
+    let mut ipv4 = MutableIpv4Packet::new(&mut frame[14..]).unwrap();
+    ipv4.set_version(4);
+    ipv4.set_header_length(5);
+    ipv4.set_total_length(ipv4_size as u16);
+    ipv4.set_ttl(64);
+    ipv4.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+    ipv4.set_source(DEVICE_IP_ADDR);
+    ipv4.set_destination(*dst.ip());
+    let checksum = ipv4::checksum(&ipv4.to_immutable());
+    ipv4.set_checksum(checksum);
+
+    let mut udp = MutableUdpPacket::new(&mut frame[34..]).unwrap();
+    udp.set_source(DEVICE_PORT);
+    udp.set_destination(dst.port());
+    udp.set_length(udp_size as u16);
+    udp.set_payload(payload);
+    let checksum = udp::ipv4_checksum(&udp.to_immutable(), &DEVICE_IP_ADDR, dst.ip());
+    udp.set_checksum(checksum);
+
+    frame
+}
+
+/// Get the sequence of a datagram echoed to the device.
+fn get_sequence(frame: &[u8]) -> Option<u32> {
+    let ethernet = EthernetPacket::new(frame)?;
+    if ethernet.get_ethertype() != EtherTypes::Ipv4 {
+        return None;
+    }
+    let ipv4 = Ipv4Packet::new(ethernet.payload())?;
+    if ipv4.get_next_level_protocol() != IpNextHeaderProtocols::Udp {
+        return None;
+    }
+    let udp = UdpPacket::new(ipv4.payload())?;
+    let payload = udp.payload();
+    if udp.get_destination() != DEVICE_PORT || payload.len() < 4 {
+        return None;
+    }
+
+    Some(u32::from_be_bytes([
+        payload[0], payload[1], payload[2], payload[3],
+    ]))
+}
+
+/// Represents the device, which sends each datagram to the captures once the last one is echoed.
+struct Device {
+    dst: SocketAddrV4,
+    captures: Vec<Sender<Vec<u8>>>,
+    rx: Receiver<Vec<u8>>,
+    echoed: HashSet<u32>,
+}
+
+impl Device {
+    /// Sends the datagrams of the sequences to the captures, and waits for the echo of each.
+    fn send(&mut self, sequences: std::ops::Range<u32>) {
+        for sequence in sequences {
+            let frame = build_frame(self.dst, &sequence.to_be_bytes());
+            // A capture which has ended drops the frames
+            for capture in self.captures.iter() {
+                let _ = capture.send(frame.clone());
+            }
+            loop {
+                let frame = self
+                    .rx
+                    .recv_timeout(Duration::from_secs(ECHO_TIMEOUT))
+                    .unwrap_or_else(|_| panic!("datagram {} is not echoed", sequence));
+                if let Some(echoed) = get_sequence(&frame) {
+                    self.echoed.insert(echoed);
+                    if echoed == sequence {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn new_redirector(
+    proxy: SocketAddr,
+    capture_tx: mpsc::Sender<Vec<u8>>,
+    handoff: Arc<Handoff>,
+) -> io::Result<Redirector> {
+    let forwarder = Forwarder::new(
+        Box::new(DeviceSender(capture_tx)),
+        1400,
+        LOCAL_HARDWARE_ADDR,
+        DEVICE_IP_ADDR,
+        LOCAL_IP_ADDR,
+    );
+    let upstreams = Upstreams::new(vec![proxy], Policy::First, Fallback::Fail)?;
+    let mut redirector = Redirector::new(
+        Arc::new(Mutex::new(forwarder)),
+        DEVICE_IP_ADDR,
+        None,
+        upstreams,
+        INITIAL_PORT,
+        64,
+    );
+    redirector.set_handoff(handoff)?;
+
+    Ok(redirector)
+}
+
+#[test]
+fn test_handoff() -> io::Result<()> {
+    let associations = Arc::new(Mutex::new(Vec::new()));
+    let proxy = spawn_proxy(Arc::clone(&associations))?;
+
+    // Both captures see the frames of the device, and send to it
+    let (capture_tx, device_rx) = mpsc::channel();
+    let (old_tx, old_rx) = mpsc::channel();
+    let (new_tx, new_rx) = mpsc::channel::<Vec<u8>>();
+    let mut device = Device {
+        dst: SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 7777),
+        captures: vec![old_tx, new_tx],
+        rx: device_rx,
+        echoed: HashSet::new(),
+    };
+
+    let old_handoff = Arc::new(Handoff::new(Duration::from_secs(1)));
+    let mut old = new_redirector(proxy, capture_tx.clone(), Arc::clone(&old_handoff))?;
+    let old_capture = thread::spawn(move || {
+        let mut rx: Box<dyn DataLinkReceiver> = Box::new(DeviceReceiver::new(old_rx));
+        old.open(&mut rx)
+    });
+    device.send(0..DATAGRAMS);
+
+    // Hand off between 2 datagrams, the new capture tells once it serves
+    let (channel, peer) = Channel::pair()?;
+    let (serving_tx, serving_rx) = mpsc::channel();
+    let new_capture = thread::spawn(move || -> io::Result<()> {
+        let new_handoff = Arc::new(Handoff::take_over(peer)?);
+        let mut new = new_redirector(proxy, capture_tx, Arc::clone(&new_handoff))?;
+        let capture = thread::spawn(move || {
+            let mut rx: Box<dyn DataLinkReceiver> = Box::new(DeviceReceiver::new(new_rx));
+            new.open(&mut rx)
+        });
+        new_handoff.ready()?;
+        let _ = serving_tx.send(new_handoff.get_phase());
+
+        match capture.join().unwrap() {
+            Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+            result => result,
+        }
+    });
+    old_handoff.hand_off(channel)?;
+    assert_eq!(old_handoff.get_phase(), Phase::Draining);
+    assert_eq!(
+        serving_rx.recv_timeout(Duration::from_secs(ECHO_TIMEOUT)),
+        Ok(Phase::Serving)
+    );
+    device.send(DATAGRAMS..2 * DATAGRAMS);
+
+    // The old capture ends once drained, with no TCP connection to drain
+    old_capture.join().unwrap()?;
+    let echoed = device.echoed.clone();
+    drop(device);
+    new_capture.join().unwrap()?;
+
+    let lost: Vec<u32> = (0..2 * DATAGRAMS)
+        .filter(|sequence| !echoed.contains(sequence))
+        .collect();
+    assert!(lost.is_empty(), "datagrams {:?} lost", lost);
+    // The proxy sees the new association from the same local port
+    let ports: HashSet<u16> = associations
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|addr| addr.port())
+        .collect();
+    assert_eq!(ports, vec![INITIAL_PORT].into_iter().collect());
+
+    Ok(())
+}
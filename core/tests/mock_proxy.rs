@@ -0,0 +1,410 @@
+//! Drives a `Redirector` with a scripted device instead of a capture. The device opens a TCP
+//! connection, sends a request and reads the reply, which a mock SOCKS5 proxy on the loopback
+//! answers by itself, or the replay of the record in `tests/fixtures` answers as recorded. A
+//! keep-alive connection is also resumed in a restarted `Redirector`, which picks it up.
+//!
+//! Run with `cargo test -p pcap2socks-core --test mock_proxy`.
+
+use pnet::datalink::MacAddr;
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::{self, Ipv4Packet, MutableIpv4Packet};
+use pnet::packet::tcp::{self, MutableTcpPacket, TcpFlags, TcpPacket};
+use pnet::packet::Packet;
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use pcap2socks_core::privacy::Redactor;
+use pcap2socks_core::record::{self, Direction, Kind, Record, Recording};
+use pcap2socks_core::upstream::{Fallback, Policy, Upstreams};
+use pcap2socks_core::{DataLinkReceiver, Forwarder, HardwareAddr, Redirector};
+
+mod common;
+
+use common::{DeviceReceiver, DeviceSender};
+
+const DEVICE_HARDWARE_ADDR: HardwareAddr = MacAddr(2, 0, 0, 0, 0, 2);
+const LOCAL_HARDWARE_ADDR: HardwareAddr = MacAddr(2, 0, 0, 0, 0, 1);
+const DEVICE_IP_ADDR: Ipv4Addr = Ipv4Addr::new(10, 6, 0, 2);
+const LOCAL_IP_ADDR: Ipv4Addr = Ipv4Addr::new(10, 6, 0, 1);
+const DEVICE_PORT: u16 = 40000;
+const TIMEOUT: u64 = 5;
+
+/// Spawns a SOCKS5 proxy without authentication answering each CONNECT by itself, with the
+/// request in upper case.
+fn spawn_proxy() -> io::Result<SocketAddr> {
+    spawn_answering_proxy(|stream, _| {
+        let mut buffer = [0u8; 1024];
+        loop {
+            let size = stream.read(&mut buffer)?;
+            if size == 0 {
+                return Ok(());
+            }
+            stream.write_all(&buffer[..size].to_ascii_uppercase())?;
+        }
+    })
+}
+
+/// Spawns a SOCKS5 proxy without authentication answering each CONNECT by itself like a keep-alive
+/// HTTP server, which replies to each request with the number of the connection and the path.
+fn spawn_http_proxy() -> io::Result<SocketAddr> {
+    spawn_answering_proxy(|stream, connection| {
+        let mut head = Vec::new();
+        let mut buffer = [0u8; 1024];
+        loop {
+            let size = stream.read(&mut buffer)?;
+            if size == 0 {
+                return Ok(());
+            }
+            head.extend_from_slice(&buffer[..size]);
+            while let Some(end) = head.windows(4).position(|window| window == b"\r\n\r\n") {
+                let request = String::from_utf8_lossy(&head[..end]).into_owned();
+                head.drain(..end + 4);
+                let path = request.split(' ').nth(1).unwrap_or("/");
+                stream.write_all(&format_http_reply(connection, path))?;
+            }
+        }
+    })
+}
+
+/// Formats the reply of the mock HTTP server to the request of the path in the connection.
+fn format_http_reply(connection: usize, path: &str) -> Vec<u8> {
+    let body = format!("{} {}", connection, path);
+    format!(
+        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: keep-alive\r\n\r\n{}",
+        body.len(),
+        body
+    )
+    .into_bytes()
+}
+
+/// Spawns a SOCKS5 proxy without authentication answering each CONNECT by itself with the answer,
+/// which is given the number of the connection, counted from 1.
+fn spawn_answering_proxy(
+    answer: fn(&mut TcpStream, usize) -> io::Result<()>,
+) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let connections = Arc::new(AtomicUsize::new(0));
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            let connections = Arc::clone(&connections);
+            thread::spawn(move || -> io::Result<()> {
+                let mut buffer = [0u8; 1024];
+                // Methods
+                stream.read_exact(&mut buffer[..2])?;
+                let methods = buffer[1] as usize;
+                stream.read_exact(&mut buffer[..methods])?;
+                stream.write_all(&[5, 0])?;
+                // CONNECT to an IPv4 destination
+                stream.read_exact(&mut buffer[..10])?;
+                let dst = SocketAddrV4::new(
+                    Ipv4Addr::new(buffer[4], buffer[5], buffer[6], buffer[7]),
+                    u16::from_be_bytes([buffer[8], buffer[9]]),
+                );
+                assert_eq!(dst.port(), 80);
+                stream.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])?;
+
+                let connection = connections.fetch_add(1, Ordering::SeqCst) + 1;
+                answer(&mut stream, connection)
+            });
+        }
+    });
+
+    Ok(addr)
+}
+
+/// Builds a TCP segment of the device in an Ethernet frame.
+fn build_frame(dst: SocketAddrV4, flags: u16, sequence: u32, ack: u32, payload: &[u8]) -> Vec<u8> {
+    let tcp_size = 20 + payload.len();
+    let ipv4_size = 20 + tcp_size;
+    let mut frame = vec![0u8; 14 + ipv4_size];
+
+    let mut ethernet = MutableEthernetPacket::new(&mut frame).unwrap();
+    ethernet.set_source(DEVICE_HARDWARE_ADDR);
+    ethernet.set_destination(LOCAL_HARDWARE_ADDR);
+    ethernet.set_ethertype(EtherTypes::Ipv4);
+
+    let mut ipv4 = MutableIpv4Packet::new(&mut frame[14..]).unwrap();
+    ipv4.set_version(4);
+    ipv4.set_header_length(5);
+    ipv4.set_total_length(ipv4_size as u16);
+    ipv4.set_ttl(64);
+    ipv4.set_next_level_protocol(IpNextHeaderProtocols::Tcp);
+    ipv4.set_source(DEVICE_IP_ADDR);
+    ipv4.set_destination(*dst.ip());
+    let checksum = ipv4::checksum(&ipv4.to_immutable());
+    ipv4.set_checksum(checksum);
+
+    let mut tcp = MutableTcpPacket::new(&mut frame[34..]).unwrap();
+    tcp.set_source(DEVICE_PORT);
+    tcp.set_destination(dst.port());
+    tcp.set_sequence(sequence);
+    tcp.set_acknowledgement(ack);
+    tcp.set_data_offset(5);
+    tcp.set_flags(flags);
+    tcp.set_window(65535);
+    tcp.set_payload(payload);
+    let checksum = tcp::ipv4_checksum(&tcp.to_immutable(), &DEVICE_IP_ADDR, dst.ip());
+    tcp.set_checksum(checksum);
+
+    frame
+}
+
+/// Represents a TCP segment to the device.
+struct Segment {
+    flags: u16,
+    sequence: u32,
+    payload: Vec<u8>,
+}
+
+/// Waits for the next TCP segment to the device.
+fn receive(rx: &Receiver<Vec<u8>>) -> io::Result<Segment> {
+    loop {
+        let frame = rx
+            .recv_timeout(Duration::from_secs(TIMEOUT))
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no segment to the device"))?;
+        let ethernet = match EthernetPacket::new(&frame) {
+            Some(ethernet) if ethernet.get_ethertype() == EtherTypes::Ipv4 => ethernet,
+            _ => continue,
+        };
+        let ipv4 = match Ipv4Packet::new(ethernet.payload()) {
+            Some(ipv4) if ipv4.get_next_level_protocol() == IpNextHeaderProtocols::Tcp => ipv4,
+            _ => continue,
+        };
+        // Frames are padded to the minimum size of Ethernet
+        let size = ipv4.get_total_length() as usize - ipv4.get_header_length() as usize * 4;
+        let tcp = match TcpPacket::new(&ipv4.payload()[..size]) {
+            Some(tcp) => tcp,
+            None => continue,
+        };
+
+        return Ok(Segment {
+            flags: tcp.get_flags(),
+            sequence: tcp.get_sequence(),
+            payload: tcp.payload().to_vec(),
+        });
+    }
+}
+
+/// Represents the scripted device of a running `Redirector`.
+struct Device {
+    tx: Sender<Vec<u8>>,
+    rx: Receiver<Vec<u8>>,
+    capture: JoinHandle<(io::Result<()>, (usize, usize))>,
+}
+
+impl Device {
+    /// Starts a `Redirector` through the proxy, which records flows if the recording is given,
+    /// and picks up established connections if `is_pickup` is set.
+    fn start(
+        proxy: SocketAddr,
+        recording: Option<Recording>,
+        is_pickup: bool,
+    ) -> io::Result<Device> {
+        let (device_tx, capture_rx) = mpsc::channel();
+        let (capture_tx, device_rx) = mpsc::channel();
+        let forwarder = Forwarder::new(
+            Box::new(DeviceSender(capture_tx)),
+            1400,
+            LOCAL_HARDWARE_ADDR,
+            DEVICE_IP_ADDR,
+            LOCAL_IP_ADDR,
+        );
+        let upstreams = Upstreams::new(vec![proxy], Policy::First, Fallback::Fail)?;
+        let mut redirector = Redirector::new(
+            Arc::new(Mutex::new(forwarder)),
+            DEVICE_IP_ADDR,
+            None,
+            upstreams,
+            30000,
+            64,
+        );
+        if let Some(recording) = recording {
+            redirector.set_recording(recording);
+        }
+        redirector.set_pickup_established(is_pickup);
+        let mut rx: Box<dyn DataLinkReceiver> = Box::new(DeviceReceiver::new(capture_rx));
+        let capture = thread::spawn(move || {
+            let result = redirector.open(&mut rx);
+
+            (result, redirector.get_tcp_pickups())
+        });
+
+        Ok(Device {
+            tx: device_tx,
+            rx: device_rx,
+            capture,
+        })
+    }
+
+    /// Sends a TCP segment to the `Redirector`.
+    fn send(&self, dst: SocketAddrV4, flags: u16, sequence: u32, ack: u32, payload: &[u8]) {
+        self.tx
+            .send(build_frame(dst, flags, sequence, ack, payload))
+            .unwrap();
+    }
+
+    /// Opens a TCP connection to the destination, and returns the sequence and the
+    /// acknowledgement of the device.
+    fn connect(&self, dst: SocketAddrV4) -> io::Result<(u32, u32)> {
+        let sequence = 1000;
+        self.send(dst, TcpFlags::SYN, sequence, 0, &[]);
+        let syn = receive(&self.rx)?;
+        assert_eq!(syn.flags & TcpFlags::SYN, TcpFlags::SYN, "no ACK/SYN");
+        let ack = syn.sequence.wrapping_add(1);
+        self.send(dst, TcpFlags::ACK, sequence + 1, ack, &[]);
+
+        Ok((sequence + 1, ack))
+    }
+
+    /// Sends the request at the sequence, and acknowledges the reply until it has the given
+    /// size. The sequence and the acknowledgement are advanced.
+    fn request(
+        &self,
+        dst: SocketAddrV4,
+        sequence: &mut u32,
+        ack: &mut u32,
+        request: &[u8],
+        reply_size: usize,
+    ) -> io::Result<Vec<u8>> {
+        self.send(dst, TcpFlags::ACK | TcpFlags::PSH, *sequence, *ack, request);
+        *sequence = sequence.wrapping_add(request.len() as u32);
+        let mut reply = Vec::new();
+        while reply.len() < reply_size {
+            let segment = receive(&self.rx)?;
+            if !segment.payload.is_empty() && segment.sequence == *ack {
+                *ack = ack.wrapping_add(segment.payload.len() as u32);
+                reply.extend_from_slice(&segment.payload);
+                self.send(dst, TcpFlags::ACK, *sequence, *ack, &[]);
+            }
+        }
+
+        Ok(reply)
+    }
+
+    /// Stops the `Redirector`, which ends the capture once the device is gone, and returns its
+    /// TCP pickups and pickup failures.
+    fn stop(self) -> io::Result<(usize, usize)> {
+        drop(self.tx);
+        match self.capture.join().unwrap() {
+            (Err(ref e), pickups) if e.kind() == io::ErrorKind::BrokenPipe => Ok(pickups),
+            (Err(e), _) => Err(e),
+            (Ok(_), pickups) => Ok(pickups),
+        }
+    }
+}
+
+/// Connects the device to the destination through the proxy, sends the request and returns the
+/// reply once it has the given size. The `Redirector` records the flow if the recording is given.
+fn exchange(
+    proxy: SocketAddr,
+    dst: SocketAddrV4,
+    request: &[u8],
+    reply_size: usize,
+    recording: Option<Recording>,
+) -> io::Result<Vec<u8>> {
+    let device = Device::start(proxy, recording, false)?;
+    let (mut sequence, mut ack) = device.connect(dst)?;
+    let reply = device.request(dst, &mut sequence, &mut ack, request, reply_size)?;
+    device.stop()?;
+
+    Ok(reply)
+}
+
+/// Get the payloads of the entries of the direction in the record, joined.
+fn get_payload(record: &Record, direction: Direction) -> Vec<u8> {
+    record
+        .entries
+        .iter()
+        .filter(|entry| entry.direction == direction)
+        .flat_map(|entry| entry.payload.iter().cloned())
+        .collect()
+}
+
+#[test]
+fn test_mock_proxy() -> io::Result<()> {
+    let proxy = spawn_proxy()?;
+    // Any destination, the mock proxy answers by itself
+    let dst = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 80), 80);
+
+    let request = b"hello through the proxy";
+    let reply = exchange(proxy, dst, request, request.len(), None)?;
+    assert_eq!(reply, request.to_ascii_uppercase());
+
+    Ok(())
+}
+
+#[test]
+fn test_replay_record() -> io::Result<()> {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/http.rec");
+    let fixture = Record::read(&path)?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let proxy = listener.local_addr()?;
+    let replay = thread::spawn(move || record::replay(&path, listener, true));
+
+    // The device sends what is recorded and receives the recorded reply, which is recorded again
+    let dir = env::temp_dir().join(format!("pcap2socks-replay-{}", process::id()));
+    let recording = Recording::new(dir.clone(), Vec::new(), 1 << 20, Redactor::default())?;
+    let request = get_payload(&fixture, Direction::Up);
+    let expected = get_payload(&fixture, Direction::Down);
+    let reply = exchange(
+        proxy,
+        fixture.dst,
+        &request,
+        expected.len(),
+        Some(recording),
+    )?;
+    assert_eq!(reply, expected);
+    replay.join().unwrap()?;
+
+    let files: Vec<_> = fs::read_dir(&dir)?.collect::<io::Result<_>>()?;
+    assert_eq!(files.len(), 1);
+    let recorded = Record::read(&files[0].path())?;
+    fs::remove_dir_all(&dir)?;
+    assert_eq!(recorded.kind, Kind::Stream);
+    assert_eq!(recorded.dst, fixture.dst);
+    assert_eq!(get_payload(&recorded, Direction::Up), request);
+    assert_eq!(get_payload(&recorded, Direction::Down), expected);
+
+    Ok(())
+}
+
+#[test]
+fn test_pickup_keep_alive() -> io::Result<()> {
+    let proxy = spawn_http_proxy()?;
+    let dst = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 80), 80);
+    let first = b"GET /first HTTP/1.1\r\nHost: example.com\r\n\r\n";
+    let second = b"GET /second HTTP/1.1\r\nHost: example.com\r\n\r\n";
+
+    // The first request goes through the first instance, which is then restarted
+    let device = Device::start(proxy, None, false)?;
+    let (mut sequence, mut ack) = device.connect(dst)?;
+    let expected = format_http_reply(1, "/first");
+    let reply = device.request(dst, &mut sequence, &mut ack, first, expected.len())?;
+    assert_eq!(reply, expected);
+    assert_eq!(device.stop()?, (0, 0));
+
+    // The second request of the keep-alive connection forces the pickup, and goes through the
+    // resumed flow in a new connection of the proxy
+    let device = Device::start(proxy, None, true)?;
+    let expected = format_http_reply(2, "/second");
+    let reply = device.request(dst, &mut sequence, &mut ack, second, expected.len())?;
+    assert_eq!(reply, expected);
+    assert_eq!(device.stop()?, (1, 0));
+
+    Ok(())
+}
@@ -1,10 +1,30 @@
 use clap::{crate_description, crate_version, Clap};
+use ipnetwork::{IpNetworkError, Ipv4Network};
 use std::clone::Clone;
+use std::env;
 use std::error::Error;
 use std::fmt::{self, Display, Formatter};
-use std::net::{AddrParseError, Ipv4Addr, SocketAddrV4};
+use std::fs;
+use std::net::{AddrParseError, Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::result;
 
+use pcap2socks_core::console::{probe, Preset, Responder, DEFAULT_PING_TTL};
+use pcap2socks_core::discovery::DiscoveryPolicy;
+use pcap2socks_core::notify::{Condition, Config as NotifyConfig};
+use pcap2socks_core::privacy::Privacy;
+use pcap2socks_core::rule::{Rule, Rules};
+use pcap2socks_core::schedule::{Schedule, Window};
+use pcap2socks_core::upstream::{Fallback, Policy};
+use pcap2socks_core::Secret;
+use pcap2socks_core::{
+    is_path_mtu_probe_supported, HalfOpenOverflow, HardwareAddr, ProxyType, ResumeStrategy,
+};
+
+/// Represents the environment variable of the username of proxies.
+const ENV_USERNAME: &str = "PCAP2SOCKS_PROXY_USER";
+/// Represents the environment variable of the password of proxies.
+const ENV_PASSWORD: &str = "PCAP2SOCKS_PROXY_PASS";
+
 /// Represents the flags of the application.
 #[derive(Clap)]
 #[clap(
@@ -16,6 +36,26 @@ pub struct Flags {
     pub verbose: bool,
     #[clap(long, short = "V", about = "Prints vverbose information")]
     pub vverbose: bool,
+    #[clap(
+        long = "log-control",
+        about = "File of commands changing the log levels while running",
+        value_name = "FILE"
+    )]
+    pub log_control: Option<String>,
+    #[clap(
+        long = "control-listen",
+        about = "Loopback address answering commands of the control file while running",
+        value_name = "ADDRESS"
+    )]
+    pub control_listen: Option<String>,
+    #[clap(
+        long = "log-ring-level",
+        about = "Level of the recent log lines kept for the control",
+        value_name = "LEVEL",
+        default_value = "debug",
+        possible_values = &["off", "error", "warn", "info", "debug", "trace"]
+    )]
+    pub log_ring_level: String,
     #[clap(
         long = "interface",
         short,
@@ -25,6 +65,13 @@ pub struct Flags {
     pub inter: Option<String>,
     #[clap(long, about = "MTU", value_name = "VALUE", default_value = "1400")]
     pub mtu: u16,
+    #[clap(
+        long = "max-threads",
+        about = "Max number of threads",
+        value_name = "VALUE",
+        default_value = "512"
+    )]
+    pub max_threads: usize,
     #[clap(long, short, about = "ARP publishing address", value_name = "ADDRESS")]
     pub publish: Option<String>,
     #[clap(long = "source", short, about = "Source", value_name = "ADDRESS")]
@@ -34,9 +81,504 @@ pub struct Flags {
         short,
         about = "Destination",
         value_name = "ADDRESS",
-        default_value = "127.0.0.1:1080"
+        default_value = "127.0.0.1:1080",
+        number_of_values = 1
+    )]
+    pub dst: Vec<String>,
+    #[clap(
+        long = "proxy-type",
+        about = "Protocol of destinations",
+        value_name = "TYPE",
+        default_value = "socks5",
+        possible_values = &["socks5", "http"]
+    )]
+    pub proxy_type: String,
+    #[clap(
+        long,
+        about = "Policy selecting a destination",
+        value_name = "POLICY",
+        default_value = "first",
+        possible_values = &["first", "latency-aware"]
+    )]
+    pub balance: String,
+    #[clap(
+        long = "latency-file",
+        about = "File persisting latencies",
+        value_name = "FILE"
+    )]
+    pub latency_file: Option<String>,
+    #[clap(
+        long = "state-file",
+        about = "File persisting the state across restarts",
+        value_name = "FILE"
+    )]
+    pub state_file: Option<String>,
+    #[clap(
+        long = "stats-file",
+        about = "Memory-mapped file exporting statistics",
+        value_name = "FILE"
+    )]
+    pub stats_file: Option<String>,
+    #[clap(
+        long,
+        about = "Policy when all destinations are down",
+        value_name = "POLICY",
+        default_value = "fail",
+        possible_values = &["direct", "fail", "gateway"]
+    )]
+    pub fallback: String,
+    #[clap(
+        long = "gateway-hardware-addr",
+        about = "Hardware address of the real gateway new connections are bridged to by the fallback policy gateway",
+        value_name = "ADDRESS"
+    )]
+    pub gateway_hardware_addr: Option<String>,
+    #[clap(
+        long = "backoff-cap",
+        about = "Max delay of retries in seconds",
+        value_name = "SECONDS",
+        default_value = "60"
+    )]
+    pub backoff_cap: u64,
+    #[clap(
+        long = "max-handshakes",
+        about = "Max number of concurrent handshakes with destinations, 0 for unlimited",
+        value_name = "VALUE",
+        default_value = "128"
+    )]
+    pub max_handshakes: usize,
+    #[clap(
+        long = "no-normalize-remote-port",
+        about = "Keeps the remote port of UDP replies as is"
+    )]
+    pub no_normalize_remote_port: bool,
+    #[clap(
+        long = "verify-checksum",
+        about = "Verifies the checksums of UDP datagrams from the source"
+    )]
+    pub verify_checksum: bool,
+    #[clap(
+        long = "pickup-established",
+        about = "Picks up TCP connections established before starting"
+    )]
+    pub pickup_established: bool,
+    #[clap(
+        long = "ping-ttl",
+        about = "TTL of replies to pings to the gateway",
+        value_name = "VALUE"
+    )]
+    pub ping_ttl: Option<u8>,
+    #[clap(
+        long = "console",
+        about = "Preset of responders for network tests of game consoles",
+        value_name = "PRESET",
+        possible_values = &["xbox", "playstation"]
+    )]
+    pub console: Option<String>,
+    #[clap(
+        long = "console-responders",
+        about = "Responders for network tests of game consoles, overriding the preset",
+        value_name = "RESPONDERS"
+    )]
+    pub console_responders: Option<String>,
+    #[clap(
+        long = "http-probe-port",
+        about = "Port of the gateway answering HTTP probes",
+        value_name = "PORT",
+        default_value = "80"
+    )]
+    pub http_probe_port: u16,
+    #[clap(
+        long = "http-probe-file",
+        about = "File of the canned response to HTTP probes",
+        value_name = "FILE"
+    )]
+    pub http_probe_file: Option<String>,
+    #[clap(
+        long = "dhcp-vendor",
+        about = "Vendor-specific information answering a DHCP vendor class",
+        value_name = "CLASS=HEX",
+        number_of_values = 1
+    )]
+    pub dhcp_vendor: Vec<String>,
+    #[clap(
+        long = "gateway-dns",
+        about = "Resolver of DNS queries to the gateway",
+        value_name = "ADDRESS"
+    )]
+    pub gateway_dns: Option<String>,
+    #[clap(
+        long = "gateway-status",
+        about = "Port of the gateway serving the status page",
+        value_name = "PORT"
+    )]
+    pub gateway_status: Option<u16>,
+    #[clap(
+        long = "gateway-upnp",
+        about = "Port of the gateway serving UPnP port mappings",
+        value_name = "PORT"
+    )]
+    pub gateway_upnp: Option<u16>,
+    #[clap(
+        long = "arp-rate",
+        about = "Max number of ARP replies to a requester in a second",
+        value_name = "VALUE",
+        default_value = "4"
+    )]
+    pub arp_rate: usize,
+    #[clap(
+        long = "host-max-flows",
+        about = "Max number of flows of a source",
+        value_name = "VALUE",
+        default_value = "512"
+    )]
+    pub host_max_flows: usize,
+    #[clap(
+        long = "host-connect-rate",
+        about = "Max number of new flows of a source in a second",
+        value_name = "VALUE",
+        default_value = "200"
+    )]
+    pub host_connect_rate: usize,
+    #[clap(
+        long = "host-ingress-rate",
+        about = "Max number of packets of a source processed in a second",
+        value_name = "VALUE",
+        default_value = "50000"
+    )]
+    pub host_ingress_rate: usize,
+    #[clap(
+        long = "udp-max-destinations",
+        about = "Max number of new UDP destinations of a source in 10 seconds",
+        value_name = "VALUE",
+        default_value = "512"
+    )]
+    pub udp_max_destinations: usize,
+    #[clap(
+        long = "udp-max-destinations-per-port",
+        about = "Max number of new UDP destinations of a source port in 10 seconds",
+        value_name = "VALUE",
+        default_value = "128"
+    )]
+    pub udp_max_destinations_per_port: usize,
+    #[clap(
+        long = "udp-allow-port",
+        about = "UDP destination ports never limited by destinations",
+        value_name = "PORT[-PORT]",
+        number_of_values = 1
+    )]
+    pub udp_allow_port: Vec<String>,
+    #[clap(
+        long = "udp-discovery-policy",
+        about = "Handling of an identical UDP payload to too many destinations",
+        value_name = "POLICY",
+        default_value = "limit",
+        possible_values = &["off", "limit", "drop", "answer"]
+    )]
+    pub udp_discovery_policy: String,
+    #[clap(
+        long = "udp-discovery-limit",
+        about = "Max number of destinations of an identical UDP payload in 10 seconds",
+        value_name = "VALUE",
+        default_value = "64"
+    )]
+    pub udp_discovery_limit: usize,
+    #[clap(
+        long = "dns-cache",
+        about = "Caches DNS answers of the max number and coalesces identical queries",
+        value_name = "ENTRIES"
+    )]
+    pub dns_cache: Option<usize>,
+    #[clap(
+        long = "tcp-handshake-timeout",
+        about = "Time the source has to complete a TCP handshake",
+        value_name = "SECONDS",
+        default_value = "10"
+    )]
+    pub tcp_handshake_timeout: u64,
+    #[clap(
+        long = "tcp-idle-timeout",
+        about = "Time without data after which a TCP connection is closed, 0 for never",
+        value_name = "SECONDS",
+        default_value = "7200"
+    )]
+    pub tcp_idle_timeout: u64,
+    #[clap(
+        long = "tcp-send-buffer",
+        about = "Max size of data a TCP connection queues for sending to the proxy",
+        value_name = "BYTES",
+        default_value = "262144"
+    )]
+    pub tcp_send_buffer: usize,
+    #[clap(
+        long = "udp-idle-timeout",
+        about = "Time without datagrams after which a UDP association is closed, 0 for never",
+        value_name = "SECONDS",
+        default_value = "300"
+    )]
+    pub udp_idle_timeout: u64,
+    #[clap(
+        long = "tcp-prewarm",
+        about = "Number of pre-warmed connections to the proxy kept for new TCP connections",
+        value_name = "VALUE",
+        default_value = "0"
+    )]
+    pub tcp_prewarm: usize,
+    #[clap(
+        long = "udp-quarantine",
+        about = "Time a killed UDP association is not bound again",
+        value_name = "SECONDS",
+        default_value = "30"
+    )]
+    pub udp_quarantine: u64,
+    #[clap(
+        long = "tcp-max-half-open",
+        about = "Max number of half-open TCP connections of a source",
+        value_name = "VALUE",
+        default_value = "64"
+    )]
+    pub tcp_max_half_open: usize,
+    #[clap(
+        long = "tcp-half-open-overflow",
+        about = "Handling of TCP SYN beyond the max number of half-open connections",
+        value_name = "POLICY",
+        default_value = "reset",
+        possible_values = &["drop", "reset", "cookie"]
+    )]
+    pub tcp_half_open_overflow: String,
+    #[clap(
+        long = "tcp-min-mss",
+        about = "Min MSS of TCP connections of the source",
+        value_name = "VALUE",
+        default_value = "536"
+    )]
+    pub tcp_min_mss: u16,
+    #[clap(
+        long = "resume-strategy",
+        about = "Handling of established TCP connections through a proxy which goes down",
+        value_name = "STRATEGY",
+        default_value = "freeze",
+        possible_values = &["freeze", "fin", "rst"]
+    )]
+    pub resume_strategy: String,
+    #[clap(
+        long = "on-flow-open",
+        about = "Command run when a flow opens",
+        value_name = "COMMAND"
+    )]
+    pub on_flow_open: Option<String>,
+    #[clap(
+        long = "on-flow-close",
+        about = "Command run when a flow closes",
+        value_name = "COMMAND"
+    )]
+    pub on_flow_close: Option<String>,
+    #[clap(
+        long = "hook-filter",
+        about = "Network of destinations running commands",
+        value_name = "NETWORK",
+        number_of_values = 1
+    )]
+    pub hook_filter: Vec<String>,
+    #[clap(
+        long = "hook-rule",
+        about = "Rule whose flows run commands",
+        value_name = "NAME",
+        number_of_values = 1
+    )]
+    pub hook_rule: Vec<String>,
+    #[clap(
+        long = "record-upstream",
+        about = "Directory recording the data exchanged with destinations",
+        value_name = "DIR"
+    )]
+    pub record_upstream: Option<String>,
+    #[clap(
+        long = "record-filter",
+        about = "Network of destinations recorded",
+        value_name = "NETWORK",
+        number_of_values = 1
+    )]
+    pub record_filter: Vec<String>,
+    #[clap(
+        long = "record-max-size",
+        about = "Max size of a record",
+        value_name = "KB",
+        default_value = "16384"
+    )]
+    pub record_max_size: usize,
+    #[clap(
+        long,
+        about = "Recording of destinations of flows",
+        value_name = "MODE",
+        default_value = "full",
+        possible_values = &["full", "truncated", "hashed", "omitted"]
+    )]
+    pub privacy: String,
+    #[clap(
+        long,
+        about = "Max age of persisted records in days",
+        value_name = "DAYS"
+    )]
+    pub retention: Option<u64>,
+    #[clap(long, about = "Raises desktop notifications on critical state changes")]
+    pub notify: bool,
+    #[clap(
+        long = "notify-event",
+        about = "Conditions raising notifications",
+        value_name = "CONDITION",
+        number_of_values = 1,
+        possible_values = &["upstreams", "udp", "interface", "quota"]
+    )]
+    pub notify_event: Vec<String>,
+    #[clap(
+        long = "notify-hold",
+        about = "Min time between 2 notifications of a condition [default: 5]",
+        value_name = "MINUTES"
+    )]
+    pub notify_hold: Option<u64>,
+    #[clap(
+        long = "notify-quota",
+        about = "Traffic notifying once exceeded",
+        value_name = "BYTES"
+    )]
+    pub notify_quota: Option<u64>,
+    #[clap(
+        long = "notify-file",
+        about = "Config file of notifications",
+        value_name = "FILE"
+    )]
+    pub notify_file: Option<String>,
+    #[clap(
+        long = "health-listen",
+        about = "Address answering HTTP health probes",
+        value_name = "ADDRESS"
+    )]
+    pub health_listen: Option<String>,
+    #[clap(
+        long = "standby-peer",
+        about = "Address of the peer in the warm standby",
+        value_name = "ADDRESS"
+    )]
+    pub standby_peer: Option<String>,
+    #[clap(
+        long = "standby-listen",
+        about = "Address receiving heartbeats of the peer",
+        value_name = "ADDRESS"
+    )]
+    pub standby_listen: Option<String>,
+    #[clap(
+        long = "standby-priority",
+        about = "Priority in the warm standby",
+        value_name = "VALUE",
+        default_value = "0"
+    )]
+    pub standby_priority: u32,
+    #[clap(
+        long = "handoff",
+        about = "Hands off to a new process started on SIGUSR2 without dropping UDP sessions"
+    )]
+    pub handoff: bool,
+    #[clap(
+        long = "handoff-drain",
+        about = "Time the old process drains TCP connections after a handoff",
+        value_name = "SECONDS",
+        default_value = "60"
+    )]
+    pub handoff_drain: u64,
+    #[clap(
+        long,
+        alias = "socks-user",
+        about = "Username of destinations",
+        value_name = "USERNAME"
+    )]
+    pub username: Option<String>,
+    #[clap(
+        long,
+        alias = "socks-pass",
+        about = "Password of destinations",
+        value_name = "PASSWORD"
+    )]
+    pub password: Option<String>,
+    #[clap(
+        long = "password-file",
+        about = "File containing the password of destinations",
+        value_name = "FILE"
+    )]
+    pub password_file: Option<String>,
+    #[clap(long = "ask-pass", about = "Asks for the password of destinations")]
+    pub ask_pass: bool,
+    #[clap(
+        long,
+        about = "Observes traffic without redirecting for minutes",
+        value_name = "MINUTES"
     )]
-    pub dst: String,
+    pub observe: Option<u64>,
+    #[clap(
+        long = "observe-report",
+        about = "File writing the observation report in JSON",
+        value_name = "FILE"
+    )]
+    pub observe_report: Option<String>,
+    #[clap(
+        long,
+        about = "Additional interface capturing another source",
+        value_name = "INTERFACE,SOURCE[,ADDRESS]",
+        number_of_values = 1
+    )]
+    pub capture: Vec<String>,
+    #[clap(
+        long,
+        about = "Time window proxying new connections",
+        value_name = "HH:MM-HH:MM[@DAYS]",
+        number_of_values = 1
+    )]
+    pub schedule: Vec<String>,
+    #[clap(
+        long,
+        about = "Routing rule of new connections to some destinations in time windows",
+        value_name = "RULE",
+        number_of_values = 1
+    )]
+    pub rule: Vec<String>,
+    #[clap(
+        long = "soak-report",
+        about = "Logs snapshots of resources every interval for finding leaks",
+        value_name = "SECONDS"
+    )]
+    pub soak_report: Option<u64>,
+    #[clap(
+        long = "summary-interval",
+        about = "Logs a summary of the traffic relayed every interval",
+        value_name = "SECONDS"
+    )]
+    pub summary_interval: Option<u64>,
+    #[clap(
+        long = "log-drops",
+        about = "Logs one in every N packets dropped before flows",
+        value_name = "1/N"
+    )]
+    pub log_drops: Option<String>,
+    #[clap(
+        long = "path-mtu-probe",
+        about = "Probes the path MTU to proxies every interval",
+        value_name = "SECONDS"
+    )]
+    pub path_mtu_probe: Option<u64>,
+    #[clap(long, about = "Kills connections randomly for testing", hidden = true)]
+    pub chaos: bool,
+    #[clap(
+        long,
+        about = "Seed of random decisions for reproducible runs",
+        value_name = "VALUE"
+    )]
+    pub seed: Option<u64>,
+    #[clap(
+        long = "insecure-isn",
+        about = "Derives initial TCP sequences from the seed, which makes them predictable",
+        requires = "seed"
+    )]
+    pub insecure_isn: bool,
 }
 
 /// Parses the arguments.
@@ -44,20 +586,150 @@ pub fn parse() -> Flags {
     Flags::parse()
 }
 
+/// Returns if the arguments ask for the version with verbose information, which is handled
+/// before parsing because the version flag exits in parsing.
+pub fn is_version_verbose() -> bool {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    args.iter().any(|arg| arg == "--version")
+        && args.iter().any(|arg| arg == "--verbose" || arg == "-v")
+}
+
+/// Get the path of the region to read and if it is printed in binary frames if the arguments are
+/// `stats --shm <FILE> [--binary]`, which is handled before parsing because the other arguments are
+/// not required for reading.
+pub fn get_stats_shm() -> Option<(String, bool)> {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    match args.as_slice() {
+        [command, flag, path] if command == "stats" && flag == "--shm" => {
+            Some((path.clone(), false))
+        }
+        [command, flag, path, binary]
+            if command == "stats" && flag == "--shm" && binary == "--binary" =>
+        {
+            Some((path.clone(), true))
+        }
+        _ => None,
+    }
+}
+
+/// Get the path of the record, the address to listen and if the timing is compressed if the
+/// arguments are `replay --upstream <FILE> --listen <ADDRESS> [--compressed]`, which is handled
+/// before parsing like `get_stats_shm`.
+pub fn get_replay() -> Option<(String, String, bool)> {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    match args.as_slice() {
+        [command, upstream, path, listen, addr]
+            if command == "replay" && upstream == "--upstream" && listen == "--listen" =>
+        {
+            Some((path.clone(), addr.clone(), false))
+        }
+        [command, upstream, path, listen, addr, compressed]
+            if command == "replay"
+                && upstream == "--upstream"
+                && listen == "--listen"
+                && compressed == "--compressed" =>
+        {
+            Some((path.clone(), addr.clone(), true))
+        }
+        _ => None,
+    }
+}
+
+/// Get the address of the STUN server and the destination if any if the arguments are
+/// `nat-test --stun <ADDRESS> [--destination <ADDRESS>]`, which is handled before parsing like
+/// `get_stats_shm`.
+pub fn get_nat_test() -> Option<(String, Option<String>)> {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    match args.as_slice() {
+        [command, stun, server] if command == "nat-test" && stun == "--stun" => {
+            Some((server.clone(), None))
+        }
+        [command, stun, server, destination, dst]
+            if command == "nat-test" && stun == "--stun" && destination == "--destination" =>
+        {
+            Some((server.clone(), Some(dst.clone())))
+        }
+        _ => None,
+    }
+}
+
+/// Represents the source, the interface, the publishing address and the duration of `check`.
+pub type CheckArgs = (String, Option<String>, Option<String>, Option<String>);
+
+/// Get the source, the interface, the publishing address and the duration if any if the arguments are
+/// `check --source <ADDRESS> [--interface <INTERFACE>] [--publish <ADDRESS>] [--duration
+/// <SECONDS>]` in any order, which is handled before parsing like `get_stats_shm`.
+pub fn get_check() -> Option<CheckArgs> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(|arg| arg.as_str()) != Some("check") || args.len() % 2 != 1 {
+        return None;
+    }
+
+    let (mut src, mut inter, mut publish, mut duration) = (None, None, None, None);
+    for pair in args[1..].chunks(2) {
+        let value = Some(pair[1].clone());
+        match pair[0].as_str() {
+            "--source" | "-s" => src = value,
+            "--interface" | "-i" => inter = value,
+            "--publish" | "-p" => publish = value,
+            "--duration" => duration = value,
+            _ => return None,
+        }
+    }
+
+    Some((src?, inter, publish, duration))
+}
+
+/// Get the username and the password of proxies from the environment variables, for the commands
+/// handled before parsing.
+pub fn get_env_credentials() -> Option<(String, Secret)> {
+    let username = env::var(ENV_USERNAME).ok()?;
+    let password = env::var(ENV_PASSWORD).unwrap_or_default();
+
+    Some((username, Secret::new(password)))
+}
+
 /// Represents an error when parse arguments.
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum ParseError {
     AddrParseError(AddrParseError),
+    NetworkParseError(IpNetworkError),
     OutOfRangeError(&'static str, &'static str),
+    MissingError(&'static str),
+    ConflictError(&'static str, String),
+    InvalidError(&'static str, String),
+    PrivacyError(&'static str),
+    UnsupportedError(&'static str),
 }
 
 impl Display for ParseError {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         match &self {
             ParseError::AddrParseError(ref e) => write!(f, "parse: {}", e),
+            ParseError::NetworkParseError(ref e) => write!(f, "parse: {}", e),
             ParseError::OutOfRangeError(ref value, ref range) => {
                 write!(f, "parse: {} is out of range {}", value, range)
             }
+            ParseError::MissingError(ref value) => write!(f, "parse: {} is missing", value),
+            ParseError::ConflictError(ref name, ref value) => {
+                write!(f, "parse: {} {} is used by multiple captures", name, value)
+            }
+            ParseError::InvalidError(ref name, ref value) => {
+                write!(f, "parse: {} {} is invalid", name, value)
+            }
+            ParseError::PrivacyError(ref name) => write!(
+                f,
+                "parse: {} records destinations, which --privacy omitted forbids",
+                name
+            ),
+            ParseError::UnsupportedError(ref name) => {
+                write!(f, "parse: {} is not supported on this platform", name)
+            }
         }
     }
 }
@@ -66,7 +738,13 @@ impl Error for ParseError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match &self {
             ParseError::AddrParseError(ref e) => Some(e),
+            ParseError::NetworkParseError(ref e) => Some(e),
             ParseError::OutOfRangeError(_, _) => None,
+            ParseError::MissingError(_) => None,
+            ParseError::ConflictError(_, _) => None,
+            ParseError::InvalidError(_, _) => None,
+            ParseError::PrivacyError(_) => None,
+            ParseError::UnsupportedError(_) => None,
         }
     }
 }
@@ -77,59 +755,729 @@ impl From<AddrParseError> for ParseError {
     }
 }
 
+impl From<IpNetworkError> for ParseError {
+    fn from(s: IpNetworkError) -> Self {
+        ParseError::NetworkParseError(s)
+    }
+}
+
 type Result = result::Result<Opts, ParseError>;
 
+/// Parses the bytes in hexadecimal, like `0104c0a80001`.
+fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
 /// Represents the initial UDP port for binding in local. This will become a option in the future release.
 const INITIAL_PORT: u16 = 32768;
 
+/// Represents an interface capturing a source, with its ARP publishing address.
+#[derive(Clone, Debug)]
+pub struct Capture {
+    pub inter: Option<String>,
+    pub src: Ipv4Addr,
+    pub publish: Option<Ipv4Addr>,
+}
+
+impl Capture {
+    /// Creates a `Capture` according to the given string in `INTERFACE,SOURCE[,ADDRESS]`.
+    fn parse(s: &str) -> result::Result<Capture, ParseError> {
+        let mut parts = s.split(',');
+        let inter = match parts.next() {
+            Some(inter) if !inter.is_empty() => String::from(inter),
+            _ => return Err(ParseError::MissingError("capture interface")),
+        };
+        let src = match parts.next() {
+            Some(src) => src.parse()?,
+            None => return Err(ParseError::MissingError("capture source")),
+        };
+        let publish = match parts.next() {
+            Some(publish) => Some(publish.parse()?),
+            None => None,
+        };
+
+        Ok(Capture {
+            inter: Some(inter),
+            src,
+            publish,
+        })
+    }
+}
+
 /// Represents the options of the application.
 pub struct Opts {
-    pub verbose: bool,
-    pub vverbose: bool,
     pub initial: u16,
     pub inter: Option<String>,
     pub mtu: u16,
+    pub max_threads: usize,
     pub publish: Option<Ipv4Addr>,
     pub src: Ipv4Addr,
-    pub dst: SocketAddrV4,
+    pub dst: Vec<SocketAddr>,
+    pub proxy_type: ProxyType,
+    pub balance: Policy,
+    pub latency_file: Option<String>,
+    pub state_file: Option<String>,
+    pub stats_file: Option<String>,
+    pub fallback: Fallback,
+    pub gateway_hardware_addr: Option<HardwareAddr>,
+    pub backoff_cap: u64,
+    pub max_handshakes: usize,
+    pub normalize_remote_port: bool,
+    pub verify_checksum: bool,
+    pub pickup_established: bool,
+    pub ping_ttl: Option<u8>,
+    pub console_responders: Vec<Responder>,
+    pub http_probe_port: u16,
+    pub http_probe_response: Vec<u8>,
+    pub dhcp_vendors: Vec<(String, Vec<u8>)>,
+    pub arp_rate: usize,
+    pub host_max_flows: usize,
+    pub host_connect_rate: usize,
+    pub host_ingress_rate: usize,
+    pub udp_max_destinations: usize,
+    pub udp_max_destinations_per_port: usize,
+    pub udp_allowed_ports: Vec<(u16, u16)>,
+    pub udp_discovery_policy: DiscoveryPolicy,
+    pub udp_discovery_limit: usize,
+    pub dns_cache: Option<usize>,
+    pub gateway_dns: Option<SocketAddrV4>,
+    pub gateway_status: Option<u16>,
+    pub gateway_upnp: Option<u16>,
+    pub tcp_handshake_timeout: u64,
+    pub tcp_idle_timeout: u64,
+    pub tcp_send_buffer: usize,
+    pub udp_idle_timeout: u64,
+    pub tcp_prewarm: usize,
+    pub udp_quarantine: u64,
+    pub tcp_max_half_open: usize,
+    pub tcp_half_open_overflow: HalfOpenOverflow,
+    pub tcp_min_mss: u16,
+    pub resume_strategy: ResumeStrategy,
+    pub on_flow_open: Option<String>,
+    pub on_flow_close: Option<String>,
+    pub hook_filters: Vec<Ipv4Network>,
+    pub hook_rules: Vec<String>,
+    pub record_upstream: Option<String>,
+    pub record_filters: Vec<Ipv4Network>,
+    pub record_max_size: usize,
+    pub privacy: Privacy,
+    pub retention: Option<u64>,
+    pub notify: bool,
+    pub notify_conditions: Vec<Condition>,
+    pub notify_hold: u64,
+    pub notify_quota: Option<u64>,
+    pub health_listen: Option<SocketAddr>,
+    /// Represents the address receiving heartbeats and the address of the peer.
+    pub standby: Option<(SocketAddr, SocketAddr)>,
+    pub standby_priority: u32,
+    pub handoff: bool,
+    pub handoff_drain: u64,
+    pub username: Option<String>,
+    pub password: Option<Secret>,
+    /// Represents if the password is given in the command line.
+    pub is_password_in_args: bool,
+    /// Represents the password given in the environment variable.
+    pub env_password: Option<Secret>,
+    pub password_file: Option<String>,
+    pub ask_pass: bool,
+    pub observe: Option<u64>,
+    pub observe_report: Option<String>,
+    /// Represents the additional captures.
+    pub captures: Vec<Capture>,
+    pub schedule: Option<Schedule>,
+    pub rules: Rules,
+    pub soak_report: Option<u64>,
+    pub summary_interval: Option<u64>,
+    pub log_drops: Option<usize>,
+    pub path_mtu_probe: Option<u64>,
+    pub chaos: bool,
+    pub seed: Option<u64>,
+    pub insecure_isn: bool,
 }
 
 impl Opts {
     /// Creates a new empty `Opts`.
     pub fn new() -> Opts {
         Opts {
-            verbose: false,
-            vverbose: false,
             initial: 32768,
             inter: None,
             mtu: 1400,
+            max_threads: 512,
             publish: None,
             src: Ipv4Addr::UNSPECIFIED,
-            dst: SocketAddrV4::new("127.0.0.1".parse().unwrap(), 1080),
+            dst: vec![SocketAddr::V4(SocketAddrV4::new(
+                "127.0.0.1".parse().unwrap(),
+                1080,
+            ))],
+            proxy_type: ProxyType::Socks5,
+            balance: Policy::First,
+            latency_file: None,
+            state_file: None,
+            stats_file: None,
+            fallback: Fallback::Fail,
+            gateway_hardware_addr: None,
+            backoff_cap: pcap2socks_core::backoff::DEFAULT_CAP,
+            max_handshakes: pcap2socks_core::backoff::DEFAULT_MAX_HANDSHAKES,
+            normalize_remote_port: true,
+            verify_checksum: false,
+            pickup_established: false,
+            ping_ttl: None,
+            console_responders: Vec::new(),
+            http_probe_port: probe::DEFAULT_PORT,
+            http_probe_response: probe::DEFAULT_RESPONSE.as_bytes().to_vec(),
+            dhcp_vendors: Vec::new(),
+            arp_rate: pcap2socks_core::DEFAULT_ARP_RATE,
+            host_max_flows: pcap2socks_core::envelope::DEFAULT_MAX_FLOWS,
+            host_connect_rate: pcap2socks_core::envelope::DEFAULT_CONNECT_RATE,
+            host_ingress_rate: pcap2socks_core::envelope::DEFAULT_INGRESS_RATE,
+            udp_max_destinations: pcap2socks_core::fanout::DEFAULT_MAX_DESTINATIONS,
+            udp_max_destinations_per_port:
+                pcap2socks_core::fanout::DEFAULT_MAX_DESTINATIONS_PER_PORT,
+            udp_allowed_ports: Vec::new(),
+            udp_discovery_policy: DiscoveryPolicy::Limit,
+            udp_discovery_limit: pcap2socks_core::discovery::DEFAULT_LIMIT,
+            dns_cache: None,
+            gateway_dns: None,
+            gateway_status: None,
+            gateway_upnp: None,
+            tcp_handshake_timeout: pcap2socks_core::DEFAULT_TCP_HANDSHAKE_TIMEOUT,
+            tcp_idle_timeout: pcap2socks_core::DEFAULT_TCP_IDLE_TIMEOUT,
+            tcp_send_buffer: pcap2socks_core::DEFAULT_TCP_SEND_BUFFER,
+            udp_idle_timeout: pcap2socks_core::DEFAULT_UDP_IDLE_TIMEOUT,
+            tcp_prewarm: 0,
+            udp_quarantine: pcap2socks_core::DEFAULT_UDP_QUARANTINE,
+            tcp_max_half_open: pcap2socks_core::DEFAULT_TCP_MAX_HALF_OPEN,
+            tcp_half_open_overflow: HalfOpenOverflow::Reset,
+            tcp_min_mss: pcap2socks_core::DEFAULT_TCP_MIN_MSS,
+            resume_strategy: ResumeStrategy::Freeze,
+            on_flow_open: None,
+            on_flow_close: None,
+            hook_filters: Vec::new(),
+            hook_rules: Vec::new(),
+            record_upstream: None,
+            record_filters: Vec::new(),
+            record_max_size: pcap2socks_core::record::DEFAULT_RECORD_MAX_SIZE,
+            privacy: Privacy::Full,
+            retention: None,
+            notify: false,
+            notify_conditions: Vec::new(),
+            notify_hold: pcap2socks_core::notify::DEFAULT_NOTIFY_HOLD,
+            notify_quota: None,
+            health_listen: None,
+            standby: None,
+            standby_priority: 0,
+            handoff: false,
+            handoff_drain: pcap2socks_core::handoff::DEFAULT_DRAIN,
+            username: None,
+            password: None,
+            is_password_in_args: false,
+            env_password: None,
+            password_file: None,
+            ask_pass: false,
+            observe: None,
+            observe_report: None,
+            captures: Vec::new(),
+            schedule: None,
+            rules: Rules::default(),
+            soak_report: None,
+            summary_interval: None,
+            log_drops: None,
+            path_mtu_probe: None,
+            chaos: false,
+            seed: None,
+            insecure_isn: false,
         }
     }
 
     /// Validates flags and creates a new `Opts`.
-    pub fn validate(flags: &Flags) -> Result {
+    pub fn validate(flags: &mut Flags) -> Result {
         if flags.mtu < 576 {
             return Err(ParseError::OutOfRangeError("MTU", "[576, 65535]"));
         }
+        if flags.max_threads < 1 {
+            return Err(ParseError::OutOfRangeError("max threads", "[1, +∞)"));
+        }
+        if flags.arp_rate < 1 {
+            return Err(ParseError::OutOfRangeError("ARP rate", "[1, +∞)"));
+        }
+        if flags.host_max_flows < 1 {
+            return Err(ParseError::OutOfRangeError("host max flows", "[1, +∞)"));
+        }
+        if flags.host_connect_rate < 1 {
+            return Err(ParseError::OutOfRangeError("host connect rate", "[1, +∞)"));
+        }
+        if flags.host_ingress_rate < 1 {
+            return Err(ParseError::OutOfRangeError("host ingress rate", "[1, +∞)"));
+        }
+        if flags.tcp_handshake_timeout < 1 {
+            return Err(ParseError::OutOfRangeError(
+                "TCP handshake timeout",
+                "[1, +∞)",
+            ));
+        }
+        // The source may send a full window before the first segment limits it
+        if flags.tcp_send_buffer < 65536 {
+            return Err(ParseError::OutOfRangeError(
+                "TCP send buffer",
+                "[65536, +∞)",
+            ));
+        }
+        if flags.tcp_max_half_open < 1 {
+            return Err(ParseError::OutOfRangeError("TCP max half-open", "[1, +∞)"));
+        }
+        if flags.tcp_min_mss < pcap2socks_core::TCP_MIN_MSS_FLOOR || flags.tcp_min_mss > 1460 {
+            return Err(ParseError::OutOfRangeError("TCP min MSS", "[88, 1460]"));
+        }
+        if flags.notify_hold == Some(0) {
+            return Err(ParseError::OutOfRangeError("notify hold", "[1, +∞)"));
+        }
+        if flags.notify_quota == Some(0) {
+            return Err(ParseError::OutOfRangeError("notify quota", "[1, +∞)"));
+        }
+        if flags.udp_max_destinations < 1 {
+            return Err(ParseError::OutOfRangeError(
+                "UDP max destinations",
+                "[1, +∞)",
+            ));
+        }
+        if flags.udp_max_destinations_per_port < 1 {
+            return Err(ParseError::OutOfRangeError(
+                "UDP max destinations per port",
+                "[1, +∞)",
+            ));
+        }
+        if flags.backoff_cap < 1 {
+            return Err(ParseError::OutOfRangeError("backoff cap", "[1, +∞)"));
+        }
+        if flags.udp_discovery_limit < 1 {
+            return Err(ParseError::OutOfRangeError(
+                "UDP discovery limit",
+                "[1, +∞)",
+            ));
+        }
+        if flags.soak_report == Some(0) {
+            return Err(ParseError::OutOfRangeError("soak report", "[1, +∞)"));
+        }
+        if flags.summary_interval == Some(0) {
+            return Err(ParseError::OutOfRangeError("summary interval", "[1, +∞)"));
+        }
+        if flags.retention == Some(0) {
+            return Err(ParseError::OutOfRangeError("retention", "[1, +∞)"));
+        }
+        let privacy = Privacy::from_name(&flags.privacy).unwrap_or(Privacy::Full);
+        // Latencies are persisted by destination prefix
+        if privacy == Privacy::Omitted && flags.latency_file.is_some() {
+            return Err(ParseError::PrivacyError("--latency-file"));
+        }
+        if flags.dns_cache == Some(0) {
+            return Err(ParseError::OutOfRangeError("DNS cache", "[1, +∞)"));
+        }
+        if flags.path_mtu_probe == Some(0) {
+            return Err(ParseError::OutOfRangeError("path MTU probe", "[1, +∞)"));
+        }
+        if flags.path_mtu_probe.is_some() && !is_path_mtu_probe_supported() {
+            return Err(ParseError::UnsupportedError("--path-mtu-probe"));
+        }
         let mut publish = None;
         if let Some(p) = &flags.publish {
             publish = Some(p.parse()?);
         }
         let src = flags.src.parse()?;
-        let dst = flags.dst.parse()?;
+        let health_listen = match flags.health_listen {
+            Some(ref health_listen) => Some(health_listen.parse()?),
+            None => None,
+        };
+        // The port of the resolver is 53 by default
+        let gateway_dns = match flags.gateway_dns {
+            Some(ref gateway_dns) => {
+                if flags.publish.is_none() {
+                    return Err(ParseError::MissingError("publish"));
+                }
+                match gateway_dns.parse::<Ipv4Addr>() {
+                    Ok(ip_addr) => Some(SocketAddrV4::new(ip_addr, 53)),
+                    Err(_) => Some(gateway_dns.parse()?),
+                }
+            }
+            None => None,
+        };
+        // Services answer as the gateway
+        if (flags.gateway_status.is_some() || flags.gateway_upnp.is_some())
+            && flags.publish.is_none()
+        {
+            return Err(ParseError::MissingError("publish"));
+        }
+        let standby = match (&flags.standby_listen, &flags.standby_peer) {
+            (Some(listen), Some(peer)) => {
+                if flags.publish.is_none() {
+                    return Err(ParseError::MissingError("publish"));
+                }
+                Some((listen.parse()?, peer.parse()?))
+            }
+            (Some(_), None) => return Err(ParseError::MissingError("standby peer")),
+            (None, Some(_)) => return Err(ParseError::MissingError("standby listen")),
+            (None, None) => None,
+        };
+        // The new process takes the gateway the standby may hold
+        if flags.handoff && standby.is_some() {
+            return Err(ParseError::InvalidError(
+                "handoff",
+                String::from("with the warm standby"),
+            ));
+        }
+        let mut dst = Vec::new();
+        for d in &flags.dst {
+            dst.push(d.parse()?);
+        }
+        let proxy_type = match flags.proxy_type.as_str() {
+            "http" => ProxyType::Http,
+            _ => ProxyType::Socks5,
+        };
+        let balance = match flags.balance.as_str() {
+            "latency-aware" => Policy::LatencyAware,
+            _ => Policy::First,
+        };
+        let fallback = Fallback::from_name(flags.fallback.as_str()).unwrap_or(Fallback::Fail);
+        let mut windows = Vec::new();
+        for window in &flags.schedule {
+            windows.push(
+                Window::parse(window)
+                    .ok_or_else(|| ParseError::InvalidError("schedule", window.clone()))?,
+            );
+        }
+        let schedule = if windows.is_empty() {
+            None
+        } else {
+            Some(Schedule::new(windows))
+        };
+        let mut rules = Vec::new();
+        for rule in &flags.rule {
+            let rule =
+                Rule::parse(rule).ok_or_else(|| ParseError::InvalidError("rule", rule.clone()))?;
+            if rules.iter().any(|r: &Rule| r.get_name() == rule.get_name()) {
+                return Err(ParseError::InvalidError(
+                    "rule",
+                    String::from(rule.get_name()),
+                ));
+            }
+            // Latencies of destinations of the rule would be persisted
+            if rule.get_privacy() == Some(Privacy::Omitted) && flags.latency_file.is_some() {
+                return Err(ParseError::PrivacyError("--latency-file"));
+            }
+            rules.push(rule);
+        }
+        // The gateway to bridge to must be known
+        let gateway_hardware_addr = match flags.gateway_hardware_addr {
+            Some(ref hardware_addr) => {
+                Some(hardware_addr.parse::<HardwareAddr>().map_err(|_| {
+                    ParseError::InvalidError("gateway hardware address", hardware_addr.clone())
+                })?)
+            }
+            None => None,
+        };
+        if gateway_hardware_addr.is_none()
+            && (fallback == Fallback::Gateway
+                || rules
+                    .iter()
+                    .any(|rule| rule.get_fallback() == Some(Fallback::Gateway)))
+        {
+            return Err(ParseError::MissingError("gateway hardware address"));
+        }
+        let rules = Rules::new(rules);
+        let tcp_half_open_overflow = match flags.tcp_half_open_overflow.as_str() {
+            "drop" => HalfOpenOverflow::Drop,
+            "cookie" => HalfOpenOverflow::Cookie,
+            _ => HalfOpenOverflow::Reset,
+        };
+        let udp_discovery_policy = match flags.udp_discovery_policy.as_str() {
+            "off" => DiscoveryPolicy::Off,
+            "drop" => DiscoveryPolicy::Drop,
+            "answer" => DiscoveryPolicy::Answer,
+            _ => DiscoveryPolicy::Limit,
+        };
+        let resume_strategy =
+            ResumeStrategy::from_name(&flags.resume_strategy).unwrap_or(ResumeStrategy::Freeze);
+        let log_drops = match flags.log_drops {
+            Some(ref log_drops) => {
+                let sample = log_drops.strip_prefix("1/").unwrap_or(log_drops);
+                match sample.trim().parse::<usize>() {
+                    Ok(sample) if sample >= 1 => Some(sample),
+                    _ => return Err(ParseError::InvalidError("log drops", log_drops.clone())),
+                }
+            }
+            None => None,
+        };
+        let mut console_responders = match flags.console {
+            Some(ref preset) => Preset::from_name(preset)
+                .ok_or_else(|| ParseError::InvalidError("console", preset.clone()))?
+                .get_responders(),
+            None => Vec::new(),
+        };
+        if let Some(ref responders) = flags.console_responders {
+            console_responders.clear();
+            for name in responders
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+            {
+                let responder = Responder::from_name(name).ok_or_else(|| {
+                    ParseError::InvalidError("console responder", String::from(name))
+                })?;
+                if !console_responders.contains(&responder) {
+                    console_responders.push(responder);
+                }
+            }
+        }
+        // An explicit TTL wins over the one of the preset
+        let ping_ttl = match flags.ping_ttl {
+            Some(ttl) => Some(ttl),
+            None if console_responders.contains(&Responder::Ping) => Some(DEFAULT_PING_TTL),
+            None => None,
+        };
+        let http_probe_response = match flags.http_probe_file {
+            Some(ref file) => {
+                let response = fs::read(file)
+                    .map_err(|_| ParseError::InvalidError("HTTP probe file", file.clone()))?;
+                if response.len() > probe::MAX_RESPONSE_SIZE {
+                    return Err(ParseError::OutOfRangeError(
+                        "HTTP probe file",
+                        "[0, 16384] Bytes",
+                    ));
+                }
+                response
+            }
+            None => probe::DEFAULT_RESPONSE.as_bytes().to_vec(),
+        };
+        let mut dhcp_vendors = Vec::new();
+        for vendor in &flags.dhcp_vendor {
+            let (class, information) = match vendor.find('=') {
+                Some(i) => (&vendor[..i], &vendor[i + 1..]),
+                None => return Err(ParseError::InvalidError("DHCP vendor", vendor.clone())),
+            };
+            let information = parse_hex(information)
+                .filter(|information| !class.is_empty() && !information.is_empty())
+                .ok_or_else(|| ParseError::InvalidError("DHCP vendor", vendor.clone()))?;
+            dhcp_vendors.push((String::from(class), information));
+        }
+
+        let udp_servers = rules.get_server_ports();
+        // Leave most local ports to the other associations
+        if udp_servers.len() > pcap2socks_core::PORT_COUNT / 2 {
+            return Err(ParseError::OutOfRangeError("UDP servers", "[0, 32]"));
+        }
+        // UDP servers are bound on startup, while HTTP proxies relay no UDP
+        if proxy_type == ProxyType::Http && !udp_servers.is_empty() {
+            return Err(ParseError::InvalidError(
+                "UDP server",
+                String::from("with proxy type http"),
+            ));
+        }
+        let mut udp_allowed_ports = Vec::new();
+        for range in &flags.udp_allow_port {
+            let (first, last) = match range.find('-') {
+                Some(i) => (&range[..i], &range[i + 1..]),
+                None => (range.as_str(), range.as_str()),
+            };
+            match (first.trim().parse::<u16>(), last.trim().parse::<u16>()) {
+                (Ok(first), Ok(last)) if first >= 1 && first <= last => {
+                    udp_allowed_ports.push((first, last))
+                }
+                _ => return Err(ParseError::InvalidError("UDP allow port", range.clone())),
+            }
+        }
+        // Matchmaking of the preset pings server lists, which is never discovery
+        if let Some(preset) = flags.console.as_ref().and_then(|p| Preset::from_name(p)) {
+            udp_allowed_ports.extend(preset.get_allowed_ports());
+        }
+        let notify_conditions: Vec<Condition> = flags
+            .notify_event
+            .iter()
+            .filter_map(|condition| Condition::from_name(condition))
+            .collect();
+        // Flags take precedence over the config file
+        let notify_config = match flags.notify_file {
+            Some(ref file) => {
+                let s = fs::read_to_string(file)
+                    .map_err(|_| ParseError::InvalidError("notify file", file.clone()))?;
+                NotifyConfig::parse(&s)
+                    .map_err(|line| ParseError::InvalidError("notify file line", line))?
+            }
+            None => NotifyConfig::default(),
+        };
+        let notify_conditions = notify_config.get_conditions(&notify_conditions);
+        if notify_conditions.is_empty() {
+            return Err(ParseError::MissingError("notify condition"));
+        }
+        let notify_hold = flags
+            .notify_hold
+            .or(notify_config.hold)
+            .unwrap_or(pcap2socks_core::notify::DEFAULT_NOTIFY_HOLD);
+        let notify_quota = flags.notify_quota.or(notify_config.quota_size);
+        let mut hook_filters = Vec::new();
+        for filter in &flags.hook_filter {
+            hook_filters.push(filter.parse()?);
+        }
+        for name in &flags.hook_rule {
+            if rules.position(name).is_none() {
+                return Err(ParseError::InvalidError("hook rule", name.clone()));
+            }
+        }
+        if flags.record_max_size < 1 {
+            return Err(ParseError::OutOfRangeError("record max size", "[1, +∞)"));
+        }
+        let mut record_filters = Vec::new();
+        for filter in &flags.record_filter {
+            record_filters.push(filter.parse()?);
+        }
+        // Flags take precedence over environment variables
+        let username = match flags.username {
+            Some(ref username) => Some(username.clone()),
+            None => env::var(ENV_USERNAME).ok(),
+        };
+        // The password is moved out of the flags, so it is zeroed once dropped
+        let is_password_in_args = flags.password.is_some();
+        let password = flags.password.take().map(Secret::new);
+        let env_password = env::var(ENV_PASSWORD).ok().map(Secret::new);
+        // Hooks never inherit the password
+        env::remove_var(ENV_PASSWORD);
+        if username.is_none()
+            && (password.is_some()
+                || env_password.is_some()
+                || flags.password_file.is_some()
+                || flags.ask_pass)
+        {
+            return Err(ParseError::MissingError("username"));
+        }
+        if let Some(observe) = flags.observe {
+            if observe < 1 {
+                return Err(ParseError::OutOfRangeError("observe", "[1, +∞)"));
+            }
+        }
+        if flags.observe_report.is_some() && flags.observe.is_none() {
+            return Err(ParseError::MissingError("observe"));
+        }
+        let mut captures = Vec::new();
+        for capture in &flags.capture {
+            captures.push(Capture::parse(capture)?);
+        }
+        // Captures must not share interfaces, sources or publishing addresses
+        let first = Capture {
+            inter: flags.inter.clone(),
+            src,
+            publish,
+        };
+        for (i, capture) in captures.iter().enumerate() {
+            for other in captures[..i].iter().chain(Some(&first)) {
+                if capture.inter.is_some() && capture.inter == other.inter {
+                    return Err(ParseError::ConflictError(
+                        "interface",
+                        capture.inter.clone().unwrap(),
+                    ));
+                }
+                if capture.src == other.src {
+                    return Err(ParseError::ConflictError("source", capture.src.to_string()));
+                }
+                if let Some(publish) = capture.publish {
+                    if capture.publish == other.publish {
+                        return Err(ParseError::ConflictError(
+                            "publishing address",
+                            publish.to_string(),
+                        ));
+                    }
+                }
+            }
+        }
 
         Ok(Opts {
-            verbose: flags.verbose,
-            vverbose: flags.vverbose,
             initial: INITIAL_PORT,
             inter: flags.inter.clone(),
             mtu: flags.mtu,
+            max_threads: flags.max_threads,
             publish,
             src,
             dst,
+            proxy_type,
+            balance,
+            latency_file: flags.latency_file.clone(),
+            state_file: flags.state_file.clone(),
+            stats_file: flags.stats_file.clone(),
+            fallback,
+            gateway_hardware_addr,
+            backoff_cap: flags.backoff_cap,
+            max_handshakes: flags.max_handshakes,
+            normalize_remote_port: !flags.no_normalize_remote_port,
+            verify_checksum: flags.verify_checksum,
+            pickup_established: flags.pickup_established,
+            ping_ttl,
+            console_responders,
+            http_probe_port: flags.http_probe_port,
+            http_probe_response,
+            dhcp_vendors,
+            arp_rate: flags.arp_rate,
+            host_max_flows: flags.host_max_flows,
+            host_connect_rate: flags.host_connect_rate,
+            host_ingress_rate: flags.host_ingress_rate,
+            udp_max_destinations: flags.udp_max_destinations,
+            udp_max_destinations_per_port: flags.udp_max_destinations_per_port,
+            udp_allowed_ports,
+            udp_discovery_policy,
+            udp_discovery_limit: flags.udp_discovery_limit,
+            dns_cache: flags.dns_cache,
+            gateway_dns,
+            gateway_status: flags.gateway_status,
+            gateway_upnp: flags.gateway_upnp,
+            tcp_handshake_timeout: flags.tcp_handshake_timeout,
+            tcp_idle_timeout: flags.tcp_idle_timeout,
+            tcp_send_buffer: flags.tcp_send_buffer,
+            udp_idle_timeout: flags.udp_idle_timeout,
+            tcp_prewarm: flags.tcp_prewarm,
+            udp_quarantine: flags.udp_quarantine,
+            tcp_max_half_open: flags.tcp_max_half_open,
+            tcp_half_open_overflow,
+            tcp_min_mss: flags.tcp_min_mss,
+            resume_strategy,
+            on_flow_open: flags.on_flow_open.clone(),
+            on_flow_close: flags.on_flow_close.clone(),
+            hook_filters,
+            hook_rules: flags.hook_rule.clone(),
+            record_upstream: flags.record_upstream.clone(),
+            record_filters,
+            record_max_size: flags.record_max_size,
+            privacy,
+            retention: flags.retention,
+            notify: flags.notify,
+            notify_conditions,
+            notify_hold,
+            notify_quota,
+            health_listen,
+            standby,
+            standby_priority: flags.standby_priority,
+            handoff: flags.handoff,
+            handoff_drain: flags.handoff_drain,
+            username,
+            password,
+            is_password_in_args,
+            env_password,
+            password_file: flags.password_file.clone(),
+            ask_pass: flags.ask_pass,
+            observe: flags.observe,
+            observe_report: flags.observe_report.clone(),
+            captures,
+            schedule,
+            rules,
+            soak_report: flags.soak_report,
+            summary_interval: flags.summary_interval,
+            log_drops,
+            path_mtu_probe: flags.path_mtu_probe,
+            chaos: flags.chaos,
+            seed: flags.seed,
+            insecure_isn: flags.insecure_isn,
         })
     }
 }
@@ -0,0 +1,777 @@
+use log::{debug, info, warn};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::args::Flags;
+use crate::info::Info;
+use crate::logger::{Directives, Logger, LOG_RING_SIZE};
+use pcap2socks_core::frame::{self, Message, MessageType, Value};
+use pcap2socks_core::monitor::{Kill, Monitor};
+use pcap2socks_core::stats::Snapshot;
+use pcap2socks_core::threads::{Purpose, Threads};
+use pcap2socks_core::{stun, Credentials};
+
+/// Represents the default number of source ports of `top-talkers`.
+const TOP_TALKERS: usize = 10;
+
+/// Represents the interval between 2 checks of the control file.
+const CONTROL_CHECK_INTERVAL: u64 = 1;
+
+/// Represents the max number of clients of the control socket served at the same time.
+const MAX_CONTROL_CLIENTS: usize = 4;
+
+/// Represents the timeout of a client of the control socket sending no command, after which it is
+/// disconnected.
+const CONTROL_READ_TIMEOUT: u64 = 60;
+
+/// Represents the interval between 2 checks of new lines of `tail -f`.
+const FOLLOW_INTERVAL: u64 = 200;
+
+/// Represents the control of the process while running, which runs the commands of the control
+/// file and the control socket. The log levels and the recent lines are changed and read in the
+/// logger, and the commands on flows are requested in the monitor once the flows are started.
+pub struct Control {
+    logger: &'static Logger,
+    monitor: Mutex<Option<Arc<Monitor>>>,
+    nat_test: Mutex<Option<(SocketAddr, Credentials)>>,
+    threads: Mutex<Option<Arc<Threads>>>,
+}
+
+impl Control {
+    /// Creates a new `Control` of the logger.
+    pub fn new(logger: &'static Logger) -> Control {
+        Control {
+            logger,
+            monitor: Mutex::new(None),
+            nat_test: Mutex::new(None),
+            threads: Mutex::new(None),
+        }
+    }
+
+    /// Sets the monitor of the `Redirector`s, which the commands on flows are requested in.
+    pub fn set_monitor(&self, monitor: Arc<Monitor>) {
+        *self.monitor.lock().unwrap() = Some(monitor);
+    }
+
+    /// Sets the destination and the credentials the NAT tests run through.
+    pub fn set_nat_test(&self, remote: SocketAddr, credentials: Credentials) {
+        *self.nat_test.lock().unwrap() = Some((remote, credentials));
+    }
+
+    /// Sets the thread registry of the process, which the commands dump.
+    pub fn set_threads(&self, threads: Arc<Threads>) {
+        *self.threads.lock().unwrap() = Some(threads);
+    }
+}
+
+/// Spawns the thread applying the commands in the control file whenever it is modified. The file
+/// has a command in each line, `set-log-level <DIRECTIVES>` sets the directives, `tail [N]`
+/// writes the last N lines in the ring to the file of the same name with the extension `.tail`,
+/// `expire-flows [RULE]` closes the flows of the rule, or of all rules, outside their windows,
+/// `kill-flow <tcp SRC_PORT DST [abort] | udp SRC_PORT>` kills a flow, and `nat-test <STUN>` tests
+/// the NAT through the destination and writes the report to the file with the extension `.nat`,
+/// `info` writes the report of `--version --verbose` in JSON to the file with the extension
+/// `.info`, `stats` writes the statistics of the first capture to the file with the extension
+/// `.stats`, `dump <threads | flows>` writes the thread registry or the top flows to the file with
+/// the extension `.dump`, and `top-talkers [N]` writes the source ports with the most new UDP destinations to the file with
+/// the extension `.talkers`. Commands run one by one, so other commands wait for a NAT test.
+pub fn watch(control: &'static Control, threads: &Arc<Threads>, path: PathBuf) -> io::Result<()> {
+    Threads::spawn(
+        threads,
+        String::from("log control"),
+        Purpose::Control,
+        move || {
+            let mut modified: Option<SystemTime> = None;
+            loop {
+                thread::sleep(Duration::from_secs(CONTROL_CHECK_INTERVAL));
+
+                let current = match fs::metadata(&path).and_then(|metadata| metadata.modified()) {
+                    Ok(current) => current,
+                    // The file is not created yet
+                    Err(_) => continue,
+                };
+                if modified == Some(current) {
+                    continue;
+                }
+                modified = Some(current);
+
+                if let Err(ref e) = apply(control, &path) {
+                    warn!("log control {}: {}", path.display(), e);
+                }
+            }
+        },
+    )?;
+
+    Ok(())
+}
+
+fn apply(control: &Control, path: &PathBuf) -> io::Result<()> {
+    let content = fs::read_to_string(path)?;
+    for line in content.lines().map(|line| line.trim()) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let command = line.split_whitespace().next().unwrap_or("");
+        let output = run(control, line)?;
+        // Outputs are written to the file of the same name with the extension of the command
+        let extension = match command {
+            "tail" => ".tail",
+            "nat-test" => ".nat",
+            "info" => ".info",
+            "top-talkers" => ".talkers",
+            "stats" => ".stats",
+            "dump" => ".dump",
+            _ => continue,
+        };
+        let mut output_path = path.clone().into_os_string();
+        output_path.push(extension);
+        fs::write(&output_path, output)?;
+    }
+
+    Ok(())
+}
+
+/// Binds the address and spawns the thread answering commands on the control socket, like the
+/// ones of the control file. A client sends a command in each line, and each command is answered
+/// with its output, and a last line of `ok` or `error: <MESSAGE>`. `tail -f [N]` writes the last N
+/// lines and then the new ones as they are logged, until the client closes the connection. The
+/// socket has no authentication, so only loopback addresses are accepted.
+pub fn listen(
+    control: &'static Control,
+    threads: &Arc<Threads>,
+    addr: SocketAddr,
+) -> io::Result<()> {
+    if !addr.ip().is_loopback() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a loopback address", addr),
+        ));
+    }
+
+    serve(control, threads, TcpListener::bind(addr)?)
+}
+
+/// Spawns the thread answering commands on the control socket like `listen` on a bound listener.
+/// Each client is served in its own thread, up to `MAX_CONTROL_CLIENTS` at the same time, and is
+/// disconnected if it sends no command in `CONTROL_READ_TIMEOUT`.
+pub fn serve(
+    control: &'static Control,
+    threads: &Arc<Threads>,
+    listener: TcpListener,
+) -> io::Result<()> {
+    let addr = listener.local_addr()?;
+    let registry = Arc::clone(threads);
+    Threads::spawn(
+        threads,
+        String::from("control"),
+        Purpose::Control,
+        move || {
+            let clients = Arc::new(AtomicUsize::new(0));
+            for stream in listener.incoming() {
+                if let Err(ref e) =
+                    stream.and_then(|stream| accept(control, &registry, &clients, stream))
+                {
+                    debug!("control: {}", e);
+                }
+            }
+        },
+    )?;
+    info!("Answer control commands on {}", addr);
+
+    Ok(())
+}
+
+/// Spawns the thread answering the commands of a client, or refuses the client if too many are
+/// served.
+fn accept(
+    control: &'static Control,
+    threads: &Arc<Threads>,
+    clients: &Arc<AtomicUsize>,
+    mut stream: TcpStream,
+) -> io::Result<()> {
+    let peer = stream.peer_addr()?;
+    if clients.fetch_add(1, Ordering::SeqCst) >= MAX_CONTROL_CLIENTS {
+        clients.fetch_sub(1, Ordering::SeqCst);
+        stream.write_all(b"error: too many clients\n")?;
+        return Err(io::Error::other(format!(
+            "refuse {}, too many clients",
+            peer
+        )));
+    }
+    stream.set_read_timeout(Some(Duration::from_secs(CONTROL_READ_TIMEOUT)))?;
+
+    let clients_cloned = Arc::clone(clients);
+    let result = Threads::spawn(
+        threads,
+        format!("control {}", peer),
+        Purpose::Control,
+        move || {
+            if let Err(ref e) = answer(control, stream) {
+                debug!("control {}: {}", peer, e);
+            }
+            clients_cloned.fetch_sub(1, Ordering::SeqCst);
+        },
+    );
+    if let Err(e) = result {
+        clients.fetch_sub(1, Ordering::SeqCst);
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+/// Answers the commands of a client until it closes the connection. A client speaking binary
+/// frames is told by its first byte and answered in frames instead of lines.
+fn answer(control: &Control, stream: TcpStream) -> io::Result<()> {
+    let mut first = [0u8; 1];
+    if stream.peek(&mut first)? == 0 {
+        return Ok(());
+    }
+    if frame::is_binary(first[0]) {
+        return answer_binary(control, stream);
+    }
+
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(argument) = line.strip_prefix("tail -f") {
+            match parse_count(argument.trim(), LOG_RING_SIZE) {
+                Ok(n) => return follow(control, reader.into_inner(), writer, n),
+                Err(ref e) => writer.write_all(format!("error: {}\n", e).as_bytes())?,
+            }
+            continue;
+        }
+        let reply = match run(control, line) {
+            Ok(output) => format!("{}ok\n", output),
+            Err(ref e) => format!("error: {}\n", e),
+        };
+        writer.write_all(reply.as_bytes())?;
+        writer.flush()?;
+    }
+}
+
+/// Writes the last N lines in the ring, and then the new lines as they are logged until the
+/// client closes the connection. Anything else the client sends is ignored.
+fn follow(
+    control: &Control,
+    mut stream: TcpStream,
+    mut writer: TcpStream,
+    n: usize,
+) -> io::Result<()> {
+    let mut last = control.logger.get_last_number();
+    let mut lines = control.logger.tail_numbered(n);
+    stream.set_read_timeout(Some(Duration::from_millis(FOLLOW_INTERVAL)))?;
+    let mut buffer = [0u8; 256];
+    loop {
+        if let Some((number, _)) = lines.last() {
+            last = *number;
+        }
+        let output: String = lines
+            .iter()
+            .map(|(_, line)| format!("{}\n", line))
+            .collect();
+        writer.write_all(output.as_bytes())?;
+        writer.flush()?;
+
+        // Wait for new lines, or for the client closing the connection
+        match stream.read(&mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(_) => {}
+            Err(ref e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+            }
+            Err(e) => return Err(e),
+        }
+        lines = control.logger.tail_after(last);
+    }
+}
+
+/// Answers the requests in binary frames of a client until it closes the connection. A frame
+/// which cannot be decoded is answered with an error and closes the connection, since the next
+/// frame cannot be found.
+fn answer_binary(control: &Control, mut stream: TcpStream) -> io::Result<()> {
+    loop {
+        let request = match Message::read(&mut stream) {
+            Ok(request) => request,
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => {
+                let error = Message::new(MessageType::Error, Value::Str(e.to_string()));
+                let _ = error.write(&mut stream);
+                return Err(e);
+            }
+        };
+        let reply = match (request.message_type, get_snapshot(control)) {
+            (MessageType::StatsRequest, Some(ref snapshot)) => frame::encode_stats(snapshot),
+            (MessageType::FlowsRequest, Some(ref snapshot)) => frame::encode_flows(&snapshot.flows),
+            (MessageType::StatsRequest, None) | (MessageType::FlowsRequest, None) => {
+                Message::new(MessageType::Error, Value::Str(not_started().to_string()))
+            }
+            (message_type, _) => Message::new(
+                MessageType::Error,
+                Value::Str(format!("unexpected message type {}", message_type as u8)),
+            ),
+        };
+        reply.write(&mut stream)?;
+        stream.flush()?;
+    }
+}
+
+/// Runs a command of the control, and returns its output, which ends with a line feed if it is
+/// not empty.
+fn run(control: &Control, line: &str) -> io::Result<String> {
+    let mut words = line.splitn(2, char::is_whitespace);
+    let command = words.next().unwrap_or("");
+    let argument = words.next().unwrap_or("").trim();
+    match command {
+        "set-log-level" => {
+            let directives = Directives::parse(argument)?;
+            if directives != control.logger.get_directives() {
+                control.logger.set_directives(directives);
+                info!("Set log level {}", control.logger.get_directives());
+            }
+
+            Ok(String::new())
+        }
+        "tail" => {
+            // The control file has nothing to follow with
+            if argument.starts_with("-f") {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "tail -f is only on the control socket",
+                ));
+            }
+            let n = parse_count(argument, LOG_RING_SIZE)?;
+
+            Ok(control
+                .logger
+                .tail(n)
+                .iter()
+                .map(|line| format!("{}\n", line))
+                .collect())
+        }
+        "expire-flows" => {
+            let rule = if argument.is_empty() {
+                None
+            } else {
+                Some(String::from(argument))
+            };
+            match *control.monitor.lock().unwrap() {
+                Some(ref monitor) => monitor.request_expiry(rule),
+                None => return Err(not_started()),
+            }
+
+            Ok(String::new())
+        }
+        "kill-flow" => {
+            let kill = Kill::parse(argument).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid flow {}", argument),
+                )
+            })?;
+            match *control.monitor.lock().unwrap() {
+                Some(ref monitor) => {
+                    info!("Request kill {}", kill);
+                    monitor.request_kill(kill);
+                }
+                None => return Err(not_started()),
+            }
+
+            Ok(String::new())
+        }
+        "nat-test" => {
+            let server = stun::parse_server(argument).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid STUN server {}", argument),
+                )
+            })?;
+            let (remote, credentials) = match *control.nat_test.lock().unwrap() {
+                Some(ref nat_test) => nat_test.clone(),
+                None => return Err(not_started()),
+            };
+            info!("Test NAT against {} via {}", server, remote);
+            let report = pcap2socks_core::test_nat(Some(remote), &credentials, server)?;
+            info!(
+                "NAT of {}: {} mapping, {} filtering",
+                report.external, report.mapping, report.filtering
+            );
+
+            Ok(format!("{}\n", report))
+        }
+        "top-talkers" => {
+            let n = parse_count(argument, TOP_TALKERS)?;
+            match *control.monitor.lock().unwrap() {
+                Some(ref monitor) => Ok(monitor
+                    .get_talkers(n)
+                    .iter()
+                    .map(|(src, count)| format!("{} {} destinations\n", src, count))
+                    .collect()),
+                None => Err(not_started()),
+            }
+        }
+        "info" => Ok(format!("{}\n", Info::probe().to_json())),
+        "stats" => match get_snapshot(control) {
+            Some(snapshot) => Ok(snapshot.report_counters()),
+            None => Err(not_started()),
+        },
+        "dump" => match argument {
+            "threads" => match *control.threads.lock().unwrap() {
+                Some(ref threads) => Ok(format!("{}\n", threads)),
+                None => Err(not_started()),
+            },
+            "flows" => match get_snapshot(control) {
+                Some(snapshot) => Ok(snapshot.report_flows()),
+                None => Err(not_started()),
+            },
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown dump {}", argument),
+            )),
+        },
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unknown command {}", command),
+        )),
+    }
+}
+
+/// Parses the number of a command, or the default one if it is omitted.
+fn parse_count(argument: &str, default: usize) -> io::Result<usize> {
+    if argument.is_empty() {
+        return Ok(default);
+    }
+
+    argument.parse::<usize>().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid number {}", argument),
+        )
+    })
+}
+
+/// Get the last statistics published by the first capture.
+fn get_snapshot(control: &Control) -> Option<Snapshot> {
+    control
+        .monitor
+        .lock()
+        .unwrap()
+        .as_ref()
+        .and_then(|monitor| monitor.get_snapshot())
+}
+
+fn not_started() -> io::Error {
+    io::Error::other("flows are not started yet")
+}
+
+/// Spawns the threads applying the commands of the control file and answering the ones of the
+/// control socket if they are given, which are recorded in the thread registry of the process.
+/// Returns the control, which the flows are set in once they are started.
+pub fn start(logger: &'static Logger, flags: &Flags, threads: &Arc<Threads>) -> &'static Control {
+    let control: &'static Control = Box::leak(Box::new(Control::new(logger)));
+    control.set_threads(Arc::clone(threads));
+    if let Some(ref path) = flags.log_control {
+        if let Err(ref e) = watch(control, threads, PathBuf::from(path)) {
+            warn!("log control {}: {}", path, e);
+        }
+    }
+    if let Some(ref addr) = flags.control_listen {
+        let result = addr
+            .parse()
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid address {}", addr),
+                )
+            })
+            .and_then(|addr| listen(control, threads, addr));
+        if let Err(ref e) = result {
+            warn!("control {}: {}", addr, e);
+        }
+    }
+
+    control
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::{Level, LevelFilter, Log, Metadata, Record};
+    use std::net::Ipv4Addr;
+
+    fn create_control(default: LevelFilter, ring_size: usize) -> &'static Control {
+        let inner = env_logger::builder()
+            .is_test(true)
+            .filter_level(LevelFilter::Trace)
+            .build();
+        let logger = Logger::new(
+            inner,
+            Directives::new(default),
+            ring_size,
+            LevelFilter::Trace,
+        );
+
+        Box::leak(Box::new(Control::new(Box::leak(Box::new(logger)))))
+    }
+
+    fn log(logger: &Logger, level: Level, target: &str, i: usize) {
+        logger.log(
+            &Record::builder()
+                .args(format_args!("line {}", i))
+                .level(level)
+                .target(target)
+                .build(),
+        );
+    }
+
+    #[test]
+    fn test_set_log_level() {
+        let control = create_control(LevelFilter::Info, 0);
+        let metadata = |level, target| Metadata::builder().level(level).target(target).build();
+        let socks = "pcap2socks_core::socks::forward";
+        let upstream = "pcap2socks_core::upstream";
+
+        // Levels change for the next lines
+        assert_eq!(run(control, "set-log-level socks=debug,warn").unwrap(), "");
+        assert!(control.logger.enabled(&metadata(Level::Debug, socks)));
+        assert!(!control.logger.enabled(&metadata(Level::Trace, socks)));
+        assert!(!control.logger.enabled(&metadata(Level::Info, upstream)));
+
+        // Invalid directives keep the levels
+        assert!(run(control, "set-log-level socks=loud").is_err());
+        assert!(control.logger.enabled(&metadata(Level::Debug, socks)));
+        run(control, "set-log-level info").unwrap();
+        assert!(!control.logger.enabled(&metadata(Level::Debug, socks)));
+        assert!(control.logger.enabled(&metadata(Level::Info, upstream)));
+    }
+
+    #[test]
+    fn test_tail() {
+        let control = create_control(LevelFilter::Warn, 3);
+        for i in 0..3 {
+            log(control.logger, Level::Trace, "pcap2socks_core::socks", i);
+        }
+
+        assert_eq!(
+            run(control, "tail 2").unwrap(),
+            "trace socks: line 1\ntrace socks: line 2\n"
+        );
+        assert!(run(control, "tail many").is_err());
+    }
+
+    #[test]
+    fn test_control_socket() {
+        let control = create_control(LevelFilter::Info, 16);
+        log(control.logger, Level::Debug, "pcap2socks_core::upstream", 0);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let threads = Arc::new(Threads::new(8));
+        serve(control, &threads, listener).unwrap();
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        writer
+            .write_all(b"set-log-level upstream=trace\n\ntail 1\nexpire-flows\nreload\ninfo\ndump threads\n")
+            .unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        let mut next = || lines.next().unwrap().unwrap();
+        assert_eq!(next(), "ok");
+        assert_eq!(next(), "debug upstream: line 0");
+        assert_eq!(next(), "ok");
+        assert_eq!(next(), "error: flows are not started yet");
+        assert_eq!(next(), "error: unknown command reload");
+        assert!(next().starts_with("{\"name\":\"pcap2socks\",\"version\":"));
+        assert_eq!(next(), "ok");
+        // The registry is not set without starting
+        assert_eq!(next(), "error: flows are not started yet");
+        control.set_threads(Arc::clone(&threads));
+        let monitor = Arc::new(Monitor::new());
+        monitor.set_talkers(Ipv4Addr::new(10, 6, 0, 2), vec![(5000, 40), (5001, 2)]);
+        control.set_monitor(monitor);
+        writer
+            .write_all(b"dump threads\ndump sockets\ntop-talkers 1\n")
+            .unwrap();
+        // The client is served in its own thread
+        assert_eq!(next(), "2 / 8 threads");
+        assert!(next().starts_with("    control (control, "));
+        assert!(next().starts_with("    control 127.0.0.1:"));
+        assert_eq!(next(), "ok");
+        assert_eq!(next(), "error: unknown dump sockets");
+        assert_eq!(next(), "10.6.0.2:5000 40 destinations");
+        assert_eq!(next(), "ok");
+        assert_eq!(
+            control.logger.get_directives(),
+            Directives::parse("upstream=trace").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_control_clients() {
+        let control = create_control(LevelFilter::Info, 16);
+        let threads = Arc::new(Threads::new(8));
+        // Only loopback addresses are accepted
+        let addr = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0);
+        let e = listen(control, &threads, addr).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        serve(control, &threads, listener).unwrap();
+
+        // An idle client does not block the others
+        let idle = TcpStream::connect(addr).unwrap();
+        idle.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        writer.write_all(b"set-log-level debug\n").unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        assert_eq!(lines.next().unwrap().unwrap(), "ok");
+
+        // Clients over the limit are refused
+        let clients: Vec<TcpStream> = (0..MAX_CONTROL_CLIENTS - 2)
+            .map(|_| TcpStream::connect(addr).unwrap())
+            .collect();
+        let refused = TcpStream::connect(addr).unwrap();
+        let mut lines = BufReader::new(refused).lines();
+        assert_eq!(lines.next().unwrap().unwrap(), "error: too many clients");
+        assert!(lines.next().is_none());
+        drop(clients);
+        drop(idle);
+    }
+
+    #[test]
+    fn test_control_follow() {
+        let control = create_control(LevelFilter::Info, 16);
+        for i in 0..3 {
+            log(control.logger, Level::Info, "pcap2socks_core", i);
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let threads = Arc::new(Threads::new(8));
+        serve(control, &threads, listener).unwrap();
+
+        let stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        writer.write_all(b"tail -f many\ntail -f 1\n").unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        let mut next = || lines.next().unwrap().unwrap();
+        assert_eq!(next(), "error: invalid number many");
+        assert_eq!(next(), "info pcap2socks: line 2");
+
+        // New lines follow without another command
+        log(control.logger, Level::Debug, "pcap2socks_core::socks", 3);
+        log(control.logger, Level::Info, "pcap2socks_core", 4);
+        assert_eq!(next(), "debug socks: line 3");
+        assert_eq!(next(), "info pcap2socks: line 4");
+
+        // The client closing the connection ends the thread
+        drop(lines);
+        drop(writer);
+        let mut is_ended = false;
+        for _ in 0..50 {
+            if threads.dump().len() == 1 {
+                is_ended = true;
+                break;
+            }
+            thread::sleep(Duration::from_millis(FOLLOW_INTERVAL));
+        }
+        assert!(is_ended);
+
+        // The control file cannot follow
+        assert!(run(control, "tail -f").is_err());
+    }
+
+    #[test]
+    fn test_control_binary() {
+        use pcap2socks_core::stats::{FlowStats, COUNTERS};
+        use std::net::SocketAddrV4;
+
+        let control = create_control(LevelFilter::Info, 16);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        serve(control, &Arc::new(Threads::new(8)), listener).unwrap();
+
+        // Nothing is published without a monitor
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let request = Message::new(MessageType::StatsRequest, Value::Null);
+        request.write(&mut stream).unwrap();
+        let reply = Message::read(&mut stream).unwrap();
+        assert_eq!(reply.message_type, MessageType::Error);
+
+        let monitor = Arc::new(Monitor::new());
+        let flow = FlowStats {
+            protocol: 6,
+            src_port: 50000,
+            dst: SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 443),
+            bytes_up: 100,
+            bytes_down: 2000,
+            rule: None,
+            window: None,
+        };
+        let snapshot = Snapshot {
+            version: 2,
+            updated: 1000,
+            counters: vec![7; COUNTERS.len()],
+            flows: vec![flow],
+        };
+        monitor.set_snapshot(snapshot.clone());
+        control.set_monitor(monitor);
+
+        // The same connection is answered again, in the order of requests
+        request.write(&mut stream).unwrap();
+        Message::new(MessageType::FlowsRequest, Value::Null)
+            .write(&mut stream)
+            .unwrap();
+        Message::new(MessageType::Flows, Value::Null)
+            .write(&mut stream)
+            .unwrap();
+        assert_eq!(
+            Message::read(&mut stream).unwrap(),
+            frame::encode_stats(&snapshot)
+        );
+        assert_eq!(
+            Message::read(&mut stream).unwrap(),
+            frame::encode_flows(&snapshot.flows)
+        );
+        let reply = Message::read(&mut stream).unwrap();
+        assert_eq!(reply.message_type, MessageType::Error);
+        assert_eq!(
+            reply.payload,
+            Value::Str(String::from("unexpected message type 4"))
+        );
+
+        // A text client on the same socket gets lines, once the binary one leaves
+        drop(stream);
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        writer.write_all(b"stats\ndump flows\n").unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        let mut next = || lines.next().unwrap().unwrap();
+        assert_eq!(next(), "Version 2, updated at 1000");
+        for _ in 0..COUNTERS.len() {
+            assert!(next().ends_with(" 7"));
+        }
+        assert_eq!(next(), "ok");
+        assert_eq!(next(), "Top flows:");
+        assert!(next().starts_with("    tcp 50000 -> 192.0.2.1:443"));
+        assert_eq!(next(), "ok");
+    }
+}
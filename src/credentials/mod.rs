@@ -0,0 +1,214 @@
+use std::io::{self, Write};
+
+use pcap2socks_core::Secret;
+
+/// Resolves the password of destinations in the order of precedence: the flag, the environment
+/// variable, the file and the prompt. Each source returns `None` if it is not given, and a source
+/// is never asked once one before it is given, so the prompt only shows without any other source.
+pub fn resolve<T, F, E, L, P>(flag: F, env: E, file: L, prompt: P) -> io::Result<Option<T>>
+where
+    F: FnOnce() -> Option<T>,
+    E: FnOnce() -> Option<T>,
+    L: FnOnce() -> Option<io::Result<T>>,
+    P: FnOnce() -> Option<io::Result<T>>,
+{
+    if let Some(password) = flag() {
+        return Ok(Some(password));
+    }
+    if let Some(password) = env() {
+        return Ok(Some(password));
+    }
+    if let Some(password) = file() {
+        return password.map(Some);
+    }
+    if let Some(password) = prompt() {
+        return password.map(Some);
+    }
+
+    Ok(None)
+}
+
+/// Prompts for the password of the given user from the standard input, which must be a terminal.
+/// The echo of the terminal is turned off while reading.
+pub fn ask_password(username: &str) -> io::Result<Secret> {
+    // A password piped in is better read from a file
+    if !atty::is(atty::Stream::Stdin) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--ask-pass requires the standard input to be a terminal",
+        ));
+    }
+    eprint!("Password for {}: ", username);
+    io::stderr().flush()?;
+
+    let mut line = String::new();
+    {
+        let _echo = Echo::off()?;
+        io::stdin().read_line(&mut line)?;
+    }
+    // The newline typed is not echoed
+    eprintln!();
+
+    Ok(Secret::from_line(line))
+}
+
+/// Represents the turned off echo of the terminal of the standard input, which is restored to the
+/// previous mode once dropped.
+struct Echo {
+    #[cfg(unix)]
+    termios: libc::termios,
+    #[cfg(windows)]
+    mode: winapi::shared::minwindef::DWORD,
+}
+
+#[cfg(unix)]
+impl Echo {
+    /// Turns off the echo of the terminal of the standard input.
+    fn off() -> io::Result<Echo> {
+        let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut silent = termios;
+        silent.c_lflag &= !libc::ECHO;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &silent) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Echo { termios })
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Echo {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.termios);
+        }
+    }
+}
+
+#[cfg(windows)]
+impl Echo {
+    /// Turns off the echo of the console of the standard input.
+    fn off() -> io::Result<Echo> {
+        use winapi::um::consoleapi::{GetConsoleMode, SetConsoleMode};
+        use winapi::um::processenv::GetStdHandle;
+        use winapi::um::winbase::STD_INPUT_HANDLE;
+        use winapi::um::wincon::ENABLE_ECHO_INPUT;
+
+        let handle = unsafe { GetStdHandle(STD_INPUT_HANDLE) };
+        let mut mode = 0;
+        if unsafe { GetConsoleMode(handle, &mut mode) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if unsafe { SetConsoleMode(handle, mode & !ENABLE_ECHO_INPUT) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Echo { mode })
+    }
+}
+
+#[cfg(windows)]
+impl Drop for Echo {
+    fn drop(&mut self) {
+        use winapi::um::consoleapi::SetConsoleMode;
+        use winapi::um::processenv::GetStdHandle;
+        use winapi::um::winbase::STD_INPUT_HANDLE;
+
+        unsafe {
+            SetConsoleMode(GetStdHandle(STD_INPUT_HANDLE), self.mode);
+        }
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+impl Echo {
+    fn off() -> io::Result<Echo> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "turning off the echo of the terminal is not supported",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    const FLAG: Option<&str> = Some("flag");
+    const ENV: Option<&str> = Some("env");
+    const FILE: Option<&str> = Some("file");
+    const PROMPT: Option<&str> = Some("prompt");
+
+    /// Resolves the sources given, and panics if a source after the resolved one is asked.
+    fn resolve_given(
+        flag: Option<&'static str>,
+        env: Option<&'static str>,
+        file: Option<&'static str>,
+        prompt: Option<&'static str>,
+    ) -> Option<&'static str> {
+        let asked = RefCell::new(Vec::new());
+        let result = resolve(
+            || {
+                asked.borrow_mut().push("flag");
+                flag
+            },
+            || {
+                asked.borrow_mut().push("env");
+                env
+            },
+            || {
+                asked.borrow_mut().push("file");
+                file.map(Ok)
+            },
+            || {
+                asked.borrow_mut().push("prompt");
+                prompt.map(Ok)
+            },
+        )
+        .unwrap();
+        // Sources after the resolved one are never asked
+        assert_eq!(asked.borrow().last().copied(), result.or(Some("prompt")));
+
+        result
+    }
+
+    #[test]
+    fn test_resolve_pairs() {
+        // Each pair of sources, the former takes precedence
+        assert_eq!(resolve_given(FLAG, ENV, None, None), FLAG);
+        assert_eq!(resolve_given(FLAG, None, FILE, None), FLAG);
+        assert_eq!(resolve_given(FLAG, None, None, PROMPT), FLAG);
+        assert_eq!(resolve_given(None, ENV, FILE, None), ENV);
+        assert_eq!(resolve_given(None, ENV, None, PROMPT), ENV);
+        assert_eq!(resolve_given(None, None, FILE, PROMPT), FILE);
+    }
+
+    #[test]
+    fn test_resolve_single() {
+        assert_eq!(resolve_given(FLAG, None, None, None), FLAG);
+        assert_eq!(resolve_given(None, ENV, None, None), ENV);
+        assert_eq!(resolve_given(None, None, FILE, None), FILE);
+        assert_eq!(resolve_given(None, None, None, PROMPT), PROMPT);
+        assert_eq!(resolve_given(None, None, None, None), None);
+        assert_eq!(resolve_given(FLAG, ENV, FILE, PROMPT), FLAG);
+    }
+
+    #[test]
+    fn test_resolve_errors() {
+        let failed = || -> Option<io::Result<&str>> {
+            Some(Err(io::Error::new(io::ErrorKind::NotFound, "no password")))
+        };
+        // A failed file is an error, and the prompt is not asked
+        let result = resolve(|| None, || None, failed, || panic!("prompt asked"));
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::NotFound);
+        // A failed file is never read with a password given before it
+        let result = resolve(|| None, || ENV, failed, || panic!("prompt asked"));
+        assert_eq!(result.unwrap(), ENV);
+        let result = resolve(|| None, || None, || None, failed);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+}
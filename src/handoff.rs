@@ -0,0 +1,84 @@
+use log::{error, info};
+use std::env;
+use std::io;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use pcap2socks_core::handoff::{Channel, Handoff, HANDOFF_FD_ENV};
+use pcap2socks_core::threads::{Purpose, Threads};
+
+/// Represents the interval between 2 checks of the upgrade signal.
+const SIGNAL_CHECK_INTERVAL: u64 = 100;
+
+/// Represents if SIGUSR2 is received since the last check.
+static IS_UPGRADE_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Spawns the thread handing off to a new process on SIGUSR2. The new process is started from the
+/// path and with the arguments of this process, so a binary replaced at the path is upgraded.
+pub fn watch(handoff: Arc<Handoff>, threads: &Arc<Threads>) -> io::Result<()> {
+    install()?;
+    Threads::spawn(
+        threads,
+        String::from("handoff"),
+        Purpose::Handoff,
+        move || loop {
+            thread::sleep(Duration::from_millis(SIGNAL_CHECK_INTERVAL));
+            if IS_UPGRADE_REQUESTED.swap(false, Ordering::Relaxed) {
+                if let Err(ref e) = upgrade(&handoff) {
+                    error!("handoff: {}", e);
+                }
+            }
+        },
+    )?;
+
+    Ok(())
+}
+
+fn upgrade(handoff: &Handoff) -> io::Result<()> {
+    // The executable of this process is gone once replaced, the one at the path started is new
+    let program = match env::args_os().next() {
+        Some(program) => program,
+        None => env::current_exe()?.into_os_string(),
+    };
+    let (channel, peer) = Channel::pair()?;
+    let mut child = Command::new(program)
+        .args(env::args_os().skip(1))
+        .env(HANDOFF_FD_ENV, peer.get_inheritable_fd()?.to_string())
+        .spawn()?;
+    drop(peer);
+    info!("Hand off to process {}", child.id());
+
+    if let Err(e) = handoff.hand_off(channel) {
+        // The new process never serves along with this one
+        let _ = child.kill();
+        let _ = child.wait();
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn install() -> io::Result<()> {
+    extern "C" fn handle(_: libc::c_int) {
+        IS_UPGRADE_REQUESTED.store(true, Ordering::Relaxed);
+    }
+
+    let result = unsafe { libc::signal(libc::SIGUSR2, handle as *const () as libc::sighandler_t) };
+    if result == libc::SIG_ERR {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn install() -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "the handoff is only supported on Unix",
+    ))
+}
@@ -0,0 +1,272 @@
+//! The report of what the binary can do on the host it runs on, printed by `--version --verbose`
+//! and returned in JSON by the `info` command of the control.
+
+use std::fmt::{self, Display, Formatter};
+
+use crate::args::Opts;
+use pcap2socks_core as lib;
+use pcap2socks_core::Probe;
+
+/// Represents the metadata of the build.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Build {
+    /// Represents the commit built, or `unknown` outside of the repository.
+    pub commit: &'static str,
+    pub profile: &'static str,
+    pub target: &'static str,
+    pub rustc: &'static str,
+}
+
+impl Build {
+    /// Get the metadata of the running build.
+    pub fn current() -> Build {
+        Build {
+            commit: env!("PCAP2SOCKS_COMMIT"),
+            profile: env!("PCAP2SOCKS_PROFILE"),
+            target: env!("PCAP2SOCKS_TARGET"),
+            rustc: env!("PCAP2SOCKS_RUSTC"),
+        }
+    }
+}
+
+/// Represents the report of what the binary can do.
+#[derive(Clone, Debug)]
+pub struct Info {
+    pub name: &'static str,
+    pub version: &'static str,
+    pub build: Build,
+    pub os: &'static str,
+    pub arch: &'static str,
+    pub features: Vec<&'static str>,
+    pub probe: Probe,
+    pub protocols: Vec<&'static str>,
+    pub proxies: Vec<&'static str>,
+    pub defaults: Vec<(&'static str, usize)>,
+}
+
+impl Info {
+    /// Probes the host and creates the report. Missing components are reported as unknown,
+    /// never as errors.
+    pub fn probe() -> Info {
+        let opts = Opts::new();
+
+        Info {
+            name: env!("CARGO_PKG_NAME"),
+            version: env!("CARGO_PKG_VERSION"),
+            build: Build::current(),
+            os: std::env::consts::OS,
+            arch: std::env::consts::ARCH,
+            features: features(),
+            probe: lib::probe(),
+            protocols: vec!["IPv4 TCP", "UDP", "ICMPv4 echo", "ARP"],
+            proxies: vec!["SOCKS5 or HTTP CONNECT over IPv4 or IPv6"],
+            defaults: vec![
+                ("mtu", opts.mtu as usize),
+                ("threads", opts.max_threads),
+                ("udp_ports", lib::PORT_COUNT),
+                ("udp_initial_port", opts.initial as usize),
+                ("arp_rate", opts.arp_rate),
+            ],
+        }
+    }
+
+    /// Get the report in JSON.
+    pub fn to_json(&self) -> String {
+        let strings = |values: &[&str]| {
+            let values: Vec<String> = values.iter().map(|value| quote(value)).collect();
+            format!("[{}]", values.join(","))
+        };
+        let defaults: Vec<String> = self
+            .defaults
+            .iter()
+            .map(|(name, value)| format!("{}:{}", quote(name), value))
+            .collect();
+
+        format!(
+            "{{\"name\":{},\"version\":{},\"build\":{{\"commit\":{},\"profile\":{},\"target\":{},\"rustc\":{}}},\"platform\":{{\"os\":{},\"arch\":{}}},\"features\":{},\"capture\":{{\"backend\":{},\"library\":{},\"interfaces\":{},\"privileged\":{}}},\"protocols\":{},\"proxies\":{},\"defaults\":{{{}}}}}",
+            quote(self.name),
+            quote(self.version),
+            quote(self.build.commit),
+            quote(self.build.profile),
+            quote(self.build.target),
+            quote(self.build.rustc),
+            quote(self.os),
+            quote(self.arch),
+            strings(&self.features),
+            quote(self.probe.backend),
+            self.probe
+                .library
+                .as_ref()
+                .map_or_else(|| String::from("null"), |library| quote(library)),
+            self.probe.interfaces,
+            self.probe
+                .is_privileged
+                .map_or_else(|| String::from("null"), |is| is.to_string()),
+            strings(&self.protocols),
+            strings(&self.proxies),
+            defaults.join(",")
+        )
+    }
+}
+
+impl Display for Info {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let unknown = "unknown";
+        let features = if self.features.is_empty() {
+            String::from("none")
+        } else {
+            self.features.join(", ")
+        };
+        let defaults: Vec<String> = self
+            .defaults
+            .iter()
+            .map(|(name, value)| format!("{} {}", name, value))
+            .collect();
+
+        writeln!(f, "{} {}", self.name, self.version)?;
+        writeln!(
+            f,
+            "Build: commit {}, {}, {}, {}",
+            self.build.commit, self.build.profile, self.build.target, self.build.rustc
+        )?;
+        writeln!(f, "Platform: {} {}", self.os, self.arch)?;
+        writeln!(f, "Features: {}", features)?;
+        writeln!(f, "Capture backend: {}", self.probe.backend)?;
+        writeln!(
+            f,
+            "Capture library: {}",
+            self.probe.library.as_deref().unwrap_or(unknown)
+        )?;
+        writeln!(f, "Capture interfaces: {}", self.probe.interfaces)?;
+        writeln!(
+            f,
+            "Capture privileges: {}",
+            match self.probe.is_privileged {
+                Some(true) => "yes",
+                Some(false) => "no",
+                None => unknown,
+            }
+        )?;
+        writeln!(f, "Protocols: {}", self.protocols.join(", "))?;
+        writeln!(f, "Proxies: {}", self.proxies.join(", "))?;
+        write!(f, "Defaults: {}", defaults.join(", "))
+    }
+}
+
+fn features() -> Vec<&'static str> {
+    let mut features = Vec::new();
+    if cfg!(feature = "notify") {
+        features.push("notify");
+    }
+    if cfg!(feature = "http") {
+        features.push("http");
+    }
+
+    features
+}
+
+/// Get the string quoted in JSON.
+fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_info(probe: Probe) -> Info {
+        Info {
+            name: "pcap2socks",
+            version: "0.1.0",
+            build: Build {
+                commit: "0123456789ab",
+                profile: "release",
+                target: "x86_64-unknown-linux-gnu",
+                rustc: "rustc 1.44.0",
+            },
+            os: "linux",
+            arch: "x86_64",
+            features: vec![],
+            probe,
+            protocols: vec!["UDP", "ARP"],
+            proxies: vec!["SOCKS5"],
+            defaults: vec![("mtu", 1400), ("threads", 512)],
+        }
+    }
+
+    #[test]
+    fn test_report() {
+        let info = create_info(Probe {
+            backend: "packet socket",
+            library: Some(String::from("kernel")),
+            interfaces: 2,
+            is_privileged: Some(true),
+        });
+
+        let lines: Vec<String> = info.to_string().lines().map(String::from).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "pcap2socks 0.1.0",
+                "Build: commit 0123456789ab, release, x86_64-unknown-linux-gnu, rustc 1.44.0",
+                "Platform: linux x86_64",
+                "Features: none",
+                "Capture backend: packet socket",
+                "Capture library: kernel",
+                "Capture interfaces: 2",
+                "Capture privileges: yes",
+                "Protocols: UDP, ARP",
+                "Proxies: SOCKS5",
+                "Defaults: mtu 1400, threads 512",
+            ]
+        );
+        assert_eq!(
+            info.to_json(),
+            "{\"name\":\"pcap2socks\",\"version\":\"0.1.0\",\"build\":{\"commit\":\"0123456789ab\",\"profile\":\"release\",\"target\":\"x86_64-unknown-linux-gnu\",\"rustc\":\"rustc 1.44.0\"},\"platform\":{\"os\":\"linux\",\"arch\":\"x86_64\"},\"features\":[],\"capture\":{\"backend\":\"packet socket\",\"library\":\"kernel\",\"interfaces\":2,\"privileged\":true},\"protocols\":[\"UDP\",\"ARP\"],\"proxies\":[\"SOCKS5\"],\"defaults\":{\"mtu\":1400,\"threads\":512}}"
+        );
+    }
+
+    #[test]
+    fn test_report_missing() {
+        // Components which are missing are unknown in the text and null in JSON
+        let info = create_info(Probe {
+            backend: "pcap",
+            library: None,
+            interfaces: 0,
+            is_privileged: None,
+        });
+        let text = info.to_string();
+        assert!(text.contains("Capture library: unknown\n"));
+        assert!(text.contains("Capture privileges: unknown\n"));
+        assert!(info
+            .to_json()
+            .contains("\"capture\":{\"backend\":\"pcap\",\"library\":null,\"interfaces\":0,\"privileged\":null}"));
+    }
+
+    #[test]
+    fn test_probe() {
+        // Probing the host never panics, whatever is missing on it
+        let info = Info::probe();
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert!(!info.build.commit.is_empty());
+        assert_eq!(info.to_string().lines().count(), 11);
+        assert!(info.to_json().starts_with("{\"name\":\"pcap2socks\""));
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("a\"b\\c\n"), "\"a\\\"b\\\\c\\u000a\"");
+    }
+}
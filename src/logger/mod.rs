@@ -0,0 +1,420 @@
+use env_logger::fmt::{Color, Target};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::collections::VecDeque;
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Write};
+use std::sync::{Mutex, RwLock};
+
+use crate::args::Flags;
+
+/// Represents the prefixes of targets of the engine and the binary, which can be omitted in
+/// directives, so modules of the engine keep the names they had in one crate.
+const TARGET_PREFIXES: [&str; 2] = ["pcap2socks_core::", "pcap2socks::"];
+
+/// Represents the target of the root of the engine, which is named as the root of the binary.
+const CORE_TARGET: &str = "pcap2socks_core";
+
+/// Represents the max number of recent log lines kept in memory.
+pub const LOG_RING_SIZE: usize = 1024;
+
+/// Get the target without the prefix of the crate.
+fn trim_target(target: &str) -> &str {
+    if target == CORE_TARGET {
+        return "pcap2socks";
+    }
+    for prefix in TARGET_PREFIXES.iter() {
+        if let Some(target) = target.strip_prefix(prefix) {
+            return target;
+        }
+    }
+
+    target
+}
+
+/// Represents the levels of log lines written out, a default one and one for each module.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Directives {
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl Directives {
+    /// Creates a new `Directives` of the default level.
+    pub fn new(default: LevelFilter) -> Directives {
+        Directives {
+            default,
+            modules: Vec::new(),
+        }
+    }
+
+    /// Parses directives like `socks=trace,upstream=debug,default=info`. A level without a module
+    /// is the default level.
+    pub fn parse(s: &str) -> io::Result<Directives> {
+        let mut directives = Directives::new(LevelFilter::Info);
+        for directive in s.split(',').map(|d| d.trim()).filter(|d| !d.is_empty()) {
+            let (module, level) = match directive.find('=') {
+                Some(i) => (directive[..i].trim(), directive[i + 1..].trim()),
+                None => ("default", directive),
+            };
+            let level = level.parse::<LevelFilter>().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid level {}", level),
+                )
+            })?;
+            if module == "default" {
+                directives.default = level;
+            } else if module.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid directive {}", directive),
+                ));
+            } else {
+                let module = trim_target(module);
+                directives.modules.retain(|(m, _)| m != module);
+                directives.modules.push((String::from(module), level));
+            }
+        }
+
+        Ok(directives)
+    }
+
+    /// Get the level of the target, by the module matching the most of it.
+    pub fn get_level(&self, target: &str) -> LevelFilter {
+        let target = trim_target(target);
+        self.modules
+            .iter()
+            .filter(|(module, _)| {
+                target == module
+                    || (target.starts_with(module.as_str())
+                        && target[module.len()..].starts_with("::"))
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map(|(_, level)| *level)
+            .unwrap_or(self.default)
+    }
+
+    /// Get the most verbose level of all the directives.
+    pub fn get_max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, |a, b| a.max(b))
+    }
+}
+
+impl Display for Directives {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let mut directives: Vec<String> = self
+            .modules
+            .iter()
+            .map(|(module, level)| format!("{}={}", module, level.to_string().to_lowercase()))
+            .collect();
+        directives.push(format!(
+            "default={}",
+            self.default.to_string().to_lowercase()
+        ));
+
+        write!(f, "{}", directives.join(","))
+    }
+}
+
+/// Represents a logger whose levels can be changed while running. Lines of the levels are written
+/// by the inner logger, and the lines of the level of the ring regardless of the levels are kept
+/// in a bounded ring if it is enabled.
+pub struct Logger {
+    inner: env_logger::Logger,
+    directives: RwLock<Directives>,
+    /// Represents the recent lines and their numbers, which count from 1.
+    ring: Option<Mutex<VecDeque<(u64, String)>>>,
+    ring_size: usize,
+    ring_level: LevelFilter,
+}
+
+impl Logger {
+    /// Creates a new `Logger`. The inner logger must write every line it is given.
+    pub fn new(
+        inner: env_logger::Logger,
+        directives: Directives,
+        ring_size: usize,
+        ring_level: LevelFilter,
+    ) -> Logger {
+        let ring = if ring_size > 0 {
+            Some(Mutex::new(VecDeque::with_capacity(ring_size)))
+        } else {
+            None
+        };
+
+        Logger {
+            inner,
+            directives: RwLock::new(directives),
+            ring,
+            ring_size,
+            ring_level,
+        }
+    }
+
+    /// Get the max level the logger takes, which is at least the level of the ring if it is
+    /// enabled.
+    pub fn get_max_level(&self) -> LevelFilter {
+        let level = self.directives.read().unwrap().get_max_level();
+        match self.ring {
+            Some(_) => level.max(self.ring_level),
+            None => level,
+        }
+    }
+
+    /// Sets the directives, which take effect immediately.
+    pub fn set_directives(&self, directives: Directives) {
+        *self.directives.write().unwrap() = directives;
+        log::set_max_level(self.get_max_level());
+    }
+
+    /// Get the directives.
+    pub fn get_directives(&self) -> Directives {
+        self.directives.read().unwrap().clone()
+    }
+
+    /// Get the last N lines in the ring, from the oldest.
+    pub fn tail(&self, n: usize) -> Vec<String> {
+        self.tail_numbered(n)
+            .into_iter()
+            .map(|(_, line)| line)
+            .collect()
+    }
+
+    /// Get the last N lines in the ring and their numbers, from the oldest.
+    pub fn tail_numbered(&self, n: usize) -> Vec<(u64, String)> {
+        match self.ring {
+            Some(ref ring) => {
+                let ring = ring.lock().unwrap();
+                ring.iter()
+                    .skip(ring.len().saturating_sub(n))
+                    .cloned()
+                    .collect()
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Get the lines in the ring after the line of the given number and their numbers, from the
+    /// oldest. Lines already dropped from the ring are missed.
+    pub fn tail_after(&self, number: u64) -> Vec<(u64, String)> {
+        match self.ring {
+            Some(ref ring) => ring
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(n, _)| *n > number)
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Get the number of the last line in the ring, or 0 if there is none.
+    pub fn get_last_number(&self) -> u64 {
+        match self.ring {
+            Some(ref ring) => ring.lock().unwrap().back().map(|(n, _)| *n).unwrap_or(0),
+            None => 0,
+        }
+    }
+
+    fn is_written(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.directives.read().unwrap().get_level(metadata.target())
+    }
+
+    fn is_kept(&self, metadata: &Metadata) -> bool {
+        self.ring.is_some() && metadata.level() <= self.ring_level
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.is_kept(metadata) || self.is_written(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if let (Some(ref ring), true) = (&self.ring, self.is_kept(record.metadata())) {
+            let line = format!(
+                "{} {}: {}",
+                level_name(record.level()),
+                trim_target(record.target()),
+                record.args()
+            );
+            let mut ring = ring.lock().unwrap();
+            let number = ring.back().map(|(n, _)| *n).unwrap_or(0) + 1;
+            if ring.len() >= self.ring_size {
+                ring.pop_front();
+            }
+            ring.push_back((number, line));
+        }
+        if self.is_written(record.metadata()) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+fn level_name(level: Level) -> &'static str {
+    match level {
+        Level::Error => "error",
+        Level::Warn => "warn",
+        Level::Info => "info",
+        Level::Debug => "debug",
+        Level::Trace => "trace",
+    }
+}
+
+/// Sets the logger. The levels can be changed while running with the control file or the control
+/// socket once they are started.
+pub fn set_logger(flags: &Flags) -> &'static Logger {
+    let level = match &flags.vverbose {
+        true => LevelFilter::Trace,
+        false => match flags.verbose {
+            true => LevelFilter::Debug,
+            false => LevelFilter::Info,
+        },
+    };
+    // The inner logger writes every line it is given, the levels are applied outside
+    let inner = env_logger::builder()
+        .target(Target::Stdout)
+        .filter_level(LevelFilter::Trace)
+        .format(|buf, record| {
+            let mut style = buf.style();
+
+            let level = match &record.level() {
+                Level::Error => style.set_bold(true).set_color(Color::Red).value("error: "),
+                Level::Warn => style
+                    .set_bold(true)
+                    .set_color(Color::Yellow)
+                    .value("warning: "),
+                Level::Info => style.set_bold(true).set_color(Color::Green).value(""),
+                _ => style.set_color(Color::Rgb(165, 165, 165)).value(""),
+            };
+            writeln!(buf, "{}{}", level, record.args())
+        })
+        .build();
+    let ring_size = if flags.log_control.is_some() || flags.control_listen.is_some() {
+        LOG_RING_SIZE
+    } else {
+        0
+    };
+    // The level is one of the possible values of the flag
+    let ring_level = flags.log_ring_level.parse().unwrap_or(LevelFilter::Debug);
+    let logger: &'static Logger = Box::leak(Box::new(Logger::new(
+        inner,
+        Directives::new(level),
+        ring_size,
+        ring_level,
+    )));
+    log::set_logger(logger).expect("logger is already set");
+    log::set_max_level(logger.get_max_level());
+
+    logger
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_logger(default: LevelFilter, ring_size: usize, ring_level: LevelFilter) -> Logger {
+        let inner = env_logger::builder()
+            .is_test(true)
+            .filter_level(LevelFilter::Trace)
+            .build();
+
+        Logger::new(inner, Directives::new(default), ring_size, ring_level)
+    }
+
+    fn log(logger: &Logger, level: Level, target: &str, i: usize) {
+        logger.log(
+            &Record::builder()
+                .args(format_args!("line {}", i))
+                .level(level)
+                .target(target)
+                .build(),
+        );
+    }
+
+    #[test]
+    fn test_set_directives() {
+        let logger = create_logger(LevelFilter::Info, 0, LevelFilter::Trace);
+        let metadata = |level, target| Metadata::builder().level(level).target(target).build();
+        let socks = "pcap2socks_core::socks::forward";
+        let upstream = "pcap2socks_core::upstream";
+
+        assert!(!logger.is_written(&metadata(Level::Debug, socks)));
+        assert!(logger.is_written(&metadata(Level::Info, upstream)));
+
+        // Levels change for the next lines
+        logger.set_directives(Directives::parse("socks=debug,warn").unwrap());
+        assert!(logger.is_written(&metadata(Level::Debug, socks)));
+        assert!(!logger.is_written(&metadata(Level::Trace, socks)));
+        assert!(!logger.is_written(&metadata(Level::Info, upstream)));
+        assert_eq!(logger.get_max_level(), LevelFilter::Debug);
+        assert!(Directives::parse("socks=loud").is_err());
+    }
+
+    #[test]
+    fn test_ring() {
+        let logger = create_logger(LevelFilter::Warn, 3, LevelFilter::Trace);
+        // Lines are kept regardless of the levels
+        assert!(logger.enabled(
+            &Metadata::builder()
+                .level(Level::Trace)
+                .target("pcap2socks_core")
+                .build()
+        ));
+        for i in 0..5 {
+            log(&logger, Level::Trace, "pcap2socks_core::socks", i);
+        }
+        log(&logger, Level::Error, "pcap2socks_core", 5);
+
+        // Only the last lines are kept
+        assert_eq!(
+            logger.tail(10),
+            vec![
+                "trace socks: line 3",
+                "trace socks: line 4",
+                "error pcap2socks: line 5"
+            ]
+        );
+        assert_eq!(logger.tail(1), vec!["error pcap2socks: line 5"]);
+
+        // No line is kept without the ring
+        let logger = create_logger(LevelFilter::Warn, 0, LevelFilter::Trace);
+        log(&logger, Level::Error, "pcap2socks_core", 0);
+        assert!(logger.tail(10).is_empty());
+        assert_eq!(logger.get_max_level(), LevelFilter::Warn);
+    }
+
+    #[test]
+    fn test_ring_level() {
+        let logger = create_logger(LevelFilter::Warn, 16, LevelFilter::Debug);
+        let metadata = |level| {
+            Metadata::builder()
+                .level(level)
+                .target("pcap2socks_core::socks")
+                .build()
+        };
+
+        // Lines more verbose than the ring are neither kept nor formatted
+        assert!(logger.enabled(&metadata(Level::Debug)));
+        assert!(!logger.enabled(&metadata(Level::Trace)));
+        assert_eq!(logger.get_max_level(), LevelFilter::Debug);
+        log(&logger, Level::Trace, "pcap2socks_core::socks", 0);
+        log(&logger, Level::Debug, "pcap2socks_core::socks", 1);
+        assert_eq!(logger.tail(10), vec!["debug socks: line 1"]);
+
+        // Lines written by the levels are still written
+        logger.set_directives(Directives::parse("socks=trace,warn").unwrap());
+        assert!(logger.enabled(&metadata(Level::Trace)));
+        assert_eq!(logger.get_max_level(), LevelFilter::Trace);
+        log(&logger, Level::Trace, "pcap2socks_core::socks", 2);
+        assert_eq!(logger.tail(10), vec!["debug socks: line 1"]);
+    }
+}
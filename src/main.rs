@@ -1,19 +1,112 @@
-use log::{error, info};
+use log::{error, info, warn};
+use std::fs;
+use std::io::{self, Write};
+use std::net::{Ipv4Addr, TcpListener};
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
-use lib::args::{self, Opts};
-use lib::{Forwarder, Redirector};
-use pcap2socks as lib;
+mod args;
+mod control;
+mod credentials;
+mod handoff;
+mod info;
+mod logger;
+mod notify;
+
+use args::{Capture, Opts};
+use info::Info;
+use lib::console::{Console, Dhcp, Igmp, Probe, Responder};
+use lib::drops::Check;
+use lib::frame;
+use lib::gateway::Status;
+use lib::handoff::{Channel, Handoff, Phase};
+use lib::health::Health;
+use lib::hooks::Hooks;
+use lib::monitor::Monitor;
+use lib::observer::Observer;
+use lib::privacy::Redactor;
+use lib::record::{self, Recording};
+use lib::standby::Standby;
+use lib::threads::{Purpose, Threads};
+use lib::upstream::{Fallback, Upstreams};
+use lib::{Credentials, Forwarder, Redirector, Secret};
+use pcap2socks_core as lib;
+
+/// Represents the read timeout of the interface in observation, after which the observer checks if
+/// the observation ends.
+const OBSERVE_READ_TIMEOUT: u64 = 100;
 
 fn main() {
+    // Capabilities
+    if args::is_version_verbose() {
+        println!("{}", Info::probe());
+        return;
+    }
+
+    // Statistics
+    if let Some((path, is_binary)) = args::get_stats_shm() {
+        match lib::stats::read(&PathBuf::from(path)) {
+            Ok(snapshot) => {
+                if is_binary {
+                    let stdout = io::stdout();
+                    let mut stdout = stdout.lock();
+                    let result = frame::encode_stats(&snapshot)
+                        .write(&mut stdout)
+                        .and_then(|_| frame::encode_flows(&snapshot.flows).write(&mut stdout))
+                        .and_then(|_| stdout.flush());
+                    if let Err(ref e) = result {
+                        eprintln!("stats: {}", e);
+                    }
+                } else {
+                    print!("{}", snapshot.report());
+                }
+            }
+            Err(ref e) => eprintln!("stats: {}", e),
+        }
+        return;
+    }
+
+    // Replay
+    if let Some((path, addr, is_compressed)) = args::get_replay() {
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+        let result = TcpListener::bind(addr.as_str())
+            .and_then(|listener| record::replay(&PathBuf::from(path), listener, is_compressed));
+        if let Err(ref e) = result {
+            eprintln!("replay: {}", e);
+        }
+        return;
+    }
+
+    // NAT test
+    if let Some((server, dst)) = args::get_nat_test() {
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+        match test_nat(&server, dst) {
+            Ok(report) => println!("{}", report),
+            Err(ref e) => eprintln!("nat-test: {}", e),
+        }
+        return;
+    }
+
+    // Check
+    if let Some((src, inter, publish, duration)) = args::get_check() {
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+        match check(&src, inter, publish, duration) {
+            Ok(report) => print!("{}", report),
+            Err(ref e) => eprintln!("check: {}", e),
+        }
+        return;
+    }
+
     // Parse arguments
-    let flags = args::parse();
+    let mut flags = args::parse();
 
     // Log
-    lib::set_logger(&flags);
+    let logger = logger::set_logger(&flags);
 
     // Validate arguments
-    let opts = match Opts::validate(&flags) {
+    let mut opts = match Opts::validate(&mut flags) {
         Ok(opts) => opts,
         Err(ref e) => {
             error!("{}", e);
@@ -21,56 +114,689 @@ fn main() {
         }
     };
 
-    // Interface
-    let inter = match lib::interface(opts.inter) {
-        Some(inter) => inter,
-        None => {
-            println!("Cannot determine interface. Available interfaces are listed below, use -i <INTERFACE> to designate:");
-            for inter in lib::interfaces().iter() {
-                println!("    {}", inter);
+    // All the threads of the process are recorded in a registry, which the max number of threads
+    // is counted across
+    let threads = Arc::new(Threads::new(opts.max_threads));
+    let control = control::start(logger, &flags, &threads);
+
+    // Credentials
+    let credentials = match opts.username.take() {
+        Some(username) => {
+            let password = opts.password.take();
+            let env_password = opts.env_password.take();
+            let password_file = opts.password_file.take();
+            let is_ask_pass = opts.ask_pass;
+            let credentials = credentials::resolve(
+                || password.map(|password| Credentials::from_password(username.clone(), password)),
+                || {
+                    env_password
+                        .map(|password| Credentials::from_password(username.clone(), password))
+                },
+                || {
+                    password_file.map(|password_file| {
+                        Credentials::from_file(username.clone(), PathBuf::from(password_file))
+                    })
+                },
+                || {
+                    if is_ask_pass {
+                        Some(
+                            credentials::ask_password(&username).map(|password| {
+                                Credentials::from_password(username.clone(), password)
+                            }),
+                        )
+                    } else {
+                        None
+                    }
+                },
+            )
+            .map(|credentials| {
+                credentials.unwrap_or_else(|| {
+                    Credentials::from_password(username.clone(), Secret::new(String::new()))
+                })
+            });
+            match credentials {
+                Ok(credentials) => credentials,
+                Err(ref e) => {
+                    error!("password: {}", e);
+                    return;
+                }
             }
-            return;
         }
+        None => Credentials::new(),
     };
-    info!("Listen on {}", inter);
-    info!("Break packets with MTU {}", opts.mtu);
+    if opts.is_password_in_args {
+        warn!("Password in the command line may be visible to other users, use the environment variable or a file instead");
+    }
 
-    // Publish
-    if let Some(publish) = opts.publish {
-        info!("Publish for {}", publish);
+    // Seed, printed so a run can be reproduced
+    let seed = opts.seed.unwrap_or_else(lib::generate_seed);
+    info!("Seed {}", seed);
+    if opts.insecure_isn {
+        warn!("Insecure initial TCP sequences derived from seed {}, which are predictable from the log", seed);
+    }
+    lib::backoff::set_seed(seed);
+    lib::backoff::set_cap(Duration::from_secs(opts.backoff_cap));
+    lib::backoff::set_max_handshakes(opts.max_handshakes);
+    // The salt is never derived from the seed, which is logged
+    let redactor = Redactor::new(opts.privacy, lib::generate_seed());
+    let retention = opts
+        .retention
+        .map(|days| Duration::from_secs(days * 24 * 60 * 60));
+    let recording = match opts.record_upstream {
+        Some(ref dir) => match Recording::new(
+            PathBuf::from(dir),
+            opts.record_filters.clone(),
+            opts.record_max_size * 1024,
+            redactor,
+        ) {
+            Ok(mut recording) => {
+                recording.set_retention(retention);
+                Some(recording)
+            }
+            Err(ref e) => {
+                error!("record: {}", e);
+                return;
+            }
+        },
+        None => None,
+    };
+    let mut rule_recordings = Vec::new();
+    for index in 0..opts.rules.len() {
+        if let Some(dir) = opts.rules.get(index).get_record_upstream() {
+            match Recording::new(
+                dir.clone(),
+                Vec::new(),
+                opts.record_max_size * 1024,
+                redactor,
+            ) {
+                Ok(mut recording) => {
+                    recording.set_retention(retention);
+                    rule_recordings.push((index, recording));
+                }
+                Err(ref e) => {
+                    error!("record: {}", e);
+                    return;
+                }
+            }
+        }
     }
 
-    // Instructions
-    lib::show_info(
-        opts.src,
-        opts.publish.unwrap_or(inter.ip_addrs[0]),
-        opts.mtu,
-    );
+    // Observe
+    if let Some(minutes) = opts.observe {
+        let inter = match lib::interface(opts.inter.clone()) {
+            Some(inter) => inter,
+            None => {
+                show_interfaces();
+                return;
+            }
+        };
+        info!("Listen on {}", inter);
+        let mut upstreams = match create_upstreams(&opts, true) {
+            Ok(upstreams) => upstreams,
+            Err(ref e) => {
+                error!("{}", e);
+                return;
+            }
+        };
+        upstreams.set_seed(seed);
 
-    // Proxy
-    let (tx, mut rx) = match inter.open() {
-        Ok((tx, rx)) => (tx, rx),
-        Err(ref e) => {
+        // Nothing is sent in observation
+        let (_, mut rx) =
+            match inter.open_with_read_timeout(Some(Duration::from_millis(OBSERVE_READ_TIMEOUT))) {
+                Ok((tx, rx)) => (tx, rx),
+                Err(ref e) => {
+                    error!("{}", e);
+                    return;
+                }
+            };
+        let mut observer = Observer::new(opts.src, upstreams);
+        observer.set_rules(opts.rules.clone());
+        if let Some(hardware_addr) = opts.gateway_hardware_addr {
+            observer.set_gateway_hardware_addr(hardware_addr);
+        }
+        if let Err(ref e) = observer.open(&mut rx, Duration::from_secs(minutes * 60)) {
             error!("{}", e);
             return;
         }
+        print!("{}", observer.report());
+        if let Some(ref observe_report) = opts.observe_report {
+            if let Err(ref e) = fs::write(observe_report, observer.report_json()) {
+                error!("observe report: {}", e);
+            }
+        }
+        return;
+    }
+
+    // Captures, the first one is given by the interface, the source and the publishing address
+    let mut captures = vec![Capture {
+        inter: opts.inter.clone(),
+        src: opts.src,
+        publish: opts.publish,
+    }];
+    captures.extend(opts.captures.iter().cloned());
+
+    // Handoff, a process started by a handoff takes over from the old one
+    let handoff = match Channel::from_env() {
+        Ok(Some(channel)) => match Handoff::take_over(channel) {
+            Ok(handoff) => Some(Arc::new(handoff)),
+            Err(ref e) => {
+                error!("handoff: {}", e);
+                return;
+            }
+        },
+        Ok(None) if opts.handoff => Some(Arc::new(Handoff::new(Duration::from_secs(
+            opts.handoff_drain,
+        )))),
+        Ok(None) => None,
+        Err(ref e) => {
+            error!("handoff: {}", e);
+            return;
+        }
     };
-    let forwarder = Forwarder::new(
-        tx,
-        opts.mtu,
-        inter.hardware_addr,
-        opts.src,
-        inter.ip_addrs[0],
+
+    // All captures are ready once they are set up
+    let health = Arc::new(Health::new());
+    // Workers of all captures are registered in one monitor
+    let monitor = Arc::new(Monitor::new());
+    control.set_monitor(Arc::clone(&monitor));
+    if let Some(remote) = opts.dst.first() {
+        control.set_nat_test(*remote, credentials.clone());
+    }
+
+    let mut redirectors = Vec::new();
+    for (i, capture) in captures.into_iter().enumerate() {
+        // Interface
+        let inter = match lib::interface(capture.inter) {
+            Some(inter) => inter,
+            None => {
+                show_interfaces();
+                return;
+            }
+        };
+        info!("Listen on {}", inter);
+        info!("Break packets with MTU {}", opts.mtu);
+
+        // Publish
+        if let Some(publish) = capture.publish {
+            info!("Publish for {}", publish);
+        }
+
+        // Instructions
+        lib::show_info(
+            capture.src,
+            capture.publish.unwrap_or(inter.ip_addrs[0]),
+            opts.mtu,
+        );
+
+        // Proxy
+        let (tx, rx) = match inter.open() {
+            Ok((tx, rx)) => (tx, rx),
+            Err(ref e) => {
+                error!("{}", e);
+                return;
+            }
+        };
+        let mut forwarder = Forwarder::new(
+            tx,
+            opts.mtu,
+            inter.hardware_addr,
+            capture.src,
+            inter.ip_addrs[0],
+        );
+        if opts.insecure_isn {
+            forwarder.set_insecure_isn_seed(seed);
+        }
+        // Only the first capture persists latencies
+        let upstreams = match create_upstreams(&opts, i == 0) {
+            Ok(upstreams) => upstreams,
+            Err(ref e) => {
+                error!("{}", e);
+                return;
+            }
+        };
+        info!("Proxy {} to {}", capture.src, upstreams);
+        // Each capture binds its own range of local UDP ports
+        let initial = opts.initial + (i * lib::PORT_COUNT) as u16;
+        let mut redirector = Redirector::new(
+            Arc::new(Mutex::new(forwarder)),
+            capture.src,
+            capture.publish,
+            upstreams,
+            initial,
+            opts.max_threads,
+        );
+        redirector.set_threads(Arc::clone(&threads));
+        // All captures share the DNS cache of the first one
+        if let Some(size) = opts.dns_cache {
+            match redirectors.first() {
+                Some((ref first, _, _)) => redirector.share_dns_cache(first),
+                None => redirector.set_dns_cache(size),
+            }
+        }
+        redirector.set_proxy_type(opts.proxy_type);
+        redirector.set_normalize_remote_port(opts.normalize_remote_port);
+        redirector.set_verify_checksum(opts.verify_checksum);
+        redirector.set_pickup_established(opts.pickup_established);
+        redirector.set_credentials(credentials.clone());
+        redirector.set_seed(seed);
+        redirector.set_ping_ttl(opts.ping_ttl);
+        if !opts.console_responders.is_empty() {
+            redirector.set_console(create_console(&opts, capture.src, capture.publish));
+        }
+        redirector.set_arp_rate(opts.arp_rate);
+        redirector.set_host_limits(opts.host_max_flows, opts.host_connect_rate);
+        redirector.set_host_ingress_rate(opts.host_ingress_rate);
+        redirector.set_tcp_half_open(
+            Duration::from_secs(opts.tcp_handshake_timeout),
+            opts.tcp_max_half_open,
+            opts.tcp_half_open_overflow,
+        );
+        redirector.set_idle_timeouts(
+            get_idle_timeout(opts.udp_idle_timeout),
+            get_idle_timeout(opts.tcp_idle_timeout),
+        );
+        redirector.set_udp_quarantine(Duration::from_secs(opts.udp_quarantine));
+        redirector.set_tcp_send_buffer(opts.tcp_send_buffer);
+        if let Err(ref e) = redirector.set_tcp_prewarm(opts.tcp_prewarm) {
+            warn!("pre-warm connections: {}", e);
+        }
+        redirector.set_resume_strategy(opts.resume_strategy);
+        // Each capture serves UDP of the rules of servers to its own source
+        redirector.set_rules(opts.rules.clone());
+        if let Some(hardware_addr) = opts.gateway_hardware_addr {
+            redirector.set_gateway_hardware_addr(hardware_addr);
+        }
+        for (index, recording) in rule_recordings.iter() {
+            redirector.set_rule_recording(*index, recording.clone());
+        }
+        redirector.set_tcp_min_mss(opts.tcp_min_mss);
+        redirector.set_udp_destination_limits(
+            opts.udp_max_destinations,
+            opts.udp_max_destinations_per_port,
+            &opts.udp_allowed_ports,
+        );
+        redirector.set_udp_discovery(opts.udp_discovery_policy, opts.udp_discovery_limit);
+        if let Some(resolver) = opts.gateway_dns {
+            if let Err(ref e) = redirector.set_gateway_dns(resolver) {
+                warn!("gateway DNS of {}: {}", capture.src, e);
+            }
+        }
+        if let Some(port) = opts.gateway_status {
+            let status = Arc::new(Status::new(Arc::clone(&monitor)));
+            if let Err(ref e) = redirector.set_gateway_service(port, status) {
+                warn!("gateway status of {}: {}", capture.src, e);
+            }
+        }
+        if let Some(port) = opts.gateway_upnp {
+            if let Err(ref e) = redirector.set_upnp(port) {
+                warn!("gateway UPnP of {}: {}", capture.src, e);
+            }
+        }
+        redirector.set_interface(inter.clone(), opts.mtu);
+        redirector.set_soak_report(opts.soak_report.map(Duration::from_secs));
+        redirector.set_log_drops(opts.log_drops);
+        if let Err(ref e) =
+            redirector.set_path_mtu_probe(opts.path_mtu_probe.map(Duration::from_secs))
+        {
+            warn!("path MTU of {}: {}", capture.src, e);
+        }
+        if opts.chaos {
+            warn!("Chaos mode, connections will be killed randomly");
+            redirector.set_chaos(true);
+        }
+        let mut hooks = Hooks::new(
+            opts.on_flow_open.clone(),
+            opts.on_flow_close.clone(),
+            opts.hook_filters.clone(),
+        );
+        hooks.set_rule_filters(opts.hook_rules.clone());
+        hooks.set_redactor(redactor);
+        redirector.set_redactor(redactor);
+        if let Some(ref recording) = recording {
+            redirector.set_recording(recording.clone());
+        }
+        redirector.set_monitor(Arc::clone(&monitor));
+        if let Err(ref e) = redirector.set_hooks(hooks) {
+            error!("hooks: {}", e);
+            return;
+        }
+        // Captures notify through and pair in the warm standby with the first one
+        if let Some((ref first, _, _)) = redirectors.first() {
+            redirector.share_notifier(first);
+            if let Err(ref e) = redirector.share_standby(first) {
+                error!("standby of {}: {}", capture.src, e);
+                return;
+            }
+        }
+        // All captures hand off together
+        if let Some(ref handoff) = handoff {
+            if let Err(ref e) = redirector.set_handoff(Arc::clone(handoff)) {
+                error!("handoff of {}: {}", capture.src, e);
+                return;
+            }
+        }
+        // Only the first capture persists the state, exports statistics and answers health probes
+        if i == 0 {
+            if opts.notify {
+                let hold = Duration::from_secs(opts.notify_hold * 60);
+                let result = notify::new_notifier(opts.notify_conditions.clone())
+                    .and_then(|notifier| redirector.set_notifier(notifier, hold));
+                if let Err(ref e) = result {
+                    warn!("notify: {}", e);
+                }
+                redirector.set_quota(opts.notify_quota);
+            }
+            if let Some(ref state_file) = opts.state_file {
+                if let Err(ref e) = redirector.load_state(PathBuf::from(state_file)) {
+                    warn!("load state: {}", e);
+                }
+            }
+            if let Some(health_listen) = opts.health_listen {
+                let result = match handoff {
+                    // The listener is passed on, so probes are answered across handoffs
+                    Some(ref handoff) => handoff
+                        .take_listener("health")
+                        .map_or_else(|| TcpListener::bind(health_listen), Ok)
+                        .and_then(|listener| {
+                            handoff.add_listener("health", &listener)?;
+                            redirector.set_health_listener(Arc::clone(&health), listener)
+                        }),
+                    None => redirector.set_health(Arc::clone(&health), health_listen),
+                };
+                if let Err(ref e) = result {
+                    error!("health: {}", e);
+                    return;
+                }
+            }
+            if let Some((listen, peer)) = opts.standby {
+                // The nonce breaks ties of the same priority
+                let standby = Standby::new(
+                    opts.standby_priority,
+                    lib::generate_seed(),
+                    capture.src.to_string(),
+                );
+                if let Err(ref e) = redirector.set_standby(standby, listen, peer) {
+                    error!("standby: {}", e);
+                    return;
+                }
+            }
+            redirector.set_stats_publish();
+            if let Some(ref stats_file) = opts.stats_file {
+                if let Err(ref e) = redirector.set_stats_export(PathBuf::from(stats_file)) {
+                    warn!("export statistics: {}", e);
+                }
+            }
+        }
+        redirectors.push((redirector, rx, inter.name.clone()));
+    }
+
+    health.set_validated(true);
+
+    if let Some(interval) = opts.summary_interval {
+        if let Err(ref e) = spawn_summary(monitor, Duration::from_secs(interval), &threads) {
+            error!("summary: {}", e);
+            return;
+        }
+    }
+
+    // Redirect additional captures in their own threads
+    let mut iter = redirectors.into_iter();
+    let (mut redirector, mut rx, _) = iter.next().unwrap();
+    let mut handles = Vec::new();
+    for (mut redirector, mut rx, name) in iter {
+        let result = Threads::spawn(
+            &threads,
+            format!("capture {}", name),
+            Purpose::Capture,
+            move || {
+                if let Err(ref e) = redirector.open(&mut rx) {
+                    error!("{}: {}", name, e);
+                }
+            },
+        );
+        match result {
+            Ok(handle) => handles.push(handle),
+            Err(ref e) => {
+                error!("{}", e);
+                return;
+            }
+        }
+    }
+    if let Some(handoff) = handoff {
+        // The old process releases its states once all captures are open
+        if handoff.get_phase() == Phase::Waiting {
+            if let Err(ref e) = handoff.ready() {
+                error!("handoff: {}", e);
+                return;
+            }
+        }
+        if opts.handoff {
+            if let Err(ref e) = handoff::watch(handoff, &threads) {
+                error!("handoff: {}", e);
+                return;
+            }
+        }
+    }
+    match redirector.open(&mut rx) {
+        // The old process exits once all captures are drained after a handoff
+        Ok(_) => {
+            for handle in handles {
+                let _ = handle.join();
+            }
+        }
+        Err(ref e) => error!("{}", e),
+    }
+}
+
+fn show_interfaces() {
+    println!("Cannot determine interface. Available interfaces are listed below, use -i <INTERFACE> to designate:");
+    for inter in lib::interfaces().iter() {
+        println!("    {}", inter);
+    }
+}
+
+/// Spawns the threads logging a summary of the monitor every interval, and the live workers and the
+/// inventories of the captures each time a line is read from the standard input until its end.
+fn spawn_summary(
+    monitor: Arc<Monitor>,
+    interval: Duration,
+    threads: &Arc<Threads>,
+) -> io::Result<()> {
+    let monitor_cloned = Arc::clone(&monitor);
+    Threads::spawn(
+        threads,
+        "summary".to_string(),
+        Purpose::Summary,
+        move || loop {
+            thread::sleep(interval);
+            info!("Summary: {}", monitor_cloned.get_summary());
+        },
+    )?;
+    Threads::spawn(
+        threads,
+        "summary table".to_string(),
+        Purpose::Summary,
+        move || {
+            let stdin = io::stdin();
+            let mut line = String::new();
+            while let Ok(size) = stdin.read_line(&mut line) {
+                if size == 0 {
+                    break;
+                }
+                line.clear();
+                let entries = monitor.get_entries();
+                info!("Summary: {} workers", entries.len());
+                for entry in entries {
+                    info!("    {}", entry);
+                }
+                // Captures log their own inventories
+                monitor.request_dump();
+            }
+        },
+    )?;
+
+    Ok(())
+}
+
+fn get_idle_timeout(secs: u64) -> Option<Duration> {
+    match secs {
+        0 => None,
+        _ => Some(Duration::from_secs(secs)),
+    }
+}
+
+fn create_upstreams(opts: &Opts, is_persist: bool) -> io::Result<Upstreams> {
+    let mut upstreams = Upstreams::new(opts.dst.clone(), opts.balance, opts.fallback)?;
+    upstreams.set_retention(
+        opts.retention
+            .map(|days| Duration::from_secs(days * 24 * 60 * 60)),
     );
-    let mut redirector = Redirector::new(
-        Arc::new(Mutex::new(forwarder)),
-        opts.src,
-        opts.publish,
-        opts.dst,
-        opts.initial,
+    if is_persist {
+        if let Some(ref latency_file) = opts.latency_file {
+            if let Err(ref e) = upstreams.load(PathBuf::from(latency_file)) {
+                warn!("load latencies: {}", e);
+            }
+        }
+    }
+    if upstreams.get_remotes().len() > 1 {
+        info!("Select destinations by {}", upstreams.get_policy());
+    }
+    match upstreams.get_fallback() {
+        Fallback::Direct => warn!("Connect directly when all destinations are down"),
+        Fallback::Gateway => {
+            warn!("Bridge to the gateway when all destinations are down")
+        }
+        Fallback::Fail => {}
+    }
+    if let Some(ref schedule) = opts.schedule {
+        info!("Proxy new connections in {}", schedule);
+        upstreams.set_schedule(schedule.clone());
+    }
+    if !opts.rules.is_empty() {
+        info!("Route new connections by {}", opts.rules);
+    }
+
+    Ok(upstreams)
+}
+
+/// Creates the responders for network tests of game consoles of the capture. The DHCP and the HTTP
+/// probe responders answer as the gateway, so they are skipped without a publishing address.
+fn create_console(opts: &Opts, src: Ipv4Addr, publish: Option<Ipv4Addr>) -> Console {
+    let mut console = Console::new();
+    for responder in &opts.console_responders {
+        match responder {
+            Responder::Ping => {}
+            Responder::Igmp => console.set_igmp(Igmp::new()),
+            Responder::Dhcp => match publish {
+                Some(publish) => {
+                    let mut dhcp = Dhcp::new(publish, src);
+                    if opts.gateway_dns.is_some() {
+                        dhcp.set_dns(Some(publish));
+                    }
+                    for (class, information) in &opts.dhcp_vendors {
+                        dhcp.add_vendor(class.clone(), information.clone());
+                    }
+                    console.set_dhcp(dhcp);
+                }
+                None => warn!(
+                    "console responder dhcp of {} requires a publishing address",
+                    src
+                ),
+            },
+            Responder::Http => match publish {
+                Some(_) => console.set_probe(Probe::new(
+                    opts.http_probe_port,
+                    opts.http_probe_response.clone(),
+                )),
+                None => warn!(
+                    "console responder http of {} requires a publishing address",
+                    src
+                ),
+            },
+        }
+    }
+    let responders: Vec<String> = opts
+        .console_responders
+        .iter()
+        .map(|responder| responder.to_string())
+        .collect();
+    info!(
+        "Answer network tests of consoles by {}",
+        responders.join(", ")
     );
-    info!("Proxy {} to {}", opts.src, opts.dst);
-    if let Err(ref e) = redirector.open(&mut rx) {
-        error!("{}", e);
+
+    console
+}
+
+/// Tests the NAT through the destination, or directly if no destination is given. The port of the
+/// STUN server is 3478 by default.
+fn test_nat(server: &str, dst: Option<String>) -> io::Result<lib::stun::NatReport> {
+    let invalid = |e: std::net::AddrParseError| io::Error::new(io::ErrorKind::InvalidInput, e);
+    let server = lib::stun::parse_server(server).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid STUN server {}", server),
+        )
+    })?;
+    let remote = match dst {
+        Some(dst) => Some(dst.parse().map_err(invalid)?),
+        None => None,
+    };
+    let credentials = match args::get_env_credentials() {
+        Some((username, password)) => Credentials::from_password(username, password),
+        None => Credentials::new(),
+    };
+    match remote {
+        Some(remote) => info!("Test NAT against {} via {}", server, remote),
+        None => info!("Test NAT against {} directly", server),
     }
+
+    lib::test_nat(remote, &credentials, server)
+}
+
+/// Checks the traffic of the source on the interface for the duration without sending anything,
+/// returns the report of the reasons its packets would be dropped for.
+fn check(
+    src: &str,
+    inter: Option<String>,
+    publish: Option<String>,
+    duration: Option<String>,
+) -> io::Result<String> {
+    let invalid = |e: std::net::AddrParseError| io::Error::new(io::ErrorKind::InvalidInput, e);
+    let src: Ipv4Addr = src.parse().map_err(invalid)?;
+    let publish: Option<Ipv4Addr> = match publish {
+        Some(publish) => Some(publish.parse().map_err(invalid)?),
+        None => None,
+    };
+    let duration = match duration {
+        Some(duration) => match duration.parse::<u64>() {
+            Ok(duration) if duration > 0 => duration,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid duration {}", duration),
+                ))
+            }
+        },
+        None => lib::drops::check::DEFAULT_DURATION,
+    };
+    let inter = match lib::interface(inter) {
+        Some(inter) => inter,
+        None => {
+            show_interfaces();
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "cannot determine interface",
+            ));
+        }
+    };
+    info!("Listen on {}", inter);
+
+    // Nothing is sent in a check, ARP is answered by the system without a publishing address
+    let (_, mut rx) =
+        inter.open_with_read_timeout(Some(Duration::from_millis(OBSERVE_READ_TIMEOUT)))?;
+    let mut check = Check::new(src, publish);
+    check.open(&mut rx, Duration::from_secs(duration))?;
+
+    Ok(check.report())
 }
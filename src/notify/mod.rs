@@ -0,0 +1,76 @@
+use std::io;
+
+use pcap2socks_core::notify::{Condition, Event, Notifier};
+
+/// Represents the title of notifications.
+#[cfg(feature = "notify")]
+const TITLE: &str = "pcap2socks";
+
+/// Creates a new `Notifier` of the given conditions delivering desktop notifications.
+pub fn new_notifier(conditions: Vec<Condition>) -> io::Result<Notifier> {
+    if !cfg!(feature = "notify") {
+        return Err(io::Error::other(
+            "notifications are not supported without the notify feature",
+        ));
+    }
+
+    let mut notifier = Notifier::new(conditions);
+    notifier.set_deliver(Box::new(deliver));
+
+    Ok(notifier)
+}
+
+#[cfg(feature = "notify")]
+fn deliver(event: &Event) -> io::Result<()> {
+    use std::process::{Command, Stdio};
+
+    let body = event.to_string();
+    let mut command = if cfg!(target_os = "windows") {
+        let script = format!(
+            "[Windows.UI.Notifications.ToastNotificationManager, Windows.UI.Notifications, ContentType = WindowsRuntime] | Out-Null; \
+             $xml = [Windows.UI.Notifications.ToastNotificationManager]::GetTemplateContent([Windows.UI.Notifications.ToastTemplateType]::ToastText02); \
+             $texts = $xml.GetElementsByTagName('text'); \
+             $texts.Item(0).AppendChild($xml.CreateTextNode('{}')) | Out-Null; \
+             $texts.Item(1).AppendChild($xml.CreateTextNode('{}')) | Out-Null; \
+             [Windows.UI.Notifications.ToastNotificationManager]::CreateToastNotifier('{}').Show([Windows.UI.Notifications.ToastNotification]::new($xml))",
+            TITLE,
+            body.replace('\'', "''"),
+            TITLE
+        );
+        let mut command = Command::new("powershell");
+        command.args(["-NoProfile", "-NonInteractive", "-Command", &script]);
+        command
+    } else if cfg!(target_os = "macos") {
+        let script = format!(
+            "display notification \"{}\" with title \"{}\"",
+            body.replace('\\', "\\\\").replace('"', "\\\""),
+            TITLE
+        );
+        let mut command = Command::new("osascript");
+        command.args(["-e", &script]);
+        command
+    } else {
+        // freedesktop.org
+        let mut command = Command::new("notify-send");
+        command.args(["--app-name", TITLE, TITLE, &body]);
+        command
+    };
+
+    let status = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!("exit with {}", status)));
+    }
+
+    Ok(())
+}
+
+#[cfg(not(feature = "notify"))]
+fn deliver(_: &Event) -> io::Result<()> {
+    Err(io::Error::other(
+        "notifications are not supported without the notify feature",
+    ))
+}